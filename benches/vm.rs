@@ -0,0 +1,85 @@
+use kale::bytecode::{self, Program};
+use kale::ir::lower;
+use kale::lexer::Lexer;
+use kale::parser::ModuleAst;
+use kale::session::Entry;
+use kale::vm::{Mode, Vm};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// The programs timed, each computing with numbers far more than it calls
+/// or branches.
+const CORPORA: [(&str, &str); 4] = [
+  (
+    "fib",
+    "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2); fib(22);",
+  ),
+  (
+    "integrate",
+    "def f(x) x * x * x - 2 * x + 1;
+    def integrate(a, b, depth)
+      if depth == 0 then (b - a) * f((a + b) / 2)
+      else integrate(a, (a + b) / 2, depth - 1) + integrate((a + b) / 2, b, depth - 1);
+    integrate(0, 2, 15);",
+  ),
+  (
+    "mandelbrot",
+    "def escape(cr, ci, zr, zi, n)
+      if n == 0 || zr * zr + zi * zi > 4 then n
+      else escape(cr, ci, zr * zr - zi * zi + cr, 2 * zr * zi + ci, n - 1);
+    def row(y, x) if x > 1 then 0 else escape(x, y, 0, 0, 64) + row(y, x + 0.05);
+    def rows(y) if y > 1.5 then 0 else row(y, -2) + rows(y + 0.05);
+    rows(-1.5);",
+  ),
+  (
+    "collatz",
+    "def steps(n) if n == 1 then 0 else 1 + steps(if n % 2 == 0 then n / 2 else 3 * n + 1);
+    def total(n) if n == 0 then 0 else steps(n) + total(n - 1);
+    total(2000);",
+  ),
+];
+
+const RUNS: usize = 5;
+
+/// Times the stack and the register machine on each program, checking that
+/// they compute the same, and prints how much faster the register machine
+/// is. Run with `cargo bench --bench vm`; `cargo test`, which doesn't pass
+/// `--bench`, only runs each program once on each machine.
+fn main() {
+  let runs = match std::env::args().any(|arg| arg == "--bench") {
+    true => RUNS,
+    false => 1,
+  };
+  println!(
+    "{:12} {:>10} {:>10} {:>7}",
+    "corpus", "stack", "register", "ratio"
+  );
+  for (name, src) in CORPORA {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let program = bytecode::compile(&lower(&module, Entry::TopLevel).unwrap());
+    let (stack, expected) = time(&program, Mode::Stack, runs);
+    let (register, results) = time(&program, Mode::Register, runs);
+    assert_eq!(results, expected, "the machines differ on {}", name);
+    println!(
+      "{:12} {:>8.2}ms {:>8.2}ms {:>6.2}x",
+      name,
+      stack.as_secs_f64() * 1e3,
+      register.as_secs_f64() * 1e3,
+      stack.as_secs_f64() / register.as_secs_f64()
+    );
+  }
+}
+
+/// The fastest of `runs` runs of `program` in `mode`, and what it returns.
+fn time(program: &Program, mode: Mode, runs: usize) -> (Duration, String) {
+  let mut vm = Vm::builder().mode(mode).build();
+  let mut fastest = Duration::MAX;
+  let mut results = String::new();
+  for _ in 0..runs {
+    let start = Instant::now();
+    let values = vm.run(program).unwrap();
+    fastest = fastest.min(start.elapsed());
+    results = format!("{:?}", values);
+  }
+  (fastest, results)
+}
@@ -0,0 +1,89 @@
+// The externs of programs built by Kale for JavaScript hosts, which
+// `run-wasm.mjs` and `run-js.mjs` give them: the functions of Kale's runtime,
+// behaving as those of `src/runtime.rs` do, and the math of the prelude. In a
+// browser, `printd` and `putchard` would write elsewhere than stdout.
+
+import { readSync, writeSync } from "node:fs";
+
+// Formats `x` as Rust's `{:?}` does: in full with at least one decimal,
+// unless it is below 1e-4 or at least 1e16, which have an exponent.
+function debug(x) {
+  if (Number.isNaN(x)) return "NaN";
+  const sign = x < 0 || Object.is(x, -0) ? "-" : "";
+  x = Math.abs(x);
+  if (x === Infinity) return `${sign}inf`;
+  if (x === 0) return `${sign}0.0`;
+  const [mantissa, e] = x.toExponential().split("e");
+  const digits = mantissa.replace(".", "");
+  const exp = Number(e);
+  if (x < 1e-4 || x >= 1e16) {
+    const fraction = digits.length > 1 ? `.${digits.slice(1)}` : "";
+    return `${sign}${digits[0]}${fraction}e${exp}`;
+  }
+  if (exp < 0) return `${sign}0.${"0".repeat(-exp - 1)}${digits}`;
+  const integer = digits.slice(0, exp + 1).padEnd(exp + 1, "0");
+  return `${sign}${integer}.${digits.slice(exp + 1) || "0"}`;
+}
+
+// Reads a line of stdin, without its newline, or null at the end of input.
+function readLine() {
+  const bytes = [];
+  const byte = Buffer.alloc(1);
+  while (readSync(0, byte, 0, 1, null) === 1) {
+    if (byte[0] === 10) return Buffer.from(bytes).toString();
+    bytes.push(byte[0]);
+  }
+  return bytes.length ? Buffer.from(bytes).toString() : null;
+}
+
+const MASK = (1n << 64n) - 1n;
+let rng = 0n;
+
+export const env = {
+  printd(x) {
+    writeSync(1, `${debug(x)}\n`);
+    return 0;
+  },
+  putchard(c) {
+    const byte = Number.isNaN(c) ? 0 : Math.min(Math.max(Math.trunc(c), 0), 255);
+    writeSync(1, Buffer.from([byte]));
+    return 0;
+  },
+  readd() {
+    const line = readLine()?.trim();
+    return line ? Number(line) : NaN;
+  },
+  // the next number of a splitmix64 sequence, scaled to [0, 1)
+  rand() {
+    rng = (rng + 0x9e3779b97f4a7c15n) & MASK;
+    let z = rng;
+    z = ((z ^ (z >> 30n)) * 0xbf58476d1ce4e5b9n) & MASK;
+    z = ((z ^ (z >> 27n)) * 0x94d049bb133111ebn) & MASK;
+    z ^= z >> 31n;
+    return Number(z >> 11n) / 2 ** 53;
+  },
+  // seeds are truncated to integers, saturating as Rust's `as i64` does
+  srand(seed) {
+    const n = Number.isNaN(seed)
+      ? 0n
+      : seed >= 2 ** 63
+        ? (1n << 63n) - 1n
+        : seed <= -(2 ** 63)
+          ? -(1n << 63n)
+          : BigInt(Math.trunc(seed));
+    rng = BigInt.asUintN(64, n);
+    return 0;
+  },
+  sin: Math.sin,
+  cos: Math.cos,
+  exp: Math.exp,
+  log: Math.log,
+  sqrt: Math.sqrt,
+  pow: Math.pow,
+  abs: Math.abs,
+  floor: Math.floor,
+  // Rust's `min` and `max` ignore NaN
+  min: (x, y) => (Number.isNaN(x) ? y : Number.isNaN(y) ? x : Math.min(x, y)),
+  max: (x, y) => (Number.isNaN(x) ? y : Number.isNaN(y) ? x : Math.max(x, y)),
+  fmod: (x, y) => x % y,
+};
@@ -0,0 +1,20 @@
+// Runs a JavaScript module built by `Kale build --emit=js prog.kale`:
+//
+//     node examples/run-js.mjs prog.js
+//
+// The module exports `instantiate`, which takes the externs of the program
+// from `env`, as `kale-env.mjs` provides them, and returns its functions
+// along with `run`, which runs it. A browser playground imports the same
+// module from the source it built, with `import()` of a blob URL.
+
+import { resolve } from "node:path";
+import { pathToFileURL } from "node:url";
+import { env } from "./kale-env.mjs";
+
+const path = process.argv[2];
+if (!path) {
+  console.error("Usage: node examples/run-js.mjs prog.js");
+  process.exit(2);
+}
+const { instantiate } = await import(pathToFileURL(resolve(path)).href);
+instantiate(env).run();
@@ -0,0 +1,18 @@
+// Runs a WebAssembly module built by `Kale build --emit=wasm prog.kale`:
+//
+//     node examples/run-wasm.mjs prog.wasm
+//
+// The module imports its externs from `env`, which `kale-env.mjs` provides.
+// It then calls `_start`, which runs the program. In a browser, the same
+// imports go to `WebAssembly.instantiateStreaming`.
+
+import { readFileSync } from "node:fs";
+import { env } from "./kale-env.mjs";
+
+const path = process.argv[2];
+if (!path) {
+  console.error("Usage: node examples/run-wasm.mjs prog.wasm");
+  process.exit(2);
+}
+const { instance } = await WebAssembly.instantiate(readFileSync(path), { env });
+instance.exports._start();
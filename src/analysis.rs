@@ -0,0 +1,759 @@
+use crate::consts::eval_const;
+use crate::eval::eval_bin;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, Pattern, ProtoAst};
+use crate::runtime::is_pure;
+use crate::value::{truthy, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// CallGraph - which functions of a module call which. Nodes are the
+/// defined functions and the externs, in the order of their first items;
+/// a function defined twice is one node. There is an edge from `f` to `g`
+/// when the body of `f` calls `g`, takes `&g`, or applies an operator `g`
+/// overloads, whatever the types of the operands. Calls through closures and function values are not followed,
+/// and a local binding hides the function of the same name.
+#[derive(Debug)]
+pub struct CallGraph {
+  names: Vec<String>,
+  externs: Vec<bool>,
+  callees: Vec<Vec<usize>>, // sorted, without duplicates
+  entry: Vec<usize>,        // called by the top-level expressions and initializers
+}
+
+pub fn call_graph(module: &ModuleAst) -> CallGraph {
+  let mut index = HashMap::new();
+  let mut graph = CallGraph {
+    names: vec![],
+    externs: vec![],
+    callees: vec![],
+    entry: vec![],
+  };
+  for item in &module.items {
+    let (name, is_extern) = match item {
+      Ast::Func(func) if !func.proto.name.is_empty() => (&func.proto.name, false),
+      Ast::Proto(proto) => (&proto.name, true),
+      _ => continue,
+    };
+    if !index.contains_key(name) {
+      index.insert(name.clone(), graph.names.len());
+      graph.names.push(name.clone());
+      graph.externs.push(is_extern);
+      graph.callees.push(vec![]);
+    }
+  }
+
+  for item in &module.items {
+    let mut callees = vec![];
+    let caller = match item {
+      Ast::Func(func) => {
+        let mut scope = func.proto.args.clone();
+        calls(&func.body, &index, &mut scope, &mut callees);
+        index.get(&func.proto.name)
+      }
+      Ast::Expr(expr) | Ast::Const(_, _, expr, _) => {
+        calls(expr, &index, &mut vec![], &mut callees);
+        None
+      }
+      Ast::Global(vars, _) => {
+        for init in vars.iter().filter_map(|(.., init)| init.as_ref()) {
+          calls(init, &index, &mut vec![], &mut callees);
+        }
+        None
+      }
+      Ast::Proto(_) | Ast::Struct(_) | Ast::Import(..) => continue,
+    };
+    let edges = match caller {
+      Some(&i) => &mut graph.callees[i],
+      None => &mut graph.entry,
+    };
+    edges.extend(callees);
+    edges.sort_unstable();
+    edges.dedup();
+  }
+  graph
+}
+
+/// The functions of `module` that can never run: those neither its
+/// top-level expressions and initializers nor its `main` reach. A module
+/// with neither is a library, all of whose functions are exported, so none
+/// of them is dead. Unused externs are only declarations, and aren't
+/// reported.
+pub fn dead_functions(module: &ModuleAst) -> Vec<&ProtoAst> {
+  let graph = call_graph(module);
+  let mut roots = graph.entry();
+  roots.extend(
+    graph
+      .names()
+      .filter(|&name| name == "main" && !graph.is_extern(name)),
+  );
+  if roots.is_empty() {
+    return vec![];
+  }
+  let live = graph.reachable(&roots);
+  let mut dead = vec![];
+  for item in &module.items {
+    match item {
+      Ast::Func(func) if !func.proto.name.is_empty() && !live.contains(&&*func.proto.name) => {
+        dead.push(&func.proto)
+      }
+      _ => (),
+    }
+  }
+  dead
+}
+
+/// Where `func` calls itself on every path through its body, before it can
+/// return: such a function never returns at all, as nothing can stop the
+/// recursion. Only direct calls count, and an error raised on the way, as
+/// by a failing `assert`, is not taken to stop it.
+pub fn unconditional_recursion(func: &FuncAst) -> Option<Span> {
+  let name = &func.proto.name;
+  match name.is_empty() || func.proto.args.contains(name) {
+    true => None,
+    false => always_calls(&func.body, name),
+  }
+}
+
+/// Where evaluating `expr` always calls `name`. Sub-expressions are taken
+/// in order until one of them always calls it, or may `return` instead.
+/// Only the conditions of `if`s and `match`es, the left operands of `&&`
+/// and `||` and the bodies of `try`s are always evaluated, unless all the
+/// branches call `name`; lambda bodies are not evaluated where they are
+/// written, and a binding of `name` hides the function from its scope.
+fn always_calls(expr: &ExprAst, name: &str) -> Option<Span> {
+  let seq = |exprs: Vec<&ExprAst>| {
+    for expr in exprs {
+      match always_calls(expr, name) {
+        Some(span) => return Some(span),
+        None if may_return(expr) => return None,
+        None => (),
+      }
+    }
+    None
+  };
+  match expr {
+    ExprAst::CallAst(callee, args, span) => {
+      seq(args.iter().collect()).or((callee == name).then_some(*span))
+    }
+    ExprAst::BinAst(lhs, BinOp::And | BinOp::Or, _, _) => always_calls(lhs, name),
+    ExprAst::IfAst { cond, then, els } => always_calls(cond, name).or_else(|| {
+      let span = always_calls(then, name)?;
+      always_calls(els, name).map(|_| span)
+    }),
+    ExprAst::MatchAst(expr, arms, _) => always_calls(expr, name).or_else(|| {
+      let spans: Option<Vec<_>> = arms
+        .iter()
+        .map(|(_, arm)| always_calls(arm, name))
+        .collect();
+      spans?.first().copied()
+    }),
+    ExprAst::TryAst(expr, ..) => always_calls(expr, name),
+    ExprAst::LambdaAst(..) => None,
+    ExprAst::LetAst(bindings, body) => {
+      let shadowed = bindings.iter().position(|(bound, ..)| bound == name);
+      let inits = bindings[..shadowed.map_or(bindings.len(), |i| i + 1)].iter();
+      let mut exprs: Vec<_> = inits.map(|(.., init)| init).collect();
+      if shadowed.is_none() {
+        exprs.push(body);
+      }
+      seq(exprs)
+    }
+    ExprAst::LetTupleAst(names, init, body) => match names.iter().any(|(bound, _)| bound == name) {
+      true => always_calls(init, name),
+      false => seq(vec![init, body]),
+    },
+    ExprAst::VarInAst(vars, body) => {
+      let shadowed = vars.iter().position(|(bound, ..)| bound == name);
+      let vars = vars[..shadowed.map_or(vars.len(), |i| i + 1)].iter();
+      let mut exprs: Vec<_> = vars.filter_map(|(.., init)| init.as_ref()).collect();
+      if shadowed.is_none() {
+        exprs.push(body);
+      }
+      seq(exprs)
+    }
+    expr => seq(expr.children()),
+  }
+}
+
+fn may_return(expr: &ExprAst) -> bool {
+  matches!(expr, ExprAst::ReturnAst(..)) || expr.children().into_iter().any(may_return)
+}
+
+/// Unreachable - why an arm of a `match` can never be taken.
+#[derive(Debug, PartialEq)]
+pub enum Unreachable {
+  Covered(usize), // the earlier arm at that index matches all its values
+  Exhausted,      // the earlier arms together match every value
+  Empty,          // its range has no values
+}
+
+/// Whether some arm of a `match` matches any value: a `_`, or both `true`
+/// and `false`.
+pub fn is_exhaustive(arms: &[(Pattern, ExprAst)]) -> bool {
+  let has = |val: bool| {
+    let lit = Pattern::Lit(ExprAst::BoolAst(val));
+    arms.iter().any(|(pattern, _)| pattern == &lit)
+  };
+  arms.iter().any(|(pattern, _)| pattern == &Pattern::Wild) || has(true) && has(false)
+}
+
+/// The arms of a `match` that can never be taken, by index, as the earlier
+/// arms already match all the values they do. Bounds and literals are
+/// compared as `match` compares them; those that aren't constant, or that
+/// can't be compared, are taken to overlap nothing.
+pub fn unreachable_arms(arms: &[(Pattern, ExprAst)]) -> Vec<(usize, Unreachable)> {
+  let constant = |expr: &ExprAst| eval_const(expr, &HashMap::new());
+  let holds = |op, lhs: &Option<Value>, rhs: &Option<Value>| match (lhs, rhs) {
+    (Some(lhs), Some(rhs)) => eval_bin(op, lhs.clone(), rhs.clone()).is_ok_and(truthy),
+    _ => false,
+  };
+  let covers = |earlier: &Pattern, pattern: &Pattern| match (earlier, pattern) {
+    (Pattern::Wild, _) => true,
+    (Pattern::Lit(lit), Pattern::Lit(other)) => holds(BinOp::Eq, &constant(lit), &constant(other)),
+    (Pattern::Range(lo, hi), Pattern::Lit(lit)) => {
+      let lit = constant(lit);
+      holds(BinOp::Ge, &lit, &constant(lo)) && holds(BinOp::Lt, &lit, &constant(hi))
+    }
+    (Pattern::Range(lo, hi), Pattern::Range(inner_lo, inner_hi)) => {
+      holds(BinOp::Ge, &constant(inner_lo), &constant(lo))
+        && holds(BinOp::Le, &constant(inner_hi), &constant(hi))
+    }
+    _ => false,
+  };
+  let empty = |pattern: &Pattern| match pattern {
+    Pattern::Range(lo, hi) => {
+      let (lo, hi) = (constant(lo), constant(hi));
+      lo.is_some() && hi.is_some() && !holds(BinOp::Lt, &lo, &hi)
+    }
+    _ => false,
+  };
+  let mut unreachable = vec![];
+  for (i, (pattern, _)) in arms.iter().enumerate() {
+    let earlier = &arms[..i];
+    if empty(pattern) {
+      unreachable.push((i, Unreachable::Empty));
+    } else if let Some(j) = earlier
+      .iter()
+      .position(|(earlier, _)| covers(earlier, pattern))
+    {
+      unreachable.push((i, Unreachable::Covered(j)));
+    } else if pattern == &Pattern::Wild && is_exhaustive(earlier) {
+      unreachable.push((i, Unreachable::Exhausted));
+    }
+  }
+  unreachable
+}
+
+/// Effects - which calls and operators of a module are pure: they always
+/// yield the same value for the same operands, without any effect. Those
+/// are the pure builtins, which an `extern` of the same name keeps pure,
+/// the builtin operators the module doesn't overload, and the pure
+/// functions it defines.
+///
+/// A function is pure when its body neither calls an extern, an output or
+/// input builtin, a closure or a function that isn't pure, nor reads or
+/// assigns a global variable. Constants, local variables and `return`s
+/// are fine, and so is constructing a struct, though no two constructions
+/// are the same value. Functions that only call each other in a cycle are
+/// pure together.
+pub struct Effects {
+  defined: HashSet<String>,
+  structs: HashSet<String>,
+  globals: HashSet<String>,
+  bodies: HashMap<String, Body>, // of the functions defined
+  pure: HashSet<String>,         // the pure ones
+}
+
+/// What the body of a function does besides calling other functions,
+/// which may or may not be pure.
+#[derive(Default)]
+struct Body {
+  effects: bool,        // calls a closure or assigns a global
+  callees: Vec<String>, // with `binary@` for an operator `@`
+  free: Vec<String>,    // the variables it reads that aren't local
+}
+
+pub fn effects(module: &ModuleAst) -> Effects {
+  let mut effects = Effects::new();
+  module.items.iter().for_each(|item| effects.declare(item));
+  effects
+}
+
+impl Effects {
+  pub fn new() -> Self {
+    Self {
+      defined: HashSet::new(),
+      structs: HashSet::new(),
+      globals: HashSet::new(),
+      bodies: HashMap::new(),
+      pure: HashSet::new(),
+    }
+  }
+
+  /// Takes the function, struct or globals `item` defines, if any, into
+  /// account. A function defined again replaces the previous definition,
+  /// for its callers too.
+  pub fn declare(&mut self, item: &Ast) {
+    match item {
+      Ast::Func(func) if !func.proto.name.is_empty() => {
+        self.defined.insert(func.proto.name.clone());
+        let mut body = Body::default();
+        summarize(&func.body, &mut func.proto.args.clone(), &mut body);
+        self.bodies.insert(func.proto.name.clone(), body);
+      }
+      Ast::Struct(decl) => {
+        self.defined.insert(decl.name.clone());
+        self.structs.insert(decl.name.clone());
+      }
+      Ast::Global(vars, _) => {
+        let names = vars.iter().map(|(name, ..)| name.clone());
+        self.globals.extend(names);
+      }
+      _ => return,
+    }
+    self.classify();
+  }
+
+  /// Finds the pure functions: starting from all those whose bodies have no
+  /// effect of their own, those calling one that isn't pure are removed
+  /// until none is left.
+  fn classify(&mut self) {
+    let mut pure: HashSet<String> = self
+      .bodies
+      .iter()
+      .filter(|(_, body)| !body.effects && !body.free.iter().any(|v| self.globals.contains(v)))
+      .map(|(name, _)| name.clone())
+      .collect();
+    loop {
+      let impure: Vec<String> = pure
+        .iter()
+        .filter(|name| {
+          let callees = &self.bodies[*name].callees;
+          !callees
+            .iter()
+            .all(|callee| self.is_pure_callee(callee, &pure))
+        })
+        .cloned()
+        .collect();
+      if impure.is_empty() {
+        break;
+      }
+      impure.iter().for_each(|name| {
+        pure.remove(name);
+      });
+    }
+    self.pure = pure;
+  }
+
+  /// Whether a call of `name` keeps its caller pure, given the functions
+  /// of the module that are `pure` so far.
+  fn is_pure_callee(&self, name: &str, pure: &HashSet<String>) -> bool {
+    match self.bodies.contains_key(name) {
+      true => pure.contains(name),
+      false if self.structs.contains(name) => true,
+      false => !self.defined.contains(name) && (is_pure(name) || name.starts_with("binary")),
+    }
+  }
+
+  /// Whether the function `name` the module defines is pure.
+  pub fn is_pure_func(&self, name: &str) -> bool {
+    self.pure.contains(name)
+  }
+
+  pub fn is_pure_call(&self, name: &str) -> bool {
+    self.pure.contains(name) || (!self.defined.contains(name) && is_pure(name))
+  }
+
+  pub fn is_pure_op(&self, op: BinOp) -> bool {
+    let name = format!("binary{}", op.as_str());
+    self.pure.contains(&name) || !self.defined.contains(&name)
+  }
+
+  /// Whether evaluating `expr` has no effect, so that it yields the same
+  /// value whenever its variables hold the same values. It may still fail.
+  pub fn is_pure(&self, expr: &ExprAst) -> bool {
+    let pure = match expr {
+      ExprAst::CallAst(name, ..) => self.is_pure_call(name),
+      ExprAst::BinAst(_, op, _, _) => self.is_pure_op(*op),
+      ExprAst::AssignAst(..) | ExprAst::ReturnAst(..) => false,
+      _ => true,
+    };
+    pure && expr.children().into_iter().all(|child| self.is_pure(child))
+  }
+}
+
+/// Collects what evaluating `expr` does into `body`, with the local bindings
+/// in `scope`. The body of a lambda only runs when the lambda is called,
+/// which is an effect already.
+fn summarize(expr: &ExprAst, scope: &mut Vec<String>, body: &mut Body) {
+  match expr {
+    ExprAst::CallAst(name, _, _) if scope.contains(name) => body.effects = true,
+    ExprAst::CallAst(name, _, _) => body.callees.push(name.clone()),
+    ExprAst::BinAst(_, op, _, _) => body.callees.push(format!("binary{}", op.as_str())),
+    ExprAst::VarAst(name, _) if !scope.contains(name) => body.free.push(name.clone()),
+    ExprAst::AssignAst(name, ..) if !scope.contains(name) => body.effects = true,
+    ExprAst::LambdaAst(..) => return,
+    // unlike in `calls`, the initializers must not see their bindings, as
+    // a binding hiding a global doesn't hide a read of it in its initializer
+    ExprAst::LetAst(bindings, rest) => {
+      let depth = scope.len();
+      for (name, _, init) in bindings {
+        summarize(init, scope, body);
+        scope.push(name.clone());
+      }
+      summarize(rest, scope, body);
+      scope.truncate(depth);
+      return;
+    }
+    ExprAst::VarInAst(vars, rest) => {
+      let depth = scope.len();
+      for (name, _, init) in vars {
+        if let Some(init) = init {
+          summarize(init, scope, body);
+        }
+        scope.push(name.clone());
+      }
+      summarize(rest, scope, body);
+      scope.truncate(depth);
+      return;
+    }
+    ExprAst::LetTupleAst(names, init, rest) => {
+      summarize(init, scope, body);
+      let depth = scope.len();
+      scope.extend(names.iter().map(|(name, _)| name.clone()));
+      summarize(rest, scope, body);
+      scope.truncate(depth);
+      return;
+    }
+    ExprAst::TryAst(expr, name, handler, _) => {
+      summarize(expr, scope, body);
+      let depth = scope.len();
+      scope.extend(name.iter().cloned());
+      summarize(handler, scope, body);
+      scope.truncate(depth);
+      return;
+    }
+    _ => (),
+  }
+  for child in expr.children() {
+    summarize(child, scope, body);
+  }
+}
+
+/// Collects the functions `expr` refers to, as indices into `index`.
+fn calls(
+  expr: &ExprAst,
+  index: &HashMap<String, usize>,
+  scope: &mut Vec<String>,
+  callees: &mut Vec<usize>,
+) {
+  let mut refer = |name: &String, scope: &Vec<String>| {
+    if let Some(&i) = index.get(name).filter(|_| !scope.contains(name)) {
+      callees.push(i);
+    }
+  };
+  let bound: Vec<&String> = match expr {
+    ExprAst::CallAst(name, _, _) | ExprAst::FuncRefAst(name, _) => {
+      refer(name, scope);
+      vec![]
+    }
+    ExprAst::BinAst(_, op, _, _) => {
+      refer(&format!("binary{}", op.as_str()), scope);
+      vec![]
+    }
+    ExprAst::LetAst(bindings, _) => bindings.iter().map(|(name, ..)| name).collect(),
+    ExprAst::LetTupleAst(names, _, _) | ExprAst::LambdaAst(names, _) => {
+      names.iter().map(|(name, _)| name).collect()
+    }
+    ExprAst::VarInAst(vars, _) => vars.iter().map(|(name, ..)| name).collect(),
+    ExprAst::TryAst(_, name, _, _) => name.iter().collect(),
+    _ => vec![],
+  };
+  // initializers may already see the bindings; that only hides more calls
+  let depth = scope.len();
+  scope.extend(bound.into_iter().cloned());
+  for child in expr.children() {
+    calls(child, index, scope, callees);
+  }
+  scope.truncate(depth);
+}
+
+impl CallGraph {
+  /// The functions and externs, in the order of their items.
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.names.iter().map(String::as_str)
+  }
+
+  pub fn is_extern(&self, name: &str) -> bool {
+    self.index(name).is_some_and(|i| self.externs[i])
+  }
+
+  /// The functions `name` calls, in the order of their items.
+  pub fn callees(&self, name: &str) -> Vec<&str> {
+    let callees = self.index(name).map_or(&[][..], |i| &self.callees[i]);
+    callees.iter().map(|&i| self.names[i].as_str()).collect()
+  }
+
+  /// The functions that call `name`, in the order of their items.
+  pub fn callers(&self, name: &str) -> Vec<&str> {
+    let Some(callee) = self.index(name) else {
+      return vec![];
+    };
+    let callers = (0..self.names.len()).filter(|&i| self.callees[i].binary_search(&callee).is_ok());
+    callers.map(|i| self.names[i].as_str()).collect()
+  }
+
+  /// The functions the top-level expressions and initializers call.
+  pub fn entry(&self) -> Vec<&str> {
+    self.entry.iter().map(|&i| self.names[i].as_str()).collect()
+  }
+
+  /// The functions `roots` may end up calling, themselves included, in the
+  /// order of their items.
+  pub fn reachable(&self, roots: &[&str]) -> Vec<&str> {
+    let mut seen = vec![false; self.names.len()];
+    let mut work: Vec<usize> = roots.iter().filter_map(|&name| self.index(name)).collect();
+    while let Some(i) = work.pop() {
+      if !std::mem::replace(&mut seen[i], true) {
+        work.extend(&self.callees[i]);
+      }
+    }
+    let live = (0..self.names.len()).filter(|&i| seen[i]);
+    live.map(|i| self.names[i].as_str()).collect()
+  }
+
+  /// The groups of functions that call each other in a cycle, including
+  /// the functions that call themselves. Both the groups and their members
+  /// are in the order of the items.
+  pub fn cycles(&self) -> Vec<Vec<&str>> {
+    let mut cycles: Vec<Vec<usize>> = self
+      .components()
+      .into_iter()
+      .filter(|scc| scc.len() > 1 || self.callees[scc[0]].contains(&scc[0]))
+      .collect();
+    cycles.iter_mut().for_each(|scc| scc.sort_unstable());
+    cycles.sort_unstable();
+    let names = |scc: Vec<usize>| scc.into_iter().map(|i| self.names[i].as_str()).collect();
+    cycles.into_iter().map(names).collect()
+  }
+
+  pub fn is_recursive(&self, name: &str) -> bool {
+    self.cycles().iter().any(|cycle| cycle.contains(&name))
+  }
+
+  /// The graph in Graphviz's DOT language, with externs drawn as boxes.
+  pub fn to_dot(&self) -> String {
+    let mut dot = "digraph calls {\n".to_string();
+    for (name, &is_extern) in self.names.iter().zip(&self.externs) {
+      match is_extern {
+        true => writeln!(dot, "  \"{}\" [shape=box];", name),
+        false => writeln!(dot, "  \"{}\";", name),
+      }
+      .unwrap();
+    }
+    for (caller, callees) in self.names.iter().zip(&self.callees) {
+      for &callee in callees {
+        writeln!(dot, "  \"{}\" -> \"{}\";", caller, self.names[callee]).unwrap();
+      }
+    }
+    dot.push_str("}\n");
+    dot
+  }
+
+  fn index(&self, name: &str) -> Option<usize> {
+    self.names.iter().position(|n| n == name)
+  }
+
+  /// The strongly connected components, by Tarjan's algorithm.
+  fn components(&self) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+      callees: &'a [Vec<usize>],
+      index: Vec<Option<usize>>,
+      low: Vec<usize>,
+      stack: Vec<usize>,
+      on_stack: Vec<bool>,
+      next: usize,
+      sccs: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+      fn visit(&mut self, v: usize) {
+        self.index[v] = Some(self.next);
+        self.low[v] = self.next;
+        self.next += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+        for &w in &self.callees[v] {
+          match self.index[w] {
+            None => {
+              self.visit(w);
+              self.low[v] = self.low[v].min(self.low[w]);
+            }
+            Some(i) if self.on_stack[w] => self.low[v] = self.low[v].min(i),
+            Some(_) => (),
+          }
+        }
+        if Some(self.low[v]) == self.index[v] {
+          let mut scc = vec![];
+          loop {
+            let w = self.stack.pop().unwrap();
+            self.on_stack[w] = false;
+            scc.push(w);
+            if w == v {
+              break;
+            }
+          }
+          self.sccs.push(scc);
+        }
+      }
+    }
+
+    let n = self.names.len();
+    let mut tarjan = Tarjan {
+      callees: &self.callees,
+      index: vec![None; n],
+      low: vec![0; n],
+      stack: vec![],
+      on_stack: vec![false; n],
+      next: 0,
+      sccs: vec![],
+    };
+    for v in 0..n {
+      if tarjan.index[v].is_none() {
+        tarjan.visit(v);
+      }
+    }
+    tarjan.sccs
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  fn graph(src: &'static str) -> CallGraph {
+    call_graph(&ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap())
+  }
+
+  #[test]
+  fn call_graph_edges() {
+    let g = graph(
+      "extern sin(x); def even(n) n == 0 || odd(n - 1); def odd(n) n != 0 && even(n - 1);
+      def f(x) let even = \\(n) n in even(x) * sin(x); def g() g() * f(1); def h() &f;
+      struct P(x); def binary + (a: P, b: P) P(a.x + b.x); def add(p: P) p + p; f(2)",
+    );
+    let names: Vec<_> = g.names().collect();
+    assert_eq!(
+      names,
+      vec!["sin", "even", "odd", "f", "g", "h", "binary+", "add"]
+    );
+    assert_eq!(g.callees("f"), vec!["sin"]);
+    assert_eq!(g.callees("g"), vec!["f", "g"]);
+    assert_eq!(g.callees("h"), vec!["f"]);
+    assert_eq!(g.callees("binary+"), vec!["binary+"]);
+    assert_eq!(g.callees("add"), vec!["binary+"]);
+    assert_eq!(g.callers("f"), vec!["g", "h"]);
+    assert_eq!(g.entry(), vec!["f"]);
+    assert!(g.is_extern("sin") && !g.is_extern("f"));
+    assert_eq!(
+      g.cycles(),
+      vec![vec!["even", "odd"], vec!["g"], vec!["binary+"]]
+    );
+    assert!(g.is_recursive("odd") && !g.is_recursive("f"));
+  }
+
+  #[test]
+  fn call_graph_dot() {
+    let g = graph("extern cos(x); def f(x) cos(x) + f(x - 1); def g() f(1)");
+    let dot = "digraph calls {
+  \"cos\" [shape=box];
+  \"f\";
+  \"g\";
+  \"f\" -> \"cos\";
+  \"f\" -> \"f\";
+  \"g\" -> \"f\";
+}
+";
+    assert_eq!(g.to_dot(), dot);
+  }
+
+  #[test]
+  fn dead_functions_from_roots() {
+    let dead = |src: &'static str| -> Vec<String> {
+      let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+      let dead = dead_functions(&module);
+      dead
+        .iter()
+        .map(|proto| format!("{} {}", proto.name, proto.span))
+        .collect()
+    };
+    let src = "extern sin(x); def a() b(); def b() sin(1); def c() d(); def d() c();
+      def e() 1; def f() 2; const K = e(); a()";
+    assert_eq!(dead(src), vec!["c 1:49", "d 1:62", "f 2:22"]);
+    assert_eq!(
+      dead("def main() g(&h); def g(f) f(); def h() 1; def i() 1"),
+      vec!["i 1:48"]
+    );
+    assert_eq!(dead("def f() 1; def g() 2"), Vec::<String>::new());
+  }
+
+  #[test]
+  fn unconditional_recursion_paths() {
+    let src =
+      "def f(x) f(x); def g(x) 1 + g(x - 1); def h(n) if n == 0 then 1 else n * h(n - 1);
+      def i(n) if n > 0 then i(n - 1) else i(n + 1); def j(n) { if n == 0 then return 0 else (); j(n) };
+      def k(n) n > 0 && k(n - 1); def l(n) match n { 0 -> l(1), _ -> l(0) };
+      def m(n) \\(x) m(x); def p(p) p(1); def q(n) let q = \\(x) x in q(n);
+      def r(n) let a = r(n) in a; def s(n) try s(n) catch e -> 0; def t(n) return t(n)";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let recursive: Vec<_> = module
+      .items
+      .iter()
+      .filter_map(|item| match item {
+        Ast::Func(func) => {
+          unconditional_recursion(func).map(|span| format!("{} {}", func.proto.name, span))
+        }
+        _ => None,
+      })
+      .collect();
+    assert_eq!(
+      recursive,
+      vec!["f 1:10", "g 1:29", "i 2:30", "l 3:59", "r 5:24", "s 5:48", "t 5:83"]
+    );
+  }
+
+  #[test]
+  fn effects_purity() {
+    let src = "var g = 1; const K = 2; extern ext(x); struct P(x);
+      def sq(x) x * x; def hyp(a, b) sqrt(sq(a) + sq(b)); def loud(x) printd(x);
+      def quiet(x) loud(x) + 1; def reads(x) x + g; def konst(x) x + K; def writes(x) g = x;
+      def count(n) var i = 0 in { i = i + n; return i }; def shadow(g) g + 1;
+      def hidden(x) let g = g in g; def even(n) if n == 0 then true else odd(n - 1);
+      def odd(n) if n == 0 then false else even(n - 1); def io(x) ext(x);
+      def apply(f, x) f(x); def lam(x) \\(y) printd(y); def mk(x) P(x); def later(x) last(x);
+      def last(x) x";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut effects = effects(&module);
+    let pure = |effects: &Effects| -> Vec<String> {
+      let names = module.items.iter().filter_map(|item| match item {
+        Ast::Func(func) => Some(func.proto.name.clone()),
+        _ => None,
+      });
+      names.filter(|name| effects.is_pure_func(name)).collect()
+    };
+    assert_eq!(
+      pure(&effects),
+      vec!["sq", "hyp", "konst", "count", "shadow", "even", "odd", "lam", "mk", "later", "last"]
+    );
+    assert!(effects.is_pure_call("hyp") && !effects.is_pure_call("quiet"));
+    assert!(effects.is_pure_call("sqrt") && !effects.is_pure_call("ext"));
+
+    let src = "def last(x) printd(x)";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    effects.declare(&module.items[0]);
+    assert!(!effects.is_pure_func("last") && !effects.is_pure_func("later"));
+  }
+}
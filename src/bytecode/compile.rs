@@ -0,0 +1,425 @@
+use super::{encode, Function, Kind, Op, Program};
+use crate::codegen::backend::{Backend, Declaration};
+use crate::diagnostic::Diagnostic;
+use crate::ir::{self, BinaryOp, Block, CmpOp, Inst, IrBackend, Target, Terminator, Type, UnaryOp};
+use crate::lexer::Span;
+use crate::parser::{FuncAst, ProtoAst};
+use crate::session::Entry;
+use crate::value::Precision;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Compiles the IR `module`, which is verified, to bytecode. Each value
+/// gets locals of its own, one for a number, an int or a boolean and one
+/// for each element of a tuple, and each instruction loads its operands and
+/// stores its result.
+pub fn compile(module: &ir::Module) -> Program {
+  let mut compiler = Compiler {
+    module,
+    constants: vec![],
+    constant_indices: HashMap::new(),
+    ints: vec![],
+    int_indices: HashMap::new(),
+  };
+  let functions = module
+    .functions
+    .iter()
+    .map(|func| compiler.compile_func(func))
+    .collect();
+  let start = module
+    .start
+    .iter()
+    .map(|name| {
+      module
+        .functions
+        .iter()
+        .position(|f| f.name == *name)
+        .unwrap() as u32
+    })
+    .collect();
+  Program {
+    constants: compiler.constants,
+    ints: compiler.ints,
+    externs: module.externs.clone(),
+    functions,
+    start,
+  }
+}
+
+/// BytecodeBackend - compiles programs to IR, then to bytecode, which is
+/// encoded to `output`.
+pub struct BytecodeBackend {
+  ir: IrBackend,
+}
+
+impl BytecodeBackend {
+  pub fn new() -> Self {
+    Self {
+      ir: IrBackend::new(),
+    }
+  }
+
+  /// Makes the backend optimize the IR it compiles, as
+  /// [`IrBackend::set_optimize`] does.
+  pub fn set_optimize(&mut self, precision: Precision) {
+    self.ir.set_optimize(precision);
+  }
+}
+
+impl Backend for BytecodeBackend {
+  fn name(&self) -> &'static str {
+    "bytecode"
+  }
+
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+    self.ir.declare_proto(proto, decl)
+  }
+
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+    self.ir.define_function(func)
+  }
+
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>> {
+    let module = self.ir.finish(entry)?;
+    std::fs::write(output, encode(&compile(&module))).map_err(|e| {
+      let msg = format!("Cannot write `{}`: {}", output.display(), e);
+      vec![Diagnostic::error(Span::default(), msg).with_code("codegen")]
+    })
+  }
+}
+
+struct Compiler<'a> {
+  module: &'a ir::Module,
+  constants: Vec<f64>,
+  constant_indices: HashMap<u64, u32>, // by the bits of each constant
+  ints: Vec<i64>,
+  int_indices: HashMap<i64, u32>,
+}
+
+impl Compiler<'_> {
+  fn constant(&mut self, n: f64) -> u32 {
+    let constants = &mut self.constants;
+    *self.constant_indices.entry(n.to_bits()).or_insert_with(|| {
+      constants.push(n);
+      constants.len() as u32 - 1
+    })
+  }
+
+  fn int(&mut self, i: i64) -> u32 {
+    let ints = &mut self.ints;
+    *self.int_indices.entry(i).or_insert_with(|| {
+      ints.push(i);
+      ints.len() as u32 - 1
+    })
+  }
+
+  fn compile_func(&mut self, func: &ir::Function) -> Function {
+    // the parameters come first, then the other values in order
+    let mut locals = vec![0; func.types.len()];
+    let mut next = 0;
+    let params = func.params();
+    let others = (0..func.types.len() as u32).map(ir::Value);
+    for value in params
+      .iter()
+      .copied()
+      .chain(others.filter(|v| !params.contains(v)))
+    {
+      locals[value.0 as usize] = next;
+      next += width(func.ty(value));
+    }
+    let mut code = FuncCode {
+      func,
+      locals,
+      code: vec![],
+      starts: vec![0; func.blocks.len()],
+      fixups: vec![],
+    };
+    for (i, data) in func.blocks.iter().enumerate() {
+      code.starts[i] = code.code.len() as u32;
+      for (value, inst) in &data.insts {
+        self.compile_inst(&mut code, inst);
+        code.store(*value);
+      }
+      let next = Block(i as u32 + 1);
+      match data.term.as_ref().expect("the IR is verified") {
+        Terminator::Jump(target) => code.goto(target, next),
+        Terminator::Branch(cond, then, els) if els.args.is_empty() => {
+          code.load(*cond);
+          code.jump(Op::JumpIfNot, els.block);
+          code.goto(then, next);
+        }
+        // the arguments of `els` are passed after the jump, which skips the
+        // code that passes those of `then`
+        Terminator::Branch(cond, then, els) => {
+          code.load(*cond);
+          let branch = code.code.len();
+          code.code.push(Op::JumpIfNot(0));
+          code.goto(then, Block(u32::MAX));
+          code.code[branch] = Op::JumpIfNot(code.code.len() as u32);
+          code.goto(els, next);
+        }
+        Terminator::Return(value) => {
+          code.load(*value);
+          code.code.push(Op::Ret);
+        }
+      }
+    }
+    let FuncCode {
+      mut code,
+      starts,
+      fixups,
+      ..
+    } = code;
+    for (at, block) in fixups {
+      let start = starts[block.0 as usize];
+      code[at] = match code[at] {
+        Op::JumpIfNot(_) => Op::JumpIfNot(start),
+        _ => Op::Jump(start),
+      };
+    }
+    Function {
+      name: func.name.clone(),
+      params: func
+        .params()
+        .iter()
+        .flat_map(|&v| kinds(func.ty(v)))
+        .collect(),
+      returns: kinds(&func.ret),
+      locals: next,
+      code,
+    }
+  }
+
+  fn compile_inst(&mut self, code: &mut FuncCode, inst: &Inst) {
+    if let Inst::Elem(tuple, i) = inst {
+      let first = code.locals[tuple.0 as usize];
+      return code.code.push(Op::Load(first + *i as u32));
+    }
+    for arg in inst.args() {
+      code.load(arg);
+    }
+    let int = |x: &ir::Value| *code.func.ty(*x) == Type::Int;
+    let op = match inst {
+      Inst::Num(n) => Op::Const(self.constant(*n)),
+      Inst::Int(i) => Op::Int(self.int(*i)),
+      Inst::Bool(b) => Op::Const(self.constant(*b as i32 as f64)),
+      Inst::Unary(UnaryOp::Neg, x) if int(x) => Op::INeg,
+      Inst::Unary(UnaryOp::Neg, _) => Op::Neg,
+      Inst::Unary(UnaryOp::ToInt, _) => Op::ToInt,
+      Inst::Unary(UnaryOp::ToNum, _) => Op::ToNum,
+      Inst::Binary(op, x, _) if int(x) => match op {
+        BinaryOp::Add => Op::IAdd,
+        BinaryOp::Sub => Op::ISub,
+        BinaryOp::Mul => Op::IMul,
+        BinaryOp::Div => Op::IDiv,
+        BinaryOp::Rem => Op::IRem,
+        BinaryOp::BitAnd => Op::BitAnd,
+        BinaryOp::BitOr => Op::BitOr,
+        BinaryOp::Xor => Op::Xor,
+        BinaryOp::Shl => Op::Shl,
+        BinaryOp::Shr => Op::Shr,
+      },
+      Inst::Binary(op, ..) => match op {
+        BinaryOp::Add => Op::Add,
+        BinaryOp::Sub => Op::Sub,
+        BinaryOp::Mul => Op::Mul,
+        BinaryOp::Div => Op::Div,
+        BinaryOp::Rem => Op::Rem,
+        _ => unreachable!("the IR is verified"),
+      },
+      Inst::Cmp(op, x, _) if int(x) => match op {
+        CmpOp::Lt => Op::ILt,
+        CmpOp::Gt => Op::IGt,
+        CmpOp::Le => Op::ILe,
+        CmpOp::Ge => Op::IGe,
+        CmpOp::Eq => Op::IEq,
+        CmpOp::Ne => Op::INe,
+      },
+      Inst::Cmp(op, ..) => match op {
+        CmpOp::Lt => Op::Lt,
+        CmpOp::Gt => Op::Gt,
+        CmpOp::Le => Op::Le,
+        CmpOp::Ge => Op::Ge,
+        CmpOp::Eq => Op::Eq,
+        CmpOp::Ne => Op::Ne,
+      },
+      Inst::Not(_) => Op::Not,
+      // booleans are numbers already, and tuples the numbers loaded
+      Inst::FromBool(_) | Inst::Tuple(_) => return,
+      Inst::Call(callee, _) => match self.module.functions.iter().position(|f| f.name == *callee) {
+        Some(i) => Op::Call(i as u32),
+        None => {
+          let i = self.module.externs.iter().position(|e| e.name == *callee);
+          Op::CallExtern(i.expect("the IR is verified") as u32)
+        }
+      },
+      Inst::Elem(..) => unreachable!(),
+    };
+    code.code.push(op);
+  }
+}
+
+/// The locals or stack slots a value of type `ty` takes.
+fn width(ty: &Type) -> u32 {
+  kinds(ty).len() as u32
+}
+
+/// What each of the locals or stack slots of a value of type `ty` holds.
+fn kinds(ty: &Type) -> Vec<Kind> {
+  match ty {
+    Type::Num | Type::Bool => vec![Kind::Num],
+    Type::Int => vec![Kind::Int],
+    Type::Tuple(elems) => elems.iter().flat_map(kinds).collect(),
+  }
+}
+
+/// FuncCode - the code of a function as it is compiled, with the jumps to
+/// the blocks that don't start yet, which are fixed at the end.
+struct FuncCode<'a> {
+  func: &'a ir::Function,
+  locals: Vec<u32>, // the first of each value
+  code: Vec<Op>,
+  starts: Vec<u32>,            // of each block
+  fixups: Vec<(usize, Block)>, // the jumps to blocks
+}
+
+impl FuncCode<'_> {
+  fn load(&mut self, value: ir::Value) {
+    let first = self.locals[value.0 as usize];
+    for i in 0..width(self.func.ty(value)) {
+      self.code.push(Op::Load(first + i));
+    }
+  }
+
+  /// Stores `value` from the top of the stack, its last element first.
+  fn store(&mut self, value: ir::Value) {
+    let first = self.locals[value.0 as usize];
+    for i in (0..width(self.func.ty(value))).rev() {
+      self.code.push(Op::Store(first + i));
+    }
+  }
+
+  /// Passes the arguments of `target` to the parameters of its block, all
+  /// loaded before any is stored, and jumps there unless it is `next`.
+  fn goto(&mut self, target: &Target, next: Block) {
+    let params = &self.func.block(target.block).params;
+    for &arg in &target.args {
+      self.load(arg);
+    }
+    for &param in params.iter().rev() {
+      self.store(param);
+    }
+    if target.block != next {
+      self.jump(Op::Jump, target.block);
+    }
+  }
+
+  fn jump(&mut self, op: fn(u32) -> Op, block: Block) {
+    self.fixups.push((self.code.len(), block));
+    self.code.push(op(0));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bytecode::decode;
+  use crate::ir::lower;
+  use crate::lexer::Lexer;
+  use crate::parser::ModuleAst;
+  use std::io::Cursor;
+
+  #[test]
+  fn bytecode_compile() {
+    let src = "extern sin(x); def f(x) if x < 1 then (x, 2) else (sin(x), 2);
+      def g(x) let (a, b) = f(x) in a + b; g(0.5);";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let module = lower(&module, Entry::TopLevel).unwrap();
+    let program = compile(&module);
+    assert_eq!(
+      program.to_string(),
+      "const 0 = 1.0
+const 1 = 0.5
+int 0 = 2
+extern 0 = sin/1
+
+fn 0 = f(num) -> (num, num), 17 locals
+     0  const 0
+     1  store 1
+     2  load 0
+     3  load 1
+     4  lt
+     5  store 2
+     6  load 2
+     7  jumpifnot 19
+     8  int 0
+     9  store 3
+    10  load 0
+    11  load 3
+    12  store 5
+    13  store 4
+    14  load 4
+    15  load 5
+    16  store 11
+    17  store 10
+    18  jump 32
+    19  load 0
+    20  callextern 0
+    21  store 6
+    22  int 0
+    23  store 7
+    24  load 6
+    25  load 7
+    26  store 9
+    27  store 8
+    28  load 8
+    29  load 9
+    30  store 11
+    31  store 10
+    32  load 10
+    33  store 12
+    34  load 11
+    35  store 13
+    36  load 13
+    37  tonum
+    38  store 14
+    39  load 12
+    40  load 14
+    41  store 16
+    42  store 15
+    43  load 15
+    44  load 16
+    45  ret
+
+fn 1 = g(num) -> num, 6 locals
+     0  load 0
+     1  call 0
+     2  store 2
+     3  store 1
+     4  load 1
+     5  store 3
+     6  load 2
+     7  store 4
+     8  load 3
+     9  load 4
+    10  add
+    11  store 5
+    12  load 5
+    13  ret
+
+fn 2 = __anon_expr() -> num, 2 locals
+     0  const 1
+     1  store 0
+     2  load 0
+     3  call 1
+     4  store 1
+     5  load 1
+     6  ret
+
+start 2
+"
+    );
+    let bytes = encode(&program);
+    assert_eq!(decode(&bytes).unwrap(), program);
+  }
+}
@@ -0,0 +1,381 @@
+use super::{Function, Kind, Op, Program};
+use crate::diagnostic::Diagnostic;
+use crate::ir::Extern;
+use crate::lexer::Span;
+
+/// The bytes that begin bytecode, before its version.
+pub const MAGIC: [u8; 4] = *b"\0kbc";
+
+/// The version of the format [`encode`] writes, the only one [`decode`]
+/// reads. It changes whenever the format does.
+pub const VERSION: u16 = 2;
+
+/// Writes `program` as bytes: [`MAGIC`] and [`VERSION`], in little
+/// endian, then the constants and ints pools, the externs, the functions
+/// and the functions that start the program. Counts, lengths and indices
+/// are unsigned LEB128s, numbers are `f64`s and ints `i64`s in little
+/// endian, names are UTF-8 after their length, and the kinds of the
+/// parameters and results of functions are a byte each after their count.
+pub fn encode(program: &Program) -> Vec<u8> {
+  let mut out = MAGIC.to_vec();
+  out.extend(VERSION.to_le_bytes());
+  uleb(&mut out, program.constants.len() as u32);
+  for n in &program.constants {
+    out.extend(n.to_le_bytes());
+  }
+  uleb(&mut out, program.ints.len() as u32);
+  for i in &program.ints {
+    out.extend(i.to_le_bytes());
+  }
+  uleb(&mut out, program.externs.len() as u32);
+  for ext in &program.externs {
+    name(&mut out, &ext.name);
+    name(&mut out, &ext.symbol);
+    uleb(&mut out, ext.params as u32);
+  }
+  uleb(&mut out, program.functions.len() as u32);
+  for func in &program.functions {
+    name(&mut out, &func.name);
+    kinds(&mut out, &func.params);
+    kinds(&mut out, &func.returns);
+    uleb(&mut out, func.locals);
+    uleb(&mut out, func.code.len() as u32);
+    for op in &func.code {
+      let (opcode, operand) = opcode(op);
+      out.push(opcode);
+      if let Some(operand) = operand {
+        uleb(&mut out, operand);
+      }
+    }
+  }
+  uleb(&mut out, program.start.len() as u32);
+  for &index in &program.start {
+    uleb(&mut out, index);
+  }
+  out
+}
+
+/// Reads the program that [`encode`] wrote as `bytes`, checking that its
+/// version is [`VERSION`] and that its instructions refer to constants,
+/// externs, functions, locals and instructions that exist.
+pub fn decode(bytes: &[u8]) -> Result<Program, Diagnostic> {
+  let mut reader = Reader { bytes, at: 0 };
+  if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+    return Err(error("Not Kale bytecode".to_string()));
+  }
+  let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+  if version != VERSION {
+    let msg = format!(
+      "Bytecode of version {} isn't supported, only of version {}",
+      version, VERSION
+    );
+    return Err(error(msg));
+  }
+  let mut constants = vec![];
+  for _ in 0..reader.uleb()? {
+    constants.push(f64::from_le_bytes(reader.take(8)?.try_into().unwrap()));
+  }
+  let mut ints = vec![];
+  for _ in 0..reader.uleb()? {
+    ints.push(i64::from_le_bytes(reader.take(8)?.try_into().unwrap()));
+  }
+  let mut externs = vec![];
+  for _ in 0..reader.uleb()? {
+    externs.push(Extern {
+      name: reader.name()?,
+      symbol: reader.name()?,
+      params: reader.uleb()? as usize,
+    });
+  }
+  let mut functions = vec![];
+  for _ in 0..reader.uleb()? {
+    let name = reader.name()?;
+    let (params, returns, locals) = (reader.kinds()?, reader.kinds()?, reader.uleb()?);
+    let mut code = vec![];
+    for _ in 0..reader.uleb()? {
+      code.push(reader.op()?);
+    }
+    functions.push(Function {
+      name,
+      params,
+      returns,
+      locals,
+      code,
+    });
+  }
+  let mut start = vec![];
+  for _ in 0..reader.uleb()? {
+    start.push(reader.uleb()?);
+  }
+  if reader.at != bytes.len() {
+    return Err(error(format!("Bytes after the end at {}", reader.at)));
+  }
+  let program = Program {
+    constants,
+    ints,
+    externs,
+    functions,
+    start,
+  };
+  check(&program)?;
+  Ok(program)
+}
+
+fn error(msg: String) -> Diagnostic {
+  Diagnostic::error(Span::default(), msg).with_code("bytecode")
+}
+
+/// Checks that the operands of the instructions of `program` are in range,
+/// as [`decode`] does.
+pub fn check(program: &Program) -> Result<(), Diagnostic> {
+  let count = |n: usize| n as u32;
+  for func in &program.functions {
+    if func.params.len() as u32 > func.locals {
+      let msg = format!(
+        "`{}` takes {} arguments, but has {} locals",
+        func.name,
+        func.params.len(),
+        func.locals
+      );
+      return Err(error(msg));
+    }
+    for (at, op) in func.code.iter().enumerate() {
+      let (what, limit) = match *op {
+        Op::Const(i) => (i, count(program.constants.len())),
+        Op::Int(i) => (i, count(program.ints.len())),
+        Op::Load(i) | Op::Store(i) => (i, func.locals),
+        Op::Call(i) => (i, count(program.functions.len())),
+        Op::CallExtern(i) => (i, count(program.externs.len())),
+        Op::Jump(i) | Op::JumpIfNot(i) => (i, count(func.code.len())),
+        _ => continue,
+      };
+      if what >= limit {
+        let msg = format!("In `{}`, {}: `{}` is out of range", func.name, at, op);
+        return Err(error(msg));
+      }
+    }
+  }
+  match program
+    .start
+    .iter()
+    .find(|&&i| i as usize >= program.functions.len())
+  {
+    Some(i) => Err(error(format!(
+      "`start` names function {}, which doesn't exist",
+      i
+    ))),
+    None => Ok(()),
+  }
+}
+
+/// The opcode of `op`, and its operand if any, which follows it as an
+/// unsigned LEB128.
+fn opcode(op: &Op) -> (u8, Option<u32>) {
+  match *op {
+    Op::Const(i) => (0x01, Some(i)),
+    Op::Load(i) => (0x02, Some(i)),
+    Op::Store(i) => (0x03, Some(i)),
+    Op::Int(i) => (0x04, Some(i)),
+    Op::Neg => (0x10, None),
+    Op::Add => (0x12, None),
+    Op::Sub => (0x13, None),
+    Op::Mul => (0x14, None),
+    Op::Div => (0x15, None),
+    Op::Rem => (0x16, None),
+    Op::Lt => (0x20, None),
+    Op::Gt => (0x21, None),
+    Op::Le => (0x22, None),
+    Op::Ge => (0x23, None),
+    Op::Eq => (0x24, None),
+    Op::Ne => (0x25, None),
+    Op::Not => (0x26, None),
+    Op::INeg => (0x50, None),
+    Op::IAdd => (0x52, None),
+    Op::ISub => (0x53, None),
+    Op::IMul => (0x54, None),
+    Op::IDiv => (0x55, None),
+    Op::IRem => (0x56, None),
+    Op::BitAnd => (0x57, None),
+    Op::BitOr => (0x58, None),
+    Op::Xor => (0x59, None),
+    Op::Shl => (0x5a, None),
+    Op::Shr => (0x5b, None),
+    Op::ILt => (0x60, None),
+    Op::IGt => (0x61, None),
+    Op::ILe => (0x62, None),
+    Op::IGe => (0x63, None),
+    Op::IEq => (0x64, None),
+    Op::INe => (0x65, None),
+    Op::ToInt => (0x70, None),
+    Op::ToNum => (0x71, None),
+    Op::Call(i) => (0x30, Some(i)),
+    Op::CallExtern(i) => (0x31, Some(i)),
+    Op::Jump(i) => (0x40, Some(i)),
+    Op::JumpIfNot(i) => (0x41, Some(i)),
+    Op::Ret => (0x42, None),
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  at: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, n: usize) -> Result<&'a [u8], Diagnostic> {
+    match self.bytes.get(self.at..self.at + n) {
+      Some(bytes) => {
+        self.at += n;
+        Ok(bytes)
+      }
+      None => Err(error("Bytecode ends too soon".to_string())),
+    }
+  }
+
+  fn uleb(&mut self) -> Result<u32, Diagnostic> {
+    let mut n = 0u64;
+    for shift in (0..35).step_by(7) {
+      let byte = self.take(1)?[0];
+      n |= ((byte & 0x7f) as u64) << shift;
+      if byte & 0x80 == 0 {
+        return u32::try_from(n).map_err(|_| error(format!("{} is too large", n)));
+      }
+    }
+    Err(error(format!("Invalid LEB128 before {}", self.at)))
+  }
+
+  fn name(&mut self) -> Result<String, Diagnostic> {
+    let len = self.uleb()? as usize;
+    let at = self.at;
+    let bytes = self.take(len)?.to_vec();
+    String::from_utf8(bytes).map_err(|_| error(format!("Invalid UTF-8 at {}", at)))
+  }
+
+  fn op(&mut self) -> Result<Op, Diagnostic> {
+    let at = self.at;
+    Ok(match self.take(1)?[0] {
+      0x01 => Op::Const(self.uleb()?),
+      0x02 => Op::Load(self.uleb()?),
+      0x03 => Op::Store(self.uleb()?),
+      0x04 => Op::Int(self.uleb()?),
+      0x10 => Op::Neg,
+      0x12 => Op::Add,
+      0x13 => Op::Sub,
+      0x14 => Op::Mul,
+      0x15 => Op::Div,
+      0x16 => Op::Rem,
+      0x20 => Op::Lt,
+      0x21 => Op::Gt,
+      0x22 => Op::Le,
+      0x23 => Op::Ge,
+      0x24 => Op::Eq,
+      0x25 => Op::Ne,
+      0x26 => Op::Not,
+      0x50 => Op::INeg,
+      0x52 => Op::IAdd,
+      0x53 => Op::ISub,
+      0x54 => Op::IMul,
+      0x55 => Op::IDiv,
+      0x56 => Op::IRem,
+      0x57 => Op::BitAnd,
+      0x58 => Op::BitOr,
+      0x59 => Op::Xor,
+      0x5a => Op::Shl,
+      0x5b => Op::Shr,
+      0x60 => Op::ILt,
+      0x61 => Op::IGt,
+      0x62 => Op::ILe,
+      0x63 => Op::IGe,
+      0x64 => Op::IEq,
+      0x65 => Op::INe,
+      0x70 => Op::ToInt,
+      0x71 => Op::ToNum,
+      0x30 => Op::Call(self.uleb()?),
+      0x31 => Op::CallExtern(self.uleb()?),
+      0x40 => Op::Jump(self.uleb()?),
+      0x41 => Op::JumpIfNot(self.uleb()?),
+      0x42 => Op::Ret,
+      opcode => return Err(error(format!("Unknown opcode 0x{:02x} at {}", opcode, at))),
+    })
+  }
+
+  fn kinds(&mut self) -> Result<Vec<Kind>, Diagnostic> {
+    let mut kinds = vec![];
+    for _ in 0..self.uleb()? {
+      let at = self.at;
+      kinds.push(match self.take(1)?[0] {
+        0 => Kind::Num,
+        1 => Kind::Int,
+        kind => return Err(error(format!("Unknown kind 0x{:02x} at {}", kind, at))),
+      });
+    }
+    Ok(kinds)
+  }
+}
+
+fn kinds(out: &mut Vec<u8>, kinds: &[Kind]) {
+  uleb(out, kinds.len() as u32);
+  out.extend(kinds.iter().map(|kind| *kind as u8));
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+  uleb(out, name.len() as u32);
+  out.extend(name.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut n: u32) {
+  loop {
+    let byte = (n & 0x7f) as u8;
+    n >>= 7;
+    if n == 0 {
+      return out.push(byte);
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bytecode_decode_errors() {
+    let program = Program {
+      constants: vec![0.5],
+      ints: vec![],
+      externs: vec![],
+      functions: vec![Function {
+        name: "f".to_string(),
+        params: vec![Kind::Num],
+        returns: vec![Kind::Num],
+        locals: 1,
+        code: vec![Op::Load(0), Op::Const(0), Op::Add, Op::Ret],
+      }],
+      start: vec![0],
+    };
+    let bytes = encode(&program);
+    assert_eq!(bytes.len(), 34);
+    assert_eq!(decode(&bytes).unwrap(), program);
+    let message = |bytes: &[u8]| decode(bytes).unwrap_err().message;
+    assert_eq!(message(b"kale"), "Not Kale bytecode");
+    let mut newer = bytes.clone();
+    newer[4] = 3;
+    assert_eq!(
+      message(&newer),
+      "Bytecode of version 3 isn't supported, only of version 2"
+    );
+    assert_eq!(message(&bytes[..20]), "Bytecode ends too soon");
+    let mut unknown = bytes.clone();
+    unknown[26] = 0xff;
+    assert_eq!(message(&unknown), "Unknown opcode 0xff at 26");
+    let mut kind = bytes.clone();
+    kind[21] = 7;
+    assert_eq!(message(&kind), "Unknown kind 0x07 at 21");
+    let mut out_of_range = bytes.clone();
+    out_of_range[27] = 1;
+    assert_eq!(
+      message(&out_of_range),
+      "In `f`, 0: `load 1` is out of range"
+    );
+  }
+}
@@ -0,0 +1,163 @@
+mod compile;
+mod encode;
+
+pub use compile::{compile, BytecodeBackend};
+pub use encode::{check, decode, encode, MAGIC, VERSION};
+
+use crate::ir::Extern;
+use std::fmt;
+
+/// Op - an instruction of the stack machine, which takes its operands from
+/// the top of the stack and pushes its results. Numbers are `f64`s, ints
+/// `i64`s, which the ops prefixed with `I` and the bitwise ones take, and
+/// booleans are `1.0` if true, else `0.0`. The arithmetic of ints fails as
+/// in the interpreter. Jumps go to the index of an instruction of the
+/// function.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Op {
+  Const(u32), // pushes a number of the constants pool
+  Int(u32),   // pushes an int of the ints pool
+  Load(u32),  // pushes a local
+  Store(u32), // pops a local
+  Neg,
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Rem,
+  Lt,
+  Gt,
+  Le,
+  Ge,
+  Eq,
+  Ne,
+  Not,
+  INeg,
+  IAdd,
+  ISub,
+  IMul,
+  IDiv,
+  IRem,
+  BitAnd,
+  BitOr,
+  Xor,
+  Shl,
+  Shr,
+  ILt,
+  IGt,
+  ILe,
+  IGe,
+  IEq,
+  INe,
+  ToInt, // rounds a number towards zero
+  ToNum,
+  Call(u32),       // a function, popping its arguments, the last on top
+  CallExtern(u32), // an extern, likewise
+  Jump(u32),
+  JumpIfNot(u32), // pops a boolean, and jumps if false
+  Ret,            // pops what the function returns, then returns it
+}
+
+/// Kind - what a parameter or a result of a function is.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Kind {
+  Num,
+  Int,
+}
+
+/// Function - a function of the program, whose arguments, of the kinds of
+/// `params`, are its first locals. It returns a value of each of the kinds
+/// of `returns`, the elements of a tuple if more than one, which its
+/// callers find on the stack in order.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+  pub name: String,
+  pub params: Vec<Kind>,
+  pub returns: Vec<Kind>,
+  pub locals: u32, // with the parameters
+  pub code: Vec<Op>,
+}
+
+/// Program - a program compiled to bytecode, which [`encode`] writes as
+/// the bytes of `.kbc` files and [`decode`] reads back. Instructions refer
+/// to numbers of `constants`, ints of `ints`, externs and functions by
+/// their index, and the program runs the functions of `start` in order.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+  pub constants: Vec<f64>,
+  pub ints: Vec<i64>,
+  pub externs: Vec<Extern>,
+  pub functions: Vec<Function>,
+  pub start: Vec<u32>,
+}
+
+impl Program {
+  pub fn function(&self, name: &str) -> Option<u32> {
+    let index = self.functions.iter().position(|func| func.name == name);
+    index.map(|index| index as u32)
+  }
+}
+
+impl fmt::Display for Op {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = format!("{:?}", self).to_lowercase();
+    match self {
+      Op::Const(i)
+      | Op::Int(i)
+      | Op::Load(i)
+      | Op::Store(i)
+      | Op::Call(i)
+      | Op::CallExtern(i)
+      | Op::Jump(i)
+      | Op::JumpIfNot(i) => write!(f, "{} {}", &name[..name.find('(').unwrap()], i),
+      _ => write!(f, "{}", name),
+    }
+  }
+}
+
+impl fmt::Display for Kind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Kind::Num => write!(f, "num"),
+      Kind::Int => write!(f, "int"),
+    }
+  }
+}
+
+impl fmt::Display for Program {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, n) in self.constants.iter().enumerate() {
+      writeln!(f, "const {} = {:?}", i, n)?;
+    }
+    for (i, n) in self.ints.iter().enumerate() {
+      writeln!(f, "int {} = {}", i, n)?;
+    }
+    for (i, ext) in self.externs.iter().enumerate() {
+      writeln!(f, "extern {} = {}/{}", i, ext.symbol, ext.params)?;
+    }
+    for (i, func) in self.functions.iter().enumerate() {
+      let kinds = |kinds: &[Kind]| {
+        let kinds: Vec<_> = kinds.iter().map(Kind::to_string).collect();
+        kinds.join(", ")
+      };
+      let returns = match &func.returns[..] {
+        [kind] => kind.to_string(),
+        returns => format!("({})", kinds(returns)),
+      };
+      writeln!(
+        f,
+        "\nfn {} = {}({}) -> {}, {} locals",
+        i,
+        func.name,
+        kinds(&func.params),
+        returns,
+        func.locals
+      )?;
+      for (at, op) in func.code.iter().enumerate() {
+        writeln!(f, "  {:4}  {}", at, op)?;
+      }
+    }
+    let start: Vec<_> = self.start.iter().map(|i| i.to_string()).collect();
+    writeln!(f, "\nstart {}", start.join(", "))
+  }
+}
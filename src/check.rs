@@ -0,0 +1,109 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Lexer;
+use crate::lint::Linter;
+use crate::loader::Loader;
+use crate::parser::ModuleAst;
+use crate::prelude::prelude;
+use crate::resolve::{Resolver, SymbolTable};
+use crate::session::Entry;
+use crate::typeck::TypeChecker;
+use std::io::Cursor;
+
+/// CheckedModule - a module that parsed, resolved and type-checked, with
+/// its imports merged in and its types annotated, the warnings of the lints
+/// about it, and the symbols it defines.
+#[derive(Debug)]
+pub struct CheckedModule {
+  pub module: ModuleAst,
+  pub warnings: Vec<Diagnostic>,
+  pub symbols: SymbolTable,
+}
+
+/// Validates the program `src` without running any of it, the way a file
+/// is checked before it runs: it is parsed, its imports are loaded relative
+/// to the working directory, its names are resolved and the arities of its
+/// calls checked, then its items are type-checked against the prelude.
+/// Each phase reports all of its errors, but only runs if the previous one
+/// found none: parsing stops at the first syntax error.
+pub fn check(src: &str) -> Result<CheckedModule, Vec<Diagnostic>> {
+  let mut lexer = Lexer::new(Cursor::new(src.to_string()));
+  let module = ModuleAst::parse(&mut lexer).map_err(|e| vec![e])?;
+  let mut module = Loader::new().expand(module, None).map_err(|e| vec![e])?;
+
+  let mut prelude = prelude();
+  let mut resolver = Resolver::new();
+  prelude.items.iter().for_each(|item| resolver.declare(item));
+  let errors = resolver.resolve_module(&module);
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+  Entry::of(&module).map_err(|e| vec![e])?; // validates the entry point
+
+  let mut checker = TypeChecker::new();
+  checker
+    .check_module(&mut prelude)
+    .expect("The prelude is well-formed");
+  checker.declare(&module);
+  let errors: Vec<_> = module
+    .items
+    .iter_mut()
+    .filter_map(|item| checker.check(item).err())
+    .collect();
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+
+  let linter = Linter::new();
+  let mut lints = linter.lint_module(&module);
+  lints.extend(linter.lint_program(&module));
+  let warnings = lints.iter().map(|lint| linter.diagnostic(lint)).collect();
+  let symbols = resolver.into_symbols();
+  Ok(CheckedModule {
+    module,
+    warnings,
+    symbols,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn errors(src: &str) -> Vec<String> {
+    let errors = check(src).unwrap_err();
+    errors.iter().map(Diagnostic::to_string).collect()
+  }
+
+  #[test]
+  fn check_phases() {
+    let checked = check("def f(x: int, y) x * 2; def main() printd(f(1, 2))").unwrap();
+    assert_eq!(checked.module.items.len(), 2);
+    let warnings: Vec<_> = checked.warnings.iter().map(Diagnostic::to_string).collect();
+    assert_eq!(warnings, vec!["1:15: Unused parameter `y` of `f`"]);
+    let f = checked.symbols.lookup("f")[0].id;
+    assert_eq!(checked.symbols.references(f).len(), 1);
+
+    assert_eq!(
+      errors("def f(x) x +; g()"),
+      vec!["1:13: Expected an expression, found Semi"]
+    );
+    assert_eq!(errors("\"abc"), vec!["1:1: Unterminated string literal"]);
+    assert_eq!(
+      errors("def f(x) x; f(1, 2); g(h)"),
+      vec![
+        "1:13: Function `f` declared at 1:5 expects 1 argument, found 2",
+        "1:22: Unknown function `g`",
+        "1:24: Unknown variable `h`",
+      ]
+    );
+    assert_eq!(
+      errors("def f(x: int) x; f(\"a\"); sqrt(true)"),
+      vec![
+        "1:18: Argument 1 of `f` expects int, found str",
+        "1:26: Argument 1 of `sqrt` expects double, found bool",
+      ]
+    );
+    let errors = check("def main() 0; 1").unwrap_err();
+    assert_eq!(errors[0].code, Some("entry"));
+  }
+}
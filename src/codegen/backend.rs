@@ -0,0 +1,281 @@
+use super::{c, js, rust, tuple_arities, unsupported_item, wasm};
+use crate::bytecode::BytecodeBackend;
+use crate::diagnostic::Diagnostic;
+use crate::ir;
+use crate::lexer::Span;
+use crate::parser::{Ast, FuncAst, ModuleAst, ProtoAst};
+use crate::session::Entry;
+use crate::value::Precision;
+use std::collections::VecDeque;
+use std::path::Path;
+
+/// Backend - compiles a checked program, item by item, to the output of a
+/// target: source, a WebAssembly module or an executable. [`define_module`]
+/// declares every function and extern of the program before it defines
+/// any, so that bodies may call the functions defined further down.
+pub trait Backend {
+  /// The name of the backend in its errors, such as `C`.
+  fn name(&self) -> &'static str;
+
+  /// Declares the extern or the function whose prototype is `proto`.
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic>;
+
+  /// Defines the function `func`, declared before, or the top-level
+  /// expression it wraps, which has no name and isn't.
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic>;
+
+  /// Writes what the functions defined compile to to `output`, for the
+  /// program to start at `entry`.
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>>;
+}
+
+/// Declaration - what a prototype declares.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Declaration {
+  Extern,
+  Function { tuple: Option<usize> }, // the arity of the tuple it returns
+}
+
+/// Declared - the functions a backend declared, as the `C`s it calls them
+/// by, to define in the order they were, and all those defined by their
+/// names in the program, the top-level expressions too, which have none.
+pub struct Declared<C> {
+  pending: VecDeque<C>,
+  funcs: Vec<(String, C)>,
+}
+
+impl<C: Clone> Declared<C> {
+  pub fn new() -> Self {
+    Self {
+      pending: VecDeque::new(),
+      funcs: vec![],
+    }
+  }
+
+  /// Records that the function `name` is declared as `callee`.
+  pub fn push(&mut self, name: &str, callee: C) {
+    self.funcs.push((name.to_string(), callee.clone()));
+    self.pending.push_back(callee);
+  }
+
+  /// The callee to define `func` as: the next one declared, unless `func`
+  /// wraps a top-level expression, which `declare` declares after those
+  /// so far.
+  pub fn define(&mut self, func: &FuncAst, declare: impl FnOnce(&[(String, C)]) -> C) -> C {
+    match func.proto.name.as_str() {
+      "" => {
+        let callee = declare(&self.funcs);
+        self.funcs.push((String::new(), callee.clone()));
+        callee
+      }
+      _ => self
+        .pending
+        .pop_front()
+        .expect("functions are declared first"),
+    }
+  }
+
+  /// The functions declared, by their names in the program.
+  pub fn funcs(&self) -> &[(String, C)] {
+    &self.funcs
+  }
+
+  /// The callees of the functions a program starting at `entry` calls.
+  pub fn entry(&self, entry: Entry) -> impl Iterator<Item = &C> + '_ {
+    let called = self.funcs.iter().filter(move |(name, _)| entry.calls(name));
+    called.map(|(_, callee)| callee)
+  }
+}
+
+/// The backends that [`build`] selects by name, with the extension of what
+/// they write. It also selects `llvm` and `cranelift`, which build
+/// executables, when Kale is built with their features.
+pub const BACKENDS: [(&str, &str); 8] = [
+  ("wasm", "wasm"),
+  ("wat", "wat"),
+  ("c", "c"),
+  ("rust", "rs"),
+  ("js", "js"),
+  ("ir", "ir"),
+  ("dot", "dot"),
+  ("bytecode", "kbc"),
+];
+
+/// Compiles the checked program `module`, which starts at `entry`, to
+/// `output` with the backend `name`, computing with numbers of the given
+/// precision. The transpilers point what they write back at `source`, the
+/// file of the program: C with `#line` directives, JavaScript with a
+/// source map, and Rust with comments.
+pub fn build(
+  name: &str,
+  module: &ModuleAst,
+  entry: Entry,
+  precision: Precision,
+  source: &Path,
+  output: &Path,
+) -> Result<(), Vec<Diagnostic>> {
+  let error = |msg: String| vec![Diagnostic::error(Span::default(), msg).with_code("codegen")];
+  let mut backend: Box<dyn Backend> = match name {
+    "wasm" => Box::new(wasm::WasmBackend::new(precision, wasm::Format::Wasm)),
+    "wat" => Box::new(wasm::WasmBackend::new(precision, wasm::Format::Wat)),
+    "c" => {
+      let mut backend = c::CBackend::new(precision);
+      backend.set_source(source, output);
+      Box::new(backend)
+    }
+    "rust" => {
+      let mut backend = rust::RustBackend::new(precision);
+      backend.set_source(source, output);
+      Box::new(backend)
+    }
+    "js" => {
+      let mut backend = js::JsBackend::new(precision);
+      backend.set_source(source, output);
+      Box::new(backend)
+    }
+    "ir" | "dot" => {
+      let mut backend = ir::IrBackend::new();
+      if name == "dot" {
+        backend.set_format(ir::Format::Dot);
+      }
+      backend.set_optimize(precision);
+      Box::new(backend)
+    }
+    "bytecode" => {
+      let mut backend = BytecodeBackend::new();
+      backend.set_optimize(precision);
+      Box::new(backend)
+    }
+    #[cfg(feature = "llvm")]
+    "llvm" => {
+      let mut options = super::native::BuildOptions::new();
+      return super::native::build(module, entry, precision, &mut options, output);
+    }
+    #[cfg(feature = "cranelift")]
+    "cranelift" => {
+      let emit = super::Emit::Executable;
+      let backend = super::cranelift::CraneliftBackend::new(precision, true, emit);
+      Box::new(backend.map_err(|e| vec![e])?)
+    }
+    name if name == "llvm" || name == "cranelift" => {
+      let msg = format!(
+        "The {0} backend needs Kale built with the {0} feature",
+        name
+      );
+      return Err(error(msg));
+    }
+    _ => return Err(error(format!("Unknown backend `{}`", name))),
+  };
+  compile(backend.as_mut(), module, entry, output)
+}
+
+/// Compiles the checked program `module`, which starts at `entry`, to
+/// `output` with `backend`.
+pub fn compile(
+  backend: &mut dyn Backend,
+  module: &ModuleAst,
+  entry: Entry,
+  output: &Path,
+) -> Result<(), Vec<Diagnostic>> {
+  define_module(backend, module)?;
+  backend.finish_module(entry, output)
+}
+
+/// Declares the functions and externs of `module` to `backend`, then
+/// defines its functions and top-level expressions in order. Every
+/// function is defined even when another one fails, and the errors are
+/// reported in order.
+pub fn define_module(backend: &mut dyn Backend, module: &ModuleAst) -> Result<(), Vec<Diagnostic>> {
+  let tuples = tuple_arities(module);
+  let mut errors = vec![];
+  for item in &module.items {
+    let res = match item {
+      Ast::Proto(proto) => backend.declare_proto(proto, Declaration::Extern),
+      Ast::Func(func) if !func.proto.name.is_empty() => {
+        let tuple = tuples.get(&func.proto.name).copied();
+        backend.declare_proto(&func.proto, Declaration::Function { tuple })
+      }
+      Ast::Func(_) | Ast::Expr(_) => continue,
+      item => Err(unsupported_item(backend.name(), item)),
+    };
+    errors.extend(res.err());
+  }
+  for item in &module.items {
+    let res = match item {
+      Ast::Func(func) => backend.define_function(func),
+      Ast::Expr(expr) => match Ast::new_top_level(expr.clone(), Span::default()) {
+        Ast::Func(func) => backend.define_function(&func),
+        _ => unreachable!(),
+      },
+      _ => continue,
+    };
+    errors.extend(res.err());
+  }
+  match errors.is_empty() {
+    true => Ok(()),
+    false => Err(errors),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  /// Records what the driver asks of it.
+  struct Recorder {
+    calls: Vec<String>,
+  }
+
+  impl Backend for Recorder {
+    fn name(&self) -> &'static str {
+      "recording"
+    }
+
+    fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+      self
+        .calls
+        .push(format!("declare {} {:?}", proto.name, decl));
+      Ok(())
+    }
+
+    fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+      self.calls.push(format!("define {:?}", func.proto.name));
+      match func.proto.name.as_str() {
+        "bad" => Err(Diagnostic::error(func.proto.span, "bad".to_string())),
+        _ => Ok(()),
+      }
+    }
+
+    fn finish_module(&mut self, entry: Entry, _: &Path) -> Result<(), Vec<Diagnostic>> {
+      self.calls.push(format!("finish {:?}", entry));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn backend_define_module() {
+    let src = "def f(x) g(x); extern sin(x); f(1); def g(x) (x, x); def bad() 0;";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut backend = Recorder { calls: vec![] };
+    let errors = define_module(&mut backend, &module).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+      backend.calls,
+      [
+        "declare f Function { tuple: Some(2) }",
+        "declare sin Extern",
+        "declare g Function { tuple: Some(2) }",
+        "declare bad Function { tuple: None }",
+        "define \"f\"",
+        "define \"\"",
+        "define \"g\"",
+        "define \"bad\"",
+      ]
+    );
+    let (source, output) = (Path::new("in.kale"), Path::new("out"));
+    let errors = build("z80", &module, Entry::Main, Precision::F64, source, output).unwrap_err();
+    assert_eq!(errors[0].message, "Unknown backend `z80`");
+  }
+}
@@ -0,0 +1,1120 @@
+use super::backend::{define_module, Backend, Declaration, Declared};
+use super::{
+  expr_span, int_divisions, is_overloaded, link_name, overload, relative_path, tuple_arity,
+  unsupported, Cond, Returns, RUNTIME,
+};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, ProtoAst, UnOp};
+use crate::prelude::prelude;
+use crate::session::Entry;
+use crate::value::Precision;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// The declarations of the runtime that transpiled programs include.
+pub const HEADER: &str = include_str!("kale.h");
+
+/// Transpiles the checked program `module`, which starts at `entry`, to C.
+///
+/// Numbers are `double`s, or `float`s in `F32` precision, and a function
+/// that returns a tuple returns a struct of them, `kale_tupleN`. Externs are
+/// declared, unless `kale.h` does: the runtime and the math of `<math.h>`.
+/// Names that aren't C identifiers, or that C reserves, are prefixed with
+/// `kale_`. The `main` of C calls that of the program, or else the
+/// top-level expressions in order, unless `KALE_NO_MAIN` is defined.
+///
+/// Calls are made in statements of their own, so that they happen in the
+/// order Kale evaluates them, which C doesn't define for arguments. `var`s
+/// aren't transpiled, as an assignment in one operand would make the order
+/// of the others matter too. Every
+/// function is transpiled even when another one fails, and the errors are
+/// reported in order.
+pub fn transpile(
+  module: &ModuleAst,
+  entry: Entry,
+  precision: Precision,
+) -> Result<String, Vec<Diagnostic>> {
+  let mut backend = CBackend::new(precision);
+  define_module(&mut backend, module)?;
+  Ok(backend.source(entry))
+}
+
+/// CBackend - transpiles programs to C, as [`transpile`] does, to the
+/// source `output`. The runtime is written next to it, as `kale.h` and
+/// `kale_runtime.c`, to compile along with it: `cc prog.c kale_runtime.c
+/// -lm`.
+pub struct CBackend {
+  transpiler: Transpiler,
+  declared: Declared<Callee>,
+  definitions: Vec<String>,
+  output: Option<String>, // the name of the C file, once lines point at the source
+}
+
+impl CBackend {
+  pub fn new(precision: Precision) -> Self {
+    let transpiler = Transpiler {
+      precision,
+      float: match precision {
+        Precision::F64 => "double",
+        Precision::F32 => "float",
+      },
+      functions: HashMap::new(),
+      returns: Returns::default(),
+      arities: BTreeSet::new(),
+      externs: vec![],
+      names: reserved(),
+      source: None,
+    };
+    Self {
+      transpiler,
+      declared: Declared::new(),
+      definitions: vec![],
+      output: None,
+    }
+  }
+
+  /// Points the lines of the functions defined from now on back at the
+  /// Kale file `source` with `#line` directives, for C compilers and
+  /// debuggers to report them there, and the lines that follow each at
+  /// `output`, where the C is written. Items imported from other files are
+  /// pointed at lines of `source` too.
+  pub fn set_source(&mut self, source: &Path, output: &Path) {
+    let dir = output.parent().unwrap_or(Path::new(""));
+    self.transpiler.source = Some(relative_path(dir, source));
+    let name = output.file_name().unwrap_or_default();
+    self.output = Some(name.to_string_lossy().into_owned());
+  }
+
+  /// The C source of the functions defined, for the program to start at
+  /// `entry`.
+  pub fn source(&self, entry: Entry) -> String {
+    let transpiler = &self.transpiler;
+    let mut out = "#include \"kale.h\"\n\n".to_string();
+    for n in &transpiler.arities {
+      let elems: Vec<_> = (0..*n).map(|i| format!("e{}", i)).collect();
+      let _ = writeln!(
+        out,
+        "typedef struct {{ {} {}; }} kale_tuple{};",
+        transpiler.float,
+        elems.join(", "),
+        n
+      );
+    }
+    if !transpiler.arities.is_empty() {
+      out.push('\n');
+    }
+    for declaration in &transpiler.externs {
+      let _ = writeln!(out, "{};", declaration);
+    }
+    if !transpiler.externs.is_empty() {
+      out.push('\n');
+    }
+    for (_, callee) in self.declared.funcs() {
+      let _ = writeln!(out, "{};", callee.call);
+    }
+    for definition in &self.definitions {
+      let _ = write!(out, "\n{}", definition);
+      if let Some(output) = &self.output {
+        let next = out.matches('\n').count() + 2;
+        let _ = writeln!(out, "#line {} {:?}", next, output);
+      }
+    }
+    out.push_str("\n#ifndef KALE_NO_MAIN\nint main(void) {\n");
+    for callee in self.declared.entry(entry) {
+      let _ = writeln!(out, "  {}();", callee.name);
+    }
+    out.push_str("  return 0;\n}\n#endif\n");
+    out
+  }
+}
+
+impl Backend for CBackend {
+  fn name(&self) -> &'static str {
+    "C"
+  }
+
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+    match decl {
+      Declaration::Extern => self.transpiler.declare_extern(proto),
+      Declaration::Function { tuple } => {
+        let callee = self.transpiler.declare(proto, tuple);
+        self.declared.push(&proto.name, callee);
+      }
+    }
+    Ok(())
+  }
+
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+    let transpiler = &mut self.transpiler;
+    let callee = self.declared.define(func, |_| {
+      let tuple = tuple_arity(&func.body, &transpiler.returns.tuples);
+      transpiler.declare(&func.proto, tuple)
+    });
+    let definition = self.transpiler.transpile_func(func, &callee)?;
+    self.definitions.push(definition);
+    Ok(())
+  }
+
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>> {
+    let source = self.source(entry);
+    let dir = output.parent().unwrap_or(Path::new(""));
+    let files = [
+      (output.to_path_buf(), source.as_str()),
+      (dir.join("kale.h"), HEADER),
+      (dir.join("kale_runtime.c"), RUNTIME),
+    ];
+    for (path, contents) in files {
+      std::fs::write(&path, contents).map_err(|e| {
+        let msg = format!("Cannot write `{}`: {}", path.display(), e);
+        vec![Diagnostic::error(Span::default(), msg).with_code("codegen")]
+      })?;
+    }
+    Ok(())
+  }
+}
+
+const KEYWORDS: [&str; 35] = [
+  "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum",
+  "extern", "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict",
+  "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef", "union",
+  "unsigned", "void", "volatile", "while", "main",
+];
+
+/// The functions of `<math.h>` that transpiled programs call, each also
+/// with the suffix `f`.
+const MATH: [&str; 12] = [
+  "fmod", "trunc", "fabs", "fmin", "fmax", "sin", "cos", "exp", "log", "sqrt", "pow", "floor",
+];
+
+/// The names that functions and variables can't take in C: its keywords
+/// and `main`, and the functions of `kale.h`.
+fn reserved() -> HashSet<String> {
+  let runtime = ["printd", "putchard", "readd", "rand", "srand"];
+  let mut names: HashSet<String> = KEYWORDS
+    .iter()
+    .chain(&runtime)
+    .map(|name| name.to_string())
+    .collect();
+  for name in MATH {
+    names.insert(name.to_string());
+    names.insert(format!("{}f", name));
+  }
+  names
+}
+
+/// A C identifier for `name`: itself if it is one that isn't reserved,
+/// else prefixed with `kale_`, with the characters C doesn't allow as
+/// `_xHH`.
+fn identifier(name: &str, reserved: &HashSet<String>) -> String {
+  let valid = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    && !name.starts_with(|c: char| c.is_ascii_digit())
+    && !name.starts_with("kale_");
+  if valid && !reserved.contains(name) {
+    return name.to_string();
+  }
+  let mut out = "kale_".to_string();
+  for c in name.chars() {
+    match c {
+      c if c.is_ascii_alphanumeric() || c == '_' => out.push(c),
+      c => {
+        let mut buf = [0; 4];
+        for byte in c.encode_utf8(&mut buf).bytes() {
+          let _ = write!(out, "_x{:02x}", byte);
+        }
+      }
+    }
+  }
+  out
+}
+
+/// A function as programs call it, by its C signature.
+type Callee = super::Callee<String>;
+
+struct Transpiler {
+  precision: Precision,
+  float: &'static str,
+  functions: HashMap<String, Callee>, // by the name programs call them
+  returns: Returns,                   // what they return
+  arities: BTreeSet<usize>,           // of the tuple structs used
+  externs: Vec<String>,               // the declarations `kale.h` lacks
+  names: HashSet<String>,             // of the functions, and those reserved
+  source: Option<String>,             // the file `#line`s point at
+}
+
+impl Transpiler {
+  /// Declares the function `proto` defines, or a top-level expression,
+  /// under a name of its own, returning a tuple of `tuple` numbers if any.
+  fn declare(&mut self, proto: &ProtoAst, tuple: Option<usize>) -> Callee {
+    self.returns.declare(proto, tuple);
+    let name = match proto.name.as_str() {
+      "" => {
+        let mut n = 0;
+        while self.names.contains(&top_level_name(n)) {
+          n += 1;
+        }
+        top_level_name(n)
+      }
+      name => identifier(name, &self.names),
+    };
+    self.names.insert(name.clone());
+    let ret = match tuple {
+      Some(n) => {
+        self.arities.insert(n);
+        format!("kale_tuple{}", n)
+      }
+      None => self.float.to_string(),
+    };
+    let params = match proto.args.len() {
+      0 => "void".to_string(),
+      n => vec![self.float; n].join(", "),
+    };
+    let (prefix, params) = match proto.name.as_str() {
+      "" => ("static ", params),
+      _ => ("", params),
+    };
+    let callee = Callee {
+      call: format!("{}{} {}({})", prefix, ret, name, params),
+      name,
+      tuple,
+      widen: false,
+    };
+    if !proto.name.is_empty() {
+      self.functions.insert(proto.name.clone(), callee.clone());
+    }
+    callee
+  }
+
+  /// Declares the extern `proto`, which `kale.h` declares if it is of the
+  /// runtime or of `<math.h>`.
+  fn declare_extern(&mut self, proto: &ProtoAst) {
+    let (symbol, widen) = link_name(proto.symbol(), self.precision);
+    let known = widen || symbol != proto.symbol() || self.names.contains(&symbol);
+    if !known {
+      let params = match proto.args.len() {
+        0 => "void".to_string(),
+        n => vec![self.float; n].join(", "),
+      };
+      let signature = format!("{} {}({})", self.float, symbol, params);
+      self.externs.push(signature);
+      self.names.insert(symbol.clone());
+    }
+    let callee = Callee {
+      call: String::new(),
+      name: symbol,
+      tuple: None,
+      widen,
+    };
+    self.functions.insert(proto.name.clone(), callee);
+  }
+
+  /// The definition of the function `func`, or of the top-level expression
+  /// it wraps, which `callee` declares.
+  fn transpile_func(&mut self, func: &FuncAst, callee: &Callee) -> Result<String, Diagnostic> {
+    let proto = &func.proto;
+    let mut body = func.body.clone();
+    body.lower_matches();
+    let mut lowering = Lowering {
+      names: self.names.clone(),
+      divisions: int_divisions(func, &self.returns.annotations),
+      transpiler: self,
+      lines: vec![],
+      depth: 1,
+      scope: vec![],
+      ret: callee.tuple,
+      temps: 0,
+      at: proto.span,
+    };
+    let mut params = vec![];
+    for arg in &proto.args {
+      let name = lowering.fresh(arg);
+      params.push(format!("{} {}", lowering.transpiler.float, name));
+      lowering.scope.push((arg.clone(), Val::Num(name)));
+    }
+    let val = lowering.lower_expr(&body, proto.span)?;
+    let val = lowering.returned(val, proto.span)?;
+    lowering.emit_return(val);
+    let params = match params.len() {
+      0 => "void".to_string(),
+      _ => params.join(", "),
+    };
+    let at = callee.call.rfind('(').unwrap();
+    let head = format!("{}({})", &callee.call[..at], params);
+    let mut out = String::new();
+    let head = format!("{} {{", head);
+    // the line of the source each statement is at, and the one the C
+    // compiler counts, which a directive sets when they differ
+    let mut at = proto.span.line;
+    let mut next = 0;
+    for line in [&head].into_iter().chain(&lowering.lines) {
+      if let Some(n) = line.strip_prefix(MARKER) {
+        at = n.parse().unwrap();
+        continue;
+      }
+      if let Some(source) = &self.source {
+        if at > 0 && at != next && !line.trim_start().starts_with('}') {
+          let _ = writeln!(out, "#line {} {:?}", at, source);
+          next = at;
+        }
+      }
+      let _ = writeln!(out, "{}", line);
+      next += 1;
+    }
+    out.push_str("}\n");
+    Ok(out)
+  }
+}
+
+/// Starts the lines that mark the line of the source the statements that
+/// follow are at, which become `#line` directives where needed.
+const MARKER: &str = "\0";
+
+fn top_level_name(n: usize) -> String {
+  match n {
+    0 => "kale_top_level".to_string(),
+    n => format!("kale_top_level_{}", n),
+  }
+}
+
+/// The value of an expression in C: a number, or the numbers of a tuple.
+/// Each is an expression without side effects, which may be evaluated any
+/// number of times.
+#[derive(Debug, Clone)]
+enum Val {
+  Num(String),
+  Tuple(Vec<String>),
+}
+
+/// Lowering - the state of the transpilation of one function, whose
+/// statements are written to `lines`. With a source to point at, each
+/// statement follows a marker of `at`, the line of the last expression
+/// lowered that knows where it is, and those written without one are at
+/// the line of the last marker.
+struct Lowering<'a> {
+  transpiler: &'a mut Transpiler,
+  lines: Vec<String>,
+  depth: usize,
+  names: HashSet<String>,   // in the function, and those it can't use
+  divisions: HashSet<Span>, // of ints, which truncate
+  scope: Vec<(String, Val)>,
+  ret: Option<usize>,
+  temps: usize,
+  at: Span,
+}
+
+impl Lowering<'_> {
+  fn emit(&mut self, line: String) {
+    if self.transpiler.source.is_some() && self.at.line > 0 {
+      self.lines.push(format!("{}{}", MARKER, self.at.line));
+    }
+    self
+      .lines
+      .push(format!("{}{}", "  ".repeat(self.depth), line));
+  }
+
+  /// A name for a new variable, `name` unless it is taken.
+  fn fresh(&mut self, name: &str) -> String {
+    let base = identifier(name, &self.transpiler.names);
+    let mut name = base.clone();
+    let mut n = 1;
+    while self.names.contains(&name) {
+      name = format!("{}_{}", base, n);
+      n += 1;
+    }
+    self.names.insert(name.clone());
+    name
+  }
+
+  fn temp(&mut self) -> String {
+    loop {
+      let name = format!("t{}", self.temps);
+      self.temps += 1;
+      if self.names.insert(name.clone()) {
+        return name;
+      }
+    }
+  }
+
+  /// Lowers `f` into statements of their own, one level deeper, returning
+  /// them along with what `f` returns.
+  fn nested<T>(
+    &mut self,
+    f: impl FnOnce(&mut Self) -> Result<T, Diagnostic>,
+  ) -> Result<(Vec<String>, T), Diagnostic> {
+    let lines = std::mem::take(&mut self.lines);
+    let at = self.at;
+    self.depth += 1;
+    let res = f(self);
+    self.depth -= 1;
+    let nested = std::mem::replace(&mut self.lines, lines);
+    self.at = at;
+    Ok((nested, res?))
+  }
+
+  fn literal(&self, n: f64) -> String {
+    let s = match self.transpiler.precision {
+      _ if n.is_nan() => "NAN".to_string(),
+      _ if n.is_infinite() => "INFINITY".to_string(),
+      Precision::F64 => format!("{:?}", n.abs()),
+      Precision::F32 => format!("{:?}f", n.abs() as f32),
+    };
+    match n.is_sign_negative() && !n.is_nan() {
+      true => format!("(-{})", s),
+      false => s,
+    }
+  }
+
+  /// `val` as returned by the function, which must return a number or a
+  /// tuple of that size.
+  fn returned(&mut self, val: Val, span: Span) -> Result<String, Diagnostic> {
+    match (val, self.ret) {
+      (Val::Num(num), None) => Ok(bare(&num).to_string()),
+      (Val::Tuple(elems), Some(n)) if elems.len() == n => {
+        // the elements of a tuple variable, in order, are that variable
+        if let Some((var, _)) = elems[0].split_once('.') {
+          if (0..n).all(|i| elems[i] == format!("{}.e{}", var, i)) {
+            return Ok(var.to_string());
+          }
+        }
+        Ok(format!("(kale_tuple{}){{{}}}", n, elems.join(", ")))
+      }
+      _ => {
+        let msg = "Function returns both numbers and tuples, or tuples of different sizes";
+        Err(Diagnostic::error(span, msg).with_code("codegen"))
+      }
+    }
+  }
+
+  /// Lowers `expr`, leaving `at` at it if it knows where it is, or else
+  /// where the last of its operands lowered is.
+  fn lower_expr(&mut self, expr: &ExprAst, span: Span) -> Result<Val, Diagnostic> {
+    let Some(at) = expr_span(expr).filter(|at| at.line > 0) else {
+      return self.lower_kind(expr, span);
+    };
+    self.at = at;
+    let val = self.lower_kind(expr, span);
+    self.at = at;
+    val
+  }
+
+  fn lower_kind(&mut self, expr: &ExprAst, span: Span) -> Result<Val, Diagnostic> {
+    let num = |lowering: &Self, n: f64| Ok(Val::Num(lowering.literal(n)));
+    match expr {
+      ExprAst::NumAst(n) => num(self, *n),
+      ExprAst::IntAst(i) => num(self, *i as f64),
+      ExprAst::BoolAst(b) => num(self, *b as i32 as f64),
+      ExprAst::UnitAst => num(self, 0.0),
+      ExprAst::VarAst(name, at) => match self.scope.iter().rev().find(|(n, _)| n == name) {
+        Some((_, val)) => Ok(val.clone()),
+        None if self.transpiler.functions.contains_key(name) => {
+          Err(unsupported("C", "functions as values", *at))
+        }
+        None => Err(unsupported("C", "global variables", *at)),
+      },
+      ExprAst::UnaryAst(UnOp::Neg, operand, at) => {
+        let operand = self.lower_num(operand, *at)?;
+        Ok(Val::Num(format!("(-{})", operand)))
+      }
+      ExprAst::UnaryAst(UnOp::Not, ..) => {
+        let cond = self.lower_cond(expr, span)?;
+        Ok(Val::Num(format!("(({}){})", self.transpiler.float, cond)))
+      }
+      ExprAst::BinAst(lhs, op, rhs, at) => self.lower_bin(lhs, *op, rhs, *at),
+      ExprAst::CallAst(name, args, at) => self.lower_call(name, args, *at, None),
+      ExprAst::IfAst { cond, then, els } => self.lower_if(cond, then, els, span),
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        let Some((last, exprs)) = exprs.split_last() else {
+          return num(self, 0.0);
+        };
+        for expr in exprs {
+          self.lower_stmt(expr, span)?;
+        }
+        self.lower_expr(last, span)
+      }
+      ExprAst::TupleAst(elems) => {
+        let elems = elems.iter().map(|elem| self.lower_num(elem, span));
+        Ok(Val::Tuple(elems.collect::<Result<_, _>>()?))
+      }
+      ExprAst::ElemAst(tuple, i) => match self.lower_expr(tuple, span)? {
+        Val::Tuple(elems) if *i < elems.len() => Ok(Val::Num(elems[*i].clone())),
+        _ => {
+          Err(Diagnostic::error(span, format!("No element {} in tuple", i)).with_code("codegen"))
+        }
+      },
+      ExprAst::LetAst(bindings, body) => {
+        let depth = self.scope.len();
+        for (name, _, init) in bindings {
+          let val = self.lower_binding(name, init, span)?;
+          self.scope.push((name.clone(), val));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let elems = self.lower_destructuring(names, init, span)?;
+        let depth = self.scope.len();
+        for ((name, _), elem) in names.iter().zip(elems) {
+          self.scope.push((name.clone(), Val::Num(elem)));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      // the code that follows a `return` never runs, so the `return`
+      // yields what it returned
+      ExprAst::ReturnAst(value, at) => {
+        let val = self.lower_expr(value, *at)?;
+        let ret = self.returned(val.clone(), *at)?;
+        self.emit_return(ret);
+        Ok(val)
+      }
+      ExprAst::MatchAst(..) => unreachable!("`match` is lowered before codegen"),
+      ExprAst::StrAst(_) => Err(unsupported("C", "strings", span)),
+      ExprAst::ArrayAst(_) | ExprAst::IndexAst(..) => Err(unsupported("C", "arrays", span)),
+      ExprAst::FieldAst(..) => Err(unsupported("C", "structs", span)),
+      ExprAst::LambdaAst(..) => Err(unsupported("C", "closures", span)),
+      ExprAst::FuncRefAst(_, at) => Err(unsupported("C", "functions as values", *at)),
+      ExprAst::VarInAst(vars, _) => {
+        let at = vars.first().map_or(span, |(_, at, _)| *at);
+        Err(unsupported("C", "`var`", at))
+      }
+      ExprAst::AssignAst(.., at) => Err(unsupported("C", "assignments", *at)),
+      ExprAst::TryAst(.., at) => Err(unsupported("C", "`try`", *at)),
+    }
+  }
+
+  /// Lowers `expr` for its side effects only.
+  fn lower_stmt(&mut self, expr: &ExprAst, span: Span) -> Result<(), Diagnostic> {
+    match expr {
+      ExprAst::CallAst(name, args, at) if self.is_call(name, args) => {
+        let call = self.call_expr(name, args, *at)?;
+        self.emit(format!("{};", call));
+      }
+      ExprAst::IfAst { cond, then, els } => {
+        let cond = self.lower_cond(cond, span)?;
+        let (then, _) = self.nested(|lowering| lowering.lower_stmt(then, span))?;
+        let (els, _) = self.nested(|lowering| lowering.lower_stmt(els, span))?;
+        self.emit_if(&cond, then, els);
+      }
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        for expr in exprs {
+          self.lower_stmt(expr, span)?;
+        }
+      }
+      ExprAst::LetAst(bindings, body) => {
+        let depth = self.scope.len();
+        for (name, _, init) in bindings {
+          let val = self.lower_binding(name, init, span)?;
+          self.scope.push((name.clone(), val));
+        }
+        let res = self.lower_stmt(body, span);
+        self.scope.truncate(depth);
+        res?;
+      }
+      _ => {
+        self.lower_expr(expr, span)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes `return val;`, returning the call of the previous statement
+  /// instead if it only assigned `val`.
+  fn emit_return(&mut self, val: String) {
+    let indent = "  ".repeat(self.depth);
+    let call = self.lines.last().and_then(|line| {
+      let decl = line.strip_prefix(&indent)?.strip_prefix("const ")?;
+      let (_, call) = decl.split_once(' ')?;
+      call
+        .strip_prefix(&val)?
+        .strip_prefix(" = ")?
+        .strip_suffix(';')
+    });
+    match call.map(|call| call.to_string()) {
+      Some(call) => {
+        self.lines.pop();
+        self.emit(format!("return {};", call));
+      }
+      None => self.emit(format!("return {};", val)),
+    }
+  }
+
+  /// Writes an `if` statement, without the branches that are empty.
+  fn emit_if(&mut self, cond: &str, then: Vec<String>, els: Vec<String>) {
+    if then.is_empty() && els.is_empty() {
+      return;
+    }
+    if then.is_empty() {
+      return self.emit_if(&format!("!{}", cond), els, then);
+    }
+    self.emit(format!("if ({}) {{", bare(cond)));
+    self.lines.extend(then);
+    if !els.is_empty() {
+      self.emit("} else {".to_string());
+      self.lines.extend(els);
+    }
+    self.emit("}".to_string());
+  }
+
+  /// The value of the variable `name` bound to `init`: a constant, or the
+  /// elements of a tuple, each named after it.
+  fn lower_binding(&mut self, name: &str, init: &ExprAst, span: Span) -> Result<Val, Diagnostic> {
+    if let ExprAst::CallAst(callee, args, at) = init {
+      if self.is_call(callee, args) {
+        return self.lower_call(callee, args, *at, Some(name));
+      }
+    }
+    match self.lower_expr(init, span)? {
+      Val::Num(num) => {
+        let var = self.fresh(name);
+        let float = self.transpiler.float;
+        self.emit(format!("const {} {} = {};", float, var, bare(&num)));
+        Ok(Val::Num(var))
+      }
+      Val::Tuple(elems) => {
+        let mut vars = vec![];
+        for (i, elem) in elems.into_iter().enumerate() {
+          let var = self.fresh(&format!("{}_{}", name, i));
+          let float = self.transpiler.float;
+          self.emit(format!("const {} {} = {};", float, var, bare(&elem)));
+          vars.push(var);
+        }
+        Ok(Val::Tuple(vars))
+      }
+    }
+  }
+
+  /// The elements of the tuple `init`, to bind to `names`.
+  fn lower_destructuring(
+    &mut self,
+    names: &[(String, Span)],
+    init: &ExprAst,
+    span: Span,
+  ) -> Result<Vec<String>, Diagnostic> {
+    let val = match init {
+      ExprAst::CallAst(callee, args, at) if self.is_call(callee, args) => {
+        self.lower_call(callee, args, *at, None)?
+      }
+      init => self.lower_expr(init, span)?,
+    };
+    match val {
+      Val::Tuple(elems) if elems.len() == names.len() => Ok(elems),
+      Val::Tuple(elems) => {
+        let msg = format!(
+          "Cannot destructure a tuple of {} into {} names",
+          elems.len(),
+          names.len()
+        );
+        Err(Diagnostic::error(span, msg).with_code("codegen"))
+      }
+      Val::Num(_) => {
+        let msg = format!("Cannot destructure a number into {} names", names.len());
+        Err(Diagnostic::error(span, msg).with_code("codegen"))
+      }
+    }
+  }
+
+  fn lower_num(&mut self, expr: &ExprAst, span: Span) -> Result<String, Diagnostic> {
+    match self.lower_expr(expr, span)? {
+      Val::Num(num) => Ok(num),
+      Val::Tuple(_) => {
+        Err(Diagnostic::error(span, "Expected a number, found a tuple").with_code("codegen"))
+      }
+    }
+  }
+
+  /// A condition, as a C expression of whether `expr` is true: nonzero,
+  /// which NaN is.
+  fn lower_cond(&mut self, expr: &ExprAst, span: Span) -> Result<String, Diagnostic> {
+    match Cond::of(expr, &self.transpiler.functions) {
+      Cond::Bool(b) => Ok((b as i32).to_string()),
+      Cond::Not(operand, at) => {
+        let cond = self.lower_cond(operand, at)?;
+        Ok(format!("!{}", cond))
+      }
+      Cond::Logical(lhs, op, rhs, at) => self.lower_logical(lhs, op, rhs, at),
+      Cond::Cmp(lhs, op, rhs, at) => {
+        let lhs = self.lower_num(lhs, at)?;
+        let rhs = self.lower_num(rhs, at)?;
+        Ok(format!("({} {} {})", lhs, op.as_str(), rhs))
+      }
+      Cond::Nonzero => {
+        let num = self.lower_num(expr, span)?;
+        Ok(format!("({} != {})", num, self.literal(0.0)))
+      }
+    }
+  }
+
+  fn lower_bin(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Val, Diagnostic> {
+    if is_overloaded(&self.transpiler.functions, op) {
+      return self.lower_call(&overload(op), &[lhs.clone(), rhs.clone()], span, None);
+    }
+    if op.is_bitwise() {
+      return Err(unsupported("C", "bitwise operators", span));
+    }
+    let float = self.transpiler.float;
+    match op {
+      BinOp::And | BinOp::Or => {
+        let cond = self.lower_logical(lhs, op, rhs, span)?;
+        return Ok(Val::Num(format!("(({}){})", float, cond)));
+      }
+      BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge | BinOp::Eq | BinOp::Ne => {
+        let lhs = self.lower_num(lhs, span)?;
+        let rhs = self.lower_num(rhs, span)?;
+        let cond = format!("({} {} {})", lhs, op.as_str(), rhs);
+        return Ok(Val::Num(format!("(({}){})", float, cond)));
+      }
+      _ => {}
+    }
+    let lhs = self.lower_num(lhs, span)?;
+    let rhs = self.lower_num(rhs, span)?;
+    let num = match op {
+      BinOp::Rem => {
+        let fmod = match self.transpiler.precision {
+          Precision::F64 => "fmod",
+          Precision::F32 => "fmodf",
+        };
+        format!("{}({}, {})", fmod, bare(&lhs), bare(&rhs))
+      }
+      BinOp::Div if self.divisions.contains(&span) => {
+        format!("kale_idiv({}, {})", bare(&lhs), bare(&rhs))
+      }
+      op => format!("({} {} {})", lhs, op.as_str(), rhs),
+    };
+    Ok(Val::Num(num))
+  }
+
+  /// `&&` and `||`, which only evaluate `rhs` when `lhs` doesn't decide:
+  /// those of C, unless `rhs` makes calls, which then happen in an `if`.
+  fn lower_logical(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<String, Diagnostic> {
+    let lhs = self.lower_cond(lhs, span)?;
+    let (lines, rhs) = self.nested(|lowering| lowering.lower_cond(rhs, span))?;
+    if lines.is_empty() {
+      return Ok(format!("({} {} {})", lhs, op.as_str(), rhs));
+    }
+    let temp = self.temp();
+    self.emit(format!("int {} = {};", temp, bare(&lhs)));
+    let mut then = lines;
+    then.push(format!(
+      "{}{} = {};",
+      "  ".repeat(self.depth + 1),
+      temp,
+      bare(&rhs)
+    ));
+    let cond = match op {
+      BinOp::And => temp.clone(),
+      _ => format!("!{}", temp),
+    };
+    self.emit_if(&cond, then, vec![]);
+    Ok(temp)
+  }
+
+  fn lower_if(
+    &mut self,
+    cond: &ExprAst,
+    then: &ExprAst,
+    els: &ExprAst,
+    span: Span,
+  ) -> Result<Val, Diagnostic> {
+    let cond = self.lower_cond(cond, span)?;
+    let (then_lines, then) = self.nested(|lowering| lowering.lower_expr(then, span))?;
+    let (els_lines, els) = self.nested(|lowering| lowering.lower_expr(els, span))?;
+    let mismatch = || {
+      let msg = "Branches of `if` yield both numbers and tuples, or tuples of different sizes";
+      Err(Diagnostic::error(span, msg).with_code("codegen"))
+    };
+    match (then, els) {
+      (Val::Num(then), Val::Num(els)) if then_lines.is_empty() && els_lines.is_empty() => {
+        Ok(Val::Num(format!("({} ? {} : {})", bare(&cond), then, els)))
+      }
+      (Val::Num(then), Val::Num(els)) => {
+        let temp = self.temp();
+        let float = self.transpiler.float;
+        self.emit(format!("{} {};", float, temp));
+        let assign = |mut lines: Vec<String>, num: String, depth: usize| {
+          lines.push(format!("{}{} = {};", "  ".repeat(depth), temp, bare(&num)));
+          lines
+        };
+        let depth = self.depth + 1;
+        let (then, els) = (
+          assign(then_lines, then, depth),
+          assign(els_lines, els, depth),
+        );
+        self.emit_if(&cond, then, els);
+        Ok(Val::Num(temp))
+      }
+      (Val::Tuple(then), Val::Tuple(els)) if then.len() == els.len() => {
+        let n = then.len();
+        let temp = self.temp();
+        self.emit(format!("kale_tuple{} {};", n, temp));
+        self.transpiler.arities.insert(n);
+        let assign = |mut lines: Vec<String>, elems: Vec<String>, depth: usize| {
+          let tuple = format!("(kale_tuple{}){{{}}}", n, elems.join(", "));
+          lines.push(format!("{}{} = {};", "  ".repeat(depth), temp, tuple));
+          lines
+        };
+        let depth = self.depth + 1;
+        let (then, els) = (
+          assign(then_lines, then, depth),
+          assign(els_lines, els, depth),
+        );
+        self.emit_if(&cond, then, els);
+        Ok(Val::Tuple(
+          (0..n).map(|i| format!("{}.e{}", temp, i)).collect(),
+        ))
+      }
+      _ => mismatch(),
+    }
+  }
+
+  /// Whether calling `name` with `args` calls a function, rather than
+  /// `int` or `float`.
+  fn is_call(&self, name: &str, args: &[ExprAst]) -> bool {
+    !matches!((name, args.len()), ("int" | "float", 1))
+  }
+
+  /// A call, made in a statement that assigns what it returns to a new
+  /// variable, named after `var` if given. `int` rounds towards zero, and
+  /// `float` does nothing.
+  fn lower_call(
+    &mut self,
+    name: &str,
+    args: &[ExprAst],
+    span: Span,
+    var: Option<&str>,
+  ) -> Result<Val, Diagnostic> {
+    if !self.is_call(name, args) {
+      let num = self.lower_num(&args[0], span)?;
+      return match name {
+        "int" => {
+          let trunc = match self.transpiler.precision {
+            Precision::F64 => "trunc",
+            Precision::F32 => "truncf",
+          };
+          Ok(Val::Num(format!("{}({})", trunc, bare(&num))))
+        }
+        _ => Ok(Val::Num(num)),
+      };
+    }
+    let call = self.call_expr(name, args, span)?;
+    let tuple = self.transpiler.functions[name].tuple;
+    let float = self.transpiler.float;
+    let var = match var {
+      Some(var) => self.fresh(var),
+      None => self.temp(),
+    };
+    match tuple {
+      Some(n) => {
+        self.emit(format!("const kale_tuple{} {} = {};", n, var, call));
+        Ok(Val::Tuple(
+          (0..n).map(|i| format!("{}.e{}", var, i)).collect(),
+        ))
+      }
+      None => {
+        self.emit(format!("const {} {} = {};", float, var, call));
+        Ok(Val::Num(var))
+      }
+    }
+  }
+
+  /// The C expression of a call of the function `name` of the module or of
+  /// the prelude, converting the numbers it takes and returns if it
+  /// computes with `double`s only.
+  fn call_expr(&mut self, name: &str, args: &[ExprAst], span: Span) -> Result<String, Diagnostic> {
+    if self.scope.iter().any(|(local, _)| local == name) {
+      return Err(unsupported("C", "closures", span));
+    }
+    if !self.transpiler.functions.contains_key(name) {
+      let proto = prelude().items.into_iter().find_map(|item| match item {
+        Ast::Proto(proto) if proto.name == name => Some(proto),
+        _ => None,
+      });
+      if let Some(proto) = proto {
+        self.transpiler.declare_extern(&proto);
+      }
+    }
+    let Some(callee) = self.transpiler.functions.get(name).cloned() else {
+      let what = format!("the builtin `{}`", name);
+      return Err(unsupported("C", &what, span));
+    };
+    let args = args.iter().map(|arg| self.lower_num(arg, span));
+    let args: Vec<_> = args.collect::<Result<_, _>>()?;
+    let args: Vec<_> = args.iter().map(|arg| bare(arg)).collect();
+    let call = format!("{}({})", callee.name, args.join(", "));
+    Ok(
+      match callee.widen && self.transpiler.precision == Precision::F32 {
+        true => format!("(float){}", call),
+        false => call,
+      },
+    )
+  }
+}
+
+/// `expr` without the parentheses around all of it, if any.
+fn bare(expr: &str) -> &str {
+  let Some(inner) = expr.strip_prefix('(').and_then(|e| e.strip_suffix(')')) else {
+    return expr;
+  };
+  let mut depth = 0;
+  for c in inner.chars() {
+    match c {
+      '(' => depth += 1,
+      ')' if depth == 0 => return expr,
+      ')' => depth -= 1,
+      _ => {}
+    }
+  }
+  inner
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codegen::backend::compile;
+  use crate::codegen::tests::transpile_src;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+  use std::process::Command;
+
+  #[test]
+  fn c_transpile() {
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a);
+      def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2);
+      def main() { let (lo, hi) = minmax(2, 1) in printd(hi % lo); printd(fib(10)) };";
+    let expected = "#include \"kale.h\"
+
+typedef struct { double e0, e1; } kale_tuple2;
+
+kale_tuple2 minmax(double, double);
+double fib(double);
+double kale_main(void);
+
+kale_tuple2 minmax(double a, double b) {
+  kale_tuple2 t0;
+  if (a < b) {
+    t0 = (kale_tuple2){a, b};
+  } else {
+    t0 = (kale_tuple2){b, a};
+  }
+  return t0;
+}
+
+double fib(double n) {
+  double t2;
+  if (n < 2.0) {
+    t2 = n;
+  } else {
+    const double t0 = fib(n - 1.0);
+    const double t1 = fib(n - 2.0);
+    t2 = t0 + t1;
+  }
+  return t2;
+}
+";
+    let c = transpile_src(transpile, src).unwrap();
+    assert!(c.starts_with(expected), "{}", c);
+
+    let dir = std::env::temp_dir().join("kale-c-transpile");
+    std::fs::create_dir_all(&dir).unwrap();
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut backend = CBackend::new(Precision::F64);
+    compile(&mut backend, &module, Entry::Main, &dir.join("prog.c")).unwrap();
+    let status = Command::new("cc")
+      .current_dir(&dir)
+      .args(["prog.c", "kale_runtime.c", "-lm", "-o", "prog"])
+      .status()
+      .unwrap();
+    assert!(status.success());
+    let res = Command::new(dir.join("prog")).output().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(String::from_utf8(res.stdout).unwrap(), "0.0\n55.0\n");
+
+    // the quotient of ints truncates, failing on zero
+    let c = transpile_src(transpile, "def half(x: int) x / 2; def main() half(7) / 2;").unwrap();
+    assert!(
+      c.contains("return kale_idiv(x, 2.0);") && c.contains("return t0 / 2.0;"),
+      "{}",
+      c
+    );
+
+    assert_eq!(
+      transpile_src(transpile, "def f(x) x; \"s\"").unwrap_err(),
+      ["1:13: The C backend doesn't support strings"]
+    );
+    assert_eq!(
+      transpile_src(transpile, "var g = 1; const N = 2; def main() 0;").unwrap_err(),
+      [
+        "1:1: The C backend doesn't support global variables",
+        "1:22: The C backend doesn't support constants"
+      ]
+    );
+    assert_eq!(
+      transpile_src(transpile, "def f(x) var a = x in a; def g(x) { x = 2; x };").unwrap_err(),
+      [
+        "1:14: The C backend doesn't support `var`",
+        "1:37: The C backend doesn't support assignments"
+      ]
+    );
+  }
+
+  #[test]
+  fn c_line_directives() {
+    let src = "def fib(n)
+  if n < 2 then n
+  else fib(n - 1) + fib(n - 2);
+def main() printd(fib(10));";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut backend = CBackend::new(Precision::F64);
+    backend.set_source(Path::new("prog.kale"), Path::new("prog.c"));
+    crate::codegen::backend::define_module(&mut backend, &module).unwrap();
+    let c = backend.source(Entry::Main);
+    let start = c.find("#line").unwrap();
+    let end = c.find("\n#ifndef").unwrap();
+    assert_eq!(
+      &c[start..end],
+      "#line 1 \"prog.kale\"
+double fib(double n) {
+  double t2;
+#line 2 \"prog.kale\"
+  if (n < 2.0) {
+#line 2 \"prog.kale\"
+    t2 = n;
+  } else {
+#line 3 \"prog.kale\"
+    const double t0 = fib(n - 1.0);
+#line 3 \"prog.kale\"
+    const double t1 = fib(n - 2.0);
+#line 3 \"prog.kale\"
+    t2 = t0 + t1;
+  }
+#line 2 \"prog.kale\"
+  return t2;
+}
+#line 25 \"prog.c\"
+
+#line 4 \"prog.kale\"
+double kale_main(void) {
+#line 4 \"prog.kale\"
+  const double t0 = fib(10.0);
+#line 4 \"prog.kale\"
+  return printd(t0);
+}
+#line 34 \"prog.c\"
+"
+    );
+  }
+}
@@ -0,0 +1,1619 @@
+use super::backend::{Backend, Declaration};
+use super::{
+  assigns, link, link_name, linkable, scalar_annotation, scratch_dir, tuple_arities, tuple_arity,
+  unsupported, unsupported_item, write_dumps, Annotation, Definitions, Emit, Engine, IrDump,
+};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, ProtoAst, UnOp};
+use crate::prelude::prelude;
+use crate::runtime::{self, IntOp};
+use crate::session::Entry;
+use crate::value::{Precision, Value};
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{
+  self, types, AbiParam, InstBuilder, MemFlags, StackSlotData, StackSlotKind, Type, UserFuncName,
+};
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module, ModuleError};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+/// CraneliftJit - runs top-level expressions as native code, as the LLVM
+/// `Jit` does, but compiled by Cranelift, which is written in Rust and
+/// needs no LLVM installed.
+///
+/// The functions and externs defined so far are compiled anew into a
+/// module along with each expression, which is then called and thrown
+/// away.
+pub struct CraneliftJit {
+  defs: Definitions,
+  precision: Precision,
+  optimize: bool,
+  dump: Option<Box<dyn Write>>, // where the CLIF of each item goes
+}
+
+impl CraneliftJit {
+  pub fn new(precision: Precision) -> Self {
+    Self {
+      defs: Definitions::new(),
+      precision,
+      optimize: true,
+      dump: None,
+    }
+  }
+
+  /// Optimizes the code for speed, the default, or not at all.
+  pub fn set_optimize(&mut self, optimize: bool) {
+    self.optimize = optimize;
+  }
+
+  /// Writes the CLIF of each function and expression compiled from now on
+  /// to `out`, before and after its optimization.
+  pub fn set_dump(&mut self, out: impl Write + 'static) {
+    self.dump = Some(Box::new(out));
+  }
+
+  /// Compiles the definitions with the function or extern `item`,
+  /// keeping the dumps of its CLIF.
+  fn define(&mut self, item: Ast) -> Result<(), Diagnostic> {
+    let name = match &item {
+      Ast::Func(func) => func.proto.name.clone(),
+      Ast::Proto(proto) => proto.name.clone(),
+      _ => unreachable!(),
+    };
+    let mut dumps = vec![];
+    let (precision, optimize, dump) = (self.precision, self.optimize, self.dump.is_some());
+    self.defs.define(item, |defs| {
+      let mut compiler = Compiler::new(precision, optimize)?;
+      compiler.set_dump(dump);
+      let res = compiler.compile_module(defs);
+      dumps = compiler.take_dumps();
+      compiler.free();
+      res.map(|_| ()).map_err(|mut errors| errors.remove(0))
+    })?;
+    dumps.retain(|dump| dump.function == name);
+    write_dumps(&mut self.dump, dumps);
+    Ok(())
+  }
+
+  /// Compiles the top-level expression `func` with the definitions, and
+  /// calls it.
+  fn eval(&mut self, func: &FuncAst) -> Result<Value, Diagnostic> {
+    let mut compiler = Compiler::new(self.precision, self.optimize)?;
+    if let Err(mut errors) = compiler.compile_module(self.defs.module()) {
+      compiler.free();
+      return Err(errors.remove(0));
+    }
+    compiler.set_dump(self.dump.is_some());
+    let res = compiler.compile_func(func);
+    write_dumps(&mut self.dump, compiler.take_dumps());
+    let res = res.and_then(|id| compiler.finalize().map(|_| id));
+    let ret = Annotation::returned(&func.proto, compiler.tuples.get("").copied());
+    runtime::take_failure();
+    let val = res.map(|id| {
+      let ptr = compiler.module.get_finalized_function(id);
+      unsafe { call(ptr, &ret, self.precision) }
+    });
+    compiler.free();
+    match runtime::take_failure() {
+      Some(msg) => Err(Diagnostic::error(Span::default(), msg).with_code("runtime")),
+      None => val,
+    }
+  }
+}
+
+impl Engine for CraneliftJit {
+  fn declare(&mut self, module: &ModuleAst) {
+    self.defs.declare(module);
+  }
+
+  fn run(&mut self, item: Ast) -> Result<Option<Value>, Diagnostic> {
+    let item = match item {
+      Ast::Expr(expr) => Ast::new_top_level(expr, Span::default()),
+      item => item,
+    };
+    match item {
+      Ast::Func(func) if func.proto.name.is_empty() => self.eval(&func).map(Some),
+      item @ (Ast::Func(_) | Ast::Proto(_)) => self.define(item).map(|_| None),
+      item => Err(unsupported_item("Cranelift", &item)),
+    }
+  }
+
+  fn set_precision(&mut self, precision: Precision) {
+    self.precision = precision;
+  }
+}
+
+/// Calls the compiled top-level expression at `ptr`, which returns `ret`,
+/// storing the tuple it yields, if any, through the pointer it takes, an
+/// element every 8 bytes.
+unsafe fn call(ptr: *const u8, ret: &Annotation, precision: Precision) -> Value {
+  match (ret, precision) {
+    (Annotation::Int, _) => {
+      let anon: extern "C" fn() -> i64 = std::mem::transmute(ptr);
+      Value::Int(anon())
+    }
+    (Annotation::Num, Precision::F64) => {
+      let anon: extern "C" fn() -> f64 = std::mem::transmute(ptr);
+      Value::Num(anon())
+    }
+    (Annotation::Num, Precision::F32) => {
+      let anon: extern "C" fn() -> f32 = std::mem::transmute(ptr);
+      Value::Num(anon() as f64)
+    }
+    (Annotation::Tuple(ints), _) => {
+      let anon: extern "C" fn(*mut u64) = std::mem::transmute(ptr);
+      let mut memory = vec![0u64; ints.len()];
+      anon(memory.as_mut_ptr());
+      let elems = memory
+        .into_iter()
+        .zip(ints)
+        .map(|(bits, int)| match (int, precision) {
+          (true, _) => Value::Int(bits as i64),
+          (false, Precision::F64) => Value::Num(f64::from_bits(bits)),
+          (false, Precision::F32) => Value::Num(f32::from_bits(bits as u32) as f64),
+        });
+      Value::Tuple(elems.collect())
+    }
+  }
+}
+
+/// CraneliftBackend - compiles programs with Cranelift to an executable for
+/// the host, as the LLVM `NativeBackend` does, or to an object file to link
+/// with the runtime. The externs link to the runtime and the C math
+/// library, and the C `main` calls the program's `main`, or else its
+/// top-level expressions in order.
+pub struct CraneliftBackend {
+  compiler: Option<Compiler<ObjectModule>>, // until the module is finished
+  emit: Emit,
+  dump: Option<Box<dyn Write>>, // where the CLIF of each function goes
+  functions: Vec<(String, FuncId, Option<usize>)>, // those defined, in order
+}
+
+impl CraneliftBackend {
+  /// A backend that optimizes the code for speed unless `optimize` is
+  /// false.
+  pub fn new(precision: Precision, optimize: bool, emit: Emit) -> Result<Self, Diagnostic> {
+    Ok(Self {
+      compiler: Some(Compiler::new_object("kale", precision, optimize)?),
+      emit,
+      dump: None,
+      functions: vec![],
+    })
+  }
+
+  /// Writes the CLIF of each function to `out`, before and after its
+  /// optimization.
+  pub fn set_dump(&mut self, out: impl Write + 'static) {
+    self.compiler().set_dump(true);
+    self.dump = Some(Box::new(out));
+  }
+
+  fn compiler(&mut self) -> &mut Compiler<ObjectModule> {
+    self.compiler.as_mut().expect("the module isn't finished")
+  }
+}
+
+impl Backend for CraneliftBackend {
+  fn name(&self) -> &'static str {
+    "Cranelift"
+  }
+
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+    let compiler = self.compiler();
+    match decl {
+      Declaration::Extern => compiler.compile_proto(proto).map(|_| ()),
+      Declaration::Function { tuple } => {
+        compiler
+          .tuples
+          .extend(tuple.map(|n| (proto.name.clone(), n)));
+        compiler.declare(proto).map(|_| ())
+      }
+    }
+  }
+
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+    let compiler = self.compiler();
+    let id = compiler.compile_func(func)?;
+    let name = &func.proto.name;
+    // top-level expressions store their tuples through a pointer
+    let memory = match name.is_empty() {
+      true => compiler.tuples.get("").copied(),
+      false => None,
+    };
+    let dumps = compiler.take_dumps();
+    write_dumps(&mut self.dump, dumps);
+    self.functions.push((name.clone(), id, memory));
+    Ok(())
+  }
+
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>> {
+    let mut compiler = self.compiler.take().expect("the module isn't finished");
+    let called: Vec<_> = self
+      .functions
+      .iter()
+      .filter(|(name, ..)| entry.calls(name))
+      .map(|&(_, id, memory)| (id, memory))
+      .collect();
+    compiler.compile_main(&called).map_err(|e| vec![e])?;
+    let object = compiler.finish().map_err(|e| vec![e])?;
+    let write = |path: &Path| {
+      std::fs::write(path, &object)
+        .map_err(|e| codegen_error(format!("Cannot write `{}`: {}", path.display(), e)))
+    };
+    if self.emit == Emit::Object {
+      return write(output).map_err(|e| vec![e]);
+    }
+    let dir = scratch_dir().map_err(|e| vec![e])?;
+    let path = dir.join("program.o");
+    let res = write(&path).and_then(|_| link(&path, &dir.join("runtime.c"), output));
+    let _ = std::fs::remove_dir_all(&dir);
+    res.map_err(|e| vec![e])
+  }
+}
+
+/// The functions native code calls by name: those of the runtime, and
+/// those of the prelude's math, which Rust computes with doubles as the
+/// interpreter does, whatever the precision. `%` calls `fmod`, and the
+/// operations on ints that fail call `kale_int_error`.
+fn symbols() -> [(&'static str, *const u8); 18] {
+  extern "C" fn sin(x: f64) -> f64 {
+    x.sin()
+  }
+  extern "C" fn cos(x: f64) -> f64 {
+    x.cos()
+  }
+  extern "C" fn exp(x: f64) -> f64 {
+    x.exp()
+  }
+  extern "C" fn log(x: f64) -> f64 {
+    x.ln()
+  }
+  extern "C" fn sqrt(x: f64) -> f64 {
+    x.sqrt()
+  }
+  extern "C" fn pow(x: f64, y: f64) -> f64 {
+    x.powf(y)
+  }
+  extern "C" fn abs(x: f64) -> f64 {
+    x.abs()
+  }
+  extern "C" fn floor(x: f64) -> f64 {
+    x.floor()
+  }
+  extern "C" fn min(x: f64, y: f64) -> f64 {
+    x.min(y)
+  }
+  extern "C" fn max(x: f64, y: f64) -> f64 {
+    x.max(y)
+  }
+  extern "C" fn fmod(x: f64, y: f64) -> f64 {
+    x % y
+  }
+  [
+    ("printd", runtime::printd as *const u8),
+    ("putchard", runtime::putchard as *const u8),
+    ("readd", runtime::readd as *const u8),
+    ("rand", runtime::rand as *const u8),
+    ("srand", runtime::srand as *const u8),
+    ("sin", sin as *const u8),
+    ("cos", cos as *const u8),
+    ("exp", exp as *const u8),
+    ("log", log as *const u8),
+    ("sqrt", sqrt as *const u8),
+    ("pow", pow as *const u8),
+    ("abs", abs as *const u8),
+    ("floor", floor as *const u8),
+    ("min", min as *const u8),
+    ("max", max as *const u8),
+    ("fmod", fmod as *const u8),
+    ("kale_int_error", runtime::kale_int_error as *const u8),
+    ("kale_failed", runtime::kale_failed as *const u8),
+  ]
+}
+
+/// Whether the extern `symbol` is one of [`symbols`], or else a function of
+/// this process, as of the C library, that the JIT can link to.
+fn resolvable(symbol: &str) -> bool {
+  symbols().iter().any(|(name, _)| *name == symbol) || linkable(symbol)
+}
+
+/// Compiler - lowers functions and externs to the CLIF of a JIT module, or
+/// of an object file, as the LLVM `Compiler` lowers them to LLVM IR: ints
+/// are `i64`s, checked as in the interpreter, numbers are doubles, or
+/// 32-bit floats in `F32` precision, and comparisons yield 1.0 or 0.0. A
+/// function that returns a tuple returns its elements as several values,
+/// except a top-level expression, which stores them through the pointer it
+/// takes.
+///
+/// An operation on ints that fails reports it to the runtime, then returns
+/// from its function and every function calling it, which checks whether
+/// the runtime was told of a failure after each call.
+pub struct Compiler<M = JITModule> {
+  module: M,
+  ctx: Context,
+  builder_ctx: FunctionBuilderContext,
+  float: Type,
+  precision: Precision,
+  functions: HashMap<String, Callee>, // by the name programs call them
+  tuples: HashMap<String, usize>,     // the arity of those returning tuples
+  imports: HashSet<FuncId>,           // the externs called, to link
+  anonymous: usize,                   // the top-level expressions compiled
+  dump: bool,
+  dumps: Vec<IrDump>,
+}
+
+/// A function as programs call it.
+#[derive(Clone, Copy)]
+struct Callee {
+  id: FuncId,
+  tuple: Option<usize>, // the arity of the tuple it returns
+  widen: bool,          // takes and returns doubles whatever the precision
+}
+
+/// How the function being compiled returns.
+enum Ret {
+  Values(Annotation),           // a number, an int, or the elements of a tuple
+  Memory(ir::Value, Vec<bool>), // a tuple, stored through a pointer
+}
+
+/// The value of an expression: a number, an int, or the numbers and ints
+/// of a tuple.
+#[derive(Clone)]
+enum Val {
+  Num(ir::Value),
+  Int(ir::Value),
+  Tuple(Vec<Val>),
+}
+
+impl Val {
+  /// The value of a number or an int.
+  fn scalar(&self) -> ir::Value {
+    match self {
+      Val::Num(n) | Val::Int(n) => *n,
+      Val::Tuple(_) => unreachable!("tuples are lowered element by element"),
+    }
+  }
+
+  /// The values it is lowered to, one for each element of a tuple.
+  fn values(&self) -> Vec<ir::Value> {
+    match self {
+      Val::Tuple(elems) => elems.iter().map(Val::scalar).collect(),
+      val => vec![val.scalar()],
+    }
+  }
+
+  fn describe(&self) -> &'static str {
+    match self {
+      Val::Num(_) => "a number",
+      Val::Int(_) => "an int",
+      Val::Tuple(_) => "a tuple",
+    }
+  }
+
+  /// The type of both `self` and `other`, if they have one: an int if both
+  /// are, or else a number, or a tuple of the same size, joined
+  /// elementwise.
+  fn join(&self, other: &Val) -> Option<Annotation> {
+    match (self, other) {
+      (Val::Int(_), Val::Int(_)) => Some(Annotation::Int),
+      (Val::Tuple(a), Val::Tuple(b)) if a.len() == b.len() => {
+        let ints = a.iter().zip(b);
+        Some(Annotation::Tuple(
+          ints
+            .map(|(a, b)| matches!((a, b), (Val::Int(_), Val::Int(_))))
+            .collect(),
+        ))
+      }
+      (Val::Tuple(_), _) | (_, Val::Tuple(_)) => None,
+      _ => Some(Annotation::Num),
+    }
+  }
+}
+
+/// A name in scope: the value it is bound to, or a mutable variable, with
+/// the type of the values it holds.
+#[derive(Clone)]
+enum Local {
+  Val(Val),
+  Var(Variable, Type),
+}
+
+fn codegen_error(msg: String) -> Diagnostic {
+  Diagnostic::error(Span::default(), msg).with_code("codegen")
+}
+
+/// The ISA of the host, for code optimized for speed unless `optimize` is
+/// false, and position-independent if `pic`, as executables are.
+fn host_isa(optimize: bool, pic: bool) -> Result<OwnedTargetIsa, Diagnostic> {
+  let mut flags = settings::builder();
+  let opt_level = if optimize { "speed" } else { "none" };
+  let settings = [
+    ("opt_level", opt_level),
+    ("enable_multi_ret_implicit_sret", "true"),
+    ("use_colocated_libcalls", "false"),
+    ("is_pic", if pic { "true" } else { "false" }),
+  ];
+  for (name, value) in settings {
+    flags
+      .set(name, value)
+      .map_err(|e| codegen_error(e.to_string()))?;
+  }
+  cranelift_native::builder()
+    .map_err(|e| codegen_error(format!("Cranelift doesn't support this machine: {}", e)))?
+    .finish(settings::Flags::new(flags))
+    .map_err(|e| codegen_error(e.to_string()))
+}
+
+/// Linking - a module that compiled code goes to, which links the externs
+/// of programs in its own way.
+pub trait Linking: Module {
+  /// The symbol that the extern `symbol` links to, and whether it takes
+  /// and returns doubles whatever the precision.
+  fn link_name(symbol: &str, precision: Precision) -> (String, bool);
+
+  /// The symbol of the function of the program named `name`.
+  fn function_name(name: &str) -> &str {
+    name
+  }
+}
+
+impl Linking for JITModule {
+  /// The externs link to [`symbols`], or to the functions of this process.
+  fn link_name(symbol: &str, _: Precision) -> (String, bool) {
+    let widen = symbols().iter().any(|(name, _)| *name == symbol);
+    (symbol.to_string(), widen)
+  }
+}
+
+impl Linking for ObjectModule {
+  /// The externs link to the runtime and the C math library, as those of
+  /// LLVM do, and `fmod` takes doubles. The program's `main` is
+  /// `__kale_main`, to make room for the C `main`.
+  fn link_name(symbol: &str, precision: Precision) -> (String, bool) {
+    match symbol {
+      "fmod" => (symbol.to_string(), true),
+      _ => link_name(symbol, precision),
+    }
+  }
+
+  fn function_name(name: &str) -> &str {
+    match name {
+      "main" => "__kale_main",
+      name => name,
+    }
+  }
+}
+
+impl Compiler<JITModule> {
+  /// A compiler for the host, which optimizes the code for speed unless
+  /// `optimize` is false.
+  pub fn new(precision: Precision, optimize: bool) -> Result<Self, Diagnostic> {
+    let isa = host_isa(optimize, false)?;
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    for (name, ptr) in symbols() {
+      builder.symbol(name, ptr);
+    }
+    Ok(Self::with_module(JITModule::new(builder), precision))
+  }
+
+  /// Links the functions compiled, for them to be called, unless an extern
+  /// that they call can't be found.
+  pub fn finalize(&mut self) -> Result<(), Diagnostic> {
+    let declarations = self.module.declarations();
+    for &id in &self.imports {
+      let symbol = declarations.get_function_decl(id).linkage_name(id);
+      if !resolvable(&symbol) {
+        let msg = format!("Cannot find the extern `{}` to link to", symbol);
+        return Err(codegen_error(msg));
+      }
+    }
+    self
+      .module
+      .finalize_definitions()
+      .map_err(|e| module_error(e, Span::default()))
+  }
+
+  /// Frees the code of the module, whose functions mustn't be called
+  /// anymore.
+  pub fn free(self) {
+    unsafe { self.module.free_memory() }
+  }
+}
+
+impl Compiler<ObjectModule> {
+  /// A compiler of the object file `name` for the host, which optimizes
+  /// the code for speed unless `optimize` is false.
+  pub fn new_object(name: &str, precision: Precision, optimize: bool) -> Result<Self, Diagnostic> {
+    let isa = host_isa(optimize, true)?;
+    let builder = ObjectBuilder::new(isa, name, default_libcall_names())
+      .map_err(|e| codegen_error(e.to_string()))?;
+    Ok(Self::with_module(ObjectModule::new(builder), precision))
+  }
+
+  /// Adds the C `main` of an executable, which calls `functions` in order,
+  /// discarding what they return, then returns 0. Each is given with the
+  /// number of values it stores through the pointer it takes, if it does.
+  pub fn compile_main(&mut self, functions: &[(FuncId, Option<usize>)]) -> Result<(), Diagnostic> {
+    let mut sig = self.module.make_signature();
+    sig.returns.push(AbiParam::new(types::I32));
+    let main = self
+      .module
+      .declare_function("main", Linkage::Export, &sig)
+      .map_err(|e| module_error(e, Span::default()))?;
+    self.ctx.func.signature = sig;
+    self.ctx.func.name = UserFuncName::user(0, main.as_u32());
+    let ptr = self.module.target_config().pointer_type();
+    let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
+    let entry = builder.create_block();
+    builder.switch_to_block(entry);
+    for &(id, memory) in functions {
+      let mut args = vec![];
+      if let Some(n) = memory {
+        let size = n as u32 * 8;
+        let slot = StackSlotData::new(StackSlotKind::ExplicitSlot, size, 3);
+        let slot = builder.create_sized_stack_slot(slot);
+        args.push(builder.ins().stack_addr(ptr, slot, 0));
+      }
+      let func = self.module.declare_func_in_func(id, builder.func);
+      builder.ins().call(func, &args);
+    }
+    let zero = builder.ins().iconst(types::I32, 0);
+    builder.ins().return_(&[zero]);
+    builder.seal_all_blocks();
+    builder.finalize();
+    let res = self
+      .module
+      .define_function(main, &mut self.ctx)
+      .map_err(|e| module_error(e, Span::default()));
+    self.module.clear_context(&mut self.ctx);
+    res
+  }
+
+  /// The object file of the functions compiled.
+  pub fn finish(self) -> Result<Vec<u8>, Diagnostic> {
+    let product = self.module.finish();
+    product.emit().map_err(|e| codegen_error(e.to_string()))
+  }
+}
+
+impl<M: Linking> Compiler<M> {
+  fn with_module(module: M, precision: Precision) -> Self {
+    Self {
+      ctx: module.make_context(),
+      module,
+      builder_ctx: FunctionBuilderContext::new(),
+      float: match precision {
+        Precision::F64 => types::F64,
+        Precision::F32 => types::F32,
+      },
+      precision,
+      functions: HashMap::new(),
+      tuples: HashMap::new(),
+      imports: HashSet::new(),
+      anonymous: 0,
+      dump: false,
+      dumps: vec![],
+    }
+  }
+
+  /// Keeps the CLIF of the functions compiled from now on, before and
+  /// after their optimization, until taken by [`Compiler::take_dumps`].
+  pub fn set_dump(&mut self, dump: bool) {
+    self.dump = dump;
+  }
+
+  pub fn take_dumps(&mut self) -> Vec<IrDump> {
+    std::mem::take(&mut self.dumps)
+  }
+
+  /// Compiles the functions and externs of `module`, declaring them all
+  /// first so that bodies may call the functions defined further down.
+  /// Every function is compiled even when another one fails, and the
+  /// errors are reported in order.
+  pub fn compile_module(&mut self, module: &ModuleAst) -> Result<Vec<FuncId>, Vec<Diagnostic>> {
+    self.tuples.extend(tuple_arities(module));
+    let mut functions = vec![];
+    let mut errors = vec![];
+    for item in &module.items {
+      let res = match item {
+        Ast::Proto(proto) => self.compile_proto(proto).map(|_| ()),
+        Ast::Func(func) if !func.proto.name.is_empty() => self.declare(&func.proto).map(|_| ()),
+        _ => Ok(()),
+      };
+      errors.extend(res.err());
+    }
+    for item in &module.items {
+      let res = match item {
+        Ast::Func(func) => self.compile_func(func),
+        Ast::Expr(expr) => {
+          let Ast::Func(func) = Ast::new_top_level(expr.clone(), Span::default()) else {
+            unreachable!()
+          };
+          self.compile_func(&func)
+        }
+        Ast::Proto(_) => continue,
+        item => Err(unsupported_item("Cranelift", item)),
+      };
+      match res {
+        Ok(id) => functions.push(id),
+        Err(e) => errors.push(e),
+      }
+    }
+    match errors.is_empty() {
+      true => Ok(functions),
+      false => Err(errors),
+    }
+  }
+
+  /// Declares the extern `proto`, which links to the function it stands
+  /// for, as the module does.
+  pub fn compile_proto(&mut self, proto: &ProtoAst) -> Result<FuncId, Diagnostic> {
+    let callee = declare_extern(&mut self.module, self.float, self.precision, proto)?;
+    self.functions.insert(proto.name.clone(), callee);
+    Ok(callee.id)
+  }
+
+  /// Compiles the function `func`, or the top-level expression it wraps,
+  /// which is named `__anon_expr`, numbered when there are several.
+  pub fn compile_func(&mut self, func: &FuncAst) -> Result<FuncId, Diagnostic> {
+    let proto = &func.proto;
+    match tuple_arity(&func.body, &self.tuples) {
+      Some(n) => {
+        self.tuples.insert(proto.name.clone(), n);
+      }
+      // a top-level expression doesn't return the tuple of the previous one
+      None if proto.name.is_empty() => {
+        self.tuples.remove("");
+      }
+      None => (),
+    }
+    let callee = self.declare(proto)?;
+    let mut body = func.body.clone();
+    body.lower_matches();
+    let res = self.lower(callee, proto, &body).and_then(|_| {
+      let before = self.dump.then(|| self.ctx.func.display().to_string());
+      self
+        .module
+        .define_function(callee.id, &mut self.ctx)
+        .map_err(|e| module_error(e, proto.span))?;
+      if let Some(before) = before {
+        let decl = self.module.declarations().get_function_decl(callee.id);
+        self.dumps.push(IrDump {
+          function: decl.linkage_name(callee.id).into_owned(),
+          before,
+          after: self.ctx.func.display().to_string(),
+        });
+      }
+      Ok(callee.id)
+    });
+    self.module.clear_context(&mut self.ctx);
+    if res.is_err() {
+      // a builder that stopped halfway leaves its context to be cleared
+      self.builder_ctx = FunctionBuilderContext::new();
+    }
+    res
+  }
+
+  /// Declares the function `proto` defines, with no body yet, taking and
+  /// returning ints where the checker annotates them, and numbers
+  /// elsewhere.
+  fn declare(&mut self, proto: &ProtoAst) -> Result<Callee, Diagnostic> {
+    let tuple = self.tuples.get(&proto.name).copied();
+    let mut sig = self.module.make_signature();
+    sig.params = (proto.arg_tys.iter())
+      .map(|ty| Annotation::of(ty.as_deref()) == Annotation::Int)
+      .map(|int| AbiParam::new(scalar_type(self.float, int)))
+      .collect();
+    let ret = Annotation::returned(proto, tuple);
+    let returns = match &ret {
+      Annotation::Tuple(ints) => ints.clone(),
+      ret => vec![*ret == Annotation::Int],
+    };
+    let returns = returns
+      .into_iter()
+      .map(|int| AbiParam::new(scalar_type(self.float, int)));
+    let name = match proto.name.as_str() {
+      "" => {
+        match ret {
+          Annotation::Tuple(_) => {
+            let ptr = self.module.target_config().pointer_type();
+            sig.params.insert(0, AbiParam::new(ptr));
+          }
+          _ => sig.returns.extend(returns),
+        }
+        self.anonymous += 1;
+        match self.anonymous {
+          1 => "__anon_expr".to_string(),
+          n => format!("__anon_expr.{}", n - 1),
+        }
+      }
+      name => {
+        sig.returns.extend(returns);
+        M::function_name(name).to_string()
+      }
+    };
+    let id = self
+      .module
+      .declare_function(&name, Linkage::Export, &sig)
+      .map_err(|e| module_error(e, proto.span))?;
+    let callee = Callee {
+      id,
+      tuple,
+      widen: false,
+    };
+    if !proto.name.is_empty() {
+      self.functions.insert(proto.name.clone(), callee);
+    }
+    Ok(callee)
+  }
+
+  /// Lowers `body`, the body of `proto`, into the function of the context.
+  fn lower(&mut self, callee: Callee, proto: &ProtoAst, body: &ExprAst) -> Result<(), Diagnostic> {
+    let decl = self.module.declarations().get_function_decl(callee.id);
+    self.ctx.func.signature = decl.signature.clone();
+    self.ctx.func.name = UserFuncName::user(0, callee.id.as_u32());
+    let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    let mut params = builder.block_params(entry).to_vec();
+    let ret = match (
+      proto.name.as_str(),
+      Annotation::returned(proto, callee.tuple),
+    ) {
+      ("", Annotation::Tuple(ints)) => Ret::Memory(params.remove(0), ints),
+      (_, ret) => Ret::Values(ret),
+    };
+    let mut lowering = Lowering {
+      builder,
+      module: &mut self.module,
+      functions: &mut self.functions,
+      imports: &mut self.imports,
+      float: self.float,
+      precision: self.precision,
+      scope: vec![],
+      vars: 0,
+      ret,
+    };
+    // the parameters assigned to are variables
+    for (name, param) in proto.args.iter().zip(params) {
+      let val = lowering.val_of(param);
+      let local = match assigns(body, name) {
+        true => lowering.declare_var(val),
+        false => Local::Val(val),
+      };
+      lowering.scope.push((name.clone(), local));
+    }
+    let val = lowering.lower_expr(body, proto.span)?;
+    lowering.build_return(val, proto.span)?;
+    lowering.builder.seal_all_blocks();
+    lowering.builder.finalize();
+    Ok(())
+  }
+}
+
+/// Declares the extern `proto` in `module`, taking and returning numbers of
+/// type `float` unless what it links to takes doubles.
+fn declare_extern<M: Linking>(
+  module: &mut M,
+  float: Type,
+  precision: Precision,
+  proto: &ProtoAst,
+) -> Result<Callee, Diagnostic> {
+  let (symbol, widen) = M::link_name(proto.symbol(), precision);
+  let float = if widen { types::F64 } else { float };
+  let mut sig = module.make_signature();
+  sig.params = vec![AbiParam::new(float); proto.args.len()];
+  sig.returns = vec![AbiParam::new(float)];
+  let id = module
+    .declare_function(&symbol, Linkage::Import, &sig)
+    .map_err(|e| module_error(e, proto.span))?;
+  Ok(Callee {
+    id,
+    tuple: None,
+    widen,
+  })
+}
+
+fn module_error(e: ModuleError, span: Span) -> Diagnostic {
+  let msg = format!("Cranelift rejected the code generated: {}", e);
+  Diagnostic::error(span, msg).with_code("codegen")
+}
+
+/// Lowering - the state of the compilation of one function.
+struct Lowering<'a, M> {
+  builder: FunctionBuilder<'a>,
+  module: &'a mut M,
+  functions: &'a mut HashMap<String, Callee>,
+  imports: &'a mut HashSet<FuncId>,
+  float: Type,
+  precision: Precision,
+  scope: Vec<(String, Local)>,
+  vars: usize, // the variables declared
+  ret: Ret,
+}
+
+impl<M: Linking> Lowering<'_, M> {
+  fn num(&mut self, n: f64) -> ir::Value {
+    match self.precision {
+      Precision::F64 => self.builder.ins().f64const(n),
+      Precision::F32 => self.builder.ins().f32const(n as f32),
+    }
+  }
+
+  /// 1.0 for the true `i8` condition `cond`, 0.0 for false.
+  fn bool_to_num(&mut self, cond: ir::Value) -> ir::Value {
+    let (one, zero) = (self.num(1.0), self.num(0.0));
+    self.builder.ins().select(cond, one, zero)
+  }
+
+  /// A number or an int, as the type of `val` says.
+  fn val_of(&self, val: ir::Value) -> Val {
+    match self.builder.func.dfg.value_type(val) {
+      types::I64 => Val::Int(val),
+      _ => Val::Num(val),
+    }
+  }
+
+  /// The type of numbers, or of ints if `int`.
+  fn scalar_type(&self, int: bool) -> Type {
+    scalar_type(self.float, int)
+  }
+
+  /// Returns `val` from the function being compiled, through the pointer it
+  /// takes if it stores a tuple, its ints converted to the numbers the
+  /// function returns in their place. Each element of a tuple in memory
+  /// takes 8 bytes, whatever its type.
+  fn build_return(&mut self, val: Val, span: Span) -> Result<(), Diagnostic> {
+    let (ret, memory) = match &self.ret {
+      Ret::Values(ret) => (ret.clone(), None),
+      Ret::Memory(ptr, ints) => (Annotation::Tuple(ints.clone()), Some(*ptr)),
+    };
+    let val = match (val, &ret) {
+      (Val::Num(_), Annotation::Int) => {
+        let msg = "Function returns an int, found a number";
+        return Err(Diagnostic::error(span, msg).with_code("codegen"));
+      }
+      (val, ret) => self.convert(val, ret, span).map_err(|_| {
+        let msg = "Function returns both numbers and tuples, or tuples of different sizes";
+        Diagnostic::error(span, msg).with_code("codegen")
+      })?,
+    };
+    match (val, memory) {
+      (Val::Tuple(elems), Some(ptr)) => {
+        for (i, elem) in elems.iter().enumerate() {
+          let elem = elem.scalar();
+          let offset = i as i32 * 8;
+          (self.builder.ins()).store(MemFlags::trusted(), elem, ptr, offset);
+        }
+        self.builder.ins().return_(&[]);
+      }
+      (val, _) => {
+        let vals = val.values();
+        self.builder.ins().return_(&vals);
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns from the function being compiled if `failed`, once the
+  /// runtime is told of the operation on ints that failed, if it is given
+  /// one with its operands, then goes on lowering where it didn't fail.
+  /// What the function returns then is never used.
+  fn build_failure(
+    &mut self,
+    failed: ir::Value,
+    op: Option<(IntOp, ir::Value, ir::Value)>,
+  ) -> Result<(), Diagnostic> {
+    let fail = self.builder.create_block();
+    let cont = self.builder.create_block();
+    self.builder.set_cold_block(fail);
+    self.builder.ins().brif(failed, fail, &[], cont, &[]);
+    self.builder.switch_to_block(fail);
+    if let Some((op, lhs, rhs)) = op {
+      let report = self.runtime_function("kale_int_error")?;
+      let op = self.builder.ins().iconst(types::I32, op as i64);
+      self.builder.ins().call(report, &[op, lhs, rhs]);
+    }
+    let returns = self.builder.func.signature.returns.clone();
+    let zeros: Vec<_> = returns
+      .iter()
+      .map(|ret| match ret.value_type {
+        types::I64 => self.builder.ins().iconst(types::I64, 0),
+        _ => self.num(0.0),
+      })
+      .collect();
+    self.builder.ins().return_(&zeros);
+    self.builder.switch_to_block(cont);
+    Ok(())
+  }
+
+  /// Returns from the function being compiled if the function of the
+  /// module it just called failed.
+  fn check_call(&mut self) -> Result<(), Diagnostic> {
+    let failed = self.runtime_function("kale_failed")?;
+    let call = self.builder.ins().call(failed, &[]);
+    let failed = self.builder.inst_results(call)[0];
+    let failed = (self.builder.ins()).icmp_imm(IntCC::NotEqual, failed, 0);
+    self.build_failure(failed, None)
+  }
+
+  /// The function `kale_int_error` or `kale_failed` of the runtime.
+  fn runtime_function(&mut self, name: &str) -> Result<ir::FuncRef, Diagnostic> {
+    let mut sig = self.module.make_signature();
+    match name {
+      "kale_int_error" => {
+        let params = [types::I32, types::I64, types::I64];
+        sig.params = params.into_iter().map(AbiParam::new).collect();
+      }
+      _ => sig.returns.push(AbiParam::new(types::I32)),
+    }
+    let id = self
+      .module
+      .declare_function(name, Linkage::Import, &sig)
+      .map_err(|e| module_error(e, Span::default()))?;
+    self.imports.insert(id);
+    Ok(self.module.declare_func_in_func(id, self.builder.func))
+  }
+
+  fn lower_expr(&mut self, expr: &ExprAst, span: Span) -> Result<Val, Diagnostic> {
+    match expr {
+      ExprAst::NumAst(n) => Ok(Val::Num(self.num(*n))),
+      ExprAst::IntAst(i) => Ok(Val::Int(self.builder.ins().iconst(types::I64, *i))),
+      ExprAst::BoolAst(b) => Ok(Val::Num(self.num(*b as i32 as f64))),
+      ExprAst::UnitAst => Ok(Val::Num(self.num(0.0))),
+      ExprAst::VarAst(name, at) => match self.scope.iter().rev().find(|(n, _)| n == name) {
+        Some((_, Local::Val(val))) => Ok(val.clone()),
+        Some((_, Local::Var(var, _))) => {
+          let var = *var;
+          let val = self.builder.use_var(var);
+          Ok(self.val_of(val))
+        }
+        None if self.functions.contains_key(name) => {
+          Err(unsupported("Cranelift", "functions as values", *at))
+        }
+        None => Err(unsupported("Cranelift", "global variables", *at)),
+      },
+      ExprAst::UnaryAst(op, operand, at) => {
+        let operand = self.lower_scalar(operand, *at)?;
+        self.lower_unary(*op, operand)
+      }
+      ExprAst::BinAst(lhs, op, rhs, at) => self.lower_bin(lhs, *op, rhs, *at),
+      ExprAst::CallAst(name, args, at) => self.lower_call(name, args, *at),
+      ExprAst::IfAst { cond, then, els } => self.lower_if(cond, then, els, span),
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        let mut val = Val::Num(self.num(0.0));
+        for expr in exprs {
+          val = self.lower_expr(expr, span)?;
+        }
+        Ok(val)
+      }
+      ExprAst::TupleAst(elems) => {
+        let elems: Result<Vec<_>, _> = elems
+          .iter()
+          .map(|elem| self.lower_scalar(elem, span))
+          .collect();
+        Ok(Val::Tuple(elems?))
+      }
+      ExprAst::ElemAst(tuple, i) => match self.lower_expr(tuple, span)? {
+        Val::Tuple(elems) if *i < elems.len() => Ok(elems[*i].clone()),
+        _ => {
+          Err(Diagnostic::error(span, format!("No element {} in tuple", i)).with_code("codegen"))
+        }
+      },
+      ExprAst::LetAst(bindings, body) => {
+        let depth = self.scope.len();
+        for (name, _, init) in bindings {
+          let val = self.lower_expr(init, span)?;
+          self.scope.push((name.clone(), Local::Val(val)));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let Val::Tuple(elems) = self.lower_expr(init, span)? else {
+          let msg = format!("Cannot destructure a number into {} names", names.len());
+          return Err(Diagnostic::error(span, msg).with_code("codegen"));
+        };
+        if elems.len() != names.len() {
+          let msg = format!(
+            "Cannot destructure a tuple of {} into {} names",
+            elems.len(),
+            names.len()
+          );
+          return Err(Diagnostic::error(span, msg).with_code("codegen"));
+        }
+        let depth = self.scope.len();
+        for ((name, _), elem) in names.iter().zip(elems) {
+          self.scope.push((name.clone(), Local::Val(elem)));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      // the code that follows a `return` is unreachable, and takes the
+      // returned value as that of the `return`, which the verifier allows
+      // as it doesn't check the dominance of unreachable blocks
+      ExprAst::ReturnAst(value, at) => {
+        let val = self.lower_expr(value, *at)?;
+        self.build_return(val.clone(), *at)?;
+        let after = self.builder.create_block();
+        self.builder.switch_to_block(after);
+        Ok(val)
+      }
+      ExprAst::MatchAst(..) => unreachable!("`match` is lowered before codegen"),
+      ExprAst::StrAst(_) => Err(unsupported("Cranelift", "strings", span)),
+      ExprAst::ArrayAst(_) | ExprAst::IndexAst(..) => Err(unsupported("Cranelift", "arrays", span)),
+      ExprAst::FieldAst(..) => Err(unsupported("Cranelift", "structs", span)),
+      ExprAst::LambdaAst(..) => Err(unsupported("Cranelift", "closures", span)),
+      ExprAst::FuncRefAst(_, at) => Err(unsupported("Cranelift", "functions as values", *at)),
+      // a variable declared without a value starts at 0, as in the
+      // interpreter
+      ExprAst::VarInAst(vars, body) => {
+        let depth = self.scope.len();
+        let mut res = Ok(());
+        for (name, _, init) in vars {
+          let val = match init {
+            Some(init) => self.lower_scalar(init, span),
+            None => Ok(Val::Num(self.num(0.0))),
+          };
+          match val {
+            Ok(val) => {
+              let var = self.declare_var(val);
+              self.scope.push((name.clone(), var));
+            }
+            Err(e) => {
+              res = Err(e);
+              break;
+            }
+          }
+        }
+        let res = res.and_then(|_| self.lower_expr(body, span));
+        self.scope.truncate(depth);
+        res
+      }
+      ExprAst::AssignAst(name, val, _) => {
+        let val = self.lower_scalar(val, span)?;
+        match self.scope.iter().rev().find(|(n, _)| n == name) {
+          Some((_, Local::Var(var, ty))) => {
+            let (var, ty) = (*var, scalar_annotation(*ty == types::I64));
+            let val = self.convert(val, &ty, span)?;
+            self.builder.def_var(var, val.scalar());
+            Ok(val)
+          }
+          Some((_, Local::Val(_))) => {
+            let msg = format!("Cannot assign to immutable binding `{}`", name);
+            Err(Diagnostic::error(span, msg).with_code("codegen"))
+          }
+          None => Err(unsupported("Cranelift", "global variables", span)),
+        }
+      }
+      ExprAst::TryAst(.., at) => Err(unsupported("Cranelift", "`try`", *at)),
+    }
+  }
+
+  /// A mutable variable, which starts at `val`, a number or an int, and
+  /// holds values of its type.
+  fn declare_var(&mut self, val: Val) -> Local {
+    let val = val.scalar();
+    let var = Variable::from_u32(self.vars as u32);
+    self.vars += 1;
+    let ty = self.builder.func.dfg.value_type(val);
+    self.builder.declare_var(var, ty);
+    self.builder.def_var(var, val);
+    Local::Var(var, ty)
+  }
+
+  /// A number or an int.
+  fn lower_scalar(&mut self, expr: &ExprAst, span: Span) -> Result<Val, Diagnostic> {
+    match self.lower_expr(expr, span)? {
+      Val::Tuple(_) => {
+        Err(Diagnostic::error(span, "Expected a number, found a tuple").with_code("codegen"))
+      }
+      val => Ok(val),
+    }
+  }
+
+  /// `val`, a number or an int, as a number.
+  fn num_of(&mut self, val: Val) -> ir::Value {
+    match val {
+      Val::Int(i) => self.builder.ins().fcvt_from_sint(self.float, i),
+      val => val.scalar(),
+    }
+  }
+
+  /// `val` as a value of type `ty`: ints convert to numbers, the elements
+  /// of tuples too, but numbers don't convert to ints.
+  fn convert(&mut self, val: Val, ty: &Annotation, span: Span) -> Result<Val, Diagnostic> {
+    match (val, ty) {
+      (Val::Int(i), Annotation::Num) => Ok(Val::Num(self.num_of(Val::Int(i)))),
+      (val @ Val::Num(_), Annotation::Num) | (val @ Val::Int(_), Annotation::Int) => Ok(val),
+      (Val::Tuple(elems), Annotation::Tuple(ints)) if elems.len() == ints.len() => {
+        let elems = elems.into_iter().zip(ints);
+        let elems: Result<Vec<_>, _> = elems
+          .map(|(elem, &int)| self.convert(elem, &scalar_annotation(int), span))
+          .collect();
+        Ok(Val::Tuple(elems?))
+      }
+      (val, ty) => {
+        let expected = match ty {
+          Annotation::Int => "an int",
+          Annotation::Tuple(_) => "a tuple",
+          Annotation::Num => "a number",
+        };
+        let msg = format!("Expected {}, found {}", expected, val.describe());
+        Err(Diagnostic::error(span, msg).with_code("codegen"))
+      }
+    }
+  }
+
+  /// A condition, as the `i8` of whether `expr` is true: nonzero, which NaN
+  /// is.
+  fn lower_cond(&mut self, expr: &ExprAst, span: Span) -> Result<ir::Value, Diagnostic> {
+    let cond = match self.lower_scalar(expr, span)? {
+      Val::Int(i) => self.builder.ins().icmp_imm(IntCC::NotEqual, i, 0),
+      val => {
+        let zero = self.num(0.0);
+        (self.builder.ins()).fcmp(FloatCC::NotEqual, val.scalar(), zero)
+      }
+    };
+    Ok(cond)
+  }
+
+  /// `-` or `!` of a number or an int, which fails to negate the least
+  /// int.
+  fn lower_unary(&mut self, op: UnOp, operand: Val) -> Result<Val, Diagnostic> {
+    let cmp = match (op, operand) {
+      (UnOp::Neg, Val::Int(i)) => {
+        let failed = self.builder.ins().icmp_imm(IntCC::Equal, i, i64::MIN);
+        self.build_failure(failed, Some((IntOp::Neg, i, i)))?;
+        return Ok(Val::Int(self.builder.ins().ineg(i)));
+      }
+      (UnOp::Neg, operand) => return Ok(Val::Num(self.builder.ins().fneg(operand.scalar()))),
+      (UnOp::Not, Val::Int(i)) => self.builder.ins().icmp_imm(IntCC::Equal, i, 0),
+      (UnOp::Not, operand) => {
+        let zero = self.num(0.0);
+        (self.builder.ins()).fcmp(FloatCC::Equal, operand.scalar(), zero)
+      }
+    };
+    Ok(Val::Num(self.bool_to_num(cmp)))
+  }
+
+  fn lower_bin(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Val, Diagnostic> {
+    let overload = format!("binary{}", op.as_str());
+    if self.functions.contains_key(&overload) {
+      return self.lower_call(&overload, &[lhs.clone(), rhs.clone()], span);
+    }
+    if let BinOp::And | BinOp::Or = op {
+      return self.lower_logical(lhs, op, rhs, span);
+    }
+    let l = self.lower_scalar(lhs, span)?;
+    let r = self.lower_scalar(rhs, span)?;
+    let (l, r) = match (l, r) {
+      (Val::Int(l), Val::Int(r)) => return self.lower_int_bin(l, op, r),
+      _ if op.is_bitwise() => {
+        let msg = format!("Operator `{}` takes ints, found numbers", op.as_str());
+        return Err(Diagnostic::error(span, msg).with_code("codegen"));
+      }
+      (l, r) => (self.num_of(l), self.num_of(r)),
+    };
+    let ins = self.builder.ins();
+    let cc = match op {
+      BinOp::Add => return Ok(Val::Num(ins.fadd(l, r))),
+      BinOp::Sub => return Ok(Val::Num(ins.fsub(l, r))),
+      BinOp::Mul => return Ok(Val::Num(ins.fmul(l, r))),
+      BinOp::Div => return Ok(Val::Num(ins.fdiv(l, r))),
+      BinOp::Rem => {
+        let fmod = ProtoAst {
+          name: "fmod".to_string(),
+          span,
+          args: vec!["x".to_string(), "y".to_string()],
+          arg_spans: vec![span, span],
+          arg_tys: vec![None, None],
+          ret_ty: None,
+        };
+        let fmod = declare_extern(self.module, self.float, self.precision, &fmod)?;
+        return Ok(Val::Num(self.call(fmod, &[l, r])?[0]));
+      }
+      BinOp::Lt => FloatCC::LessThan,
+      BinOp::Gt => FloatCC::GreaterThan,
+      BinOp::Le => FloatCC::LessThanOrEqual,
+      BinOp::Ge => FloatCC::GreaterThanOrEqual,
+      BinOp::Eq => FloatCC::Equal,
+      BinOp::Ne => FloatCC::NotEqual,
+      _ => unreachable!(),
+    };
+    let cmp = self.builder.ins().fcmp(cc, l, r);
+    Ok(Val::Num(self.bool_to_num(cmp)))
+  }
+
+  /// An operation on ints, which fails as in the interpreter: on overflow,
+  /// on a division by zero and on a shift by a negative number of bits or
+  /// by 64 or more.
+  fn lower_int_bin(&mut self, l: ir::Value, op: BinOp, r: ir::Value) -> Result<Val, Diagnostic> {
+    let ins = self.builder.ins();
+    let (res, failed) = match op {
+      BinOp::Add => ins.sadd_overflow(l, r),
+      BinOp::Sub => ins.ssub_overflow(l, r),
+      BinOp::Mul => ins.smul_overflow(l, r),
+      // the least int divided by -1 overflows
+      BinOp::Div | BinOp::Rem => {
+        let by_zero = ins.icmp_imm(IntCC::Equal, r, 0);
+        let is_min = self.builder.ins().icmp_imm(IntCC::Equal, l, i64::MIN);
+        let by_minus_one = self.builder.ins().icmp_imm(IntCC::Equal, r, -1);
+        let overflow = self.builder.ins().band(is_min, by_minus_one);
+        let failed = self.builder.ins().bor(by_zero, overflow);
+        self.build_failure(failed, Some((IntOp::of(op).unwrap(), l, r)))?;
+        let res = match op {
+          BinOp::Div => self.builder.ins().sdiv(l, r),
+          _ => self.builder.ins().srem(l, r),
+        };
+        return Ok(Val::Int(res));
+      }
+      BinOp::Shl | BinOp::Shr => {
+        let failed = ins.icmp_imm(IntCC::UnsignedGreaterThanOrEqual, r, 64);
+        self.build_failure(failed, Some((IntOp::of(op).unwrap(), l, r)))?;
+        let res = match op {
+          BinOp::Shl => self.builder.ins().ishl(l, r),
+          _ => self.builder.ins().sshr(l, r),
+        };
+        return Ok(Val::Int(res));
+      }
+      BinOp::BitAnd => return Ok(Val::Int(ins.band(l, r))),
+      BinOp::BitOr => return Ok(Val::Int(ins.bor(l, r))),
+      BinOp::Xor => return Ok(Val::Int(ins.bxor(l, r))),
+      op => {
+        let cc = match op {
+          BinOp::Lt => IntCC::SignedLessThan,
+          BinOp::Gt => IntCC::SignedGreaterThan,
+          BinOp::Le => IntCC::SignedLessThanOrEqual,
+          BinOp::Ge => IntCC::SignedGreaterThanOrEqual,
+          BinOp::Eq => IntCC::Equal,
+          BinOp::Ne => IntCC::NotEqual,
+          _ => unreachable!(),
+        };
+        let cmp = ins.icmp(cc, l, r);
+        return Ok(Val::Num(self.bool_to_num(cmp)));
+      }
+    };
+    self.build_failure(failed, Some((IntOp::of(op).unwrap(), l, r)))?;
+    Ok(Val::Int(res))
+  }
+
+  /// `&&` and `||`, which only evaluate `rhs` when `lhs` doesn't decide.
+  fn lower_logical(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Val, Diagnostic> {
+    let l = self.lower_cond(lhs, span)?;
+    let rhs_block = self.builder.create_block();
+    let merge = self.builder.create_block();
+    let res = self.builder.append_block_param(merge, types::I8);
+    match op {
+      BinOp::And => self.builder.ins().brif(l, rhs_block, &[], merge, &[l]),
+      _ => self.builder.ins().brif(l, merge, &[l], rhs_block, &[]),
+    };
+    self.builder.switch_to_block(rhs_block);
+    let r = self.lower_cond(rhs, span)?;
+    self.builder.ins().jump(merge, &[r]);
+    self.builder.switch_to_block(merge);
+    Ok(Val::Num(self.bool_to_num(res)))
+  }
+
+  fn lower_if(
+    &mut self,
+    cond: &ExprAst,
+    then: &ExprAst,
+    els: &ExprAst,
+    span: Span,
+  ) -> Result<Val, Diagnostic> {
+    let cond = self.lower_cond(cond, span)?;
+    let then_block = self.builder.create_block();
+    let else_block = self.builder.create_block();
+    let merge = self.builder.create_block();
+    self
+      .builder
+      .ins()
+      .brif(cond, then_block, &[], else_block, &[]);
+    // each branch ends in a block of its own, where what it yields converts
+    // to the type of both once known
+    let mut branches = vec![];
+    for (block, branch) in [(then_block, then), (else_block, els)] {
+      self.builder.switch_to_block(block);
+      let val = self.lower_expr(branch, span)?;
+      let end = self.builder.create_block();
+      self.builder.ins().jump(end, &[]);
+      branches.push((val, end));
+    }
+    let Some(ty) = branches[0].0.join(&branches[1].0) else {
+      let msg = "Branches of `if` yield both numbers and tuples, or tuples of different sizes";
+      return Err(Diagnostic::error(span, msg).with_code("codegen"));
+    };
+    for (val, end) in branches {
+      self.builder.switch_to_block(end);
+      let val = self.convert(val, &ty, span)?;
+      self.builder.ins().jump(merge, &val.values());
+    }
+    let ints = match &ty {
+      Annotation::Tuple(ints) => ints.clone(),
+      ty => vec![*ty == Annotation::Int],
+    };
+    for int in ints {
+      self
+        .builder
+        .append_block_param(merge, self.scalar_type(int));
+    }
+    self.builder.switch_to_block(merge);
+    let params = self.builder.block_params(merge).to_vec();
+    let mut vals = params.into_iter().map(|param| self.val_of(param));
+    Ok(match ty {
+      Annotation::Tuple(_) => Val::Tuple(vals.collect()),
+      _ => vals.next_back().unwrap(),
+    })
+  }
+
+  /// A call of a function of the module, which returns if that fails, or
+  /// of an extern, or of `int` and `float`, which convert numbers to ints,
+  /// rounding towards zero, and ints to numbers.
+  fn lower_call(&mut self, name: &str, args: &[ExprAst], span: Span) -> Result<Val, Diagnostic> {
+    if self.scope.iter().any(|(local, _)| local == name) {
+      return Err(unsupported("Cranelift", "closures", span));
+    }
+    let mut vals = vec![];
+    for arg in args {
+      vals.push(self.lower_scalar(arg, span)?);
+    }
+    match (name, &vals[..]) {
+      ("float", [x]) => return Ok(Val::Num(self.num_of(x.clone()))),
+      ("int", [Val::Int(i)]) => return Ok(Val::Int(*i)),
+      ("int", [Val::Num(x)]) => return self.lower_to_int(*x).map(Val::Int),
+      _ => (),
+    }
+    if !self.functions.contains_key(name) {
+      let proto = prelude().items.into_iter().find_map(|item| match item {
+        Ast::Proto(proto) if proto.name == name => Some(proto),
+        _ => None,
+      });
+      if let Some(proto) = proto {
+        let callee = declare_extern(self.module, self.float, self.precision, &proto)?;
+        self.functions.insert(name.to_string(), callee);
+      }
+    }
+    let Some(callee) = self.functions.get(name).copied() else {
+      let what = format!("the builtin `{}`", name);
+      return Err(unsupported("Cranelift", &what, span));
+    };
+    let params = self.module.declarations().get_function_decl(callee.id);
+    let params: Vec<_> = params
+      .signature
+      .params
+      .iter()
+      .map(|param| param.value_type)
+      .collect();
+    let mut args = vec![];
+    for (val, ty) in vals.into_iter().zip(params) {
+      let ty = scalar_annotation(ty == types::I64);
+      args.push(self.convert(val, &ty, span)?.scalar());
+    }
+    let results = self.call(callee, &args)?;
+    let mut vals = results.into_iter().map(|res| self.val_of(res));
+    Ok(match callee.tuple {
+      Some(_) => Val::Tuple(vals.collect()),
+      None => vals.next_back().unwrap(),
+    })
+  }
+
+  /// `int(x)`: `x` rounded towards zero, which fails out of the range of
+  /// ints, as does NaN.
+  fn lower_to_int(&mut self, x: ir::Value) -> Result<ir::Value, Diagnostic> {
+    // i64::MAX rounds up to 2^63, hence the exclusive bound
+    let bound = 2f64.powi(63);
+    let (min, max) = (self.num(-bound), self.num(bound));
+    let ins = self.builder.ins();
+    let above_min = ins.fcmp(FloatCC::GreaterThanOrEqual, x, min);
+    let below_max = self.builder.ins().fcmp(FloatCC::LessThan, x, max);
+    let in_range = self.builder.ins().band(above_min, below_max);
+    let failed = self.builder.ins().icmp_imm(IntCC::Equal, in_range, 0);
+    let double = match self.precision {
+      Precision::F64 => x,
+      Precision::F32 => self.builder.ins().fpromote(types::F64, x),
+    };
+    let bits = (self.builder.ins()).bitcast(types::I64, MemFlags::new(), double);
+    self.build_failure(failed, Some((IntOp::ToInt, bits, bits)))?;
+    Ok(self.builder.ins().fcvt_to_sint_sat(types::I64, x))
+  }
+
+  /// Calls `callee` with `args`, widening them to doubles and narrowing
+  /// what it returns back if it computes with doubles only, then returns
+  /// if it is a function of the module that failed.
+  fn call(&mut self, callee: Callee, args: &[ir::Value]) -> Result<Vec<ir::Value>, Diagnostic> {
+    let widen = callee.widen && self.precision == Precision::F32;
+    let args: Vec<_> = match widen {
+      true => args
+        .iter()
+        .map(|&arg| self.builder.ins().fpromote(types::F64, arg))
+        .collect(),
+      false => args.to_vec(),
+    };
+    let external = self
+      .module
+      .declarations()
+      .get_function_decl(callee.id)
+      .linkage
+      == Linkage::Import;
+    if external {
+      self.imports.insert(callee.id);
+    }
+    let func = self
+      .module
+      .declare_func_in_func(callee.id, self.builder.func);
+    let call = self.builder.ins().call(func, &args);
+    let results = self.builder.inst_results(call).to_vec();
+    if !external {
+      self.check_call()?;
+    }
+    Ok(match widen {
+      true => results
+        .into_iter()
+        .map(|res| self.builder.ins().fdemote(self.float, res))
+        .collect(),
+      false => results,
+    })
+  }
+}
+
+/// The type of numbers of type `float`, or of ints if `int`.
+fn scalar_type(float: Type, int: bool) -> Type {
+  match int {
+    true => types::I64,
+    false => float,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codegen::backend::compile;
+  use crate::lexer::Lexer;
+  use crate::session::Session;
+  use std::io::Cursor;
+  use std::process::Command;
+  use std::rc::Rc;
+
+  fn run(jit: &mut CraneliftJit, src: &'static str) -> Vec<Result<Option<Value>, String>> {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let res = module.items.into_iter().map(|item| jit.run(item));
+    res.map(|res| res.map_err(|e| e.to_string())).collect()
+  }
+
+  #[test]
+  fn cranelift_run() {
+    let mut jit = CraneliftJit::new(Precision::F64);
+    let src = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2); fib(20);
+      def twice(x) x * 2; def f(x) twice(x) + 1; f(1); def twice(x) x * 3; f(1);
+      def minmax(a, b) if a < b then (a, b) else (b, a); minmax(3, 2);
+      sqrt(16) + abs(-1) + min(2, 3); srand(1); rand() == rand(); \"s\"";
+    let num = |n| Ok(Some(Value::Num(n)));
+    let tuple = Value::Tuple(Rc::new([Value::Num(2.0), Value::Num(3.0)]));
+    assert_eq!(
+      run(&mut jit, src),
+      vec![
+        Ok(None),
+        num(6765.0),
+        Ok(None),
+        Ok(None),
+        num(3.0),
+        Ok(None),
+        num(4.0),
+        Ok(None),
+        Ok(Some(tuple)),
+        num(7.0),
+        num(0.0),
+        num(0.0),
+        Err("4:67: The Cranelift backend doesn't support strings".to_string()),
+      ]
+    );
+    let src = "def sign(x) { if x < 0 then return -1 else (); x > 0 };
+      sign(-5) + sign(0) * 10 + sign(3) * 100; 7 % 3 + (0 && 1) + (0 || 2);
+      extern nowhere(x); nowhere(1)";
+    assert_eq!(
+      run(&mut jit, src),
+      vec![
+        Ok(None),
+        num(99.0),
+        num(2.0),
+        Ok(None),
+        Err("Cannot find the extern `nowhere` to link to".to_string()),
+      ]
+    );
+    let mut jit = CraneliftJit::new(Precision::F64);
+    let src = "def even(n) if n == 0 then 1 else odd(n - 1);
+      def odd(n) if n == 0 then 0 else even(n - 1); even(10)";
+    jit.declare(&ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap());
+    assert_eq!(run(&mut jit, src), vec![Ok(None), Ok(None), num(1.0)]);
+    let mut jit = CraneliftJit::new(Precision::F32);
+    let (a, b) = (0.1f32 + 0.2f32, (2.0f64.sqrt() as f32));
+    assert_eq!(
+      run(&mut jit, "(0.1 + 0.2, sqrt(2))"),
+      vec![Ok(Some(Value::Tuple(Rc::new([
+        Value::Num(a as f64),
+        Value::Num(b as f64)
+      ]))))]
+    );
+  }
+
+  #[test]
+  fn cranelift_ints() {
+    // checked, as the interpreter does, for the types it infers
+    let src = "def big(a: int): int a * a + 1; big(94906267); big(3037000500);
+      def halves(a: int) (a, a / 2, a / 2.0); halves(7); int(0.0 / 0.0); -(6 xor 3) << 2;
+      def count(n: int): int var i = 0, s = 10 in { s = s + n; i = s * 2; i - 1 }; count(5);
+      def inc(x) { x = x + 1; x * 2 }; inc(2.5); def fail(n: int): int n % 0; fail(1) + 1";
+    for precision in [Precision::F64, Precision::F32] {
+      let mut session = Session::new();
+      session.set_engine(Some(Box::new(CraneliftJit::new(precision))));
+      let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+      let res = session.run_module(module).into_iter();
+      let res: Vec<_> = res.map(|res| res.map_err(|e| e.to_string())).collect();
+      let halves = [Value::Int(7), Value::Int(3), Value::Num(3.5)];
+      assert_eq!(
+        res,
+        vec![
+          Ok(None),
+          Ok(Some(Value::Int(9007199515875290))),
+          Err("Integer overflow in `3037000500 * 3037000500`".to_string()),
+          Ok(None),
+          Ok(Some(Value::Tuple(Rc::new(halves)))),
+          Err("Cannot convert NaN to int".to_string()),
+          Ok(Some(Value::Int(-20))),
+          Ok(None),
+          Ok(Some(Value::Int(29))),
+          Ok(None),
+          Ok(Some(Value::Num(7.0))),
+          Ok(None),
+          Err("Integer division by zero".to_string()),
+        ]
+      );
+    }
+  }
+
+  #[test]
+  fn cranelift_build() {
+    // the program's `main` makes room for that of C
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a); def main() 1;
+      minmax(2, 1); let (lo, hi) = minmax(5, 4) in printd(hi % lo); printd(sqrt(16) + min(2, 3));
+      printd(0.1 + 0.2)";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let output = std::env::temp_dir().join("kale-cranelift-build");
+    let emit = Emit::Executable;
+    for (precision, expected) in [
+      (Precision::F64, "1.0\n6.0\n0.30000000000000004\n"),
+      (Precision::F32, "1.0\n6.0\n0.30000001192092896\n"),
+    ] {
+      let mut backend = CraneliftBackend::new(precision, true, emit).unwrap();
+      compile(&mut backend, &module, Entry::TopLevel, &output).unwrap();
+      let res = Command::new(&output).output().unwrap();
+      let _ = std::fs::remove_file(&output);
+      assert!(res.status.success());
+      assert_eq!(String::from_utf8(res.stdout).unwrap(), expected);
+    }
+  }
+}
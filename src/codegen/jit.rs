@@ -0,0 +1,295 @@
+use super::llvm::{Compiler, OptLevel, Pass};
+use super::{linkable, unsupported_item, write_dumps, Definitions, Engine};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, FuncAst, ModuleAst};
+use crate::runtime;
+use crate::value::{Precision, Value};
+use inkwell::context::Context;
+use inkwell::execution_engine::ExecutionEngine;
+use inkwell::targets::{InitializationConfig, Target};
+use inkwell::types::BasicTypeEnum;
+use inkwell::values::BasicValue;
+use inkwell::OptimizationLevel;
+use std::io::Write;
+
+/// Jit - runs top-level expressions as native code, compiled by the LLVM
+/// backend along with the functions and externs defined so far, the way
+/// the interpreter runs them.
+///
+/// Each expression is compiled into a module of its own, named
+/// `__anon_expr`, then called and thrown away. Its value is a number, an
+/// int, or a tuple of them: booleans and unit are numbers in native code.
+/// An operation on ints that fails makes it fail with the error of the
+/// interpreter.
+pub struct Jit {
+  context: Context,
+  defs: Definitions,
+  precision: Precision,
+  passes: Vec<Pass>,
+  dump: Option<Box<dyn Write>>, // where the IR of each item goes
+}
+
+impl Jit {
+  pub fn new(precision: Precision) -> Self {
+    Self {
+      context: Context::create(),
+      defs: Definitions::new(),
+      precision,
+      passes: OptLevel::O2.passes().to_vec(),
+      dump: None,
+    }
+  }
+
+  /// Optimizes the code with `passes` instead of those of `-O2`.
+  pub fn set_passes(&mut self, passes: &[Pass]) {
+    self.passes = passes.to_vec();
+  }
+
+  /// Writes the IR of each function and expression compiled from now on to
+  /// `out`, before and after its optimization.
+  pub fn set_dump(&mut self, out: impl Write + 'static) {
+    self.dump = Some(Box::new(out));
+  }
+
+  /// Compiles the definitions with the function or extern `item`,
+  /// keeping the dumps of its IR.
+  fn define(&mut self, item: Ast) -> Result<(), Diagnostic> {
+    let name = match &item {
+      Ast::Func(func) => func.proto.name.clone(),
+      Ast::Proto(proto) => proto.name.clone(),
+      _ => unreachable!(),
+    };
+    let mut dumps = vec![];
+    let (context, precision, passes) = (&self.context, self.precision, &self.passes);
+    let dump = self.dump.is_some();
+    self.defs.define(item, |defs| {
+      let mut compiler = Compiler::new(context, "jit", precision);
+      compiler.set_passes(passes);
+      compiler.set_dump(dump);
+      let res = compiler.compile_module(defs);
+      dumps = compiler.take_dumps();
+      res.map(|_| ()).map_err(|mut errors| errors.remove(0))
+    })?;
+    dumps.retain(|dump| dump.function == name);
+    write_dumps(&mut self.dump, dumps);
+    Ok(())
+  }
+
+  /// Compiles the top-level expression `func` with the definitions, and
+  /// calls it.
+  fn eval(&mut self, func: &FuncAst) -> Result<Value, Diagnostic> {
+    let mut compiler = Compiler::new(&self.context, "jit", self.precision);
+    compiler.set_passes(&self.passes);
+    if let Err(mut errors) = compiler.compile_module(self.defs.module()) {
+      return Err(errors.remove(0));
+    }
+    compiler.set_dump(self.dump.is_some());
+    let function = compiler.compile_func(func)?;
+    write_dumps(&mut self.dump, compiler.take_dumps());
+    let name = function.get_name().to_str().unwrap().to_string();
+    let engine = self.engine(&compiler)?;
+    runtime::take_failure();
+    let val = unsafe {
+      match (function.get_type().get_return_type(), self.precision) {
+        (Some(BasicTypeEnum::IntType(_)), _) => {
+          type Anon = unsafe extern "C" fn() -> i64;
+          Value::Int(engine.get_function::<Anon>(&name).unwrap().call())
+        }
+        (Some(_), Precision::F64) => {
+          type Anon = unsafe extern "C" fn() -> f64;
+          Value::Num(engine.get_function::<Anon>(&name).unwrap().call())
+        }
+        (Some(_), Precision::F32) => {
+          type Anon = unsafe extern "C" fn() -> f32;
+          Value::Num(engine.get_function::<Anon>(&name).unwrap().call() as f64)
+        }
+        (None, _) => {
+          // the tuple, whose fields are laid out as the target does
+          let ptr = function.get_first_param().unwrap().get_type();
+          let tuple = ptr
+            .into_pointer_type()
+            .get_element_type()
+            .into_struct_type();
+          let data = engine.get_target_data();
+          let mut memory = vec![0u64; (data.get_abi_size(&tuple) as usize).div_ceil(8)];
+          let ptr = memory.as_mut_ptr() as *mut u8;
+          type Anon = unsafe extern "C" fn(*mut u8);
+          engine.get_function::<Anon>(&name).unwrap().call(ptr);
+          let fields = tuple.get_field_types().into_iter().enumerate();
+          let elems = fields.map(|(i, ty)| {
+            let field = ptr.add(data.offset_of_element(&tuple, i as u32).unwrap() as usize);
+            match ty {
+              BasicTypeEnum::IntType(_) => Value::Int((field as *const i64).read()),
+              _ => match self.precision {
+                Precision::F64 => Value::Num((field as *const f64).read()),
+                Precision::F32 => Value::Num((field as *const f32).read() as f64),
+              },
+            }
+          });
+          Value::Tuple(elems.collect())
+        }
+      }
+    };
+    match runtime::take_failure() {
+      Some(msg) => Err(Diagnostic::error(Span::default(), msg).with_code("runtime")),
+      None => Ok(val),
+    }
+  }
+
+  /// An execution engine for the module of `compiler`, in which the
+  /// functions of the runtime are those of this process, and those of the
+  /// C math library are looked up by name, unless a function it declares
+  /// and calls, an extern or one whose definition failed, can't be found.
+  fn engine<'ctx>(&self, compiler: &Compiler<'ctx>) -> Result<ExecutionEngine<'ctx>, Diagnostic> {
+    let error = |msg: String| Diagnostic::error(Span::default(), msg).with_code("codegen");
+    Target::initialize_native(&InitializationConfig::default()).map_err(error)?;
+    let module = compiler.module();
+    let engine = module
+      .create_jit_execution_engine(OptimizationLevel::None)
+      .map_err(|e| error(e.to_string()))?;
+    let runtime: [(&str, usize); 7] = [
+      ("printd", runtime::printd as *const () as usize),
+      ("putchard", runtime::putchard as *const () as usize),
+      ("readd", runtime::readd as *const () as usize),
+      ("rand", runtime::rand as *const () as usize),
+      ("srand", runtime::srand as *const () as usize),
+      (
+        "kale_int_error",
+        runtime::kale_int_error as *const () as usize,
+      ),
+      ("kale_failed", runtime::kale_failed as *const () as usize),
+    ];
+    for (name, addr) in runtime {
+      if let Some(function) = module.get_function(name) {
+        engine.add_global_mapping(&function, addr);
+      }
+    }
+    for function in module.get_functions() {
+      let name = function.get_name().to_string_lossy();
+      let mapped = runtime.iter().any(|(runtime, _)| *runtime == name);
+      let intrinsic = name.starts_with("llvm.");
+      let called = function
+        .as_global_value()
+        .as_pointer_value()
+        .get_first_use()
+        .is_some();
+      let declared = function.count_basic_blocks() == 0 && called;
+      if declared && !mapped && !intrinsic && !linkable(&name) {
+        let msg = format!("Cannot find the extern `{}` to link to", name);
+        return Err(error(msg));
+      }
+    }
+    Ok(engine)
+  }
+}
+
+impl Engine for Jit {
+  fn declare(&mut self, module: &ModuleAst) {
+    self.defs.declare(module);
+  }
+
+  fn run(&mut self, item: Ast) -> Result<Option<Value>, Diagnostic> {
+    let item = match item {
+      Ast::Expr(expr) => Ast::new_top_level(expr, Span::default()),
+      item => item,
+    };
+    match item {
+      Ast::Func(func) if func.proto.name.is_empty() => self.eval(&func).map(Some),
+      item @ (Ast::Func(_) | Ast::Proto(_)) => self.define(item).map(|_| None),
+      item => Err(unsupported_item("LLVM", &item)),
+    }
+  }
+
+  fn set_precision(&mut self, precision: Precision) {
+    self.precision = precision;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::session::Session;
+  use std::io::Cursor;
+  use std::rc::Rc;
+
+  fn run(jit: &mut Jit, src: &'static str) -> Vec<Result<Option<Value>, String>> {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let res = module.items.into_iter().map(|item| jit.run(item));
+    res.map(|res| res.map_err(|e| e.to_string())).collect()
+  }
+
+  #[test]
+  fn jit_run() {
+    let mut jit = Jit::new(Precision::F64);
+    let src = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2); fib(20);
+      def twice(x) x * 2; def f(x) twice(x) + 1; f(1); def twice(x) x * 3; f(1);
+      def minmax(a, b) if a < b then (a, b) else (b, a); minmax(3, 2);
+      sqrt(16) + abs(-1) + min(2, 3); srand(1); rand() == rand(); \"s\"";
+    let num = |n| Ok(Some(Value::Num(n)));
+    let tuple = Value::Tuple(Rc::new([Value::Num(2.0), Value::Num(3.0)]));
+    assert_eq!(
+      run(&mut jit, src),
+      vec![
+        Ok(None),
+        num(6765.0),
+        Ok(None),
+        Ok(None),
+        num(3.0),
+        Ok(None),
+        num(4.0),
+        Ok(None),
+        Ok(Some(tuple)),
+        num(7.0),
+        num(0.0),
+        num(0.0),
+        Err("4:67: The LLVM backend doesn't support strings".to_string()),
+      ]
+    );
+    let mut jit = Jit::new(Precision::F64);
+    let src = "def even(n) if n == 0 then 1 else odd(n - 1);
+      def odd(n) if n == 0 then 0 else even(n - 1); even(10)";
+    jit.declare(&ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap());
+    assert_eq!(run(&mut jit, src), vec![Ok(None), Ok(None), num(1.0)]);
+    let mut jit = Jit::new(Precision::F64);
+    jit.declare(&ModuleAst::parse(&mut Lexer::new(Cursor::new("def later(x) x;"))).unwrap());
+    assert_eq!(
+      run(&mut jit, "extern nowhere(x); nowhere(1); later(1)"),
+      vec![
+        Ok(None),
+        Err("Cannot find the extern `nowhere` to link to".to_string()),
+        Err("Cannot find the extern `later` to link to".to_string()),
+      ]
+    );
+    let mut jit = Jit::new(Precision::F32);
+    assert_eq!(
+      run(&mut jit, "0.1 + 0.2"),
+      vec![num((0.1f32 + 0.2f32) as f64)]
+    );
+  }
+
+  #[test]
+  fn jit_ints() {
+    // checked, as the interpreter does, for the types it infers
+    let src = "def big(a: int): int a * a + 1; big(94906267); big(3037000500);
+      def halves(a: int) (a, a / 2, a / 2.0); halves(7); int(0.0 / 0.0)";
+    let mut session = Session::new();
+    session.set_engine(Some(Box::new(Jit::new(Precision::F64))));
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let res = session.run_module(module).into_iter();
+    let res: Vec<_> = res.map(|res| res.map_err(|e| e.to_string())).collect();
+    let halves = [Value::Int(7), Value::Int(3), Value::Num(3.5)];
+    assert_eq!(
+      res,
+      vec![
+        Ok(None),
+        Ok(Some(Value::Int(9007199515875290))),
+        Err("Integer overflow in `3037000500 * 3037000500`".to_string()),
+        Ok(None),
+        Ok(Some(Value::Tuple(Rc::new(halves)))),
+        Err("Cannot convert NaN to int".to_string()),
+      ]
+    );
+  }
+}
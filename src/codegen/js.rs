@@ -0,0 +1,1224 @@
+use super::backend::{define_module, Backend, Declaration, Declared};
+use super::{
+  check_return, expr_span, int_divisions, is_overloaded, overload, relative_path, tuple_arity,
+  unsupported, Cond, Returns, Shape,
+};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, ProtoAst, UnOp};
+use crate::prelude::prelude;
+use crate::session::Entry;
+use crate::value::Precision;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Transpiles the checked program `module`, which starts at `entry`, to an
+/// ES module, which exports `instantiate(env)`.
+///
+/// `instantiate` takes the externs of the program, as the imports of a
+/// WebAssembly module built by Kale: the functions of the runtime and
+/// those the program declares, by symbol. It returns the functions of the
+/// program, by name, along with `run`, which calls `main`, or else the
+/// top-level expressions in order. The math of the prelude is that of
+/// `Math`, and tuples are arrays. Numbers are rounded to 32 bits after
+/// each operation in `F32` precision. `var`s and assignments aren't
+/// transpiled. Every function is transpiled even when another one fails,
+/// and the errors are reported in order.
+pub fn transpile(
+  module: &ModuleAst,
+  entry: Entry,
+  precision: Precision,
+) -> Result<String, Vec<Diagnostic>> {
+  let mut backend = JsBackend::new(precision);
+  define_module(&mut backend, module)?;
+  Ok(backend.source(entry))
+}
+
+/// JsBackend - transpiles programs to JavaScript, as [`transpile`] does, to
+/// the module `output`.
+pub struct JsBackend {
+  transpiler: Transpiler,
+  declared: Declared<Callee>,
+  definitions: Vec<String>,
+  map: Option<String>, // the name of the source map, once there is one
+}
+
+impl JsBackend {
+  pub fn new(precision: Precision) -> Self {
+    let transpiler = Transpiler {
+      precision,
+      functions: HashMap::new(),
+      returns: Returns::default(),
+      imports: vec![],
+      helpers: BTreeSet::new(),
+      names: reserved(),
+      source: None,
+    };
+    Self {
+      transpiler,
+      declared: Declared::new(),
+      definitions: vec![],
+      map: None,
+    }
+  }
+
+  /// Maps the functions defined from now on back to the Kale file `source`
+  /// with a source map, which [`Backend::finish_module`] writes along with
+  /// `output`, adding `.map` to its name. Items imported from other files
+  /// are mapped to `source` too.
+  pub fn set_source(&mut self, source: &Path, output: &Path) {
+    let dir = output.parent().unwrap_or(Path::new(""));
+    self.transpiler.source = Some(relative_path(dir, source));
+    let name = output.file_name().unwrap_or_default();
+    self.map = Some(format!("{}.map", name.to_string_lossy()));
+  }
+
+  /// The source of the module of the functions defined, whose `run` starts
+  /// the program at `entry`.
+  pub fn source(&self, entry: Entry) -> String {
+    self.generate(entry).0
+  }
+
+  /// The source map of the module [`source`](Self::source) returns, in
+  /// version 3 of the format, once there is a source to map to.
+  pub fn source_map(&self, entry: Entry) -> Option<String> {
+    self.map_lines(&self.generate(entry).1)
+  }
+
+  fn map_lines(&self, lines: &[Option<(usize, Span)>]) -> Option<String> {
+    let source = self.transpiler.source.as_ref()?;
+    let mut mappings = String::new();
+    let mut last = (0, 0);
+    for (i, line) in lines.iter().enumerate() {
+      if i > 0 {
+        mappings.push(';');
+      }
+      if let Some((col, at)) = line {
+        // the column in the line, the source, then its line and column
+        let at = (at.line as i64 - 1, at.col.max(1) as i64 - 1);
+        for n in [*col as i64, 0, at.0 - last.0, at.1 - last.1] {
+          vlq(&mut mappings, n);
+        }
+        last = at;
+      }
+    }
+    let file = self.map.as_deref().unwrap_or_default();
+    let file = file.strip_suffix(".map").unwrap_or(file);
+    Some(format!(
+      "{{\"version\":3,\"file\":{:?},\"sources\":[{:?}],\"names\":[],\"mappings\":{:?}}}\n",
+      file, source, mappings
+    ))
+  }
+
+  /// The source of the module, along with where each of its lines starts
+  /// and the position in the source it maps to, if any, which markers
+  /// of the definitions hold until then.
+  fn generate(&self, entry: Entry) -> (String, Vec<Option<(usize, Span)>>) {
+    let transpiler = &self.transpiler;
+    let mut out = String::new();
+    for (name, code) in HELPERS {
+      if transpiler.helpers.contains(name) {
+        let _ = writeln!(out, "{}", code);
+      }
+    }
+    out.push_str("export function instantiate(env) {\n");
+    let imports: Vec<_> = transpiler
+      .imports
+      .iter()
+      .map(|(symbol, name)| match symbol == name {
+        true => name.clone(),
+        false => format!("{:?}: {}", symbol, name),
+      })
+      .collect();
+    if !imports.is_empty() {
+      let _ = writeln!(out, "  const {{ {} }} = env;", imports.join(", "));
+    }
+    for definition in &self.definitions {
+      let _ = write!(out, "\n{}{}\n", definition, MARKER);
+    }
+    out.push_str("\n  function run() {\n");
+    for callee in self.declared.entry(entry) {
+      let _ = writeln!(out, "    {}();", callee.name);
+    }
+    out.push_str("  }\n\n  return { ");
+    for (name, callee) in self
+      .declared
+      .funcs()
+      .iter()
+      .filter(|(name, _)| !name.is_empty())
+    {
+      match *name == callee.name {
+        true => {
+          let _ = write!(out, "{}, ", name);
+        }
+        false => {
+          let _ = write!(out, "{:?}: {}, ", name, callee.name);
+        }
+      }
+    }
+    out.push_str("run };\n}\n");
+    if let Some(map) = self.map.as_ref().filter(|_| transpiler.source.is_some()) {
+      let _ = writeln!(out, "//# sourceMappingURL={}", map);
+    }
+    let mut code = String::new();
+    let mut lines = vec![];
+    let mut at = None;
+    for line in out.lines() {
+      match line.strip_prefix(MARKER) {
+        Some(marker) => {
+          at = marker.split_once(':').map(|(line, col)| Span {
+            line: line.parse().unwrap(),
+            col: col.parse().unwrap(),
+          })
+        }
+        None => {
+          let col = line.len() - line.trim_start().len();
+          lines.push(at.map(|at| (col, at)));
+          let _ = writeln!(code, "{}", line);
+        }
+      }
+    }
+    (code, lines)
+  }
+}
+
+/// Starts the lines that mark the position in the source of the lines
+/// that follow, as `line:col`, or that those map to none if empty.
+const MARKER: &str = "\0";
+
+/// Writes `n` as a base 64 VLQ, as the mappings of source maps hold it.
+fn vlq(out: &mut String, n: i64) {
+  const DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut n = (n.unsigned_abs() << 1) | (n < 0) as u64;
+  loop {
+    let digit = (n & 31) as usize;
+    n >>= 5;
+    match n {
+      0 => return out.push(DIGITS[digit] as char),
+      _ => out.push(DIGITS[digit | 32] as char),
+    }
+  }
+}
+
+impl Backend for JsBackend {
+  fn name(&self) -> &'static str {
+    "JavaScript"
+  }
+
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+    match decl {
+      Declaration::Extern => self.transpiler.declare_extern(proto),
+      Declaration::Function { tuple } => {
+        let callee = self.transpiler.declare(proto, tuple);
+        self.declared.push(&proto.name, callee);
+      }
+    }
+    Ok(())
+  }
+
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+    let transpiler = &mut self.transpiler;
+    let callee = self.declared.define(func, |_| {
+      let tuple = tuple_arity(&func.body, &transpiler.returns.tuples);
+      transpiler.declare(&func.proto, tuple)
+    });
+    let definition = self.transpiler.transpile_func(func, &callee)?;
+    self.definitions.push(definition);
+    Ok(())
+  }
+
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>> {
+    let write = |path: &Path, contents: String| {
+      std::fs::write(path, contents).map_err(|e| {
+        let msg = format!("Cannot write `{}`: {}", path.display(), e);
+        vec![Diagnostic::error(Span::default(), msg).with_code("codegen")]
+      })
+    };
+    let (code, lines) = self.generate(entry);
+    write(output, code)?;
+    match (self.map_lines(&lines), &self.map) {
+      (Some(map), Some(name)) => write(&output.with_file_name(name), map),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// The functions written along with the programs that call them: `min`
+/// and `max` ignore NaN, as those of the runtime do.
+const HELPERS: [(&str, &str); 3] = [
+  (
+    "min",
+    "function min(x, y) {
+  return Number.isNaN(x) ? y : Number.isNaN(y) ? x : Math.min(x, y);
+}
+",
+  ),
+  (
+    "max",
+    "function max(x, y) {
+  return Number.isNaN(x) ? y : Number.isNaN(y) ? x : Math.max(x, y);
+}
+",
+  ),
+  (
+    "idiv",
+    "function idiv(x, y) {
+  if (y === 0) {
+    throw new RangeError(\"Integer division by zero\");
+  }
+  return Math.trunc(x / y);
+}
+",
+  ),
+];
+
+/// The names that functions and variables can't take in the module: the
+/// reserved words of JavaScript, and those the module itself uses.
+fn reserved() -> HashSet<String> {
+  let words = [
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+    "arguments",
+    "eval",
+    "undefined",
+    "NaN",
+    "Infinity",
+    "Math",
+    "Number",
+    "env",
+    "instantiate",
+    "run",
+    "min",
+    "max",
+    "idiv",
+  ];
+  words.iter().map(|word| word.to_string()).collect()
+}
+
+/// A JavaScript identifier for `name`: itself if it is one that isn't
+/// reserved, else with the characters JavaScript doesn't allow as `_xHH`
+/// and a trailing `_`.
+fn identifier(name: &str, reserved: &HashSet<String>) -> String {
+  let valid = name
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+    && !name.starts_with(|c: char| c.is_ascii_digit());
+  if valid && !reserved.contains(name) {
+    return name.to_string();
+  }
+  let mut out = String::new();
+  for c in name.chars() {
+    match c {
+      c if c.is_ascii_alphanumeric() || c == '_' || c == '$' => out.push(c),
+      c => {
+        let mut buf = [0; 4];
+        for byte in c.encode_utf8(&mut buf).bytes() {
+          let _ = write!(out, "_x{:02x}", byte);
+        }
+      }
+    }
+  }
+  format!("{}_", out)
+}
+
+/// How a function is called.
+#[derive(Debug, Clone)]
+enum Call {
+  Function,       // of the program
+  Import,         // taken from `env`
+  Math(String),   // of `Math`, or a helper
+  Helper(String), // written along with the program
+}
+
+/// A function as programs call it, which never widens what it takes and
+/// returns, all numbers being `double`s.
+type Callee = super::Callee<Call>;
+
+struct Transpiler {
+  precision: Precision,
+  functions: HashMap<String, Callee>, // by the name programs call them
+  returns: Returns,                   // what they return
+  imports: Vec<(String, String)>,     // the symbol and name of each
+  helpers: BTreeSet<String>,          // of them called
+  names: HashSet<String>,             // of the functions and imports
+  source: Option<String>,             // the file the lines are mapped to
+}
+
+impl Transpiler {
+  /// Declares the function `proto` defines, or a top-level expression,
+  /// under a name of its own, returning a tuple of `tuple` numbers if any.
+  fn declare(&mut self, proto: &ProtoAst, tuple: Option<usize>) -> Callee {
+    self.returns.declare(proto, tuple);
+    let base = match proto.name.as_str() {
+      "" => "topLevel".to_string(),
+      name => identifier(name, &self.names),
+    };
+    let mut name = base.clone();
+    let mut n = 1;
+    while self.names.contains(&name) {
+      name = format!("{}{}", base, n);
+      n += 1;
+    }
+    self.names.insert(name.clone());
+    let callee = Callee {
+      name,
+      call: Call::Function,
+      tuple,
+      widen: false,
+    };
+    if !proto.name.is_empty() {
+      self.functions.insert(proto.name.clone(), callee.clone());
+    }
+    callee
+  }
+
+  /// Declares the extern `proto`: a function of `Math` if it is math of
+  /// the prelude, or else one of `env`.
+  fn declare_extern(&mut self, proto: &ProtoAst) {
+    let symbol = proto.symbol();
+    let call = match symbol {
+      "sin" | "cos" | "exp" | "log" | "sqrt" | "pow" | "abs" | "floor" => {
+        Call::Math(format!("Math.{}", symbol))
+      }
+      "min" | "max" => Call::Helper(symbol.to_string()),
+      _ => Call::Import,
+    };
+    let name = match call {
+      Call::Import => match self.imports.iter().find(|(s, _)| s == symbol) {
+        Some((_, name)) => name.clone(),
+        None => {
+          let name = identifier(symbol, &self.names);
+          self.names.insert(name.clone());
+          self.imports.push((symbol.to_string(), name.clone()));
+          name
+        }
+      },
+      _ => symbol.to_string(),
+    };
+    let callee = Callee {
+      name,
+      call,
+      tuple: None,
+      widen: false,
+    };
+    self.functions.insert(proto.name.clone(), callee);
+  }
+
+  /// The definition of the function `func`, or of the top-level expression
+  /// it wraps, which `callee` declares, in the body of `instantiate`.
+  fn transpile_func(&mut self, func: &FuncAst, callee: &Callee) -> Result<String, Diagnostic> {
+    let proto = &func.proto;
+    let mut body = func.body.clone();
+    body.lower_matches();
+    let mut lowering = Lowering {
+      names: self.names.clone(),
+      divisions: int_divisions(func, &self.returns.annotations),
+      transpiler: self,
+      lines: vec![],
+      depth: 2,
+      scope: vec![],
+      ret: match callee.tuple {
+        Some(n) => Shape::Tuple(n),
+        None => Shape::Num,
+      },
+      temps: 0,
+      at: proto.span,
+    };
+    let mut params = vec![];
+    for arg in &proto.args {
+      let name = lowering.fresh(arg);
+      params.push(name.clone());
+      lowering.scope.push((arg.clone(), name, Shape::Num));
+    }
+    let code = lowering.lower_expr(&body, proto.span)?;
+    check_return(lowering.ret, code.shape, proto.span)?;
+    lowering.emit(format!("return {};", code.text));
+    let mut out = String::new();
+    if lowering.transpiler.source.is_some() && proto.span.line > 0 {
+      let _ = writeln!(out, "{}{}:{}", MARKER, proto.span.line, proto.span.col);
+    }
+    let _ = writeln!(out, "  function {}({}) {{", callee.name, params.join(", "));
+    for line in lowering.lines {
+      let _ = writeln!(out, "{}", line);
+    }
+    out.push_str("  }\n");
+    Ok(out)
+  }
+}
+
+/// How tightly an expression binds, to parenthesize it as an operand.
+const PREC_COND: u8 = 2; // `?:`
+const PREC_OR: u8 = 3;
+const PREC_AND: u8 = 4;
+const PREC_EQ: u8 = 8;
+const PREC_CMP: u8 = 9;
+const PREC_ADD: u8 = 11;
+const PREC_MUL: u8 = 12;
+const PREC_UNARY: u8 = 14;
+const PREC_ATOM: u8 = 18;
+
+/// Code - an expression in JavaScript.
+#[derive(Debug, Clone)]
+struct Code {
+  text: String,
+  prec: u8,
+  shape: Shape,
+  pure: bool, // has no effect, as it makes no call
+}
+
+impl Code {
+  fn new(text: String, prec: u8) -> Self {
+    Code {
+      text,
+      prec,
+      shape: Shape::Num,
+      pure: true,
+    }
+  }
+
+  /// The code as an operand that must bind at least as tightly as `prec`.
+  fn operand(&self, prec: u8) -> String {
+    match self.prec >= prec {
+      true => self.text.clone(),
+      false => format!("({})", self.text),
+    }
+  }
+}
+
+/// Lowering - the state of the transpilation of one function, whose
+/// statements are written to `lines`. Calls stay in expressions, which
+/// JavaScript evaluates from left to right as Kale does, but for those
+/// before an operand that needs statements of its own. With a source to
+/// map to, each statement follows a marker of `at`, the position of the
+/// last expression lowered that knows where it is.
+struct Lowering<'a> {
+  transpiler: &'a mut Transpiler,
+  lines: Vec<String>,
+  depth: usize,
+  names: HashSet<String>,   // in the function, and those it can't use
+  divisions: HashSet<Span>, // of ints, which truncate
+  scope: Vec<(String, String, Shape)>,
+  ret: Shape,
+  temps: usize,
+  at: Span,
+}
+
+impl Lowering<'_> {
+  fn emit(&mut self, line: String) {
+    if self.transpiler.source.is_some() && self.at.line > 0 {
+      let marker = format!("{}{}:{}", MARKER, self.at.line, self.at.col);
+      self.lines.push(marker);
+    }
+    self
+      .lines
+      .push(format!("{}{}", "  ".repeat(self.depth), line));
+  }
+
+  /// A name for a new variable, `name` unless it is taken.
+  fn fresh(&mut self, name: &str) -> String {
+    let base = identifier(name, &self.transpiler.names);
+    let mut name = base.clone();
+    let mut n = 1;
+    while self.names.contains(&name) {
+      name = format!("{}{}", base, n);
+      n += 1;
+    }
+    self.names.insert(name.clone());
+    name
+  }
+
+  fn temp(&mut self) -> String {
+    loop {
+      let name = format!("t{}", self.temps);
+      self.temps += 1;
+      if self.names.insert(name.clone()) {
+        return name;
+      }
+    }
+  }
+
+  /// Lowers `f` into statements of their own, one level deeper, returning
+  /// them along with what `f` returns.
+  fn nested<T>(
+    &mut self,
+    f: impl FnOnce(&mut Self) -> Result<T, Diagnostic>,
+  ) -> Result<(Vec<String>, T), Diagnostic> {
+    let lines = std::mem::take(&mut self.lines);
+    let at = self.at;
+    self.depth += 1;
+    let res = f(self);
+    self.depth -= 1;
+    let nested = std::mem::replace(&mut self.lines, lines);
+    self.at = at;
+    Ok((nested, res?))
+  }
+
+  /// Keeps the operands `before` evaluated before the one just lowered
+  /// from the statement `mark`: if it wrote statements, those of them that
+  /// make calls are evaluated into constants ahead of them.
+  fn keep_order(&mut self, before: &mut [Code], mark: usize) {
+    if self.lines.len() == mark {
+      return;
+    }
+    let mut consts = vec![];
+    for code in before.iter_mut().filter(|code| !code.pure) {
+      let temp = self.temp();
+      let indent = "  ".repeat(self.depth);
+      consts.push(format!("{}const {} = {};", indent, temp, code.text));
+      *code = Code {
+        shape: code.shape,
+        ..Code::new(temp, PREC_ATOM)
+      };
+    }
+    self.lines.splice(mark..mark, consts);
+  }
+
+  /// Lowers the numbers `exprs` in order.
+  fn lower_nums(&mut self, exprs: &[&ExprAst], span: Span) -> Result<Vec<Code>, Diagnostic> {
+    let mut codes = vec![];
+    for expr in exprs {
+      let mark = self.lines.len();
+      let code = self.lower_num(expr, span)?;
+      self.keep_order(&mut codes, mark);
+      codes.push(code);
+    }
+    Ok(codes)
+  }
+
+  fn literal(&self, n: f64) -> Code {
+    let n = match self.transpiler.precision {
+      Precision::F64 => n,
+      Precision::F32 => n as f32 as f64,
+    };
+    let text = match n {
+      n if n.is_nan() => "NaN".to_string(),
+      n if n.is_infinite() => "Infinity".to_string(),
+      n if n.fract() == 0.0 && n.abs() < 1e16 => format!("{}", n.abs()),
+      n => format!("{:?}", n.abs()),
+    };
+    match n.is_sign_negative() && !n.is_nan() {
+      true => Code::new(format!("-{}", text), PREC_UNARY),
+      false => Code::new(text, PREC_ATOM),
+    }
+  }
+
+  /// `code` rounded to 32 bits in `F32` precision.
+  fn round(&self, code: Code) -> Code {
+    match self.transpiler.precision {
+      Precision::F64 => code,
+      Precision::F32 => Code {
+        pure: code.pure,
+        ..Code::new(format!("Math.fround({})", code.text), PREC_ATOM)
+      },
+    }
+  }
+
+  /// Lowers `expr`, leaving `at` at it if it knows where it is, or else
+  /// where the last of its operands lowered is.
+  fn lower_expr(&mut self, expr: &ExprAst, span: Span) -> Result<Code, Diagnostic> {
+    let Some(at) = expr_span(expr).filter(|at| at.line > 0) else {
+      return self.lower_kind(expr, span);
+    };
+    self.at = at;
+    let code = self.lower_kind(expr, span);
+    self.at = at;
+    code
+  }
+
+  fn lower_kind(&mut self, expr: &ExprAst, span: Span) -> Result<Code, Diagnostic> {
+    match expr {
+      ExprAst::NumAst(n) => Ok(self.literal(*n)),
+      ExprAst::IntAst(i) => Ok(self.literal(*i as f64)),
+      ExprAst::BoolAst(b) => Ok(self.literal(*b as i32 as f64)),
+      ExprAst::UnitAst => Ok(self.literal(0.0)),
+      ExprAst::VarAst(name, at) => match self.scope.iter().rev().find(|(n, ..)| n == name) {
+        Some((_, var, shape)) => Ok(Code {
+          shape: *shape,
+          ..Code::new(var.clone(), PREC_ATOM)
+        }),
+        None if self.transpiler.functions.contains_key(name) => {
+          Err(unsupported("JavaScript", "functions as values", *at))
+        }
+        None => Err(unsupported("JavaScript", "global variables", *at)),
+      },
+      ExprAst::UnaryAst(UnOp::Neg, operand, at) => {
+        let operand = self.lower_num(operand, *at)?;
+        let text = match operand.operand(PREC_UNARY) {
+          text if text.starts_with('-') => format!("-({})", text),
+          text => format!("-{}", text),
+        };
+        Ok(Code {
+          pure: operand.pure,
+          ..Code::new(text, PREC_UNARY)
+        })
+      }
+      ExprAst::UnaryAst(UnOp::Not, ..) => {
+        let cond = self.lower_cond(expr, span)?;
+        Ok(self.bool_to_num(cond))
+      }
+      ExprAst::BinAst(lhs, op, rhs, at) => self.lower_bin(lhs, *op, rhs, *at),
+      ExprAst::CallAst(name, args, at) => {
+        let (code, call) = self.lower_call(name, args, *at)?;
+        Ok(match call {
+          Call::Function => code,
+          _ => self.round(code),
+        })
+      }
+      ExprAst::IfAst { cond, then, els } => self.lower_if(cond, then, els, span),
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        let Some((last, exprs)) = exprs.split_last() else {
+          return Ok(self.literal(0.0));
+        };
+        for expr in exprs {
+          self.lower_stmt(expr, span)?;
+        }
+        self.lower_expr(last, span)
+      }
+      ExprAst::TupleAst(elems) => {
+        let elems: Vec<_> = elems.iter().collect();
+        let codes = self.lower_nums(&elems, span)?;
+        let texts: Vec<_> = codes.iter().map(|code| code.text.clone()).collect();
+        Ok(Code {
+          shape: Shape::Tuple(codes.len()),
+          pure: codes.iter().all(|code| code.pure),
+          ..Code::new(format!("[{}]", texts.join(", ")), PREC_ATOM)
+        })
+      }
+      ExprAst::ElemAst(tuple, i) => {
+        let tuple = self.lower_expr(tuple, span)?;
+        match tuple.shape {
+          Shape::Tuple(n) if *i < n => Ok(Code {
+            pure: tuple.pure,
+            ..Code::new(format!("{}[{}]", tuple.operand(PREC_ATOM), i), PREC_ATOM)
+          }),
+          _ => {
+            Err(Diagnostic::error(span, format!("No element {} in tuple", i)).with_code("codegen"))
+          }
+        }
+      }
+      ExprAst::LetAst(bindings, body) => {
+        let depth = self.scope.len();
+        for (name, _, init) in bindings {
+          let init = self.lower_expr(init, span)?;
+          let var = self.fresh(name);
+          self.emit(format!("const {} = {};", var, init.text));
+          self.scope.push((name.clone(), var, init.shape));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let init = self.lower_expr(init, span)?;
+        let msg = match init.shape {
+          Shape::Tuple(n) if n == names.len() => None,
+          Shape::Tuple(n) => Some(format!(
+            "Cannot destructure a tuple of {} into {} names",
+            n,
+            names.len()
+          )),
+          Shape::Num => Some(format!(
+            "Cannot destructure a number into {} names",
+            names.len()
+          )),
+        };
+        if let Some(msg) = msg {
+          return Err(Diagnostic::error(span, msg).with_code("codegen"));
+        }
+        let depth = self.scope.len();
+        let vars: Vec<_> = names.iter().map(|(name, _)| self.fresh(name)).collect();
+        self.emit(format!("const [{}] = {};", vars.join(", "), init.text));
+        for ((name, _), var) in names.iter().zip(vars) {
+          self.scope.push((name.clone(), var, Shape::Num));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      // the code that follows a `return` never runs, so the `return`
+      // yields what it returned
+      ExprAst::ReturnAst(value, at) => {
+        let value = self.lower_expr(value, *at)?;
+        check_return(self.ret, value.shape, *at)?;
+        self.emit(format!("return {};", value.text));
+        Ok(value)
+      }
+      ExprAst::MatchAst(..) => unreachable!("`match` is lowered before codegen"),
+      ExprAst::StrAst(_) => Err(unsupported("JavaScript", "strings", span)),
+      ExprAst::ArrayAst(_) | ExprAst::IndexAst(..) => {
+        Err(unsupported("JavaScript", "arrays", span))
+      }
+      ExprAst::FieldAst(..) => Err(unsupported("JavaScript", "structs", span)),
+      ExprAst::LambdaAst(..) => Err(unsupported("JavaScript", "closures", span)),
+      ExprAst::FuncRefAst(_, at) => Err(unsupported("JavaScript", "functions as values", *at)),
+      ExprAst::VarInAst(vars, _) => {
+        let at = vars.first().map_or(span, |(_, at, _)| *at);
+        Err(unsupported("JavaScript", "`var`", at))
+      }
+      ExprAst::AssignAst(.., at) => Err(unsupported("JavaScript", "assignments", *at)),
+      ExprAst::TryAst(.., at) => Err(unsupported("JavaScript", "`try`", *at)),
+    }
+  }
+
+  /// Lowers `expr` for its effects only. Those that have none, but for
+  /// their value, are left out.
+  fn lower_stmt(&mut self, expr: &ExprAst, span: Span) -> Result<(), Diagnostic> {
+    match expr {
+      ExprAst::IfAst { cond, then, els } => {
+        let cond = self.lower_cond(cond, span)?;
+        let (then, _) = self.nested(|lowering| lowering.lower_stmt(then, span))?;
+        let (els, _) = self.nested(|lowering| lowering.lower_stmt(els, span))?;
+        self.emit_if(cond, then, els);
+      }
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        for expr in exprs {
+          self.lower_stmt(expr, span)?;
+        }
+      }
+      ExprAst::LetAst(bindings, body) => {
+        let depth = self.scope.len();
+        for (name, _, init) in bindings {
+          let init = self.lower_expr(init, span)?;
+          let var = self.fresh(name);
+          self.emit(format!("const {} = {};", var, init.text));
+          self.scope.push((name.clone(), var, init.shape));
+        }
+        let res = self.lower_stmt(body, span);
+        self.scope.truncate(depth);
+        res?;
+      }
+      // what the call returns needn't be rounded
+      ExprAst::CallAst(name, args, at) => {
+        let (code, _) = self.lower_call(name, args, *at)?;
+        if !code.pure {
+          self.emit(format!("{};", code.text));
+        }
+      }
+      expr => {
+        let code = self.lower_expr(expr, span)?;
+        if !code.pure {
+          self.emit(format!("{};", code.text));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes an `if` statement, without the branches that are empty.
+  fn emit_if(&mut self, cond: Code, then: Vec<String>, els: Vec<String>) {
+    if then.is_empty() && els.is_empty() {
+      if !cond.pure {
+        self.emit(format!("{};", cond.text));
+      }
+      return;
+    }
+    if then.is_empty() {
+      let cond = Code::new(format!("!{}", cond.operand(PREC_UNARY)), PREC_UNARY);
+      return self.emit_if(cond, els, then);
+    }
+    self.emit(format!("if ({}) {{", cond.text));
+    self.lines.extend(then);
+    if !els.is_empty() {
+      self.emit("} else {".to_string());
+      self.lines.extend(els);
+    }
+    self.emit("}".to_string());
+  }
+
+  fn lower_num(&mut self, expr: &ExprAst, span: Span) -> Result<Code, Diagnostic> {
+    let code = self.lower_expr(expr, span)?;
+    match code.shape {
+      Shape::Num => Ok(code),
+      Shape::Tuple(_) => {
+        Err(Diagnostic::error(span, "Expected a number, found a tuple").with_code("codegen"))
+      }
+    }
+  }
+
+  /// A number that is 1 where `cond` holds, else 0.
+  fn bool_to_num(&self, cond: Code) -> Code {
+    Code {
+      pure: cond.pure,
+      ..Code::new(
+        format!("{} ? 1 : 0", cond.operand(PREC_COND + 1)),
+        PREC_COND,
+      )
+    }
+  }
+
+  /// A condition, as a boolean of whether `expr` is true: nonzero, which
+  /// NaN is, unlike in JavaScript.
+  fn lower_cond(&mut self, expr: &ExprAst, span: Span) -> Result<Code, Diagnostic> {
+    match Cond::of(expr, &self.transpiler.functions) {
+      Cond::Bool(b) => Ok(Code::new(b.to_string(), PREC_ATOM)),
+      Cond::Not(operand, at) => {
+        let cond = self.lower_cond(operand, at)?;
+        Ok(Code {
+          pure: cond.pure,
+          ..Code::new(format!("!{}", cond.operand(PREC_UNARY)), PREC_UNARY)
+        })
+      }
+      Cond::Logical(lhs, op, rhs, at) => self.lower_logical(lhs, op, rhs, at),
+      Cond::Cmp(lhs, op, rhs, at) => {
+        let (op, prec) = match op {
+          BinOp::Eq => ("===", PREC_EQ),
+          BinOp::Ne => ("!==", PREC_EQ),
+          op => (op.as_str(), PREC_CMP),
+        };
+        let codes = self.lower_nums(&[lhs, rhs], at)?;
+        let text = format!(
+          "{} {} {}",
+          codes[0].operand(prec),
+          op,
+          codes[1].operand(prec + 1)
+        );
+        Ok(Code {
+          pure: codes[0].pure && codes[1].pure,
+          ..Code::new(text, prec)
+        })
+      }
+      Cond::Nonzero => {
+        let num = self.lower_num(expr, span)?;
+        Ok(Code {
+          pure: num.pure,
+          ..Code::new(format!("{} !== 0", num.operand(PREC_EQ)), PREC_EQ)
+        })
+      }
+    }
+  }
+
+  fn lower_bin(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Code, Diagnostic> {
+    if is_overloaded(&self.transpiler.functions, op) {
+      let call = ExprAst::CallAst(overload(op), vec![lhs.clone(), rhs.clone()], span);
+      return self.lower_expr(&call, span);
+    }
+    if op.is_bitwise() {
+      return Err(unsupported("JavaScript", "bitwise operators", span));
+    }
+    let prec = match op {
+      BinOp::Add | BinOp::Sub => PREC_ADD,
+      BinOp::Mul | BinOp::Div | BinOp::Rem => PREC_MUL,
+      _ => {
+        let expr = ExprAst::BinAst(Box::new(lhs.clone()), op, Box::new(rhs.clone()), span);
+        let cond = self.lower_cond(&expr, span)?;
+        return Ok(self.bool_to_num(cond));
+      }
+    };
+    let codes = self.lower_nums(&[lhs, rhs], span)?;
+    // dividing ints truncates, and fails by zero
+    if op == BinOp::Div && self.divisions.contains(&span) {
+      self.transpiler.helpers.insert("idiv".to_string());
+      let text = format!("idiv({}, {})", codes[0].text, codes[1].text);
+      return Ok(Code {
+        pure: false,
+        ..Code::new(text, PREC_ATOM)
+      });
+    }
+    let text = format!(
+      "{} {} {}",
+      codes[0].operand(prec),
+      op.as_str(),
+      codes[1].operand(prec + 1)
+    );
+    Ok(self.round(Code {
+      pure: codes[0].pure && codes[1].pure,
+      ..Code::new(text, prec)
+    }))
+  }
+
+  /// `&&` and `||`, which only evaluate `rhs` when `lhs` doesn't decide:
+  /// those of JavaScript, unless `rhs` needs statements, which then go in
+  /// an `if`.
+  fn lower_logical(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Code, Diagnostic> {
+    let lhs = self.lower_cond(lhs, span)?;
+    let (lines, rhs) = self.nested(|lowering| lowering.lower_cond(rhs, span))?;
+    let prec = match op {
+      BinOp::And => PREC_AND,
+      _ => PREC_OR,
+    };
+    if lines.is_empty() {
+      let text = format!(
+        "{} {} {}",
+        lhs.operand(prec),
+        op.as_str(),
+        rhs.operand(prec + 1)
+      );
+      return Ok(Code {
+        pure: lhs.pure && rhs.pure,
+        ..Code::new(text, prec)
+      });
+    }
+    let temp = self.temp();
+    self.emit(format!("let {} = {};", temp, lhs.text));
+    let mut then = lines;
+    then.push(format!(
+      "{}{} = {};",
+      "  ".repeat(self.depth + 1),
+      temp,
+      rhs.text
+    ));
+    let cond = match op {
+      BinOp::And => Code::new(temp.clone(), PREC_ATOM),
+      _ => Code::new(format!("!{}", temp), PREC_UNARY),
+    };
+    self.emit_if(cond, then, vec![]);
+    Ok(Code::new(temp, PREC_ATOM))
+  }
+
+  fn lower_if(
+    &mut self,
+    cond: &ExprAst,
+    then: &ExprAst,
+    els: &ExprAst,
+    span: Span,
+  ) -> Result<Code, Diagnostic> {
+    let cond = self.lower_cond(cond, span)?;
+    let (then_lines, then) = self.nested(|lowering| lowering.lower_expr(then, span))?;
+    let (els_lines, els) = self.nested(|lowering| lowering.lower_expr(els, span))?;
+    if then.shape != els.shape {
+      let msg = "Branches of `if` yield both numbers and tuples, or tuples of different sizes";
+      return Err(Diagnostic::error(span, msg).with_code("codegen"));
+    }
+    if then_lines.is_empty() && els_lines.is_empty() {
+      let text = format!(
+        "{} ? {} : {}",
+        cond.operand(PREC_COND + 1),
+        then.operand(PREC_COND),
+        els.operand(PREC_COND)
+      );
+      return Ok(Code {
+        shape: then.shape,
+        pure: cond.pure && then.pure && els.pure,
+        ..Code::new(text, PREC_COND)
+      });
+    }
+    let temp = self.temp();
+    self.emit(format!("let {};", temp));
+    let depth = self.depth + 1;
+    let assign = |mut lines: Vec<String>, code: Code| {
+      lines.push(format!("{}{} = {};", "  ".repeat(depth), temp, code.text));
+      lines
+    };
+    let shape = then.shape;
+    let (then, els) = (assign(then_lines, then), assign(els_lines, els));
+    self.emit_if(cond, then, els);
+    Ok(Code {
+      shape,
+      ..Code::new(temp, PREC_ATOM)
+    })
+  }
+
+  /// A call of a function of the module or of the prelude, or of `int`,
+  /// which rounds towards zero, or `float`, which does nothing, along with
+  /// how it is called.
+  fn lower_call(
+    &mut self,
+    name: &str,
+    args: &[ExprAst],
+    span: Span,
+  ) -> Result<(Code, Call), Diagnostic> {
+    if self.scope.iter().any(|(local, ..)| local == name) {
+      return Err(unsupported("JavaScript", "closures", span));
+    }
+    let callee = match (name, args.len()) {
+      ("float", 1) => return Ok((self.lower_num(&args[0], span)?, Call::Function)),
+      ("int", 1) => Callee {
+        name: "Math.trunc".to_string(),
+        call: Call::Math("Math.trunc".to_string()),
+        tuple: None,
+        widen: false,
+      },
+      _ => {
+        if !self.transpiler.functions.contains_key(name) {
+          let proto = prelude().items.into_iter().find_map(|item| match item {
+            Ast::Proto(proto) if proto.name == name => Some(proto),
+            _ => None,
+          });
+          if let Some(proto) = proto {
+            self.transpiler.declare_extern(&proto);
+          }
+        }
+        let Some(callee) = self.transpiler.functions.get(name).cloned() else {
+          let what = format!("the builtin `{}`", name);
+          return Err(unsupported("JavaScript", &what, span));
+        };
+        callee
+      }
+    };
+    let args: Vec<_> = args.iter().collect();
+    let codes = self.lower_nums(&args, span)?;
+    let texts: Vec<_> = codes.iter().map(|code| code.text.clone()).collect();
+    let (function, pure) = match &callee.call {
+      Call::Function | Call::Import => (callee.name.clone(), false),
+      Call::Math(function) => (function.clone(), codes.iter().all(|code| code.pure)),
+      Call::Helper(function) => {
+        self.transpiler.helpers.insert(function.clone());
+        (function.clone(), codes.iter().all(|code| code.pure))
+      }
+    };
+    let code = Code {
+      shape: match callee.tuple {
+        Some(n) => Shape::Tuple(n),
+        None => Shape::Num,
+      },
+      pure,
+      ..Code::new(format!("{}({})", function, texts.join(", ")), PREC_ATOM)
+    };
+    Ok((code, callee.call))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codegen::tests::transpile_src;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  #[test]
+  fn js_transpile() {
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a);
+      def sign(x) { if x < 0 then return -1 else (); if x > 0 then 1 else 0 };
+      def f(x) printd(x) + (let y = min(x, 2) in y * y);
+      def main() { let (lo, hi) = minmax(2, 1) in printd(hi % lo); f(sign(-3)) };";
+    let expected = "function min(x, y) {
+  return Number.isNaN(x) ? y : Number.isNaN(y) ? x : Math.min(x, y);
+}
+
+export function instantiate(env) {
+  const { printd } = env;
+
+  function minmax(a, b) {
+    return a < b ? [a, b] : [b, a];
+  }
+
+  function sign(x) {
+    if (x < 0) {
+      return -1;
+    }
+    return x > 0 ? 1 : 0;
+  }
+
+  function f(x) {
+    const t0 = printd(x);
+    const y = min(x, 2);
+    return t0 + y * y;
+  }
+
+  function main() {
+    const [lo, hi] = minmax(2, 1);
+    printd(hi % lo);
+    return f(sign(-3));
+  }
+
+  function run() {
+    main();
+  }
+
+  return { minmax, sign, f, main, run };
+}
+";
+    assert_eq!(transpile_src(transpile, src).unwrap(), expected);
+
+    // the quotient of ints truncates
+    let js = transpile_src(transpile, "def half(x: int) x / 2; def main() half(7) / 2;").unwrap();
+    assert!(
+      js.contains("return idiv(x, 2);") && js.contains("return half(7) / 2;"),
+      "{}",
+      js
+    );
+
+    assert_eq!(
+      transpile_src(transpile, "def f(x) x; \"s\"").unwrap_err(),
+      ["1:13: The JavaScript backend doesn't support strings"]
+    );
+  }
+
+  #[test]
+  fn js_source_map() {
+    let src = "def fib(n)
+  if n < 2 then n
+  else fib(n - 1) + fib(n - 2);
+def main() printd(fib(10));";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut backend = JsBackend::new(Precision::F64);
+    backend.set_source(Path::new("prog.kale"), Path::new("prog.js"));
+    define_module(&mut backend, &module).unwrap();
+    let js = backend.source(Entry::Main);
+    assert!(
+      js.ends_with("\n//# sourceMappingURL=prog.js.map\n"),
+      "{}",
+      js
+    );
+    // `fib` at its name, 1:5, then its `return` at the condition, 2:6, and
+    // `main` at 4:5, then its `return` at the call of `printd`, 4:12
+    assert_eq!(
+      backend.source_map(Entry::Main).unwrap(),
+      "{\"version\":3,\"file\":\"prog.js\",\"sources\":[\"prog.kale\"],\"names\":[],\
+        \"mappings\":\";;;EAAI;IACC;EAAA;;EAED;IAAO;EAAA;;;;;;;;\"}\n"
+    );
+
+    let mut mappings = String::new();
+    for n in [0, 1, -1, 15, 16, -16, 1000] {
+      vlq(&mut mappings, n);
+    }
+    assert_eq!(mappings, "ACDegBhBw+B");
+  }
+}
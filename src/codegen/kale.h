@@ -0,0 +1,21 @@
+/* The runtime of C transpiled from Kale, which kale_runtime.c implements:
+ * compile it along with the program, and link with the math library. */
+
+#ifndef KALE_H
+#define KALE_H
+
+#include <math.h>
+
+double printd(double x);
+double putchard(double c);
+double readd(void);
+
+/* Divides the ints `a` and `b`, exiting on a division by zero. */
+double kale_idiv(double a, double b);
+
+/* These replace the `rand` and `srand` of <stdlib.h>, which mustn't be
+ * included along with this header. */
+double rand(void);
+double srand(double seed);
+
+#endif
@@ -0,0 +1,1611 @@
+use super::{
+  assigns, expr_span, link_name, tuple_arities, tuple_arity, unsupported, unsupported_item,
+  Annotation, IrDump,
+};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, ProtoAst, UnOp};
+use crate::prelude::prelude;
+use crate::runtime::IntOp;
+use crate::value::Precision;
+use inkwell::attributes::{Attribute, AttributeLoc};
+use inkwell::basic_block::BasicBlock;
+use inkwell::builder::{Builder, BuilderError};
+use inkwell::context::Context;
+use inkwell::debug_info::{
+  debug_metadata_version, AsDIScope, DICompileUnit, DIFlags, DIFlagsConstants, DISubprogram,
+  DIType, DWARFEmissionKind, DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::intrinsics::Intrinsic;
+use inkwell::module::{FlagBehavior, Module};
+use inkwell::passes::PassManager;
+use inkwell::targets::TargetMachine;
+use inkwell::types::{
+  BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FloatType, IntType, StructType,
+};
+use inkwell::values::{
+  AnyValue, BasicMetadataValueEnum, BasicValueEnum, FloatValue, FunctionValue, IntValue,
+  PointerValue,
+};
+use inkwell::{AddressSpace, FloatPredicate, IntPredicate};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Compiler - lowers functions and externs to the LLVM IR of one module.
+/// Numbers are doubles, or 32-bit floats in `F32` precision, and so are
+/// booleans, comparisons yielding 1.0 or 0.0 as in the interpreter. Ints are
+/// `i64`s, the parameters and returns the checker annotates as ints too,
+/// and convert to numbers where they meet them. A function that returns a
+/// tuple takes a pointer to a struct of its elements as its first, `sret`,
+/// parameter, and stores them there instead.
+///
+/// Operations on ints fail as in the interpreter, on overflow or a division
+/// by zero: the runtime's `kale_int_error` is told why, and the function
+/// returns, as does each function that calls it, which asks `kale_failed`.
+///
+/// The externs of the prelude are declared once called. Top-level
+/// expressions are compiled as functions named `__anon_expr`, which LLVM
+/// numbers when there are several.
+///
+/// The variables of `var` and the parameters assigned to live in stack
+/// slots of the entry block, as in chapter 7 of LLVM's tutorial, for
+/// [`Pass::Mem2Reg`] to promote them to registers.
+///
+/// Each function is optimized once compiled, by the passes of its
+/// [`OptLevel`] or those it is given, and the IR of a function can be kept
+/// as it was before and after them with [`Compiler::set_dump`]. With
+/// [`Compiler::set_debug_info`], functions are described in DWARF, and
+/// their instructions located at the lines of the expressions they compute.
+pub struct Compiler<'ctx> {
+  context: &'ctx Context,
+  module: Module<'ctx>,
+  builder: Builder<'ctx>,
+  float: FloatType<'ctx>,
+  int: IntType<'ctx>,
+  precision: Precision,
+  functions: HashMap<String, Callee<'ctx>>, // by the name programs call them
+  tuples: HashMap<String, usize>,           // the arity of those returning tuples
+  scope: Vec<(String, Local<'ctx>)>,
+  function: Option<Callee<'ctx>>, // the one being compiled
+  passes: Vec<Pass>,
+  dump: bool,
+  dumps: Vec<IrDump>,
+  debug: Option<DebugInfo<'ctx>>,
+}
+
+/// DebugInfo - the DWARF description of a module compiled from one source
+/// file, and the subprogram of the function being compiled, in which the
+/// instructions are located.
+struct DebugInfo<'ctx> {
+  builder: DebugInfoBuilder<'ctx>,
+  unit: DICompileUnit<'ctx>,
+  float: DIType<'ctx>,
+  int: DIType<'ctx>,
+  subprogram: Option<DISubprogram<'ctx>>,
+}
+
+/// A function as programs call it.
+#[derive(Clone, Copy)]
+struct Callee<'ctx> {
+  function: FunctionValue<'ctx>,
+  sret: Option<StructType<'ctx>>, // of the tuple it returns
+  widen: bool,                    // takes and returns doubles whatever the precision
+  external: bool,                 // an extern, which doesn't fail
+}
+
+/// The value of an expression: a number, an int, or the numbers and ints
+/// of a tuple.
+#[derive(Clone)]
+enum Val<'ctx> {
+  Num(FloatValue<'ctx>),
+  Int(IntValue<'ctx>),
+  Tuple(Vec<Val<'ctx>>),
+}
+
+impl<'ctx> Val<'ctx> {
+  fn of(val: BasicValueEnum<'ctx>) -> Self {
+    match val {
+      BasicValueEnum::IntValue(i) => Val::Int(i),
+      val => Val::Num(val.into_float_value()),
+    }
+  }
+
+  /// The LLVM value of a number or an int.
+  fn scalar(&self) -> BasicValueEnum<'ctx> {
+    match self {
+      Val::Num(n) => (*n).into(),
+      Val::Int(i) => (*i).into(),
+      Val::Tuple(_) => unreachable!("tuples are stored element by element"),
+    }
+  }
+
+  fn describe(&self) -> &'static str {
+    match self {
+      Val::Num(_) => "a number",
+      Val::Int(_) => "an int",
+      Val::Tuple(_) => "a tuple",
+    }
+  }
+}
+
+/// A name in scope: the value it is bound to, or the stack slot of a
+/// mutable variable.
+#[derive(Clone)]
+enum Local<'ctx> {
+  Val(Val<'ctx>),
+  Var(PointerValue<'ctx>),
+}
+
+/// Pass - an optimization that runs on each function, named as in LLVM's
+/// `opt`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Pass {
+  Mem2Reg,     // promotes the variables in memory to registers
+  InstCombine, // simplifies instructions, e.g. `x * 2` to `x + x`
+  Reassociate, // reorders commutative operations to fold their constants
+  Gvn,         // computes the same value once
+  SimplifyCfg, // merges blocks and removes the unreachable ones
+}
+
+impl Pass {
+  pub const ALL: &'static [Pass] = &[
+    Pass::Mem2Reg,
+    Pass::InstCombine,
+    Pass::Reassociate,
+    Pass::Gvn,
+    Pass::SimplifyCfg,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Mem2Reg => "mem2reg",
+      Self::InstCombine => "instcombine",
+      Self::Reassociate => "reassociate",
+      Self::Gvn => "gvn",
+      Self::SimplifyCfg => "simplifycfg",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.iter().copied().find(|pass| pass.name() == name)
+  }
+
+  fn add_to(self, manager: &PassManager<FunctionValue>) {
+    match self {
+      Self::Mem2Reg => manager.add_promote_memory_to_register_pass(),
+      Self::InstCombine => manager.add_instruction_combining_pass(),
+      Self::Reassociate => manager.add_reassociate_pass(),
+      Self::Gvn => manager.add_gvn_pass(),
+      Self::SimplifyCfg => manager.add_cfg_simplification_pass(),
+    }
+  }
+}
+
+/// OptLevel - how much to optimize: not at all, as the code is generated
+/// to read it, by tidying it up, or with the passes of LLVM's tutorial
+/// besides.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OptLevel {
+  O0,
+  O1,
+  O2,
+}
+
+impl OptLevel {
+  pub fn passes(self) -> &'static [Pass] {
+    match self {
+      Self::O0 => &[],
+      Self::O1 => &[Pass::Mem2Reg, Pass::InstCombine, Pass::SimplifyCfg],
+      Self::O2 => &[
+        Pass::Mem2Reg,
+        Pass::InstCombine,
+        Pass::Reassociate,
+        Pass::Gvn,
+        Pass::SimplifyCfg,
+      ],
+    }
+  }
+
+  /// The level of `-O0`, `-O1` or `-O2`, given `0`, `1` or `2`.
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "0" => Some(Self::O0),
+      "1" => Some(Self::O1),
+      "2" => Some(Self::O2),
+      _ => None,
+    }
+  }
+}
+
+impl From<BuilderError> for Diagnostic {
+  fn from(e: BuilderError) -> Self {
+    let msg = format!("LLVM could not build an instruction: {}", e);
+    Diagnostic::error(Span::default(), msg).with_code("codegen")
+  }
+}
+
+impl<'ctx> Compiler<'ctx> {
+  pub fn new(context: &'ctx Context, name: &str, precision: Precision) -> Self {
+    let float = match precision {
+      Precision::F64 => context.f64_type(),
+      Precision::F32 => context.f32_type(),
+    };
+    Self {
+      context,
+      module: context.create_module(name),
+      builder: context.create_builder(),
+      float,
+      int: context.i64_type(),
+      precision,
+      functions: HashMap::new(),
+      tuples: HashMap::new(),
+      scope: vec![],
+      function: None,
+      passes: vec![],
+      dump: false,
+      dumps: vec![],
+      debug: None,
+    }
+  }
+
+  pub fn module(&self) -> &Module<'ctx> {
+    &self.module
+  }
+
+  /// Generates code for `machine`, whose triple and data layout the module
+  /// takes, for the passes to know the sizes and alignments of its types.
+  pub fn set_target(&mut self, machine: &TargetMachine) {
+    self.module.set_triple(&machine.get_triple());
+    let layout = machine.get_target_data().get_data_layout();
+    self.module.set_data_layout(&layout);
+  }
+
+  /// Optimizes the functions compiled from now on at `level`.
+  pub fn set_opt_level(&mut self, level: OptLevel) {
+    self.set_passes(level.passes());
+  }
+
+  /// Optimizes the functions compiled from now on by running `passes` on
+  /// them, in order.
+  pub fn set_passes(&mut self, passes: &[Pass]) {
+    self.passes = passes.to_vec();
+  }
+
+  /// Keeps the IR of the functions compiled from now on, before and after
+  /// their optimization, until taken by [`Compiler::take_dumps`].
+  pub fn set_dump(&mut self, dump: bool) {
+    self.dump = dump;
+  }
+
+  pub fn take_dumps(&mut self) -> Vec<IrDump> {
+    std::mem::take(&mut self.dumps)
+  }
+
+  /// Describes the functions compiled from now on in DWARF, as compiled
+  /// from the file `source`, optimized unless there are no passes to run.
+  /// Items imported from other files are described as lines of it too.
+  /// [`Compiler::finish_debug_info`] completes the description.
+  pub fn set_debug_info(&mut self, source: &Path) {
+    let version = self
+      .context
+      .i32_type()
+      .const_int(debug_metadata_version() as u64, false);
+    let flag = FlagBehavior::Warning;
+    self
+      .module
+      .add_basic_value_flag("Debug Info Version", flag, version);
+    let dwarf = self.context.i32_type().const_int(4, false);
+    self
+      .module
+      .add_basic_value_flag("Dwarf Version", flag, dwarf);
+    let file = source.file_name().unwrap_or_default().to_string_lossy();
+    let dir = source.parent().filter(|dir| !dir.as_os_str().is_empty());
+    let dir = dir.unwrap_or(Path::new("."));
+    let dir = std::fs::canonicalize(dir).unwrap_or(dir.to_path_buf());
+    let (builder, unit) = self.module.create_debug_info_builder(
+      true,
+      DWARFSourceLanguage::C,
+      &file,
+      &dir.to_string_lossy(),
+      "Kale",
+      !self.passes.is_empty(),
+      "",
+      0,
+      "",
+      DWARFEmissionKind::Full,
+      0,
+      false,
+      false,
+      "",
+      "",
+    );
+    let (name, bits) = match self.precision {
+      Precision::F64 => ("double", 64),
+      Precision::F32 => ("float", 32),
+    };
+    const DW_ATE_FLOAT: u32 = 0x04;
+    const DW_ATE_SIGNED: u32 = 0x05;
+    let float = builder
+      .create_basic_type(name, bits, DW_ATE_FLOAT, DIFlags::ZERO)
+      .unwrap()
+      .as_type();
+    let int = builder
+      .create_basic_type("int", 64, DW_ATE_SIGNED, DIFlags::ZERO)
+      .unwrap()
+      .as_type();
+    self.debug = Some(DebugInfo {
+      builder,
+      unit,
+      float,
+      int,
+      subprogram: None,
+    });
+  }
+
+  /// Completes the debug info of the module, if any, once its functions
+  /// are compiled, then verifies the module, whose functions couldn't be
+  /// on their own.
+  pub fn finish_debug_info(&self) -> Result<(), Diagnostic> {
+    let Some(debug) = &self.debug else {
+      return Ok(());
+    };
+    debug.builder.finalize();
+    self.module.verify().map_err(|e| {
+      let msg = format!("LLVM rejected the code generated: {}", e.to_string_lossy());
+      Diagnostic::error(Span::default(), msg).with_code("codegen")
+    })
+  }
+
+  /// Compiles the functions and externs of `module`, declaring them all
+  /// first so that bodies may call the functions defined further down.
+  /// Every function is compiled even when another one fails, and the
+  /// errors are reported in order.
+  pub fn compile_module(
+    &mut self,
+    module: &ModuleAst,
+  ) -> Result<Vec<FunctionValue<'ctx>>, Vec<Diagnostic>> {
+    self.tuples.extend(tuple_arities(module));
+    for item in &module.items {
+      match item {
+        Ast::Proto(proto) => {
+          self.compile_proto(proto);
+        }
+        Ast::Func(func) if !func.proto.name.is_empty() => {
+          self.declare(&func.proto);
+        }
+        _ => (),
+      }
+    }
+    let mut functions = vec![];
+    let mut errors = vec![];
+    for item in &module.items {
+      match item {
+        Ast::Func(func) => match self.compile_func(func) {
+          Ok(function) => functions.push(function),
+          Err(e) => errors.push(e),
+        },
+        Ast::Expr(expr) => {
+          let Ast::Func(func) = Ast::new_top_level(expr.clone(), Span::default()) else {
+            unreachable!()
+          };
+          match self.compile_func(&func) {
+            Ok(function) => functions.push(function),
+            Err(e) => errors.push(e),
+          }
+        }
+        Ast::Proto(_) => (),
+        item => errors.push(unsupported_item("LLVM", item)),
+      }
+    }
+    match errors.is_empty() {
+      true => Ok(functions),
+      false => Err(errors),
+    }
+  }
+
+  /// Declares the extern `proto`, which links to the function of the C
+  /// math library or of the runtime it stands for.
+  pub fn compile_proto(&mut self, proto: &ProtoAst) -> FunctionValue<'ctx> {
+    let (symbol, widen) = link_name(proto.symbol(), self.precision);
+    let function = self.module.get_function(&symbol).unwrap_or_else(|| {
+      let float = match widen {
+        true => self.context.f64_type(),
+        false => self.float,
+      };
+      let params: Vec<BasicMetadataTypeEnum> = vec![float.into(); proto.args.len()];
+      let function = self
+        .module
+        .add_function(&symbol, float.fn_type(&params, false), None);
+      for (param, name) in function.get_param_iter().zip(&proto.args) {
+        param.set_name(name);
+      }
+      function
+    });
+    let callee = Callee {
+      function,
+      sret: None,
+      widen,
+      external: true,
+    };
+    self.functions.insert(proto.name.clone(), callee);
+    function
+  }
+
+  /// Declares the function `proto` defines, which returns a tuple of
+  /// `tuple` numbers if any, for the functions compiled before it to call.
+  pub fn declare_function(&mut self, proto: &ProtoAst, tuple: Option<usize>) {
+    if let Some(n) = tuple {
+      self.tuples.insert(proto.name.clone(), n);
+    }
+    self.declare(proto);
+  }
+
+  /// Compiles the function `func`, or the top-level expression it wraps.
+  /// The calls compiled so far of a function only declared, by
+  /// [`Compiler::compile_module`], now call it, while one defined again
+  /// replaces the previous one for the functions compiled afterwards. A
+  /// function that fails to compile is left out of the module.
+  pub fn compile_func(&mut self, func: &FuncAst) -> Result<FunctionValue<'ctx>, Diagnostic> {
+    let proto = &func.proto;
+    match tuple_arity(&func.body, &self.tuples) {
+      Some(n) => {
+        self.tuples.insert(proto.name.clone(), n);
+      }
+      // a top-level expression doesn't return the tuple of the previous one
+      None if proto.name.is_empty() => {
+        self.tuples.remove("");
+      }
+      None => (),
+    }
+    let previous = self.functions.get(&proto.name).copied();
+    let callee = self.declare(proto);
+    let function = callee.function;
+    self.function = Some(callee);
+    let entry = self.context.append_basic_block(function, "entry");
+    self.builder.position_at_end(entry);
+    self.describe(function, proto, callee.sret.is_some());
+    let mut body = func.body.clone();
+    body.lower_matches();
+    let params = function
+      .get_param_iter()
+      .skip(callee.sret.is_some() as usize);
+    self.scope.clear();
+    let res = params
+      .zip(&proto.args)
+      .try_for_each(|(param, name)| {
+        let param = Val::of(param);
+        let local = match assigns(&body, name) {
+          true => Local::Var(self.build_var(name, param)?),
+          false => Local::Val(param),
+        };
+        self.scope.push((name.clone(), local));
+        Ok(())
+      })
+      .and_then(|_| self.compile_expr(&body, proto.span))
+      .and_then(|val| self.build_return(val, proto.span))
+      // the subprograms of debug info are only complete, and verified, with
+      // the module
+      .and_then(|_| match self.debug.is_some() || function.verify(false) {
+        true => Ok(()),
+        false => {
+          let msg = format!("LLVM rejected the code generated for `{}`", proto.name);
+          Err(Diagnostic::error(proto.span, msg).with_code("codegen"))
+        }
+      });
+    if let Err(e) = res {
+      unsafe { function.delete() };
+      match previous {
+        Some(previous) => self.functions.insert(proto.name.clone(), previous),
+        None => self.functions.remove(&proto.name),
+      };
+      return Err(e);
+    }
+    let declared = previous
+      .map(|previous| previous.function)
+      .filter(|declared| {
+        declared.count_basic_blocks() == 0
+          && declared.get_name().to_bytes() == proto.name.as_bytes()
+          && declared.get_type() == function.get_type()
+      });
+    if let Some(declared) = declared {
+      declared.replace_all_uses_with(function);
+      unsafe { declared.delete() };
+      function.as_global_value().set_name(&proto.name);
+    }
+    let before = self.dump.then(|| function.print_to_string().to_string());
+    self.optimize(function);
+    if let Some(before) = before {
+      self.dumps.push(IrDump {
+        function: function.get_name().to_string_lossy().into_owned(),
+        before,
+        after: function.print_to_string().to_string(),
+      });
+    }
+    Ok(function)
+  }
+
+  /// Compiles `func`, as [`Compiler::compile_func`] does, and yields its
+  /// LLVM IR, after the passes, to inspect without running it.
+  pub fn emit_ir(&mut self, func: &FuncAst) -> Result<String, Diagnostic> {
+    let function = self.compile_func(func)?;
+    Ok(function.print_to_string().to_string())
+  }
+
+  /// Runs the passes on `function`.
+  fn optimize(&self, function: FunctionValue<'ctx>) {
+    if self.passes.is_empty() {
+      return;
+    }
+    let manager = PassManager::create(&self.module);
+    for pass in &self.passes {
+      pass.add_to(&manager);
+    }
+    manager.initialize();
+    manager.run_on(&function);
+    manager.finalize();
+  }
+
+  /// Adds the C `main` of an executable, which calls `functions` in order,
+  /// discarding what they return, then returns 0. The program's own `main`,
+  /// if any, is renamed `__kale_main` to make room for it.
+  pub fn compile_main(
+    &mut self,
+    functions: &[FunctionValue<'ctx>],
+  ) -> Result<FunctionValue<'ctx>, Diagnostic> {
+    if let Some(main) = self.module.get_function("main") {
+      main.as_global_value().set_name("__kale_main");
+    }
+    let int = self.context.i32_type();
+    let main = self
+      .module
+      .add_function("main", int.fn_type(&[], false), None);
+    self.builder.unset_current_debug_location();
+    self.function = Some(Callee {
+      function: main,
+      sret: None,
+      widen: false,
+      external: false,
+    });
+    let entry = self.context.append_basic_block(main, "entry");
+    self.builder.position_at_end(entry);
+    for &function in functions {
+      let mut args: Vec<BasicMetadataValueEnum> = vec![];
+      if let Some(ret) = function.get_first_param() {
+        let ptr = ret.get_type().into_pointer_type();
+        let tuple = ptr.get_element_type().into_struct_type();
+        args.push(self.entry_alloca(tuple, "tuple")?.into());
+      }
+      self.builder.build_call(function, &args, "")?;
+    }
+    self.builder.build_return(Some(&int.const_zero()))?;
+    Ok(main)
+  }
+
+  /// Adds the function `proto` defines to the module, with no body yet.
+  fn declare(&mut self, proto: &ProtoAst) -> Callee<'ctx> {
+    let name = match proto.name.as_str() {
+      "" => "__anon_expr",
+      name => name,
+    };
+    let mut params: Vec<BasicMetadataTypeEnum> = (proto.arg_tys.iter())
+      .map(|ty| {
+        self
+          .scalar_type(Annotation::of(ty.as_deref()) == Annotation::Int)
+          .into()
+      })
+      .collect();
+    params.resize(proto.args.len(), self.float.into());
+    let ret = Annotation::returned(proto, self.tuples.get(&proto.name).copied());
+    let sret = match &ret {
+      Annotation::Tuple(ints) => {
+        let fields: Vec<_> = ints.iter().map(|&int| self.scalar_type(int)).collect();
+        Some(self.context.struct_type(&fields, false))
+      }
+      _ => None,
+    };
+    let ty = match sret {
+      Some(tuple) => {
+        params.insert(0, tuple.ptr_type(AddressSpace::default()).into());
+        self.context.void_type().fn_type(&params, false)
+      }
+      None => self
+        .scalar_type(ret == Annotation::Int)
+        .fn_type(&params, false),
+    };
+    let function = self.module.add_function(name, ty, None);
+    if let Some(tuple) = sret {
+      function.get_first_param().unwrap().set_name("ret");
+      let kind = Attribute::get_named_enum_kind_id("sret");
+      let attribute = self.context.create_type_attribute(kind, tuple.into());
+      function.add_attribute(AttributeLoc::Param(0), attribute);
+    }
+    let params = function.get_param_iter().skip(sret.is_some() as usize);
+    for (param, name) in params.zip(&proto.args) {
+      param.set_name(name);
+    }
+    let callee = Callee {
+      function,
+      sret,
+      widen: false,
+      external: false,
+    };
+    if !proto.name.is_empty() {
+      self.functions.insert(proto.name.clone(), callee);
+    }
+    callee
+  }
+
+  /// Describes `function`, which `proto` declares, as the subprogram the
+  /// instructions compiled next are located in, if there is debug info,
+  /// from the line of `proto`, or line 0 for a top-level expression.
+  fn describe(&mut self, function: FunctionValue<'ctx>, proto: &ProtoAst, sret: bool) {
+    let Some(debug) = &mut self.debug else {
+      return;
+    };
+    let file = debug.unit.get_file();
+    let (float, int) = (debug.float, debug.int);
+    let of = |ty: BasicTypeEnum| match ty.is_int_type() {
+      true => int,
+      false => float,
+    };
+    let ty = function.get_type();
+    let params = ty.get_param_types().into_iter().skip(sret as usize);
+    let params: Vec<_> = params.map(of).collect();
+    let ret = ty.get_return_type().map(of);
+    let ty = debug
+      .builder
+      .create_subroutine_type(file, ret, &params, DIFlags::ZERO);
+    let name = match proto.name.as_str() {
+      "" => "__anon_expr",
+      name => name,
+    };
+    let line = proto.span.line as u32;
+    let subprogram = debug.builder.create_function(
+      file.as_debug_info_scope(),
+      name,
+      None,
+      file,
+      line,
+      ty,
+      false,
+      true,
+      line,
+      DIFlags::ZERO,
+      !self.passes.is_empty(),
+    );
+    function.set_subprogram(subprogram);
+    debug.subprogram = Some(subprogram);
+    let scope = subprogram.as_debug_info_scope();
+    let col = proto.span.col as u32;
+    let location = debug
+      .builder
+      .create_debug_location(self.context, line, col, scope, None);
+    self.builder.set_current_debug_location(location);
+  }
+
+  /// Locates the instructions built next at `span`, in the function being
+  /// compiled, if there is debug info and `span` is in the source.
+  fn locate(&self, span: Span) {
+    let Some(debug) = &self.debug else {
+      return;
+    };
+    let Some(subprogram) = debug.subprogram.filter(|_| span.line > 0) else {
+      return;
+    };
+    let location = debug.builder.create_debug_location(
+      self.context,
+      span.line as u32,
+      span.col as u32,
+      subprogram.as_debug_info_scope(),
+      None,
+    );
+    self.builder.set_current_debug_location(location);
+  }
+
+  /// Returns `val` from the function being compiled, through its `sret`
+  /// parameter if it returns a tuple, its ints converted to the numbers
+  /// the function returns in their place.
+  fn build_return(&mut self, val: Val<'ctx>, span: Span) -> Result<(), Diagnostic> {
+    let callee = self.function.unwrap();
+    let ret = match callee.sret {
+      Some(tuple) => tuple.into(),
+      None => callee.function.get_type().get_return_type().unwrap(),
+    };
+    let val = match (val, ret) {
+      (Val::Num(_), BasicTypeEnum::IntType(_)) => {
+        let msg = "Function returns an int, found a number";
+        return Err(Diagnostic::error(span, msg).with_code("codegen"));
+      }
+      (val, ret) => self.convert(val, ret, span).map_err(|_| {
+        let msg = "Function returns both numbers and tuples, or tuples of different sizes";
+        Diagnostic::error(span, msg).with_code("codegen")
+      })?,
+    };
+    match val {
+      Val::Tuple(elems) => {
+        let ptr = callee
+          .function
+          .get_first_param()
+          .unwrap()
+          .into_pointer_value();
+        self.store_tuple(ptr, &elems)?;
+        self.builder.build_return(None)?;
+      }
+      val => {
+        self.builder.build_return(Some(&val.scalar()))?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns from the function being compiled if `failed`, once the
+  /// runtime is told of the operation on ints that failed, if it is given
+  /// one with its operands, then goes on compiling where it didn't fail.
+  /// What the function returns then is never used.
+  fn build_failure(
+    &mut self,
+    failed: IntValue<'ctx>,
+    op: Option<(IntOp, IntValue<'ctx>, IntValue<'ctx>)>,
+  ) -> Result<(), Diagnostic> {
+    let function = self.function.unwrap().function;
+    let fail_bb = self.context.append_basic_block(function, "fail");
+    let cont_bb = self.context.append_basic_block(function, "cont");
+    self
+      .builder
+      .build_conditional_branch(failed, fail_bb, cont_bb)?;
+    self.builder.position_at_end(fail_bb);
+    if let Some((op, lhs, rhs)) = op {
+      let report = self.runtime_function("kale_int_error");
+      let op = self.context.i32_type().const_int(op as u64, false);
+      let args = [op.into(), lhs.into(), rhs.into()];
+      self.builder.build_call(report, &args, "")?;
+    }
+    match function.get_type().get_return_type() {
+      Some(ty) => self.builder.build_return(Some(&ty.const_zero()))?,
+      None => self.builder.build_return(None)?,
+    };
+    self.builder.position_at_end(cont_bb);
+    Ok(())
+  }
+
+  /// Returns from the function being compiled if the function of the
+  /// module it just called failed.
+  fn check_call(&mut self) -> Result<(), Diagnostic> {
+    let failed = self.runtime_function("kale_failed");
+    let failed = self.builder.build_call(failed, &[], "failed")?;
+    let failed = failed.try_as_basic_value().left().unwrap().into_int_value();
+    let zero = failed.get_type().const_zero();
+    let failed = self
+      .builder
+      .build_int_compare(IntPredicate::NE, failed, zero, "failedtmp")?;
+    self.build_failure(failed, None)
+  }
+
+  /// The function `kale_int_error` or `kale_failed` of the runtime.
+  fn runtime_function(&self, name: &str) -> FunctionValue<'ctx> {
+    self.module.get_function(name).unwrap_or_else(|| {
+      let i32 = self.context.i32_type();
+      let ty = match name {
+        "kale_int_error" => {
+          let params = [i32.into(), self.int.into(), self.int.into()];
+          self.context.void_type().fn_type(&params, false)
+        }
+        _ => i32.fn_type(&[], false),
+      };
+      self.module.add_function(name, ty, None)
+    })
+  }
+
+  fn compile_expr(&mut self, expr: &ExprAst, span: Span) -> Result<Val<'ctx>, Diagnostic> {
+    if let Some(at) = expr_span(expr) {
+      self.locate(at);
+    }
+    let num = |n: f64| Ok(Val::Num(self.float.const_float(n)));
+    match expr {
+      ExprAst::NumAst(n) => num(*n),
+      ExprAst::IntAst(i) => Ok(Val::Int(self.int.const_int(*i as u64, true))),
+      ExprAst::BoolAst(b) => num(*b as i32 as f64),
+      ExprAst::UnitAst => num(0.0),
+      ExprAst::VarAst(name, at) => match self.scope.iter().rev().find(|(n, _)| n == name) {
+        Some((_, Local::Val(val))) => Ok(val.clone()),
+        Some((_, Local::Var(ptr))) => Ok(Val::of(self.builder.build_load(*ptr, name)?)),
+        None if self.functions.contains_key(name) => {
+          Err(unsupported("LLVM", "functions as values", *at))
+        }
+        None => Err(unsupported("LLVM", "global variables", *at)),
+      },
+      ExprAst::UnaryAst(op, operand, at) => {
+        let operand = self.compile_scalar(operand, *at)?;
+        self.locate(*at);
+        self.build_unary(*op, operand)
+      }
+      ExprAst::BinAst(lhs, op, rhs, at) => self.compile_bin(lhs, *op, rhs, *at),
+      ExprAst::CallAst(name, args, at) => self.compile_call(name, args, *at),
+      ExprAst::IfAst { cond, then, els } => self.compile_if(cond, then, els, span),
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        let mut val = Val::Num(self.float.const_zero());
+        for expr in exprs {
+          val = self.compile_expr(expr, span)?;
+        }
+        Ok(val)
+      }
+      ExprAst::TupleAst(elems) => {
+        let elems: Result<Vec<_>, _> = elems
+          .iter()
+          .map(|elem| self.compile_scalar(elem, span))
+          .collect();
+        Ok(Val::Tuple(elems?))
+      }
+      ExprAst::ElemAst(tuple, i) => match self.compile_expr(tuple, span)? {
+        Val::Tuple(elems) if *i < elems.len() => Ok(elems[*i].clone()),
+        _ => {
+          Err(Diagnostic::error(span, format!("No element {} in tuple", i)).with_code("codegen"))
+        }
+      },
+      ExprAst::LetAst(bindings, body) => {
+        let depth = self.scope.len();
+        for (name, _, init) in bindings {
+          let val = self.compile_expr(init, span)?;
+          self.scope.push((name.clone(), Local::Val(val)));
+        }
+        let res = self.compile_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let Val::Tuple(elems) = self.compile_expr(init, span)? else {
+          let msg = format!("Cannot destructure a number into {} names", names.len());
+          return Err(Diagnostic::error(span, msg).with_code("codegen"));
+        };
+        if elems.len() != names.len() {
+          let msg = format!(
+            "Cannot destructure a tuple of {} into {} names",
+            elems.len(),
+            names.len()
+          );
+          return Err(Diagnostic::error(span, msg).with_code("codegen"));
+        }
+        let depth = self.scope.len();
+        for ((name, _), elem) in names.iter().zip(elems) {
+          self.scope.push((name.clone(), Local::Val(elem)));
+        }
+        let res = self.compile_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      // the code that follows a `return` is unreachable, and takes the
+      // returned value as that of the `return`, which has the right type
+      ExprAst::ReturnAst(value, at) => {
+        let val = self.compile_expr(value, *at)?;
+        self.locate(*at);
+        self.build_return(val.clone(), *at)?;
+        let function = self.function.unwrap().function;
+        let after = self.context.append_basic_block(function, "afterreturn");
+        self.builder.position_at_end(after);
+        Ok(val)
+      }
+      ExprAst::MatchAst(..) => unreachable!("`match` is lowered before codegen"),
+      ExprAst::StrAst(_) => Err(unsupported("LLVM", "strings", span)),
+      ExprAst::ArrayAst(_) | ExprAst::IndexAst(..) => Err(unsupported("LLVM", "arrays", span)),
+      ExprAst::FieldAst(..) => Err(unsupported("LLVM", "structs", span)),
+      ExprAst::LambdaAst(..) => Err(unsupported("LLVM", "closures", span)),
+      ExprAst::FuncRefAst(_, at) => Err(unsupported("LLVM", "functions as values", *at)),
+      // a variable declared without a value starts at 0, as in the
+      // interpreter
+      ExprAst::VarInAst(vars, body) => {
+        let depth = self.scope.len();
+        let mut res = Ok(());
+        for (name, _, init) in vars {
+          let val = match init {
+            Some(init) => self.compile_scalar(init, span),
+            None => Ok(Val::Num(self.float.const_zero())),
+          };
+          match val.and_then(|val| self.build_var(name, val)) {
+            Ok(ptr) => self.scope.push((name.clone(), Local::Var(ptr))),
+            Err(e) => {
+              res = Err(e);
+              break;
+            }
+          }
+        }
+        let res = res.and_then(|_| self.compile_expr(body, span));
+        self.scope.truncate(depth);
+        res
+      }
+      ExprAst::AssignAst(name, val, _) => {
+        let val = self.compile_scalar(val, span)?;
+        match self.scope.iter().rev().find(|(n, _)| n == name) {
+          Some((_, Local::Var(ptr))) => {
+            let ptr = *ptr;
+            let ty = BasicTypeEnum::try_from(ptr.get_type().get_element_type()).unwrap();
+            let val = self.convert(val, ty, span)?;
+            self.builder.build_store(ptr, val.scalar())?;
+            Ok(val)
+          }
+          Some((_, Local::Val(_))) => {
+            let msg = format!("Cannot assign to immutable binding `{}`", name);
+            Err(Diagnostic::error(span, msg).with_code("codegen"))
+          }
+          None => Err(unsupported("LLVM", "global variables", span)),
+        }
+      }
+      ExprAst::TryAst(.., at) => Err(unsupported("LLVM", "`try`", *at)),
+    }
+  }
+
+  /// A number or an int.
+  fn compile_scalar(&mut self, expr: &ExprAst, span: Span) -> Result<Val<'ctx>, Diagnostic> {
+    match self.compile_expr(expr, span)? {
+      Val::Tuple(_) => {
+        Err(Diagnostic::error(span, "Expected a number, found a tuple").with_code("codegen"))
+      }
+      val => Ok(val),
+    }
+  }
+
+  /// `val`, a number or an int, as a number.
+  fn to_num(&self, val: Val<'ctx>) -> Result<FloatValue<'ctx>, Diagnostic> {
+    match val {
+      Val::Int(i) => Ok(
+        self
+          .builder
+          .build_signed_int_to_float(i, self.float, "numtmp")?,
+      ),
+      val => Ok(val.scalar().into_float_value()),
+    }
+  }
+
+  /// `val` as a value of type `ty`: ints convert to numbers, the elements
+  /// of tuples too, but numbers don't convert to ints.
+  fn convert(
+    &self,
+    val: Val<'ctx>,
+    ty: BasicTypeEnum<'ctx>,
+    span: Span,
+  ) -> Result<Val<'ctx>, Diagnostic> {
+    match (val, ty) {
+      (Val::Int(i), BasicTypeEnum::FloatType(float)) => Ok(Val::Num(
+        self.builder.build_signed_int_to_float(i, float, "numtmp")?,
+      )),
+      (val @ Val::Num(_), BasicTypeEnum::FloatType(_))
+      | (val @ Val::Int(_), BasicTypeEnum::IntType(_)) => Ok(val),
+      (Val::Tuple(elems), BasicTypeEnum::StructType(tuple))
+        if elems.len() == tuple.count_fields() as usize =>
+      {
+        let elems = elems.into_iter().zip(tuple.get_field_types());
+        let elems: Result<Vec<_>, _> = elems
+          .map(|(elem, ty)| self.convert(elem, ty, span))
+          .collect();
+        Ok(Val::Tuple(elems?))
+      }
+      (val, ty) => {
+        let expected = match ty {
+          BasicTypeEnum::IntType(_) => "an int",
+          BasicTypeEnum::StructType(_) => "a tuple",
+          _ => "a number",
+        };
+        let msg = format!("Expected {}, found {}", expected, val.describe());
+        Err(Diagnostic::error(span, msg).with_code("codegen"))
+      }
+    }
+  }
+
+  /// The type of the values of both `a` and `b`, if they have one: that of
+  /// ints if both are, or else of numbers, or of tuples of the same size,
+  /// joined elementwise.
+  fn join(&self, a: &Val<'ctx>, b: &Val<'ctx>) -> Option<BasicTypeEnum<'ctx>> {
+    match (a, b) {
+      (Val::Int(_), Val::Int(_)) => Some(self.int.into()),
+      (Val::Tuple(a), Val::Tuple(b)) if a.len() == b.len() => {
+        let fields: Option<Vec<_>> = a.iter().zip(b).map(|(a, b)| self.join(a, b)).collect();
+        Some(self.context.struct_type(&fields?, false).into())
+      }
+      (Val::Tuple(_), _) | (_, Val::Tuple(_)) => None,
+      _ => Some(self.float.into()),
+    }
+  }
+
+  /// The type of the numbers, or of the ints if `int`.
+  fn scalar_type(&self, int: bool) -> BasicTypeEnum<'ctx> {
+    match int {
+      true => self.int.into(),
+      false => self.float.into(),
+    }
+  }
+
+  /// A condition, as the `i1` of whether `expr` is true: nonzero, which NaN
+  /// is.
+  fn compile_cond(&mut self, expr: &ExprAst, span: Span) -> Result<IntValue<'ctx>, Diagnostic> {
+    let cond = match self.compile_scalar(expr, span)? {
+      Val::Int(i) => {
+        let zero = self.int.const_zero();
+        (self.builder).build_int_compare(IntPredicate::NE, i, zero, "cond")?
+      }
+      val => {
+        let zero = self.float.const_zero();
+        let val = val.scalar().into_float_value();
+        (self.builder).build_float_compare(FloatPredicate::UNE, val, zero, "cond")?
+      }
+    };
+    Ok(cond)
+  }
+
+  /// `-` or `!` of a number or an int, which fails to negate the least
+  /// int.
+  fn build_unary(&mut self, op: UnOp, operand: Val<'ctx>) -> Result<Val<'ctx>, Diagnostic> {
+    let cmp = match (op, operand) {
+      (UnOp::Neg, Val::Int(i)) => {
+        let min = self.int.const_int(i64::MIN as u64, true);
+        let failed = self
+          .builder
+          .build_int_compare(IntPredicate::EQ, i, min, "overflow")?;
+        self.build_failure(failed, Some((IntOp::Neg, i, i)))?;
+        return Ok(Val::Int(self.builder.build_int_neg(i, "negtmp")?));
+      }
+      (UnOp::Neg, operand) => {
+        let operand = operand.scalar().into_float_value();
+        return Ok(Val::Num(self.builder.build_float_neg(operand, "negtmp")?));
+      }
+      (UnOp::Not, Val::Int(i)) => {
+        let zero = self.int.const_zero();
+        (self.builder).build_int_compare(IntPredicate::EQ, i, zero, "nottmp")?
+      }
+      (UnOp::Not, operand) => {
+        let zero = self.float.const_zero();
+        let operand = operand.scalar().into_float_value();
+        (self.builder).build_float_compare(FloatPredicate::OEQ, operand, zero, "nottmp")?
+      }
+    };
+    Ok(Val::Num(
+      self
+        .builder
+        .build_unsigned_int_to_float(cmp, self.float, "booltmp")?,
+    ))
+  }
+
+  fn compile_bin(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Val<'ctx>, Diagnostic> {
+    let overload = format!("binary{}", op.as_str());
+    if self.functions.contains_key(&overload) {
+      return self.compile_call(&overload, &[lhs.clone(), rhs.clone()], span);
+    }
+    if let BinOp::And | BinOp::Or = op {
+      return self.compile_logical(lhs, op, rhs, span);
+    }
+    let l = self.compile_scalar(lhs, span)?;
+    let r = self.compile_scalar(rhs, span)?;
+    self.locate(span);
+    let (l, r) = match (l, r) {
+      (Val::Int(l), Val::Int(r)) => return self.build_int_bin(l, op, r),
+      _ if op.is_bitwise() => {
+        let msg = format!("Operator `{}` takes ints, found numbers", op.as_str());
+        return Err(Diagnostic::error(span, msg).with_code("codegen"));
+      }
+      (l, r) => (self.to_num(l)?, self.to_num(r)?),
+    };
+    let b = &self.builder;
+    let predicate = match op {
+      BinOp::Add => return Ok(Val::Num(b.build_float_add(l, r, "addtmp")?)),
+      BinOp::Sub => return Ok(Val::Num(b.build_float_sub(l, r, "subtmp")?)),
+      BinOp::Mul => return Ok(Val::Num(b.build_float_mul(l, r, "multmp")?)),
+      BinOp::Div => return Ok(Val::Num(b.build_float_div(l, r, "divtmp")?)),
+      BinOp::Rem => return Ok(Val::Num(b.build_float_rem(l, r, "remtmp")?)),
+      BinOp::Lt => FloatPredicate::OLT,
+      BinOp::Gt => FloatPredicate::OGT,
+      BinOp::Le => FloatPredicate::OLE,
+      BinOp::Ge => FloatPredicate::OGE,
+      BinOp::Eq => FloatPredicate::OEQ,
+      BinOp::Ne => FloatPredicate::UNE,
+      _ => unreachable!(),
+    };
+    let cmp = b.build_float_compare(predicate, l, r, "cmptmp")?;
+    Ok(Val::Num(
+      b.build_unsigned_int_to_float(cmp, self.float, "booltmp")?,
+    ))
+  }
+
+  /// An operation on ints, which fails as in the interpreter: on overflow,
+  /// on a division by zero and on a shift by a negative number of bits or
+  /// by 64 or more.
+  fn build_int_bin(
+    &mut self,
+    l: IntValue<'ctx>,
+    op: BinOp,
+    r: IntValue<'ctx>,
+  ) -> Result<Val<'ctx>, Diagnostic> {
+    let with_overflow = match op {
+      BinOp::Add => Some(("llvm.sadd.with.overflow", "addtmp")),
+      BinOp::Sub => Some(("llvm.ssub.with.overflow", "subtmp")),
+      BinOp::Mul => Some(("llvm.smul.with.overflow", "multmp")),
+      _ => None,
+    };
+    if let Some((intrinsic, name)) = with_overflow {
+      let intrinsic = Intrinsic::find(intrinsic).unwrap();
+      let intrinsic = intrinsic
+        .get_declaration(&self.module, &[self.int.into()])
+        .unwrap();
+      let res = self
+        .builder
+        .build_call(intrinsic, &[l.into(), r.into()], "")?;
+      let res = res.try_as_basic_value().left().unwrap().into_struct_value();
+      let overflow = self.builder.build_extract_value(res, 1, "overflow")?;
+      let failed = Some((IntOp::of(op).unwrap(), l, r));
+      self.build_failure(overflow.into_int_value(), failed)?;
+      let res = self.builder.build_extract_value(res, 0, name)?;
+      return Ok(Val::Int(res.into_int_value()));
+    }
+    let b = &self.builder;
+    let failed = match op {
+      // the least int divided by -1 overflows
+      BinOp::Div | BinOp::Rem => {
+        let (zero, minus_one) = (self.int.const_zero(), self.int.const_all_ones());
+        let min = self.int.const_int(i64::MIN as u64, true);
+        let by_zero = b.build_int_compare(IntPredicate::EQ, r, zero, "byzero")?;
+        let is_min = b.build_int_compare(IntPredicate::EQ, l, min, "ismin")?;
+        let by_minus_one = b.build_int_compare(IntPredicate::EQ, r, minus_one, "byminusone")?;
+        let overflow = b.build_and(is_min, by_minus_one, "overflow")?;
+        Some(b.build_or(by_zero, overflow, "failed")?)
+      }
+      BinOp::Shl | BinOp::Shr => {
+        let bits = self.int.const_int(64, false);
+        Some(b.build_int_compare(IntPredicate::UGE, r, bits, "toofar")?)
+      }
+      _ => None,
+    };
+    if let Some(failed) = failed {
+      self.build_failure(failed, Some((IntOp::of(op).unwrap(), l, r)))?;
+    }
+    let b = &self.builder;
+    let predicate = match op {
+      BinOp::Div => return Ok(Val::Int(b.build_int_signed_div(l, r, "divtmp")?)),
+      BinOp::Rem => return Ok(Val::Int(b.build_int_signed_rem(l, r, "remtmp")?)),
+      BinOp::BitAnd => return Ok(Val::Int(b.build_and(l, r, "andtmp")?)),
+      BinOp::BitOr => return Ok(Val::Int(b.build_or(l, r, "ortmp")?)),
+      BinOp::Xor => return Ok(Val::Int(b.build_xor(l, r, "xortmp")?)),
+      BinOp::Shl => return Ok(Val::Int(b.build_left_shift(l, r, "shltmp")?)),
+      BinOp::Shr => return Ok(Val::Int(b.build_right_shift(l, r, true, "shrtmp")?)),
+      BinOp::Lt => IntPredicate::SLT,
+      BinOp::Gt => IntPredicate::SGT,
+      BinOp::Le => IntPredicate::SLE,
+      BinOp::Ge => IntPredicate::SGE,
+      BinOp::Eq => IntPredicate::EQ,
+      BinOp::Ne => IntPredicate::NE,
+      _ => unreachable!(),
+    };
+    let cmp = b.build_int_compare(predicate, l, r, "cmptmp")?;
+    Ok(Val::Num(
+      b.build_unsigned_int_to_float(cmp, self.float, "booltmp")?,
+    ))
+  }
+
+  /// `&&` and `||`, which only evaluate `rhs` when `lhs` doesn't decide.
+  fn compile_logical(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Val<'ctx>, Diagnostic> {
+    let function = self.function.unwrap().function;
+    let l = self.compile_cond(lhs, span)?;
+    let lhs_end = self.builder.get_insert_block().unwrap();
+    let rhs_bb = self.context.append_basic_block(function, "rhs");
+    let merge_bb = self.context.append_basic_block(function, "logicalcont");
+    match op {
+      BinOp::And => self.builder.build_conditional_branch(l, rhs_bb, merge_bb)?,
+      _ => self.builder.build_conditional_branch(l, merge_bb, rhs_bb)?,
+    };
+    self.builder.position_at_end(rhs_bb);
+    let r = self.compile_cond(rhs, span)?;
+    let rhs_end = self.builder.get_insert_block().unwrap();
+    self.builder.build_unconditional_branch(merge_bb)?;
+    self.builder.position_at_end(merge_bb);
+    let phi = self
+      .builder
+      .build_phi(self.context.bool_type(), "logicaltmp")?;
+    phi.add_incoming(&[(&l, lhs_end), (&r, rhs_end)]);
+    let res = phi.as_basic_value().into_int_value();
+    Ok(Val::Num(
+      self
+        .builder
+        .build_unsigned_int_to_float(res, self.float, "booltmp")?,
+    ))
+  }
+
+  fn compile_if(
+    &mut self,
+    cond: &ExprAst,
+    then: &ExprAst,
+    els: &ExprAst,
+    span: Span,
+  ) -> Result<Val<'ctx>, Diagnostic> {
+    let function = self.function.unwrap().function;
+    let cond = self.compile_cond(cond, span)?;
+    let then_bb = self.context.append_basic_block(function, "then");
+    let else_bb = self.context.append_basic_block(function, "else");
+    let merge_bb = self.context.append_basic_block(function, "ifcont");
+    self
+      .builder
+      .build_conditional_branch(cond, then_bb, else_bb)?;
+    let mut branches = vec![];
+    for (bb, branch) in [(then_bb, then), (else_bb, els)] {
+      self.builder.position_at_end(bb);
+      let val = self.compile_expr(branch, span)?;
+      branches.push((val, self.builder.get_insert_block().unwrap()));
+    }
+    let [(then_val, then_end), (else_val, else_end)] = <[_; 2]>::try_from(branches).ok().unwrap();
+    let Some(ty) = self.join(&then_val, &else_val) else {
+      let msg = "Branches of `if` yield both numbers and tuples, or tuples of different sizes";
+      return Err(Diagnostic::error(span, msg).with_code("codegen"));
+    };
+    // an int that joins a number converts to one at the end of its branch
+    let mut vals = vec![];
+    for (val, end) in [(then_val, then_end), (else_val, else_end)] {
+      self.builder.position_at_end(end);
+      vals.push(self.convert(val, ty, span)?);
+      self.builder.build_unconditional_branch(merge_bb)?;
+    }
+    self.builder.position_at_end(merge_bb);
+    let [a, b] = <[_; 2]>::try_from(vals).ok().unwrap();
+    self.build_phi(a, then_end, b, else_end)
+  }
+
+  /// The value of `a` when coming from the block `a_end`, or else of `b`,
+  /// of the same type, from `b_end`.
+  fn build_phi(
+    &self,
+    a: Val<'ctx>,
+    a_end: BasicBlock<'ctx>,
+    b: Val<'ctx>,
+    b_end: BasicBlock<'ctx>,
+  ) -> Result<Val<'ctx>, Diagnostic> {
+    match (a, b) {
+      (Val::Tuple(a), Val::Tuple(b)) => {
+        let elems = a.into_iter().zip(b);
+        let elems: Result<Vec<_>, _> = elems
+          .map(|(a, b)| self.build_phi(a, a_end, b, b_end))
+          .collect();
+        Ok(Val::Tuple(elems?))
+      }
+      (a, b) => {
+        let (a, b) = (a.scalar(), b.scalar());
+        let phi = self.builder.build_phi(a.get_type(), "iftmp")?;
+        phi.add_incoming(&[(&a, a_end), (&b, b_end)]);
+        Ok(Val::of(phi.as_basic_value()))
+      }
+    }
+  }
+
+  /// A call of a function of the module, which returns if that fails, or
+  /// of an extern, or of `int` and `float`, which convert numbers to ints,
+  /// rounding towards zero, and ints to numbers.
+  fn compile_call(
+    &mut self,
+    name: &str,
+    args: &[ExprAst],
+    span: Span,
+  ) -> Result<Val<'ctx>, Diagnostic> {
+    if self.scope.iter().any(|(local, _)| local == name) {
+      return Err(unsupported("LLVM", "closures", span));
+    }
+    let mut vals = vec![];
+    for arg in args {
+      vals.push(self.compile_scalar(arg, span)?);
+    }
+    self.locate(span);
+    match (name, &vals[..]) {
+      ("float", [x]) => return Ok(Val::Num(self.to_num(x.clone())?)),
+      ("int", [Val::Int(i)]) => return Ok(Val::Int(*i)),
+      ("int", [Val::Num(x)]) => return Ok(Val::Int(self.build_to_int(*x)?)),
+      _ => (),
+    }
+    if !self.functions.contains_key(name) {
+      let proto = prelude().items.into_iter().find_map(|item| match item {
+        Ast::Proto(proto) if proto.name == name => Some(proto),
+        _ => None,
+      });
+      if let Some(proto) = proto {
+        self.compile_proto(&proto);
+      }
+    }
+    let Some(callee) = self.functions.get(name).copied() else {
+      return Err(unsupported(
+        "LLVM",
+        &format!("the builtin `{}`", name),
+        span,
+      ));
+    };
+    let widen = callee.widen && self.precision == Precision::F32;
+    let params = callee.function.get_type().get_param_types();
+    let params = params.into_iter().skip(callee.sret.is_some() as usize);
+    let mut args: Vec<BasicMetadataValueEnum> = vec![];
+    for (val, ty) in vals.into_iter().zip(params) {
+      let arg = match widen {
+        true => {
+          let val = self.to_num(val)?;
+          let f64 = self.context.f64_type();
+          self.builder.build_float_ext(val, f64, "widetmp")?.into()
+        }
+        false => self.convert(val, ty, span)?.scalar(),
+      };
+      args.push(arg.into());
+    }
+    let Some(tuple) = callee.sret else {
+      let call = self.builder.build_call(callee.function, &args, "calltmp")?;
+      let res = Val::of(call.try_as_basic_value().left().unwrap());
+      if !callee.external {
+        self.check_call()?;
+      }
+      return match (res, widen) {
+        (Val::Num(res), true) => Ok(Val::Num(self.builder.build_float_trunc(
+          res,
+          self.float,
+          "narrowtmp",
+        )?)),
+        (res, _) => Ok(res),
+      };
+    };
+    let ptr = self.entry_alloca(tuple, "tuple")?;
+    args.insert(0, ptr.into());
+    self.builder.build_call(callee.function, &args, "")?;
+    self.check_call()?;
+    let mut elems = vec![];
+    for i in 0..tuple.count_fields() {
+      let field = self.builder.build_struct_gep(ptr, i, "elemptr")?;
+      elems.push(Val::of(self.builder.build_load(field, "elem")?));
+    }
+    Ok(Val::Tuple(elems))
+  }
+
+  /// `int(x)`: `x` rounded towards zero, which fails out of the range of
+  /// ints, as does NaN.
+  fn build_to_int(&mut self, x: FloatValue<'ctx>) -> Result<IntValue<'ctx>, Diagnostic> {
+    // i64::MAX rounds up to 2^63, hence the exclusive bound
+    let bound = 2f64.powi(63);
+    let b = &self.builder;
+    let min = self.float.const_float(-bound);
+    let max = self.float.const_float(bound);
+    let above_min = b.build_float_compare(FloatPredicate::OGE, x, min, "abovemin")?;
+    let below_max = b.build_float_compare(FloatPredicate::OLT, x, max, "belowmax")?;
+    let in_range = b.build_and(above_min, below_max, "inrange")?;
+    let failed = b.build_not(in_range, "failed")?;
+    let double = match self.precision {
+      Precision::F64 => x,
+      Precision::F32 => b.build_float_ext(x, self.context.f64_type(), "widetmp")?,
+    };
+    let bits = b.build_bit_cast(double, self.int, "bits")?.into_int_value();
+    self.build_failure(failed, Some((IntOp::ToInt, bits, bits)))?;
+    Ok(
+      self
+        .builder
+        .build_float_to_signed_int(x, self.int, "inttmp")?,
+    )
+  }
+
+  /// Stores the numbers and ints of a tuple in the struct `ptr` points to.
+  fn store_tuple(
+    &mut self,
+    ptr: PointerValue<'ctx>,
+    elems: &[Val<'ctx>],
+  ) -> Result<(), Diagnostic> {
+    for (i, elem) in elems.iter().enumerate() {
+      let field = self.builder.build_struct_gep(ptr, i as u32, "elemptr")?;
+      self.builder.build_store(field, elem.scalar())?;
+    }
+    Ok(())
+  }
+
+  /// The stack slot of the mutable variable `name`, which starts at `val`,
+  /// a number or an int, and holds values of its type.
+  fn build_var(&mut self, name: &str, val: Val<'ctx>) -> Result<PointerValue<'ctx>, Diagnostic> {
+    let val = val.scalar();
+    let ptr = self.entry_alloca(val.get_type(), name)?;
+    self.builder.build_store(ptr, val)?;
+    Ok(ptr)
+  }
+
+  /// Stack memory for a value of type `ty` in the entry block of the
+  /// function being compiled, where it is allocated once per call.
+  fn entry_alloca(
+    &mut self,
+    ty: impl BasicType<'ctx>,
+    name: &str,
+  ) -> Result<PointerValue<'ctx>, Diagnostic> {
+    let function = self.function.unwrap().function;
+    let entry = function.get_first_basic_block().unwrap();
+    let builder = self.context.create_builder();
+    match entry.get_first_instruction() {
+      Some(first) => builder.position_before(&first),
+      None => builder.position_at_end(entry),
+    }
+    Ok(builder.build_alloca(ty, name)?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  fn compile(src: &'static str, precision: Precision) -> Result<String, Vec<String>> {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context, "test", precision);
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    match compiler.compile_module(&module) {
+      Ok(_) => {
+        assert!(compiler.module().verify().is_ok());
+        Ok(compiler.module().print_to_string().to_string())
+      }
+      Err(errors) => Err(errors.iter().map(Diagnostic::to_string).collect()),
+    }
+  }
+
+  #[test]
+  fn llvm_functions() {
+    let src = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2);
+      def binary ~ 5 (a b) a * 2 + b; def half(x) int(x / 2) ~ 1; extern abs(x);
+      def sorted(a, b) if a < b && !(a == b) then (a, b) else (b, a);
+      def spread(a, b) let (lo, hi) = sorted(a, b) in match hi - lo { 0 -> 0, _ -> abs(hi) };
+      fib(10)";
+    let ir = compile(src, Precision::F64).unwrap();
+    for expected in [
+      "define double @fib(double %n)",
+      "%cmptmp = fcmp olt double %n, 2.000000e+00",
+      "%booltmp = uitofp i1 %cmptmp to double",
+      "%calltmp = call double @fib(double %subtmp)",
+      "%inttmp = fptosi double %divtmp to i64",
+      "call double @\"binary~\"(double %numtmp, double 1.000000e+00)",
+      "define void @sorted({ double, double }* sret({ double, double }) %ret, double %a, double %b)",
+      "call void @sorted({ double, double }* %tuple, double %a, double %b)",
+      "declare double @fabs(double)",
+      "define double @__anon_expr()",
+    ] {
+      assert!(ir.contains(expected), "no `{}` in:\n{}", expected, ir);
+    }
+    let ir = compile("extern printd(x); def f(x) printd(sin(x));", Precision::F32).unwrap();
+    for expected in [
+      "define float @f(float %x)",
+      "call float @sinf(float %x)",
+      "fpext float",
+    ] {
+      assert!(ir.contains(expected), "no `{}` in:\n{}", expected, ir);
+    }
+  }
+
+  #[test]
+  fn llvm_ints() {
+    let src = "def big(a: int): int a * a + 1; def half(a: int): int a / 2;
+      def pick(a: int, x) if x < 0 then a else x; def bits(a: int): int (a << 3) | 1;";
+    let ir = compile(src, Precision::F64).unwrap();
+    for expected in [
+      "define i64 @big(i64 %a)",
+      "call { i64, i1 } @llvm.smul.with.overflow.i64(i64 %a, i64 %a)",
+      "call void @kale_int_error(i32 2, i64 %a, i64 %a)",
+      "%divtmp = sdiv i64 %a, 2",
+      "%numtmp = sitofp i64 %a to double",
+      "%iftmp = phi double [ %numtmp, %then ], [ %x, %else ]",
+      "%shltmp = shl i64 %a, 3",
+    ] {
+      assert!(ir.contains(expected), "no `{}` in:\n{}", expected, ir);
+    }
+    assert_eq!(
+      compile("def f(x) x << 1; def g(x): int x;", Precision::F64),
+      Err(vec![
+        "1:12: Operator `<<` takes ints, found numbers".to_string(),
+        "1:22: Function returns an int, found a number".to_string(),
+      ])
+    );
+  }
+
+  #[test]
+  fn llvm_emit_ir() {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context, "test", Precision::F64);
+    let src = "def twice(x) x * 2;";
+    let Ast::Func(func) = Ast::parse(&mut Lexer::new(Cursor::new(src))).unwrap() else {
+      unreachable!()
+    };
+    assert_eq!(
+      compiler.emit_ir(&func).unwrap(),
+      "define double @twice(double %x) {
+entry:
+  %multmp = fmul double %x, 2.000000e+00
+  ret double %multmp
+}
+"
+    );
+  }
+
+  #[test]
+  fn llvm_unsupported() {
+    let src = "def f(s) len(s); def g(x) \"a\"; def h(x) { return (x, x); x }; def ok(x) x;";
+    assert_eq!(
+      compile(src, Precision::F64),
+      Err(vec![
+        "1:10: The LLVM backend doesn't support the builtin `len`".to_string(),
+        "1:22: The LLVM backend doesn't support strings".to_string(),
+        "1:36: Function returns both numbers and tuples, or tuples of different sizes".to_string(),
+      ])
+    );
+  }
+
+  #[test]
+  fn llvm_passes() {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context, "test", Precision::F64);
+    compiler.set_passes(&[Pass::Gvn, Pass::SimplifyCfg]);
+    compiler.set_dump(true);
+    let src = "def f(x) (x + 1) * 2 + (x + 1) * 2; def g(x) if 1 then x else 0;";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    compiler.compile_module(&module).unwrap();
+    let dumps = compiler.take_dumps();
+    let names: Vec<_> = dumps.iter().map(|dump| dump.function.as_str()).collect();
+    assert_eq!(names, ["f", "g"]);
+    assert!(dumps[0].before.contains("%addtmp1 = fadd double %x, 1.0"));
+    assert!(!dumps[0].after.contains("%addtmp1"), "{}", dumps[0]);
+    assert!(dumps[1].before.contains("else:"));
+    assert!(!dumps[1].after.contains("else:"), "{}", dumps[1]);
+    assert!(compiler.take_dumps().is_empty());
+    assert_eq!(Pass::from_name("instcombine"), Some(Pass::InstCombine));
+    assert_eq!(OptLevel::from_name("3"), None);
+  }
+
+  #[test]
+  fn llvm_debug_info() {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context, "test", Precision::F64);
+    compiler.set_debug_info(Path::new("prog.kale"));
+    let src = "def fib(n)\n  if n < 2 then n\n  else fib(n - 1) + fib(n - 2);\nfib(10);";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let functions = compiler.compile_module(&module).unwrap();
+    compiler.compile_main(&functions[1..]).unwrap();
+    compiler.finish_debug_info().unwrap();
+    let ir = compiler.module().print_to_string().to_string();
+    for expected in [
+      "!DIFile(filename: \"prog.kale\"",
+      "!DISubprogram(name: \"fib\", linkageName: \"fib\", scope: !3, file: !3, line: 1",
+      "!DISubprogram(name: \"__anon_expr\"",
+      "!DILocation(line: 2, column: 8",
+      "!DILocation(line: 3, column: 19",
+      "!DILocation(line: 4, column: 1",
+    ] {
+      assert!(ir.contains(expected), "no `{}` in:\n{}", expected, ir);
+    }
+  }
+
+  #[test]
+  fn llvm_mutable_variables() {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context, "test", Precision::F64);
+    compiler.set_passes(&[Pass::Mem2Reg]);
+    compiler.set_dump(true);
+    let src = "def clamp(x, y) { if x < 0 then x = 0 else 0; x + y };
+      def f(a) var b = a * 2, c in { b = b + 1; if b > 10 then c = 10 else c = b; c * a };";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    compiler.compile_module(&module).unwrap();
+    let dumps = compiler.take_dumps();
+    assert!(dumps[0].before.contains("%x1 = alloca double"));
+    assert!(!dumps[0].before.contains("%y1"), "{}", dumps[0]);
+    assert!(dumps[1].before.contains("store double 0.0"));
+    for dump in &dumps {
+      assert!(!dump.after.contains("alloca"), "{}", dump);
+      assert!(dump.after.contains("phi double"), "{}", dump);
+    }
+    assert_eq!(
+      compile(
+        "def g(x) let y = x in y = 1; def h(x) z = x;",
+        Precision::F64
+      ),
+      Err(vec![
+        "1:5: Cannot assign to immutable binding `y`".to_string(),
+        "1:34: The LLVM backend doesn't support global variables".to_string(),
+      ])
+    );
+  }
+}
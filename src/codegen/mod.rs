@@ -0,0 +1,663 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, ProtoAst, UnOp};
+use crate::value::{Precision, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub mod backend;
+pub mod c;
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+#[cfg(feature = "llvm")]
+pub mod jit;
+pub mod js;
+#[cfg(feature = "llvm")]
+pub mod llvm;
+#[cfg(feature = "llvm")]
+pub mod native;
+pub mod rust;
+pub mod wasm;
+
+/// The C source of the runtime that executables link to, as do the
+/// programs transpiled to C.
+pub const RUNTIME: &str = include_str!("runtime.c");
+
+/// The error of a backend about a construct it can't compile. Native code
+/// computes with numbers, and with ints where the checker infers them: the
+/// literals, variables and arithmetic of numbers, ints and booleans, calls,
+/// conditionals, `let`s, `var`s, `match`es, `return`s and tuples of
+/// those. Strings, arrays, structs, closures and the other constructs only
+/// run in the interpreter, and the transpilers and WebAssembly don't
+/// support `var`s or assignments either, which are reported at the first
+/// name they bind or assign.
+pub fn unsupported(backend: &str, what: &str, span: Span) -> Diagnostic {
+  let msg = format!("The {} backend doesn't support {}", backend, what);
+  Diagnostic::error(span, msg).with_code("codegen")
+}
+
+/// The error of a backend about an item other than a function, an extern
+/// or a top-level expression.
+pub fn unsupported_item(backend: &str, item: &Ast) -> Diagnostic {
+  match item {
+    Ast::Global(_, span) => unsupported(backend, "global variables", *span),
+    Ast::Const(.., span) => unsupported(backend, "constants", *span),
+    Ast::Struct(decl) => unsupported(backend, "structs", decl.span),
+    Ast::Import(_, _, span) => unsupported(backend, "`import`", *span),
+    Ast::Func(_) | Ast::Proto(_) | Ast::Expr(_) => unreachable!("backends compile those"),
+  }
+}
+
+/// Engine - runs the items of a session in place of the interpreter, as
+/// native code as the JITs of the backends do, or as bytecode in the VM.
+pub trait Engine {
+  /// Declares the functions of `module`, so that those defined first can
+  /// call those defined further down.
+  fn declare(&mut self, module: &ModuleAst);
+
+  /// Defines a function or an extern, which is compiled right away to
+  /// report its errors, or runs a top-level expression, yielding its value.
+  fn run(&mut self, item: Ast) -> Result<Option<Value>, Diagnostic>;
+
+  fn set_precision(&mut self, precision: Precision);
+}
+
+/// Definitions - the latest definition of each function and extern given
+/// to a JIT, which compiles them anew along with each top-level
+/// expression. A function defined again thus replaces the previous one for
+/// every caller, as in the interpreter.
+pub struct Definitions {
+  module: ModuleAst,
+}
+
+impl Definitions {
+  pub fn new() -> Self {
+    Self {
+      module: ModuleAst { items: vec![] },
+    }
+  }
+
+  pub fn module(&self) -> &ModuleAst {
+    &self.module
+  }
+
+  /// Declares the functions of `module` not defined yet, by their
+  /// prototypes.
+  pub fn declare(&mut self, module: &ModuleAst) {
+    for item in &module.items {
+      if let Ast::Func(func) = item {
+        if !func.proto.name.is_empty() && self.position(&func.proto.name).is_none() {
+          self.module.items.push(Ast::Proto(func.proto.clone()));
+        }
+      }
+    }
+  }
+
+  /// Replaces the definition of the name of `item`, a function or an
+  /// extern, with it, unless `check` fails on the definitions that result,
+  /// which are then left as they were.
+  pub fn define<E>(
+    &mut self,
+    item: Ast,
+    check: impl FnOnce(&ModuleAst) -> Result<(), E>,
+  ) -> Result<(), E> {
+    let name = match &item {
+      Ast::Func(func) => &func.proto.name,
+      Ast::Proto(proto) => &proto.name,
+      _ => unreachable!("only functions and externs are defined"),
+    };
+    let previous = self.position(name).map(|i| self.module.items.remove(i));
+    self.module.items.push(item);
+    let res = check(&self.module);
+    if res.is_err() {
+      self.module.items.pop();
+      self.module.items.extend(previous);
+    }
+    res
+  }
+
+  fn position(&self, name: &str) -> Option<usize> {
+    self.module.items.iter().position(|def| match def {
+      Ast::Func(func) => func.proto.name == name,
+      Ast::Proto(proto) => proto.name == name,
+      _ => false,
+    })
+  }
+}
+
+/// IrDump - the code of a function in the IR of a backend, before and
+/// after its optimization.
+#[derive(Debug, PartialEq)]
+pub struct IrDump {
+  pub function: String,
+  pub before: String,
+  pub after: String,
+}
+
+impl fmt::Display for IrDump {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "; `{}` before optimization", self.function)?;
+    writeln!(f, "{}", self.before.trim_end())?;
+    writeln!(f, "; `{}` after optimization", self.function)?;
+    writeln!(f, "{}", self.after.trim_end())
+  }
+}
+
+/// Writes `dumps` to `out`, if any. A dump that can't be written is lost,
+/// as it isn't an error of the program.
+pub fn write_dumps(out: &mut Option<Box<dyn std::io::Write>>, dumps: Vec<IrDump>) {
+  if let Some(out) = out {
+    for dump in dumps {
+      let _ = write!(out, "{}", dump);
+    }
+  }
+}
+
+/// Emit - what to build: an executable, or an object file to link with the
+/// runtime, as for a target the C compiler can't link for.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Emit {
+  Executable,
+  Object,
+}
+
+/// Links `object` with the runtime, written to `runtime`, into the
+/// executable `output`.
+pub fn link(object: &Path, runtime: &Path, output: &Path) -> Result<(), Diagnostic> {
+  std::fs::write(runtime, RUNTIME).map_err(|e| codegen_error(e.to_string()))?;
+  let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+  let res = Command::new(&cc)
+    .arg(object)
+    .arg(runtime)
+    .args(["-lm", "-o"])
+    .arg(output)
+    .output();
+  let failed = match res {
+    Ok(res) if res.status.success() => return Ok(()),
+    Ok(res) => String::from_utf8_lossy(&res.stderr).trim_end().to_string(),
+    Err(e) => e.to_string(),
+  };
+  let msg = format!("Cannot link `{}` with `{}`", output.display(), cc);
+  Err(
+    Diagnostic::error(Span::default(), msg)
+      .with_code("link")
+      .with_note(failed),
+  )
+}
+
+/// A new directory for the intermediate files of a build.
+pub fn scratch_dir() -> Result<PathBuf, Diagnostic> {
+  static BUILDS: AtomicUsize = AtomicUsize::new(0);
+  let n = BUILDS.fetch_add(1, Ordering::Relaxed);
+  let dir = std::env::temp_dir().join(format!("kale-build-{}-{}", std::process::id(), n));
+  std::fs::create_dir_all(&dir).map_err(|e| codegen_error(e.to_string()))?;
+  Ok(dir)
+}
+
+fn codegen_error(msg: String) -> Diagnostic {
+  Diagnostic::error(Span::default(), msg).with_code("codegen")
+}
+
+/// The number of values each function of `module` returns as a tuple, for
+/// those that return one: native code passes them through memory that the
+/// caller provides, in the manner of C's `sret`. A function that calls
+/// itself, or others that return tuples, is known from its other paths.
+pub fn tuple_arities(module: &ModuleAst) -> HashMap<String, usize> {
+  let mut arities = HashMap::new();
+  loop {
+    let before = arities.len();
+    for item in &module.items {
+      if let Ast::Func(func) = item {
+        if let Some(n) = tuple_arity(&func.body, &arities) {
+          arities.insert(func.proto.name.clone(), n);
+        }
+      }
+    }
+    if arities.len() == before {
+      return arities;
+    }
+  }
+}
+
+/// The number of values of the tuple that `body` yields or `return`s, if
+/// it yields one on any path, knowing the functions of `arities` to return
+/// tuples.
+pub fn tuple_arity(body: &ExprAst, arities: &HashMap<String, usize>) -> Option<usize> {
+  tail_arity(body, arities).or_else(|| returned_arity(body, arities))
+}
+
+/// Callee - a function or an extern as the transpilers call it: by `name`,
+/// as `call` says, which each of them defines, returning a tuple of `tuple`
+/// numbers if any.
+#[derive(Debug, Clone)]
+pub struct Callee<C> {
+  pub name: String,
+  pub call: C,
+  pub tuple: Option<usize>, // the arity of the tuple it returns
+  pub widen: bool,          // takes and returns doubles whatever the precision
+}
+
+/// Returns - what the functions a transpiler declared return, by their
+/// names in the program.
+#[derive(Default)]
+pub struct Returns {
+  pub tuples: HashMap<String, usize>, // the arity of those returning tuples
+  pub annotations: HashMap<String, Annotation>, // as annotated, for `int_divisions`
+}
+
+impl Returns {
+  /// Records what the function `proto` defines, or a top-level expression,
+  /// returns: a tuple of `tuple` numbers if any.
+  pub fn declare(&mut self, proto: &ProtoAst, tuple: Option<usize>) {
+    if let (Some(n), false) = (tuple, proto.name.is_empty()) {
+      self.tuples.insert(proto.name.clone(), n);
+    }
+    let ret = Annotation::returned(proto, tuple);
+    self.annotations.insert(proto.name.clone(), ret);
+  }
+}
+
+/// What an expression yields in the transpilers: a number, or the numbers
+/// of a tuple.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Shape {
+  Num,
+  Tuple(usize),
+}
+
+impl Shape {
+  /// How many numbers it is.
+  pub fn numbers(self) -> usize {
+    match self {
+      Shape::Num => 1,
+      Shape::Tuple(n) => n,
+    }
+  }
+}
+
+/// Checks that a function declared to return `ret` returns `shape` at
+/// `span`.
+pub fn check_return(ret: Shape, shape: Shape, span: Span) -> Result<(), Diagnostic> {
+  match shape == ret {
+    true => Ok(()),
+    false => {
+      let msg = "Function returns both numbers and tuples, or tuples of different sizes";
+      Err(Diagnostic::error(span, msg).with_code("codegen"))
+    }
+  }
+}
+
+/// Annotation - what backends make of the type the checker annotates a
+/// parameter or a return with: an int, a tuple, with which of its elements
+/// are ints, or else a number, which those not annotated are.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Annotation {
+  Num,
+  Int,
+  Tuple(Vec<bool>),
+}
+
+impl Annotation {
+  pub fn of(ty: Option<&str>) -> Self {
+    match ty {
+      Some("int") => Annotation::Int,
+      Some(ty) if ty.starts_with('(') && !ty[1..].contains('(') => {
+        let elems = ty[1..ty.len() - 1].split(", ");
+        Annotation::Tuple(elems.map(|elem| elem == "int").collect())
+      }
+      _ => Annotation::Num,
+    }
+  }
+
+  /// What `proto` returns, as annotated, or else a tuple of `tuple`
+  /// numbers when its body yields one.
+  pub fn returned(proto: &ProtoAst, tuple: Option<usize>) -> Self {
+    match (Self::of(proto.ret_ty.as_deref()), tuple) {
+      (Annotation::Num, Some(n)) => Annotation::Tuple(vec![false; n]),
+      (ret, _) => ret,
+    }
+  }
+}
+
+fn tail_arity(expr: &ExprAst, arities: &HashMap<String, usize>) -> Option<usize> {
+  match expr {
+    ExprAst::TupleAst(elems) => Some(elems.len()),
+    ExprAst::CallAst(name, ..) => arities.get(name).copied(),
+    ExprAst::IfAst { then, els, .. } => {
+      tail_arity(then, arities).or_else(|| tail_arity(els, arities))
+    }
+    ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => tail_arity(exprs.last()?, arities),
+    ExprAst::LetAst(_, body) | ExprAst::LetTupleAst(_, _, body) | ExprAst::VarInAst(_, body) => {
+      tail_arity(body, arities)
+    }
+    ExprAst::MatchAst(_, arms, _) => arms.iter().find_map(|(_, arm)| tail_arity(arm, arities)),
+    _ => None,
+  }
+}
+
+fn returned_arity(expr: &ExprAst, arities: &HashMap<String, usize>) -> Option<usize> {
+  match expr {
+    ExprAst::ReturnAst(value, _) => tail_arity(value, arities),
+    ExprAst::LambdaAst(..) => None,
+    expr => expr
+      .children()
+      .into_iter()
+      .find_map(|child| returned_arity(child, arities)),
+  }
+}
+
+/// The symbol native code links the extern `symbol` of the prelude to, in
+/// the C math library or the runtime, and whether it takes and returns
+/// doubles whatever the precision. The math functions have an `f`-suffixed
+/// variant for 32-bit floats; other externs link to themselves.
+pub fn link_name(symbol: &str, precision: Precision) -> (String, bool) {
+  let math = match symbol {
+    "abs" => "fabs",
+    "min" => "fmin",
+    "max" => "fmax",
+    "sin" | "cos" | "exp" | "log" | "sqrt" | "pow" | "floor" => symbol,
+    "printd" | "putchard" | "readd" | "rand" | "srand" => return (symbol.to_string(), true),
+    _ => return (symbol.to_string(), false),
+  };
+  match precision {
+    Precision::F64 => (math.to_string(), false),
+    Precision::F32 => (format!("{}f", math), false),
+  }
+}
+
+/// Whether `symbol` is a function of this process, as of the C library,
+/// that a JIT can link to.
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+pub fn linkable(symbol: &str) -> bool {
+  let Ok(symbol) = std::ffi::CString::new(symbol) else {
+    return false;
+  };
+  unsafe { !libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr()).is_null() }
+}
+
+/// The divisions of ints in `func`, by the line and column of their
+/// operators: those whose operands
+/// both are ints, as the checker types them, where the interpreter
+/// truncates the quotient. `returns` has what the functions of the program
+/// return, as annotated.
+pub fn int_divisions(func: &FuncAst, returns: &HashMap<String, Annotation>) -> HashSet<Span> {
+  let tys = func.proto.arg_tys.iter();
+  let mut typing = IntTyping {
+    returns,
+    scope: (func.proto.args.iter().cloned())
+      .zip(tys.map(|ty| Annotation::of(ty.as_deref())))
+      .collect(),
+    divisions: HashSet::new(),
+  };
+  typing.annotation(&func.body);
+  typing.divisions
+}
+
+/// IntTyping - tells the ints of a function from its numbers, to find the
+/// divisions of ints in it.
+struct IntTyping<'a> {
+  returns: &'a HashMap<String, Annotation>,
+  scope: Vec<(String, Annotation)>,
+  divisions: HashSet<Span>,
+}
+
+impl IntTyping<'_> {
+  /// What `expr` yields, noting the divisions of ints in it.
+  fn annotation(&mut self, expr: &ExprAst) -> Annotation {
+    let depth = self.scope.len();
+    let ty = match expr {
+      ExprAst::IntAst(_) => Annotation::Int,
+      ExprAst::VarAst(name, _) => match self.scope.iter().rev().find(|(n, _)| n == name) {
+        Some((_, ty)) => ty.clone(),
+        None => Annotation::Num,
+      },
+      ExprAst::UnaryAst(op, operand, _) => match (op, self.annotation(operand)) {
+        (UnOp::Neg, Annotation::Int) => Annotation::Int,
+        _ => Annotation::Num,
+      },
+      ExprAst::BinAst(lhs, op, rhs, span) => {
+        let ints =
+          (self.annotation(lhs), self.annotation(rhs)) == (Annotation::Int, Annotation::Int);
+        if ints && *op == BinOp::Div {
+          self.divisions.insert(*span);
+        }
+        match op {
+          BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem if ints => Annotation::Int,
+          op if op.is_bitwise() => Annotation::Int,
+          _ => Annotation::Num,
+        }
+      }
+      ExprAst::CallAst(name, args, _) => {
+        for arg in args {
+          self.annotation(arg);
+        }
+        match self.returns.get(name) {
+          _ if name == "int" && args.len() == 1 => Annotation::Int,
+          Some(ret) => ret.clone(),
+          None => Annotation::Num,
+        }
+      }
+      ExprAst::IfAst { cond, then, els } => {
+        self.annotation(cond);
+        let then = self.annotation(then);
+        join(then, self.annotation(els))
+      }
+      ExprAst::TupleAst(elems) => {
+        let elems = elems
+          .iter()
+          .map(|elem| self.annotation(elem) == Annotation::Int);
+        Annotation::Tuple(elems.collect())
+      }
+      ExprAst::ElemAst(tuple, i) => match self.annotation(tuple) {
+        Annotation::Tuple(ints) if ints.get(*i) == Some(&true) => Annotation::Int,
+        _ => Annotation::Num,
+      },
+      ExprAst::LetAst(bindings, body) => {
+        for (name, _, init) in bindings {
+          let ty = self.annotation(init);
+          self.scope.push((name.clone(), ty));
+        }
+        self.annotation(body)
+      }
+      ExprAst::VarInAst(vars, body) => {
+        for (name, _, init) in vars {
+          let ty = init
+            .as_ref()
+            .map_or(Annotation::Num, |init| self.annotation(init));
+          self.scope.push((name.clone(), ty));
+        }
+        self.annotation(body)
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let ints = match self.annotation(init) {
+          Annotation::Tuple(ints) => ints,
+          _ => vec![],
+        };
+        for (i, (name, _)) in names.iter().enumerate() {
+          let ty = scalar_annotation(ints.get(i) == Some(&true));
+          self.scope.push((name.clone(), ty));
+        }
+        self.annotation(body)
+      }
+      ExprAst::MatchAst(scrutinee, arms, _) => {
+        self.annotation(scrutinee);
+        let arms = arms.iter().map(|(_, arm)| self.annotation(arm));
+        arms
+          .collect::<Vec<_>>()
+          .into_iter()
+          .reduce(join)
+          .unwrap_or(Annotation::Num)
+      }
+      expr => {
+        let children = expr.children();
+        let tys: Vec<_> = children
+          .into_iter()
+          .map(|child| self.annotation(child))
+          .collect();
+        match expr {
+          ExprAst::BlockAst(_) | ExprAst::SeqAst(_) => tys.into_iter().last(),
+          ExprAst::AssignAst(..) | ExprAst::ReturnAst(..) => tys.into_iter().next(),
+          _ => None,
+        }
+        .unwrap_or(Annotation::Num)
+      }
+    };
+    self.scope.truncate(depth);
+    ty
+  }
+}
+
+/// The type of the values of both `a` and `b`: that of ints if both are,
+/// or else of numbers, or of tuples, joined elementwise.
+fn join(a: Annotation, b: Annotation) -> Annotation {
+  match (a, b) {
+    (Annotation::Int, Annotation::Int) => Annotation::Int,
+    (Annotation::Tuple(a), Annotation::Tuple(b)) => {
+      Annotation::Tuple(a.iter().zip(b).map(|(a, b)| *a && b).collect())
+    }
+    _ => Annotation::Num,
+  }
+}
+
+/// The annotation of an int if `int`, or else of a number.
+pub fn scalar_annotation(int: bool) -> Annotation {
+  match int {
+    true => Annotation::Int,
+    false => Annotation::Num,
+  }
+}
+
+/// Whether `expr` assigns to a variable named `name`, or to another of
+/// that name it declares.
+pub fn assigns(expr: &ExprAst, name: &str) -> bool {
+  match expr {
+    ExprAst::AssignAst(assigned, ..) if assigned == name => true,
+    expr => expr
+      .children()
+      .into_iter()
+      .any(|child| assigns(child, name)),
+  }
+}
+
+/// The name of the function that overloads the operator `op`.
+pub fn overload(op: BinOp) -> String {
+  format!("binary{}", op.as_str())
+}
+
+/// Whether `functions`, by the names programs call them, overload `op`.
+pub fn is_overloaded<V>(functions: &HashMap<String, V>, op: BinOp) -> bool {
+  functions.contains_key(&overload(op))
+}
+
+/// Cond - an expression as a condition, which backends branch on without
+/// computing the number it yields where they can.
+pub enum Cond<'a> {
+  Bool(bool),
+  Not(&'a ExprAst, Span),
+  Logical(&'a ExprAst, BinOp, &'a ExprAst, Span), // `&&` or `||`
+  Cmp(&'a ExprAst, BinOp, &'a ExprAst, Span),
+  Nonzero, // any other expression, true unless it yields zero
+}
+
+impl<'a> Cond<'a> {
+  /// `expr` as a condition, knowing the operators that `functions`, by the
+  /// names programs call them, overload to be calls.
+  pub fn of<V>(expr: &'a ExprAst, functions: &HashMap<String, V>) -> Self {
+    match expr {
+      ExprAst::BoolAst(b) => Cond::Bool(*b),
+      ExprAst::UnaryAst(UnOp::Not, operand, at) => Cond::Not(operand, *at),
+      ExprAst::BinAst(lhs, op, rhs, at) if !is_overloaded(functions, *op) => match op {
+        BinOp::And | BinOp::Or => Cond::Logical(lhs, *op, rhs, *at),
+        BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge | BinOp::Eq | BinOp::Ne => {
+          Cond::Cmp(lhs, *op, rhs, *at)
+        }
+        _ => Cond::Nonzero,
+      },
+      _ => Cond::Nonzero,
+    }
+  }
+}
+
+/// Where `expr` is in the source, for the expressions that know.
+pub fn expr_span(expr: &ExprAst) -> Option<Span> {
+  match expr {
+    ExprAst::VarAst(_, at)
+    | ExprAst::UnaryAst(_, _, at)
+    | ExprAst::BinAst(_, _, _, at)
+    | ExprAst::CallAst(_, _, at)
+    | ExprAst::AssignAst(_, _, at)
+    | ExprAst::MatchAst(_, _, at)
+    | ExprAst::ReturnAst(_, at)
+    | ExprAst::TryAst(.., at)
+    | ExprAst::FuncRefAst(_, at) => Some(*at),
+    _ => None,
+  }
+}
+
+/// The path of `file` from the directory `dir`, with `/` between its
+/// components, for a file written there to refer to it. It is `file` as
+/// given unless both exist.
+pub fn relative_path(dir: &Path, file: &Path) -> String {
+  let dir = match dir.as_os_str().is_empty() {
+    true => Path::new("."),
+    false => dir,
+  };
+  let (Ok(dir), Ok(file)) = (dir.canonicalize(), file.canonicalize()) else {
+    return file.display().to_string();
+  };
+  let common = dir
+    .components()
+    .zip(file.components())
+    .take_while(|(a, b)| a == b)
+    .count();
+  let up = dir.components().skip(common).map(|_| "..".to_string());
+  let down = file.components().skip(common);
+  let down = down.map(|c| c.as_os_str().to_string_lossy().into_owned());
+  up.chain(down).collect::<Vec<_>>().join("/")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::session::Entry;
+  use std::io::Cursor;
+
+  type Transpile = fn(&ModuleAst, Entry, Precision) -> Result<String, Vec<Diagnostic>>;
+
+  /// What `transpile` makes of the program `src`, or its errors.
+  pub(super) fn transpile_src(
+    transpile: Transpile,
+    src: &'static str,
+  ) -> Result<String, Vec<String>> {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let entry = Entry::of(&module).unwrap();
+    transpile(&module, entry, Precision::F64)
+      .map_err(|errors| errors.iter().map(|e| e.to_string()).collect())
+  }
+
+  #[test]
+  fn codegen_tuple_arities() {
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a);
+      def sorted(a, b) { printd(a); minmax(a, b) };
+      def early(n) { if n < 0 then return (0, 0, 0) else (); f(n) };
+      def f(n) if n then f(n - 1) else (n, n, n); def g(x) x;";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut arities: Vec<_> = tuple_arities(&module).into_iter().collect();
+    arities.sort();
+    let expected = [("early", 3), ("f", 3), ("minmax", 2), ("sorted", 2)];
+    let expected: Vec<_> = expected.iter().map(|&(f, n)| (f.to_string(), n)).collect();
+    assert_eq!(arities, expected);
+    assert_eq!(
+      link_name("abs", Precision::F32),
+      ("fabsf".to_string(), false)
+    );
+    assert_eq!(
+      link_name("printd", Precision::F32),
+      ("printd".to_string(), true)
+    );
+  }
+}
@@ -0,0 +1,265 @@
+use super::backend::{compile, Backend, Declaration};
+use super::llvm::{Compiler, OptLevel, Pass};
+use super::{link, scratch_dir, write_dumps, Emit};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{FuncAst, ModuleAst, ProtoAst};
+use crate::session::Entry;
+use crate::value::Precision;
+use inkwell::context::Context;
+use inkwell::module::Module;
+use inkwell::targets::{
+  CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+};
+use inkwell::values::FunctionValue;
+use inkwell::OptimizationLevel;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// BuildOptions - how to compile a program, and for which machine: the
+/// host unless given the `target` triple, such as
+/// `aarch64-unknown-linux-gnu`. The `cpu` defaults to that of the host, or
+/// to the generic one of another target, and its `features`, such as
+/// `+neon,-fp-armv8`, to those of the CPU. Given the `debug` source file,
+/// the program is described in DWARF, for debuggers to step through it.
+pub struct BuildOptions {
+  pub passes: Vec<Pass>,
+  pub dump: Option<Box<dyn Write>>, // where the IR of each function goes
+  pub target: Option<String>,
+  pub cpu: Option<String>,
+  pub features: Option<String>,
+  pub emit: Emit,
+  pub debug: Option<PathBuf>, // the file the program comes from
+}
+
+impl BuildOptions {
+  pub fn new() -> Self {
+    Self {
+      passes: OptLevel::O2.passes().to_vec(),
+      dump: None,
+      target: None,
+      cpu: None,
+      features: None,
+      emit: Emit::Executable,
+      debug: None,
+    }
+  }
+}
+
+/// Compiles the checked program `module`, which starts at `entry`, to the
+/// executable `output`, computing with doubles of the given precision. The
+/// program is compiled to an object file, along with a C `main` that calls
+/// the program's `main`, or else runs its top-level expressions in order,
+/// ignoring their values. The C compiler, `cc` unless `$CC` names another,
+/// then compiles the runtime and links them with the C math library; to
+/// emit the object file alone, it is the `output`.
+pub fn build(
+  module: &ModuleAst,
+  entry: Entry,
+  precision: Precision,
+  options: &mut BuildOptions,
+  output: &Path,
+) -> Result<(), Vec<Diagnostic>> {
+  let context = Context::create();
+  let name = output.file_stem().unwrap_or_default().to_string_lossy();
+  let mut backend = NativeBackend::new(&context, &name, precision, options).map_err(|e| vec![e])?;
+  compile(&mut backend, module, entry, output)
+}
+
+/// NativeBackend - compiles programs with LLVM, as [`build`] does, for the
+/// machine of its options.
+pub struct NativeBackend<'a, 'ctx> {
+  compiler: Compiler<'ctx>,
+  machine: TargetMachine,
+  options: &'a mut BuildOptions,
+  functions: Vec<FunctionValue<'ctx>>, // those defined, in order
+}
+
+impl<'a, 'ctx> NativeBackend<'a, 'ctx> {
+  pub fn new(
+    context: &'ctx Context,
+    name: &str,
+    precision: Precision,
+    options: &'a mut BuildOptions,
+  ) -> Result<Self, Diagnostic> {
+    let machine = target_machine(options)?;
+    let mut compiler = Compiler::new(context, name, precision);
+    compiler.set_target(&machine);
+    compiler.set_passes(&options.passes);
+    compiler.set_dump(options.dump.is_some());
+    if let Some(source) = &options.debug {
+      compiler.set_debug_info(source);
+    }
+    Ok(Self {
+      compiler,
+      machine,
+      options,
+      functions: vec![],
+    })
+  }
+}
+
+impl Backend for NativeBackend<'_, '_> {
+  fn name(&self) -> &'static str {
+    "LLVM"
+  }
+
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+    match decl {
+      Declaration::Extern => {
+        self.compiler.compile_proto(proto);
+      }
+      Declaration::Function { tuple } => self.compiler.declare_function(proto, tuple),
+    }
+    Ok(())
+  }
+
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+    let function = self.compiler.compile_func(func)?;
+    self.functions.push(function);
+    Ok(())
+  }
+
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>> {
+    write_dumps(&mut self.options.dump, self.compiler.take_dumps());
+    let called = |function: &&_| match entry {
+      Entry::Main => function_name(function) == "main",
+      Entry::TopLevel => function_name(function).starts_with("__anon_expr"),
+    };
+    let called: Vec<_> = self.functions.iter().filter(called).copied().collect();
+    self.compiler.compile_main(&called).map_err(|e| vec![e])?;
+    self.compiler.finish_debug_info().map_err(|e| vec![e])?;
+    let module = self.compiler.module();
+    if self.options.emit == Emit::Object {
+      return write_object(&self.machine, module, output).map_err(|e| vec![e]);
+    }
+    let dir = scratch_dir().map_err(|e| vec![e])?;
+    let object = dir.join("program.o");
+    let res = write_object(&self.machine, module, &object)
+      .and_then(|_| link(&object, &dir.join("runtime.c"), output));
+    let _ = std::fs::remove_dir_all(&dir);
+    res.map_err(|e| vec![e])
+  }
+}
+
+fn function_name(function: &FunctionValue) -> String {
+  function.get_name().to_string_lossy().into_owned()
+}
+
+/// The machine `options` ask to generate code for.
+fn target_machine(options: &BuildOptions) -> Result<TargetMachine, Diagnostic> {
+  let config = InitializationConfig::default();
+  let (triple, cpu, features) = match &options.target {
+    None => {
+      Target::initialize_native(&config).map_err(error)?;
+      let features = match options.cpu {
+        None => TargetMachine::get_host_cpu_features().to_string(),
+        Some(_) => String::new(),
+      };
+      let cpu = TargetMachine::get_host_cpu_name().to_string();
+      (TargetMachine::get_default_triple(), cpu, features)
+    }
+    Some(triple) => {
+      Target::initialize_all(&config);
+      (TargetTriple::create(triple), String::new(), String::new())
+    }
+  };
+  let cpu = options.cpu.clone().unwrap_or(cpu);
+  let features = options.features.clone().unwrap_or(features);
+  let name = triple.as_str().to_string_lossy();
+  let target =
+    Target::from_triple(&triple).map_err(|_| error(format!("Unknown target `{}`", name)))?;
+  target
+    .create_target_machine(
+      &triple,
+      &cpu,
+      &features,
+      OptimizationLevel::Default,
+      RelocMode::PIC,
+      CodeModel::Default,
+    )
+    .ok_or_else(|| error(format!("No machine for `{}` with CPU `{}`", name, cpu)))
+}
+
+/// Writes `module` to the object file `path`, for `machine`.
+fn write_object(machine: &TargetMachine, module: &Module, path: &Path) -> Result<(), Diagnostic> {
+  machine
+    .write_to_file(module, FileType::Object, path)
+    .map_err(|e| error(format!("Cannot write `{}`: {}", path.display(), e)))
+}
+
+fn error(msg: String) -> Diagnostic {
+  Diagnostic::error(Span::default(), msg).with_code("codegen")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::runtime;
+  use std::io::Cursor;
+  use std::process::Command;
+
+  fn build_and_run(src: &'static str, name: &str) -> String {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let entry = Entry::of(&module).unwrap();
+    let output = std::env::temp_dir().join(name);
+    let mut options = BuildOptions::new();
+    build(&module, entry, Precision::F64, &mut options, &output).unwrap();
+    let res = Command::new(&output).output().unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert!(res.status.success());
+    String::from_utf8(res.stdout).unwrap()
+  }
+
+  #[test]
+  fn native_build() {
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a);
+      def show(a, b) let (lo, hi) = minmax(a, b) in { printd(lo); printd(hi) };
+      def main() { show(3, 2); putchard(72); putchard(10); srand(42); printd(rand()) };";
+    runtime::srand(42.0);
+    let expected = format!("2.0\n3.0\nH\n{:?}\n", runtime::rand());
+    assert_eq!(build_and_run(src, "kale-native-main"), expected);
+
+    let src = "printd(1); printd(-0.0); printd(0.1 + 0.2); printd(1 / 3.0);
+      printd(123456.5); printd(0.0001); printd(0.00001234); printd(pow(10, 15));
+      printd(pow(10, 16)); printd(2.5 * pow(10, 100)); printd(1 / 0.0); printd(0 / 0.0); printd(-1 / 0.0);";
+    let nums = [
+      1.0,
+      -0.0,
+      0.1 + 0.2,
+      1.0 / 3.0,
+      123456.5,
+      0.0001,
+      0.00001234,
+      1e15,
+      1e16,
+      2.5e100,
+      f64::INFINITY,
+      f64::NAN,
+      f64::NEG_INFINITY,
+    ];
+    let expected: String = nums.iter().map(|n| format!("{:?}\n", n)).collect();
+    assert_eq!(build_and_run(src, "kale-native-top-level"), expected);
+  }
+
+  #[test]
+  fn native_cross() {
+    let module =
+      ModuleAst::parse(&mut Lexer::new(Cursor::new("def main() printd(sqrt(2));"))).unwrap();
+    let output = std::env::temp_dir().join("kale-native-cross.o");
+    let mut options = BuildOptions::new();
+    options.target = Some("aarch64-unknown-linux-gnu".to_string());
+    options.cpu = Some("cortex-a72".to_string());
+    options.emit = Emit::Object;
+    build(&module, Entry::Main, Precision::F64, &mut options, &output).unwrap();
+    let object = std::fs::read(&output).unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert_eq!(object[..4], *b"\x7fELF");
+    assert_eq!(object[18..20], 183u16.to_le_bytes()); // EM_AARCH64
+
+    options.target = Some("z80-unknown-none".to_string());
+    let errors = build(&module, Entry::Main, Precision::F64, &mut options, &output).unwrap_err();
+    assert_eq!(errors[0].message, "Unknown target `z80-unknown-none`");
+  }
+}
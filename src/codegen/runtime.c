@@ -0,0 +1,202 @@
+/* The runtime of executables built by Kale: the functions of runtime.rs
+ * that native code links to by name, written in C so that they can be
+ * compiled and linked along with the program. Each behaves as its Rust
+ * counterpart does, down to how `printd` formats numbers and the sequence
+ * `rand` yields for a seed. */
+
+#define _POSIX_C_SOURCE 200809L
+#include <math.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+
+/* Declared here rather than with <stdlib.h>, whose `rand` and `srand`
+ * would conflict with those of Kale. */
+double strtod(const char *s, char **end);
+int atoi(const char *s);
+void free(void *p);
+void exit(int status);
+
+/* Writes the digits of the shortest decimal that reads back as `x`, a
+ * finite positive number, to `digits`, returning its exponent. */
+static int shortest(double x, char *digits) {
+  char buf[32];
+  for (int precision = 0; precision < 17; precision++) {
+    snprintf(buf, sizeof buf, "%.*e", precision, x);
+    if (strtod(buf, NULL) == x) {
+      break;
+    }
+  }
+  char *e = strchr(buf, 'e');
+  int exp = atoi(e + 1);
+  int n = 0;
+  for (char *c = buf; c < e; c++) {
+    if (*c != '.') {
+      digits[n++] = *c;
+    }
+  }
+  while (n > 1 && digits[n - 1] == '0') {
+    n--;
+  }
+  digits[n] = '\0';
+  return exp;
+}
+
+/* Prints `x` as Rust's `{:?}` does: in full with at least one decimal,
+ * unless it is below 1e-4 or at least 1e16, which are printed with an
+ * exponent. */
+double printd(double x) {
+  if (isnan(x)) {
+    puts("NaN");
+    return 0.0;
+  }
+  if (signbit(x)) {
+    putchar('-');
+    x = -x;
+  }
+  if (isinf(x)) {
+    puts("inf");
+    return 0.0;
+  }
+  char digits[32];
+  int exp = x == 0.0 ? 0 : shortest(x, digits);
+  if (x == 0.0) {
+    strcpy(digits, "0");
+  }
+  int n = (int)strlen(digits);
+  if (x != 0.0 && (x < 1e-4 || x >= 1e16)) {
+    printf("%c", digits[0]);
+    if (n > 1) {
+      printf(".%s", digits + 1);
+    }
+    printf("e%d\n", exp);
+  } else if (exp < 0) {
+    printf("0.");
+    for (int i = 0; i < -exp - 1; i++) {
+      putchar('0');
+    }
+    printf("%s\n", digits);
+  } else {
+    for (int i = 0; i <= exp; i++) {
+      putchar(i < n ? digits[i] : '0');
+    }
+    printf(".%s\n", n > exp + 1 ? digits + exp + 1 : "0");
+  }
+  return 0.0;
+}
+
+/* Prints the character with code `c`, saturated to a byte. */
+double putchard(double c) {
+  putchar(isnan(c) || c < 0 ? 0 : c > 255 ? 255 : (int)c);
+  return 0.0;
+}
+
+/* Reads a double on a line of its own, or returns NaN at the end of input
+ * or on a line that isn't a number. */
+double readd(void) {
+  char *line = NULL;
+  size_t size = 0;
+  double x = NAN;
+  if (getline(&line, &size, stdin) >= 0) {
+    char *start = line, *end;
+    while (*start == ' ' || *start == '\t') {
+      start++;
+    }
+    double parsed = strtod(start, &end);
+    while (*end == ' ' || *end == '\t' || *end == '\r' || *end == '\n') {
+      end++;
+    }
+    if (end != start && *end == '\0') {
+      x = parsed;
+    }
+  }
+  free(line);
+  return x;
+}
+
+static uint64_t rng = 0;
+
+/* The next number of a splitmix64 sequence, scaled to [0, 1). */
+double rand(void) {
+  uint64_t z = rng += 0x9e3779b97f4a7c15;
+  z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9;
+  z = (z ^ (z >> 27)) * 0x94d049bb133111eb;
+  z ^= z >> 31;
+  return (double)(z >> 11) / (double)(1ull << 53);
+}
+
+/* Restarts the sequence of `rand`, from the seed truncated to an integer
+ * as Rust's saturating `as i64` does. */
+double srand(double seed) {
+  int64_t n = isnan(seed) ? 0
+    : seed >= 9223372036854775807.0 ? INT64_MAX
+    : seed <= -9223372036854775808.0 ? INT64_MIN
+    : (int64_t)seed;
+  rng = (uint64_t)n;
+  return 0.0;
+}
+
+/* Prints `x`, a whole number, in full as Rust's `{}` does: the digits of
+ * the shortest decimal that reads back as it, padded with zeros. */
+static void print_whole(FILE *out, double x) {
+  if (isnan(x)) {
+    fputs("NaN", out);
+    return;
+  }
+  if (signbit(x)) {
+    fputc('-', out);
+    x = -x;
+  }
+  if (isinf(x)) {
+    fputs("inf", out);
+    return;
+  }
+  char digits[32] = "0";
+  int exp = x == 0.0 ? 0 : shortest(x, digits);
+  int n = (int)strlen(digits);
+  for (int i = 0; i <= exp; i++) {
+    fputc(i < n ? digits[i] : '0', out);
+  }
+}
+
+/* Reports the failure of the operation on ints with code `op`, as
+ * runtime.rs numbers them in `IntOp`, with the error of the interpreter,
+ * and exits. */
+void kale_int_error(uint32_t op, int64_t lhs, int64_t rhs) {
+  static const char *const ops[] = {"+", "-", "*", "/", "%"};
+  fflush(stdout);
+  fputs("error: ", stderr);
+  if ((op == 3 || op == 4) && rhs == 0) {
+    fputs("Integer division by zero", stderr);
+  } else if (op <= 4) {
+    fprintf(stderr, "Integer overflow in `%lld %s %lld`", (long long)lhs, ops[op],
+            (long long)rhs);
+  } else if (op <= 6) {
+    fprintf(stderr, "Cannot shift by %lld bits", (long long)rhs);
+  } else if (op == 7) {
+    fprintf(stderr, "Integer overflow in `-%lld`", (long long)lhs);
+  } else {
+    double x;
+    memcpy(&x, &lhs, sizeof x);
+    fputs("Cannot convert ", stderr);
+    print_whole(stderr, trunc(x));
+    fputs(" to int", stderr);
+  }
+  fputc('\n', stderr);
+  exit(1);
+}
+
+/* The quotient of the ints `a` and `b`, which transpiled C holds in
+ * doubles, truncated as Kale divides ints. */
+double kale_idiv(double a, double b) {
+  if (b == 0) {
+    kale_int_error(3, (int64_t)a, 0);
+  }
+  return (double)((int64_t)a / (int64_t)b);
+}
+
+/* Whether native code failed, which it never returns from here, as
+ * `kale_int_error` exits. */
+uint32_t kale_failed(void) {
+  return 0;
+}
@@ -0,0 +1,1073 @@
+use super::backend::{define_module, Backend, Declaration, Declared};
+use super::{
+  check_return, int_divisions, is_overloaded, overload, relative_path, tuple_arity, unsupported,
+  Cond, Returns, Shape,
+};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, ProtoAst, UnOp};
+use crate::prelude::prelude;
+use crate::session::Entry;
+use crate::value::Precision;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Transpiles the checked program `module`, which starts at `entry`, to a
+/// Rust module, to vendor into a crate.
+///
+/// Numbers are `f64`s, or `f32`s in `F32` precision, and tuples are tuples
+/// of them. The functions of the program are `pub`, and `run` calls `main`,
+/// or else the top-level expressions in order. The math of the prelude is
+/// that of the standard library, the functions of the runtime the program
+/// calls are written along with it, and its own externs are declared in an
+/// `extern "C"` block. Names that Rust reserves are raw identifiers, and
+/// the characters it doesn't allow in names are replaced. `var`s and
+/// assignments aren't transpiled. Every function is transpiled even when
+/// another one fails, and the errors are reported in order.
+pub fn transpile(
+  module: &ModuleAst,
+  entry: Entry,
+  precision: Precision,
+) -> Result<String, Vec<Diagnostic>> {
+  let mut backend = RustBackend::new(precision);
+  define_module(&mut backend, module)?;
+  Ok(backend.source(entry))
+}
+
+/// RustBackend - transpiles programs to Rust, as [`transpile`] does, to the
+/// source `output`.
+pub struct RustBackend {
+  transpiler: Transpiler,
+  declared: Declared<Callee>,
+  definitions: Vec<String>,
+}
+
+impl RustBackend {
+  pub fn new(precision: Precision) -> Self {
+    let transpiler = Transpiler {
+      precision,
+      float: match precision {
+        Precision::F64 => "f64",
+        Precision::F32 => "f32",
+      },
+      functions: HashMap::new(),
+      returns: Returns::default(),
+      externs: vec![],
+      runtime: BTreeSet::new(),
+      names: HashSet::from(["run".to_string()]),
+      source: None,
+    };
+    Self {
+      transpiler,
+      declared: Declared::new(),
+      definitions: vec![],
+    }
+  }
+
+  /// Points the functions defined from now on back at the Kale file
+  /// `source`, from the directory of `output`, with a comment of the line
+  /// each starts at. Rust has no `#line` directive to point its errors and
+  /// debuggers there, so the statements are left unmarked. Items imported
+  /// from other files are pointed at lines of `source` too.
+  pub fn set_source(&mut self, source: &Path, output: &Path) {
+    let dir = output.parent().unwrap_or(Path::new(""));
+    self.transpiler.source = Some(relative_path(dir, source));
+  }
+
+  /// The source of the module of the functions defined, whose `run` starts
+  /// the program at `entry`.
+  pub fn source(&self, entry: Entry) -> String {
+    let transpiler = &self.transpiler;
+    let mut out = String::new();
+    if !transpiler.externs.is_empty() {
+      out.push_str("extern \"C\" {\n");
+      for declaration in &transpiler.externs {
+        let _ = writeln!(out, "    {};", declaration);
+      }
+      out.push_str("}\n\n");
+    }
+    for definition in &self.definitions {
+      let _ = writeln!(out, "{}", definition);
+    }
+    out.push_str("pub fn run() {\n");
+    for callee in self.declared.entry(entry) {
+      let _ = writeln!(out, "    {}();", callee.name);
+    }
+    out.push_str("}\n");
+    if transpiler.runtime.contains("rand") || transpiler.runtime.contains("srand") {
+      out.push_str(RNG);
+    }
+    for (name, code) in RUNTIME {
+      if transpiler.runtime.contains(name) {
+        let _ = write!(out, "\n{}", code);
+      }
+    }
+    out
+  }
+}
+
+impl Backend for RustBackend {
+  fn name(&self) -> &'static str {
+    "Rust"
+  }
+
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+    match decl {
+      Declaration::Extern => self.transpiler.declare_extern(proto),
+      Declaration::Function { tuple } => {
+        let callee = self.transpiler.declare(proto, tuple);
+        self.declared.push(&proto.name, callee);
+      }
+    }
+    Ok(())
+  }
+
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+    let transpiler = &mut self.transpiler;
+    let callee = self.declared.define(func, |_| {
+      let tuple = tuple_arity(&func.body, &transpiler.returns.tuples);
+      transpiler.declare(&func.proto, tuple)
+    });
+    let definition = self.transpiler.transpile_func(func, &callee)?;
+    self.definitions.push(definition);
+    Ok(())
+  }
+
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>> {
+    std::fs::write(output, self.source(entry)).map_err(|e| {
+      let msg = format!("Cannot write `{}`: {}", output.display(), e);
+      vec![Diagnostic::error(Span::default(), msg).with_code("codegen")]
+    })
+  }
+}
+
+/// The functions of the runtime, as `src/runtime.rs` has them for native
+/// code, written along with the programs that call them.
+const RUNTIME: [(&str, &str); 6] = [
+  (
+    "printd",
+    "fn printd(x: f64) -> f64 {
+    println!(\"{:?}\", x);
+    0.0
+}
+",
+  ),
+  (
+    "putchard",
+    "fn putchard(c: f64) -> f64 {
+    use std::io::Write;
+    let _ = std::io::stdout().write_all(&[c as u8]);
+    0.0
+}
+",
+  ),
+  (
+    "readd",
+    "fn readd() -> f64 {
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => f64::NAN,
+        Ok(_) => line.trim().parse().unwrap_or(f64::NAN),
+    }
+}
+",
+  ),
+  (
+    "kale_idiv",
+    "fn kale_idiv(x: f64, y: f64) -> f64 {
+    if y == 0.0 {
+        panic!(\"Integer division by zero\");
+    }
+    (x / y).trunc()
+}
+",
+  ),
+  (
+    "rand",
+    "fn rand() -> f64 {
+    let mut z = RNG.get().wrapping_add(0x9e3779b97f4a7c15);
+    RNG.set(z);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+",
+  ),
+  (
+    "srand",
+    "fn srand(seed: f64) -> f64 {
+    RNG.set(seed as i64 as u64);
+    0.0
+}
+",
+  ),
+];
+
+const RNG: &str = "
+thread_local! {
+    static RNG: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+";
+
+const KEYWORDS: [&str; 38] = [
+  "as", "break", "const", "continue", "else", "enum", "extern", "false", "fn", "for", "if", "impl",
+  "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct",
+  "trait", "true", "type", "unsafe", "use", "where", "while", "async", "await", "dyn", "abstract",
+  "try", "yield", "macro",
+];
+
+/// A Rust identifier for `name`: itself, or a raw identifier if it is a
+/// keyword, with the characters Rust doesn't allow in names as `_xHH`.
+fn identifier(name: &str) -> String {
+  if KEYWORDS.contains(&name) {
+    return format!("r#{}", name);
+  }
+  if ["self", "super", "crate", "Self", "_"].contains(&name) {
+    return format!("{}_", name);
+  }
+  let mut out = String::new();
+  for c in name.chars() {
+    match c {
+      c if c.is_ascii_alphanumeric() || c == '_' => out.push(c),
+      c => {
+        let mut buf = [0; 4];
+        for byte in c.encode_utf8(&mut buf).bytes() {
+          let _ = write!(out, "_x{:02x}", byte);
+        }
+      }
+    }
+  }
+  match out.starts_with(|c: char| c.is_ascii_digit()) {
+    true => format!("_{}", out),
+    false => out,
+  }
+}
+
+/// How a function is called.
+#[derive(Debug, Clone)]
+enum Call {
+  Function,       // of the program
+  Extern,         // in an `unsafe` block
+  Runtime,        // of the runtime, which widens
+  Method(String), // of numbers, as the math of the prelude is
+}
+
+/// A function as programs call it.
+type Callee = super::Callee<Call>;
+
+struct Transpiler {
+  precision: Precision,
+  float: &'static str,
+  functions: HashMap<String, Callee>, // by the name programs call them
+  returns: Returns,                   // what they return
+  externs: Vec<String>,               // the declarations of the `extern` block
+  runtime: BTreeSet<String>,          // the functions of it called
+  names: HashSet<String>,             // of the functions
+  source: Option<String>,             // the file functions point at
+}
+
+impl Transpiler {
+  /// Declares the function `proto` defines, or a top-level expression,
+  /// under a name of its own, returning a tuple of `tuple` numbers if any.
+  fn declare(&mut self, proto: &ProtoAst, tuple: Option<usize>) -> Callee {
+    self.returns.declare(proto, tuple);
+    let name = match proto.name.as_str() {
+      "" => {
+        let mut n = 0;
+        while self.names.contains(&top_level_name(n)) {
+          n += 1;
+        }
+        top_level_name(n)
+      }
+      name => {
+        let mut name = identifier(name);
+        while self.names.contains(&name) {
+          name.push('_');
+        }
+        name
+      }
+    };
+    self.names.insert(name.clone());
+    let callee = Callee {
+      name,
+      call: Call::Function,
+      tuple,
+      widen: false,
+    };
+    if !proto.name.is_empty() {
+      self.functions.insert(proto.name.clone(), callee.clone());
+    }
+    callee
+  }
+
+  /// Declares the extern `proto`: a method of numbers if it is math of the
+  /// prelude, a function of the runtime, or else a C function.
+  fn declare_extern(&mut self, proto: &ProtoAst) {
+    let symbol = proto.symbol();
+    let method = match symbol {
+      "log" => Some("ln"),
+      "pow" => Some("powf"),
+      "sin" | "cos" | "exp" | "sqrt" | "abs" | "floor" | "min" | "max" => Some(symbol),
+      _ => None,
+    };
+    let call = match (method, symbol) {
+      (Some(method), _) => Call::Method(method.to_string()),
+      (None, "printd" | "putchard" | "readd" | "rand" | "srand") => Call::Runtime,
+      _ => {
+        let params: Vec<_> = (0..proto.args.len())
+          .map(|i| format!("{}: {}", identifier(&proto.args[i]), self.float))
+          .collect();
+        let declaration = format!("fn {}({}) -> {}", symbol, params.join(", "), self.float);
+        if !self.externs.contains(&declaration) {
+          self.externs.push(declaration);
+        }
+        Call::Extern
+      }
+    };
+    let callee = Callee {
+      name: symbol.to_string(),
+      widen: matches!(call, Call::Runtime),
+      call,
+      tuple: None,
+    };
+    self.functions.insert(proto.name.clone(), callee);
+  }
+
+  /// The definition of the function `func`, or of the top-level expression
+  /// it wraps, which `callee` declares.
+  fn transpile_func(&mut self, func: &FuncAst, callee: &Callee) -> Result<String, Diagnostic> {
+    let proto = &func.proto;
+    let mut body = func.body.clone();
+    body.lower_matches();
+    let float = self.float;
+    let ret_shape = match callee.tuple {
+      Some(n) => Shape::Tuple(n),
+      None => Shape::Num,
+    };
+    let mut lowering = Lowering {
+      divisions: int_divisions(func, &self.returns.annotations),
+      transpiler: self,
+      scope: vec![],
+      ret: ret_shape,
+    };
+    let mut params = vec![];
+    for arg in &proto.args {
+      let name = identifier(arg);
+      params.push(format!("{}: {}", name, float));
+      lowering.scope.push((arg.clone(), name, Shape::Num));
+    }
+    let body = match &body {
+      // returning from the end of the function is returning anyway
+      ExprAst::ReturnAst(value, _) => value,
+      body => body,
+    };
+    let (stmts, tail) = lowering.lower_block(body, proto.span)?;
+    check_return(lowering.ret, tail.shape, proto.span)?;
+    let ret = match callee.tuple {
+      Some(n) => format!("({})", vec![float; n].join(", ")),
+      None => float.to_string(),
+    };
+    let vis = match proto.name.as_str() {
+      "" => "",
+      _ => "pub ",
+    };
+    let mut out = String::new();
+    if let Some(source) = self.source.as_ref().filter(|_| proto.span.line > 0) {
+      let _ = writeln!(out, "// {}:{}", source, proto.span.line);
+    }
+    let _ = writeln!(
+      out,
+      "{}fn {}({}) -> {} {{",
+      vis,
+      callee.name,
+      params.join(", "),
+      ret
+    );
+    for line in stmts.iter().chain([&tail.text]) {
+      let _ = writeln!(out, "{}", indent(line));
+    }
+    out.push_str("}\n");
+    Ok(out)
+  }
+}
+
+fn top_level_name(n: usize) -> String {
+  match n {
+    0 => "top_level".to_string(),
+    n => format!("top_level_{}", n),
+  }
+}
+
+/// `code` indented by a level, but for its empty lines.
+fn indent(code: &str) -> String {
+  let lines = code.lines().map(|line| match line {
+    "" => String::new(),
+    line => format!("    {}", line),
+  });
+  lines.collect::<Vec<_>>().join("\n")
+}
+
+/// A block of `stmts` ending with `tail`, as an expression.
+fn block(stmts: Vec<String>, tail: &str) -> String {
+  let lines: Vec<_> = stmts.iter().map(|stmt| indent(stmt)).collect();
+  format!("{{\n{}\n{}\n}}", lines.join("\n"), indent(tail))
+}
+
+/// How tightly an expression binds, to parenthesize it as an operand.
+const PREC_IF: u8 = 0; // `if`, `return`, and what can't be an operand
+const PREC_OR: u8 = 3;
+const PREC_AND: u8 = 4;
+const PREC_CMP: u8 = 5;
+const PREC_ADD: u8 = 7;
+const PREC_MUL: u8 = 8;
+const PREC_UNARY: u8 = 9;
+const PREC_ATOM: u8 = 10;
+
+/// Code - an expression in Rust.
+#[derive(Debug, Clone)]
+struct Code {
+  text: String,
+  prec: u8,
+  shape: Shape,
+  literal: bool, // an unsuffixed number, which a method can't be called on
+}
+
+impl Code {
+  fn new(text: String, prec: u8) -> Self {
+    Code {
+      text,
+      prec,
+      shape: Shape::Num,
+      literal: false,
+    }
+  }
+
+  /// The code as an operand that must bind at least as tightly as `prec`.
+  fn operand(&self, prec: u8) -> String {
+    match self.prec >= prec {
+      true => self.text.clone(),
+      false => format!("({})", self.text),
+    }
+  }
+}
+
+/// Lowering - the state of the transpilation of one function. Variables
+/// keep their names, which Rust lets `let`s shadow as Kale does.
+struct Lowering<'a> {
+  transpiler: &'a mut Transpiler,
+  divisions: HashSet<Span>, // of ints, which truncate
+  scope: Vec<(String, String, Shape)>,
+  ret: Shape,
+}
+
+impl Lowering<'_> {
+  fn literal(&self, n: f64) -> Code {
+    let float = self.transpiler.float;
+    let text = match self.transpiler.precision {
+      _ if n.is_nan() => format!("{}::NAN", float),
+      _ if n.is_infinite() => format!("{}::INFINITY", float),
+      Precision::F64 => format!("{:?}", n.abs()),
+      Precision::F32 => format!("{:?}", n.abs() as f32),
+    };
+    let literal = !text.contains("::");
+    match n.is_sign_negative() && !n.is_nan() {
+      true => Code {
+        literal,
+        ..Code::new(format!("-{}", text), PREC_UNARY)
+      },
+      false => Code {
+        literal,
+        ..Code::new(text, PREC_ATOM)
+      },
+    }
+  }
+
+  /// The statements and the value of `expr`, as the end of a block: the
+  /// statements of blocks and the bindings of `let`s are those of the
+  /// block itself.
+  fn lower_block(&mut self, expr: &ExprAst, span: Span) -> Result<(Vec<String>, Code), Diagnostic> {
+    let mut stmts = vec![];
+    let depth = self.scope.len();
+    let tail = self.lower_tail(expr, span, &mut stmts);
+    self.scope.truncate(depth);
+    Ok((stmts, tail?))
+  }
+
+  fn lower_tail(
+    &mut self,
+    expr: &ExprAst,
+    span: Span,
+    stmts: &mut Vec<String>,
+  ) -> Result<Code, Diagnostic> {
+    match expr {
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) if !exprs.is_empty() => {
+        let (last, exprs) = exprs.split_last().unwrap();
+        for expr in exprs {
+          self.lower_stmt(expr, span, stmts)?;
+        }
+        self.lower_tail(last, span, stmts)
+      }
+      ExprAst::LetAst(..) | ExprAst::LetTupleAst(..) => {
+        let body = self.lower_let(expr, span, stmts)?;
+        self.lower_tail(body, span, stmts)
+      }
+      expr => self.lower_expr(expr, span),
+    }
+  }
+
+  /// Binds the names of the `let` `expr` in `stmts`, returning its body.
+  fn lower_let<'e>(
+    &mut self,
+    expr: &'e ExprAst,
+    span: Span,
+    stmts: &mut Vec<String>,
+  ) -> Result<&'e ExprAst, Diagnostic> {
+    match expr {
+      ExprAst::LetAst(bindings, body) => {
+        for (name, _, init) in bindings {
+          let init = self.lower_expr(init, span)?;
+          let var = identifier(name);
+          stmts.push(format!("let {} = {};", var, init.text));
+          self.scope.push((name.clone(), var, init.shape));
+        }
+        Ok(body)
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let init = self.lower_expr(init, span)?;
+        self.check_destructuring(init.shape, names.len(), span)?;
+        let vars: Vec<_> = names.iter().map(|(name, _)| identifier(name)).collect();
+        stmts.push(format!("let ({}) = {};", vars.join(", "), init.text));
+        for ((name, _), var) in names.iter().zip(vars) {
+          self.scope.push((name.clone(), var, Shape::Num));
+        }
+        Ok(body)
+      }
+      expr => Ok(expr),
+    }
+  }
+
+  fn check_destructuring(&self, shape: Shape, names: usize, span: Span) -> Result<(), Diagnostic> {
+    let msg = match shape {
+      Shape::Tuple(n) if n == names => return Ok(()),
+      Shape::Tuple(n) => format!("Cannot destructure a tuple of {} into {} names", n, names),
+      Shape::Num => format!("Cannot destructure a number into {} names", names),
+    };
+    Err(Diagnostic::error(span, msg).with_code("codegen"))
+  }
+
+  /// Lowers `expr` for its effects only, into `stmts`. Those that have
+  /// none, but for their value, are left out.
+  fn lower_stmt(
+    &mut self,
+    expr: &ExprAst,
+    span: Span,
+    stmts: &mut Vec<String>,
+  ) -> Result<(), Diagnostic> {
+    match expr {
+      ExprAst::NumAst(_)
+      | ExprAst::IntAst(_)
+      | ExprAst::BoolAst(_)
+      | ExprAst::UnitAst
+      | ExprAst::VarAst(..) => {
+        self.lower_expr(expr, span)?;
+      }
+      // the math of numbers has no effect but for its operands
+      ExprAst::CallAst(name, args, _) if self.is_math(name, args.len()) => {
+        for arg in args {
+          self.lower_stmt(arg, span, stmts)?;
+        }
+      }
+      ExprAst::CallAst(..) => {
+        let call = self.lower_expr(expr, span)?;
+        let call = call.text.strip_suffix(" as f32").unwrap_or(&call.text);
+        stmts.push(format!("{};", call));
+      }
+      ExprAst::IfAst { cond, then, els } => {
+        let cond = self.lower_cond(cond, span)?;
+        let then = self.lower_stmts(then, span)?;
+        let els = self.lower_stmts(els, span)?;
+        match (then.is_empty(), els.is_empty()) {
+          (true, true) => {}
+          (false, true) => stmts.push(format!("if {} {}", cond.text, block_of(then))),
+          (true, false) => {
+            let cond = format!("!{}", cond.operand(PREC_UNARY));
+            stmts.push(format!("if {} {}", cond, block_of(els)));
+          }
+          (false, false) => stmts.push(format!(
+            "if {} {} else {}",
+            cond.text,
+            block_of(then),
+            block_of(els)
+          )),
+        }
+      }
+      ExprAst::BlockAst(_) | ExprAst::LetAst(..) | ExprAst::LetTupleAst(..) => {
+        let inner = self.lower_stmts(expr, span)?;
+        if !inner.is_empty() {
+          stmts.push(block_of(inner));
+        }
+      }
+      ExprAst::SeqAst(exprs) => {
+        for expr in exprs {
+          self.lower_stmt(expr, span, stmts)?;
+        }
+      }
+      ExprAst::ReturnAst(value, at) => {
+        let value = self.lower_expr(value, *at)?;
+        check_return(self.ret, value.shape, *at)?;
+        stmts.push(format!("return {};", value.text));
+      }
+      expr => {
+        let code = self.lower_expr(expr, span)?;
+        stmts.push(format!("let _ = {};", code.text));
+      }
+    }
+    Ok(())
+  }
+
+  /// The statements of `expr` in a block of its own, for its effects only.
+  fn lower_stmts(&mut self, expr: &ExprAst, span: Span) -> Result<Vec<String>, Diagnostic> {
+    let mut stmts = vec![];
+    let depth = self.scope.len();
+    let res = match expr {
+      ExprAst::LetAst(..) | ExprAst::LetTupleAst(..) => self
+        .lower_let(expr, span, &mut stmts)
+        .and_then(|body| self.lower_stmts(body, span))
+        .map(|inner| stmts.extend(inner)),
+      expr => self.lower_stmt(expr, span, &mut stmts),
+    };
+    self.scope.truncate(depth);
+    res?;
+    Ok(stmts)
+  }
+
+  fn lower_expr(&mut self, expr: &ExprAst, span: Span) -> Result<Code, Diagnostic> {
+    match expr {
+      ExprAst::NumAst(n) => Ok(self.literal(*n)),
+      ExprAst::IntAst(i) => Ok(self.literal(*i as f64)),
+      ExprAst::BoolAst(b) => Ok(self.literal(*b as i32 as f64)),
+      ExprAst::UnitAst => Ok(self.literal(0.0)),
+      ExprAst::VarAst(name, at) => match self.scope.iter().rev().find(|(n, ..)| n == name) {
+        Some((_, var, shape)) => Ok(Code {
+          shape: *shape,
+          ..Code::new(var.clone(), PREC_ATOM)
+        }),
+        None if self.transpiler.functions.contains_key(name) => {
+          Err(unsupported("Rust", "functions as values", *at))
+        }
+        None => Err(unsupported("Rust", "global variables", *at)),
+      },
+      ExprAst::UnaryAst(UnOp::Neg, operand, at) => {
+        let operand = self.lower_num(operand, *at)?;
+        let text = match operand.operand(PREC_UNARY) {
+          text if text.starts_with('-') => format!("-({})", text),
+          text => format!("-{}", text),
+        };
+        Ok(Code {
+          literal: operand.literal,
+          ..Code::new(text, PREC_UNARY)
+        })
+      }
+      ExprAst::UnaryAst(UnOp::Not, ..) => {
+        let cond = self.lower_cond(expr, span)?;
+        Ok(self.bool_to_num(cond))
+      }
+      ExprAst::BinAst(lhs, op, rhs, at) => self.lower_bin(lhs, *op, rhs, *at),
+      ExprAst::CallAst(name, args, at) => self.lower_call(name, args, *at),
+      ExprAst::IfAst { cond, then, els } => self.lower_if(cond, then, els, span),
+      ExprAst::BlockAst(_)
+      | ExprAst::SeqAst(_)
+      | ExprAst::LetAst(..)
+      | ExprAst::LetTupleAst(..) => {
+        match expr {
+          ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) if exprs.is_empty() => {
+            return Ok(self.literal(0.0));
+          }
+          _ => {}
+        }
+        let (stmts, tail) = self.lower_block(expr, span)?;
+        if stmts.is_empty() {
+          return Ok(tail);
+        }
+        Ok(Code {
+          shape: tail.shape,
+          ..Code::new(block(stmts, &tail.text), PREC_ATOM)
+        })
+      }
+      ExprAst::TupleAst(elems) => {
+        let mut texts = vec![];
+        for elem in elems {
+          texts.push(self.lower_num(elem, span)?.text);
+        }
+        Ok(Code {
+          shape: Shape::Tuple(elems.len()),
+          ..Code::new(format!("({})", texts.join(", ")), PREC_ATOM)
+        })
+      }
+      ExprAst::ElemAst(tuple, i) => {
+        let tuple = self.lower_expr(tuple, span)?;
+        match tuple.shape {
+          Shape::Tuple(n) if *i < n => Ok(Code::new(
+            format!("{}.{}", tuple.operand(PREC_ATOM), i),
+            PREC_ATOM,
+          )),
+          _ => {
+            Err(Diagnostic::error(span, format!("No element {} in tuple", i)).with_code("codegen"))
+          }
+        }
+      }
+      ExprAst::ReturnAst(value, at) => {
+        let value = self.lower_expr(value, *at)?;
+        check_return(self.ret, value.shape, *at)?;
+        Ok(Code {
+          shape: value.shape,
+          ..Code::new(format!("return {}", value.text), PREC_IF)
+        })
+      }
+      ExprAst::MatchAst(..) => unreachable!("`match` is lowered before codegen"),
+      ExprAst::StrAst(_) => Err(unsupported("Rust", "strings", span)),
+      ExprAst::ArrayAst(_) | ExprAst::IndexAst(..) => Err(unsupported("Rust", "arrays", span)),
+      ExprAst::FieldAst(..) => Err(unsupported("Rust", "structs", span)),
+      ExprAst::LambdaAst(..) => Err(unsupported("Rust", "closures", span)),
+      ExprAst::FuncRefAst(_, at) => Err(unsupported("Rust", "functions as values", *at)),
+      ExprAst::VarInAst(vars, _) => {
+        let at = vars.first().map_or(span, |(_, at, _)| *at);
+        Err(unsupported("Rust", "`var`", at))
+      }
+      ExprAst::AssignAst(.., at) => Err(unsupported("Rust", "assignments", *at)),
+      ExprAst::TryAst(.., at) => Err(unsupported("Rust", "`try`", *at)),
+    }
+  }
+
+  fn lower_num(&mut self, expr: &ExprAst, span: Span) -> Result<Code, Diagnostic> {
+    let code = self.lower_expr(expr, span)?;
+    match code.shape {
+      Shape::Num => Ok(code),
+      Shape::Tuple(_) => {
+        Err(Diagnostic::error(span, "Expected a number, found a tuple").with_code("codegen"))
+      }
+    }
+  }
+
+  /// A number that is 1 where `cond` holds, else 0.
+  fn bool_to_num(&self, cond: Code) -> Code {
+    let (one, zero) = (self.literal(1.0), self.literal(0.0));
+    let text = format!(
+      "if {} {{ {} }} else {{ {} }}",
+      cond.text, one.text, zero.text
+    );
+    Code::new(text, PREC_IF)
+  }
+
+  /// A condition, as a `bool` of whether `expr` is true: nonzero, which NaN
+  /// is.
+  fn lower_cond(&mut self, expr: &ExprAst, span: Span) -> Result<Code, Diagnostic> {
+    match Cond::of(expr, &self.transpiler.functions) {
+      Cond::Bool(b) => Ok(Code::new(b.to_string(), PREC_ATOM)),
+      Cond::Not(operand, at) => {
+        let cond = self.lower_cond(operand, at)?;
+        Ok(Code::new(
+          format!("!{}", cond.operand(PREC_ATOM)),
+          PREC_UNARY,
+        ))
+      }
+      Cond::Logical(lhs, op, rhs, at) => {
+        let prec = match op {
+          BinOp::And => PREC_AND,
+          _ => PREC_OR,
+        };
+        let lhs = self.lower_cond(lhs, at)?;
+        let rhs = self.lower_cond(rhs, at)?;
+        let text = format!(
+          "{} {} {}",
+          lhs.operand(prec),
+          op.as_str(),
+          rhs.operand(prec + 1)
+        );
+        Ok(Code::new(text, prec))
+      }
+      Cond::Cmp(lhs, op, rhs, at) => {
+        let lhs = self.lower_num(lhs, at)?;
+        let rhs = self.lower_num(rhs, at)?;
+        let text = format!(
+          "{} {} {}",
+          lhs.operand(PREC_CMP + 1),
+          op.as_str(),
+          rhs.operand(PREC_CMP + 1)
+        );
+        Ok(Code::new(text, PREC_CMP))
+      }
+      Cond::Nonzero => {
+        let num = self.lower_num(expr, span)?;
+        let text = format!(
+          "{} != {}",
+          num.operand(PREC_CMP + 1),
+          self.literal(0.0).text
+        );
+        Ok(Code::new(text, PREC_CMP))
+      }
+    }
+  }
+
+  fn lower_bin(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Code, Diagnostic> {
+    if is_overloaded(&self.transpiler.functions, op) {
+      return self.lower_call(&overload(op), &[lhs.clone(), rhs.clone()], span);
+    }
+    if op.is_bitwise() {
+      return Err(unsupported("Rust", "bitwise operators", span));
+    }
+    let prec = match op {
+      BinOp::Add | BinOp::Sub => PREC_ADD,
+      BinOp::Mul | BinOp::Div | BinOp::Rem => PREC_MUL,
+      _ => {
+        let expr = ExprAst::BinAst(Box::new(lhs.clone()), op, Box::new(rhs.clone()), span);
+        let cond = self.lower_cond(&expr, span)?;
+        return Ok(self.bool_to_num(cond));
+      }
+    };
+    let lhs = self.lower_num(lhs, span)?;
+    let rhs = self.lower_num(rhs, span)?;
+    // dividing ints truncates, and fails by zero, in the runtime, which
+    // takes `f64`s
+    if op == BinOp::Div && self.divisions.contains(&span) {
+      self.transpiler.runtime.insert("kale_idiv".to_string());
+      let args = [lhs, rhs].map(|arg| match self.transpiler.precision {
+        Precision::F64 => arg.text,
+        Precision::F32 if arg.literal => format!("f64::from({}_f32)", arg.text),
+        Precision::F32 => format!("f64::from({})", arg.text),
+      });
+      let call = format!("kale_idiv({}, {})", args[0], args[1]);
+      return Ok(match self.transpiler.precision {
+        Precision::F64 => Code::new(call, PREC_ATOM),
+        Precision::F32 => Code::new(format!("{} as f32", call), PREC_UNARY),
+      });
+    }
+    let text = format!(
+      "{} {} {}",
+      lhs.operand(prec),
+      op.as_str(),
+      rhs.operand(prec + 1)
+    );
+    Ok(Code::new(text, prec))
+  }
+
+  fn lower_if(
+    &mut self,
+    cond: &ExprAst,
+    then: &ExprAst,
+    els: &ExprAst,
+    span: Span,
+  ) -> Result<Code, Diagnostic> {
+    let cond = self.lower_cond(cond, span)?;
+    let (then_stmts, then) = self.lower_block(then, span)?;
+    let else_if = matches!(els, ExprAst::IfAst { .. });
+    let (els_stmts, els) = match els {
+      ExprAst::IfAst { .. } => (vec![], self.lower_expr(els, span)?),
+      els => self.lower_block(els, span)?,
+    };
+    if then.shape != els.shape {
+      let msg = "Branches of `if` yield both numbers and tuples, or tuples of different sizes";
+      return Err(Diagnostic::error(span, msg).with_code("codegen"));
+    }
+    let short = then_stmts.is_empty()
+      && els_stmts.is_empty()
+      && !else_if
+      && !format!("{}{}{}", cond.text, then.text, els.text).contains('\n')
+      && cond.text.len() + then.text.len() + els.text.len() < 60;
+    let text = match short {
+      true => format!(
+        "if {} {{ {} }} else {{ {} }}",
+        cond.text, then.text, els.text
+      ),
+      false => {
+        let els = match else_if {
+          true => els.text,
+          false => block(els_stmts, &els.text),
+        };
+        format!(
+          "if {} {} else {}",
+          cond.text,
+          block(then_stmts, &then.text),
+          els
+        )
+      }
+    };
+    Ok(Code {
+      shape: then.shape,
+      ..Code::new(text, PREC_IF)
+    })
+  }
+
+  /// A call of a function of the module or of the prelude, or of `int`,
+  /// which rounds towards zero, or `float`, which does nothing.
+  fn lower_call(&mut self, name: &str, args: &[ExprAst], span: Span) -> Result<Code, Diagnostic> {
+    if self.scope.iter().any(|(local, ..)| local == name) {
+      return Err(unsupported("Rust", "closures", span));
+    }
+    match (name, args.len()) {
+      ("float", 1) => return self.lower_num(&args[0], span),
+      ("int", 1) => return self.method(&args[0], "trunc", &[], span),
+      _ => {}
+    }
+    let Some(callee) = self.callee(name) else {
+      let what = format!("the builtin `{}`", name);
+      return Err(unsupported("Rust", &what, span));
+    };
+    if let Call::Method(method) = &callee.call {
+      return self.method(&args[0], method, &args[1..], span);
+    }
+    let widen = callee.widen && self.transpiler.precision == Precision::F32;
+    let mut texts = vec![];
+    for arg in args {
+      let arg = self.lower_num(arg, span)?;
+      texts.push(match widen {
+        true if arg.literal => format!("f64::from({}_f32)", arg.text),
+        true => format!("f64::from({})", arg.text),
+        false => arg.text,
+      });
+    }
+    let call = format!("{}({})", callee.name, texts.join(", "));
+    let text = match callee.call {
+      Call::Extern => format!("unsafe {{ {} }}", call),
+      Call::Runtime => {
+        self.transpiler.runtime.insert(callee.name.clone());
+        match widen {
+          true => return Ok(Code::new(format!("{} as f32", call), PREC_UNARY)),
+          false => call,
+        }
+      }
+      _ => call,
+    };
+    Ok(Code {
+      shape: match callee.tuple {
+        Some(n) => Shape::Tuple(n),
+        None => Shape::Num,
+      },
+      ..Code::new(text, PREC_ATOM)
+    })
+  }
+
+  /// The function `name` of the module, or else of the prelude.
+  fn callee(&mut self, name: &str) -> Option<Callee> {
+    if !self.transpiler.functions.contains_key(name) {
+      let proto = prelude().items.into_iter().find_map(|item| match item {
+        Ast::Proto(proto) if proto.name == name => Some(proto),
+        _ => None,
+      });
+      if let Some(proto) = proto {
+        self.transpiler.declare_extern(&proto);
+      }
+    }
+    self.transpiler.functions.get(name).cloned()
+  }
+
+  /// Whether calling `name` with `arity` arguments is a method of numbers.
+  fn is_math(&mut self, name: &str, arity: usize) -> bool {
+    if self.scope.iter().any(|(local, ..)| local == name) {
+      return false;
+    }
+    match (name, arity) {
+      ("int" | "float", 1) => true,
+      _ => matches!(
+        self.callee(name),
+        Some(Callee {
+          call: Call::Method(_),
+          ..
+        })
+      ),
+    }
+  }
+
+  /// `receiver.method(args)`, with the type of a literal receiver given.
+  fn method(
+    &mut self,
+    receiver: &ExprAst,
+    method: &str,
+    args: &[ExprAst],
+    span: Span,
+  ) -> Result<Code, Diagnostic> {
+    let receiver = self.lower_num(receiver, span)?;
+    let text = match receiver.literal {
+      true => format!("{}_{}", receiver.text, self.transpiler.float),
+      false => receiver.text.clone(),
+    };
+    let receiver = Code { text, ..receiver };
+    let mut texts = vec![];
+    for arg in args {
+      texts.push(self.lower_num(arg, span)?.text);
+    }
+    let text = format!(
+      "{}.{}({})",
+      receiver.operand(PREC_ATOM),
+      method,
+      texts.join(", ")
+    );
+    Ok(Code::new(text, PREC_ATOM))
+  }
+}
+
+/// `stmts` in a block.
+fn block_of(stmts: Vec<String>) -> String {
+  let lines: Vec<_> = stmts.iter().map(|stmt| indent(stmt)).collect();
+  format!("{{\n{}\n}}", lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codegen::tests::transpile_src;
+
+  #[test]
+  fn rust_transpile() {
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a);
+      def norm(x, y) sqrt(x * x + y * y);
+      def sign(x) { if x < 0 then return -1 else (); if x > 0 then 1 else 0 };
+      def main() { let (lo, hi) = minmax(2, 1) in printd(hi % lo); printd(int(-2.5)) };";
+    let expected = "pub fn minmax(a: f64, b: f64) -> (f64, f64) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+pub fn norm(x: f64, y: f64) -> f64 {
+    (x * x + y * y).sqrt()
+}
+
+pub fn sign(x: f64) -> f64 {
+    if x < 0.0 {
+        return -1.0;
+    }
+    if x > 0.0 { 1.0 } else { 0.0 }
+}
+
+pub fn main() -> f64 {
+    {
+        let (lo, hi) = minmax(2.0, 1.0);
+        printd(hi % lo);
+    }
+    printd((-2.5_f64).trunc())
+}
+
+pub fn run() {
+    main();
+}
+
+fn printd(x: f64) -> f64 {
+    println!(\"{:?}\", x);
+    0.0
+}
+";
+    assert_eq!(transpile_src(transpile, src).unwrap(), expected);
+
+    assert_eq!(
+      transpile_src(transpile, "def f(x) x; \"s\"").unwrap_err(),
+      ["1:13: The Rust backend doesn't support strings"]
+    );
+  }
+}
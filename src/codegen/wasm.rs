@@ -0,0 +1,1100 @@
+use super::backend::{define_module, Backend, Declaration, Declared};
+use super::{check_return, int_divisions, is_overloaded, overload, tuple_arity, unsupported};
+use super::{Returns, Shape};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, ProtoAst, UnOp};
+use crate::prelude::prelude;
+use crate::session::Entry;
+use crate::value::Precision;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Format - how a WebAssembly module is written: in the binary format of
+/// `.wasm` files, or as the WAT text of `.wat` files.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+  Wasm,
+  Wat,
+}
+
+/// WasmModule - a program in WebAssembly. Every value is an `f64`, or an
+/// `f32` in `F32` precision, and a function that returns a tuple returns
+/// its numbers as several results.
+///
+/// Externs are imported from the module `env`, which the host provides:
+/// those of the runtime and the math of the prelude, which take and return
+/// `f64`s whatever the precision, and `fmod` for `%`; `sqrt`, `abs`,
+/// `floor` and `int` are instructions. The functions of the program are
+/// exported by name, along with `_start`, which calls `main`, or else runs
+/// the top-level expressions in order.
+#[derive(Debug)]
+pub struct WasmModule {
+  float: ValType,
+  types: Vec<FuncType>,
+  imports: Vec<(String, usize)>, // the symbol and type of each
+  functions: Vec<Function>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ValType {
+  I32,
+  F32,
+  F64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct FuncType {
+  params: Vec<ValType>,
+  results: Vec<ValType>,
+}
+
+#[derive(Debug)]
+struct Function {
+  name: String,
+  ty: usize,
+  locals: usize, // beyond the parameters, all numbers
+  body: Vec<Instr>,
+  export: bool,
+}
+
+/// A function called, numbered among the imports or the functions defined.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Func {
+  Import(usize),
+  Defined(usize),
+}
+
+/// An instruction. Those of numbers are of the type of the module's.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Instr {
+  Const(f64),
+  I32Const(i32),
+  LocalGet(u32),
+  LocalSet(u32),
+  Call(Func),
+  Drop,
+  Return,
+  If(BlockType),
+  Else,
+  End,
+  Float(FloatOp),
+  I64DivS, // traps on a division by zero
+}
+
+/// What an `if` yields: an `i32`, a number, or the numbers of the
+/// function type of that index.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum BlockType {
+  I32,
+  Float,
+  Type(usize),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FloatOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Neg,
+  Abs,
+  Sqrt,
+  Floor,
+  Trunc,
+  Eq,
+  Ne,
+  Lt,
+  Gt,
+  Le,
+  Ge,
+  FromBool, // `convert_i32_u`
+  Promote,  // an `f32` to `f64`
+  Demote,   // an `f64` to `f32`
+  ToInt,    // `i64.trunc_*_s`, of a number holding an int
+  FromInt,  // `convert_i64_s`
+}
+
+impl ValType {
+  fn code(self) -> u8 {
+    match self {
+      ValType::I32 => 0x7f,
+      ValType::F32 => 0x7d,
+      ValType::F64 => 0x7c,
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      ValType::I32 => "i32",
+      ValType::F32 => "f32",
+      ValType::F64 => "f64",
+    }
+  }
+}
+
+impl FloatOp {
+  /// The opcode and name of the operation on `float`s.
+  fn encoding(self, float: ValType) -> (u8, &'static str) {
+    let f32 = float == ValType::F32;
+    let (f32_op, f64_op, name) = match self {
+      FloatOp::Add => (0x92, 0xa0, "add"),
+      FloatOp::Sub => (0x93, 0xa1, "sub"),
+      FloatOp::Mul => (0x94, 0xa2, "mul"),
+      FloatOp::Div => (0x95, 0xa3, "div"),
+      FloatOp::Neg => (0x8c, 0x9a, "neg"),
+      FloatOp::Abs => (0x8b, 0x99, "abs"),
+      FloatOp::Sqrt => (0x91, 0x9f, "sqrt"),
+      FloatOp::Floor => (0x8e, 0x9c, "floor"),
+      FloatOp::Trunc => (0x8f, 0x9d, "trunc"),
+      FloatOp::Eq => (0x5b, 0x61, "eq"),
+      FloatOp::Ne => (0x5c, 0x62, "ne"),
+      FloatOp::Lt => (0x5d, 0x63, "lt"),
+      FloatOp::Gt => (0x5e, 0x64, "gt"),
+      FloatOp::Le => (0x5f, 0x65, "le"),
+      FloatOp::Ge => (0x60, 0x66, "ge"),
+      FloatOp::FromBool => (0xb3, 0xb8, "convert_i32_u"),
+      FloatOp::Promote => return (0xbb, "f64.promote_f32"),
+      FloatOp::Demote => return (0xb6, "f32.demote_f64"),
+      FloatOp::ToInt if f32 => return (0xae, "i64.trunc_f32_s"),
+      FloatOp::ToInt => return (0xb0, "i64.trunc_f64_s"),
+      FloatOp::FromInt => (0xb4, 0xb9, "convert_i64_s"),
+    };
+    match f32 {
+      true => (f32_op, name),
+      false => (f64_op, name),
+    }
+  }
+}
+
+/// Compiles the checked program `module`, which starts at `entry`, but for
+/// its `var`s and assignments. Every function is compiled even when another
+/// one fails, and the errors are reported in order.
+pub fn compile(
+  module: &ModuleAst,
+  entry: Entry,
+  precision: Precision,
+) -> Result<WasmModule, Vec<Diagnostic>> {
+  let mut backend = WasmBackend::new(precision, Format::Wasm);
+  define_module(&mut backend, module)?;
+  Ok(backend.into_module(entry))
+}
+
+/// WasmBackend - compiles programs to WebAssembly, as [`compile`] does,
+/// writing the module `output` in the given format.
+pub struct WasmBackend {
+  compiler: Compiler,
+  format: Format,
+  declared: Declared<Callee>,
+}
+
+impl WasmBackend {
+  pub fn new(precision: Precision, format: Format) -> Self {
+    let float = match precision {
+      Precision::F64 => ValType::F64,
+      Precision::F32 => ValType::F32,
+    };
+    let compiler = Compiler {
+      wasm: WasmModule {
+        float,
+        types: vec![],
+        imports: vec![],
+        functions: vec![],
+      },
+      functions: HashMap::new(),
+      returns: Returns::default(),
+    };
+    Self {
+      compiler,
+      format,
+      declared: Declared::new(),
+    }
+  }
+
+  /// The module of the functions defined, whose `_start` starts the
+  /// program at `entry`.
+  pub fn into_module(mut self, entry: Entry) -> WasmModule {
+    self.compiler.compile_start(entry, &self.declared);
+    self.compiler.wasm
+  }
+}
+
+impl Backend for WasmBackend {
+  fn name(&self) -> &'static str {
+    "WebAssembly"
+  }
+
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+    match decl {
+      Declaration::Extern => {
+        self.compiler.import(proto);
+      }
+      Declaration::Function { tuple } => {
+        let callee = self.compiler.declare(proto, tuple);
+        self.declared.push(&proto.name, callee);
+      }
+    }
+    Ok(())
+  }
+
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+    let compiler = &mut self.compiler;
+    let callee = self.declared.define(func, |_| {
+      let tuple = tuple_arity(&func.body, &compiler.returns.tuples);
+      compiler.declare(&func.proto, tuple)
+    });
+    self.compiler.compile_func(func, &callee)
+  }
+
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>> {
+    self.compiler.compile_start(entry, &self.declared);
+    let wasm = &self.compiler.wasm;
+    let bytes = match self.format {
+      Format::Wasm => wasm.to_bytes(),
+      Format::Wat => wasm.to_wat().into_bytes(),
+    };
+    std::fs::write(output, bytes).map_err(|e| {
+      let msg = format!("Cannot write `{}`: {}", output.display(), e);
+      vec![Diagnostic::error(Span::default(), msg).with_code("codegen")]
+    })
+  }
+}
+
+/// A function as programs call it, by its name in the module and its
+/// index among the imports or the functions defined.
+type Callee = super::Callee<Func>;
+
+struct Compiler {
+  wasm: WasmModule,
+  functions: HashMap<String, Callee>, // by the name programs call them
+  returns: Returns,                   // what they return
+}
+
+impl Compiler {
+  /// Declares the function `proto` defines, or a top-level expression,
+  /// with no body yet, returning a tuple of `tuple` numbers if any.
+  fn declare(&mut self, proto: &ProtoAst, tuple: Option<usize>) -> Callee {
+    self.returns.declare(proto, tuple);
+    let float = self.wasm.float;
+    let ty = self.wasm.intern_type(FuncType {
+      params: vec![float; proto.args.len()],
+      results: vec![float; tuple.unwrap_or(1)],
+    });
+    let index = self.wasm.functions.len();
+    let name = match proto.name.as_str() {
+      "" => {
+        let n = self.wasm.functions.iter().filter(|f| !f.export).count();
+        match n {
+          0 => "__anon_expr".to_string(),
+          n => format!("__anon_expr.{}", n),
+        }
+      }
+      name => name.to_string(),
+    };
+    self.wasm.functions.push(Function {
+      name: name.clone(),
+      ty,
+      locals: 0,
+      body: vec![],
+      export: !proto.name.is_empty(),
+    });
+    let callee = Callee {
+      name,
+      call: Func::Defined(index),
+      tuple,
+      widen: false,
+    };
+    if !proto.name.is_empty() {
+      self.functions.insert(proto.name.clone(), callee.clone());
+    }
+    callee
+  }
+
+  /// Imports the extern `proto` from `env`.
+  fn import(&mut self, proto: &ProtoAst) -> Callee {
+    let callee = self.wasm.import(proto.symbol(), proto.args.len());
+    self.functions.insert(proto.name.clone(), callee.clone());
+    callee
+  }
+
+  /// Compiles the function `func`, or the top-level expression it wraps,
+  /// into the function `callee` declares.
+  fn compile_func(&mut self, func: &FuncAst, callee: &Callee) -> Result<(), Diagnostic> {
+    let proto = &func.proto;
+    let mut body = func.body.clone();
+    body.lower_matches();
+    let ret = match callee.tuple {
+      Some(n) => Shape::Tuple(n),
+      None => Shape::Num,
+    };
+    let mut lowering = Lowering {
+      divisions: int_divisions(func, &self.returns.annotations),
+      compiler: self,
+      body: vec![],
+      locals: proto.args.len(),
+      scope: (0..proto.args.len())
+        .map(|i| (proto.args[i].clone(), vec![i as u32], Shape::Num))
+        .collect(),
+      ret,
+    };
+    let shape = lowering.lower_expr(&body, proto.span)?;
+    check_return(lowering.ret, shape, proto.span)?;
+    lowering.body.push(Instr::End);
+    let (instrs, locals) = (lowering.body, lowering.locals);
+    let Func::Defined(index) = callee.call else {
+      unreachable!()
+    };
+    let function = &mut self.wasm.functions[index];
+    function.body = instrs;
+    function.locals = locals - proto.args.len();
+    Ok(())
+  }
+
+  /// Adds `_start`, which calls `main`, or else the top-level expressions
+  /// among those `declared`, unnamed, dropping what they return.
+  fn compile_start(&mut self, entry: Entry, declared: &Declared<Callee>) {
+    let mut body = vec![];
+    for callee in declared.entry(entry) {
+      body.push(Instr::Call(callee.call));
+      body.extend(vec![Instr::Drop; callee.tuple.unwrap_or(1)]);
+    }
+    body.push(Instr::End);
+    let ty = self.wasm.intern_type(FuncType {
+      params: vec![],
+      results: vec![],
+    });
+    self.wasm.functions.push(Function {
+      name: "_start".to_string(),
+      ty,
+      locals: 0,
+      body,
+      export: true,
+    });
+  }
+}
+
+impl WasmModule {
+  fn intern_type(&mut self, ty: FuncType) -> usize {
+    match self.types.iter().position(|t| *t == ty) {
+      Some(index) => index,
+      None => {
+        self.types.push(ty);
+        self.types.len() - 1
+      }
+    }
+  }
+
+  /// Imports `symbol` from `env`, once, taking `arity` numbers.
+  fn import(&mut self, symbol: &str, arity: usize) -> Callee {
+    let widen = matches!(
+      symbol,
+      "printd"
+        | "putchard"
+        | "readd"
+        | "rand"
+        | "srand"
+        | "sin"
+        | "cos"
+        | "exp"
+        | "log"
+        | "sqrt"
+        | "pow"
+        | "abs"
+        | "floor"
+        | "min"
+        | "max"
+        | "fmod"
+    );
+    let float = if widen { ValType::F64 } else { self.float };
+    let ty = self.intern_type(FuncType {
+      params: vec![float; arity],
+      results: vec![float],
+    });
+    let index = match self.imports.iter().position(|(s, _)| s == symbol) {
+      Some(index) => index,
+      None => {
+        self.imports.push((symbol.to_string(), ty));
+        self.imports.len() - 1
+      }
+    };
+    Callee {
+      name: symbol.to_string(),
+      call: Func::Import(index),
+      tuple: None,
+      widen,
+    }
+  }
+
+  fn func_index(&self, func: Func) -> usize {
+    match func {
+      Func::Import(index) => index,
+      Func::Defined(index) => self.imports.len() + index,
+    }
+  }
+
+  /// The module in the binary format.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut out = b"\0asm".to_vec();
+    out.extend(1u32.to_le_bytes());
+    let mut types = vec![];
+    uleb(&mut types, self.types.len() as u64);
+    for ty in &self.types {
+      types.push(0x60);
+      for vals in [&ty.params, &ty.results] {
+        uleb(&mut types, vals.len() as u64);
+        types.extend(vals.iter().map(|val| val.code()));
+      }
+    }
+    section(&mut out, 1, &types);
+    let mut imports = vec![];
+    uleb(&mut imports, self.imports.len() as u64);
+    for (symbol, ty) in &self.imports {
+      name(&mut imports, "env");
+      name(&mut imports, symbol);
+      imports.push(0x00);
+      uleb(&mut imports, *ty as u64);
+    }
+    section(&mut out, 2, &imports);
+    let mut functions = vec![];
+    uleb(&mut functions, self.functions.len() as u64);
+    for function in &self.functions {
+      uleb(&mut functions, function.ty as u64);
+    }
+    section(&mut out, 3, &functions);
+    let exported: Vec<_> = self
+      .functions
+      .iter()
+      .enumerate()
+      .filter(|(_, f)| f.export)
+      .collect();
+    let mut exports = vec![];
+    uleb(&mut exports, exported.len() as u64);
+    for (index, function) in exported {
+      name(&mut exports, &function.name);
+      exports.push(0x00);
+      uleb(&mut exports, self.func_index(Func::Defined(index)) as u64);
+    }
+    section(&mut out, 7, &exports);
+    let mut code = vec![];
+    uleb(&mut code, self.functions.len() as u64);
+    for function in &self.functions {
+      let mut body = vec![];
+      match function.locals {
+        0 => body.push(0),
+        n => {
+          body.push(1);
+          uleb(&mut body, n as u64);
+          body.push(self.float.code());
+        }
+      }
+      for instr in &function.body {
+        self.encode(instr, &mut body);
+      }
+      uleb(&mut code, body.len() as u64);
+      code.extend(body);
+    }
+    section(&mut out, 10, &code);
+    out
+  }
+
+  fn encode(&self, instr: &Instr, out: &mut Vec<u8>) {
+    match *instr {
+      Instr::Const(n) => match self.float {
+        ValType::F32 => {
+          out.push(0x43);
+          out.extend((n as f32).to_le_bytes());
+        }
+        _ => {
+          out.push(0x44);
+          out.extend(n.to_le_bytes());
+        }
+      },
+      Instr::I32Const(n) => {
+        out.push(0x41);
+        sleb(out, n as i64);
+      }
+      Instr::LocalGet(local) => {
+        out.push(0x20);
+        uleb(out, local as u64);
+      }
+      Instr::LocalSet(local) => {
+        out.push(0x21);
+        uleb(out, local as u64);
+      }
+      Instr::Call(func) => {
+        out.push(0x10);
+        uleb(out, self.func_index(func) as u64);
+      }
+      Instr::Drop => out.push(0x1a),
+      Instr::Return => out.push(0x0f),
+      Instr::If(ty) => {
+        out.push(0x04);
+        match ty {
+          BlockType::I32 => out.push(ValType::I32.code()),
+          BlockType::Float => out.push(self.float.code()),
+          BlockType::Type(index) => sleb(out, index as i64),
+        }
+      }
+      Instr::Else => out.push(0x05),
+      Instr::End => out.push(0x0b),
+      Instr::Float(op) => out.push(op.encoding(self.float).0),
+      Instr::I64DivS => out.push(0x7f),
+    }
+  }
+
+  /// The module as WAT text, with the functions named as in the program
+  /// and the imports as `$env.symbol`.
+  pub fn to_wat(&self) -> String {
+    let mut out = "(module\n".to_string();
+    for (index, ty) in self.types.iter().enumerate() {
+      let _ = writeln!(out, "  (type (;{};) (func{}))", index, signature(ty));
+    }
+    for (symbol, ty) in &self.imports {
+      let _ = writeln!(
+        out,
+        "  (import \"env\" \"{}\" (func $env.{} (type {})))",
+        symbol, symbol, ty
+      );
+    }
+    for function in &self.functions {
+      let ty = &self.types[function.ty];
+      let _ = write!(out, "  (func ${} (type {})", function.name, function.ty);
+      let _ = writeln!(out, "{}", signature(ty));
+      if function.locals > 0 {
+        let locals = vec![self.float.name(); function.locals];
+        let _ = writeln!(out, "    (local {})", locals.join(" "));
+      }
+      let mut depth = 2;
+      let (_, instrs) = function.body.split_last().unwrap(); // the last `end`
+      for instr in instrs {
+        if let Instr::Else | Instr::End = instr {
+          depth -= 1;
+        }
+        let _ = writeln!(out, "{}{}", "  ".repeat(depth), self.instr_text(instr));
+        if let Instr::If(_) | Instr::Else = instr {
+          depth += 1;
+        }
+      }
+      out.push_str("  )\n");
+    }
+    for function in self.functions.iter().filter(|f| f.export) {
+      let _ = writeln!(
+        out,
+        "  (export \"{}\" (func ${}))",
+        function.name, function.name
+      );
+    }
+    out.push_str(")\n");
+    out
+  }
+
+  fn instr_text(&self, instr: &Instr) -> String {
+    let float = self.float.name();
+    match *instr {
+      Instr::Const(n) => {
+        let n = match self.float {
+          ValType::F32 => n as f32 as f64,
+          _ => n,
+        };
+        let n = match n {
+          n if n.is_nan() => "nan".to_string(),
+          n if n.is_infinite() => n.to_string(),
+          n => format!("{:?}", n),
+        };
+        format!("{}.const {}", float, n)
+      }
+      Instr::I32Const(n) => format!("i32.const {}", n),
+      Instr::LocalGet(local) => format!("local.get {}", local),
+      Instr::LocalSet(local) => format!("local.set {}", local),
+      Instr::Call(Func::Import(index)) => format!("call $env.{}", self.imports[index].0),
+      Instr::Call(Func::Defined(index)) => format!("call ${}", self.functions[index].name),
+      Instr::Drop => "drop".to_string(),
+      Instr::Return => "return".to_string(),
+      Instr::If(BlockType::I32) => "if (result i32)".to_string(),
+      Instr::If(BlockType::Float) => format!("if (result {})", float),
+      Instr::If(BlockType::Type(index)) => format!("if (type {})", index),
+      Instr::Else => "else".to_string(),
+      Instr::End => "end".to_string(),
+      Instr::Float(op) => match op.encoding(self.float).1 {
+        name if name.contains('.') => name.to_string(),
+        name => format!("{}.{}", float, name),
+      },
+      Instr::I64DivS => "i64.div_s".to_string(),
+    }
+  }
+}
+
+/// The parameters and results of `ty`, in WAT.
+fn signature(ty: &FuncType) -> String {
+  let mut out = String::new();
+  for (keyword, vals) in [("param", &ty.params), ("result", &ty.results)] {
+    if !vals.is_empty() {
+      let names: Vec<_> = vals.iter().map(|val| val.name()).collect();
+      let _ = write!(out, " ({} {})", keyword, names.join(" "));
+    }
+  }
+  out
+}
+
+/// Appends the section `id` with `contents`.
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+  out.push(id);
+  uleb(out, contents.len() as u64);
+  out.extend(contents);
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+  uleb(out, name.len() as u64);
+  out.extend(name.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut n: u64) {
+  loop {
+    let byte = (n & 0x7f) as u8;
+    n >>= 7;
+    if n == 0 {
+      return out.push(byte);
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+fn sleb(out: &mut Vec<u8>, mut n: i64) {
+  loop {
+    let byte = (n & 0x7f) as u8;
+    n >>= 7;
+    if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+      return out.push(byte);
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+/// Lowering - the state of the compilation of one function. Variables are
+/// locals, one per number.
+struct Lowering<'a> {
+  compiler: &'a mut Compiler,
+  divisions: HashSet<Span>, // of ints, which truncate
+  body: Vec<Instr>,
+  locals: usize, // the parameters and the locals so far
+  scope: Vec<(String, Vec<u32>, Shape)>,
+  ret: Shape,
+}
+
+impl Lowering<'_> {
+  fn new_locals(&mut self, n: usize) -> Vec<u32> {
+    let locals = (self.locals..self.locals + n).map(|i| i as u32).collect();
+    self.locals += n;
+    locals
+  }
+
+  /// Pops the values of `shape` into new locals.
+  fn set_locals(&mut self, shape: Shape) -> Vec<u32> {
+    let locals = self.new_locals(shape.numbers());
+    let sets = locals.iter().rev().map(|&local| Instr::LocalSet(local));
+    self.body.extend(sets);
+    locals
+  }
+
+  fn lower_expr(&mut self, expr: &ExprAst, span: Span) -> Result<Shape, Diagnostic> {
+    let num = |lowering: &mut Self, n: f64| {
+      lowering.body.push(Instr::Const(n));
+      Ok(Shape::Num)
+    };
+    match expr {
+      ExprAst::NumAst(n) => num(self, *n),
+      ExprAst::IntAst(i) => num(self, *i as f64),
+      ExprAst::BoolAst(b) => num(self, *b as i32 as f64),
+      ExprAst::UnitAst => num(self, 0.0),
+      ExprAst::VarAst(name, at) => match self.scope.iter().rev().find(|(n, ..)| n == name) {
+        Some((_, locals, shape)) => {
+          let shape = *shape;
+          let gets = locals.iter().map(|&local| Instr::LocalGet(local));
+          self.body.extend(gets.collect::<Vec<_>>());
+          Ok(shape)
+        }
+        None if self.compiler.functions.contains_key(name) => {
+          Err(unsupported("WebAssembly", "functions as values", *at))
+        }
+        None => Err(unsupported("WebAssembly", "global variables", *at)),
+      },
+      ExprAst::UnaryAst(op, operand, at) => {
+        self.lower_num(operand, *at)?;
+        match op {
+          UnOp::Neg => self.body.push(Instr::Float(FloatOp::Neg)),
+          UnOp::Not => {
+            self.body.push(Instr::Const(0.0));
+            self.body.push(Instr::Float(FloatOp::Eq));
+            self.body.push(Instr::Float(FloatOp::FromBool));
+          }
+        }
+        Ok(Shape::Num)
+      }
+      ExprAst::BinAst(lhs, op, rhs, at) => self.lower_bin(lhs, *op, rhs, *at),
+      ExprAst::CallAst(name, args, at) => self.lower_call(name, args, *at),
+      ExprAst::IfAst { cond, then, els } => self.lower_if(cond, then, els, span),
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        let Some((last, exprs)) = exprs.split_last() else {
+          return num(self, 0.0);
+        };
+        for expr in exprs {
+          let shape = self.lower_expr(expr, span)?;
+          self.body.extend(vec![Instr::Drop; shape.numbers()]);
+        }
+        self.lower_expr(last, span)
+      }
+      ExprAst::TupleAst(elems) => {
+        for elem in elems {
+          self.lower_num(elem, span)?;
+        }
+        Ok(Shape::Tuple(elems.len()))
+      }
+      ExprAst::ElemAst(tuple, i) => match self.lower_expr(tuple, span)? {
+        Shape::Tuple(n) if *i < n => {
+          let locals = self.set_locals(Shape::Tuple(n));
+          self.body.push(Instr::LocalGet(locals[*i]));
+          Ok(Shape::Num)
+        }
+        _ => {
+          Err(Diagnostic::error(span, format!("No element {} in tuple", i)).with_code("codegen"))
+        }
+      },
+      ExprAst::LetAst(bindings, body) => {
+        let depth = self.scope.len();
+        for (name, _, init) in bindings {
+          let shape = self.lower_expr(init, span)?;
+          let locals = self.set_locals(shape);
+          self.scope.push((name.clone(), locals, shape));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let n = match self.lower_expr(init, span)? {
+          Shape::Tuple(n) if n == names.len() => n,
+          Shape::Tuple(n) => {
+            let msg = format!(
+              "Cannot destructure a tuple of {} into {} names",
+              n,
+              names.len()
+            );
+            return Err(Diagnostic::error(span, msg).with_code("codegen"));
+          }
+          Shape::Num => {
+            let msg = format!("Cannot destructure a number into {} names", names.len());
+            return Err(Diagnostic::error(span, msg).with_code("codegen"));
+          }
+        };
+        let locals = self.set_locals(Shape::Tuple(n));
+        let depth = self.scope.len();
+        for ((name, _), local) in names.iter().zip(locals) {
+          self.scope.push((name.clone(), vec![local], Shape::Num));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      // the code that follows a `return` is unreachable, where WebAssembly
+      // lets any values be popped, so the `return` yields what it returned
+      ExprAst::ReturnAst(value, at) => {
+        let shape = self.lower_expr(value, *at)?;
+        check_return(self.ret, shape, *at)?;
+        self.body.push(Instr::Return);
+        Ok(shape)
+      }
+      ExprAst::MatchAst(..) => unreachable!("`match` is lowered before codegen"),
+      ExprAst::StrAst(_) => Err(unsupported("WebAssembly", "strings", span)),
+      ExprAst::ArrayAst(_) | ExprAst::IndexAst(..) => {
+        Err(unsupported("WebAssembly", "arrays", span))
+      }
+      ExprAst::FieldAst(..) => Err(unsupported("WebAssembly", "structs", span)),
+      ExprAst::LambdaAst(..) => Err(unsupported("WebAssembly", "closures", span)),
+      ExprAst::FuncRefAst(_, at) => Err(unsupported("WebAssembly", "functions as values", *at)),
+      ExprAst::VarInAst(vars, _) => {
+        let at = vars.first().map_or(span, |(_, at, _)| *at);
+        Err(unsupported("WebAssembly", "`var`", at))
+      }
+      ExprAst::AssignAst(.., at) => Err(unsupported("WebAssembly", "assignments", *at)),
+      ExprAst::TryAst(.., at) => Err(unsupported("WebAssembly", "`try`", *at)),
+    }
+  }
+
+  fn lower_num(&mut self, expr: &ExprAst, span: Span) -> Result<(), Diagnostic> {
+    match self.lower_expr(expr, span)? {
+      Shape::Num => Ok(()),
+      Shape::Tuple(_) => {
+        Err(Diagnostic::error(span, "Expected a number, found a tuple").with_code("codegen"))
+      }
+    }
+  }
+
+  /// A condition, as the `i32` of whether `expr` is true: nonzero, which
+  /// NaN is.
+  fn lower_cond(&mut self, expr: &ExprAst, span: Span) -> Result<(), Diagnostic> {
+    self.lower_num(expr, span)?;
+    self.body.push(Instr::Const(0.0));
+    self.body.push(Instr::Float(FloatOp::Ne));
+    Ok(())
+  }
+
+  fn lower_bin(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Shape, Diagnostic> {
+    if is_overloaded(&self.compiler.functions, op) {
+      return self.lower_call(&overload(op), &[lhs.clone(), rhs.clone()], span);
+    }
+    if let BinOp::And | BinOp::Or = op {
+      return self.lower_logical(lhs, op, rhs, span);
+    }
+    if op.is_bitwise() {
+      return Err(unsupported("WebAssembly", "bitwise operators", span));
+    }
+    // dividing ints truncates, and traps by zero
+    if op == BinOp::Div && self.divisions.contains(&span) {
+      for operand in [lhs, rhs] {
+        self.lower_num(operand, span)?;
+        self.body.push(Instr::Float(FloatOp::ToInt));
+      }
+      self.body.push(Instr::I64DivS);
+      self.body.push(Instr::Float(FloatOp::FromInt));
+      return Ok(Shape::Num);
+    }
+    if op == BinOp::Rem {
+      let fmod = self.compiler.wasm.import("fmod", 2);
+      return self.call(&fmod, &[lhs.clone(), rhs.clone()], span);
+    }
+    self.lower_num(lhs, span)?;
+    self.lower_num(rhs, span)?;
+    let op = match op {
+      BinOp::Add => FloatOp::Add,
+      BinOp::Sub => FloatOp::Sub,
+      BinOp::Mul => FloatOp::Mul,
+      BinOp::Div => FloatOp::Div,
+      BinOp::Lt => FloatOp::Lt,
+      BinOp::Gt => FloatOp::Gt,
+      BinOp::Le => FloatOp::Le,
+      BinOp::Ge => FloatOp::Ge,
+      BinOp::Eq => FloatOp::Eq,
+      BinOp::Ne => FloatOp::Ne,
+      _ => unreachable!(),
+    };
+    self.body.push(Instr::Float(op));
+    if !matches!(
+      op,
+      FloatOp::Add | FloatOp::Sub | FloatOp::Mul | FloatOp::Div
+    ) {
+      self.body.push(Instr::Float(FloatOp::FromBool));
+    }
+    Ok(Shape::Num)
+  }
+
+  /// `&&` and `||`, which only evaluate `rhs` when `lhs` doesn't decide.
+  fn lower_logical(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Shape, Diagnostic> {
+    self.lower_cond(lhs, span)?;
+    self.body.push(Instr::If(BlockType::I32));
+    if op == BinOp::Or {
+      self.body.push(Instr::I32Const(1));
+      self.body.push(Instr::Else);
+    }
+    self.lower_cond(rhs, span)?;
+    if op == BinOp::And {
+      self.body.push(Instr::Else);
+      self.body.push(Instr::I32Const(0));
+    }
+    self.body.push(Instr::End);
+    self.body.push(Instr::Float(FloatOp::FromBool));
+    Ok(Shape::Num)
+  }
+
+  fn lower_if(
+    &mut self,
+    cond: &ExprAst,
+    then: &ExprAst,
+    els: &ExprAst,
+    span: Span,
+  ) -> Result<Shape, Diagnostic> {
+    self.lower_cond(cond, span)?;
+    let start = self.body.len();
+    self.body.push(Instr::If(BlockType::Float));
+    let shape = self.lower_expr(then, span)?;
+    self.body.push(Instr::Else);
+    if self.lower_expr(els, span)? != shape {
+      let msg = "Branches of `if` yield both numbers and tuples, or tuples of different sizes";
+      return Err(Diagnostic::error(span, msg).with_code("codegen"));
+    }
+    self.body.push(Instr::End);
+    if let Shape::Tuple(n) = shape {
+      let float = self.compiler.wasm.float;
+      let ty = self.compiler.wasm.intern_type(FuncType {
+        params: vec![],
+        results: vec![float; n],
+      });
+      self.body[start] = Instr::If(BlockType::Type(ty));
+    }
+    Ok(shape)
+  }
+
+  /// A call of a function of the module, or of `int` and `float`, which
+  /// round towards zero and do nothing with `f64`s.
+  fn lower_call(&mut self, name: &str, args: &[ExprAst], span: Span) -> Result<Shape, Diagnostic> {
+    if self.scope.iter().any(|(local, ..)| local == name) {
+      return Err(unsupported("WebAssembly", "closures", span));
+    }
+    let declared = self.compiler.functions.contains_key(name);
+    let op = match (name, args.len()) {
+      ("float", 1) => None,
+      ("int", 1) => Some(FloatOp::Trunc),
+      ("sqrt", 1) if !declared => Some(FloatOp::Sqrt),
+      ("abs", 1) if !declared => Some(FloatOp::Abs),
+      ("floor", 1) if !declared => Some(FloatOp::Floor),
+      _ => return self.lower_function_call(name, args, span),
+    };
+    self.lower_num(&args[0], span)?;
+    self.body.extend(op.map(Instr::Float));
+    Ok(Shape::Num)
+  }
+
+  fn lower_function_call(
+    &mut self,
+    name: &str,
+    args: &[ExprAst],
+    span: Span,
+  ) -> Result<Shape, Diagnostic> {
+    if !self.compiler.functions.contains_key(name) {
+      let proto = prelude().items.into_iter().find_map(|item| match item {
+        Ast::Proto(proto) if proto.name == name => Some(proto),
+        _ => None,
+      });
+      if let Some(proto) = proto {
+        self.compiler.import(&proto);
+      }
+    }
+    let Some(callee) = self.compiler.functions.get(name).cloned() else {
+      let what = format!("the builtin `{}`", name);
+      return Err(unsupported("WebAssembly", &what, span));
+    };
+    self.call(&callee, args, span)
+  }
+
+  /// Calls `callee` with `args`, widening them to `f64`s and narrowing what
+  /// it returns back if it computes with `f64`s only.
+  fn call(&mut self, callee: &Callee, args: &[ExprAst], span: Span) -> Result<Shape, Diagnostic> {
+    let widen = callee.widen && self.compiler.wasm.float == ValType::F32;
+    for arg in args {
+      self.lower_num(arg, span)?;
+      if widen {
+        self.body.push(Instr::Float(FloatOp::Promote));
+      }
+    }
+    self.body.push(Instr::Call(callee.call));
+    if widen {
+      self.body.push(Instr::Float(FloatOp::Demote));
+    }
+    Ok(match callee.tuple {
+      Some(n) => Shape::Tuple(n),
+      None => Shape::Num,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  fn compile_src(src: &'static str) -> Result<WasmModule, Vec<String>> {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let entry = Entry::of(&module).unwrap();
+    compile(&module, entry, Precision::F64)
+      .map_err(|errors| errors.iter().map(|e| e.to_string()).collect())
+  }
+
+  #[test]
+  fn wasm_module() {
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a);
+      let (lo, hi) = minmax(2, 1) in printd(hi % lo)";
+    let wasm = compile_src(src).unwrap();
+    let expected = "(module
+  (type (;0;) (func (param f64 f64) (result f64 f64)))
+  (type (;1;) (func (result f64 f64)))
+  (type (;2;) (func (result f64)))
+  (type (;3;) (func (param f64) (result f64)))
+  (type (;4;) (func (param f64 f64) (result f64)))
+  (type (;5;) (func))
+  (import \"env\" \"printd\" (func $env.printd (type 3)))
+  (import \"env\" \"fmod\" (func $env.fmod (type 4)))
+  (func $minmax (type 0) (param f64 f64) (result f64 f64)
+    local.get 0
+    local.get 1
+    f64.lt
+    f64.convert_i32_u
+    f64.const 0.0
+    f64.ne
+    if (type 1)
+      local.get 0
+      local.get 1
+    else
+      local.get 1
+      local.get 0
+    end
+  )
+  (func $__anon_expr (type 2) (result f64)
+    (local f64 f64)
+    f64.const 2.0
+    f64.const 1.0
+    call $minmax
+    local.set 1
+    local.set 0
+    local.get 1
+    local.get 0
+    call $env.fmod
+    call $env.printd
+  )
+  (func $_start (type 5)
+    call $__anon_expr
+    drop
+  )
+  (export \"minmax\" (func $minmax))
+  (export \"_start\" (func $_start))
+)
+";
+    assert_eq!(wasm.to_wat(), expected);
+    let bytes = wasm.to_bytes();
+    assert_eq!(bytes[..8], *b"\0asm\x01\0\0\0");
+    let mut sections = vec![];
+    let mut at = 8;
+    while at < bytes.len() {
+      sections.push(bytes[at]);
+      let (mut size, mut shift) = (0, 0);
+      loop {
+        at += 1;
+        size |= ((bytes[at] & 0x7f) as usize) << shift;
+        shift += 7;
+        if bytes[at] & 0x80 == 0 {
+          break;
+        }
+      }
+      at += 1 + size;
+    }
+    assert_eq!(at, bytes.len());
+    assert_eq!(sections, [1, 2, 3, 7, 10]);
+
+    assert_eq!(
+      compile_src("def f(x) x; \"s\"").unwrap_err(),
+      ["1:13: The WebAssembly backend doesn't support strings"]
+    );
+  }
+}
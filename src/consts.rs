@@ -0,0 +1,227 @@
+use crate::eval::{eval_bin, eval_index, eval_unary};
+use crate::parser::{ExprAst, FuncAst};
+use crate::runtime::{call_builtin, is_pure};
+use crate::value::{truthy, Value};
+use std::collections::HashMap;
+use std::io;
+
+/// Evaluates the initializer of a `const`, which may only be built from
+/// literals, earlier constants, builtin operators, conditionals, arrays and
+/// tuples, indexing, and calls to the pure builtins such as `sqrt`, so that
+/// lookup tables cost nothing at runtime. Returns `None` if it is not a
+/// constant expression, or if evaluating it fails.
+pub fn eval_const(expr: &ExprAst, consts: &HashMap<String, Value>) -> Option<Value> {
+  let all = |exprs: &[ExprAst]| -> Option<Vec<Value>> {
+    exprs.iter().map(|expr| eval_const(expr, consts)).collect()
+  };
+  match expr {
+    ExprAst::UnitAst => Some(Value::Unit),
+    ExprAst::NumAst(n) => Some(Value::Num(*n)),
+    ExprAst::IntAst(i) => Some(Value::Int(*i)),
+    ExprAst::StrAst(s) => Some(s.as_str().into()),
+    ExprAst::BoolAst(b) => Some((*b).into()),
+    ExprAst::VarAst(name, _) => consts.get(name).cloned(),
+    ExprAst::UnaryAst(op, operand, _) => eval_unary(*op, eval_const(operand, consts)?).ok(),
+    ExprAst::BinAst(lhs, op, rhs, _) => {
+      eval_bin(*op, eval_const(lhs, consts)?, eval_const(rhs, consts)?).ok()
+    }
+    ExprAst::IfAst { cond, then, els } => match truthy(eval_const(cond, consts)?) {
+      true => eval_const(then, consts),
+      false => eval_const(els, consts),
+    },
+    ExprAst::ArrayAst(elems) => Some(Value::Array(all(elems)?.into())),
+    ExprAst::TupleAst(elems) => Some(Value::Tuple(all(elems)?.into())),
+    ExprAst::IndexAst(array, index) => {
+      eval_index(eval_const(array, consts)?, eval_const(index, consts)?).ok()
+    }
+    ExprAst::ElemAst(tuple, i) => match eval_const(tuple, consts)? {
+      Value::Tuple(elems) => elems.get(*i).cloned(),
+      _ => None,
+    },
+    ExprAst::CallAst(name, args, _) if is_pure(name) => {
+      call_builtin(name, &all(args)?, &mut io::sink(), &mut io::empty())?.ok()
+    }
+    _ => None,
+  }
+}
+
+/// Replaces every use of a constant in `expr` with its value, then folds the
+/// operators, conditionals and indexing left with only literal operands.
+/// Calls aren't folded, since a function may be defined with the name of a
+/// builtin later on. Bindings introduced inside `expr` shadow the constants
+/// of the same name.
+pub fn fold_consts(expr: &mut ExprAst, consts: &HashMap<String, Value>) -> Result<(), String> {
+  fold(expr, consts, &mut vec![])
+}
+
+/// Like [`fold_consts`] on a function body, where parameters shadow
+/// constants too.
+pub fn fold_func_consts(func: &mut FuncAst, consts: &HashMap<String, Value>) -> Result<(), String> {
+  fold(&mut func.body, consts, &mut func.proto.args.clone())
+}
+
+fn fold(
+  expr: &mut ExprAst,
+  consts: &HashMap<String, Value>,
+  shadowed: &mut Vec<String>,
+) -> Result<(), String> {
+  let is_const =
+    |name: &String, shadowed: &Vec<String>| !shadowed.contains(name) && consts.contains_key(name);
+  match expr {
+    ExprAst::VarAst(name, _) if is_const(name, shadowed) => {
+      *expr = consts[name.as_str()].to_ast();
+      return Ok(());
+    }
+    ExprAst::AssignAst(name, ..) if is_const(name, shadowed) => {
+      return Err(format!("Cannot assign to constant `{}`", name));
+    }
+    ExprAst::LetAst(bindings, body) => {
+      let depth = shadowed.len();
+      for (name, _, init) in bindings {
+        fold(init, consts, shadowed)?;
+        shadowed.push(name.clone());
+      }
+      fold(body, consts, shadowed)?;
+      shadowed.truncate(depth);
+      return Ok(());
+    }
+    ExprAst::LetTupleAst(names, init, body) => {
+      fold(init, consts, shadowed)?;
+      let depth = shadowed.len();
+      shadowed.extend(names.iter().map(|(name, _)| name.clone()));
+      fold(body, consts, shadowed)?;
+      shadowed.truncate(depth);
+      return Ok(());
+    }
+    ExprAst::VarInAst(vars, body) => {
+      let depth = shadowed.len();
+      for (name, _, init) in vars {
+        if let Some(init) = init {
+          fold(init, consts, shadowed)?;
+        }
+        shadowed.push(name.clone());
+      }
+      fold(body, consts, shadowed)?;
+      shadowed.truncate(depth);
+      return Ok(());
+    }
+    ExprAst::TryAst(expr, name, handler, _) => {
+      fold(expr, consts, shadowed)?;
+      let depth = shadowed.len();
+      shadowed.extend(name.iter().cloned());
+      fold(handler, consts, shadowed)?;
+      shadowed.truncate(depth);
+      return Ok(());
+    }
+    ExprAst::LambdaAst(args, body) => {
+      let depth = shadowed.len();
+      shadowed.extend(args.iter().map(|(arg, _)| arg.clone()));
+      fold(body, consts, shadowed)?;
+      shadowed.truncate(depth);
+      return Ok(());
+    }
+    _ => (),
+  }
+  for child in expr.children_mut() {
+    fold(child, consts, shadowed)?;
+  }
+  let foldable = matches!(
+    expr,
+    ExprAst::UnaryAst(..)
+      | ExprAst::BinAst(..)
+      | ExprAst::IfAst { .. }
+      | ExprAst::IndexAst(..)
+      | ExprAst::ElemAst(..)
+  );
+  // the constants left are shadowed ones, so none may be looked up
+  if let Some(val) = foldable
+    .then(|| eval_const(expr, &HashMap::new()))
+    .flatten()
+  {
+    *expr = val.to_ast();
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::{Lexer, Span};
+  use crate::parser::{Ast, BinOp};
+  use std::io::Cursor;
+
+  /// The expression `src`, parsed as a top-level expression.
+  fn expr(src: &'static str) -> ExprAst {
+    let Ast::Func(func) = Ast::parse(&mut Lexer::new(Cursor::new(src))).unwrap() else {panic!()};
+    func.body
+  }
+
+  #[test]
+  fn fold_tables() {
+    use ExprAst::*;
+    let src = "def f(i) let T = 1 in SQRT[2] + T + SQRT[i] * -(PAIR.1 - 1)";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let Ast::Func(mut func) = Ast::parse(&mut lexer).unwrap() else {panic!()};
+    let mut consts = HashMap::new();
+    for (name, init) in [
+      ("SQRT", "[sqrt(0), sqrt(1), sqrt(4.0)]"),
+      ("PAIR", "(len(\"ab\"), 3)"),
+    ] {
+      consts.insert(name.to_string(), eval_const(&expr(init), &consts).unwrap());
+    }
+    assert_eq!(consts["PAIR"].to_string(), "(2, 3)");
+    fold_func_consts(&mut func, &consts).unwrap();
+    let LetAst(_, body) = func.body else { panic!() };
+    let BinAst(lhs, BinOp::Add, rhs, _) = body.without_spans() else {panic!()};
+    let index = IndexAst(
+      Box::new(consts["SQRT"].to_ast()),
+      Box::new(VarAst("i".to_string(), Span::default())),
+    );
+    assert_eq!(
+      *rhs,
+      BinAst(
+        Box::new(index),
+        BinOp::Mul,
+        Box::new(IntAst(-2)),
+        Span::default()
+      )
+    );
+    let BinAst(lhs, BinOp::Add, _, _) = *lhs else {panic!()};
+    assert_eq!(*lhs, NumAst(2.0));
+    assert_eq!(eval_const(&expr("[1][1] + rand()"), &consts), None);
+  }
+
+  #[test]
+  fn fold_shadowed() {
+    use ExprAst::*;
+    let src = "def f(x) let y = N in x + y + (let N = 1 in N) + N";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let Ast::Func(mut func) = Ast::parse(&mut lexer).unwrap() else {panic!()};
+    let consts = HashMap::from([
+      ("N".to_string(), Value::Num(4.0)),
+      ("x".to_string(), Value::Int(5)),
+    ]);
+    fold_func_consts(&mut func, &consts).unwrap();
+    let ExprAst::LetAst(bindings, body) = func.body.without_spans() else {panic!()};
+    assert_eq!(bindings[0].2, NumAst(4.0));
+    let BinAst(lhs, BinOp::Add, rhs, _) = *body else {panic!()};
+    assert_eq!(*rhs, NumAst(4.0));
+    let BinAst(lhs, BinOp::Add, rhs, _) = *lhs else {panic!()};
+    assert_eq!(
+      *rhs,
+      LetAst(
+        vec![("N".to_string(), Span::default(), IntAst(1))],
+        Box::new(VarAst("N".to_string(), Span::default()))
+      )
+    );
+    assert_eq!(
+      *lhs,
+      BinAst(
+        Box::new(VarAst("x".to_string(), Span::default())),
+        BinOp::Add,
+        Box::new(VarAst("y".to_string(), Span::default())),
+        Span::default()
+      )
+    );
+  }
+}
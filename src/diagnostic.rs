@@ -0,0 +1,519 @@
+use crate::lexer::Span;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+  Error,
+  Warning,
+  Note,
+}
+
+impl Severity {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Self::Error => "error",
+      Self::Warning => "warning",
+      Self::Note => "note",
+    }
+  }
+}
+
+/// Label - a secondary location a diagnostic points at, such as the
+/// declaration a call disagrees with.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Label {
+  pub span: Span,
+  pub message: String,
+}
+
+/// Suggestion - a replacement for the source at `span` that would fix the
+/// problem.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Suggestion {
+  pub span: Span,
+  pub replacement: String,
+  pub message: String,
+}
+
+/// Diagnostic - an error or a warning about a program, as the lexer, the
+/// parser, the resolver, the type checker, the linter and the backends
+/// report them. The `code` names the kind of problem: `syntax`,
+/// `unresolved`, `arity`, `extern`, `type`, `import`, `entry`, `codegen`,
+/// `link` and `runtime` for errors, and the name of the lint for warnings.
+/// Displayed as
+/// `file:line:col: message`, followed by the notes, one per line; the
+/// `file` is that of an imported module, and the position is left out when
+/// the span is the default one, as for errors found at run time.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+  pub severity: Severity,
+  pub code: Option<&'static str>,
+  pub message: String,
+  pub primary_span: Span,
+  pub labels: Vec<Label>,
+  pub notes: Vec<String>,
+  pub suggestion: Option<Suggestion>,
+  pub file: Option<PathBuf>,
+}
+
+impl Diagnostic {
+  pub fn new(severity: Severity, span: Span, message: impl Into<String>) -> Self {
+    Self {
+      severity,
+      code: None,
+      message: message.into(),
+      primary_span: span,
+      labels: vec![],
+      notes: vec![],
+      suggestion: None,
+      file: None,
+    }
+  }
+
+  pub fn error(span: Span, message: impl Into<String>) -> Self {
+    Self::new(Severity::Error, span, message)
+  }
+
+  pub fn warning(span: Span, message: impl Into<String>) -> Self {
+    Self::new(Severity::Warning, span, message)
+  }
+
+  pub fn with_code(mut self, code: &'static str) -> Self {
+    self.code = Some(code);
+    self
+  }
+
+  pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+    self.labels.push(Label {
+      span,
+      message: message.into(),
+    });
+    self
+  }
+
+  pub fn with_note(mut self, note: impl Into<String>) -> Self {
+    self.notes.push(note.into());
+    self
+  }
+
+  pub fn with_suggestion(
+    mut self,
+    span: Span,
+    replacement: impl Into<String>,
+    message: impl Into<String>,
+  ) -> Self {
+    self.suggestion = Some(Suggestion {
+      span,
+      replacement: replacement.into(),
+      message: message.into(),
+    });
+    self
+  }
+
+  /// Places the diagnostic in the file at `path`, unless it already is in
+  /// one.
+  pub fn in_file(mut self, path: &Path) -> Self {
+    self.file.get_or_insert_with(|| path.to_path_buf());
+    self
+  }
+
+  fn has_span(&self) -> bool {
+    self.primary_span.line > 0
+  }
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(file) = &self.file {
+      write!(f, "{}:", file.display())?;
+    }
+    match self.has_span() {
+      true => write!(f, "{}: {}", self.primary_span, self.message)?,
+      false if self.file.is_some() => write!(f, " {}", self.message)?,
+      false => write!(f, "{}", self.message)?,
+    }
+    for note in &self.notes {
+      write!(f, "\n  = note: {}", note)?;
+    }
+    Ok(())
+  }
+}
+
+impl Diagnostic {
+  /// The diagnostic as a JSON object on a single line, for editors and CI
+  /// tools. The schema is:
+  ///
+  /// ```text
+  /// {
+  ///   "severity": "error" | "warning" | "note",
+  ///   "code": string | null,
+  ///   "message": string,
+  ///   "file": string | null,
+  ///   "span": {"line": number, "col": number} | null,
+  ///   "labels": [{"span": span, "message": string}],
+  ///   "notes": [string],
+  ///   "suggestion": {"span": span, "replacement": string, "message": string} | null
+  /// }
+  /// ```
+  ///
+  /// Lines and columns start at 1. A diagnostic without a `file` is about
+  /// the REPL input, and one without a `span` has no position, as most
+  /// errors found at run time.
+  pub fn to_json(&self) -> String {
+    let span = |span: Span| match span.line {
+      0 => "null".to_string(),
+      _ => format!(r#"{{"line":{},"col":{}}}"#, span.line, span.col),
+    };
+    let labels: Vec<_> = self
+      .labels
+      .iter()
+      .map(|label| {
+        let message = json_str(&label.message);
+        format!(r#"{{"span":{},"message":{}}}"#, span(label.span), message)
+      })
+      .collect();
+    let notes: Vec<_> = self.notes.iter().map(|note| json_str(note)).collect();
+    let suggestion = self.suggestion.as_ref().map_or("null".to_string(), |s| {
+      format!(
+        r#"{{"span":{},"replacement":{},"message":{}}}"#,
+        span(s.span),
+        json_str(&s.replacement),
+        json_str(&s.message)
+      )
+    });
+    format!(
+      r#"{{"severity":{},"code":{},"message":{},"file":{},"span":{},"labels":[{}],"notes":[{}],"suggestion":{}}}"#,
+      json_str(self.severity.as_str()),
+      self.code.map_or("null".to_string(), json_str),
+      json_str(&self.message),
+      self
+        .file
+        .as_ref()
+        .map_or("null".to_string(), |file| json_str(
+          &file.display().to_string()
+        )),
+      span(self.primary_span),
+      labels.join(","),
+      notes.join(","),
+      suggestion
+    )
+  }
+}
+
+/// Quotes `s` as a JSON string.
+fn json_str(s: &str) -> String {
+  let mut out = String::from('"');
+  for c in s.chars() {
+    match c {
+      '"' => out += "\\\"",
+      '\\' => out += "\\\\",
+      '\n' => out += "\\n",
+      '\r' => out += "\\r",
+      '\t' => out += "\\t",
+      c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+/// ErrorFormat - how the driver prints diagnostics: rendered for people to
+/// read, or as JSON lines for tools, following [`Diagnostic::to_json`],
+/// in the file given on the command line unless they are in another one.
+pub enum ErrorFormat {
+  Human(Renderer),
+  Json(Option<PathBuf>),
+}
+
+impl ErrorFormat {
+  /// The diagnostic as printed, ending with a newline.
+  pub fn format(&self, diagnostic: &Diagnostic) -> String {
+    match self {
+      Self::Human(renderer) => renderer.render(diagnostic),
+      Self::Json(None) => format!("{}\n", diagnostic.to_json()),
+      Self::Json(Some(file)) => format!("{}\n", diagnostic.clone().in_file(file).to_json()),
+    }
+  }
+}
+
+/// Whether diagnostics printed to stderr should be colored: only when it is
+/// a terminal, and `NO_COLOR` isn't set.
+pub fn stderr_color() -> bool {
+  io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const CYAN: &str = "\x1b[1;36m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Renderer - prints diagnostics the way rustc does, with the source lines
+/// their spans are on and a caret under the token each span starts:
+///
+/// ```text
+/// error[unresolved]: Unknown variable `lenght`
+///  --> main.kale:1:17
+///   |
+/// 1 | def f(length) lenght * 2
+///   |               ^^^^^^
+/// ```
+///
+/// Labels are underlined with dashes and followed by their message. The
+/// source of a diagnostic in a file is read from that file, the others are
+/// taken to be in the source given to the renderer, if any. A span only
+/// marks where a token starts, so the underline runs to the end of the
+/// token there.
+pub struct Renderer {
+  color: bool,
+  source: Option<(String, String)>, // name and text
+}
+
+impl Renderer {
+  pub fn new(color: bool) -> Self {
+    Self {
+      color,
+      source: None,
+    }
+  }
+
+  /// Makes the diagnostics that aren't in a file be shown in `text`, read
+  /// from `name`.
+  pub fn with_source(mut self, name: impl Into<String>, text: impl Into<String>) -> Self {
+    self.source = Some((name.into(), text.into()));
+    self
+  }
+
+  pub fn render(&self, diagnostic: &Diagnostic) -> String {
+    let mut out = String::new();
+    let sev_color = match diagnostic.severity {
+      Severity::Error => RED,
+      Severity::Warning => YELLOW,
+      Severity::Note => CYAN,
+    };
+    out += &self.paint(sev_color, diagnostic.severity.as_str());
+    if let Some(code) = diagnostic.code {
+      out += &self.paint(sev_color, &format!("[{}]", code));
+    }
+    out += &self.paint(BOLD, &format!(": {}", diagnostic.message));
+    out.push('\n');
+
+    let (name, text) = match &diagnostic.file {
+      Some(file) => (
+        file.display().to_string(),
+        std::fs::read_to_string(file).ok(),
+      ),
+      None => match &self.source {
+        Some((name, text)) => (name.clone(), Some(text.clone())),
+        None => ("<stdin>".to_string(), None),
+      },
+    };
+    let mut marks: Vec<(Span, char, &str)> = vec![];
+    if diagnostic.has_span() {
+      marks.push((diagnostic.primary_span, '^', ""));
+    }
+    marks.extend(
+      diagnostic
+        .labels
+        .iter()
+        .filter(|label| label.span.line > 0)
+        .map(|label| (label.span, '-', label.message.as_str())),
+    );
+    let width = marks
+      .iter()
+      .map(|(span, ..)| digits(span.line))
+      .max()
+      .unwrap_or(0);
+    let gutter = " ".repeat(width);
+    if diagnostic.has_span() {
+      let arrow = self.paint(BLUE, "-->");
+      out += &format!("{}{} {}:{}\n", gutter, arrow, name, diagnostic.primary_span);
+    }
+    let lines: Vec<&str> = text
+      .as_deref()
+      .map_or(vec![], |text| text.lines().collect());
+    let shown = |span: &Span| span.line <= lines.len();
+    if marks.iter().any(|(span, ..)| shown(span)) {
+      let bar = self.paint(BLUE, "|");
+      out += &format!("{} {}\n", gutter, bar);
+      marks.sort_by_key(|(span, ..)| (span.line, span.col));
+      let mut last_line = 0;
+      for (span, marker, label) in marks.iter().filter(|(span, ..)| shown(span)) {
+        let line = lines[span.line - 1];
+        if span.line != last_line {
+          let number = self.paint(BLUE, &format!("{:>width$}", span.line));
+          out += &format!("{} {} {}\n", number, bar, line);
+          last_line = span.line;
+        }
+        let start = (span.col - 1).min(line.len());
+        let indent: String = line[..start]
+          .chars()
+          .map(|c| if c == '\t' { '\t' } else { ' ' })
+          .collect();
+        let underline = marker.to_string().repeat(token_len(&line[start..]));
+        let color = if *marker == '-' { BLUE } else { sev_color };
+        let underline = self.paint(color, &format!("{} {}", underline, label));
+        out += &format!("{} {} {}{}\n", gutter, bar, indent, underline.trim_end());
+      }
+    }
+    for note in &diagnostic.notes {
+      out += &format!("{} {} note: {}\n", gutter, self.paint(BLUE, "="), note);
+    }
+    if let Some(suggestion) = &diagnostic.suggestion {
+      out += &format!(
+        "{} {} help: {}: `{}`\n",
+        gutter,
+        self.paint(BLUE, "="),
+        suggestion.message,
+        suggestion.replacement
+      );
+    }
+    if out.lines().count() > 1 {
+      out.push('\n');
+    }
+    out
+  }
+
+  fn paint(&self, color: &str, text: &str) -> String {
+    match self.color {
+      true => format!("{}{}{}", color, text, RESET),
+      false => text.to_string(),
+    }
+  }
+}
+
+fn digits(n: usize) -> usize {
+  n.to_string().len()
+}
+
+/// The length of the token `rest` starts with: a name or a number, a string
+/// literal, or else a single character.
+fn token_len(rest: &str) -> usize {
+  let mut chars = rest.char_indices();
+  match chars.next() {
+    None => 1,
+    Some((_, c)) if c.is_ascii_alphanumeric() => rest
+      .find(|c: char| !c.is_ascii_alphanumeric() && !matches!(c, '.' | '_'))
+      .unwrap_or(rest.len()),
+    Some((_, '"')) => chars
+      .find(|&(_, c)| c == '"')
+      .map_or(rest.chars().count(), |(i, _)| rest[..=i].chars().count()),
+    Some(_) => 1,
+  }
+}
+
+/// A syntax error at `span`. The lexer and the parser return these as the
+/// error of their results.
+pub fn syntax_error(span: Span, message: impl Into<String>) -> Diagnostic {
+  Diagnostic::error(span, message).with_code("syntax")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn diagnostic_syntax_error() {
+    let span = Span { line: 2, col: 3 };
+    let diagnostic = syntax_error(span, "Expected `)`");
+    assert_eq!(diagnostic.code, Some("syntax"));
+    assert_eq!(diagnostic.severity, Severity::Error);
+    assert_eq!(diagnostic.to_string(), "2:3: Expected `)`");
+  }
+
+  #[test]
+  fn diagnostic_notes() {
+    let span = Span { line: 1, col: 5 };
+    let diagnostic = Diagnostic::error(span, "Unknown variable `lenght`")
+      .with_code("unresolved")
+      .with_label(Span { line: 1, col: 1 }, "declared here")
+      .with_note("names are case-sensitive")
+      .with_suggestion(span, "length", "a parameter has a similar name");
+    assert_eq!(
+      diagnostic.to_string(),
+      "1:5: Unknown variable `lenght`\n  = note: names are case-sensitive"
+    );
+    assert_eq!(diagnostic.labels.len(), 1);
+    assert_eq!(diagnostic.suggestion.unwrap().replacement, "length");
+  }
+
+  #[test]
+  fn diagnostic_render() {
+    let src = "def f(length)\n  lenght * 2;\nf(\"abc\", 1)";
+    let renderer = Renderer::new(false).with_source("f.kale", src);
+    let unresolved = Diagnostic::error(Span { line: 2, col: 3 }, "Unknown variable `lenght`")
+      .with_code("unresolved")
+      .with_suggestion(
+        Span { line: 2, col: 3 },
+        "length",
+        "a parameter has a similar name",
+      );
+    assert_eq!(
+      renderer.render(&unresolved),
+      "error[unresolved]: Unknown variable `lenght`
+ --> f.kale:2:3
+  |
+2 |   lenght * 2;
+  |   ^^^^^^
+  = help: a parameter has a similar name: `length`
+
+"
+    );
+    let arity = Diagnostic::error(Span { line: 3, col: 3 }, "Function `f` expects 1 argument")
+      .with_label(Span { line: 1, col: 5 }, "declared here")
+      .with_note("extra arguments are not ignored");
+    assert_eq!(
+      renderer.render(&arity),
+      "error: Function `f` expects 1 argument
+ --> f.kale:3:3
+  |
+1 | def f(length)
+  |     - declared here
+3 | f(\"abc\", 1)
+  |   ^^^^^
+  = note: extra arguments are not ignored
+
+"
+    );
+    let runtime = Diagnostic::error(Span::default(), "Integer division by zero");
+    assert_eq!(
+      renderer.render(&runtime),
+      "error: Integer division by zero\n"
+    );
+    let colored = Renderer::new(true).render(&runtime);
+    assert_eq!(
+      colored,
+      "\x1b[1;31merror\x1b[0m\x1b[1m: Integer division by zero\x1b[0m\n"
+    );
+  }
+
+  #[test]
+  fn diagnostic_json() {
+    let diagnostic = Diagnostic::warning(Span { line: 1, col: 7 }, "Unused parameter `x`")
+      .with_code("unused-param")
+      .with_label(Span { line: 1, col: 1 }, "in \"f\"")
+      .with_note("prefix it\twith `_`")
+      .in_file(Path::new("lib.kale"));
+    assert_eq!(
+      diagnostic.to_json(),
+      r#"{"severity":"warning","code":"unused-param","message":"Unused parameter `x`","file":"lib.kale","span":{"line":1,"col":7},"labels":[{"span":{"line":1,"col":1},"message":"in \"f\""}],"notes":["prefix it\twith `_`"],"suggestion":null}"#
+    );
+    let runtime = Diagnostic::error(Span::default(), "Integer division by zero").with_suggestion(
+      Span { line: 2, col: 1 },
+      "a\\b",
+      "escape",
+    );
+    assert_eq!(
+      ErrorFormat::Json(None).format(&runtime),
+      r#"{"severity":"error","code":null,"message":"Integer division by zero","file":null,"span":null,"labels":[],"notes":[],"suggestion":{"span":{"line":2,"col":1},"replacement":"a\\b","message":"escape"}}"#.to_string() + "\n"
+    );
+    // in the file given on the command line, unless in another one
+    let format = ErrorFormat::Json(Some(PathBuf::from("main.kale")));
+    assert!(format.format(&runtime).contains(r#""file":"main.kale""#));
+    assert!(format.format(&diagnostic).contains(r#""file":"lib.kale""#));
+  }
+}
@@ -0,0 +1,1354 @@
+use crate::consts::{eval_const, fold_consts, fold_func_consts};
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, Pattern, ProtoAst, StructAst, UnOp};
+use crate::runtime::call_builtin;
+use crate::value::{truthy, Closure, Precision, StructVal, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::rc::Rc;
+
+/// Interpreter - a tree-walking evaluator for parsed items. Values are
+/// doubles, integers, strings, arrays, tuples, structs, closures or
+/// functions (see [`Value`]): `true` and `false` are 1.0 and 0.0, and
+/// conditions follow [`truthy`]. The name of a defined function evaluates to
+/// the function, unless a variable of that name is in scope.
+pub struct Interpreter {
+  funcs: HashMap<String, Rc<FuncAst>>,
+  overloads: Vec<(BinOp, Rc<FuncAst>)>, // of builtin operators, by operand types
+  structs: HashMap<String, Rc<StructAst>>,
+  externs: HashMap<String, ProtoAst>,
+  globals: HashMap<String, Value>,
+  consts: HashMap<String, Value>,
+  out: Box<dyn Write>,     // where `printf` and friends print to
+  input: Box<dyn BufRead>, // where `readd` reads from
+  precision: Precision,
+  failed_at: Option<Span>, // where the last runtime error happened, if known
+  panicked: Option<i64>,   // the code of the `panic` it was raised by, if any
+}
+
+/// Env - the lexical scope of the expression being evaluated. Bindings are
+/// pushed when entering `let`/`var` and popped when leaving it, so lookups
+/// from the back always find the innermost binding of a name.
+struct Env {
+  vars: Vec<Binding>,
+}
+
+/// Unwind - why the evaluation of an expression stopped early: a runtime
+/// error, or a `return` making its way out to the enclosing call.
+#[derive(Debug)]
+enum Unwind {
+  Error(String),
+  Return(Tail),
+}
+
+/// Tail - the outcome of evaluating an expression in tail position: its
+/// value, or the call whose result is its value, still to be made.
+#[derive(Debug)]
+enum Tail {
+  Value(Value),
+  Call(Callee, Vec<Value>),
+}
+
+#[derive(Debug)]
+enum Callee {
+  Func(Rc<FuncAst>),
+  Closure(Rc<Closure>),
+}
+
+impl From<String> for Unwind {
+  fn from(e: String) -> Self {
+    Self::Error(e)
+  }
+}
+
+struct Binding {
+  name: String,
+  val: Value,
+  mutable: bool, // `var` bindings and parameters, but not `let` bindings
+}
+
+impl Env {
+  fn new() -> Self {
+    Self { vars: vec![] }
+  }
+
+  fn lookup(&self, name: &str) -> Option<Value> {
+    self
+      .vars
+      .iter()
+      .rev()
+      .find(|b| b.name == name)
+      .map(|b| b.val.clone())
+  }
+
+  fn lookup_mut(&mut self, name: &str) -> Option<&mut Binding> {
+    self.vars.iter_mut().rev().find(|b| b.name == name)
+  }
+}
+
+impl Interpreter {
+  pub fn new() -> Self {
+    Self::with_output(io::stdout())
+  }
+
+  pub fn with_output(out: impl Write + 'static) -> Self {
+    Self {
+      funcs: HashMap::new(),
+      overloads: vec![],
+      structs: HashMap::new(),
+      externs: HashMap::new(),
+      globals: HashMap::new(),
+      consts: HashMap::new(),
+      out: Box::new(out),
+      input: Box::new(BufReader::new(io::stdin())),
+      precision: Precision::F64,
+      failed_at: None,
+      panicked: None,
+    }
+  }
+
+  /// Makes `readd` read from `input` instead of stdin, for embedders.
+  pub fn set_input(&mut self, input: impl BufRead + 'static) {
+    self.input = Box::new(input);
+  }
+
+  /// Computes with doubles of the given precision from now on. Functions
+  /// defined before keep the literals they were defined with.
+  pub fn set_precision(&mut self, precision: Precision) {
+    self.precision = precision;
+  }
+
+  pub fn precision(&self) -> Precision {
+    self.precision
+  }
+
+  /// Where the error that the last item failed with happened, for the
+  /// errors that know: those of `assert`.
+  pub fn failed_at(&self) -> Option<Span> {
+    self.failed_at
+  }
+
+  /// Runs one top-level item. Definitions and declarations are recorded and
+  /// yield `None`; top-level expressions are evaluated right away. Constants
+  /// defined so far are folded into the item before anything else, after
+  /// rounding its literals to the precision in use.
+  pub fn run(&mut self, mut ast: Ast) -> Result<Option<Value>, String> {
+    self.failed_at = None;
+    self.panicked = None;
+    if self.precision != Precision::F64 {
+      self.round_literals(&mut ast);
+    }
+    match ast {
+      Ast::Expr(mut expr) => {
+        fold_consts(&mut expr, &self.consts)?;
+        self.eval_body(&expr, &mut Env::new()).map(Some)
+      }
+      Ast::Proto(proto) => {
+        self.externs.insert(proto.name.clone(), proto);
+        Ok(None)
+      }
+      Ast::Func(mut func) if func.proto.name.is_empty() => {
+        fold_func_consts(&mut func, &self.consts)?;
+        self.eval_body(&func.body, &mut Env::new()).map(Some)
+      }
+      Ast::Func(mut func) => {
+        fold_func_consts(&mut func, &self.consts)?;
+        match BinOp::overloaded_by(&func.proto.name) {
+          Some(op) => {
+            // replaces the overload for the same operand types
+            let same = |prev: &FuncAst| prev.proto.arg_tys == func.proto.arg_tys;
+            self.overloads.retain(|(o, prev)| *o != op || !same(prev));
+            self.overloads.push((op, Rc::new(func)));
+          }
+          None => {
+            self.funcs.insert(func.proto.name.clone(), Rc::new(func));
+          }
+        }
+        Ok(None)
+      }
+      Ast::Global(vars, _) => {
+        for (name, _, init) in vars {
+          let val = match init {
+            Some(mut init) => {
+              fold_consts(&mut init, &self.consts)?;
+              self.eval_body(&init, &mut Env::new())?
+            }
+            None => Value::Num(0.0),
+          };
+          self.globals.insert(name, val);
+        }
+        Ok(None)
+      }
+      Ast::Const(name, _, init, _) => {
+        let val = eval_const(&init, &self.consts).ok_or(format!(
+          "Initializer of const `{}` is not a constant expression",
+          name
+        ))?;
+        self.consts.insert(name, val);
+        Ok(None)
+      }
+      Ast::Struct(decl) => {
+        self.structs.insert(decl.name.clone(), Rc::new(decl));
+        Ok(None)
+      }
+      // the loader splices imported files into the module
+      Ast::Import(path, ..) => Err(format!("Unresolved import of `{}`", path)),
+    }
+  }
+
+  fn round_literals(&self, ast: &mut Ast) {
+    fn round(expr: &mut ExprAst, precision: Precision) {
+      if let ExprAst::NumAst(n) = expr {
+        *n = precision.round(*n);
+      }
+      for child in expr.children_mut() {
+        round(child, precision);
+      }
+    }
+    match ast {
+      Ast::Expr(expr) | Ast::Const(_, _, expr, _) => round(expr, self.precision),
+      Ast::Func(func) => round(&mut func.body, self.precision),
+      Ast::Global(vars, _) => vars
+        .iter_mut()
+        .filter_map(|(.., init)| init.as_mut())
+        .for_each(|init| round(init, self.precision)),
+      Ast::Proto(_) | Ast::Struct(_) | Ast::Import(..) => (),
+    }
+  }
+
+  /// Runs all items of a module in order, returning the values of its
+  /// top-level expressions.
+  pub fn run_module(&mut self, module: ModuleAst) -> Result<Vec<Value>, String> {
+    let mut vals = vec![];
+    for item in module.items {
+      vals.extend(self.run(item)?);
+    }
+    Ok(vals)
+  }
+
+  /// Evaluates the body of a function, where a `return` ends the
+  /// evaluation with its value. Top-level items count as function bodies.
+  fn eval_body(&mut self, body: &ExprAst, env: &mut Env) -> Result<Value, String> {
+    let val = match self.eval_tail(body, env) {
+      Ok(tail) | Err(Unwind::Return(tail)) => self.finish(tail)?,
+      Err(Unwind::Error(e)) => return Err(e),
+    };
+    Ok(self.precision.round_value(val))
+  }
+
+  /// Evaluates `expr`, rounding a resulting double to the precision in use,
+  /// so every operation sees operands of that precision.
+  fn eval(&mut self, expr: &ExprAst, env: &mut Env) -> Result<Value, Unwind> {
+    let val = self.eval_expr(expr, env)?;
+    Ok(self.precision.round_value(val))
+  }
+
+  fn eval_expr(&mut self, expr: &ExprAst, env: &mut Env) -> Result<Value, Unwind> {
+    match expr {
+      ExprAst::NumAst(n) => Ok(Value::Num(*n)),
+      ExprAst::IntAst(i) => Ok(Value::Int(*i)),
+      ExprAst::StrAst(s) => Ok(s.as_str().into()),
+      ExprAst::BoolAst(b) => Ok((*b).into()),
+      ExprAst::UnitAst => Ok(Value::Unit),
+      ExprAst::VarAst(name, _) => Ok(
+        env
+          .lookup(name)
+          .or_else(|| self.globals.get(name).cloned())
+          .or_else(|| self.funcs.get(name).cloned().map(Value::Func))
+          .ok_or(format!("Unknown variable name `{}`", name))?,
+      ),
+      ExprAst::UnaryAst(op, operand, _) => {
+        let val = self.eval(operand, env)?;
+        Ok(eval_unary(*op, val)?)
+      }
+      ExprAst::BinAst(lhs, op @ (BinOp::And | BinOp::Or), rhs, _) => {
+        let lhs = truthy(self.eval(lhs, env)?);
+        match (op, lhs) {
+          (BinOp::And, false) => Ok(false.into()),
+          (BinOp::Or, true) => Ok(true.into()),
+          _ => Ok(truthy(self.eval(rhs, env)?).into()),
+        }
+      }
+      ExprAst::BinAst(lhs, op, rhs, _) => {
+        let lhs = self.eval(lhs, env)?;
+        let rhs = self.eval(rhs, env)?;
+        match self.overload(*op, &lhs, &rhs)? {
+          Some(func) => Ok(self.call_tail(Callee::Func(func), vec![lhs, rhs])?),
+          None => Ok(eval_bin(*op, lhs, rhs)?),
+        }
+      }
+      ExprAst::CallAst(..)
+      | ExprAst::IfAst { .. }
+      | ExprAst::BlockAst(_)
+      | ExprAst::SeqAst(_)
+      | ExprAst::LetAst(..)
+      | ExprAst::LetTupleAst(..)
+      | ExprAst::VarInAst(..)
+      | ExprAst::MatchAst(..)
+      | ExprAst::ReturnAst(..)
+      | ExprAst::TryAst(..) => {
+        let tail = self.eval_tail(expr, env)?;
+        Ok(self.finish(tail)?)
+      }
+      ExprAst::LambdaAst(args, body) => {
+        let mut used = HashSet::new();
+        mentioned_names(body, &mut used);
+        let captured = env.vars.iter().filter(|b| used.contains(&b.name));
+        Ok(Value::Closure(Rc::new(Closure {
+          args: args.iter().map(|(arg, _)| arg.clone()).collect(),
+          body: body.as_ref().clone(),
+          captured: captured.map(|b| (b.name.clone(), b.val.clone())).collect(),
+        })))
+      }
+      ExprAst::ArrayAst(elems) => {
+        let elems = elems
+          .iter()
+          .map(|elem| self.eval(elem, env))
+          .collect::<Result<_, _>>()?;
+        Ok(Value::Array(elems))
+      }
+      ExprAst::TupleAst(elems) => {
+        let elems = elems
+          .iter()
+          .map(|elem| self.eval(elem, env))
+          .collect::<Result<_, _>>()?;
+        Ok(Value::Tuple(elems))
+      }
+      ExprAst::ElemAst(tuple, i) => match self.eval(tuple, env)? {
+        Value::Tuple(elems) => Ok(elems.get(*i).cloned().ok_or(format!(
+          "Tuple index {} out of range for tuple of length {}",
+          i,
+          elems.len()
+        ))?),
+        val => Err(format!("Cannot take element {} of {}", i, val.kind()).into()),
+      },
+      ExprAst::IndexAst(array, index) => {
+        let array = self.eval(array, env)?;
+        let index = self.eval(index, env)?;
+        Ok(eval_index(array, index)?)
+      }
+      ExprAst::FieldAst(expr, field) => match self.eval(expr, env)? {
+        Value::Struct(s) => Ok(
+          s.field(field)
+            .cloned()
+            .ok_or(format!("No field `{}` on struct `{}`", field, s.decl.name))?,
+        ),
+        val => Err(format!("Cannot access field `{}` of {}", field, val.kind()).into()),
+      },
+      ExprAst::AssignAst(name, val, _) => {
+        let val = self.eval(val, env)?;
+        let slot = match env.lookup_mut(name) {
+          Some(b) if b.mutable => &mut b.val,
+          Some(_) => return Err(format!("Cannot assign to immutable binding `{}`", name).into()),
+          None => self
+            .globals
+            .get_mut(name)
+            .ok_or(format!("Unknown variable name `{}`", name))?,
+        };
+        *slot = val.clone();
+        Ok(val)
+      }
+      ExprAst::FuncRefAst(name, _) => match self.funcs.get(name) {
+        Some(func) => Ok(Value::Func(func.clone())),
+        None if self.externs.contains_key(name) => {
+          Err(format!("No implementation for extern `{}`", name).into())
+        }
+        None => Err(format!("Unknown function referenced `{}`", name).into()),
+      },
+    }
+  }
+
+  /// Evaluates `expr` in tail position: a call to a function or closure
+  /// that would produce the value of `expr` is returned instead of made, so
+  /// the caller can make it after leaving the current frame.
+  fn eval_tail(&mut self, expr: &ExprAst, env: &mut Env) -> Result<Tail, Unwind> {
+    match expr {
+      ExprAst::CallAst(name, args, span) => {
+        let callee = env.lookup(name).or_else(|| self.globals.get(name).cloned());
+        let args = args
+          .iter()
+          .map(|arg| self.eval(arg, env))
+          .collect::<Result<Vec<_>, _>>()?;
+        match callee {
+          // a variable holding a function value shadows the function of
+          // that name
+          Some(Value::Closure(closure)) => Ok(Tail::Call(Callee::Closure(closure), args)),
+          Some(Value::Func(func)) => Ok(Tail::Call(Callee::Func(func), args)),
+          _ if self.structs.contains_key(name) => Ok(Tail::Value(self.call(name, args)?)),
+          _ if name == "assert" && !self.funcs.contains_key(name) => {
+            let res = assert(args);
+            if res.is_err() {
+              self.failed_at = Some(*span);
+            }
+            Ok(Tail::Value(res?))
+          }
+          _ => match self.funcs.get(name) {
+            Some(func) => Ok(Tail::Call(Callee::Func(func.clone()), args)),
+            None => Ok(Tail::Value(self.call(name, args)?)),
+          },
+        }
+      }
+      ExprAst::IfAst { cond, then, els } => match truthy(self.eval(cond, env)?) {
+        true => self.eval_tail(then, env),
+        false => self.eval_tail(els, env),
+      },
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        let Some((last, init)) = exprs.split_last() else {
+          return Ok(Tail::Value(Value::Num(0.0)));
+        };
+        for expr in init {
+          self.eval(expr, env)?;
+        }
+        self.eval_tail(last, env)
+      }
+      ExprAst::LetAst(bindings, body) => {
+        let bindings = bindings
+          .iter()
+          .map(|(name, _, init)| (name, Some(init), false));
+        self.eval_scoped(bindings, body, env)
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let elems = match self.eval(init, env)? {
+          Value::Tuple(elems) if elems.len() == names.len() => elems,
+          Value::Tuple(elems) => {
+            return Err(
+              format!(
+                "Cannot destructure a tuple of length {} into {} bindings",
+                elems.len(),
+                names.len()
+              )
+              .into(),
+            )
+          }
+          val => return Err(format!("Cannot destructure {} as a tuple", val.kind()).into()),
+        };
+        let depth = env.vars.len();
+        for ((name, _), val) in names.iter().zip(elems.iter()) {
+          env.vars.push(Binding {
+            name: name.clone(),
+            val: val.clone(),
+            mutable: false,
+          });
+        }
+        let res = self.eval_tail(body, env);
+        env.vars.truncate(depth);
+        res
+      }
+      ExprAst::VarInAst(vars, body) => {
+        let bindings = vars
+          .iter()
+          .map(|(name, _, init)| (name, init.as_ref(), true));
+        self.eval_scoped(bindings, body, env)
+      }
+      ExprAst::MatchAst(expr, arms, _) => {
+        let val = self.eval(expr, env)?;
+        for (pat, body) in arms {
+          if self.matches(pat, &val, env)? {
+            return self.eval_tail(body, env);
+          }
+        }
+        Err(format!("No arm of `match` matches {}", val).into())
+      }
+      ExprAst::ReturnAst(val, _) => Err(Unwind::Return(self.eval_tail(val, env)?)),
+      // not a tail position: the call has to be made for its error to be
+      // caught here, as has the one a `return` in it leaves pending
+      ExprAst::TryAst(expr, name, handler, _) => match self.eval(expr, env) {
+        Err(Unwind::Return(tail @ Tail::Call(..))) => match self.finish(tail) {
+          Ok(val) => Err(Unwind::Return(Tail::Value(val))),
+          Err(e) => self.catch(e, name.as_ref(), handler, env),
+        },
+        Err(Unwind::Error(e)) => self.catch(e, name.as_ref(), handler, env),
+        res => res.map(Tail::Value),
+      },
+      _ => self.eval(expr, env).map(Tail::Value),
+    }
+  }
+
+  /// Evaluates `handler` in tail position, with the error `e` bound to `name`
+  /// if given, as the `catch` of a `try` does. The error is the tuple
+  /// `(code, message)`, where `code` is that of the `panic` raising it, or
+  /// -1 for the runtime's own errors.
+  fn catch(
+    &mut self,
+    e: String,
+    name: Option<&String>,
+    handler: &ExprAst,
+    env: &mut Env,
+  ) -> Result<Tail, Unwind> {
+    self.failed_at = None;
+    let code = self.panicked.take().unwrap_or(-1);
+    let depth = env.vars.len();
+    if let Some(name) = name {
+      env.vars.push(Binding {
+        name: name.clone(),
+        val: Value::Tuple(Rc::new([Value::Int(code), e.as_str().into()])),
+        mutable: false,
+      });
+    }
+    let res = self.eval_tail(handler, env);
+    env.vars.truncate(depth);
+    res
+  }
+
+  fn matches(&mut self, pat: &Pattern, val: &Value, env: &mut Env) -> Result<bool, Unwind> {
+    let mut test = |op, lit| {
+      let lit = self.eval(lit, env)?;
+      Ok(truthy(eval_bin(op, val.clone(), lit)?))
+    };
+    match pat {
+      Pattern::Wild => Ok(true),
+      Pattern::Lit(lit) => test(BinOp::Eq, lit),
+      Pattern::Range(lo, hi) => Ok(test(BinOp::Ge, lo)? && test(BinOp::Lt, hi)?),
+    }
+  }
+
+  /// Evaluates `body` in tail position with `bindings` in scope, each one
+  /// initialized in order so it sees the ones before it. Uninitialized
+  /// bindings are 0.0.
+  fn eval_scoped<'a>(
+    &mut self,
+    bindings: impl Iterator<Item = (&'a String, Option<&'a ExprAst>, bool)>,
+    body: &ExprAst,
+    env: &mut Env,
+  ) -> Result<Tail, Unwind> {
+    let depth = env.vars.len();
+    let mut res = Ok(());
+    for (name, init, mutable) in bindings {
+      match init.map_or(Ok(Value::Num(0.0)), |init| self.eval(init, env)) {
+        Ok(val) => env.vars.push(Binding {
+          name: name.clone(),
+          val,
+          mutable,
+        }),
+        Err(e) => {
+          res = Err(e);
+          break;
+        }
+      }
+    }
+    let res = res.and_then(|_| self.eval_tail(body, env));
+    env.vars.truncate(depth);
+    res
+  }
+
+  /// Makes the call left pending by [`Interpreter::eval_tail`], if any.
+  fn finish(&mut self, tail: Tail) -> Result<Value, String> {
+    match tail {
+      Tail::Value(val) => Ok(val),
+      Tail::Call(callee, args) => self.call_tail(callee, args),
+    }
+  }
+
+  /// Calls `callee`, and then in turn each function or closure the previous
+  /// one tail-called, all from the same Rust frame. Tail-recursive programs
+  /// thus run in constant stack space however deep they recurse.
+  fn call_tail(&mut self, mut callee: Callee, mut args: Vec<Value>) -> Result<Value, String> {
+    // closures aren't annotated
+    let ret = match &callee {
+      Callee::Func(func) => func.proto.ret_ty.clone(),
+      Callee::Closure(_) => None,
+    };
+    loop {
+      let mut env = callee.enter(args)?;
+      let body = match &callee {
+        Callee::Func(func) => &func.body,
+        Callee::Closure(closure) => &closure.body,
+      };
+      match self.eval_tail(body, &mut env) {
+        Ok(Tail::Value(val)) | Err(Unwind::Return(Tail::Value(val))) => {
+          return Ok(coerce(val, ret.as_deref()));
+        }
+        Ok(Tail::Call(next, next_args)) | Err(Unwind::Return(Tail::Call(next, next_args))) => {
+          callee = next;
+          args = next_args;
+        }
+        Err(Unwind::Error(e)) => return Err(e),
+      }
+    }
+  }
+
+  /// The user-defined overload of `op` that applies to `lhs` and `rhs`, if
+  /// any. Operators on two numbers are always the builtin ones.
+  fn overload(&self, op: BinOp, lhs: &Value, rhs: &Value) -> Result<Option<Rc<FuncAst>>, String> {
+    if lhs.as_f64().is_some() && rhs.as_f64().is_some() {
+      return Ok(None);
+    }
+    let mut found = self.overloads.iter().filter(|(o, func)| {
+      let tys = func.proto.arg_tys.iter();
+      *o == op
+        && tys
+          .zip([lhs, rhs])
+          .all(|(ty, val)| has_type(val, ty.as_deref()))
+    });
+    match (found.next(), found.next()) {
+      (Some((_, a)), Some((_, b))) => Err(format!(
+        "Ambiguous operator `{}` for {} and {}: overloads for {} and {} both apply",
+        op.as_str(),
+        type_of(lhs),
+        type_of(rhs),
+        operand_types(a),
+        operand_types(b)
+      )),
+      (found, _) => Ok(found.map(|(_, func)| func.clone())),
+    }
+  }
+
+  /// Calls a defined function in a fresh scope holding only its arguments.
+  /// Calling a struct constructs an instance of it. Builtins are only called
+  /// when no function or struct of that name is defined.
+  fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+    if let Some(decl) = self.structs.get(name) {
+      if decl.fields.len() != args.len() {
+        return Err(format!(
+          "Incorrect # arguments passed to `{}`: expected {}, got {}",
+          name,
+          decl.fields.len(),
+          args.len()
+        ));
+      }
+      let decl = decl.clone();
+      return Ok(Value::Struct(Rc::new(StructVal { decl, fields: args })));
+    }
+    let Some(func) = self.funcs.get(name).cloned() else {
+      let symbol = self.externs.get(name).map_or(name, ProtoAst::symbol);
+      if let Some(res) = call_builtin(symbol, &args, &mut self.out, &mut self.input) {
+        if let ("panic", [Value::Int(code)]) = (symbol, &args[..]) {
+          self.panicked = Some(*code);
+        }
+        return res;
+      }
+      return match self.externs.contains_key(name) {
+        true => Err(format!("No implementation for extern `{}`", name)),
+        false => Err(format!("Unknown function referenced `{}`", name)),
+      };
+    };
+    self.call_tail(Callee::Func(func), args)
+  }
+}
+
+impl Callee {
+  /// The scope of a call: a function sees only its arguments, and a closure
+  /// its captured bindings, which are immutable, and then its arguments.
+  fn enter(&self, args: Vec<Value>) -> Result<Env, String> {
+    let (params, tys, captured) = match self {
+      Self::Func(func) => (&func.proto.args, &func.proto.arg_tys[..], &[][..]),
+      Self::Closure(closure) => (&closure.args, &[][..], &closure.captured[..]),
+    };
+    if params.len() != args.len() {
+      let callee = match self {
+        Self::Func(func) => format!("`{}`", func.proto.name),
+        Self::Closure(_) => "closure".to_string(),
+      };
+      return Err(format!(
+        "Incorrect # arguments passed to {}: expected {}, got {}",
+        callee,
+        params.len(),
+        args.len()
+      ));
+    }
+    let captured = captured.iter().map(|(name, val)| Binding {
+      name: name.clone(),
+      val: val.clone(),
+      mutable: false,
+    });
+    let args = params
+      .iter()
+      .zip(args)
+      .enumerate()
+      .map(|(i, (name, val))| Binding {
+        name: name.clone(),
+        val: coerce(val, tys.get(i).and_then(Option::as_deref)),
+        mutable: true,
+      });
+    Ok(Env {
+      vars: captured.chain(args).collect(),
+    })
+  }
+}
+
+/// `assert(cond)` yields unit when `cond` holds, and fails otherwise.
+fn assert(args: Vec<Value>) -> Result<Value, String> {
+  match <[Value; 1]>::try_from(args) {
+    Ok([cond]) => match truthy(cond) {
+      true => Ok(Value::Unit),
+      false => Err("Assertion failed".to_string()),
+    },
+    Err(args) => Err(format!(
+      "Function `assert` expects 1 argument, found {}",
+      args.len()
+    )),
+  }
+}
+
+/// Collects the names of the variables and functions `expr` refers to,
+/// whether or not they are bound inside it.
+fn mentioned_names(expr: &ExprAst, names: &mut HashSet<String>) {
+  match expr {
+    ExprAst::VarAst(name, _) | ExprAst::AssignAst(name, ..) | ExprAst::CallAst(name, _, _) => {
+      names.insert(name.clone());
+    }
+    _ => (),
+  }
+  for child in expr.children() {
+    mentioned_names(child, names);
+  }
+}
+
+/// `array[index]`, where the index must be an int within the bounds of the
+/// array.
+pub fn eval_index(array: Value, index: Value) -> Result<Value, String> {
+  let Value::Array(elems) = array else {
+    return Err(format!("Cannot index into {}", array.kind()));
+  };
+  let Value::Int(i) = index else {
+    return Err(format!(
+      "Array index must be an int, found {}",
+      index.kind()
+    ));
+  };
+  match usize::try_from(i).ok().and_then(|i| elems.get(i)) {
+    Some(elem) => Ok(elem.clone()),
+    None => Err(format!(
+      "Index {} out of bounds for array of length {}",
+      i,
+      elems.len()
+    )),
+  }
+}
+
+/// Whether `val` has the annotated type `ty`, where no annotation means
+/// double. Booleans are doubles at runtime.
+fn has_type(val: &Value, ty: Option<&str>) -> bool {
+  match (ty.unwrap_or("double"), val) {
+    ("double", Value::Num(_) | Value::Int(_))
+    | ("int", Value::Int(_))
+    | ("bool", Value::Num(_)) => true,
+    ("str", Value::Str(_)) | ("array", Value::Array(_)) | ("unit", Value::Unit) => true,
+    ("func", Value::Closure(_) | Value::Func(_)) => true,
+    (ty, Value::Tuple(_)) => ty.starts_with('('),
+    (ty, Value::Struct(s)) => s.decl.name == ty,
+    _ => false,
+  }
+}
+
+/// `val` as a value of the type `ty` of a parameter or a return, as the
+/// checker annotates it: an int passed or returned as a double is one, as
+/// it is in native code.
+fn coerce(val: Value, ty: Option<&str>) -> Value {
+  match (ty, val) {
+    (Some("double"), Value::Int(i)) => Value::Num(i as f64),
+    (_, val) => val,
+  }
+}
+
+/// The name of the type of `val`, for error messages.
+fn type_of(val: &Value) -> &str {
+  match val {
+    Value::Struct(s) => &s.decl.name,
+    val => val.kind(),
+  }
+}
+
+/// The operand types of an overload, like `(Point, double)`.
+fn operand_types(func: &FuncAst) -> String {
+  let tys = func.proto.arg_tys.iter();
+  let tys: Vec<_> = tys.map(|ty| ty.as_deref().unwrap_or("double")).collect();
+  format!("({})", tys.join(", "))
+}
+
+/// Applies a builtin prefix operator.
+pub fn eval_unary(op: UnOp, val: Value) -> Result<Value, String> {
+  match (op, val) {
+    (UnOp::Not, val) => Ok((!truthy(val)).into()),
+    (UnOp::Neg, Value::Num(n)) => Ok(Value::Num(-n)),
+    (UnOp::Neg, Value::Int(i)) => i
+      .checked_neg()
+      .map(Value::Int)
+      .ok_or(format!("Integer overflow in `-{}`", i)),
+    (UnOp::Neg, val) => Err(format!("Operator `-` cannot be applied to {}", val.kind())),
+  }
+}
+
+/// Applies a builtin binary operator; comparisons yield 1.0 or 0.0. An
+/// integer operand is promoted to double unless both operands are integers.
+pub fn eval_bin(op: BinOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+  let (lhs, rhs) = match (lhs, rhs) {
+    (Value::Int(lhs), Value::Int(rhs)) => return eval_int_bin(op, lhs, rhs),
+    (Value::Str(lhs), Value::Str(rhs)) => return eval_str_bin(op, &lhs, &rhs),
+    (lhs, rhs) if op.is_bitwise() => {
+      return Err(format!(
+        "Operator `{}` cannot be applied to {} and {}",
+        op.as_str(),
+        lhs.kind(),
+        rhs.kind()
+      ))
+    }
+    (lhs, rhs) => match (lhs.as_f64(), rhs.as_f64()) {
+      (Some(l), Some(r)) => (l, r),
+      _ => {
+        return Err(format!(
+          "Operator `{}` cannot be applied to {} and {}",
+          op.as_str(),
+          lhs.kind(),
+          rhs.kind()
+        ))
+      }
+    },
+  };
+  let val = match op {
+    BinOp::Add => Value::Num(lhs + rhs),
+    BinOp::Sub => Value::Num(lhs - rhs),
+    BinOp::Mul => Value::Num(lhs * rhs),
+    BinOp::Div => Value::Num(lhs / rhs),
+    BinOp::Rem => Value::Num(lhs % rhs), // same as fmod
+    BinOp::Lt => (lhs < rhs).into(),
+    BinOp::Gt => (lhs > rhs).into(),
+    BinOp::Le => (lhs <= rhs).into(),
+    BinOp::Ge => (lhs >= rhs).into(),
+    BinOp::Eq => (lhs == rhs).into(),
+    BinOp::Ne => (lhs != rhs).into(),
+    BinOp::And => (lhs != 0.0 && rhs != 0.0).into(),
+    BinOp::Or => (lhs != 0.0 || rhs != 0.0).into(),
+    BinOp::BitAnd | BinOp::BitOr | BinOp::Xor | BinOp::Shl | BinOp::Shr => unreachable!(),
+  };
+  Ok(val)
+}
+
+/// Integer arithmetic is checked: overflow and division by zero are errors.
+/// Division truncates towards zero and `%` takes the sign of the dividend.
+/// Shifts by less than 0 or more than 63 bits are errors too, while the
+/// bits shifted out by `<<` are lost.
+pub fn eval_int_bin(op: BinOp, lhs: i64, rhs: i64) -> Result<Value, String> {
+  let res = match op {
+    BinOp::Add => lhs.checked_add(rhs),
+    BinOp::Sub => lhs.checked_sub(rhs),
+    BinOp::Mul => lhs.checked_mul(rhs),
+    BinOp::Div | BinOp::Rem if rhs == 0 => return Err("Integer division by zero".to_string()),
+    BinOp::Div => lhs.checked_div(rhs),
+    BinOp::Rem => lhs.checked_rem(rhs),
+    BinOp::Lt => return Ok((lhs < rhs).into()),
+    BinOp::Gt => return Ok((lhs > rhs).into()),
+    BinOp::Le => return Ok((lhs <= rhs).into()),
+    BinOp::Ge => return Ok((lhs >= rhs).into()),
+    BinOp::Eq => return Ok((lhs == rhs).into()),
+    BinOp::Ne => return Ok((lhs != rhs).into()),
+    BinOp::And => return Ok((lhs != 0 && rhs != 0).into()),
+    BinOp::Or => return Ok((lhs != 0 || rhs != 0).into()),
+    BinOp::BitAnd => Some(lhs & rhs),
+    BinOp::BitOr => Some(lhs | rhs),
+    BinOp::Xor => Some(lhs ^ rhs),
+    BinOp::Shl | BinOp::Shr if !(0..64).contains(&rhs) => {
+      return Err(format!("Cannot shift by {} bits", rhs));
+    }
+    BinOp::Shl => Some(lhs << rhs),
+    BinOp::Shr => Some(lhs >> rhs),
+  };
+  res.map(Value::Int).ok_or(format!(
+    "Integer overflow in `{} {} {}`",
+    lhs,
+    op.as_str(),
+    rhs
+  ))
+}
+
+/// `+` concatenates strings, and comparisons order them lexicographically.
+fn eval_str_bin(op: BinOp, lhs: &str, rhs: &str) -> Result<Value, String> {
+  match op {
+    BinOp::Add => Ok([lhs, rhs].concat().as_str().into()),
+    BinOp::Lt => Ok((lhs < rhs).into()),
+    BinOp::Gt => Ok((lhs > rhs).into()),
+    BinOp::Le => Ok((lhs <= rhs).into()),
+    BinOp::Ge => Ok((lhs >= rhs).into()),
+    BinOp::Eq => Ok((lhs == rhs).into()),
+    BinOp::Ne => Ok((lhs != rhs).into()),
+    BinOp::And => Ok((!lhs.is_empty() && !rhs.is_empty()).into()),
+    BinOp::Or => Ok((!lhs.is_empty() || !rhs.is_empty()).into()),
+    _ => Err(format!(
+      "Operator `{}` cannot be applied to str and str",
+      op.as_str()
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  fn run(src: &'static str) -> Vec<f64> {
+    let vals = run_values(src).into_iter();
+    vals.map(|val| val.as_f64().unwrap()).collect()
+  }
+
+  fn run_values(src: &'static str) -> Vec<Value> {
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let module = ModuleAst::parse(&mut lexer).unwrap();
+    Interpreter::new().run_module(module).unwrap()
+  }
+
+  fn run_err(src: &'static str) -> String {
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let module = ModuleAst::parse(&mut lexer).unwrap();
+    Interpreter::new().run_module(module).unwrap_err()
+  }
+
+  #[test]
+  fn eval_function_call() {
+    let src = "def add(a, b) a + b; add(1, 2) * 3; 4 < 3 ? 1 : 2";
+    assert_eq!(run(src), vec![9.0, 2.0]);
+  }
+
+  #[test]
+  fn eval_if() {
+    let src = "def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2); fib(10)";
+    assert_eq!(run(src), vec![55.0]);
+  }
+
+  #[test]
+  fn eval_binary_op() {
+    let src = "def binary ~ 5 (a b) if a then 1 else if b then 1 else 0; 0 ~ 1; 0 ~ 1 < 0";
+    assert_eq!(run(src), vec![1.0, 0.0]);
+  }
+
+  #[test]
+  fn eval_comparisons() {
+    let src = "1 > 2; 2 >= 2; 3 <= 2; 1 + 1 == 2; 1 != 1; 1 < 2 == 2 > 1";
+    assert_eq!(run(src), vec![0.0, 1.0, 0.0, 1.0, 0.0, 1.0]);
+  }
+
+  #[test]
+  fn eval_f32() {
+    use Value::*;
+    let src =
+      "const TENTH = 0.1; TENTH + 0.2; 16777216.0 + 1; 1.0 / 3; sqrt(2); 16777217; [0.1][0]";
+    let mut interp = Interpreter::new();
+    interp.set_precision(Precision::F32);
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    assert_eq!(
+      interp.run_module(module).unwrap(),
+      vec![
+        Num((0.1f32 + 0.2f32) as f64),
+        Num(16777216.0),
+        Num((1.0f32 / 3.0) as f64),
+        Num(2f32.sqrt() as f64),
+        Int(16777217),
+        Num(0.1f32 as f64),
+      ]
+    );
+  }
+
+  #[test]
+  fn eval_try() {
+    use Value::*;
+    let src = "def at(i) try [1, 2][i] catch e -> e.1; at(1); at(2); try panic(3) catch 0; \
+      try try panic(1) catch e -> panic(e.0 + 1) catch e -> e; def f(x) try return x catch 0; f(4); \
+      def bad() panic(5); def g() try return bad() catch 9; g(); def h() try return f(6) catch 9; h()";
+    assert_eq!(
+      run_values(src),
+      vec![
+        Int(2),
+        "Index 2 out of bounds for array of length 2".into(),
+        Int(0),
+        Tuple(Rc::new([Int(2), "Panicked with code 2".into()])),
+        Int(4),
+        Int(9),
+        Int(6),
+      ]
+    );
+    assert_eq!(run_err("panic(7)"), "Panicked with code 7");
+    assert_eq!(
+      run_err("panic(1.5)"),
+      "`panic` expects an int, found double"
+    );
+  }
+
+  #[test]
+  fn eval_comparison_chain() {
+    let src = "1 < 2 < 3; 1 < 3 < 2; 3 > 2 >= 2 > 1; 1 < 2 > 0 == 1";
+    assert_eq!(run(src), vec![1.0, 0.0, 1.0, 1.0]);
+    let src = "var n = 0; def f(x) { n = n + 1; x }; 1 < f(2) < 3; n; 3 < f(2) < f(4); n";
+    assert_eq!(run(src), vec![1.0, 1.0, 0.0, 2.0]);
+  }
+
+  #[test]
+  fn eval_short_circuit() {
+    let src =
+      "var n = 0; def hit() n = n + 1; 0 && hit(); 2 || hit(); n; 1 && hit(); 0 || hit(); n";
+    assert_eq!(run(src), vec![0.0, 1.0, 0.0, 1.0, 1.0, 2.0]);
+  }
+
+  #[test]
+  fn eval_inf_nan() {
+    let src = "nan == nan; nan != nan; nan < 1; nan >= 1; inf > 9007199254740993; -inf < -1.5; inf == inf; \
+      inf - inf != inf - inf; 1 / inf; -1 / 0.0 == -inf; inf * 0 != 0; max(nan, 1)";
+    assert_eq!(
+      run(src),
+      vec![0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 0.0, 1.0, 1.0, 1.0]
+    );
+    let vals: Vec<_> = run_values("nan; -inf; const C = nan; [C]")
+      .iter()
+      .map(Value::to_string)
+      .collect();
+    assert_eq!(vals, vec!["nan", "-inf", "[nan]"]);
+    assert_eq!(run_err("int(nan)"), "Cannot convert NaN to int");
+  }
+
+  #[test]
+  fn eval_rem() {
+    let src = "7.0 % 3; -7.0 % 3; 7 % -3.0; -7.0 % -3; 7.5 % 2; 1 + 10 % 4 * 2; 9.0 / 2 % 2";
+    assert_eq!(run(src), vec![1.0, -1.0, 1.0, -1.0, 1.5, 5.0, 0.5]);
+    let src = "def isnan(x) x != x; isnan(1.0 % 0); isnan(0.0 % 0); isnan((0.0 / 0) % 2); 2 % inf";
+    assert_eq!(run(src), vec![1.0, 1.0, 1.0, 2.0]);
+  }
+
+  #[test]
+  fn eval_int() {
+    use Value::*;
+    let src = "7 / 2; -7 % 3; 7 / 2.0; 1 + 0.5; 9007199254740993 + 0; 2 * 3 == 6.0";
+    assert_eq!(
+      run_values(src),
+      vec![
+        Int(3),
+        Int(-1),
+        Num(3.5),
+        Num(1.5),
+        Int(9007199254740993),
+        Num(1.0)
+      ]
+    );
+    let src = "int(2.9); int(-2.9); int(7); float(7); float(7) / 2; int(7.0) / 2";
+    assert_eq!(
+      run_values(src),
+      vec![Int(2), Int(-2), Int(7), Num(7.0), Num(3.5), Int(3)]
+    );
+    assert_eq!(run_err("1 % 0"), "Integer division by zero");
+    assert_eq!(
+      run_err("9223372036854775807 + 1"),
+      "Integer overflow in `9223372036854775807 + 1`"
+    );
+    assert_eq!(run_err("int(1.0 / 0)"), "Cannot convert inf to int");
+  }
+
+  #[test]
+  fn eval_bitwise() {
+    use Value::*;
+    let src = "6 & 3; 6 | 3; 6 xor 3; 1 + 2 << 1; -16 >> 2; 1 << 63; 1 | 2 == 3; 5 & 4 xor 1 | 8";
+    let vals = vec![
+      Int(2),
+      Int(7),
+      Int(5),
+      Int(6),
+      Int(-4),
+      Int(i64::MIN),
+      Num(1.0),
+      Int(13),
+    ];
+    assert_eq!(run_values(src), vals);
+    assert_eq!(run_err("1 << 64"), "Cannot shift by 64 bits");
+    assert_eq!(run_err("1 >> -1"), "Cannot shift by -1 bits");
+    assert_eq!(
+      run_err("1.5 & 1"),
+      "Operator `&` cannot be applied to double and int"
+    );
+  }
+
+  #[test]
+  fn eval_strings() {
+    use Value::*;
+    let src =
+      r#"def greet(name) "Hello, " + name + "!"; greet("Kale"); len(greet("")); len("héllo")"#;
+    assert_eq!(run_values(src), vec!["Hello, Kale!".into(), Int(8), Int(5)]);
+    let src = r#""a" < "b"; "abc" == "ab" + "c"; "b" >= "ba"; if "" then 1 else 2"#;
+    assert_eq!(run(src), vec![1.0, 1.0, 0.0, 2.0]);
+    assert_eq!(
+      run_err(r#""a" + 1"#),
+      "Operator `+` cannot be applied to str and int"
+    );
+    assert_eq!(
+      run_err(r#""ab" * "c""#),
+      "Operator `*` cannot be applied to str and str"
+    );
+    assert_eq!(
+      run_err("len(1.5)"),
+      "`len` expects a str or array, found double"
+    );
+  }
+
+  #[test]
+  fn eval_printf() {
+    #[derive(Clone)]
+    struct Buf(Rc<std::cell::RefCell<Vec<u8>>>);
+    impl Write for Buf {
+      fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(bytes)
+      }
+      fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+      }
+    }
+    let buf = Buf(Rc::default());
+    let mut interp = Interpreter::with_output(buf.clone());
+    let src =
+      r#"def show(x, y) printf("x = {}, y = {}\n", x, y); show(1, 2.5); format("{}{}", "a", 1)"#;
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let vals = interp.run_module(module).unwrap();
+    assert_eq!(vals, vec![Value::Int(15), "a1".into()]);
+    assert_eq!(buf.0.borrow().as_slice(), b"x = 1, y = 2.5\n");
+    assert_eq!(
+      run_err(r#"printf("{}")"#),
+      "Format string has 1 placeholders, but 0 arguments were given"
+    );
+  }
+
+  #[test]
+  fn eval_unit() {
+    let src = "def say(x) printd(x); say(1); if say(2) then 1 else 0; ()";
+    let mut interp = Interpreter::with_output(io::sink());
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let vals = interp.run_module(module).unwrap();
+    assert_eq!(vals, vec![Value::Unit, Value::Int(0), Value::Unit]);
+    assert_eq!(
+      run_err("() * 2"),
+      "Operator `*` cannot be applied to unit and int"
+    );
+  }
+
+  #[test]
+  fn eval_arrays() {
+    use Value::*;
+    let src = "def sum(a, n) if n == 0 then 0 else a[n - 1] + sum(a, n - 1);
+      var xs = [1, 2, 3 * 4]; sum(xs, len(xs)); [[1], []][0][0]; len([]); [\"a\", 1.5][1]";
+    assert_eq!(run_values(src), vec![Int(15), Int(1), Int(0), Num(1.5)]);
+    let err = "Index 3 out of bounds for array of length 3";
+    assert_eq!(run_err("[1, 2, 3][3]"), err);
+    let err = "Index -1 out of bounds for array of length 1";
+    assert_eq!(run_err("[1][-1]"), err);
+    assert_eq!(
+      run_err("[1][0.0]"),
+      "Array index must be an int, found double"
+    );
+    assert_eq!(run_err("1[0]"), "Cannot index into int");
+  }
+
+  #[test]
+  fn eval_structs() {
+    let src = "struct Point(x, y); def norm2(p) p.x * p.x + p.y * p.y;
+      var p = Point(3, 4); norm2(p); Point(p, [1]).x.y; Point(1, 2)";
+    let vals: Vec<_> = run_values(src).iter().map(Value::to_string).collect();
+    assert_eq!(vals, vec!["25", "4", "Point(1, 2)"]);
+    let err = "No field `z` on struct `Point`";
+    assert_eq!(run_err("struct Point(x, y); Point(1, 2).z"), err);
+    let err = "Incorrect # arguments passed to `Point`: expected 2, got 1";
+    assert_eq!(run_err("struct Point(x, y); Point(1)"), err);
+    assert_eq!(run_err("(1).x"), "Cannot access field `x` of int");
+  }
+
+  #[test]
+  fn eval_overloads() {
+    let src = "struct V(x, y); def binary + (a: V, b: V) V(a.x + b.x, a.y + b.y);
+      def binary * (a: V, k) V(a.x * k, a.y * k); def binary * (k, a: V) a * k;
+      def binary == (a: V, b: V) a.x == b.x && a.y == b.y;
+      let v = (V(1, 2) + V(3, 4)) * 2 in v.x + v.y; 2 * V(1, 1) == V(2, 2); 1 + 2 * 3";
+    assert_eq!(run(src), vec![20.0, 1.0, 7.0]);
+    let src = "struct V(x, y); def binary - (a: V, b) a; def binary - (a: V, b: int) b;
+      V(1, 1) - 2.5; V(1, 1) - 2";
+    assert_eq!(
+      run_err(src),
+      "Ambiguous operator `-` for V and int: overloads for (V, double) and (V, int) both apply"
+    );
+  }
+
+  #[test]
+  fn eval_let_tuple() {
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a);
+      let (lo, hi) = minmax(7, 3) in hi - lo; let t = (1, (2, 3.5)), (a, u) = t in a + u.1";
+    assert_eq!(run(src), vec![4.0, 4.5]);
+    assert_eq!(
+      run_err("let (a, b) = (1, 2, 3) in a"),
+      "Cannot destructure a tuple of length 3 into 2 bindings"
+    );
+    assert_eq!(
+      run_err("let (a) = 1 in a"),
+      "Cannot destructure int as a tuple"
+    );
+    let err = "Tuple index 2 out of range for tuple of length 2";
+    assert_eq!(run_err("(1, 2).2"), err);
+    assert_eq!(
+      run_err("let (a, b) = (1, 2) in a = 3"),
+      "Cannot assign to immutable binding `a`"
+    );
+  }
+
+  #[test]
+  fn eval_return() {
+    let src = "def clamp(x) { if x < 0 then return 0 else 0; if x > 10 then return 10 else 0; x };
+      clamp(-5); clamp(50); clamp(7); def twice(x) 1 + (return 2 * x); twice(3) + 1;
+      var n = 0; def g() let a = 1 in { n = a; return n + 1; n = 5 }; g(); n; return 4";
+    assert_eq!(run(src), vec![0.0, 10.0, 7.0, 7.0, 2.0, 1.0, 4.0]);
+  }
+
+  #[test]
+  fn eval_closures() {
+    let src = "def sum(f, i, n) if i >= n then 0 else f(i) + sum(f, i + 1, n);
+      def integrate(f, lo, hi) let dx = (hi - lo) / 4.0 in dx * sum(\\(i) f(lo + (i + 0.5) * dx), 0, 4);
+      integrate(\\(x) x * x, 0, 1); var k = 1 in let add = \\(x) x + k in { k = 10; add(1) };
+      var sq = \\(x) x * x; sq(3); def sq(x) 0; sq(4); let f = \\() return 3 in f() + 1";
+    assert_eq!(run(src), vec![0.328125, 2.0, 9.0, 16.0, 4.0]);
+    let vals: Vec<_> = run_values("\\(x, y) x")
+      .iter()
+      .map(Value::to_string)
+      .collect();
+    assert_eq!(vals, vec!["\\(x, y)"]);
+    assert_eq!(
+      run_err("let k = 1, f = \\() k = 2 in f()"),
+      "Cannot assign to immutable binding `k`"
+    );
+    assert_eq!(
+      run_err("let f = \\(x) x in f()"),
+      "Incorrect # arguments passed to closure: expected 1, got 0"
+    );
+  }
+
+  #[test]
+  fn eval_func_values() {
+    let src = "def apply(f, x) f(x); def sq(x) x * x; apply(sq, 3); var g = sq; g(4);
+      def compose(f, g) \\(x) f(g(x)); let h = compose(sq, \\(x) x + 1) in h(2);
+      def id(sq) sq; id(2); sq";
+    let vals: Vec<_> = run_values(src).iter().map(Value::to_string).collect();
+    assert_eq!(vals, vec!["9", "16", "9", "2", "&sq"]);
+    assert_eq!(
+      run_err("def sq(x) x * x; def apply(f) f(1, 2); apply(sq)"),
+      "Incorrect # arguments passed to `sq`: expected 1, got 2"
+    );
+  }
+
+  #[test]
+  fn eval_func_refs() {
+    let src = "def add(a, b) a + b; def sub(a, b) a - b; var ops = [&add, &sub];
+      def dispatch(i, a, b) let op = ops[i] in op(a, b); dispatch(0, 5, 3); dispatch(1, 5, 3);
+      def id(add) &add; let f = id(1) in f(1, 1)";
+    assert_eq!(run(src), vec![8.0, 2.0, 2.0]);
+    assert_eq!(run_err("&nope"), "Unknown function referenced `nope`");
+    assert_eq!(
+      run_err("extern sin(x); &sin"),
+      "No implementation for extern `sin`"
+    );
+  }
+
+  #[test]
+  fn eval_tail_calls() {
+    let src = "def sum(n, acc) if n == 0 then acc else sum(n - 1, acc + n); sum(1000000, 0);
+      def even(n) match n { 0 -> 1, _ -> odd(n - 1) }; def odd(n) if n == 0 then 0 else even(n - 1);
+      even(100001); def count(n) { if n == 0 then return 7 else 0; let m = n - 1 in return count(m) };
+      count(100000); def down(f, n) if n == 0 then 1 else f(f, n - 1); down(\\(f, n) down(f, n), 100000)";
+    assert_eq!(run(src), vec![500000500000.0, 0.0, 7.0, 1.0]);
+  }
+
+  #[test]
+  fn eval_match() {
+    let src = "def sign(x) match x { 0 -> 0, -1..0 -> -1, 0..1 -> 0.5, _ -> 1 };
+      sign(0); sign(-0.5); sign(0.25); sign(7); sign(-3.0);
+      def f(s) match s { \"a\" -> 1, \"b\" -> 2 }; f(\"b\"); match 1 + 1 { 2 -> 3, 2 -> 4 }";
+    let expected = vec![0.0, -1.0, 0.5, 1.0, 1.0, 2.0, 3.0];
+    assert_eq!(run(src), expected);
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let mut module = ModuleAst::parse(&mut lexer).unwrap();
+    for item in &mut module.items {
+      match item {
+        Ast::Func(func) => func.body.lower_matches(),
+        Ast::Expr(expr) => expr.lower_matches(),
+        _ => (),
+      }
+    }
+    let vals = Interpreter::new().run_module(module).unwrap();
+    let vals: Vec<_> = vals.iter().map(|val| val.as_f64().unwrap()).collect();
+    assert_eq!(vals, expected);
+    let src = "def f(s) match s { \"a\" -> 1, \"b\" -> 2 }; f(\"c\")";
+    assert_eq!(run_err(src), "No arm of `match` matches c");
+  }
+
+  #[test]
+  fn eval_unary() {
+    let src = "!0; !2; !!0.5; -3 + 1; !(1 < 0) && !false";
+    assert_eq!(run(src), vec![1.0, 0.0, 1.0, -2.0, 1.0]);
+  }
+
+  #[test]
+  fn eval_truthiness() {
+    let src = "if true then 1 else 2; false; if 0 * -1 then 1 else 2; if 0.5 then 1 else 2";
+    assert_eq!(run(src), vec![1.0, 0.0, 2.0, 1.0]);
+  }
+
+  #[test]
+  fn eval_let_scoping() {
+    let src = "let a = 2, b = a + 1 in a * b; let x = 1 in (let x = 2 in x) + x";
+    assert_eq!(run(src), vec![6.0, 3.0]);
+  }
+
+  #[test]
+  fn eval_var_in() {
+    let src = "def f(n) var acc = 1, i = acc + 1 in { acc = acc * n; acc + i }; f(5)";
+    assert_eq!(run(src), vec![7.0]);
+    let src = "var a = 1 in { a = a + 1; a * 10 }; def g(x) { x = x + 1; x }; g(1)";
+    assert_eq!(run(src), vec![20.0, 2.0]);
+  }
+
+  #[test]
+  fn eval_assign() {
+    let src = "var a, b in { a = b = 2; a + b }; var a in (a = 3) * 2";
+    assert_eq!(run(src), vec![4.0, 6.0]);
+  }
+
+  #[test]
+  fn eval_globals() {
+    let src =
+      "var count = 10, step; def tick() count = count + step; step = 2; tick(); tick(); count";
+    assert_eq!(run(src), vec![2.0, 12.0, 14.0, 14.0]);
+  }
+
+  #[test]
+  fn eval_consts() {
+    let src = "const N = 4; const M = N * 2 + 1; def f(x) x * M; f(2); def g(N) N; g(1)";
+    assert_eq!(run(src), vec![18.0, 1.0]);
+    let src = "const ROOTS = [sqrt(1), sqrt(2), pow(3, 0.5)]; def root(n: int) ROOTS[n - 1];
+      root(3) * root(3) > 2.99; int(ROOTS[1] * 1000)";
+    assert_eq!(run(src), vec![1.0, 1414.0]);
+  }
+
+  #[test]
+  fn eval_const_errors() {
+    let mut interp = Interpreter::new();
+    let src = "const X = foo(1); const Y = 1; Y = 2";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let err = interp.run(Ast::parse(&mut lexer).unwrap()).unwrap_err();
+    assert_eq!(err, "Initializer of const `X` is not a constant expression");
+    lexer.next_token().unwrap();
+    interp.run(Ast::parse(&mut lexer).unwrap()).unwrap();
+    lexer.next_token().unwrap();
+    let err = interp.run(Ast::parse(&mut lexer).unwrap()).unwrap_err();
+    assert_eq!(err, "Cannot assign to constant `Y`");
+  }
+
+  #[test]
+  fn eval_let_immutable() {
+    let src = "let a = 1 in a = 2";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let err = Interpreter::new()
+      .run(Ast::parse(&mut lexer).unwrap())
+      .unwrap_err();
+    assert_eq!(err, "Cannot assign to immutable binding `a`");
+  }
+
+  #[test]
+  fn eval_let_not_dynamic() {
+    let src = "def f() x; let x = 1 in f()";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let mut interp = Interpreter::new();
+    interp.run(Ast::parse(&mut lexer).unwrap()).unwrap();
+    lexer.next_token().unwrap();
+    lexer.next_token().unwrap();
+    let err = interp.run(Ast::parse(&mut lexer).unwrap()).unwrap_err();
+    assert_eq!(err, "Unknown variable name `x`");
+  }
+}
@@ -0,0 +1,266 @@
+use super::{Block, Function, Module, Terminator};
+use std::fmt::Write as _;
+
+/// Cfg - the control-flow graph of a function: the blocks each block goes
+/// to, and those the entry reaches, with the blocks they come from. Edges
+/// to blocks the function doesn't have are left out, for the verifier to
+/// report.
+pub struct Cfg<'a> {
+  func: &'a Function,
+  succs: Vec<Vec<Block>>,
+  preds: Vec<Vec<Block>>, // among the blocks the entry reaches
+  order: Vec<Block>,      // the blocks the entry reaches, in reverse postorder
+}
+
+impl<'a> Cfg<'a> {
+  pub fn new(func: &'a Function) -> Self {
+    let n = func.blocks.len();
+    let succs: Vec<Vec<Block>> = (0..n as u32)
+      .map(|block| {
+        let succs = func.successors(Block(block));
+        succs
+          .into_iter()
+          .filter(|succ| (succ.0 as usize) < n)
+          .collect()
+      })
+      .collect();
+    // a depth-first search, which finishes each block after those it goes to
+    let mut seen = vec![false; n];
+    let mut order = vec![];
+    let mut stack = vec![];
+    if n > 0 {
+      seen[0] = true;
+      stack.push((Block(0), 0));
+    }
+    while let Some((block, next)) = stack.pop() {
+      match succs[block.0 as usize].get(next) {
+        Some(&succ) => {
+          stack.push((block, next + 1));
+          if !std::mem::replace(&mut seen[succ.0 as usize], true) {
+            stack.push((succ, 0));
+          }
+        }
+        None => order.push(block),
+      }
+    }
+    order.reverse();
+    let mut preds = vec![vec![]; n];
+    for &block in &order {
+      for &succ in &succs[block.0 as usize] {
+        preds[succ.0 as usize].push(block);
+      }
+    }
+    Cfg {
+      func,
+      succs,
+      preds,
+      order,
+    }
+  }
+
+  pub fn successors(&self, block: Block) -> &[Block] {
+    &self.succs[block.0 as usize]
+  }
+
+  /// The blocks the entry reaches that go to `block`, once for each edge.
+  pub fn predecessors(&self, block: Block) -> &[Block] {
+    &self.preds[block.0 as usize]
+  }
+
+  /// The blocks the entry reaches, each before those it goes to but along
+  /// the edges back to it, starting at the entry.
+  pub fn reverse_postorder(&self) -> &[Block] {
+    &self.order
+  }
+
+  pub fn is_reachable(&self, block: Block) -> bool {
+    block.0 == 0 || !self.preds[block.0 as usize].is_empty()
+  }
+
+  /// The blocks that dominate each block the entry reaches, by index.
+  pub fn dominators(&self) -> Vec<Option<Vec<bool>>> {
+    let n = self.func.blocks.len();
+    let mut dominators = vec![None; n];
+    for &block in &self.order {
+      dominators[block.0 as usize] = Some(vec![block.0 != 0; n]);
+    }
+    if n > 0 {
+      dominators[0].as_mut().unwrap()[0] = true;
+    }
+    let mut changed = true;
+    while changed {
+      changed = false;
+      for &block in self.order.iter().skip(1) {
+        let mut doms = vec![true; n];
+        for pred in &self.preds[block.0 as usize] {
+          let pred = dominators[pred.0 as usize].as_ref().unwrap();
+          doms
+            .iter_mut()
+            .zip(pred)
+            .for_each(|(dom, pred)| *dom &= pred);
+        }
+        doms[block.0 as usize] = true;
+        if dominators[block.0 as usize].as_ref() != Some(&doms) {
+          dominators[block.0 as usize] = Some(doms);
+          changed = true;
+        }
+      }
+    }
+    dominators
+  }
+
+  /// The graph in the DOT language of Graphviz, a box of code for each
+  /// block, with dashed borders for those the entry doesn't reach.
+  /// Branches label their edges `true` and `false`, and edges label the
+  /// values passed to the parameters of the block they go to.
+  pub fn to_dot(&self) -> String {
+    let mut out = format!("digraph {} {{\n", quote(&self.func.name));
+    out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+    self.write_dot(&mut out, "", "  ");
+    out.push_str("}\n");
+    out
+  }
+
+  /// Writes the blocks and edges of the graph, with the names of the blocks
+  /// after `prefix`.
+  fn write_dot(&self, out: &mut String, prefix: &str, indent: &str) {
+    let func = self.func;
+    let node = |block: Block| quote(&format!("{}{}", prefix, block));
+    for (i, lines) in func.block_lines().into_iter().enumerate() {
+      let block = Block(i as u32);
+      let label: String = lines.iter().map(|line| escape(line) + "\\l").collect();
+      let style = match self.is_reachable(block) {
+        true => "",
+        false => ", style=dashed",
+      };
+      let _ = writeln!(
+        out,
+        "{}{} [label=\"{}\"{}];",
+        indent,
+        node(block),
+        label,
+        style
+      );
+    }
+    for (i, data) in func.blocks.iter().enumerate() {
+      let targets = match &data.term {
+        Some(Terminator::Branch(_, then, els)) => vec![("true", then), ("false", els)],
+        Some(term) => term.targets().into_iter().map(|t| ("", t)).collect(),
+        None => vec![],
+      };
+      for (kind, target) in targets {
+        if target.block.0 as usize >= func.blocks.len() {
+          continue;
+        }
+        let args: Vec<_> = target.args.iter().map(|arg| arg.to_string()).collect();
+        let label = match args.is_empty() {
+          true => kind.to_string(),
+          false => format!("{}({})", kind, args.join(", ")),
+        };
+        let _ = write!(
+          out,
+          "{}{} -> {}",
+          indent,
+          node(Block(i as u32)),
+          node(target.block)
+        );
+        match label.is_empty() {
+          true => out.push_str(";\n"),
+          false => {
+            let _ = writeln!(out, " [label={}];", quote(&label));
+          }
+        }
+      }
+    }
+  }
+}
+
+impl Module {
+  /// The graphs of the functions in the DOT language, as [`Cfg::to_dot`]
+  /// writes them, each in a cluster of its own.
+  pub fn to_dot(&self) -> String {
+    let mut out = "digraph module {\n".to_string();
+    out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+    for func in &self.functions {
+      let _ = writeln!(
+        out,
+        "  subgraph {} {{",
+        quote(&format!("cluster_{}", func.name))
+      );
+      let _ = writeln!(out, "    label={};", quote(&func.name));
+      Cfg::new(func).write_dot(&mut out, &format!("{}.", func.name), "    ");
+      out.push_str("  }\n");
+    }
+    out.push_str("}\n");
+    out
+  }
+}
+
+fn escape(text: &str) -> String {
+  text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn quote(text: &str) -> String {
+  format!("\"{}\"", escape(text))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::lower;
+  use crate::lexer::Lexer;
+  use crate::parser::ModuleAst;
+  use crate::session::Entry;
+  use std::io::Cursor;
+
+  #[test]
+  fn cfg_dot() {
+    let src = "def f(x) { if x < 0 then return 0 else (); x > 1 || x < -1 };";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let module = lower(&module, Entry::TopLevel).unwrap();
+    let func = module.function("f").unwrap();
+    let cfg = Cfg::new(func);
+    let blocks = |blocks: &[Block]| blocks.iter().map(|b| b.0).collect::<Vec<_>>();
+    assert_eq!(blocks(cfg.reverse_postorder()), [0, 2, 3, 4, 5, 1]);
+    assert_eq!(blocks(cfg.predecessors(Block(5))), [3, 4]);
+    let dominators = cfg.dominators();
+    let dominated = |block: usize| {
+      let doms = dominators[block].as_ref().unwrap();
+      (0..doms.len()).filter(|&i| doms[i]).collect::<Vec<_>>()
+    };
+    assert_eq!(dominated(5), [0, 2, 3, 5]);
+    assert_eq!(
+      cfg.to_dot(),
+      r#"digraph "f" {
+  node [shape=box, fontname="monospace"];
+  "b0" [label="b0:\l%1 = num 0.0\l%2 = lt %0, %1\lbr %2, b1, b2\l"];
+  "b1" [label="b1:\l%3 = num 0.0\lret %3\l"];
+  "b2" [label="b2:\l%4 = num 0.0\ljump b3(%4)\l"];
+  "b3" [label="b3(%5: num):\l%6 = num 1.0\l%7 = gt %0, %6\lbr %7, b5(%7), b4\l"];
+  "b4" [label="b4:\l%8 = num 1.0\l%9 = neg %8\l%10 = lt %0, %9\ljump b5(%10)\l"];
+  "b5" [label="b5(%11: bool):\l%12 = frombool %11\lret %12\l"];
+  "b0" -> "b1" [label="true"];
+  "b0" -> "b2" [label="false"];
+  "b2" -> "b3" [label="(%4)"];
+  "b3" -> "b5" [label="true(%7)"];
+  "b3" -> "b4" [label="false"];
+  "b4" -> "b5" [label="(%10)"];
+}
+"#
+    );
+
+    let mut func = func.clone();
+    func.blocks[0].term = Some(Terminator::Jump(crate::ir::Target {
+      block: Block(2),
+      args: vec![],
+    }));
+    let cfg = Cfg::new(&func);
+    assert!(!cfg.is_reachable(Block(1)));
+    assert!(cfg
+      .to_dot()
+      .contains("\"b1\" [label=\"b1:\\l%3 = num 0.0\\lret %3\\l\", style=dashed];"));
+    assert!(module
+      .to_dot()
+      .contains("  subgraph \"cluster_f\" {\n    label=\"f\";\n    \"f.b0\""));
+  }
+}
@@ -0,0 +1,203 @@
+use super::{Block, Function, Inst, Terminator, Value};
+
+/// Dead-code elimination: removes the blocks the entry of `func` doesn't
+/// reach, as those after branches that were folded, the instructions
+/// whose values are never used, unless they have an effect, and the
+/// parameters of blocks that are never used, with the values passed to
+/// them. Values count as used only by code that is kept, so that a chain
+/// of dead instructions, or a loop of parameters, goes at once. The values
+/// that remain are numbered anew.
+pub fn dce(func: &mut Function) {
+  func.remove_unreachable();
+  let mut defs = vec![None; func.types.len()]; // the block and index of each
+  let mut incoming = vec![vec![]; func.blocks.len()]; // the edges to each
+  let mut work = vec![];
+  for (i, data) in func.blocks.iter().enumerate() {
+    let block = Block(i as u32);
+    for (j, &param) in data.params.iter().enumerate() {
+      defs[param.0 as usize] = Some((block, None, j));
+    }
+    for (j, (value, inst)) in data.insts.iter().enumerate() {
+      defs[value.0 as usize] = Some((block, Some(inst), j));
+      if inst.has_effect(&func.types) {
+        work.extend(inst.args());
+      }
+    }
+    let term = data.term.as_ref().expect("the IR is verified");
+    match term {
+      Terminator::Jump(_) => {}
+      Terminator::Branch(cond, ..) => work.push(*cond),
+      Terminator::Return(value) => work.push(*value),
+    }
+    for (edge, target) in term.targets().into_iter().enumerate() {
+      incoming[target.block.0 as usize].push((block, edge));
+    }
+  }
+  let mut live = vec![false; func.types.len()];
+  while let Some(value) = work.pop() {
+    if std::mem::replace(&mut live[value.0 as usize], true) {
+      continue;
+    }
+    match defs[value.0 as usize] {
+      Some((_, Some(inst), _)) => work.extend(inst.args()),
+      Some((block, None, j)) if block.0 != 0 => {
+        for &(pred, edge) in &incoming[block.0 as usize] {
+          let term = func.block(pred).term.as_ref().unwrap();
+          work.push(term.targets()[edge].args[j]);
+        }
+      }
+      _ => {}
+    }
+  }
+  let kept: Vec<Vec<bool>> = func
+    .blocks
+    .iter()
+    .enumerate()
+    .map(|(i, data)| {
+      let kept = |param: &Value| i == 0 || live[param.0 as usize];
+      data.params.iter().map(kept).collect()
+    })
+    .collect();
+  for (i, data) in func.blocks.iter_mut().enumerate() {
+    let mut params = kept[i].iter();
+    data.params.retain(|_| *params.next().unwrap());
+    let types = &func.types;
+    let live = |(value, inst): &(Value, Inst)| inst.has_effect(types) || live[value.0 as usize];
+    data.insts.retain(live);
+    for target in data.term.as_mut().unwrap().targets_mut() {
+      let mut args = kept[target.block.0 as usize].iter();
+      target.args.retain(|_| *args.next().unwrap());
+    }
+  }
+  func.remove_unreachable();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bytecode;
+  use crate::eval::Interpreter;
+  use crate::ir::{lower, optimize, verify};
+  use crate::lexer::Lexer;
+  use crate::parser::ModuleAst;
+  use crate::session::Entry;
+  use crate::typeck::TypeChecker;
+  use crate::value::Precision;
+  use crate::vm::{Mode, Vm};
+  use std::io::Cursor;
+
+  fn parse(src: &str) -> ModuleAst {
+    ModuleAst::parse(&mut Lexer::new(Cursor::new(src.to_string()))).unwrap()
+  }
+
+  #[test]
+  fn dce_unused() {
+    // the product is unused, and so is the join of `y`, passed `x` or 0
+    let src = "def f(x) var y = 0.0 in { if x > 0 then y = x else (); let d = x * 2 in x };";
+    let mut module = lower(&parse(src), Entry::TopLevel).unwrap();
+    dce(&mut module.functions[0]);
+    assert_eq!(verify(&module).map_err(|e| e.len()), Ok(()));
+    assert_eq!(
+      module.functions[0].to_string(),
+      "fn f(%0: num) -> num {
+b0:
+  %1 = num 0.0
+  %2 = gt %0, %1
+  br %2, b1, b2
+b1:
+  jump b3
+b2:
+  jump b3
+b3:
+  ret %0
+}
+"
+    );
+  }
+
+  #[test]
+  fn optimize_matches_interpreter() {
+    let srcs = [
+      "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2); fib(15);",
+      "def f(x) var y = 1.0, z = 0.0 in {
+         if x > 0 then y = y + x else z = 5.0;
+         (x > 1 && (y = y * 10) > 0) || (z = 7.0) > 0;
+         y + z
+       }; f(2); f(1); f(-1);",
+      "def g(x) { let d = x * 2 in (); if 1 < 2 then x + 1 else x - 1 }; g(4);",
+      "def h(x) { if !(1 > 2) then return x / 3.0 else (); x }; h(1.0) + h(2.0);",
+      "def k(x) var s = 0.1 in { if s < 1 then s = s + 0.2 else s = x; s * x }; k(3);",
+      "def m(a, b) let (lo, hi) = if a < b then (a, b) else (b, a) in hi - lo; m(2, 7) * m(7, 2);",
+      "def p(n) n * 3 - n % 4 + -n; p(7) + p(-5); 9 % 4 * 2;",
+      "def q(a, b) a / (b + 0.5) + 7 % b / 2.0; q(7, 2); q(-7, 3); let n = 7 in n / 2.0;",
+    ];
+    for precision in [Precision::F64, Precision::F32] {
+      for src in srcs {
+        let mut interp = Interpreter::new();
+        interp.set_precision(precision);
+        let expected = interp.run_module(parse(src)).unwrap();
+        let mut module = lower(&parse(src), Entry::TopLevel).unwrap();
+        optimize(&mut module, precision);
+        assert_eq!(verify(&module).map_err(|e| e.len()), Ok(()), "{}", src);
+        let program = bytecode::compile(&module);
+        let values = Vm::builder().precision(precision).build().run(&program);
+        let nums = |values: Vec<_>| {
+          values
+            .iter()
+            .map(crate::value::Value::as_f64)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(nums(values.unwrap()), nums(expected), "{}", src);
+      }
+    }
+
+    // ints are exact beyond 2^53, and fail as in the interpreter
+    let def = "def big(a: int): int a * a + 1;";
+    for (call, expected) in [
+      (
+        "big(94906267);",
+        Ok(vec![crate::value::Value::Int(9007199515875290)]),
+      ),
+      (
+        "big(3037000500);",
+        Err("Integer overflow in `3037000500 * 3037000500`".to_string()),
+      ),
+    ] {
+      let src = format!("{} {}", def, call);
+      assert_eq!(Interpreter::new().run_module(parse(&src)), expected);
+      let program = bytecode::compile(&lower(&parse(&src), Entry::TopLevel).unwrap());
+      for mode in [Mode::Stack, Mode::Register] {
+        assert_eq!(Vm::builder().mode(mode).build().run(&program), expected);
+      }
+    }
+
+    // as checked, a double divides, and an int truncates or fails on zero
+    for (src, expected) in [
+      (
+        "def half(x) x / 2; half(7);",
+        Ok(vec![crate::value::Value::Num(3.5)]),
+      ),
+      (
+        "def half(x: int) x / 2; half(7);",
+        Ok(vec![crate::value::Value::Int(3)]),
+      ),
+      (
+        "def half(x: int) 2 / x; half(0);",
+        Err("Integer division by zero".to_string()),
+      ),
+    ] {
+      let mut module = parse(src);
+      TypeChecker::new().check_module(&mut module).unwrap();
+      let program = bytecode::compile(&lower(&module, Entry::TopLevel).unwrap());
+      assert_eq!(Interpreter::new().run_module(module), expected, "{}", src);
+      for mode in [Mode::Stack, Mode::Register] {
+        assert_eq!(
+          Vm::builder().mode(mode).build().run(&program),
+          expected,
+          "{}",
+          src
+        );
+      }
+    }
+  }
+}
@@ -0,0 +1,943 @@
+use super::{optimize, verify};
+use super::{
+  BinaryOp, Block, CmpOp, Extern, Function, Inst, Module, Target, Terminator, Type, UnaryOp, Value,
+};
+use crate::codegen::backend::{define_module, Backend, Declaration, Declared};
+use crate::codegen::{is_overloaded, overload, tuple_arity, unsupported, Annotation, Cond};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, ProtoAst, UnOp};
+use crate::prelude::prelude;
+use crate::session::Entry;
+use crate::value::Precision;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Lowers the checked program `module`, which starts at `entry`, to IR,
+/// without the blocks that follow a `return`. Every function is lowered even
+/// when another one fails, and the errors are reported in order.
+pub fn lower(module: &ModuleAst, entry: Entry) -> Result<Module, Vec<Diagnostic>> {
+  let mut backend = IrBackend::new();
+  define_module(&mut backend, module)?;
+  Ok(backend.take_module(entry))
+}
+
+/// Format - how IR is written: as text, or as the graphs of its functions
+/// in the DOT language of Graphviz.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+  Text,
+  Dot,
+}
+
+/// IrBackend - lowers programs to IR, as [`lower`] does, which is verified
+/// and written to `output` in the [`Format`] set, text unless set. The IR
+/// is the same whatever the precision, unless it is optimized.
+pub struct IrBackend {
+  lowerer: Lowerer,
+  declared: Declared<Callee>,
+  functions: Vec<Function>,
+  format: Format,
+  optimize: Option<Precision>,
+}
+
+impl IrBackend {
+  pub fn new() -> Self {
+    Self {
+      lowerer: Lowerer {
+        externs: vec![],
+        functions: HashMap::new(),
+        tuples: HashMap::new(),
+      },
+      declared: Declared::new(),
+      functions: vec![],
+      format: Format::Text,
+      optimize: None,
+    }
+  }
+
+  pub fn set_format(&mut self, format: Format) {
+    self.format = format;
+  }
+
+  /// Makes the backend [`optimize`] the IR, computing with numbers of
+  /// `precision`.
+  pub fn set_optimize(&mut self, precision: Precision) {
+    self.optimize = Some(precision);
+  }
+
+  /// The module of the functions lowered so far, which starts at `entry`.
+  pub fn take_module(&mut self, entry: Entry) -> Module {
+    let declared = std::mem::replace(&mut self.declared, Declared::new());
+    let start = declared.entry(entry).map(|callee| callee.name.clone());
+    let start = start.collect();
+    Module {
+      externs: std::mem::take(&mut self.lowerer.externs),
+      functions: std::mem::take(&mut self.functions),
+      start,
+    }
+  }
+
+  /// Declares and lowers `func`, of the items given one at a time rather
+  /// than as a module, and yields the text of its IR, before it is
+  /// optimized, to inspect without running it. The module they make starts
+  /// at the entry [`take_module`](Self::take_module) is given.
+  pub fn emit_ir(&mut self, func: &FuncAst) -> Result<String, Diagnostic> {
+    if !func.proto.name.is_empty() {
+      let tuple = tuple_arity(&func.body, &self.lowerer.tuples);
+      self.declare_proto(&func.proto, Declaration::Function { tuple })?;
+    }
+    self.define_function(func)?;
+    Ok(self.functions.last().unwrap().to_string())
+  }
+
+  /// The module of the functions lowered so far, as [`take_module`] takes
+  /// it, verified, then optimized if set.
+  ///
+  /// [`take_module`]: IrBackend::take_module
+  pub fn finish(&mut self, entry: Entry) -> Result<Module, Vec<Diagnostic>> {
+    let mut module = self.take_module(entry);
+    verify(&module)?;
+    if let Some(precision) = self.optimize {
+      optimize(&mut module, precision);
+    }
+    Ok(module)
+  }
+}
+
+impl Backend for IrBackend {
+  fn name(&self) -> &'static str {
+    "IR"
+  }
+
+  fn declare_proto(&mut self, proto: &ProtoAst, decl: Declaration) -> Result<(), Diagnostic> {
+    match decl {
+      Declaration::Extern => self.lowerer.declare_extern(proto),
+      Declaration::Function { tuple } => {
+        let callee = self.lowerer.declare(proto, tuple, self.declared.funcs());
+        self.declared.push(&proto.name, callee);
+      }
+    }
+    Ok(())
+  }
+
+  fn define_function(&mut self, func: &FuncAst) -> Result<(), Diagnostic> {
+    let lowerer = &mut self.lowerer;
+    let callee = self.declared.define(func, |funcs| {
+      let tuple = tuple_arity(&func.body, &lowerer.tuples);
+      lowerer.declare(&func.proto, tuple, funcs)
+    });
+    let function = self.lowerer.lower_func(func, &callee)?;
+    self.functions.push(function);
+    Ok(())
+  }
+
+  fn finish_module(&mut self, entry: Entry, output: &Path) -> Result<(), Vec<Diagnostic>> {
+    let module = self.finish(entry)?;
+    let text = match self.format {
+      Format::Text => module.to_string(),
+      Format::Dot => module.to_dot(),
+    };
+    std::fs::write(output, text).map_err(|e| {
+      let msg = format!("Cannot write `{}`: {}", output.display(), e);
+      vec![Diagnostic::error(Span::default(), msg).with_code("codegen")]
+    })
+  }
+}
+
+/// A function or an extern as programs call it.
+#[derive(Debug, Clone)]
+struct Callee {
+  name: String,
+  params: Vec<Type>,
+  ret: Type,
+}
+
+struct Lowerer {
+  externs: Vec<Extern>,
+  functions: HashMap<String, Callee>, // by the name programs call them
+  tuples: HashMap<String, usize>,     // the arity of those returning tuples
+}
+
+/// The type of IR values of a parameter or a return, as annotated.
+fn annotated(annotation: Annotation) -> Type {
+  match annotation {
+    Annotation::Num => Type::Num,
+    Annotation::Int => Type::Int,
+    Annotation::Tuple(ints) => Type::Tuple(ints.into_iter().map(int_or_num).collect()),
+  }
+}
+
+fn int_or_num(int: bool) -> Type {
+  match int {
+    true => Type::Int,
+    false => Type::Num,
+  }
+}
+
+/// How errors name a value of type `ty`.
+fn describe(ty: &Type) -> &'static str {
+  match ty {
+    Type::Num => "a number",
+    Type::Int => "an int",
+    Type::Bool => "a boolean",
+    Type::Tuple(_) => "a tuple",
+  }
+}
+
+/// Whether `expr` is an int literal, negated or not.
+fn is_int_literal(expr: &ExprAst) -> bool {
+  match expr {
+    ExprAst::IntAst(_) => true,
+    ExprAst::UnaryAst(UnOp::Neg, operand, _) => matches!(**operand, ExprAst::IntAst(_)),
+    _ => false,
+  }
+}
+
+impl Lowerer {
+  /// Declares the function `proto` defines, or a top-level expression,
+  /// after those of `funcs`, returning a tuple of `tuple` numbers if any.
+  /// Its parameters and return have the types they are annotated with.
+  fn declare(
+    &mut self,
+    proto: &ProtoAst,
+    tuple: Option<usize>,
+    funcs: &[(String, Callee)],
+  ) -> Callee {
+    if let (Some(n), false) = (tuple, proto.name.is_empty()) {
+      self.tuples.insert(proto.name.clone(), n);
+    }
+    let name = match proto.name.as_str() {
+      "" => match funcs.iter().filter(|(name, _)| name.is_empty()).count() {
+        0 => "__anon_expr".to_string(),
+        n => format!("__anon_expr.{}", n),
+      },
+      name => name.to_string(),
+    };
+    let callee = Callee {
+      name,
+      params: proto
+        .arg_tys
+        .iter()
+        .map(|ty| annotated(Annotation::of(ty.as_deref())))
+        .collect(),
+      ret: annotated(Annotation::returned(proto, tuple)),
+    };
+    if !proto.name.is_empty() {
+      self.functions.insert(proto.name.clone(), callee.clone());
+    }
+    callee
+  }
+
+  fn declare_extern(&mut self, proto: &ProtoAst) {
+    let ext = Extern {
+      name: proto.name.clone(),
+      symbol: proto.symbol().to_string(),
+      params: proto.args.len(),
+    };
+    match self.externs.iter_mut().find(|e| e.name == ext.name) {
+      Some(declared) => *declared = ext,
+      None => self.externs.push(ext),
+    }
+    let callee = Callee {
+      name: proto.name.clone(),
+      params: vec![Type::Num; proto.args.len()],
+      ret: Type::Num,
+    };
+    self.functions.insert(proto.name.clone(), callee);
+  }
+
+  /// Lowers the function `func`, or the top-level expression it wraps,
+  /// which `callee` declares.
+  fn lower_func(&mut self, func: &FuncAst, callee: &Callee) -> Result<Function, Diagnostic> {
+    let proto = &func.proto;
+    let mut body = func.body.clone();
+    body.lower_matches();
+    let function = Function::new(&callee.name, &callee.params, callee.ret.clone());
+    // parameters are mutable, as in the interpreter
+    let params = function.params().iter();
+    let scope = proto
+      .args
+      .iter()
+      .zip(params)
+      .map(|(name, &value)| (name.clone(), value, true));
+    let mut lowering = Lowering {
+      lowerer: self,
+      scope: scope.collect(),
+      func: function,
+      block: Block(0),
+    };
+    // nothing calls a top-level expression, which returns what it yields
+    let value = match (proto.name.is_empty(), &proto.ret_ty) {
+      (true, None) => {
+        let value = lowering.lower_expr(&body, proto.span)?;
+        lowering.func.ret = lowering.func.ty(value).clone();
+        value
+      }
+      _ => lowering.lower_return(&body, proto.span)?,
+    };
+    lowering
+      .func
+      .terminate(lowering.block, Terminator::Return(value));
+    let mut func = lowering.func;
+    func.remove_unreachable();
+    Ok(func)
+  }
+}
+
+/// Lowering - the state of the lowering of one function, whose
+/// instructions go to the end of `block`. Each name in `scope` is bound to
+/// a value, which assigning a mutable variable binds it to anew, and the
+/// blocks where branches join take the variables assigned differently
+/// along each as parameters.
+///
+/// Ints stay ints, as in the interpreter, until they meet numbers: the
+/// operands of an operator, the branches of an `if` and what is assigned,
+/// passed or returned where numbers are expected convert to numbers then.
+/// The checker annotates parameters and returns with the types it infers,
+/// so a parameter is an int where the interpreter finds it one.
+struct Lowering<'a> {
+  lowerer: &'a mut Lowerer,
+  func: Function,
+  block: Block,
+  scope: Vec<(String, Value, bool)>, // whether each is mutable
+}
+
+impl Lowering<'_> {
+  fn push(&mut self, inst: Inst, ty: Type) -> Value {
+    self.func.push(self.block, inst, ty)
+  }
+
+  fn num(&mut self, n: f64) -> Value {
+    self.push(Inst::Num(n), Type::Num)
+  }
+
+  /// Ends the current block with `term`, continuing in `block`.
+  fn switch(&mut self, term: Terminator, block: Block) {
+    self.func.terminate(self.block, term);
+    self.block = block;
+  }
+
+  /// `value` as a value of type `ty`, if it is one already or an int that
+  /// converts to a number, or a tuple whose elements do.
+  fn convert(&mut self, value: Value, ty: &Type) -> Option<Value> {
+    match (self.func.ty(value).clone(), ty) {
+      (found, ty) if found == *ty => Some(value),
+      (Type::Int, Type::Num) => Some(self.push(Inst::Unary(UnaryOp::ToNum, value), Type::Num)),
+      (Type::Tuple(elems), Type::Tuple(tys)) if elems.len() == tys.len() => {
+        let mut values = vec![];
+        for (i, (elem, ty)) in elems.into_iter().zip(tys).enumerate() {
+          let elem = self.push(Inst::Elem(value, i), elem);
+          values.push(self.convert(elem, ty)?);
+        }
+        Some(self.push(Inst::Tuple(values), ty.clone()))
+      }
+      _ => None,
+    }
+  }
+
+  /// `expr` as a value of type `ty`, as [`convert`](Self::convert) makes
+  /// it. An int literal where a number is expected is lowered as one.
+  fn lower_as(&mut self, expr: &ExprAst, ty: &Type, span: Span) -> Result<Value, Diagnostic> {
+    match (expr, ty) {
+      (ExprAst::IntAst(i), Type::Num) => return Ok(self.num(*i as f64)),
+      (ExprAst::UnaryAst(UnOp::Neg, operand, at), Type::Num) if is_int_literal(expr) => {
+        let operand = self.lower_as(operand, ty, *at)?;
+        return Ok(self.push(Inst::Unary(UnaryOp::Neg, operand), Type::Num));
+      }
+      _ => {}
+    }
+    let value = self.lower_expr(expr, span)?;
+    let found = self.func.ty(value).clone();
+    self.convert(value, ty).ok_or_else(|| {
+      let msg = format!("Expected {}, found {}", describe(ty), describe(&found));
+      Diagnostic::error(span, msg).with_code("codegen")
+    })
+  }
+
+  /// What the function returns, `value`, as the type of its return.
+  fn lower_return(&mut self, value: &ExprAst, span: Span) -> Result<Value, Diagnostic> {
+    let ret = self.func.ret.clone();
+    if is_int_literal(value) {
+      return self.lower_as(value, &ret, span);
+    }
+    let value = self.lower_expr(value, span)?;
+    let found = self.func.ty(value).clone();
+    self.convert(value, &ret).ok_or_else(|| {
+      let msg = match (&ret, &found) {
+        (Type::Int, Type::Num) => "Function returns an int, found a number".to_string(),
+        _ => "Function returns both numbers and tuples, or tuples of different sizes".to_string(),
+      };
+      Diagnostic::error(span, msg).with_code("codegen")
+    })
+  }
+
+  fn lower_expr(&mut self, expr: &ExprAst, span: Span) -> Result<Value, Diagnostic> {
+    match expr {
+      ExprAst::NumAst(n) => Ok(self.num(*n)),
+      ExprAst::IntAst(i) => Ok(self.push(Inst::Int(*i), Type::Int)),
+      ExprAst::BoolAst(b) => Ok(self.num(*b as i32 as f64)),
+      ExprAst::UnitAst => Ok(self.num(0.0)),
+      ExprAst::VarAst(name, at) => match self.scope.iter().rev().find(|(n, ..)| n == name) {
+        Some((_, value, _)) => Ok(*value),
+        None if self.lowerer.functions.contains_key(name) => {
+          Err(unsupported("IR", "functions as values", *at))
+        }
+        None => Err(unsupported("IR", "global variables", *at)),
+      },
+      ExprAst::UnaryAst(UnOp::Neg, operand, at) => {
+        let operand = self.lower_scalar(operand, *at)?;
+        let ty = self.func.ty(operand).clone();
+        Ok(self.push(Inst::Unary(UnaryOp::Neg, operand), ty))
+      }
+      ExprAst::UnaryAst(UnOp::Not, ..) => {
+        let cond = self.lower_cond(expr, span)?;
+        Ok(self.push(Inst::FromBool(cond), Type::Num))
+      }
+      ExprAst::BinAst(lhs, op, rhs, at) => self.lower_bin(lhs, *op, rhs, *at),
+      ExprAst::CallAst(name, args, at) => self.lower_call(name, args, *at),
+      ExprAst::IfAst { cond, then, els } => self.lower_if(cond, then, els, span),
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        let Some((last, exprs)) = exprs.split_last() else {
+          return Ok(self.num(0.0));
+        };
+        for expr in exprs {
+          self.lower_expr(expr, span)?;
+        }
+        self.lower_expr(last, span)
+      }
+      ExprAst::TupleAst(elems) => {
+        let mut values = vec![];
+        for elem in elems {
+          values.push(self.lower_scalar(elem, span)?);
+        }
+        let tys = values.iter().map(|&value| self.func.ty(value).clone());
+        let ty = Type::Tuple(tys.collect());
+        Ok(self.push(Inst::Tuple(values), ty))
+      }
+      ExprAst::ElemAst(tuple, i) => {
+        let tuple = self.lower_expr(tuple, span)?;
+        match self.func.ty(tuple).clone() {
+          Type::Tuple(elems) if *i < elems.len() => {
+            Ok(self.push(Inst::Elem(tuple, *i), elems[*i].clone()))
+          }
+          _ => {
+            Err(Diagnostic::error(span, format!("No element {} in tuple", i)).with_code("codegen"))
+          }
+        }
+      }
+      ExprAst::LetAst(bindings, body) => {
+        let depth = self.scope.len();
+        for (name, _, init) in bindings {
+          let value = self.lower_expr(init, span)?;
+          self.scope.push((name.clone(), value, false));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let tuple = self.lower_expr(init, span)?;
+        let elems = match self.func.ty(tuple).clone() {
+          Type::Tuple(elems) if elems.len() == names.len() => elems,
+          Type::Tuple(elems) => {
+            let msg = format!(
+              "Cannot destructure a tuple of {} into {} names",
+              elems.len(),
+              names.len()
+            );
+            return Err(Diagnostic::error(span, msg).with_code("codegen"));
+          }
+          _ => {
+            let msg = format!("Cannot destructure a number into {} names", names.len());
+            return Err(Diagnostic::error(span, msg).with_code("codegen"));
+          }
+        };
+        let depth = self.scope.len();
+        for (i, ((name, _), ty)) in names.iter().zip(elems).enumerate() {
+          let elem = self.push(Inst::Elem(tuple, i), ty);
+          self.scope.push((name.clone(), elem, false));
+        }
+        let res = self.lower_expr(body, span);
+        self.scope.truncate(depth);
+        res
+      }
+      // the code that follows a `return` goes to a block of its own, which
+      // nothing reaches, so the `return` yields what it returned
+      ExprAst::ReturnAst(value, at) => {
+        let value = self.lower_return(value, *at)?;
+        let dead = self.func.add_block();
+        self.switch(Terminator::Return(value), dead);
+        Ok(value)
+      }
+      ExprAst::MatchAst(..) => unreachable!("`match` is lowered before codegen"),
+      ExprAst::StrAst(_) => Err(unsupported("IR", "strings", span)),
+      ExprAst::ArrayAst(_) | ExprAst::IndexAst(..) => Err(unsupported("IR", "arrays", span)),
+      ExprAst::FieldAst(..) => Err(unsupported("IR", "structs", span)),
+      ExprAst::LambdaAst(..) => Err(unsupported("IR", "closures", span)),
+      ExprAst::FuncRefAst(_, at) => Err(unsupported("IR", "functions as values", *at)),
+      ExprAst::VarInAst(vars, body) => {
+        let depth = self.scope.len();
+        let mut res = Ok(());
+        for (name, _, init) in vars {
+          let value = match init {
+            Some(init) => self.lower_scalar(init, span),
+            None => Ok(self.num(0.0)),
+          };
+          match value {
+            Ok(value) => self.scope.push((name.clone(), value, true)),
+            Err(e) => {
+              res = Err(e);
+              break;
+            }
+          }
+        }
+        let res = res.and_then(|_| self.lower_expr(body, span));
+        self.scope.truncate(depth);
+        res
+      }
+      // a variable keeps the type of its initializer
+      ExprAst::AssignAst(name, value, _) => {
+        let Some(&(_, var, mutable)) = self.scope.iter().rev().find(|(n, ..)| n == name) else {
+          return Err(unsupported("IR", "global variables", span));
+        };
+        if !mutable {
+          let msg = format!("Cannot assign to immutable binding `{}`", name);
+          return Err(Diagnostic::error(span, msg).with_code("codegen"));
+        }
+        let ty = self.func.ty(var).clone();
+        let value = self.lower_as(value, &ty, span)?;
+        let (_, var, _) = self
+          .scope
+          .iter_mut()
+          .rev()
+          .find(|(n, ..)| n == name)
+          .unwrap();
+        *var = value;
+        Ok(value)
+      }
+      ExprAst::TryAst(.., at) => Err(unsupported("IR", "`try`", *at)),
+    }
+  }
+
+  /// A number or an int, which tuples aren't.
+  fn lower_scalar(&mut self, expr: &ExprAst, span: Span) -> Result<Value, Diagnostic> {
+    let value = self.lower_expr(expr, span)?;
+    match self.func.ty(value) {
+      Type::Num | Type::Int => Ok(value),
+      _ => Err(Diagnostic::error(span, "Expected a number, found a tuple").with_code("codegen")),
+    }
+  }
+
+  /// The operands of a binary operator, of the same type: when one is an
+  /// int and the other a number, the int converts to a number, as the
+  /// interpreter promotes it. An int literal takes the type of the other
+  /// operand, which is lowered first.
+  fn lower_operands(
+    &mut self,
+    lhs: &ExprAst,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<(Value, Value), Diagnostic> {
+    if is_int_literal(lhs) {
+      let rhs = self.lower_scalar(rhs, span)?;
+      let ty = self.func.ty(rhs).clone();
+      return Ok((self.lower_as(lhs, &ty, span)?, rhs));
+    }
+    let lhs = self.lower_scalar(lhs, span)?;
+    let rhs = match is_int_literal(rhs) {
+      true => {
+        let ty = self.func.ty(lhs).clone();
+        self.lower_as(rhs, &ty, span)?
+      }
+      false => self.lower_scalar(rhs, span)?,
+    };
+    match (self.func.ty(lhs), self.func.ty(rhs)) {
+      (Type::Int, Type::Num) => Ok((self.convert(lhs, &Type::Num).unwrap(), rhs)),
+      (Type::Num, Type::Int) => Ok((lhs, self.convert(rhs, &Type::Num).unwrap())),
+      _ => Ok((lhs, rhs)),
+    }
+  }
+
+  /// A condition, as the boolean of whether `expr` is true: nonzero, which
+  /// NaN is.
+  fn lower_cond(&mut self, expr: &ExprAst, span: Span) -> Result<Value, Diagnostic> {
+    match Cond::of(expr, &self.lowerer.functions) {
+      Cond::Bool(b) => Ok(self.push(Inst::Bool(b), Type::Bool)),
+      Cond::Not(operand, at) => {
+        let cond = self.lower_cond(operand, at)?;
+        Ok(self.push(Inst::Not(cond), Type::Bool))
+      }
+      Cond::Logical(lhs, op, rhs, at) => self.lower_logical(lhs, op, rhs, at),
+      Cond::Cmp(lhs, op, rhs, at) => {
+        let op = match op {
+          BinOp::Lt => CmpOp::Lt,
+          BinOp::Gt => CmpOp::Gt,
+          BinOp::Le => CmpOp::Le,
+          BinOp::Ge => CmpOp::Ge,
+          BinOp::Eq => CmpOp::Eq,
+          _ => CmpOp::Ne,
+        };
+        let (lhs, rhs) = self.lower_operands(lhs, rhs, at)?;
+        Ok(self.push(Inst::Cmp(op, lhs, rhs), Type::Bool))
+      }
+      Cond::Nonzero => {
+        let value = self.lower_scalar(expr, span)?;
+        let zero = match self.func.ty(value) {
+          Type::Int => self.push(Inst::Int(0), Type::Int),
+          _ => self.num(0.0),
+        };
+        Ok(self.push(Inst::Cmp(CmpOp::Ne, value, zero), Type::Bool))
+      }
+    }
+  }
+
+  fn lower_bin(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Value, Diagnostic> {
+    if is_overloaded(&self.lowerer.functions, op) {
+      return self.lower_call(&overload(op), &[lhs.clone(), rhs.clone()], span);
+    }
+    let binary = match op {
+      BinOp::Add => BinaryOp::Add,
+      BinOp::Sub => BinaryOp::Sub,
+      BinOp::Mul => BinaryOp::Mul,
+      BinOp::Div => BinaryOp::Div,
+      BinOp::Rem => BinaryOp::Rem,
+      BinOp::BitAnd => BinaryOp::BitAnd,
+      BinOp::BitOr => BinaryOp::BitOr,
+      BinOp::Xor => BinaryOp::Xor,
+      BinOp::Shl => BinaryOp::Shl,
+      BinOp::Shr => BinaryOp::Shr,
+      _ => {
+        let expr = ExprAst::BinAst(Box::new(lhs.clone()), op, Box::new(rhs.clone()), span);
+        let cond = self.lower_cond(&expr, span)?;
+        return Ok(self.push(Inst::FromBool(cond), Type::Num));
+      }
+    };
+    let (lhs, rhs) = self.lower_operands(lhs, rhs, span)?;
+    let ty = self.func.ty(lhs).clone();
+    if binary.is_bitwise() && ty != Type::Int {
+      let msg = format!("Operator `{}` takes ints, found numbers", op.as_str());
+      return Err(Diagnostic::error(span, msg).with_code("codegen"));
+    }
+    Ok(self.push(Inst::Binary(binary, lhs, rhs), ty))
+  }
+
+  /// `&&` and `||`, which only evaluate `rhs` when `lhs` doesn't decide,
+  /// and otherwise pass `lhs` on.
+  fn lower_logical(
+    &mut self,
+    lhs: &ExprAst,
+    op: BinOp,
+    rhs: &ExprAst,
+    span: Span,
+  ) -> Result<Value, Diagnostic> {
+    let lhs = self.lower_cond(lhs, span)?;
+    let (branch_end, before) = (self.block, self.vars());
+    let next = self.func.add_block();
+    self.block = next;
+    let rhs = self.lower_cond(rhs, span)?;
+    let join = self.func.add_block();
+    let cond = self.func.add_param(join, Type::Bool);
+    let [decided, rhs] = self.join(join, [(lhs, before), (rhs, self.vars())]);
+    let rhs_target = Target {
+      block: next,
+      args: vec![],
+    };
+    let branch = match op {
+      BinOp::And => Terminator::Branch(lhs, rhs_target, decided),
+      _ => Terminator::Branch(lhs, decided, rhs_target),
+    };
+    self.func.terminate(branch_end, branch);
+    self.switch(Terminator::Jump(rhs), join);
+    Ok(cond)
+  }
+
+  /// The values of the mutable variables in scope.
+  fn vars(&self) -> Vec<Value> {
+    let vars = self.scope.iter().filter(|(.., mutable)| *mutable);
+    vars.map(|(_, value, _)| *value).collect()
+  }
+
+  /// The targets of the two branches that join at `join`, each passing its
+  /// value, for the first parameter of `join`, and its values of the
+  /// mutable variables in scope, as [`vars`](Self::vars) has them. The
+  /// variables they differ on become parameters of `join` too, which they
+  /// are bound to from there.
+  fn join(&mut self, join: Block, ends: [(Value, Vec<Value>); 2]) -> [Target; 2] {
+    let [(first, first_vars), (second, second_vars)] = ends;
+    let mut args = [vec![first], vec![second]];
+    let vars = self.scope.iter_mut().filter(|(.., mutable)| *mutable);
+    for ((_, var, _), (first, second)) in vars.zip(first_vars.into_iter().zip(second_vars)) {
+      *var = match first == second {
+        true => first,
+        false => {
+          args[0].push(first);
+          args[1].push(second);
+          let ty = self.func.ty(first).clone();
+          self.func.add_param(join, ty)
+        }
+      };
+    }
+    args.map(|args| Target { block: join, args })
+  }
+
+  /// The type both branches of an `if` yield, of types `a` and `b`: a
+  /// number when one yields an int and the other a number, which may be
+  /// elements of tuples.
+  fn join_type(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+      (a, b) if a == b => Some(a.clone()),
+      (Type::Int | Type::Num, Type::Int | Type::Num) => Some(Type::Num),
+      (Type::Tuple(a), Type::Tuple(b)) if a.len() == b.len() => {
+        let elems = a.iter().zip(b).map(|(a, b)| Self::join_type(a, b));
+        elems.collect::<Option<_>>().map(Type::Tuple)
+      }
+      _ => None,
+    }
+  }
+
+  fn lower_if(
+    &mut self,
+    cond: &ExprAst,
+    then: &ExprAst,
+    els: &ExprAst,
+    span: Span,
+  ) -> Result<Value, Diagnostic> {
+    let cond = self.lower_cond(cond, span)?;
+    let (then_block, els_block) = (self.func.add_block(), self.func.add_block());
+    let target = |block| Target {
+      block,
+      args: vec![],
+    };
+    let branch = Terminator::Branch(cond, target(then_block), target(els_block));
+    self.switch(branch, then_block);
+    let before = self.vars();
+    let then = self.lower_expr(then, span)?;
+    let (then_end, then_vars) = (self.block, self.vars());
+    let vars = self.scope.iter_mut().filter(|(.., mutable)| *mutable);
+    vars
+      .zip(&before)
+      .for_each(|((_, var, _), value)| *var = *value);
+    self.block = els_block;
+    let els = self.lower_expr(els, span)?;
+    let Some(ty) = Self::join_type(self.func.ty(then), self.func.ty(els)) else {
+      let msg = "Branches of `if` yield both numbers and tuples, or tuples of different sizes";
+      return Err(Diagnostic::error(span, msg).with_code("codegen"));
+    };
+    let els = self.convert(els, &ty).unwrap();
+    let els_end = std::mem::replace(&mut self.block, then_end);
+    let then = self.convert(then, &ty).unwrap();
+    self.block = els_end;
+    let join = self.func.add_block();
+    let value = self.func.add_param(join, ty);
+    let [then, els] = self.join(join, [(then, then_vars), (els, self.vars())]);
+    self.func.terminate(then_end, Terminator::Jump(then));
+    self.switch(Terminator::Jump(els), join);
+    Ok(value)
+  }
+
+  /// A call of a function of the module or of the prelude, or of `int`,
+  /// which rounds a number towards zero, or `float`, which converts an int
+  /// to a number.
+  fn lower_call(&mut self, name: &str, args: &[ExprAst], span: Span) -> Result<Value, Diagnostic> {
+    if self.scope.iter().any(|(local, ..)| local == name) {
+      return Err(unsupported("IR", "closures", span));
+    }
+    match (name, args.len()) {
+      ("float", 1) => return self.lower_as(&args[0], &Type::Num, span),
+      ("int", 1) => {
+        let arg = self.lower_scalar(&args[0], span)?;
+        return Ok(match self.func.ty(arg) {
+          Type::Num => self.push(Inst::Unary(UnaryOp::ToInt, arg), Type::Int),
+          _ => arg,
+        });
+      }
+      _ => {}
+    }
+    if !self.lowerer.functions.contains_key(name) {
+      let proto = prelude().items.into_iter().find_map(|item| match item {
+        Ast::Proto(proto) if proto.name == name => Some(proto),
+        _ => None,
+      });
+      if let Some(proto) = proto {
+        self.lowerer.declare_extern(&proto);
+      }
+    }
+    let Some(callee) = self.lowerer.functions.get(name).cloned() else {
+      let what = format!("the builtin `{}`", name);
+      return Err(unsupported("IR", &what, span));
+    };
+    let mut values = vec![];
+    let params = callee.params.iter().chain(std::iter::repeat(&Type::Num));
+    for (arg, ty) in args.iter().zip(params) {
+      values.push(self.lower_as(arg, ty, span)?);
+    }
+    Ok(self.push(Inst::Call(callee.name, values), callee.ret))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::verify;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  fn lower_src(src: &'static str) -> Result<Module, Vec<String>> {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let entry = Entry::of(&module).unwrap();
+    lower(&module, entry).map_err(|errors| errors.iter().map(|e| e.to_string()).collect())
+  }
+
+  #[test]
+  fn lower_module() {
+    let src = "def minmax(a, b) if a < b then (a, b) else (b, a);
+      def sign(x) { if x < 0 then return -1 else (); !(x == 0) && x > 1 };
+      let (lo, hi) = minmax(2, 1) in printd(hi % lo);";
+    let expected = "extern printd(num) -> num
+
+fn minmax(%0: num, %1: num) -> (num, num) {
+b0:
+  %2 = lt %0, %1
+  br %2, b1, b2
+b1:
+  %3 = tuple %0, %1
+  jump b3(%3)
+b2:
+  %4 = tuple %1, %0
+  jump b3(%4)
+b3(%5: (num, num)):
+  ret %5
+}
+
+fn sign(%0: num) -> num {
+b0:
+  %1 = num 0.0
+  %2 = lt %0, %1
+  br %2, b1, b2
+b1:
+  %3 = num 1.0
+  %4 = neg %3
+  ret %4
+b2:
+  %5 = num 0.0
+  jump b3(%5)
+b3(%6: num):
+  %7 = num 0.0
+  %8 = eq %0, %7
+  %9 = not %8
+  br %9, b4, b5(%9)
+b4:
+  %10 = num 1.0
+  %11 = gt %0, %10
+  jump b5(%11)
+b5(%12: bool):
+  %13 = frombool %12
+  ret %13
+}
+
+fn __anon_expr() -> num {
+b0:
+  %0 = num 2.0
+  %1 = num 1.0
+  %2 = call minmax(%0, %1)
+  %3 = elem %2, 0
+  %4 = elem %2, 1
+  %5 = rem %4, %3
+  %6 = call printd(%5)
+  ret %6
+}
+
+start __anon_expr
+";
+    let module = lower_src(src).unwrap();
+    assert_eq!(module.to_string(), expected);
+    assert_eq!(verify(&module).map_err(|e| e.len()), Ok(()));
+
+    assert_eq!(
+      lower_src("def f(x) x; \"s\"").unwrap_err(),
+      ["1:13: The IR backend doesn't support strings"]
+    );
+
+    // the quotient of ints truncates, that of doubles doesn't
+    let module = lower_src("def half(x: int): int x / 2; half(7)").unwrap();
+    assert!(module.to_string().contains("div %0, %1"), "{}", module);
+    let src = "def f(x) x / 2.0 + sqrt(x) / 2 + -x / (x * 0.5); f(7)";
+    assert!(lower_src(src).is_ok());
+  }
+
+  #[test]
+  fn lower_vars() {
+    // `y` joins the values of both branches, `z` passes through unchanged
+    let src = "def f(x) var y = 1.0, z = 2.0 in { if x > 0 then y = x else (); y + z };";
+    let module = lower_src(src).unwrap();
+    assert_eq!(verify(&module).map_err(|e| e.len()), Ok(()));
+    assert_eq!(
+      module.function("f").unwrap().to_string(),
+      "fn f(%0: num) -> num {
+b0:
+  %1 = num 1.0
+  %2 = num 2.0
+  %3 = num 0.0
+  %4 = gt %0, %3
+  br %4, b1, b2
+b1:
+  jump b3(%0, %0)
+b2:
+  %5 = num 0.0
+  jump b3(%5, %1)
+b3(%6: num, %7: num):
+  %8 = add %7, %2
+  ret %8
+}
+"
+    );
+
+    assert_eq!(
+      lower_src("def f(x) let y = x in y = 1;").unwrap_err(),
+      ["1:5: Cannot assign to immutable binding `y`"]
+    );
+  }
+
+  #[test]
+  fn lower_emit_ir() {
+    let src = "def twice(x) x * 2; twice(3)";
+    let mut backend = IrBackend::new();
+    let mut ir = vec![];
+    for item in ModuleAst::parse(&mut Lexer::new(Cursor::new(src)))
+      .unwrap()
+      .items
+    {
+      match item {
+        Ast::Func(func) => ir.push(backend.emit_ir(&func).unwrap()),
+        _ => unreachable!(),
+      }
+    }
+    assert_eq!(
+      ir,
+      [
+        "fn twice(%0: num) -> num {
+b0:
+  %1 = num 2.0
+  %2 = mul %0, %1
+  ret %2
+}
+",
+        "fn __anon_expr() -> num {
+b0:
+  %0 = num 3.0
+  %1 = call twice(%0)
+  ret %1
+}
+"
+      ]
+    );
+    assert_eq!(backend.take_module(Entry::TopLevel).start, ["__anon_expr"]);
+  }
+}
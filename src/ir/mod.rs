@@ -0,0 +1,562 @@
+mod cfg;
+mod dce;
+mod lower;
+mod sccp;
+mod verify;
+
+pub use cfg::Cfg;
+pub use dce::dce;
+pub use lower::{lower, Format, IrBackend};
+pub use sccp::sccp;
+pub use verify::verify;
+
+use crate::parser::BinOp;
+use crate::value::Precision;
+use std::fmt;
+
+/// Value - a virtual register, `%N` in the text of the IR.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct Value(pub u32);
+
+/// Block - a basic block of a function, `bN` in the text of the IR. The
+/// entry of a function is `b0`, whose parameters are those of the
+/// function.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct Block(pub u32);
+
+/// Type - what a value holds: a number, of the precision the program is
+/// compiled with, an int of 64 bits, a boolean, which conditions are, or a
+/// tuple of numbers and ints.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Type {
+  Num,
+  Int,
+  Bool,
+  Tuple(Vec<Type>),
+}
+
+/// UnaryOp - the operations on a number or an int. `ToInt` rounds a number
+/// towards zero, as `int` does, and `ToNum` converts an int to a number.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnaryOp {
+  Neg,
+  ToInt,
+  ToNum,
+}
+
+/// BinaryOp - the arithmetic of two numbers or of two ints, and the
+/// bitwise operators of ints. `Rem` of numbers is that of `fmod`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinaryOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Rem,
+  BitAnd,
+  BitOr,
+  Xor,
+  Shl,
+  Shr,
+}
+
+impl BinaryOp {
+  pub fn is_bitwise(&self) -> bool {
+    matches!(
+      self,
+      Self::BitAnd | Self::BitOr | Self::Xor | Self::Shl | Self::Shr
+    )
+  }
+
+  /// The operator of programs that this is.
+  pub fn bin_op(self) -> BinOp {
+    match self {
+      BinaryOp::Add => BinOp::Add,
+      BinaryOp::Sub => BinOp::Sub,
+      BinaryOp::Mul => BinOp::Mul,
+      BinaryOp::Div => BinOp::Div,
+      BinaryOp::Rem => BinOp::Rem,
+      BinaryOp::BitAnd => BinOp::BitAnd,
+      BinaryOp::BitOr => BinOp::BitOr,
+      BinaryOp::Xor => BinOp::Xor,
+      BinaryOp::Shl => BinOp::Shl,
+      BinaryOp::Shr => BinOp::Shr,
+    }
+  }
+
+  /// The result of the op on the numbers `x` and `y`, which it isn't
+  /// bitwise for.
+  pub fn apply(self, x: f64, y: f64) -> f64 {
+    match self {
+      BinaryOp::Add => x + y,
+      BinaryOp::Sub => x - y,
+      BinaryOp::Mul => x * y,
+      BinaryOp::Div => x / y,
+      BinaryOp::Rem => x % y,
+      _ => unreachable!("bitwise operators take ints"),
+    }
+  }
+}
+
+/// CmpOp - the comparisons of numbers, which are false for NaN but `Ne`,
+/// or of ints.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CmpOp {
+  Lt,
+  Gt,
+  Le,
+  Ge,
+  Eq,
+  Ne,
+}
+
+impl CmpOp {
+  pub fn apply<T: PartialOrd>(self, x: T, y: T) -> bool {
+    match self {
+      CmpOp::Lt => x < y,
+      CmpOp::Gt => x > y,
+      CmpOp::Le => x <= y,
+      CmpOp::Ge => x >= y,
+      CmpOp::Eq => x == y,
+      CmpOp::Ne => x != y,
+    }
+  }
+}
+
+/// Inst - an instruction, which defines one value. The arithmetic of ints
+/// is checked as in the interpreter: overflow, a zero divisor, shifts by
+/// less than 0 or more than 63 bits and numbers out of the range of ints
+/// fail, which stops the program with that error.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inst {
+  Num(f64),
+  Int(i64),
+  Bool(bool),
+  Unary(UnaryOp, Value),
+  Binary(BinaryOp, Value, Value),
+  Cmp(CmpOp, Value, Value),
+  Not(Value),
+  FromBool(Value), // 1.0 if true, else 0.0
+  Call(String, Vec<Value>),
+  Tuple(Vec<Value>),
+  Elem(Value, usize),
+}
+
+impl Inst {
+  /// The values the instruction uses, in order.
+  pub fn args(&self) -> Vec<Value> {
+    match self {
+      Inst::Num(_) | Inst::Int(_) | Inst::Bool(_) => vec![],
+      Inst::Unary(_, x) | Inst::Not(x) | Inst::FromBool(x) | Inst::Elem(x, _) => vec![*x],
+      Inst::Binary(_, x, y) | Inst::Cmp(_, x, y) => vec![*x, *y],
+      Inst::Call(_, args) | Inst::Tuple(args) => args.clone(),
+    }
+  }
+
+  /// The values the instruction uses, in order, to replace.
+  pub fn args_mut(&mut self) -> Vec<&mut Value> {
+    match self {
+      Inst::Num(_) | Inst::Int(_) | Inst::Bool(_) => vec![],
+      Inst::Unary(_, x) | Inst::Not(x) | Inst::FromBool(x) | Inst::Elem(x, _) => vec![x],
+      Inst::Binary(_, x, y) | Inst::Cmp(_, x, y) => vec![x, y],
+      Inst::Call(_, args) | Inst::Tuple(args) => args.iter_mut().collect(),
+    }
+  }
+
+  /// Whether the instruction may have an effect, and so can't be removed
+  /// or moved even when its value is unused: calls, and the arithmetic of
+  /// ints that may fail, given the `types` of the values.
+  pub fn has_effect(&self, types: &[Type]) -> bool {
+    let int = |x: &Value| types[x.0 as usize] == Type::Int;
+    match self {
+      Inst::Call(..) => true,
+      Inst::Unary(UnaryOp::Neg, x) => int(x),
+      Inst::Unary(UnaryOp::ToInt, _) => true,
+      Inst::Binary(BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::Xor, ..) => false,
+      Inst::Binary(_, x, _) => int(x),
+      _ => false,
+    }
+  }
+}
+
+/// Target - a block a terminator goes to, with the values of its
+/// parameters.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Target {
+  pub block: Block,
+  pub args: Vec<Value>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Terminator {
+  Jump(Target),
+  Branch(Value, Target, Target), // to the first if the boolean is true
+  Return(Value),
+}
+
+impl Terminator {
+  pub fn targets(&self) -> Vec<&Target> {
+    match self {
+      Terminator::Jump(target) => vec![target],
+      Terminator::Branch(_, then, els) => vec![then, els],
+      Terminator::Return(_) => vec![],
+    }
+  }
+
+  pub fn targets_mut(&mut self) -> Vec<&mut Target> {
+    match self {
+      Terminator::Jump(target) => vec![target],
+      Terminator::Branch(_, then, els) => vec![then, els],
+      Terminator::Return(_) => vec![],
+    }
+  }
+
+  /// The values the terminator uses, in order.
+  pub fn args(&self) -> Vec<Value> {
+    match self {
+      Terminator::Jump(target) => target.args.clone(),
+      Terminator::Branch(cond, then, els) => {
+        let args = then.args.iter().chain(&els.args).copied();
+        std::iter::once(*cond).chain(args).collect()
+      }
+      Terminator::Return(value) => vec![*value],
+    }
+  }
+
+  /// The values the terminator uses, in order, to replace.
+  pub fn args_mut(&mut self) -> Vec<&mut Value> {
+    match self {
+      Terminator::Jump(target) => target.args.iter_mut().collect(),
+      Terminator::Branch(cond, then, els) => {
+        let args = then.args.iter_mut().chain(&mut els.args);
+        std::iter::once(cond).chain(args).collect()
+      }
+      Terminator::Return(value) => vec![value],
+    }
+  }
+}
+
+/// BlockData - the parameters, instructions and terminator of a block,
+/// which has none until it is complete.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct BlockData {
+  pub params: Vec<Value>,
+  pub insts: Vec<(Value, Inst)>,
+  pub term: Option<Terminator>,
+}
+
+/// Function - a function of the program, or a top-level expression, which
+/// is named `__anon_expr`, then `__anon_expr.1` and so on.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+  pub name: String,
+  pub ret: Type,
+  pub types: Vec<Type>, // of each value
+  pub blocks: Vec<BlockData>,
+}
+
+impl Function {
+  /// A function taking parameters of the types `params`, with an entry
+  /// block that is empty.
+  pub fn new(name: &str, params: &[Type], ret: Type) -> Self {
+    let mut func = Function {
+      name: name.to_string(),
+      ret,
+      types: vec![],
+      blocks: vec![],
+    };
+    let entry = func.add_block();
+    for ty in params {
+      func.add_param(entry, ty.clone());
+    }
+    func
+  }
+
+  pub fn params(&self) -> &[Value] {
+    &self.blocks[0].params
+  }
+
+  pub fn ty(&self, value: Value) -> &Type {
+    &self.types[value.0 as usize]
+  }
+
+  pub fn block(&self, block: Block) -> &BlockData {
+    &self.blocks[block.0 as usize]
+  }
+
+  pub fn block_mut(&mut self, block: Block) -> &mut BlockData {
+    &mut self.blocks[block.0 as usize]
+  }
+
+  pub fn add_block(&mut self) -> Block {
+    self.blocks.push(BlockData::default());
+    Block(self.blocks.len() as u32 - 1)
+  }
+
+  fn new_value(&mut self, ty: Type) -> Value {
+    self.types.push(ty);
+    Value(self.types.len() as u32 - 1)
+  }
+
+  pub fn add_param(&mut self, block: Block, ty: Type) -> Value {
+    let value = self.new_value(ty);
+    self.block_mut(block).params.push(value);
+    value
+  }
+
+  /// Appends `inst`, which yields a `ty`, to `block`.
+  pub fn push(&mut self, block: Block, inst: Inst, ty: Type) -> Value {
+    let value = self.new_value(ty);
+    self.block_mut(block).insts.push((value, inst));
+    value
+  }
+
+  pub fn terminate(&mut self, block: Block, term: Terminator) {
+    self.block_mut(block).term = Some(term);
+  }
+
+  pub fn successors(&self, block: Block) -> Vec<Block> {
+    match &self.block(block).term {
+      Some(term) => term.targets().iter().map(|target| target.block).collect(),
+      None => vec![],
+    }
+  }
+
+  /// Removes the blocks the entry doesn't reach, as those following a
+  /// `return`, and numbers the blocks and values that remain anew, in
+  /// order.
+  pub fn remove_unreachable(&mut self) {
+    let mut reachable = Cfg::new(self).reverse_postorder().to_vec();
+    reachable.sort();
+    let mut blocks = vec![None; self.blocks.len()];
+    for (i, block) in reachable.iter().enumerate() {
+      blocks[block.0 as usize] = Some(Block(i as u32));
+    }
+    let mut values = vec![None; self.types.len()];
+    let mut types = vec![];
+    let mut number = |value: Value, types: &mut Vec<Type>| {
+      values[value.0 as usize] = Some(Value(types.len() as u32));
+      types.push(self.types[value.0 as usize].clone());
+    };
+    for &block in &reachable {
+      let data = self.block(block);
+      for &param in &data.params {
+        number(param, &mut types);
+      }
+      for (value, _) in &data.insts {
+        number(*value, &mut types);
+      }
+    }
+    let value = |value: &mut Value| *value = values[value.0 as usize].unwrap_or(*value);
+    let mut kept = vec![];
+    for block in reachable {
+      let mut data = std::mem::take(self.block_mut(block));
+      data.params.iter_mut().for_each(value);
+      for (result, inst) in &mut data.insts {
+        value(result);
+        inst.args_mut().into_iter().for_each(value);
+      }
+      if let Some(term) = &mut data.term {
+        let target = |target: &mut Target| {
+          target.block = blocks[target.block.0 as usize].unwrap();
+          target.args.iter_mut().for_each(value);
+        };
+        match term {
+          Terminator::Jump(to) => target(to),
+          Terminator::Branch(cond, then, els) => {
+            value(cond);
+            target(then);
+            target(els);
+          }
+          Terminator::Return(result) => value(result),
+        }
+      }
+      kept.push(data);
+    }
+    self.blocks = kept;
+    self.types = types;
+  }
+}
+
+/// Extern - a function the program declares, which is linked by `symbol`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Extern {
+  pub name: String,
+  pub symbol: String,
+  pub params: usize,
+}
+
+/// Module - the intermediate representation of a checked program, between
+/// the AST and the backends that take it: the bytecode compiler and the
+/// `ir` and `dot` outputs. The other backends lower the AST themselves.
+/// Its functions are made of basic blocks of instructions in SSA form, whose
+/// values are virtual registers, each defined once. Blocks take parameters
+/// in place of phi nodes, which the terminators of their predecessors pass
+/// them. The program runs the functions of `start` in order: `main`, or
+/// else its top-level expressions.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Module {
+  pub externs: Vec<Extern>,
+  pub functions: Vec<Function>,
+  pub start: Vec<String>,
+}
+
+impl Module {
+  pub fn function(&self, name: &str) -> Option<&Function> {
+    self.functions.iter().find(|func| func.name == name)
+  }
+
+  pub fn extern_(&self, name: &str) -> Option<&Extern> {
+    self.externs.iter().find(|ext| ext.name == name)
+  }
+}
+
+/// Optimizes the functions of `module`, verified, computing with numbers
+/// of `precision`: folds their constants, as [`sccp`] does, then removes
+/// the code that is no longer used or reached, as [`dce`] does, which
+/// cleans up after the other passes.
+pub fn optimize(module: &mut Module, precision: Precision) {
+  for func in &mut module.functions {
+    sccp(func, precision);
+    dce(func);
+  }
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "%{}", self.0)
+  }
+}
+
+impl fmt::Display for Block {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "b{}", self.0)
+  }
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Type::Num => write!(f, "num"),
+      Type::Int => write!(f, "int"),
+      Type::Bool => write!(f, "bool"),
+      Type::Tuple(elems) => {
+        let elems: Vec<_> = elems.iter().map(Type::to_string).collect();
+        write!(f, "({})", elems.join(", "))
+      }
+    }
+  }
+}
+
+/// The values `values`, separated by commas.
+fn list(values: &[Value]) -> String {
+  let values: Vec<_> = values.iter().map(|value| value.to_string()).collect();
+  values.join(", ")
+}
+
+impl fmt::Display for Target {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.args.is_empty() {
+      true => write!(f, "{}", self.block),
+      false => write!(f, "{}({})", self.block, list(&self.args)),
+    }
+  }
+}
+
+impl fmt::Display for Inst {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Inst::Num(n) => write!(f, "num {:?}", n),
+      Inst::Int(i) => write!(f, "int {}", i),
+      Inst::Bool(b) => write!(f, "bool {}", b),
+      Inst::Unary(op, x) => write!(f, "{} {}", format!("{:?}", op).to_lowercase(), x),
+      Inst::Binary(op, x, y) => write!(f, "{} {}, {}", format!("{:?}", op).to_lowercase(), x, y),
+      Inst::Cmp(op, x, y) => write!(f, "{} {}, {}", format!("{:?}", op).to_lowercase(), x, y),
+      Inst::Not(x) => write!(f, "not {}", x),
+      Inst::FromBool(x) => write!(f, "frombool {}", x),
+      Inst::Call(callee, args) => write!(f, "call {}({})", callee, list(args)),
+      Inst::Tuple(elems) => write!(f, "tuple {}", list(elems)),
+      Inst::Elem(tuple, i) => write!(f, "elem {}, {}", tuple, i),
+    }
+  }
+}
+
+impl fmt::Display for Terminator {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Terminator::Jump(target) => write!(f, "jump {}", target),
+      Terminator::Branch(cond, then, els) => write!(f, "br {}, {}, {}", cond, then, els),
+      Terminator::Return(value) => write!(f, "ret {}", value),
+    }
+  }
+}
+
+impl fmt::Display for Function {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let params: Vec<_> = self
+      .params()
+      .iter()
+      .map(|param| format!("{}: {}", param, self.ty(*param)))
+      .collect();
+    writeln!(
+      f,
+      "fn {}({}) -> {} {{",
+      self.name,
+      params.join(", "),
+      self.ret
+    )?;
+    for lines in self.block_lines() {
+      writeln!(f, "{}", lines[0])?;
+      for line in &lines[1..] {
+        writeln!(f, "  {}", line)?;
+      }
+    }
+    writeln!(f, "}}")
+  }
+}
+
+impl Function {
+  /// The text of each block: its label, then its instructions and its
+  /// terminator.
+  fn block_lines(&self) -> Vec<Vec<String>> {
+    let mut blocks = vec![];
+    for (i, data) in self.blocks.iter().enumerate() {
+      let params: Vec<_> = data
+        .params
+        .iter()
+        .map(|param| format!("{}: {}", param, self.ty(*param)))
+        .collect();
+      let mut lines = vec![match i == 0 || params.is_empty() {
+        true => format!("{}:", Block(i as u32)),
+        false => format!("{}({}):", Block(i as u32), params.join(", ")),
+      }];
+      for (value, inst) in &data.insts {
+        lines.push(format!("{} = {}", value, inst));
+      }
+      lines.push(match &data.term {
+        Some(term) => term.to_string(),
+        None => "...".to_string(),
+      });
+      blocks.push(lines);
+    }
+    blocks
+  }
+}
+
+impl fmt::Display for Module {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for ext in &self.externs {
+      let params = vec!["num"; ext.params].join(", ");
+      write!(f, "extern {}({}) -> num", ext.name, params)?;
+      match ext.symbol == ext.name {
+        true => writeln!(f)?,
+        false => writeln!(f, " = {:?}", ext.symbol)?,
+      }
+    }
+    for func in &self.functions {
+      writeln!(f)?;
+      write!(f, "{}", func)?;
+    }
+    writeln!(f, "\nstart {}", self.start.join(", "))
+  }
+}
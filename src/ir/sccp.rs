@@ -0,0 +1,284 @@
+use super::{Block, Cfg, Function, Inst, Terminator, Type, UnaryOp, Value};
+use crate::eval::{eval_int_bin, eval_unary};
+use crate::parser::UnOp;
+use crate::runtime::to_int;
+use crate::value::{self, Precision};
+use std::collections::HashMap;
+
+/// Lattice - what is known of a value: nothing while no code that runs
+/// defines it, that it is a constant, or that it varies.
+#[derive(Debug, Clone, Copy)]
+enum Lattice {
+  Unknown,
+  Num(f64),
+  Int(i64),
+  Bool(bool),
+  Varies,
+}
+
+impl Lattice {
+  fn same(self, other: Lattice) -> bool {
+    match (self, other) {
+      (Lattice::Num(x), Lattice::Num(y)) => x.to_bits() == y.to_bits(),
+      (Lattice::Int(x), Lattice::Int(y)) => x == y,
+      (Lattice::Bool(x), Lattice::Bool(y)) => x == y,
+      (Lattice::Unknown, Lattice::Unknown) | (Lattice::Varies, Lattice::Varies) => true,
+      _ => false,
+    }
+  }
+
+  /// What is known of a value that is either `self` or `other`.
+  fn meet(self, other: Lattice) -> Lattice {
+    match (self, other) {
+      (Lattice::Unknown, x) | (x, Lattice::Unknown) => x,
+      (x, y) if x.same(y) => x,
+      _ => Lattice::Varies,
+    }
+  }
+}
+
+/// Sparse conditional constant propagation: finds the values of `func`
+/// that are constants, computing with numbers of the given precision, and
+/// the edges that can't be taken since the branch that takes them is on a
+/// constant. Only the edges found to be taken make the values they pass
+/// to the parameters of a block vary, so constants flow through joins
+/// where the other ways in are never taken, as those of mutable variables.
+///
+/// The instructions found to yield constants become those constants, as do
+/// the parameters, whose uses get a constant defined at the start of their
+/// block, and the branches on constants become jumps. The blocks that
+/// are no longer reached are left for [`Function::remove_unreachable`].
+pub fn sccp(func: &mut Function, precision: Precision) {
+  let order = Cfg::new(func).reverse_postorder().to_vec();
+  let n = func.blocks.len();
+  let mut incoming = vec![vec![]; n]; // the edge and terminator of each
+  for (i, data) in func.blocks.iter().enumerate() {
+    if let Some(term) = &data.term {
+      for (edge, target) in term.targets().into_iter().enumerate() {
+        incoming[target.block.0 as usize].push((Block(i as u32), edge));
+      }
+    }
+  }
+  let tuples: HashMap<Value, Vec<Value>> = func
+    .blocks
+    .iter()
+    .flat_map(|data| &data.insts)
+    .filter_map(|(value, inst)| match inst {
+      Inst::Tuple(elems) => Some((*value, elems.clone())),
+      _ => None,
+    })
+    .collect();
+  let mut lattice = vec![Lattice::Unknown; func.types.len()];
+  for &param in func.params() {
+    lattice[param.0 as usize] = Lattice::Varies;
+  }
+  let mut taken: Vec<Vec<bool>> = func.blocks.iter().map(|_| vec![false; 2]).collect();
+  let mut reached = vec![false; n];
+  reached[0] = true;
+  let mut changed = true;
+  while changed {
+    changed = false;
+    let set = |lattice: &mut Vec<Lattice>, value: Value, known: Lattice| {
+      let old = &mut lattice[value.0 as usize];
+      !std::mem::replace(old, known).same(known)
+    };
+    for &block in &order {
+      if !reached[block.0 as usize] {
+        continue;
+      }
+      let data = func.block(block);
+      if block.0 != 0 {
+        for (j, &param) in data.params.iter().enumerate() {
+          let mut known = Lattice::Unknown;
+          for &(pred, edge) in &incoming[block.0 as usize] {
+            if taken[pred.0 as usize][edge] {
+              let term = func.block(pred).term.as_ref().unwrap();
+              let arg = term.targets()[edge].args[j];
+              known = known.meet(lattice[arg.0 as usize]);
+            }
+          }
+          changed |= set(&mut lattice, param, known);
+        }
+      }
+      for (value, inst) in &data.insts {
+        let known = evaluate(inst, &lattice, &tuples, precision);
+        changed |= set(&mut lattice, *value, known);
+      }
+      let edges = match data.term.as_ref().expect("the IR is verified") {
+        Terminator::Jump(_) => [true, false],
+        Terminator::Branch(cond, ..) => match lattice[cond.0 as usize] {
+          Lattice::Bool(b) => [b, !b],
+          Lattice::Unknown => [false, false],
+          _ => [true, true],
+        },
+        Terminator::Return(_) => [false, false],
+      };
+      let targets = data.term.as_ref().unwrap().targets();
+      for (edge, target) in targets.iter().enumerate() {
+        if edges[edge] && !taken[block.0 as usize][edge] {
+          taken[block.0 as usize][edge] = true;
+          reached[target.block.0 as usize] = true;
+          changed = true;
+        }
+      }
+    }
+  }
+  rewrite(func, &lattice, &reached);
+}
+
+/// What is known of the value of `inst`, given what is known of its
+/// operands. The arithmetic of ints that fails is left to fail when the
+/// program runs.
+fn evaluate(
+  inst: &Inst,
+  lattice: &[Lattice],
+  tuples: &HashMap<Value, Vec<Value>>,
+  precision: Precision,
+) -> Lattice {
+  let known = |value: &Value| lattice[value.0 as usize];
+  let num = |n: f64| Lattice::Num(precision.round(n));
+  let args: Vec<_> = inst.args().iter().map(known).collect();
+  if args.iter().any(|arg| matches!(arg, Lattice::Unknown)) && !matches!(inst, Inst::Elem(..)) {
+    return Lattice::Unknown;
+  }
+  let int = |val: Result<value::Value, String>| match val {
+    Ok(value::Value::Int(i)) => Lattice::Int(i),
+    _ => Lattice::Varies,
+  };
+  match (inst, &args[..]) {
+    (Inst::Num(n), _) => num(*n),
+    (Inst::Int(i), _) => Lattice::Int(*i),
+    (Inst::Bool(b), _) => Lattice::Bool(*b),
+    (Inst::Unary(UnaryOp::Neg, _), [Lattice::Num(x)]) => num(-x),
+    (Inst::Unary(UnaryOp::Neg, _), [Lattice::Int(i)]) => {
+      int(eval_unary(UnOp::Neg, value::Value::Int(*i)))
+    }
+    (Inst::Unary(UnaryOp::ToInt, _), [Lattice::Num(x)]) => {
+      to_int(*x).map_or(Lattice::Varies, Lattice::Int)
+    }
+    (Inst::Unary(UnaryOp::ToNum, _), [Lattice::Int(i)]) => num(*i as f64),
+    (Inst::Binary(op, ..), [Lattice::Num(x), Lattice::Num(y)]) => num(op.apply(*x, *y)),
+    (Inst::Binary(op, ..), [Lattice::Int(x), Lattice::Int(y)]) => {
+      int(eval_int_bin(op.bin_op(), *x, *y))
+    }
+    (Inst::Cmp(op, ..), [Lattice::Num(x), Lattice::Num(y)]) => Lattice::Bool(op.apply(x, y)),
+    (Inst::Cmp(op, ..), [Lattice::Int(x), Lattice::Int(y)]) => Lattice::Bool(op.apply(x, y)),
+    (Inst::Not(_), [Lattice::Bool(b)]) => Lattice::Bool(!b),
+    (Inst::FromBool(_), [Lattice::Bool(b)]) => num(*b as i32 as f64),
+    (Inst::Elem(tuple, i), _) => match tuples.get(tuple) {
+      Some(elems) => known(&elems[*i]),
+      None => Lattice::Varies,
+    },
+    _ => Lattice::Varies,
+  }
+}
+
+/// Replaces what is found constant in the blocks `reached` with constants.
+fn rewrite(func: &mut Function, lattice: &[Lattice], reached: &[bool]) {
+  let constant = |value: Value| match lattice[value.0 as usize] {
+    Lattice::Num(n) => Some((Inst::Num(n), Type::Num)),
+    Lattice::Int(i) => Some((Inst::Int(i), Type::Int)),
+    Lattice::Bool(b) => Some((Inst::Bool(b), Type::Bool)),
+    _ => None,
+  };
+  let mut replaced = HashMap::new();
+  for (i, _) in reached.iter().enumerate().filter(|(_, reached)| **reached) {
+    let block = Block(i as u32);
+    let mut consts = vec![];
+    for param in func.block(block).params.clone() {
+      if let (Some((inst, ty)), true) = (constant(param), i > 0) {
+        let value = func.new_value(ty);
+        consts.push((value, inst));
+        replaced.insert(param, value);
+      }
+    }
+    let data = func.block_mut(block);
+    for (value, inst) in &mut data.insts {
+      if let (Some((constant, _)), false) = (constant(*value), inst.args().is_empty()) {
+        *inst = constant;
+      }
+    }
+    data.insts.splice(0..0, consts);
+    if let Some(Terminator::Branch(cond, then, els)) = &data.term {
+      if let Some((Inst::Bool(b), _)) = constant(*cond) {
+        let target = if b { then.clone() } else { els.clone() };
+        data.term = Some(Terminator::Jump(target));
+      }
+    }
+  }
+  let replace = |value: &mut Value| *value = replaced.get(value).copied().unwrap_or(*value);
+  for data in &mut func.blocks {
+    for (_, inst) in &mut data.insts {
+      inst.args_mut().into_iter().for_each(replace);
+    }
+    if let Some(term) = &mut data.term {
+      term.args_mut().into_iter().for_each(replace);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::{lower, verify};
+  use crate::lexer::Lexer;
+  use crate::parser::ModuleAst;
+  use crate::session::Entry;
+  use std::io::Cursor;
+
+  fn optimized(src: &'static str, precision: Precision) -> String {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut module = lower(&module, Entry::TopLevel).unwrap();
+    for func in &mut module.functions {
+      sccp(func, precision);
+      func.remove_unreachable();
+    }
+    assert_eq!(verify(&module).map_err(|e| e.len()), Ok(()));
+    module.functions[0].to_string()
+  }
+
+  #[test]
+  fn sccp_constants() {
+    // `y` is 2 along the only way taken, so the second `if` folds too
+    let src =
+      "def f(x) var y = 1.0 in { if y > 0 then y = y + 1 else y = x; if y == 2 then x * y else x };";
+    assert_eq!(
+      optimized(src, Precision::F64),
+      "fn f(%0: num) -> num {
+b0:
+  %1 = num 1.0
+  %2 = num 0.0
+  %3 = bool true
+  jump b1
+b1:
+  %4 = num 1.0
+  %5 = num 2.0
+  jump b2(%5, %5)
+b2(%6: num, %7: num):
+  %8 = num 2.0
+  %9 = num 2.0
+  %10 = num 2.0
+  %11 = bool true
+  jump b3
+b3:
+  %12 = mul %0, %9
+  jump b4(%12)
+b4(%13: num):
+  ret %13
+}
+"
+    );
+
+    // the parameters vary, and what calls return, and F32 rounds
+    let src = "def f(x) if x > 0.1 + 0.2 then sin(x) else 0.1 + 0.2;";
+    assert!(optimized(src, Precision::F64).contains("= num 0.30000000000000004\n"));
+    assert!(optimized(src, Precision::F32).contains("= num 0.30000001192092896\n"));
+    assert!(optimized(src, Precision::F64).contains("br %"));
+
+    // ints fold as they compute, and what would fail is left to fail
+    let src = "def f(a: int): int if 2 + 3 > 4 then a + 5 * 6 + (1 << 64) else a;";
+    let optimized = optimized(src, Precision::F64);
+    assert!(optimized.contains("= int 30\n"));
+    assert!(optimized.contains("= shl %"));
+  }
+}
@@ -0,0 +1,338 @@
+use super::{Block, Cfg, Function, Inst, Module, Target, Terminator, Type, UnaryOp, Value};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use std::collections::HashSet;
+
+/// Checks that `module` is well formed, reporting every error in order:
+/// that functions and externs are named once and the functions of `start`
+/// exist, and that in each function
+///
+/// - every block ends with a terminator, which goes to blocks of the
+///   function other than the entry with values for each of their
+///   parameters;
+/// - every value is defined once, before its uses in its block, and in a
+///   block that dominates those of the others, unless the entry doesn't
+///   reach them;
+/// - operands have the types instructions and terminators expect, calls
+///   are of functions or externs with as many arguments as they take, and
+///   values have the types of what defines them.
+pub fn verify(module: &Module) -> Result<(), Vec<Diagnostic>> {
+  let mut errors = vec![];
+  let mut error =
+    |msg: String| errors.push(Diagnostic::error(Span::default(), msg).with_code("ir"));
+  let mut names = HashSet::new();
+  for ext in &module.externs {
+    if !names.insert(ext.name.as_str()) {
+      error(format!("`{}` is declared more than once", ext.name));
+    }
+  }
+  for func in &module.functions {
+    if !names.insert(func.name.as_str()) {
+      error(format!("`{}` is defined more than once", func.name));
+    }
+  }
+  for name in &module.start {
+    if module.function(name).is_none() {
+      error(format!("`start` names `{}`, which isn't defined", name));
+    }
+  }
+  for func in &module.functions {
+    let mut verifier = Verifier {
+      module,
+      func,
+      defs: vec![None; func.types.len()],
+      errors: vec![],
+    };
+    verifier.verify();
+    verifier.errors.into_iter().for_each(&mut error);
+  }
+  match errors.is_empty() {
+    true => Ok(()),
+    false => Err(errors),
+  }
+}
+
+/// Verifier - the state of the verification of one function.
+struct Verifier<'a> {
+  module: &'a Module,
+  func: &'a Function,
+  defs: Vec<Option<(Block, usize)>>, // the block of each value, and where
+  errors: Vec<String>,
+}
+
+impl Verifier<'_> {
+  fn error(&mut self, block: Block, msg: String) {
+    let msg = format!("In `{}`, {}: {}", self.func.name, block, msg);
+    self.errors.push(msg);
+  }
+
+  fn verify(&mut self) {
+    let func = self.func;
+    if func.blocks.is_empty() {
+      return self.errors.push(format!("`{}` has no blocks", func.name));
+    }
+    // the parameters of a block are at 0, and its instructions after them
+    for (i, data) in func.blocks.iter().enumerate() {
+      let block = Block(i as u32);
+      let params = data.params.iter().map(|&param| (param, 0));
+      let insts = data
+        .insts
+        .iter()
+        .enumerate()
+        .map(|(i, (value, _))| (*value, i + 1));
+      for (value, at) in params.chain(insts).collect::<Vec<_>>() {
+        match self.defs.get(value.0 as usize) {
+          None => self.error(block, format!("{} has no type", value)),
+          Some(Some(_)) => self.error(block, format!("{} is defined more than once", value)),
+          Some(None) => self.defs[value.0 as usize] = Some((block, at)),
+        }
+      }
+    }
+    for &param in func.params() {
+      self.expect_number(Block(0), param);
+    }
+    let dominators = Cfg::new(func).dominators();
+    for (i, data) in func.blocks.iter().enumerate() {
+      let block = Block(i as u32);
+      let dominators = dominators[i].as_ref();
+      let uses = |(i, (_, inst)): (usize, &(Value, Inst))| (inst.args(), i + 1);
+      let mut uses: Vec<_> = data.insts.iter().enumerate().map(uses).collect();
+      if let Some(term) = &data.term {
+        uses.push((term.args(), data.insts.len() + 1));
+      }
+      for (args, at) in uses {
+        for arg in args {
+          self.check_use(block, at, arg, dominators);
+        }
+      }
+      for (value, inst) in &data.insts {
+        self.check_inst(block, *value, inst);
+      }
+      match &data.term {
+        Some(term) => self.check_term(block, term),
+        None => self.error(block, "no terminator".to_string()),
+      }
+    }
+  }
+
+  /// Checks that `value`, used at `at` in `block`, is defined before.
+  fn check_use(&mut self, block: Block, at: usize, value: Value, dominators: Option<&Vec<bool>>) {
+    let def = match self.defs.get(value.0 as usize) {
+      Some(Some(def)) => *def,
+      _ => return self.error(block, format!("{} is used but never defined", value)),
+    };
+    let defined = match def {
+      (def, def_at) if def == block => def_at < at,
+      (def, _) => dominators.is_none_or(|doms| doms[def.0 as usize]),
+    };
+    if !defined {
+      self.error(block, format!("{} is used before it is defined", value));
+    }
+  }
+
+  fn ty(&self, value: Value) -> Option<Type> {
+    self.func.types.get(value.0 as usize).cloned()
+  }
+
+  fn expect(&mut self, block: Block, value: Value, ty: Type) {
+    match self.ty(value) {
+      Some(found) if found != ty => {
+        let msg = format!("{} is a {}, expected a {}", value, found, ty);
+        self.error(block, msg)
+      }
+      _ => {}
+    }
+  }
+
+  /// Checks that `value` is a number or an int, and yields which.
+  fn expect_number(&mut self, block: Block, value: Value) -> Type {
+    match self.ty(value) {
+      Some(ty @ (Type::Num | Type::Int)) => ty,
+      Some(found) => {
+        let msg = format!("{} is a {}, expected a num or an int", value, found);
+        self.error(block, msg);
+        Type::Num
+      }
+      None => Type::Num,
+    }
+  }
+
+  fn check_inst(&mut self, block: Block, value: Value, inst: &Inst) {
+    let ty = match inst {
+      Inst::Num(_) => Type::Num,
+      Inst::Int(_) => Type::Int,
+      Inst::Bool(_) => Type::Bool,
+      Inst::Unary(UnaryOp::Neg, x) => self.expect_number(block, *x),
+      Inst::Unary(UnaryOp::ToInt, x) => {
+        self.expect(block, *x, Type::Num);
+        Type::Int
+      }
+      Inst::Unary(UnaryOp::ToNum, x) => {
+        self.expect(block, *x, Type::Int);
+        Type::Num
+      }
+      Inst::Binary(op, x, y) => {
+        let ty = match op.is_bitwise() {
+          true => {
+            self.expect(block, *x, Type::Int);
+            Type::Int
+          }
+          false => self.expect_number(block, *x),
+        };
+        self.expect(block, *y, ty.clone());
+        ty
+      }
+      Inst::Cmp(_, x, y) => {
+        let ty = self.expect_number(block, *x);
+        self.expect(block, *y, ty);
+        Type::Bool
+      }
+      Inst::Not(x) => {
+        self.expect(block, *x, Type::Bool);
+        Type::Bool
+      }
+      Inst::FromBool(x) => {
+        self.expect(block, *x, Type::Bool);
+        Type::Num
+      }
+      Inst::Call(callee, args) => {
+        let signature = match (self.module.function(callee), self.module.extern_(callee)) {
+          (Some(func), _) => {
+            let params = func.params().iter().map(|&param| func.ty(param).clone());
+            Some((params.collect::<Vec<_>>(), func.ret.clone()))
+          }
+          (None, Some(ext)) => Some((vec![Type::Num; ext.params], Type::Num)),
+          (None, None) => None,
+        };
+        let Some((params, ret)) = signature else {
+          return self.error(
+            block,
+            format!("`{}` is neither a function nor an extern", callee),
+          );
+        };
+        if args.len() != params.len() {
+          let msg = format!(
+            "`{}` takes {} arguments, given {}",
+            callee,
+            params.len(),
+            args.len()
+          );
+          self.error(block, msg);
+        }
+        for (&arg, param) in args.iter().zip(params) {
+          self.expect(block, arg, param);
+        }
+        ret
+      }
+      Inst::Tuple(elems) => {
+        let elems = elems.iter().map(|&elem| self.expect_number(block, elem));
+        Type::Tuple(elems.collect())
+      }
+      Inst::Elem(tuple, i) => match self.ty(*tuple) {
+        Some(Type::Tuple(elems)) if *i < elems.len() => elems[*i].clone(),
+        Some(ty) => {
+          let msg = format!("{} is a {}, without element {}", tuple, ty, i);
+          self.error(block, msg);
+          Type::Num
+        }
+        None => Type::Num,
+      },
+    };
+    self.expect(block, value, ty);
+  }
+
+  fn check_term(&mut self, block: Block, term: &Terminator) {
+    match term {
+      Terminator::Branch(cond, ..) => self.expect(block, *cond, Type::Bool),
+      Terminator::Return(value) => self.expect(block, *value, self.func.ret.clone()),
+      Terminator::Jump(_) => {}
+    }
+    for target in term.targets() {
+      self.check_target(block, target);
+    }
+  }
+
+  fn check_target(&mut self, block: Block, target: &Target) {
+    let Some(data) = self.func.blocks.get(target.block.0 as usize) else {
+      return self.error(
+        block,
+        format!("goes to {}, which doesn't exist", target.block),
+      );
+    };
+    if target.block == Block(0) {
+      return self.error(block, "goes to the entry block".to_string());
+    }
+    if target.args.len() != data.params.len() {
+      let msg = format!(
+        "passes {} arguments to {}, which takes {}",
+        target.args.len(),
+        target.block,
+        data.params.len()
+      );
+      return self.error(block, msg);
+    }
+    for (&arg, &param) in target.args.iter().zip(&data.params) {
+      if let Some(ty) = self.ty(param) {
+        self.expect(block, arg, ty);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::{CmpOp, Extern};
+
+  fn errors(module: &Module) -> Vec<String> {
+    match verify(module) {
+      Ok(()) => vec![],
+      Err(errors) => errors.iter().map(|e| e.message.clone()).collect(),
+    }
+  }
+
+  #[test]
+  fn verify_functions() {
+    // f(x) = if x < 1 then x else g(x), with its blocks in the wrong places
+    let mut f = Function::new("f", &[Type::Num], Type::Num);
+    let x = f.params()[0];
+    let (then, els, join) = (f.add_block(), f.add_block(), f.add_block());
+    let one = f.push(Block(0), Inst::Num(1.0), Type::Num);
+    let cond = f.push(Block(0), Inst::Cmp(CmpOp::Lt, x, one), Type::Bool);
+    let target = |block, args| Target { block, args };
+    let branch = Terminator::Branch(cond, target(then, vec![]), target(els, vec![]));
+    f.terminate(Block(0), branch);
+    f.terminate(then, Terminator::Jump(target(join, vec![x])));
+    let y = f.push(els, Inst::Call("g".to_string(), vec![x]), Type::Num);
+    f.terminate(els, Terminator::Jump(target(join, vec![y])));
+    let result = f.add_param(join, Type::Num);
+    f.terminate(join, Terminator::Return(result));
+    let mut module = Module {
+      externs: vec![Extern {
+        name: "g".to_string(),
+        symbol: "g".to_string(),
+        params: 1,
+      }],
+      functions: vec![f],
+      start: vec![],
+    };
+    assert_eq!(errors(&module), Vec::<String>::new());
+
+    let f = &mut module.functions[0];
+    f.blocks[then.0 as usize].term = Some(Terminator::Jump(target(join, vec![y])));
+    f.blocks[els.0 as usize].insts[0].1 = Inst::Call("g".to_string(), vec![]);
+    f.blocks[join.0 as usize].term = Some(Terminator::Return(cond));
+    f.blocks[0].insts.swap(0, 1);
+    module.start.push("main".to_string());
+    assert_eq!(
+      errors(&module),
+      [
+        "`start` names `main`, which isn't defined",
+        "In `f`, b0: %1 is used before it is defined",
+        "In `f`, b1: %3 is used before it is defined",
+        "In `f`, b2: `g` takes 1 arguments, given 0",
+        "In `f`, b3: %2 is a bool, expected a num",
+      ]
+    );
+  }
+}
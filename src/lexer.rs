@@ -1,5 +1,12 @@
-use std::io::Read;
+use crate::diagnostic::{syntax_error, Diagnostic};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
 use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Clone, PartialOrd)]
 pub enum Token {
@@ -7,95 +14,449 @@ pub enum Token {
   Def,
   LeftParen,
   RightParen,
+  LeftBrace,
+  RightBrace,
+  LeftBracket,
+  RightBracket,
   Comma,
+  Dot,
+  DotDot,
   Semi,
   Add,
   Sub,
   Mul,
+  Div,
+  Rem,
   Less,
+  Greater,
+  LessEq,
+  GreaterEq,
+  Equal,
+  NotEqual,
+  And,
+  Or,
+  BitAnd,
+  BitOr,
+  Xor,
+  Shl,
+  Shr,
+  Not,
+  Question,
+  Colon,
+  Arrow,
+  Underscore,
+  Lambda,
+  Assign,
   Extern,
+  Binary,
+  Let,
+  Var,
+  Const,
+  Struct,
+  Match,
+  Return,
+  Try,
+  Catch,
+  Import,
+  Include,
+  In,
+  If,
+  Then,
+  Else,
+  True,
+  False,
   Identifier(String),
   Number(f64),
+  Int(i64),
+  Str(String),
+  Op(char), // any other ASCII punctuation, usable as a user-defined operator
+}
+
+/// Span - the 1-based line and column where a token starts. ASTs compare
+/// their spans too; [`ExprAst::without_spans`](crate::parser::ExprAst::without_spans)
+/// compares expressions by their structure alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+  pub line: usize,
+  pub col: usize,
+}
+
+impl fmt::Display for Span {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.line, self.col)
+  }
 }
 
 pub struct Lexer {
   peeker: Peekable<Box<dyn Iterator<Item = u8>>>,
-  tok_1st: Token,
-  tok_2nd: Token,
+  pos: Rc<Cell<Span>>, // of the byte last taken from the reader
+  tok_1st: Result<Token, Diagnostic>,
+  tok_2nd: Result<Token, Diagnostic>,
+  span_1st: Span,
+  span_2nd: Span,
+  span_cur: Span,       // of the token being lexed
+  span_last: Span,      // of the token last taken by the parser
+  after_dot: bool,      // `t.0.1` indexes twice, it's not `t` dot `0.1`
+  after_binary: bool,   // `def binary | 5` declares `|`, it's not bitwise or
+  dotdot: Option<Span>, // a `..` already taken while lexing a number
+  files: Vec<PathBuf>,  // the file being lexed, after the files including it
+  include: Option<Box<Lexer>>,
+  peeked: usize, // the tokens lexed ahead so far, 2 once the first is asked for
+  operators: Rc<RefCell<HashMap<char, i8>>>, // shared with the lexers of `include`s
 }
 
 impl Lexer {
   pub fn new(reader: impl Read + 'static) -> Lexer {
-    let bytes: Box<dyn Iterator<Item = u8>> = Box::new(reader.bytes().filter_map(Result::ok));
-    let mut lexer = Self {
+    Self::with_files(reader, vec![])
+  }
+
+  /// A lexer reading the file at `path`, which `include`s are relative to.
+  /// Those of other lexers are relative to the working directory.
+  pub fn open(path: &Path) -> io::Result<Lexer> {
+    let path = path.canonicalize()?;
+    Ok(Self::with_files(File::open(&path)?, vec![path]))
+  }
+
+  fn with_files(reader: impl Read + 'static, files: Vec<PathBuf>) -> Lexer {
+    let pos = Rc::new(Cell::new(Span { line: 1, col: 1 }));
+    let mut next = Span { line: 1, col: 1 };
+    let tracker = pos.clone();
+    // The peeker looks at most one byte ahead, so the last byte taken from
+    // the reader is always the one `self.peeker.next()` just returned.
+    let bytes: Box<dyn Iterator<Item = u8>> = Box::new(
+      BufReader::new(reader)
+        .bytes()
+        .filter_map(Result::ok)
+        .inspect(move |&b| {
+          tracker.set(next);
+          next = match b {
+            b'\n' => Span {
+              line: next.line + 1,
+              col: 1,
+            },
+            _ => Span {
+              col: next.col + 1,
+              ..next
+            },
+          };
+        }),
+    );
+    Self {
       peeker: bytes.peekable(),
-      tok_1st: Token::Eof,
-      tok_2nd: Token::Eof,
-    };
-    lexer.tok_1st = lexer.get_tok();
-    lexer.tok_2nd = lexer.get_tok();
-    lexer
+      pos,
+      tok_1st: Ok(Token::Eof),
+      tok_2nd: Ok(Token::Eof),
+      span_1st: Span::default(),
+      span_2nd: Span::default(),
+      span_cur: Span::default(),
+      span_last: Span::default(),
+      after_dot: false,
+      after_binary: false,
+      dotdot: None,
+      files,
+      include: None,
+      peeked: 0,
+      operators: Rc::default(),
+    }
+  }
+
+  /// The precedence of the operator `op` declared with `def binary`, in the
+  /// source read so far. Declarations in an included file hold after it.
+  pub fn precedence(&self, op: char) -> Option<i8> {
+    self.operators.borrow().get(&op).copied()
+  }
+
+  pub fn declare_operator(&mut self, op: char, precedence: i8) {
+    self.operators.borrow_mut().insert(op, precedence);
+  }
+
+  /// Lexes the two tokens to peek at, unless they are, on the first call
+  /// that looks at them rather than when the lexer is made, so that making
+  /// a lexer reads nothing. A token that fails to lex is kept as its error,
+  /// and skipped by [`Lexer::next_token`].
+  fn peek(&mut self) {
+    if self.peeked == 0 {
+      self.tok_1st = self.lex();
+      self.span_1st = self.span_cur;
+      self.peeked = 1;
+    }
+    if self.peeked == 1 {
+      self.tok_2nd = self.lex();
+      self.span_2nd = self.span_cur;
+      self.peeked = 2;
+    }
+  }
+
+  pub fn peek_first(&mut self) -> Result<&Token, Diagnostic> {
+    self.peek();
+    self.tok_1st.as_ref().map_err(Clone::clone)
+  }
+
+  pub fn peek_second(&mut self) -> Result<&Token, Diagnostic> {
+    self.peek();
+    self.tok_2nd.as_ref().map_err(Clone::clone)
+  }
+
+  /// The position of the first peeked token.
+  pub fn span(&mut self) -> Span {
+    self.peek();
+    self.span_1st
   }
 
-  pub fn peek_first(&self) -> &Token {
-    &self.tok_1st
+  /// The position of the token last taken by [`Lexer::next_token`].
+  pub fn last_span(&self) -> Span {
+    self.span_last
   }
 
-  pub fn peek_second(&self) -> &Token {
-    &self.tok_2nd
+  /// Takes the first peeked token, or the error it failed to lex with. The
+  /// lexer resumes after a failed token either way.
+  pub fn next_token(&mut self) -> Result<Token, Diagnostic> {
+    self.peek();
+    let next = self.lex();
+    self.span_last = self.span_1st;
+    self.span_1st = self.span_2nd;
+    self.span_2nd = self.span_cur;
+    let second = std::mem::replace(&mut self.tok_2nd, next);
+    std::mem::replace(&mut self.tok_1st, second)
   }
 
-  pub fn next_token(&mut self) -> Token {
-    let tmp = self.tok_2nd.clone();
-    self.tok_2nd = self.get_tok();
-    let res = self.tok_1st.clone();
-    self.tok_1st = tmp;
-    res
+  /// The next token, taken from the file of an `include` until it runs out.
+  /// The tokens of an included file keep their positions in that file.
+  fn lex(&mut self) -> Result<Token, Diagnostic> {
+    if let Some(include) = &mut self.include {
+      if include.peek_first() != Ok(&Token::Eof) {
+        self.span_cur = include.span();
+        return include.next_token();
+      }
+      self.include = None;
+    }
+    match self.get_tok()? {
+      Token::Include => {
+        let Token::Str(path) = self.get_tok()? else {
+          return Err(syntax_error(
+            self.span_cur,
+            "Expected file name after `include`",
+          ));
+        };
+        let span = self.span_cur;
+        let dir = self
+          .files
+          .last()
+          .and_then(|f| f.parent())
+          .unwrap_or(Path::new(""));
+        let target = dir
+          .join(&path)
+          .canonicalize()
+          .map_err(|e| syntax_error(span, format!("Cannot include `{}`: {}", path, e)))?;
+        if self.files.contains(&target) {
+          return Err(syntax_error(span, format!("`{}` includes itself", path)));
+        }
+        let file = File::open(&target)
+          .map_err(|e| syntax_error(span, format!("Cannot include `{}`: {}", path, e)))?;
+        let mut files = self.files.clone();
+        files.push(target);
+        let mut include = Self::with_files(file, files);
+        include.operators = self.operators.clone();
+        self.include = Some(Box::new(include));
+        self.lex()
+      }
+      tok => Ok(tok),
+    }
   }
 
-  fn get_tok(&mut self) -> Token {
+  fn get_tok(&mut self) -> Result<Token, Diagnostic> {
+    if let Some(span) = self.dotdot.take() {
+      self.span_cur = span;
+      return Ok(Token::DotDot);
+    }
     let peeked = self.peeker.next();
-    match peeked {
+    self.span_cur = self.pos.get();
+    let tok = match peeked {
       None => Token::Eof,
       Some(b'(') => Token::LeftParen,
       Some(b')') => Token::RightParen,
+      Some(b'{') => Token::LeftBrace,
+      Some(b'}') => Token::RightBrace,
+      Some(b'[') => Token::LeftBracket,
+      Some(b']') => Token::RightBracket,
       Some(b',') => Token::Comma,
+      Some(b'.') if self.peeker.next_if_eq(&b'.').is_some() => Token::DotDot,
+      Some(b'.') => Token::Dot,
       Some(b';') => Token::Semi,
       Some(b'+') => Token::Add,
+      Some(b'-') if self.peeker.next_if_eq(&b'>').is_some() => Token::Arrow,
       Some(b'-') => Token::Sub,
       Some(b'*') => Token::Mul,
+      Some(b'/') => Token::Div,
+      Some(b'%') => Token::Rem,
+      Some(b'<') if self.peeker.next_if_eq(&b'<').is_some() => Token::Shl,
+      Some(b'<') if self.peeker.next_if_eq(&b'=').is_some() => Token::LessEq,
       Some(b'<') => Token::Less,
+      Some(b'>') if self.peeker.next_if_eq(&b'>').is_some() => Token::Shr,
+      Some(b'>') if self.peeker.next_if_eq(&b'=').is_some() => Token::GreaterEq,
+      Some(b'>') => Token::Greater,
+      Some(b'=') if self.peeker.next_if_eq(&b'=').is_some() => Token::Equal,
+      Some(b'=') => Token::Assign,
+      Some(b'!') if self.peeker.next_if_eq(&b'=').is_some() => Token::NotEqual,
+      Some(b'!') => Token::Not,
+      Some(b'&') if self.peeker.next_if_eq(&b'&').is_some() => Token::And,
+      Some(b'&') => Token::BitAnd,
+      Some(b'|') if self.peeker.next_if_eq(&b'|').is_some() => Token::Or,
+      Some(b'|') if self.after_binary => Token::Op('|'),
+      Some(b'|') => Token::BitOr,
+      Some(b'?') => Token::Question,
+      Some(b':') => Token::Colon,
+      Some(b'_') => Token::Underscore,
+      Some(b'\\') => Token::Lambda,
       Some(b'#') => {
-        while let Some(_) = self.peeker.next_if(|x| *x != b'\n') {}
+        while self.peeker.next_if(|x| *x != b'\n').is_some() {}
         self.peeker.next();
-        self.get_tok()
+        return self.get_tok();
       }
+      Some(b'"') => self.get_str()?,
       Some(c) if c.is_ascii_whitespace() => {
-        while let Some(_) = self.peeker.next_if(u8::is_ascii_whitespace) {}
-        self.get_tok()
+        while self.peeker.next_if(u8::is_ascii_whitespace).is_some() {}
+        return self.get_tok();
       }
       Some(c) if c.is_ascii_alphabetic() => {
+        // `_` alone is the wildcard, but may go on a name like `area_of`
         let mut ident = vec![c];
-        while let Some(x) = self.peeker.next_if(u8::is_ascii_alphanumeric) {
+        while let Some(x) = self
+          .peeker
+          .next_if(|x| x.is_ascii_alphanumeric() || *x == b'_')
+        {
           ident.push(x);
         }
         let ident = String::from_utf8(ident).unwrap();
         match ident.as_str() {
           "def" => Token::Def,
           "extern" => Token::Extern,
+          "binary" => Token::Binary,
+          "let" => Token::Let,
+          "var" => Token::Var,
+          "const" => Token::Const,
+          "struct" => Token::Struct,
+          "match" => Token::Match,
+          "return" => Token::Return,
+          "try" => Token::Try,
+          "catch" => Token::Catch,
+          "import" => Token::Import,
+          "include" => Token::Include,
+          "xor" => Token::Xor,
+          "in" => Token::In,
+          "if" => Token::If,
+          "then" => Token::Then,
+          "else" => Token::Else,
+          "inf" => Token::Number(f64::INFINITY),
+          "nan" => Token::Number(f64::NAN),
+          "true" => Token::True,
+          "false" => Token::False,
           _ => Token::Identifier(ident),
         }
       }
       Some(c) if c.is_ascii_digit() => {
         let mut num = vec![c];
-        while let Some(x) = self.peeker.next_if(|x| x.is_ascii_digit() || *x == b'.') {
+        while let Some(x) = self.peeker.next_if(u8::is_ascii_digit) {
           num.push(x);
         }
-        let num: f64 = String::from_utf8(num).unwrap().parse().unwrap();
-        Token::Number(num)
+        if !self.after_dot && self.peeker.next_if_eq(&b'.').is_some() {
+          let dot = self.pos.get();
+          match self.peeker.next_if_eq(&b'.') {
+            // `1..10` is a range, not `1.` followed by `.10`
+            Some(_) => self.dotdot = Some(dot),
+            None => {
+              num.push(b'.');
+              while let Some(x) = self.peeker.next_if(u8::is_ascii_digit) {
+                num.push(x);
+              }
+            }
+          }
+        }
+        let num = String::from_utf8(num).unwrap();
+        match num.contains('.') {
+          true => Token::Number(num.parse().unwrap()),
+          false => Token::Int(num.parse().expect("Integer literal out of range")),
+        }
+      }
+      Some(c) => Token::Op(c as char),
+    };
+    self.after_dot = tok == Token::Dot;
+    self.after_binary = tok == Token::Binary;
+    Ok(tok)
+  }
+
+  /// Lexes the rest of a string literal after its opening `"`.
+  fn get_str(&mut self) -> Result<Token, Diagnostic> {
+    let mut bytes = vec![];
+    loop {
+      match self.peeker.next() {
+        None => return Err(syntax_error(self.span_cur, "Unterminated string literal")),
+        Some(b'"') => break,
+        Some(b'\\') => match self.peeker.next() {
+          Some(b'n') => bytes.push(b'\n'),
+          Some(b't') => bytes.push(b'\t'),
+          Some(b'0') => bytes.push(b'\0'),
+          Some(c @ (b'"' | b'\\')) => bytes.push(c),
+          Some(b'u') => {
+            let c = self.get_unicode_escape()?;
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+          }
+          Some(c) => {
+            self.skip_str();
+            let msg = format!("Unknown escape sequence `\\{}`", c as char);
+            return Err(syntax_error(self.span_cur, msg));
+          }
+          None => return Err(syntax_error(self.span_cur, "Unterminated string literal")),
+        },
+        Some(c) => bytes.push(c),
       }
-      _ => Token::Def,
     }
+    Ok(Token::Str(
+      String::from_utf8(bytes).expect("String literal is not valid UTF-8"),
+    ))
+  }
+
+  /// Skips the rest of a malformed string literal, so that lexing resumes
+  /// after it.
+  fn skip_str(&mut self) {
+    while let Some(c) = self.peeker.next() {
+      match c {
+        b'"' => break,
+        b'\\' => {
+          self.peeker.next();
+        }
+        _ => (),
+      }
+    }
+  }
+
+  /// Lexes the `{1F600}` of a `\u{1F600}` escape: one to six hex digits
+  /// naming a Unicode scalar value, so surrogates are rejected.
+  fn get_unicode_escape(&mut self) -> Result<char, Diagnostic> {
+    if self.peeker.next() != Some(b'{') {
+      return Err(syntax_error(self.span_cur, "Expected `{` after `\\u`"));
+    }
+    let mut digits = String::new();
+    loop {
+      match self.peeker.next() {
+        Some(b'}') => break,
+        Some(c) if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c as char),
+        _ => {
+          return Err(syntax_error(
+            self.span_cur,
+            "Expected 1 to 6 hex digits and `}` in `\\u{...}`",
+          ))
+        }
+      }
+    }
+    let code = u32::from_str_radix(&digits, 16)
+      .map_err(|_| syntax_error(self.span_cur, "Expected hex digits in `\\u{}`"))?;
+    char::from_u32(code).ok_or_else(|| {
+      syntax_error(
+        self.span_cur,
+        format!("Invalid code point `\\u{{{}}}`", digits),
+      )
+    })
   }
 }
 
@@ -109,44 +470,307 @@ mod tests {
     let source = b"";
     let reader = Cursor::new(&source[..]);
     let mut lexer = Lexer::new(reader);
-    assert_eq!(lexer.next_token(), Token::Eof);
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
   }
 
   #[test]
   fn token_parenthese_comma() {
     let source = b"(,)";
     let mut lexer = Lexer::new(Cursor::new(&source[..]));
-    assert_eq!(lexer.next_token(), Token::LeftParen);
-    assert_eq!(lexer.next_token(), Token::Comma);
-    assert_eq!(lexer.next_token(), Token::RightParen);
+    assert_eq!(lexer.next_token().unwrap(), Token::LeftParen);
+    assert_eq!(lexer.next_token().unwrap(), Token::Comma);
+    assert_eq!(lexer.next_token().unwrap(), Token::RightParen);
   }
 
   #[test]
+  fn token_arithmetic() {
+    let source = "+ - * / %";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::Add);
+    assert_eq!(lexer.next_token().unwrap(), Token::Sub);
+    assert_eq!(lexer.next_token().unwrap(), Token::Mul);
+    assert_eq!(lexer.next_token().unwrap(), Token::Div);
+    assert_eq!(lexer.next_token().unwrap(), Token::Rem);
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_comparisons() {
+    let source = "< <= > >= == != = !";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::Less);
+    assert_eq!(lexer.next_token().unwrap(), Token::LessEq);
+    assert_eq!(lexer.next_token().unwrap(), Token::Greater);
+    assert_eq!(lexer.next_token().unwrap(), Token::GreaterEq);
+    assert_eq!(lexer.next_token().unwrap(), Token::Equal);
+    assert_eq!(lexer.next_token().unwrap(), Token::NotEqual);
+    assert_eq!(lexer.next_token().unwrap(), Token::Assign);
+    assert_eq!(lexer.next_token().unwrap(), Token::Not);
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_logical() {
+    let source = "&& || & | xor << >> <<= ^";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::And);
+    assert_eq!(lexer.next_token().unwrap(), Token::Or);
+    assert_eq!(lexer.next_token().unwrap(), Token::BitAnd);
+    assert_eq!(lexer.next_token().unwrap(), Token::BitOr);
+    assert_eq!(lexer.next_token().unwrap(), Token::Xor);
+    assert_eq!(lexer.next_token().unwrap(), Token::Shl);
+    assert_eq!(lexer.next_token().unwrap(), Token::Shr);
+    assert_eq!(lexer.next_token().unwrap(), Token::Shl);
+    assert_eq!(lexer.next_token().unwrap(), Token::Assign);
+    assert_eq!(lexer.next_token().unwrap(), Token::Op('^'));
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_braces() {
+    let source = "{;}";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::LeftBrace);
+    assert_eq!(lexer.next_token().unwrap(), Token::Semi);
+    assert_eq!(lexer.next_token().unwrap(), Token::RightBrace);
+  }
+
+  #[test]
+  fn token_brackets() {
+    let source = "[1]";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::LeftBracket);
+    assert_eq!(lexer.next_token().unwrap(), Token::Int(1));
+    assert_eq!(lexer.next_token().unwrap(), Token::RightBracket);
+  }
+
+  #[test]
+  #[allow(clippy::approx_constant)]
   fn token_numbers() {
-    let source = "3.14";
-    let mut lexer = Lexer::new(Cursor::new(&source[..]));
-    assert_eq!(lexer.next_token(), Token::Number(3.14_f64));
-    assert_eq!(lexer.next_token(), Token::Eof);
+    let source = "3.14 42 1. inf nan";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::Number(3.14_f64));
+    assert_eq!(lexer.next_token().unwrap(), Token::Int(42));
+    assert_eq!(lexer.next_token().unwrap(), Token::Number(1.0));
+    assert_eq!(lexer.next_token().unwrap(), Token::Number(f64::INFINITY));
+    assert!(matches!(lexer.next_token().unwrap(), Token::Number(n) if n.is_nan()));
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_tuple_index() {
+    let source = "t.0.1 1.5";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Identifier("t".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Dot);
+    assert_eq!(lexer.next_token().unwrap(), Token::Int(0));
+    assert_eq!(lexer.next_token().unwrap(), Token::Dot);
+    assert_eq!(lexer.next_token().unwrap(), Token::Int(1));
+    assert_eq!(lexer.next_token().unwrap(), Token::Number(1.5));
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_match() {
+    let source = "match x { 1..10 -> 2.5.., _ -> 1 }";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    let mut spans = vec![];
+    while lexer.peek_first().unwrap() != &Token::Eof {
+      let span = lexer.span();
+      spans.push((lexer.next_token().unwrap(), span.col));
+    }
+    assert_eq!(
+      spans,
+      vec![
+        (Token::Match, 1),
+        (Token::Identifier("x".to_string()), 7),
+        (Token::LeftBrace, 9),
+        (Token::Int(1), 11),
+        (Token::DotDot, 12),
+        (Token::Int(10), 14),
+        (Token::Arrow, 17),
+        (Token::Number(2.5), 20),
+        (Token::DotDot, 23),
+        (Token::Comma, 25),
+        (Token::Underscore, 27),
+        (Token::Arrow, 29),
+        (Token::Int(1), 32),
+        (Token::RightBrace, 34),
+      ]
+    );
+  }
+
+  #[test]
+  fn token_strings() {
+    let source = r#""hello" "a\"b\n""#;
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::Str("hello".to_string()));
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Str("a\"b\n".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_unicode_escapes() {
+    let source = r#""\u{48}\u{e9}\u{1F600}!" "\u{10FFFF}""#;
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Str("H\u{e9}\u{1F600}!".to_string())
+    );
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Str("\u{10FFFF}".to_string())
+    );
+  }
+
+  #[test]
+  fn token_unicode_surrogate() {
+    let err = Lexer::new(Cursor::new(r#""\u{D800}""#))
+      .next_token()
+      .unwrap_err();
+    assert_eq!(err.message, "Invalid code point `\\u{D800}`");
   }
 
   #[test]
   fn token_identifiers() {
-    let source = "foo def bar extern";
-    let mut lexer = Lexer::new(Cursor::new(&source[..]));
-    assert_eq!(lexer.next_token(), Token::Identifier("foo".to_string()));
-    assert_eq!(lexer.next_token(), Token::Def);
-    assert_eq!(lexer.next_token(), Token::Identifier("bar".to_string()));
-    assert_eq!(lexer.next_token(), Token::Extern);
-    assert_eq!(lexer.next_token(), Token::Eof);
+    let source = "foo def bar extern let var const struct match return try catch import in a_1 _";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Identifier("foo".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Def);
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Identifier("bar".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Extern);
+    assert_eq!(lexer.next_token().unwrap(), Token::Let);
+    assert_eq!(lexer.next_token().unwrap(), Token::Var);
+    assert_eq!(lexer.next_token().unwrap(), Token::Const);
+    assert_eq!(lexer.next_token().unwrap(), Token::Struct);
+    assert_eq!(lexer.next_token().unwrap(), Token::Match);
+    assert_eq!(lexer.next_token().unwrap(), Token::Return);
+    assert_eq!(lexer.next_token().unwrap(), Token::Try);
+    assert_eq!(lexer.next_token().unwrap(), Token::Catch);
+    assert_eq!(lexer.next_token().unwrap(), Token::Import);
+    assert_eq!(lexer.next_token().unwrap(), Token::In);
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Identifier("a_1".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Underscore);
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_if_then_else() {
+    let source = "if x then y else z";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::If);
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Identifier("x".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Then);
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Identifier("y".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Else);
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Identifier("z".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_user_op() {
+    let source = "def binary | 5";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::Def);
+    assert_eq!(lexer.next_token().unwrap(), Token::Binary);
+    assert_eq!(lexer.next_token().unwrap(), Token::Op('|'));
+    assert_eq!(lexer.next_token().unwrap(), Token::Int(5));
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
+  }
+
+  #[test]
+  fn token_spans() {
+    let source = "def foo(x)\n  x + 10 # done\n;";
+    let mut lexer = Lexer::new(Cursor::new(source));
+    let mut spans = vec![];
+    while lexer.peek_first().unwrap() != &Token::Eof {
+      let span = lexer.span();
+      spans.push((lexer.next_token().unwrap(), span.line, span.col));
+    }
+    assert_eq!(
+      spans,
+      vec![
+        (Token::Def, 1, 1),
+        (Token::Identifier("foo".to_string()), 1, 5),
+        (Token::LeftParen, 1, 8),
+        (Token::Identifier("x".to_string()), 1, 9),
+        (Token::RightParen, 1, 10),
+        (Token::Identifier("x".to_string()), 2, 3),
+        (Token::Add, 2, 5),
+        (Token::Int(10), 2, 7),
+        (Token::Semi, 3, 1),
+      ]
+    );
+  }
+
+  #[test]
+  fn token_include() {
+    let dir = std::env::temp_dir().join(format!("kale-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("inc")).unwrap();
+    std::fs::write(dir.join("main.kale"), "include \"inc/a.kale\" 3").unwrap();
+    std::fs::write(dir.join("inc/a.kale"), "1 include \"b.kale\"").unwrap();
+    std::fs::write(dir.join("inc/b.kale"), "\n  2").unwrap();
+    let mut lexer = Lexer::open(&dir.join("main.kale")).unwrap();
+    let mut spans = vec![];
+    while lexer.peek_first().unwrap() != &Token::Eof {
+      let span = lexer.span();
+      spans.push((lexer.next_token().unwrap(), span.line, span.col));
+    }
+    assert_eq!(
+      spans,
+      vec![
+        (Token::Int(1), 1, 1),
+        (Token::Int(2), 2, 3),
+        (Token::Int(3), 1, 22),
+      ]
+    );
+  }
+
+  #[test]
+  fn token_include_cycle() {
+    let dir = std::env::temp_dir().join(format!("kale-include-cycle-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("inc")).unwrap();
+    std::fs::write(dir.join("main.kale"), "include \"inc/a.kale\"").unwrap();
+    std::fs::write(dir.join("inc/a.kale"), "include \"../main.kale\"").unwrap();
+    let mut lexer = Lexer::open(&dir.join("main.kale")).unwrap();
+    let err = lexer.next_token().unwrap_err();
+    assert_eq!(err.message, "`../main.kale` includes itself");
   }
 
   #[test]
   fn token_comment() {
     let source = "def foo  # this is commment \n 42";
-    let mut lexer = Lexer::new(Cursor::new(&source[..]));
-    assert_eq!(lexer.next_token(), Token::Def);
-    assert_eq!(lexer.next_token(), Token::Identifier("foo".to_string()));
-    assert_eq!(lexer.next_token(), Token::Number(42.0_f64));
-    assert_eq!(lexer.next_token(), Token::Eof);
+    let mut lexer = Lexer::new(Cursor::new(source));
+    assert_eq!(lexer.next_token().unwrap(), Token::Def);
+    assert_eq!(
+      lexer.next_token().unwrap(),
+      Token::Identifier("foo".to_string())
+    );
+    assert_eq!(lexer.next_token().unwrap(), Token::Int(42));
+    assert_eq!(lexer.next_token().unwrap(), Token::Eof);
   }
 }
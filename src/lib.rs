@@ -0,0 +1,29 @@
+#![allow(
+  clippy::match_ref_pats,
+  clippy::enum_variant_names,
+  clippy::result_large_err,
+  clippy::new_without_default
+)]
+
+pub mod analysis;
+pub mod bytecode;
+pub mod check;
+pub mod codegen;
+pub mod consts;
+pub mod diagnostic;
+pub mod eval;
+pub mod ir;
+pub mod lexer;
+pub mod lint;
+pub mod loader;
+pub mod parser;
+pub mod passes;
+pub mod prelude;
+pub mod resolve;
+pub mod runtime;
+pub mod session;
+pub mod typeck;
+pub mod value;
+pub mod vm;
+
+pub use check::{check, CheckedModule};
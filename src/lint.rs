@@ -0,0 +1,799 @@
+use crate::analysis::{
+  dead_functions, is_exhaustive, unconditional_recursion, unreachable_arms, Unreachable,
+};
+use crate::consts::eval_const;
+use crate::diagnostic::{Diagnostic, Severity, Suggestion};
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, Pattern};
+use crate::value::{truthy, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Lint - a kind of suspicious code the linter warns about. Each one can be
+/// configured on its own, by the name it is shown with.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Lint {
+  UnusedParam,
+  UnusedBinding,
+  Shadowing,
+  DeadFunction,
+  InfiniteRecursion,
+  DivisionByZero,
+  ConstantComparison,
+  Overflow,
+  NonExhaustiveMatch,
+  UnreachableArm,
+  NamingStyle,
+}
+
+impl Lint {
+  pub const ALL: &'static [Lint] = &[
+    Lint::UnusedParam,
+    Lint::UnusedBinding,
+    Lint::Shadowing,
+    Lint::DeadFunction,
+    Lint::InfiniteRecursion,
+    Lint::DivisionByZero,
+    Lint::ConstantComparison,
+    Lint::Overflow,
+    Lint::NonExhaustiveMatch,
+    Lint::UnreachableArm,
+    Lint::NamingStyle,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::UnusedParam => "unused-param",
+      Self::UnusedBinding => "unused-binding",
+      Self::Shadowing => "shadowing",
+      Self::DeadFunction => "dead-function",
+      Self::InfiniteRecursion => "infinite-recursion",
+      Self::DivisionByZero => "division-by-zero",
+      Self::ConstantComparison => "constant-comparison",
+      Self::Overflow => "overflow",
+      Self::NonExhaustiveMatch => "non-exhaustive-match",
+      Self::UnreachableArm => "unreachable-arm",
+      Self::NamingStyle => "naming-style",
+    }
+  }
+
+  /// The level of the lint unless configured: the optional ones, about
+  /// style, are allowed.
+  pub fn default_level(self) -> LintLevel {
+    match self {
+      Self::NamingStyle => LintLevel::Allow,
+      _ => LintLevel::Warn,
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.iter().copied().find(|lint| lint.name() == name)
+  }
+}
+
+/// LintLevel - what to do about a lint: nothing, warn, or report an error
+/// that stops the item from running. Lints warn unless configured, except
+/// for the optional ones.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LintLevel {
+  Allow,
+  Warn,
+  Deny,
+}
+
+impl LintLevel {
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Allow => "allow",
+      Self::Warn => "warn",
+      Self::Deny => "deny",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    [Self::Allow, Self::Warn, Self::Deny]
+      .into_iter()
+      .find(|level| level.name() == name)
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+  pub lint: Lint,
+  pub span: Span,
+  pub msg: String,
+  pub notes: Vec<String>,
+  pub suggestion: Option<Suggestion>,
+}
+
+impl Warning {
+  pub fn new(lint: Lint, span: Span, msg: String) -> Self {
+    Self {
+      lint,
+      span,
+      msg,
+      notes: vec![],
+      suggestion: None,
+    }
+  }
+
+  pub fn with_note(mut self, note: String) -> Self {
+    self.notes.push(note);
+    self
+  }
+
+  pub fn with_suggestion(mut self, span: Span, replacement: String, message: &str) -> Self {
+    self.suggestion = Some(Suggestion {
+      span,
+      replacement,
+      message: message.to_string(),
+    });
+    self
+  }
+}
+
+impl fmt::Display for Warning {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {} [{}]", self.span, self.msg, self.lint.name())
+  }
+}
+
+impl From<&Warning> for Diagnostic {
+  fn from(warning: &Warning) -> Self {
+    let mut diagnostic = Diagnostic::warning(warning.span, &warning.msg);
+    diagnostic.notes = warning.notes.clone();
+    diagnostic.suggestion = warning.suggestion.clone();
+    diagnostic.with_code(warning.lint.name())
+  }
+}
+
+/// Linter - looks for code that is valid but likely wrong, such as a
+/// parameter the body never reads because of a typo in its uses, or a
+/// `let` that hides a parameter of the same name, or numeric hazards such
+/// as a division by zero, which only show up as an `inf` or a NaN much
+/// later on. Warnings
+/// don't stop a program from running, unless their lint is denied. Bindings
+/// carry no span, so they are
+/// reported at the innermost node around them that does.
+pub struct Linter {
+  levels: HashMap<Lint, LintLevel>,
+}
+
+/// A parameter or binding in scope, and whether anything has read it yet.
+struct Local {
+  name: String,
+  lint: Lint,
+  span: Span,
+  read: bool,
+}
+
+impl Linter {
+  pub fn new() -> Self {
+    Self {
+      levels: HashMap::new(),
+    }
+  }
+
+  pub fn set_level(&mut self, lint: Lint, level: LintLevel) {
+    self.levels.insert(lint, level);
+  }
+
+  pub fn level(&self, lint: Lint) -> LintLevel {
+    let level = self.levels.get(&lint).copied();
+    level.unwrap_or(lint.default_level())
+  }
+
+  /// Stops reporting `lint`.
+  pub fn allow(&mut self, lint: Lint) {
+    self.set_level(lint, LintLevel::Allow);
+  }
+
+  /// Sets the level of the lint named `name`, which warns if there is no
+  /// such lint.
+  pub fn set_level_by_name(
+    &mut self,
+    name: &str,
+    level: LintLevel,
+    span: Span,
+  ) -> Option<Diagnostic> {
+    match Lint::from_name(name) {
+      Some(lint) => {
+        self.set_level(lint, level);
+        None
+      }
+      None => {
+        let msg = format!("Unknown lint `{}`", name);
+        Some(Diagnostic::warning(span, msg).with_code("unknown-lint"))
+      }
+    }
+  }
+
+  /// Sets the levels of lints from a configuration file, whose `[lints]`
+  /// table maps lint names to levels, as in
+  ///
+  /// ```text
+  /// [lints]
+  /// unused-param = "allow"
+  /// shadowing = "deny"
+  /// ```
+  ///
+  /// The other tables are left for other tools. Lines starting with `#` are
+  /// comments. Unknown lints are warned about, while malformed lines and
+  /// unknown levels are errors, which leave the levels as they were.
+  pub fn configure(&mut self, src: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut levels = vec![];
+    let mut in_lints = false;
+    for (i, line) in src.lines().enumerate() {
+      let indent = line.len() - line.trim_start().len();
+      let span = Span {
+        line: i + 1,
+        col: indent + 1,
+      };
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      if line.starts_with('[') {
+        in_lints = line == "[lints]";
+        continue;
+      }
+      if !in_lints {
+        continue;
+      }
+      let Some((name, level)) = line.split_once('=') else {
+        let msg = "Expected `lint = level`";
+        diagnostics.push(Diagnostic::error(span, msg).with_code("config"));
+        continue;
+      };
+      let value = level.trim().trim_matches('"');
+      match LintLevel::from_name(value) {
+        Some(level) => levels.push((name.trim(), level, span)),
+        None => {
+          let msg = format!(
+            "Unknown lint level `{}`, expected `allow`, `warn` or `deny`",
+            value
+          );
+          diagnostics.push(Diagnostic::error(span, msg).with_code("config"));
+        }
+      }
+    }
+    if diagnostics.is_empty() {
+      for (name, level, span) in levels {
+        diagnostics.extend(self.set_level_by_name(name, level, span));
+      }
+    }
+    diagnostics
+  }
+
+  /// `warning` as reported at the level of its lint.
+  pub fn diagnostic(&self, warning: &Warning) -> Diagnostic {
+    let mut diagnostic = Diagnostic::from(warning);
+    if self.level(warning.lint) == LintLevel::Deny {
+      diagnostic.severity = Severity::Error;
+    }
+    diagnostic
+  }
+
+  pub fn lint_module(&self, module: &ModuleAst) -> Vec<Warning> {
+    module
+      .items
+      .iter()
+      .flat_map(|item| self.lint(item))
+      .collect()
+  }
+
+  pub fn lint(&self, item: &Ast) -> Vec<Warning> {
+    let mut warnings = vec![];
+    match item {
+      Ast::Func(func) => self.lint_func(func, &mut warnings),
+      Ast::Expr(expr) | Ast::Const(_, _, expr, _) => {
+        self.visit(expr, &mut vec![], Span::default(), &mut warnings)
+      }
+      Ast::Global(vars, _) => {
+        for init in vars.iter().filter_map(|(.., init)| init.as_ref()) {
+          self.visit(init, &mut vec![], Span::default(), &mut warnings);
+        }
+      }
+      Ast::Proto(_) | Ast::Struct(_) | Ast::Import(..) => (),
+    }
+    warnings.retain(|warning| self.level(warning.lint) != LintLevel::Allow);
+    warnings
+  }
+
+  /// Warns about the functions of a whole program that can never run, and
+  /// about the names of its functions and parameters that aren't snake_case.
+  /// These need all of its items at once, unlike the other lints.
+  pub fn lint_program(&self, module: &ModuleAst) -> Vec<Warning> {
+    let mut warnings = vec![];
+    if self.level(Lint::DeadFunction) != LintLevel::Allow {
+      let dead = dead_functions(module).into_iter().map(|proto| {
+        let msg = format!("Function `{}` is never called", proto.name);
+        Warning::new(Lint::DeadFunction, proto.span, msg)
+      });
+      warnings.extend(dead);
+    }
+    if self.level(Lint::NamingStyle) != LintLevel::Allow {
+      warnings.extend(naming_style(module));
+    }
+    warnings
+  }
+
+  fn lint_func(&self, func: &FuncAst, warnings: &mut Vec<Warning>) {
+    let proto = &func.proto;
+    let mut scope = proto
+      .args
+      .iter()
+      .zip(&proto.arg_spans)
+      .map(|(name, &span)| Local {
+        name: name.clone(),
+        lint: Lint::UnusedParam,
+        span,
+        read: false,
+      })
+      .collect();
+    self.visit(&func.body, &mut scope, proto.span, warnings);
+    for param in scope.into_iter().filter(|param| !param.read) {
+      let msg = format!("Unused parameter `{}` of `{}`", param.name, proto.name);
+      warnings.push(Warning::new(Lint::UnusedParam, param.span, msg));
+    }
+    if let Some(call) = unconditional_recursion(func) {
+      let msg = format!(
+        "`{}` calls itself on every path, so it never returns",
+        proto.name
+      );
+      warnings.push(Warning::new(Lint::InfiniteRecursion, call, msg));
+    }
+  }
+
+  fn visit(&self, expr: &ExprAst, scope: &mut Vec<Local>, span: Span, warnings: &mut Vec<Warning>) {
+    let mut span = span;
+    let bind = |scope: &mut Vec<Local>, name: &String, span: Span| {
+      scope.push(Local {
+        name: name.clone(),
+        lint: Lint::UnusedBinding,
+        span,
+        read: false,
+      })
+    };
+    let read = |scope: &mut Vec<Local>, name: &String| {
+      if let Some(local) = scope.iter_mut().rev().find(|local| &local.name == name) {
+        local.read = true;
+      }
+    };
+    Self::hazards(expr, warnings);
+    match expr {
+      ExprAst::VarAst(name, _) => read(scope, name),
+      ExprAst::CallAst(name, _, at) => {
+        read(scope, name);
+        span = *at;
+      }
+      ExprAst::LetAst(bindings, body) => {
+        let depth = scope.len();
+        for (name, at, init) in bindings {
+          self.visit(init, scope, span, warnings);
+          Self::shadow(scope, name, *at, warnings);
+          bind(scope, name, *at);
+        }
+        self.visit(body, scope, span, warnings);
+        Self::leave(scope, depth, warnings);
+        return;
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        self.visit(init, scope, span, warnings);
+        let depth = scope.len();
+        for (name, at) in names {
+          Self::shadow(scope, name, *at, warnings);
+          bind(scope, name, *at);
+        }
+        self.visit(body, scope, span, warnings);
+        Self::leave(scope, depth, warnings);
+        return;
+      }
+      ExprAst::VarInAst(vars, body) => {
+        let depth = scope.len();
+        for (name, at, init) in vars {
+          if let Some(init) = init {
+            self.visit(init, scope, span, warnings);
+          }
+          Self::shadow(scope, name, *at, warnings);
+          bind(scope, name, *at);
+        }
+        self.visit(body, scope, span, warnings);
+        Self::leave(scope, depth, warnings);
+        return;
+      }
+      // lambda parameters and `catch` bindings may shadow, and aren't
+      // reported when unused
+      ExprAst::LambdaAst(args, body) => {
+        let depth = scope.len();
+        args.iter().for_each(|(name, at)| bind(scope, name, *at));
+        self.visit(body, scope, span, warnings);
+        scope.truncate(depth);
+        return;
+      }
+      ExprAst::TryAst(expr, name, handler, at) => {
+        self.visit(expr, scope, *at, warnings);
+        let depth = scope.len();
+        name.iter().for_each(|name| bind(scope, name, *at));
+        self.visit(handler, scope, *at, warnings);
+        scope.truncate(depth);
+        return;
+      }
+      ExprAst::MatchAst(_, arms, at) => {
+        Self::arms(arms, *at, warnings);
+        span = *at;
+      }
+      ExprAst::UnaryAst(_, _, at) | ExprAst::BinAst(_, _, _, at) | ExprAst::ReturnAst(_, at) => {
+        span = *at
+      }
+      _ => (),
+    }
+    for child in expr.children() {
+      self.visit(child, scope, span, warnings);
+    }
+  }
+
+  /// Warns about the numeric hazards of `expr` that its constant operands
+  /// make certain: a division or remainder by zero, a comparison that always
+  /// has the same result, and arithmetic on finite numbers, or a call to a
+  /// pure builtin, that overflows to infinity.
+  fn hazards(expr: &ExprAst, warnings: &mut Vec<Warning>) {
+    let constant = |expr: &ExprAst| eval_const(expr, &HashMap::new());
+    let finite = |val: &Option<Value>| match val {
+      Some(Value::Num(n)) => n.is_finite(),
+      Some(Value::Int(_)) => true,
+      _ => false,
+    };
+    let mut warn = |lint, span, msg: String| warnings.push(Warning::new(lint, span, msg));
+    match expr {
+      ExprAst::BinAst(_, op @ (BinOp::Div | BinOp::Rem), rhs, span) => {
+        if let Some(Value::Int(0)) | Some(Value::Num(0.0)) = constant(rhs) {
+          let what = match op {
+            BinOp::Div => "Division",
+            _ => "Remainder of a division",
+          };
+          warn(Lint::DivisionByZero, *span, format!("{} by zero", what));
+        }
+      }
+      ExprAst::BinAst(
+        lhs,
+        op @ (BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge | BinOp::Eq | BinOp::Ne),
+        rhs,
+        span,
+      ) if constant(lhs).is_some() && constant(rhs).is_some() => {
+        if let Some(val) = constant(expr) {
+          let msg = format!(
+            "Comparison with `{}` is always {}",
+            op.as_str(),
+            truthy(val)
+          );
+          warn(Lint::ConstantComparison, *span, msg);
+        }
+      }
+      _ => (),
+    }
+    let (operands, span): (Vec<&ExprAst>, Span) = match expr {
+      ExprAst::BinAst(lhs, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div, rhs, span) => {
+        (vec![lhs, rhs], *span)
+      }
+      ExprAst::CallAst(_, args, span) => (args.iter().collect(), *span),
+      _ => return,
+    };
+    if operands.iter().all(|operand| finite(&constant(operand))) {
+      if let Some(Value::Num(n)) = constant(expr) {
+        if n.is_infinite() {
+          let msg = "Constant arithmetic overflows to infinity".to_string();
+          warn(Lint::Overflow, span, msg);
+        }
+      }
+    }
+  }
+
+  /// Warns about a `match` at `span` whose value may match none of its
+  /// `arms`, which is an error when it happens, and about the arms that
+  /// can never be taken.
+  fn arms(arms: &[(Pattern, ExprAst)], span: Span, warnings: &mut Vec<Warning>) {
+    if !is_exhaustive(arms) {
+      let msg = "`match` has no `_` arm".to_string();
+      let note = "a value that no arm matches is an error at runtime".to_string();
+      warnings.push(Warning::new(Lint::NonExhaustiveMatch, span, msg).with_note(note));
+    }
+    for (i, why) in unreachable_arms(arms) {
+      let msg = match why {
+        Unreachable::Covered(j) => format!(
+          "Arm {} of `match` is unreachable, as arm {} matches all its values",
+          i + 1,
+          j + 1
+        ),
+        Unreachable::Exhausted => format!(
+          "Arm {} of `match` is unreachable, as the arms before it match every value",
+          i + 1
+        ),
+        Unreachable::Empty => format!(
+          "Arm {} of `match` is unreachable, as its range is empty",
+          i + 1
+        ),
+      };
+      warnings.push(Warning::new(Lint::UnreachableArm, span, msg));
+    }
+  }
+
+  /// Warns if the `let`/`var` binding `name` about to come into scope hides
+  /// a parameter or binding of an enclosing one.
+  fn shadow(scope: &[Local], name: &str, span: Span, warnings: &mut Vec<Warning>) {
+    let Some(outer) = scope.iter().rev().find(|local| local.name == name) else {
+      return;
+    };
+    if !name.starts_with('$') {
+      let outer = match outer.lint {
+        Lint::UnusedParam => "parameter",
+        _ => "binding",
+      };
+      let msg = format!("Binding `{}` shadows a {} of the same name", name, outer);
+      warnings.push(Warning::new(Lint::Shadowing, span, msg));
+    }
+  }
+
+  /// Pops the bindings of a scope being left, reporting those never read.
+  /// Names made up by the parser's desugarings start with `$`.
+  fn leave(scope: &mut Vec<Local>, depth: usize, warnings: &mut Vec<Warning>) {
+    for local in scope.drain(depth..).filter(|local| !local.read) {
+      if !local.name.starts_with('$') {
+        let msg = format!("Unused binding `{}`", local.name);
+        warnings.push(Warning::new(local.lint, local.span, msg));
+      }
+    }
+  }
+}
+
+/// Warns about the functions and parameters of `module` whose names aren't
+/// snake_case, suggesting the snake_case one. When a function of that name
+/// is defined too, the camelCase one may well have been meant to be it.
+/// Operators, the names the parser makes up and the namespaces of imported
+/// functions are left alone.
+fn naming_style(module: &ModuleAst) -> Vec<Warning> {
+  let mut defined = HashMap::new();
+  let funcs: Vec<_> = module
+    .items
+    .iter()
+    .filter_map(|item| match item {
+      Ast::Func(func) if !func.proto.name.is_empty() => Some(&func.proto),
+      _ => None,
+    })
+    .collect();
+  for proto in &funcs {
+    defined.entry(proto.name.as_str()).or_insert(proto.span);
+  }
+  let mut warnings = vec![];
+  for proto in funcs {
+    if let Some(snake) = snake_case(&proto.name) {
+      let msg = format!(
+        "Function `{}` should be snake_case, like `{}`",
+        proto.name, snake
+      );
+      let mut warning = Warning::new(Lint::NamingStyle, proto.span, msg);
+      if let Some(at) = defined.get(snake.as_str()) {
+        warning = warning.with_note(format!("did you mean `{}`, defined at {}?", snake, at));
+      }
+      warnings.push(warning.with_suggestion(proto.span, snake, "rename it"));
+    }
+    for (arg, &at) in proto.args.iter().zip(&proto.arg_spans) {
+      if let Some(snake) = snake_case(arg) {
+        let msg = format!(
+          "Parameter `{}` of `{}` should be snake_case, like `{}`",
+          arg, proto.name, snake
+        );
+        warnings.push(Warning::new(Lint::NamingStyle, at, msg));
+      }
+    }
+  }
+  warnings
+}
+
+/// The snake_case spelling of `name`, unless it is spelled so already or
+/// isn't an identifier. `parseHTTPRequest` becomes `parse_http_request`.
+fn snake_case(name: &str) -> Option<String> {
+  let (ns, ident) = match name.rsplit_once('.') {
+    Some((ns, ident)) => (&name[..ns.len() + 1], ident),
+    None => ("", name),
+  };
+  let is_ident = ident.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+  if !is_ident || !ident.chars().any(|c| c.is_ascii_uppercase()) {
+    return None;
+  }
+  let chars: Vec<_> = ident.chars().collect();
+  let mut snake = ns.to_string();
+  for (i, &c) in chars.iter().enumerate() {
+    let prev = i.checked_sub(1).map(|i| chars[i]);
+    let next = chars.get(i + 1);
+    let starts_word = c.is_ascii_uppercase()
+      && match prev {
+        Some(p) if p.is_ascii_lowercase() || p.is_ascii_digit() => true,
+        Some(p) if p.is_ascii_uppercase() => next.is_some_and(char::is_ascii_lowercase),
+        _ => false,
+      };
+    if starts_word {
+      snake.push('_');
+    }
+    snake.push(c.to_ascii_lowercase());
+  }
+  Some(snake)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  fn lint(linter: &Linter, src: &'static str) -> Vec<String> {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let warnings = linter.lint_module(&module);
+    warnings.iter().map(Warning::to_string).collect()
+  }
+
+  #[test]
+  fn lint_unused() {
+    let src = "def area(length, width) lenght * width;
+      def f(x, g) { let y = x, z = 1 in y + g(0); var w in w = 2 };
+      let (a, b) = (1, 2) in \\(a) a + b; def h(n) let n = 1 in n";
+    let mut linter = Linter::new();
+    assert_eq!(
+      lint(&linter, src),
+      vec![
+        "1:10: Unused parameter `length` of `area` [unused-param]",
+        "2:32: Unused binding `z` [unused-binding]",
+        "2:55: Unused binding `w` [unused-binding]",
+        "3:12: Unused binding `a` [unused-binding]",
+        "3:55: Binding `n` shadows a parameter of the same name [shadowing]",
+        "3:48: Unused parameter `n` of `h` [unused-param]",
+      ]
+    );
+    linter.allow(Lint::from_name("unused-param").unwrap());
+    linter.allow(Lint::Shadowing);
+    assert_eq!(lint(&linter, src).len(), 3);
+    assert_eq!(
+      lint(&linter, "1 < f() < 2; match 1 { _ -> 0 }"),
+      Vec::<String>::new()
+    );
+  }
+
+  #[test]
+  fn lint_infinite_recursion() {
+    let src = "def loop(x) loop(x); def fact(n) if n < 2 then 1 else n * fact(n - 1)";
+    let mut linter = Linter::new();
+    assert_eq!(
+      lint(&linter, src),
+      vec!["1:13: `loop` calls itself on every path, so it never returns [infinite-recursion]"]
+    );
+    linter.allow(Lint::InfiniteRecursion);
+    assert_eq!(lint(&linter, src), Vec::<String>::new());
+  }
+
+  #[test]
+  fn lint_numeric_hazards() {
+    let src = "def f(x) x / 0 + x % (2 - 2) + x / 0.5; 1 < 2; f(1) == 1; \"a\" != \"a\";
+      exp(700) * exp(700); exp(700) + 1; exp(1000) - 1; inf * 2";
+    let mut linter = Linter::new();
+    assert_eq!(
+      lint(&linter, src),
+      vec![
+        "1:12: Division by zero [division-by-zero]",
+        "1:20: Remainder of a division by zero [division-by-zero]",
+        "1:43: Comparison with `<` is always true [constant-comparison]",
+        "1:63: Comparison with `!=` is always false [constant-comparison]",
+        "2:16: Constant arithmetic overflows to infinity [overflow]",
+        "2:42: Constant arithmetic overflows to infinity [overflow]",
+      ]
+    );
+    linter.allow(Lint::DivisionByZero);
+    linter.allow(Lint::ConstantComparison);
+    linter.allow(Lint::Overflow);
+    assert_eq!(lint(&linter, src), Vec::<String>::new());
+  }
+
+  #[test]
+  fn lint_match_arms() {
+    let src = "def f(x) match x { 0..10 -> 1, 5 -> 2, 2..4 -> 3, 3..3 -> 4, 0 -> 5 };
+      def g(b) match b { true -> 1, false -> 2, _ -> 3 };
+      def h(s) match s { \"a\" -> 1, \"a\" -> 2, _ -> 3, \"b\" -> 4 };
+      f(1) + g(true) + h(\"a\")";
+    let mut linter = Linter::new();
+    assert_eq!(
+      lint(&linter, src),
+      vec![
+        "1:10: `match` has no `_` arm [non-exhaustive-match]",
+        "1:10: Arm 2 of `match` is unreachable, as arm 1 matches all its values [unreachable-arm]",
+        "1:10: Arm 3 of `match` is unreachable, as arm 1 matches all its values [unreachable-arm]",
+        "1:10: Arm 4 of `match` is unreachable, as its range is empty [unreachable-arm]",
+        "1:10: Arm 5 of `match` is unreachable, as arm 1 matches all its values [unreachable-arm]",
+        "2:16: Arm 3 of `match` is unreachable, as the arms before it match every value [unreachable-arm]",
+        "3:16: Arm 2 of `match` is unreachable, as arm 1 matches all its values [unreachable-arm]",
+        "3:16: Arm 4 of `match` is unreachable, as arm 3 matches all its values [unreachable-arm]",
+      ]
+    );
+    linter.allow(Lint::NonExhaustiveMatch);
+    linter.allow(Lint::UnreachableArm);
+    assert_eq!(lint(&linter, src), Vec::<String>::new());
+  }
+
+  #[test]
+  fn lint_naming_style() {
+    let src = "def computeArea(w, hVal) w * hVal; def compute_area(w, h) w * h;
+      def parseHTTPRequest(x) x; def binary ~ 5 (a b) a; def v2(x) x;
+      computeArea(1, 2) + compute_area(1, 2) + parseHTTPRequest(1) + v2(1 ~ 2)";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut linter = Linter::new();
+    assert!(linter.lint_program(&module).is_empty());
+    linter.set_level(Lint::NamingStyle, LintLevel::Warn);
+    let warnings = linter.lint_program(&module);
+    let shown: Vec<_> = warnings.iter().map(Warning::to_string).collect();
+    assert_eq!(
+      shown,
+      vec![
+        "1:5: Function `computeArea` should be snake_case, like `compute_area` [naming-style]",
+        "1:20: Parameter `hVal` of `computeArea` should be snake_case, like `h_val` [naming-style]",
+        "2:11: Function `parseHTTPRequest` should be snake_case, like `parse_http_request` [naming-style]",
+      ]
+    );
+    let diagnostic = linter.diagnostic(&warnings[0]);
+    assert_eq!(
+      diagnostic.notes,
+      vec!["did you mean `compute_area`, defined at 1:40?"]
+    );
+    assert_eq!(diagnostic.suggestion.unwrap().replacement, "compute_area");
+  }
+
+  #[test]
+  fn lint_shadowing() {
+    let src =
+      "def f(x) let y = x in { let y = y + 1 in y; var x = 2 in let (y, z) = (x, y) in y + z }";
+    let linter = Linter::new();
+    assert_eq!(
+      lint(&linter, src),
+      vec![
+        "1:29: Binding `y` shadows a binding of the same name [shadowing]",
+        "1:49: Binding `x` shadows a parameter of the same name [shadowing]",
+        "1:63: Binding `y` shadows a binding of the same name [shadowing]",
+      ]
+    );
+    let src = "def g(x) let f = \\(x) x in try f(x) catch x -> 0; 1 < h(1) < 2 < h(2)";
+    assert_eq!(lint(&linter, src), Vec::<String>::new());
+  }
+
+  #[test]
+  fn lint_config() {
+    let mut linter = Linter::new();
+    let config = "# levels\n[package]\nname = \"x\"\n[lints]\nshadowing = \"deny\"\n  unused-param = allow\nnaming = \"warn\"\n";
+    let diagnostics: Vec<_> = linter
+      .configure(config)
+      .iter()
+      .map(Diagnostic::to_string)
+      .collect();
+    assert_eq!(diagnostics, vec!["7:1: Unknown lint `naming`"]);
+    assert_eq!(linter.level(Lint::Shadowing), LintLevel::Deny);
+    assert_eq!(linter.level(Lint::UnusedParam), LintLevel::Allow);
+    assert_eq!(linter.level(Lint::UnusedBinding), LintLevel::Warn);
+    let src = "def f(x) let x = 1, y = 2 in x";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let severities: Vec<_> = linter
+      .lint_module(&module)
+      .iter()
+      .map(|warning| linter.diagnostic(warning).severity)
+      .collect();
+    assert_eq!(severities, vec![Severity::Error, Severity::Warning]);
+
+    let mut linter = Linter::new();
+    let diagnostics = linter.configure("[lints]\nshadowing = loud\nunused-param\n");
+    let diagnostics: Vec<_> = diagnostics.iter().map(Diagnostic::to_string).collect();
+    assert_eq!(
+      diagnostics,
+      vec![
+        "2:1: Unknown lint level `loud`, expected `allow`, `warn` or `deny`",
+        "3:1: Expected `lint = level`",
+      ]
+    );
+    assert_eq!(linter.level(Lint::Shadowing), LintLevel::Warn);
+  }
+}
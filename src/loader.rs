@@ -0,0 +1,335 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{Lexer, Span};
+use crate::parser::{Ast, ExprAst, ModuleAst, ProtoAst};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Loader - reads source files along with the files they import. Each
+/// `import` is replaced by the items of the imported file, so the result is
+/// a single module. The functions, externs and structs of a file imported
+/// into a namespace are renamed to `ns.name`, uses included. A file imported
+/// more than once into the same namespace is only merged where it is first
+/// imported, and a file importing itself, directly or not, is an error.
+pub struct Loader {
+  loaded: HashSet<(PathBuf, Option<String>)>,
+  stack: Vec<PathBuf>, // the files being loaded, importers first
+}
+
+impl Loader {
+  pub fn new() -> Self {
+    Self {
+      loaded: HashSet::new(),
+      stack: vec![],
+    }
+  }
+
+  /// Loads the file at `path`, as given on the command line.
+  pub fn load(&mut self, path: &Path) -> Result<ModuleAst, Diagnostic> {
+    let path = path.canonicalize().map_err(|e| cannot_open(path, e))?;
+    self.loaded.insert((path.clone(), None));
+    self.load_canonical(path)
+  }
+
+  /// Loads the file of `import "path"` at `span` in the file `from`, which
+  /// is relative to the directory of `from`, into the namespace `ns` if
+  /// any. Imports typed into the REPL have no `from`, and are relative to
+  /// the working directory.
+  pub fn import(
+    &mut self,
+    from: Option<&Path>,
+    path: &str,
+    ns: Option<&str>,
+    span: Span,
+  ) -> Result<ModuleAst, Diagnostic> {
+    let error = |msg: String| {
+      let error = Diagnostic::error(span, msg).with_code("import");
+      match from {
+        Some(from) => error.in_file(from),
+        None => error,
+      }
+    };
+    let dir = from.and_then(Path::parent).unwrap_or(Path::new(""));
+    let target = dir
+      .join(path)
+      .canonicalize()
+      .map_err(|e| error(format!("Cannot import `{}`: {}", path, e)))?;
+    if let Some(i) = self.stack.iter().position(|p| p == &target) {
+      let cycle: Vec<_> = self.stack[i..]
+        .iter()
+        .chain([&target])
+        .map(|p| p.file_name().unwrap_or_default().to_string_lossy())
+        .collect();
+      return Err(error(format!("Import cycle: {}", cycle.join(" -> "))));
+    }
+    if !self.loaded.insert((target.clone(), ns.map(str::to_string))) {
+      return Ok(ModuleAst { items: vec![] });
+    }
+    let mut module = self.load_canonical(target)?;
+    if let Some(ns) = ns {
+      Namespace::new(ns, &module).apply(&mut module);
+    }
+    Ok(module)
+  }
+
+  fn load_canonical(&mut self, path: PathBuf) -> Result<ModuleAst, Diagnostic> {
+    let mut lexer = Lexer::open(&path).map_err(|e| cannot_open(&path, e))?;
+    let module = ModuleAst::parse(&mut lexer).map_err(|e| e.in_file(&path))?;
+    self.stack.push(path.clone());
+    let res = self.expand(module, Some(&path));
+    self.stack.pop();
+    res
+  }
+
+  /// Replaces the imports of `module`, read from the file `from` if any,
+  /// with the items of the imported files. Those of a module that isn't
+  /// read from a file are relative to the working directory.
+  pub fn expand(
+    &mut self,
+    module: ModuleAst,
+    from: Option<&Path>,
+  ) -> Result<ModuleAst, Diagnostic> {
+    let mut items = vec![];
+    for item in module.items {
+      match item {
+        Ast::Import(import, ns, span) => {
+          let module = self.import(from, &import, ns.as_deref(), span)?;
+          items.extend(module.items);
+        }
+        item => items.push(item),
+      }
+    }
+    Ok(ModuleAst { items })
+  }
+}
+
+fn cannot_open(path: &Path, e: std::io::Error) -> Diagnostic {
+  let msg = format!("Cannot open `{}`: {}", path.display(), e);
+  Diagnostic::error(Span::default(), msg)
+}
+
+/// Namespace - the names a module defines, to be prefixed with `ns.`.
+/// Operators stay global, as their names can't be written qualified.
+struct Namespace<'a> {
+  ns: &'a str,
+  names: HashSet<String>,
+}
+
+impl<'a> Namespace<'a> {
+  fn new(ns: &'a str, module: &ModuleAst) -> Self {
+    let names = module.items.iter().filter_map(|item| match item {
+      Ast::Func(func) => Some(&func.proto.name),
+      Ast::Proto(proto) => Some(&proto.name),
+      Ast::Struct(decl) => Some(&decl.name),
+      _ => None,
+    });
+    let names = names
+      .filter(|name| !name.is_empty() && !name.starts_with("binary"))
+      .cloned()
+      .collect();
+    Self { ns, names }
+  }
+
+  fn qualify(&self, name: &mut String) {
+    if self.names.contains(name) {
+      *name = format!("{}.{}", self.ns, name);
+    }
+  }
+
+  fn qualify_proto(&self, proto: &mut ProtoAst) {
+    self.qualify(&mut proto.name);
+    proto
+      .arg_tys
+      .iter_mut()
+      .flatten()
+      .for_each(|ty| self.qualify(ty));
+    proto.ret_ty.iter_mut().for_each(|ty| self.qualify(ty));
+  }
+
+  fn apply(&self, module: &mut ModuleAst) {
+    for item in &mut module.items {
+      match item {
+        Ast::Expr(expr) | Ast::Const(_, _, expr, _) => self.rename(expr, &mut vec![]),
+        Ast::Proto(proto) => self.qualify_proto(proto),
+        Ast::Func(func) => {
+          self.qualify_proto(&mut func.proto);
+          self.rename(&mut func.body, &mut func.proto.args.clone());
+        }
+        Ast::Global(vars, _) => {
+          for init in vars.iter_mut().filter_map(|(.., init)| init.as_mut()) {
+            self.rename(init, &mut vec![]);
+          }
+        }
+        Ast::Struct(decl) => {
+          self.qualify(&mut decl.name);
+          decl
+            .field_tys
+            .iter_mut()
+            .flatten()
+            .for_each(|ty| self.qualify(ty));
+        }
+        Ast::Import(..) => (), // already replaced by the imported items
+      }
+    }
+  }
+
+  /// Qualifies the uses of the module's names in `expr`. Bindings
+  /// introduced inside `expr` shadow the names, as in [`crate::consts`].
+  fn rename(&self, expr: &mut ExprAst, shadowed: &mut Vec<String>) {
+    match expr {
+      ExprAst::CallAst(name, _, _) | ExprAst::VarAst(name, _) if !shadowed.contains(name) => {
+        self.qualify(name)
+      }
+      ExprAst::FuncRefAst(name, _) => self.qualify(name),
+      ExprAst::LetAst(bindings, body) => {
+        let depth = shadowed.len();
+        for (name, _, init) in bindings {
+          self.rename(init, shadowed);
+          shadowed.push(name.clone());
+        }
+        self.rename(body, shadowed);
+        shadowed.truncate(depth);
+        return;
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        self.rename(init, shadowed);
+        let depth = shadowed.len();
+        shadowed.extend(names.iter().map(|(name, _)| name.clone()));
+        self.rename(body, shadowed);
+        shadowed.truncate(depth);
+        return;
+      }
+      ExprAst::VarInAst(vars, body) => {
+        let depth = shadowed.len();
+        for (name, _, init) in vars {
+          if let Some(init) = init {
+            self.rename(init, shadowed);
+          }
+          shadowed.push(name.clone());
+        }
+        self.rename(body, shadowed);
+        shadowed.truncate(depth);
+        return;
+      }
+      ExprAst::TryAst(expr, name, handler, _) => {
+        self.rename(expr, shadowed);
+        let depth = shadowed.len();
+        shadowed.extend(name.iter().cloned());
+        self.rename(handler, shadowed);
+        shadowed.truncate(depth);
+        return;
+      }
+      ExprAst::LambdaAst(args, body) => {
+        let depth = shadowed.len();
+        shadowed.extend(args.iter().map(|(arg, _)| arg.clone()));
+        self.rename(body, shadowed);
+        shadowed.truncate(depth);
+        return;
+      }
+      _ => (),
+    }
+    for child in expr.children_mut() {
+      self.rename(child, shadowed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  /// A fresh directory holding `files`, given as (name, source) pairs.
+  fn tree(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kale-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    for (name, src) in files {
+      fs::write(dir.join(name), src).unwrap();
+    }
+    dir
+  }
+
+  fn names(module: &ModuleAst) -> Vec<&str> {
+    let names = module.items.iter().filter_map(|item| match item {
+      Ast::Func(func) => Some(func.proto.name.as_str()),
+      _ => None,
+    });
+    names.collect()
+  }
+
+  #[test]
+  fn load_imports() {
+    let dir = tree(
+      "imports",
+      &[
+        (
+          "main.kale",
+          "import \"lib/a.kale\"; import \"lib/b.kale\"; def main() 0",
+        ),
+        ("lib/a.kale", "import \"c.kale\"; def a() c()"),
+        ("lib/b.kale", "import \"c.kale\"; def b() c()"),
+        ("lib/c.kale", "def c() 1"),
+      ],
+    );
+    let module = Loader::new().load(&dir.join("main.kale")).unwrap();
+    assert_eq!(names(&module), vec!["c", "a", "b", "main"]);
+  }
+
+  #[test]
+  fn load_namespaced() {
+    let dir = tree(
+      "namespaced",
+      &[
+        (
+          "main.kale",
+          "import geo; def dist(p) geo.dist(p, p); dist(geo.Point(1, 2))",
+        ),
+        (
+          "geo.kale",
+          "struct Point(x, y); extern sqrt(x);
+           def dist(p: Point, q: Point) let dist = &sqrt in dist(sq(p.x - q.x) + sq(p.y - q.y));
+           def sq(x) x * x",
+        ),
+      ],
+    );
+    let module = Loader::new().load(&dir.join("main.kale")).unwrap();
+    assert_eq!(names(&module), vec!["geo.dist", "geo.sq", "dist", ""]);
+    let Ast::Proto(proto) = &module.items[1] else {panic!()};
+    assert_eq!((proto.name.as_str(), proto.symbol()), ("geo.sqrt", "sqrt"));
+    let Ast::Func(func) = &module.items[2] else {panic!()};
+    assert_eq!(func.proto.arg_tys, vec![Some("geo.Point".to_string()); 2]);
+    let ExprAst::LetAst(bindings, body) = &func.body else {panic!()};
+    assert_eq!(
+      bindings[0].2.without_spans(),
+      ExprAst::FuncRefAst("geo.sqrt".to_string(), Span::default())
+    );
+    let ExprAst::CallAst(name, args, _) = &**body else {panic!()};
+    assert_eq!(name, "dist"); // the local binding, not `geo.dist`
+    let ExprAst::BinAst(lhs, _, _, _) = &args[0] else {panic!()};
+    let ExprAst::CallAst(name, _, _) = &**lhs else {panic!()};
+    assert_eq!(name, "geo.sq");
+  }
+
+  #[test]
+  fn load_errors() {
+    let dir = tree(
+      "errors",
+      &[
+        ("main.kale", "def main() 0;\n  import \"a.kale\""),
+        ("a.kale", "import \"b.kale\""),
+        ("b.kale", "def b() 1; import \"main.kale\""),
+        ("bad.kale", "import \"lib/none.kale\""),
+      ],
+    );
+    let err = Loader::new().load(&dir.join("main.kale")).unwrap_err();
+    let b = dir.join("b.kale").canonicalize().unwrap();
+    let cycle = "Import cycle: main.kale -> a.kale -> b.kale -> main.kale";
+    assert_eq!(err.to_string(), format!("{}:1:12: {}", b.display(), cycle));
+    let err = Loader::new().load(&dir.join("bad.kale")).unwrap_err();
+    let bad = dir.join("bad.kale").canonicalize().unwrap();
+    assert!(err.to_string().starts_with(&format!(
+      "{}:1:1: Cannot import `lib/none.kale`: ",
+      bad.display()
+    )));
+  }
+}
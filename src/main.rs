@@ -1,9 +1,663 @@
 #![allow(non_snake_case)]
-#![feature(is_some_and)]
+#![allow(clippy::match_ref_pats)]
 
-mod ast;
-mod lexer;
+use kale::bytecode;
+use kale::codegen::backend::{self, BACKENDS};
+#[cfg(feature = "cranelift")]
+use kale::codegen::cranelift::{CraneliftBackend, CraneliftJit};
+#[cfg(feature = "llvm")]
+use kale::codegen::jit::Jit;
+#[cfg(feature = "llvm")]
+use kale::codegen::llvm::{OptLevel, Pass};
+#[cfg(feature = "llvm")]
+use kale::codegen::native::BuildOptions;
+use kale::codegen::{Emit, Engine};
+use kale::diagnostic::{stderr_color, Diagnostic, ErrorFormat, Renderer, Severity};
+use kale::lexer::Span;
+use kale::lexer::{Lexer, Token};
+use kale::lint::LintLevel;
+use kale::parser::Ast;
+use kale::prelude::prelude;
+use kale::session::Session;
+use kale::value::{Precision, Value};
+use kale::vm::{Mode, Vm, VmEngine};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-fn main() {
-  println!("Hello, world!");
+/// Whether an error was reported, for Kale to exit with a failure.
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Reports an error that isn't a diagnostic, as `eprintln!` would after
+/// `Error: `.
+macro_rules! fail {
+  ($($arg:tt)*) => {{
+    FAILED.store(true, Ordering::Relaxed);
+    eprintln!("Error: {}", format_args!($($arg)*))
+  }};
+}
+
+/// Usage: `Kale [build [-o output] [--emit=exe|obj|wasm|wat|c|rust|js|ir|dot|bytecode]
+/// [--backend=llvm|cranelift] [--target triple] [--cpu name] [--features list] [-g]]
+/// [-O] [--inline=N] [--f32] [--allow|warn|deny=lint]
+/// [--config=file] [--error-format=human|json] [--sandbox]
+/// [--allow-extern=name,..] [--jit[=llvm|cranelift] [--opt-level=0|1|2]
+/// [--passes=name,..] [--dump-ir]] [--vm[=stack|register]] [path]`. Without a path,
+/// items are read from stdin. `-O` strips `assert`s and computes common
+/// subexpressions once, `--inline=16` inlines the functions whose body is at
+/// most 16 nodes, `--f32` makes doubles 32 bits wide, `--allow=unused-param`
+/// silences that lint and `--deny=shadowing` makes that one an error. The
+/// levels of lints are first read from the `[lints]` of `--config`, or else
+/// of `kale.toml` in the working directory if any. Errors and warnings are shown
+/// with the source lines they are about, in color when stderr is a terminal,
+/// or with `--error-format=json` as one JSON object per line.
+/// `--sandbox` only lets programs declare the externs of the prelude, and
+/// those `--allow-extern` names, which implies it. `--jit` runs the program
+/// as native code, when built with the `llvm` feature, optimized at
+/// `--opt-level=2` unless given the LLVM `--passes` to run, e.g.
+/// `--passes=instcombine,gvn`. `--dump-ir` shows the IR of each function
+/// before and after them. `--jit=cranelift` compiles with Cranelift
+/// instead, when built with the `cranelift` feature, which needs no LLVM
+/// installed: it optimizes unless given `--opt-level=0`. `--vm` runs the
+/// program as bytecode in the VM, as it does `.kbc` files, and
+/// `--vm=register` translates the bytecode for a register machine first. `Kale build prog.kale` compiles the program to
+/// the executable `prog` instead, or to `-o output`, with the same options.
+/// `--backend=cranelift` compiles it with Cranelift, for the host. With
+/// LLVM, `--target aarch64-unknown-linux-gnu` compiles it for another
+/// machine, whose `--cpu` and `--features` may be given, and `--emit=obj`
+/// stops at the object file, to link with `src/codegen/runtime.c` there.
+/// `-g` describes the program in DWARF, for `gdb` or `lldb` to step through
+/// its source.
+/// `--emit=wasm` compiles it to a WebAssembly module instead, or to WAT
+/// text with `--emit=wat`, which needs no LLVM: `examples/run-wasm.mjs`
+/// runs it with Node. `--emit=c` transpiles it to C, along with the
+/// `kale.h` and `kale_runtime.c` to compile it with, `--emit=rust` to
+/// a Rust module whose `run` runs it, and `--emit=js` to a JavaScript module,
+/// which `examples/run-js.mjs` runs. The C points back at the lines of
+/// `prog.kale` with `#line` directives, and the JavaScript with the source
+/// map `prog.js.map`. `--emit=ir` writes the intermediate
+/// representation of its functions instead, optimized, `--emit=dot`
+/// their control-flow graphs, for Graphviz to draw, and `--emit=bytecode` the
+/// bytecode they compile to, as `prog.kbc`. Kale exits with a failure if
+/// it reported any error.
+fn main() -> ExitCode {
+  run();
+  match FAILED.load(Ordering::Relaxed) {
+    true => ExitCode::FAILURE,
+    false => ExitCode::SUCCESS,
+  }
+}
+
+fn run() {
+  let mut session = Session::new();
+  let mut args: Vec<_> = std::env::args().skip(1).collect();
+  for name in BUILD_OPTIONS {
+    if let Some(i) = args.iter().position(|arg| arg == name) {
+      if i + 1 == args.len() {
+        return fail!("`{}` expects a value", name);
+      }
+      let value = args.remove(i + 1);
+      args[i] = format!("{}={}", name, value);
+    }
+  }
+  let output = match args.iter().position(|arg| arg == "-o") {
+    Some(i) if i + 1 < args.len() => {
+      args.remove(i);
+      Some(args.remove(i))
+    }
+    Some(_) => return fail!("`-o` expects a path"),
+    None => None,
+  };
+  let (flags, mut paths): (Vec<_>, Vec<_>) = args.into_iter().partition(|arg| arg.starts_with('-'));
+  let build = paths.first().is_some_and(|arg| arg == "build");
+  if build {
+    paths.remove(0);
+  } else if output.is_some() {
+    return fail!("`-o` only applies to `build`");
+  }
+  let mut json = false;
+  let mut config = None;
+  let mut levels = vec![];
+  let mut sandbox: Option<Vec<String>> = None;
+  let mut backend_flags = vec![];
+  let mut precision = Precision::F64;
+  for flag in flags {
+    let level = flag.split_once('=').and_then(|(level, lint)| {
+      let level = LintLevel::from_name(level.strip_prefix("--")?)?;
+      Some((lint.to_string(), level))
+    });
+    let threshold = flag.strip_prefix("--inline=").map(str::parse);
+    let config_path = flag.strip_prefix("--config=");
+    let allowed = flag.strip_prefix("--allow-extern=");
+    match flag.as_str() {
+      "-O" => {
+        session.set_strip_asserts(true);
+        session.set_cse(true);
+      }
+      "--f32" => {
+        precision = Precision::F32;
+        session.set_precision(precision);
+      }
+      "--error-format=human" => json = false,
+      "--error-format=json" => json = true,
+      "--sandbox" => {
+        sandbox.get_or_insert_with(Vec::new);
+      }
+      "--jit" | "--dump-ir" | "--vm" => backend_flags.push(flag),
+      _ if flag.starts_with("--jit=") || flag.starts_with("--vm=") => backend_flags.push(flag),
+      _ if flag.starts_with("--opt-level=") || flag.starts_with("--passes=") => {
+        backend_flags.push(flag)
+      }
+      _ if BUILD_OPTIONS
+        .iter()
+        .any(|name| flag.starts_with(&format!("{}=", name))) =>
+      {
+        backend_flags.push(flag)
+      }
+      "--emit=exe" | "--emit=obj" | "-g" => backend_flags.push(flag),
+      _ if flag
+        .strip_prefix("--emit=")
+        .is_some_and(|emit| BACKENDS.iter().any(|(name, _)| *name == emit)) =>
+      {
+        backend_flags.push(flag)
+      }
+      _ if flag.starts_with("--backend=") => backend_flags.push(flag),
+      _ if flag.starts_with("--error-format=") => {
+        return fail!("Unknown error format in `{}`", flag)
+      }
+      _ if threshold.is_some() => match threshold.unwrap() {
+        Ok(threshold) => session.set_inline_threshold(threshold),
+        Err(_) => return fail!("Invalid threshold in `{}`", flag),
+      },
+      _ if config_path.is_some() => config = config_path.map(str::to_string),
+      _ if allowed.is_some() => {
+        let names = allowed.unwrap().split(',').map(str::to_string);
+        sandbox.get_or_insert_with(Vec::new).extend(names);
+      }
+      _ if level.is_some() => levels.extend(level),
+      _ => return fail!("Unknown option `{}`", flag),
+    }
+  }
+  if let Some(names) = sandbox {
+    let mut allowed: HashSet<_> = prelude()
+      .items
+      .iter()
+      .filter_map(|item| match item {
+        Ast::Proto(proto) => Some(proto.symbol().to_string()),
+        _ => None,
+      })
+      .collect();
+    allowed.extend(names);
+    session.set_allowed_externs(Some(allowed));
+  }
+  if !build {
+    if let Err(msg) = configure_jit(&mut session, &backend_flags) {
+      return fail!("{}", msg);
+    }
+  }
+  let renderer = Renderer::new(stderr_color());
+  let source = paths
+    .last()
+    .and_then(|path| std::fs::read_to_string(path).ok());
+  let format = match (json, source) {
+    (true, _) => ErrorFormat::Json(paths.last().map(PathBuf::from)),
+    (false, Some(text)) => ErrorFormat::Human(renderer.with_source(paths.last().unwrap(), text)),
+    (false, None) => ErrorFormat::Human(renderer),
+  };
+  let config = config.or_else(|| {
+    let found = Path::new("kale.toml").exists();
+    found.then(|| "kale.toml".to_string())
+  });
+  if let Some(config) = config {
+    if !configure(&mut session, &config, &format) {
+      return;
+    }
+  }
+  for (lint, level) in levels {
+    if let Some(unknown) = session.set_lint_level_by_name(&lint, level) {
+      emit(&format, &unknown);
+    }
+  }
+  if build {
+    return match paths.pop() {
+      Some(path) => build_file(&mut session, &path, output, &backend_flags, &format),
+      None => fail!("`build` expects the path of a program"),
+    };
+  }
+  let Some(path) = paths.pop() else {
+    return repl(&mut session, &format);
+  };
+  if path.ends_with(".kbc") {
+    let vm = backend_flags.iter().find(|flag| flag.starts_with("--vm"));
+    let mode = match vm.map(|flag| vm_mode(flag)).transpose() {
+      Ok(mode) => mode,
+      Err(msg) => return fail!("{}", msg),
+    };
+    return run_bytecode(&path, mode.unwrap_or(Mode::Stack), precision, &format);
+  }
+  let results = session.run_file(Path::new(&path));
+  warn(&mut session, &format);
+  match results {
+    Ok(results) => results.into_iter().for_each(|res| report(&format, res)),
+    Err(e) => report(&format, Err(e)),
+  }
+}
+
+/// The options of `build` that take a value, which may follow them or
+/// `=`.
+const BUILD_OPTIONS: [&str; 3] = ["--target", "--cpu", "--features"];
+
+/// Runs the bytecode at `path` with the VM in `mode`, computing with
+/// numbers of the given precision, and reports what each function that
+/// starts it returns.
+fn run_bytecode(path: &str, mode: Mode, precision: Precision, format: &ErrorFormat) {
+  let bytes = match std::fs::read(path) {
+    Ok(bytes) => bytes,
+    Err(e) => {
+      let msg = format!("Cannot open `{}`: {}", path, e);
+      return report(format, Err(Diagnostic::error(Span::default(), msg)));
+    }
+  };
+  let program = match bytecode::decode(&bytes) {
+    Ok(program) => program,
+    Err(e) => return report(format, Err(e)),
+  };
+  let mut vm = Vm::builder().mode(mode).precision(precision).build();
+  match vm.run(&program) {
+    Ok(values) => values
+      .into_iter()
+      .for_each(|val| report(format, Ok(Some(val)))),
+    Err(msg) => report(
+      format,
+      Err(Diagnostic::error(Span::default(), msg).with_code("vm")),
+    ),
+  }
+}
+
+/// The mode of the VM that `--vm` names: `--vm=stack`, the default, or
+/// `--vm=register`.
+fn vm_mode(flag: &str) -> Result<Mode, String> {
+  match flag.strip_prefix("--vm=") {
+    None | Some("stack") => Ok(Mode::Stack),
+    Some("register") => Ok(Mode::Register),
+    Some(_) => Err(format!("Unknown mode in `{}`", flag)),
+  }
+}
+
+/// Makes the session run items with the JIT that `--jit` names among
+/// `flags`, configured by the others: `--jit=llvm`, the default when Kale
+/// is built with the `llvm` feature, or `--jit=cranelift`. `--vm` runs
+/// them with the bytecode VM instead, which takes no other options.
+fn configure_jit(session: &mut Session, flags: &[String]) -> Result<(), String> {
+  let vm = flags
+    .iter()
+    .find(|flag| *flag == "--vm" || flag.starts_with("--vm="));
+  if let Some(vm) = vm {
+    if let Some(flag) = flags.iter().find(|flag| *flag != vm) {
+      return Err(format!("`{}` doesn't apply to `{}`", flag, vm));
+    }
+    let engine = VmEngine::new(Vm::builder().mode(vm_mode(vm)?).build());
+    session.set_engine(Some(Box::new(engine)));
+    return Ok(());
+  }
+  let jit = flags
+    .iter()
+    .find(|flag| *flag == "--jit" || flag.starts_with("--jit="));
+  let Some(jit) = jit else {
+    return match flags.first() {
+      Some(flag) => Err(format!("`{}` needs `--jit`", flag)),
+      None => Ok(()),
+    };
+  };
+  let build_only = flags.iter().find(|flag| {
+    flag.starts_with("--emit=")
+      || flag.starts_with("--backend=")
+      || *flag == "-g"
+      || BUILD_OPTIONS.iter().any(|name| flag.starts_with(name))
+  });
+  if let Some(flag) = build_only {
+    return Err(format!("`{}` only applies to `build`", flag));
+  }
+  let backend = match jit.strip_prefix("--jit=") {
+    Some(backend) => backend,
+    None if cfg!(feature = "cranelift") && !cfg!(feature = "llvm") => "cranelift",
+    None => "llvm",
+  };
+  let engine: Result<Box<dyn Engine>, String> = match backend {
+    #[cfg(feature = "llvm")]
+    "llvm" => llvm_jit(flags).map(|jit| Box::new(jit) as _),
+    #[cfg(feature = "cranelift")]
+    "cranelift" => cranelift_jit(flags).map(|jit| Box::new(jit) as _),
+    name if name == "llvm" || name == "cranelift" => Err(format!(
+      "`{}` needs Kale built with the {} feature",
+      jit, name
+    )),
+    _ => Err(format!("Unknown backend in `{}`", jit)),
+  };
+  session.set_engine(Some(engine?));
+  Ok(())
+}
+
+/// The LLVM JIT, configured by `flags`.
+#[cfg(feature = "llvm")]
+fn llvm_jit(flags: &[String]) -> Result<Jit, String> {
+  let options = build_options(flags)?;
+  let mut jit = Jit::new(Precision::F64);
+  jit.set_passes(&options.passes);
+  if let Some(dump) = options.dump {
+    jit.set_dump(dump);
+  }
+  Ok(jit)
+}
+
+/// The Cranelift JIT, configured by `flags`.
+#[cfg(feature = "cranelift")]
+fn cranelift_jit(flags: &[String]) -> Result<CraneliftJit, String> {
+  let (optimize, dump) = cranelift_options(flags)?;
+  let mut jit = CraneliftJit::new(Precision::F64);
+  jit.set_optimize(optimize);
+  if dump {
+    jit.set_dump(std::io::stderr());
+  }
+  Ok(jit)
+}
+
+/// Whether Cranelift optimizes, as it does unless given `--opt-level=0`,
+/// and whether `--dump-ir` shows CLIF, among `flags`. The options of LLVM
+/// are errors.
+#[cfg(feature = "cranelift")]
+fn cranelift_options(flags: &[String]) -> Result<(bool, bool), String> {
+  let (mut optimize, mut dump) = (true, false);
+  for flag in flags {
+    let llvm_only = flag.starts_with("--passes=")
+      || flag == "-g"
+      || BUILD_OPTIONS
+        .iter()
+        .any(|name| flag.starts_with(&format!("{}=", name)));
+    match flag.strip_prefix("--opt-level=") {
+      Some("0") => optimize = false,
+      Some("1" | "2") => optimize = true,
+      Some(_) => return Err(format!("Invalid level in `{}`", flag)),
+      None if llvm_only => return Err(format!("`{}` only applies to LLVM", flag)),
+      None if flag == "--dump-ir" => dump = true,
+      None => (),
+    }
+  }
+  Ok((optimize, dump))
+}
+
+/// The options of the LLVM backend among `flags`.
+#[cfg(feature = "llvm")]
+fn build_options(flags: &[String]) -> Result<BuildOptions, String> {
+  let mut options = BuildOptions::new();
+  for flag in flags {
+    if let Some(names) = flag.strip_prefix("--passes=") {
+      let passes = names
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| Pass::from_name(name).ok_or_else(|| format!("Unknown pass `{}`", name)));
+      options.passes = passes.collect::<Result<_, _>>()?;
+    } else if let Some(level) = flag.strip_prefix("--opt-level=") {
+      let level = OptLevel::from_name(level);
+      options.passes = level
+        .ok_or_else(|| format!("Invalid level in `{}`", flag))?
+        .passes()
+        .to_vec();
+    } else if flag == "--dump-ir" {
+      options.dump = Some(Box::new(std::io::stderr()));
+    } else if let Some(target) = flag.strip_prefix("--target=") {
+      options.target = Some(target.to_string());
+    } else if let Some(cpu) = flag.strip_prefix("--cpu=") {
+      options.cpu = Some(cpu.to_string());
+    } else if let Some(features) = flag.strip_prefix("--features=") {
+      options.features = Some(features.to_string());
+    } else if flag == "--emit=obj" {
+      options.emit = Emit::Object;
+    } else if flag == "--emit=exe" {
+      options.emit = Emit::Executable;
+    }
+  }
+  Ok(options)
+}
+
+/// Compiles the program at `path` to an executable, or with the backend
+/// that `--emit` names among [`BACKENDS`], such as `--emit=wasm`, named
+/// after it in the working directory unless given `output`.
+fn build_file(
+  session: &mut Session,
+  path: &str,
+  output: Option<String>,
+  flags: &[String],
+  format: &ErrorFormat,
+) {
+  if let Some(flag) = flags.iter().find(|flag| flag.starts_with("--jit")) {
+    return fail!("`{}` doesn't apply to `build`", flag);
+  }
+  let emit = flags
+    .iter()
+    .rev()
+    .find_map(|flag| flag.strip_prefix("--emit="));
+  let backend = emit.and_then(|emit| BACKENDS.iter().find(|(name, _)| *name == emit));
+  let Some(&(name, extension)) = backend else {
+    return build_native(session, path, output, flags, format);
+  };
+  if let Some(flag) = flags.iter().find(|flag| !flag.starts_with("--emit=")) {
+    return fail!("`{}` doesn't apply to `--emit={}`", flag, name);
+  }
+  let path = Path::new(path);
+  let output = match output {
+    Some(output) => output.into(),
+    None => Path::new(path.file_stem().unwrap_or_default()).with_extension(extension),
+  };
+  if check_output(path, &output) {
+    let res = session.build_with(path, |module, entry, precision| {
+      backend::build(name, module, entry, precision, path, &output)
+    });
+    report_build(session, format, res);
+  }
+}
+
+/// Compiles the program at `path` to an executable, or to an object file
+/// given `--emit=obj`, with the backend that `--backend` names among
+/// `flags`: `llvm`, the default when Kale is built with the `llvm`
+/// feature, or `cranelift`.
+fn build_native(
+  session: &mut Session,
+  path: &str,
+  output: Option<String>,
+  flags: &[String],
+  format: &ErrorFormat,
+) {
+  let name = match flags
+    .iter()
+    .rev()
+    .find_map(|flag| flag.strip_prefix("--backend="))
+  {
+    Some(name) => name,
+    None if cfg!(feature = "llvm") => "llvm",
+    None if cfg!(feature = "cranelift") => "cranelift",
+    None => {
+      let names: Vec<_> = BACKENDS.iter().map(|(name, _)| *name).collect();
+      return fail!(
+        "`build` needs Kale built with the llvm or cranelift feature, unless given `--emit={}`",
+        names.join("|")
+      );
+    }
+  };
+  let emit = match flags.iter().rev().find(|flag| flag.starts_with("--emit=")) {
+    Some(flag) if flag == "--emit=obj" => Emit::Object,
+    _ => Emit::Executable,
+  };
+  let path = Path::new(path);
+  let output = match (output, emit) {
+    (Some(output), _) => PathBuf::from(output),
+    (None, Emit::Executable) => PathBuf::from(path.file_stem().unwrap_or_default()),
+    (None, Emit::Object) => Path::new(path.file_stem().unwrap_or_default()).with_extension("o"),
+  };
+  if !check_output(path, &output) {
+    return;
+  }
+  let res = match name {
+    "llvm" => build_llvm(session, path, &output, flags),
+    "cranelift" => build_cranelift(session, path, &output, emit, flags),
+    _ => Err(format!("Unknown backend in `--backend={}`", name)),
+  };
+  match res {
+    Ok(res) => report_build(session, format, res),
+    Err(msg) => fail!("{}", msg),
+  }
+}
+
+/// Compiles the program at `path` with LLVM, as `flags` ask, unless they
+/// are invalid.
+#[cfg(feature = "llvm")]
+fn build_llvm(
+  session: &mut Session,
+  path: &Path,
+  output: &Path,
+  flags: &[String],
+) -> Result<Result<(), Vec<Diagnostic>>, String> {
+  let mut options = build_options(flags)?;
+  if flags.iter().any(|flag| flag == "-g") {
+    options.debug = Some(path.to_path_buf());
+  }
+  Ok(session.build_file(path, output, &mut options))
+}
+
+#[cfg(not(feature = "llvm"))]
+fn build_llvm(
+  _: &mut Session,
+  _: &Path,
+  _: &Path,
+  _: &[String],
+) -> Result<Result<(), Vec<Diagnostic>>, String> {
+  Err("`--backend=llvm` needs Kale built with the llvm feature".to_string())
+}
+
+/// Compiles the program at `path` with Cranelift, as `flags` ask, unless
+/// they are invalid.
+#[cfg(feature = "cranelift")]
+fn build_cranelift(
+  session: &mut Session,
+  path: &Path,
+  output: &Path,
+  emit: Emit,
+  flags: &[String],
+) -> Result<Result<(), Vec<Diagnostic>>, String> {
+  let (optimize, dump) = cranelift_options(flags)?;
+  Ok(session.build_with(path, |module, entry, precision| {
+    let mut backend = CraneliftBackend::new(precision, optimize, emit).map_err(|e| vec![e])?;
+    if dump {
+      backend.set_dump(std::io::stderr());
+    }
+    backend::compile(&mut backend, module, entry, output)
+  }))
+}
+
+#[cfg(not(feature = "cranelift"))]
+fn build_cranelift(
+  _: &mut Session,
+  _: &Path,
+  _: &Path,
+  _: Emit,
+  _: &[String],
+) -> Result<Result<(), Vec<Diagnostic>>, String> {
+  Err("`--backend=cranelift` needs Kale built with the cranelift feature".to_string())
+}
+
+/// Whether `build` may write `output`, which mustn't be the program at
+/// `path`.
+fn check_output(path: &Path, output: &Path) -> bool {
+  if output == path {
+    fail!("`build` would overwrite `{}`, give `-o`", path.display());
+  }
+  output != path
+}
+
+fn report_build(session: &mut Session, format: &ErrorFormat, res: Result<(), Vec<Diagnostic>>) {
+  warn(session, format);
+  if let Err(errors) = res {
+    errors.into_iter().for_each(|e| report(format, Err(e)));
+  }
+}
+
+/// Sets the levels of lints from the configuration file at `path`, telling
+/// whether it could be used.
+fn configure(session: &mut Session, path: &str, format: &ErrorFormat) -> bool {
+  let diagnostics = match std::fs::read_to_string(path) {
+    Ok(src) => session.configure_lints(&src),
+    Err(e) => {
+      let msg = format!("Cannot open `{}`: {}", path, e);
+      vec![Diagnostic::error(Span::default(), msg)]
+    }
+  };
+  let mut ok = true;
+  for diagnostic in diagnostics {
+    ok &= diagnostic.severity != Severity::Error;
+    emit(format, &diagnostic.in_file(Path::new(path)));
+  }
+  ok
+}
+
+/// Runs the items read from stdin as soon as each one is parsed.
+fn repl(session: &mut Session, format: &ErrorFormat) {
+  let mut lexer = Lexer::new(std::io::stdin());
+  loop {
+    // the lexer lexes ahead when peeked at, which may fail as parsing does
+    match lexer.peek_first().cloned() {
+      Err(e) => {
+        report(format, Err(e));
+        let _ = lexer.next_token(); // skip the token that failed to lex
+      }
+      Ok(Token::Eof) => break,
+      Ok(Token::Semi) => {
+        let _ = lexer.next_token();
+      }
+      Ok(_) => match Ast::parse(&mut lexer) {
+        Ok(ast) => {
+          let res = session.run(ast);
+          warn(session, format);
+          report(format, res);
+        }
+        Err(e) => {
+          report(format, Err(e.clone()));
+          skip_item(&mut lexer, format, &e);
+        }
+      },
+    }
+  }
+}
+
+/// Skips the rest of an item that failed to parse with `failed`, up to the
+/// next `;`, reporting the other tokens on the way that fail to lex.
+fn skip_item(lexer: &mut Lexer, format: &ErrorFormat, failed: &Diagnostic) {
+  while !matches!(lexer.peek_first(), Ok(&Token::Semi | &Token::Eof)) {
+    match lexer.next_token() {
+      Err(e) if &e != failed => report(format, Err(e)),
+      _ => (),
+    }
+  }
+}
+
+fn warn(session: &mut Session, format: &ErrorFormat) {
+  for warning in session.take_warnings() {
+    emit(format, &warning);
+  }
+}
+
+fn report(format: &ErrorFormat, res: Result<Option<Value>, Diagnostic>) {
+  match res {
+    Ok(Some(val)) => println!("Evaluated to {}", val),
+    Ok(None) => (),
+    Err(e) => emit(format, &e),
+  }
+}
+
+/// Prints `diagnostic`, noting whether it is an error.
+fn emit(format: &ErrorFormat, diagnostic: &Diagnostic) {
+  if diagnostic.severity == Severity::Error {
+    FAILED.store(true, Ordering::Relaxed);
+  }
+  eprint!("{}", format.format(diagnostic));
 }
@@ -1,352 +1,2489 @@
 #![allow(unused)]
-use crate::lexer::{Lexer, Token};
+use crate::diagnostic::{syntax_error, Diagnostic};
+use crate::lexer::{Lexer, Span, Token};
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq)]
 pub enum Ast {
   Expr(ExprAst),
   Proto(ProtoAst),
   Func(FuncAst),
+  Global(Vec<(String, Span, Option<ExprAst>)>, Span), // top-level `var g = 0, h;`, span of `var`
+  Const(String, Span, ExprAst, Span), // `const PI = 3.14159;`, spans of the name and the value
+  Struct(StructAst),
+  Import(String, Option<String>, Span), // path of the file and its namespace
 }
 
+/// ModuleAst - all the top-level items of one source file, in order.
 #[derive(Debug, PartialEq)]
+pub struct ModuleAst {
+  pub items: Vec<Ast>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum ExprAst {
   NumAst(f64),
-  VarAst(String),
-  BinAst(Box<ExprAst>, char, Box<ExprAst>),
-  CallAst(String, Vec<ExprAst>),
+  IntAst(i64),
+  BoolAst(bool),
+  UnitAst, // `()`
+  StrAst(String),
+  VarAst(String, Span), // span of the name
+  UnaryAst(UnOp, Box<ExprAst>, Span),
+  BinAst(Box<ExprAst>, BinOp, Box<ExprAst>, Span), // span of the operator
+  CallAst(String, Vec<ExprAst>, Span),
+  IfAst {
+    cond: Box<ExprAst>,
+    then: Box<ExprAst>,
+    els: Box<ExprAst>,
+  },
+  BlockAst(Vec<ExprAst>),                       // value of the last expression
+  SeqAst(Vec<ExprAst>),                         // braced function body
+  TupleAst(Vec<ExprAst>),                       // `(a, b, ...)`
+  ElemAst(Box<ExprAst>, usize),                 // tuple element `t.0`
+  FieldAst(Box<ExprAst>, String),               // struct field `p.x`
+  ArrayAst(Vec<ExprAst>),                       // `[a, b, ...]`
+  IndexAst(Box<ExprAst>, Box<ExprAst>),         // `a[i]`
+  LambdaAst(Vec<(String, Span)>, Box<ExprAst>), // `\(x, y) x + y`
+  LetAst(Vec<(String, Span, ExprAst)>, Box<ExprAst>), // `let a = 1, b = 2 in body`
+  LetTupleAst(Vec<(String, Span)>, Box<ExprAst>, Box<ExprAst>), // `let (a, b) = t in body`
+  VarInAst(Vec<(String, Span, Option<ExprAst>)>, Box<ExprAst>), // `var a = 1, b in body`
+  AssignAst(String, Box<ExprAst>, Span),        // `a = expr`, span of `a`
+  MatchAst(Box<ExprAst>, Vec<(Pattern, ExprAst)>, Span), // span of `match`
+  ReturnAst(Box<ExprAst>, Span),                // `return expr`
+  TryAst(Box<ExprAst>, Option<String>, Box<ExprAst>, Span), // `try expr catch e -> handler`
+  FuncRefAst(String, Span),                     // `&foo`
 }
 
-#[derive(Debug, PartialEq)]
+/// BinOp - the builtin binary operators. Comparisons and the logical
+/// operators yield 1.0 or 0.0; `&&` and `||` only evaluate their right
+/// operand when the left one doesn't already decide the result. `%` is C's
+/// `fmod`: the result takes the sign of the dividend. The bitwise operators
+/// `&`, `|`, `xor`, `<<` and `>>` only apply to ints, in two's complement,
+/// and `>>` copies the sign bit. Doubles follow IEEE 754, like the C they
+/// compile to: `nan` is unequal to everything, itself included, so
+/// `nan == nan` is 0.0 and every ordering with it is 0.0 too, while `inf`
+/// is above every other double and `inf - inf` is `nan`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Rem,
+  Lt,
+  Gt,
+  Le,
+  Ge,
+  Eq,
+  Ne,
+  And,
+  Or,
+  BitAnd,
+  BitOr,
+  Xor,
+  Shl,
+  Shr,
+}
+
+/// UnOp - the builtin prefix operators. `!x` is 1.0 when `x` is false and
+/// 0.0 otherwise.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnOp {
+  Not,
+  Neg,
+}
+
+/// Pattern - the left-hand side of a `match` arm. A literal matches the
+/// values `==` to it, and the range `lo..hi` the values `x` with
+/// `lo <= x && x < hi`. The bounds are always literals.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+  Lit(ExprAst),
+  Range(ExprAst, ExprAst),
+  Wild, // `_`
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct ProtoAst {
-  name: String,
-  args: Vec<String>,
+  pub name: String,
+  pub span: Span,
+  pub args: Vec<String>,
+  pub arg_spans: Vec<Span>,         // where each parameter is named
+  pub arg_tys: Vec<Option<String>>, // optional `x: double` annotations
+  pub ret_ty: Option<String>,       // optional `(...) : double` annotation
+}
+
+/// StructAst - `struct Point(x, y: int)`. Calling `Point(1, 2)` constructs
+/// a point, and `p.x` reads a field.
+#[derive(Debug, PartialEq)]
+pub struct StructAst {
+  pub name: String,
+  pub span: Span,
+  pub fields: Vec<String>,
+  pub field_tys: Vec<Option<String>>, // optional `x: double` annotations
 }
 
 #[derive(Debug, PartialEq)]
 pub struct FuncAst {
-  proto: ProtoAst,
-  body: ExprAst,
+  pub proto: ProtoAst,
+  pub body: ExprAst,
 }
 
 impl Ast {
   // pub fn new(lexer: Lexer) -> Self {}
-  pub fn parse(lexer: &mut Lexer) -> Self {
-    match lexer.peek_first() {
+  pub fn parse(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    match lexer.peek_first()? {
       &Token::Extern => Self::parse_extern(lexer),
-      &Token::Def => Self::Func(FuncAst::parse(lexer)),
+      &Token::Def => Ok(Self::Func(FuncAst::parse(lexer)?)),
+      &Token::Var => Self::parse_global(lexer),
+      &Token::Const => Self::parse_const(lexer),
+      &Token::Struct => Ok(Self::Struct(StructAst::parse(lexer)?)),
+      &Token::Import => Self::parse_import(lexer),
       _ => Self::parse_top_level_expr(lexer),
     }
   }
 
-  fn parse_extern(lexer: &mut Lexer) -> Self {
-    lexer.next_token(); // eat `extern`
-    Self::Proto(ProtoAst::parse(lexer))
+  /// `import "path/to/file.kale"` merges the definitions of the file into
+  /// the importer's, while `import math` puts those of `math.kale` in the
+  /// `math` namespace, as `math.sin`.
+  fn parse_import(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    lexer.next_token()?; // eat `import`
+    match lexer.next_token()? {
+      Token::Str(path) => Ok(Self::Import(path, None, span)),
+      Token::Identifier(name) => Ok(Self::Import(format!("{}.kale", name), Some(name), span)),
+      _ => Err(syntax_error(
+        lexer.last_span(),
+        "Expected file name or module name after `import`",
+      )),
+    }
+  }
+
+  fn parse_const(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `const`
+    let Token::Identifier(name) = lexer.next_token()? else {
+      return Err(syntax_error(
+        lexer.last_span(),
+        "Expected identifier after `const`",
+      ));
+    };
+    let at = lexer.last_span();
+    match lexer.next_token()? {
+      Token::Assign => (),
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected `=` in const declaration",
+        ))
+      }
+    }
+    let span = lexer.span();
+    Ok(Self::Const(name, at, ExprAst::parse(lexer)?, span))
+  }
+
+  /// A top-level `var g = 0;` declares globals, while `var a in body` is
+  /// still an ordinary expression.
+  fn parse_global(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    let vars = ExprAst::parse_var_list(lexer)?;
+    match lexer.peek_first()? {
+      &Token::In => {
+        lexer.next_token()?; // eat `in`
+        let body = ExprAst::parse(lexer)?;
+        Ok(Self::new_top_level(
+          ExprAst::VarInAst(vars, Box::new(body)),
+          span,
+        ))
+      }
+      _ => Ok(Self::Global(vars, span)),
+    }
+  }
+
+  fn parse_extern(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `extern`
+    Ok(Self::Proto(ProtoAst::parse(lexer)?))
+  }
+
+  fn parse_top_level_expr(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    Ok(Self::new_top_level(ExprAst::parse(lexer)?, span))
   }
 
-  fn parse_top_level_expr(lexer: &mut Lexer) -> Self {
-    let expr = ExprAst::parse(lexer);
+  /// Wraps a top-level expression into an anonymous function.
+  pub fn new_top_level(expr: ExprAst, span: Span) -> Self {
     let proto = ProtoAst {
       name: String::new(),
+      span,
       args: vec![],
+      arg_spans: vec![],
+      arg_tys: vec![],
+      ret_ty: None,
     };
     Self::Func(FuncAst { proto, body: expr })
   }
 }
 
+/// The left-hand side of a `let` binding.
+enum LetPat {
+  Name(String, Span),
+  Tuple(Vec<(String, Span)>),
+}
+
+impl ModuleAst {
+  pub fn parse(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let mut items = vec![];
+    loop {
+      match lexer.peek_first()? {
+        &Token::Eof => break,
+        &Token::Semi => {
+          lexer.next_token()?;
+        }
+        _ => items.push(Ast::parse(lexer)?),
+      }
+    }
+    Ok(Self { items })
+  }
+}
+
 impl ExprAst {
-  fn parse(lexer: &mut Lexer) -> Self {
-    let lhs = Self::parse_primary(lexer);
-    Self::parse_bin_rhs(lexer, lhs, 0)
+  /// The direct sub-expressions of this node, in evaluation order.
+  pub fn children(&self) -> Vec<&ExprAst> {
+    match self {
+      Self::NumAst(_)
+      | Self::IntAst(_)
+      | Self::BoolAst(_)
+      | Self::UnitAst
+      | Self::StrAst(_)
+      | Self::VarAst(_, _)
+      | Self::FuncRefAst(..) => vec![],
+      Self::BinAst(lhs, _, rhs, _) | Self::IndexAst(lhs, rhs) => vec![lhs, rhs],
+      Self::IfAst { cond, then, els } => vec![cond, then, els],
+      Self::CallAst(_, exprs, _)
+      | Self::BlockAst(exprs)
+      | Self::SeqAst(exprs)
+      | Self::TupleAst(exprs)
+      | Self::ArrayAst(exprs) => exprs.iter().collect(),
+      Self::UnaryAst(_, expr, _)
+      | Self::ElemAst(expr, _)
+      | Self::FieldAst(expr, _)
+      | Self::LambdaAst(_, expr)
+      | Self::AssignAst(_, expr, _)
+      | Self::ReturnAst(expr, _) => vec![expr],
+      Self::TryAst(expr, _, handler, _) => vec![expr, handler],
+      Self::LetAst(bindings, body) => {
+        let inits = bindings.iter().map(|(.., init)| init);
+        inits.chain([body.as_ref()]).collect()
+      }
+      Self::LetTupleAst(_, init, body) => vec![init, body],
+      Self::VarInAst(vars, body) => {
+        let inits = vars.iter().filter_map(|(.., init)| init.as_ref());
+        inits.chain([body.as_ref()]).collect()
+      }
+      Self::MatchAst(expr, arms, _) => {
+        let bodies = arms.iter().map(|(_, body)| body);
+        [expr.as_ref()].into_iter().chain(bodies).collect()
+      }
+    }
   }
 
-  fn parse_bin_rhs(lexer: &mut Lexer, lhs: ExprAst, prec_prev: i8) -> Self {
-    let prec_cur = Self::get_precedence(lexer.peek_first());
-    if prec_cur <= prec_prev {
-      return lhs;
+  /// This expression with all its spans reset, for comparing expressions by
+  /// their structure alone, as spans only matter for error messages.
+  pub fn without_spans(&self) -> Self {
+    let mut expr = self.clone();
+    expr.erase_spans();
+    expr
+  }
+
+  fn erase_spans(&mut self) {
+    match self {
+      Self::VarAst(_, span)
+      | Self::UnaryAst(_, _, span)
+      | Self::BinAst(_, _, _, span)
+      | Self::CallAst(_, _, span)
+      | Self::AssignAst(_, _, span)
+      | Self::ReturnAst(_, span)
+      | Self::TryAst(_, _, _, span)
+      | Self::FuncRefAst(_, span) => *span = Span::default(),
+      Self::MatchAst(_, arms, span) => {
+        *span = Span::default();
+        for (pattern, _) in arms {
+          match pattern {
+            Pattern::Lit(lit) => lit.erase_spans(),
+            Pattern::Range(lo, hi) => {
+              lo.erase_spans();
+              hi.erase_spans();
+            }
+            Pattern::Wild => {}
+          }
+        }
+      }
+      Self::LetAst(bindings, _) => {
+        let spans = bindings.iter_mut().map(|(_, span, _)| span);
+        spans.for_each(|span| *span = Span::default());
+      }
+      Self::VarInAst(vars, _) => {
+        let spans = vars.iter_mut().map(|(_, span, _)| span);
+        spans.for_each(|span| *span = Span::default());
+      }
+      Self::LetTupleAst(names, ..) | Self::LambdaAst(names, _) => {
+        let spans = names.iter_mut().map(|(_, span)| span);
+        spans.for_each(|span| *span = Span::default());
+      }
+      _ => {}
+    }
+    for child in self.children_mut() {
+      child.erase_spans();
+    }
+  }
+
+  /// Mutable counterpart of [`ExprAst::children`].
+  pub fn children_mut(&mut self) -> Vec<&mut ExprAst> {
+    match self {
+      Self::NumAst(_)
+      | Self::IntAst(_)
+      | Self::BoolAst(_)
+      | Self::UnitAst
+      | Self::StrAst(_)
+      | Self::VarAst(_, _)
+      | Self::FuncRefAst(..) => vec![],
+      Self::BinAst(lhs, _, rhs, _) | Self::IndexAst(lhs, rhs) => vec![lhs, rhs],
+      Self::IfAst { cond, then, els } => vec![cond, then, els],
+      Self::CallAst(_, exprs, _)
+      | Self::BlockAst(exprs)
+      | Self::SeqAst(exprs)
+      | Self::TupleAst(exprs)
+      | Self::ArrayAst(exprs) => exprs.iter_mut().collect(),
+      Self::UnaryAst(_, expr, _)
+      | Self::ElemAst(expr, _)
+      | Self::FieldAst(expr, _)
+      | Self::LambdaAst(_, expr)
+      | Self::AssignAst(_, expr, _)
+      | Self::ReturnAst(expr, _) => vec![expr],
+      Self::TryAst(expr, _, handler, _) => vec![expr, handler],
+      Self::LetAst(bindings, body) => {
+        let inits = bindings.iter_mut().map(|(.., init)| init);
+        inits.chain([body.as_mut()]).collect()
+      }
+      Self::LetTupleAst(_, init, body) => vec![init, body],
+      Self::VarInAst(vars, body) => {
+        let inits = vars.iter_mut().filter_map(|(.., init)| init.as_mut());
+        inits.chain([body.as_mut()]).collect()
+      }
+      Self::MatchAst(expr, arms, _) => {
+        let bodies = arms.iter_mut().map(|(_, body)| body);
+        [expr.as_mut()].into_iter().chain(bodies).collect()
+      }
+    }
+  }
+
+  /// Rewrites every `match` in this expression into nested `if`s, for the
+  /// backends that only know conditionals. The scrutinee is bound to a
+  /// name no program can spell, and is evaluated once. A value no arm
+  /// matches makes the rewritten expression yield 0.0, where the
+  /// interpreter reports an error instead.
+  pub fn lower_matches(&mut self) {
+    for child in self.children_mut() {
+      child.lower_matches();
+    }
+    if let Self::MatchAst(..) = self {
+      let Self::MatchAst(expr, arms, span) = std::mem::replace(self, Self::NumAst(0.0)) else {
+        unreachable!()
+      };
+      let var = || Box::new(Self::VarAst("$match".to_string(), Span::default()));
+      let mut chain = Self::NumAst(0.0);
+      for (pat, body) in arms.into_iter().rev() {
+        let cond = match pat {
+          Pattern::Wild => {
+            chain = body;
+            continue;
+          }
+          Pattern::Lit(lit) => Self::BinAst(var(), BinOp::Eq, Box::new(lit), span),
+          Pattern::Range(lo, hi) => Self::BinAst(
+            Box::new(Self::BinAst(Box::new(lo), BinOp::Le, var(), span)),
+            BinOp::And,
+            Box::new(Self::BinAst(var(), BinOp::Lt, Box::new(hi), span)),
+            span,
+          ),
+        };
+        chain = Self::IfAst {
+          cond: Box::new(cond),
+          then: Box::new(body),
+          els: Box::new(chain),
+        };
+      }
+      let binding = ("$match".to_string(), Span::default(), *expr);
+      *self = Self::LetAst(vec![binding], Box::new(chain));
+    }
+  }
+
+  /// Replaces every call of `assert` in this expression with `()`, for
+  /// builds that trade the checks for speed. The condition isn't evaluated
+  /// at all then.
+  pub fn strip_asserts(&mut self) {
+    match self {
+      Self::CallAst(name, ..) if name == "assert" => *self = Self::UnitAst,
+      _ => self
+        .children_mut()
+        .into_iter()
+        .for_each(Self::strip_asserts),
+    }
+  }
+
+  fn parse(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let lhs = Self::parse_primary(lexer)?;
+    let expr = Self::parse_bin_rhs(lexer, lhs, 0)?;
+    match lexer.peek_first()? {
+      &Token::Question => Self::parse_cond(lexer, expr),
+      &Token::Assign => Self::parse_assign(lexer, expr),
+      _ => Ok(expr),
     }
+  }
 
-    let operator = match lexer.next_token() {
-      Token::Less => '<',
-      Token::Add => '+',
-      Token::Sub => '-',
-      Token::Mul => '*',
-      _ => panic!(),
+  /// `a = expr` stores into a variable and evaluates to the stored value.
+  /// It binds loosest of all and nests to the right: `a = b = 1`.
+  fn parse_assign(lexer: &mut Lexer, dest: ExprAst) -> Result<Self, Diagnostic> {
+    let Self::VarAst(name, span) = dest else {
+      return Err(syntax_error(
+        lexer.span(),
+        "Destination of `=` must be a variable",
+      ));
     };
-    let mut rhs = Self::parse_primary(lexer);
-    let mut prec_next = Self::get_precedence(lexer.peek_first());
+    lexer.next_token()?; // eat `=`
+    let val = Self::parse(lexer)?;
+    Ok(Self::AssignAst(name, Box::new(val), span))
+  }
+
+  /// `cond ? then : else` is sugar for `if cond then then else else`. It
+  /// binds looser than any binary operator, and nests to the right:
+  /// `a ? b : c ? d : e` is `a ? b : (c ? d : e)`.
+  fn parse_cond(lexer: &mut Lexer, cond: ExprAst) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `?`
+    let then = Self::parse(lexer)?;
+    match lexer.next_token()? {
+      Token::Colon => (),
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected `:` in conditional expression",
+        ))
+      }
+    }
+    let els = Self::parse(lexer)?;
+    Ok(Self::IfAst {
+      cond: Box::new(cond),
+      then: Box::new(then),
+      els: Box::new(els),
+    })
+  }
+
+  /// `if cond then expr else expr`; the `else` branch extends as far to the
+  /// right as possible.
+  fn parse_if(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `if`
+    let cond = Self::parse(lexer)?;
+    match lexer.next_token()? {
+      Token::Then => (),
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected `then` after if condition",
+        ))
+      }
+    }
+    let then = Self::parse(lexer)?;
+    match lexer.next_token()? {
+      Token::Else => (),
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected `else` after then branch",
+        ))
+      }
+    }
+    let els = Self::parse(lexer)?;
+    Ok(Self::IfAst {
+      cond: Box::new(cond),
+      then: Box::new(then),
+      els: Box::new(els),
+    })
+  }
+
+  /// `match x { 0 -> a, 1..10 -> b, _ -> c }` tries the arms in order
+  /// and evaluates to the body of the first one whose pattern matches `x`.
+  fn parse_match(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    lexer.next_token()?; // eat `match`
+    let expr = Self::parse(lexer)?;
+    match lexer.next_token()? {
+      Token::LeftBrace => (),
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected `{` after match scrutinee",
+        ))
+      }
+    }
+    let mut arms = vec![];
+    loop {
+      if lexer.peek_first()? == &Token::RightBrace && !arms.is_empty() {
+        break;
+      }
+      let pat = Self::parse_pattern(lexer)?;
+      match lexer.next_token()? {
+        Token::Arrow => (),
+        _ => {
+          return Err(syntax_error(
+            lexer.last_span(),
+            "Expected `->` after match pattern",
+          ))
+        }
+      }
+      arms.push((pat, Self::parse(lexer)?));
+      match lexer.peek_first()? {
+        &Token::RightBrace => break,
+        &Token::Comma => {
+          lexer.next_token()?;
+        }
+        _ => {
+          return Err(syntax_error(
+            lexer.span(),
+            "Expected `}` or `,` after match arm",
+          ))
+        }
+      }
+    }
+    lexer.next_token()?; // eat `}`
+    Ok(Self::MatchAst(Box::new(expr), arms, span))
+  }
+
+  fn parse_pattern(lexer: &mut Lexer) -> Result<Pattern, Diagnostic> {
+    if lexer.peek_first()? == &Token::Underscore {
+      lexer.next_token()?;
+      return Ok(Pattern::Wild);
+    }
+    let lit = Self::parse_pattern_lit(lexer)?;
+    match lexer.peek_first()? {
+      &Token::DotDot => {
+        lexer.next_token()?;
+        Ok(Pattern::Range(lit, Self::parse_pattern_lit(lexer)?))
+      }
+      _ => Ok(Pattern::Lit(lit)),
+    }
+  }
+
+  fn parse_pattern_lit(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    Ok(match lexer.next_token()? {
+      Token::Number(n) => Self::NumAst(n),
+      Token::Int(i) => Self::IntAst(i),
+      Token::Str(s) => Self::StrAst(s),
+      Token::True => Self::BoolAst(true),
+      Token::False => Self::BoolAst(false),
+      Token::Sub => match lexer.next_token()? {
+        Token::Number(n) => Self::NumAst(-n),
+        Token::Int(i) => Self::IntAst(-i),
+        _ => {
+          return Err(syntax_error(
+            lexer.last_span(),
+            "Expected number after `-` in pattern",
+          ))
+        }
+      },
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected literal, range or `_` in match pattern",
+        ))
+      }
+    })
+  }
+
+  /// `return expr` leaves the enclosing function with the value of `expr`,
+  /// which extends as far to the right as possible.
+  fn parse_return(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    lexer.next_token()?; // eat `return`
+    let val = Self::parse(lexer)?;
+    Ok(Self::ReturnAst(Box::new(val), span))
+  }
+
+  /// `try expr catch handler` yields the value of `expr`, or of `handler`
+  /// when evaluating `expr` fails with a runtime error. `catch e -> handler`
+  /// binds the error to `e` in `handler`, as a tuple `(code, message)`.
+  fn parse_try(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    lexer.next_token()?; // eat `try`
+    let expr = Self::parse(lexer)?;
+    match lexer.next_token()? {
+      Token::Catch => (),
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected `catch` after try expression",
+        ))
+      }
+    }
+    let arrow = lexer.peek_second()? == &Token::Arrow;
+    let name = match lexer.peek_first()? {
+      Token::Identifier(_) if arrow => {
+        let Token::Identifier(name) = lexer.next_token()? else {
+          unreachable!()
+        };
+        lexer.next_token()?; // eat `->`
+        Some(name)
+      }
+      _ => None,
+    };
+    let handler = Self::parse(lexer)?;
+    Ok(Self::TryAst(Box::new(expr), name, Box::new(handler), span))
+  }
+
+  fn parse_bin_rhs(lexer: &mut Lexer, lhs: ExprAst, prec_prev: i8) -> Result<Self, Diagnostic> {
+    let prec_cur = Self::peek_precedence(lexer)?;
+    if prec_cur <= prec_prev {
+      return Ok(lhs);
+    }
+
+    let span = lexer.span();
+    let operator = lexer.next_token()?;
+    let mut rhs = Self::parse_primary(lexer)?;
+    let mut prec_next = Self::peek_precedence(lexer)?;
 
     loop {
       if prec_next <= prec_cur {
-        let lhs_new = Self::BinAst(Box::new(lhs), operator, Box::new(rhs));
+        let lhs_new =
+          match Self::is_comparison(&operator) && Self::is_comparison(lexer.peek_first()?) {
+            true => Self::parse_chain(lexer, lhs, vec![((operator, span), rhs)])?,
+            false => Self::new_bin(lhs, &operator, rhs, span)?,
+          };
         break Self::parse_bin_rhs(lexer, lhs_new, prec_prev);
       } else {
-        rhs = Self::parse_bin_rhs(lexer, rhs, prec_cur);
-        prec_next = Self::get_precedence(lexer.peek_first());
+        rhs = Self::parse_bin_rhs(lexer, rhs, prec_cur)?;
+        prec_next = Self::peek_precedence(lexer)?;
       }
     }
   }
 
-  fn parse_primary(lexer: &mut Lexer) -> Self {
-    match lexer.peek_first() {
-      &Token::Number(_) => Self::parse_number(lexer),
-      &Token::LeftParen => Self::parse_paren(lexer),
-      &Token::Identifier(_) => match lexer.peek_second() {
-        &Token::LeftParen => Self::parse_call(lexer),
-        _ => Self::parse_var(lexer),
+  fn is_comparison(token: &Token) -> bool {
+    matches!(
+      token,
+      Token::Less | Token::Greater | Token::LessEq | Token::GreaterEq
+    )
+  }
+
+  /// Parses the rest of a comparison chain such as `a < b <= c`, which
+  /// means `a < b && b <= c`. Operands other than the last are bound to
+  /// `$cmp<i>` names, so each is evaluated at most once and in order.
+  fn parse_chain(
+    lexer: &mut Lexer,
+    first: ExprAst,
+    mut links: Vec<((Token, Span), ExprAst)>,
+  ) -> Result<Self, Diagnostic> {
+    while Self::is_comparison(lexer.peek_first()?) {
+      let span = lexer.span();
+      let op = lexer.next_token()?;
+      let rhs = Self::parse_primary(lexer)?;
+      let rhs = Self::parse_bin_rhs(lexer, rhs, Self::get_precedence(&op))?;
+      links.push(((op, span), rhs));
+    }
+    let (binding, lhs) = Self::bind_operand(first, 0);
+    let chain = Self::lower_chain(lhs, links.into_iter(), 1)?;
+    Ok(Self::with_binding(binding, chain))
+  }
+
+  fn lower_chain(
+    lhs: ExprAst,
+    mut links: std::vec::IntoIter<((Token, Span), ExprAst)>,
+    i: usize,
+  ) -> Result<Self, Diagnostic> {
+    let ((op, span), rhs) = links.next().unwrap();
+    if links.len() == 0 {
+      return Self::new_bin(lhs, &op, rhs, span);
+    }
+    let (binding, rhs) = Self::bind_operand(rhs, i);
+    let rest = Self::lower_chain(rhs.clone(), links, i + 1)?;
+    let cmp = Self::new_bin(lhs, &op, rhs, span)?;
+    Ok(Self::with_binding(
+      binding,
+      Self::BinAst(Box::new(cmp), BinOp::And, Box::new(rest), span),
+    ))
+  }
+
+  /// Literals and variables can be repeated as they are; anything else is
+  /// named so that repeating it does not evaluate it again.
+  fn bind_operand(expr: ExprAst, i: usize) -> (Option<(String, Span, ExprAst)>, ExprAst) {
+    match expr {
+      Self::VarAst(_, _)
+      | Self::NumAst(_)
+      | Self::IntAst(_)
+      | Self::BoolAst(_)
+      | Self::StrAst(_) => (None, expr),
+      _ => {
+        let name = format!("$cmp{}", i);
+        (
+          Some((name.clone(), Span::default(), expr)),
+          Self::VarAst(name, Span::default()),
+        )
+      }
+    }
+  }
+
+  fn with_binding(binding: Option<(String, Span, ExprAst)>, body: ExprAst) -> Self {
+    match binding {
+      Some(binding) => Self::LetAst(vec![binding], Box::new(body)),
+      None => body,
+    }
+  }
+
+  /// Builds a binary expression. User-defined operators are lowered to
+  /// calls of their `binary<op>` function right away.
+  fn new_bin(lhs: ExprAst, op: &Token, rhs: ExprAst, span: Span) -> Result<Self, Diagnostic> {
+    match (BinOp::from_token(op), op) {
+      (Some(op), _) => Ok(Self::BinAst(Box::new(lhs), op, Box::new(rhs), span)),
+      (None, Token::Op(c)) => Ok(Self::CallAst(format!("binary{}", c), vec![lhs, rhs], span)),
+      _ => Err(syntax_error(
+        span,
+        format!("Unknown binary operator {:?}", op),
+      )),
+    }
+  }
+
+  fn parse_primary(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    let expr = match lexer.peek_first()? {
+      &Token::Number(_) | &Token::Int(_) => Self::parse_number(lexer)?,
+      &Token::Str(_) => Self::parse_str(lexer)?,
+      &Token::Not | &Token::Sub => Self::parse_unary(lexer)?,
+      &Token::True => {
+        lexer.next_token()?;
+        Self::BoolAst(true)
+      }
+      &Token::False => {
+        lexer.next_token()?;
+        Self::BoolAst(false)
+      }
+      &Token::LeftParen => Self::parse_paren(lexer)?,
+      &Token::LeftBrace => Self::parse_block(lexer)?,
+      &Token::LeftBracket => Self::parse_array(lexer)?,
+      &Token::Lambda => Self::parse_lambda(lexer)?,
+      &Token::Let => Self::parse_let(lexer)?,
+      &Token::Var => Self::parse_var_in(lexer)?,
+      &Token::If => Self::parse_if(lexer)?,
+      &Token::Match => Self::parse_match(lexer)?,
+      &Token::Return => Self::parse_return(lexer)?,
+      &Token::Try => Self::parse_try(lexer)?,
+      &Token::BitAnd => Self::parse_func_ref(lexer)?,
+      &Token::Identifier(_) => match lexer.peek_second()? {
+        &Token::LeftParen => Self::parse_call(lexer)?,
+        _ => Self::parse_var(lexer)?,
       },
-      _ => panic!(),
+      tok => {
+        return Err(syntax_error(
+          span,
+          format!("Expected an expression, found {:?}", tok),
+        ))
+      }
+    };
+    Self::parse_postfix(lexer, expr, span)
+  }
+
+  /// Values can't be called with `(...)`, so `math.sin(x)` calls the
+  /// function `sin` of the `math` namespace rather than a field.
+  fn parse_postfix(lexer: &mut Lexer, mut expr: ExprAst, span: Span) -> Result<Self, Diagnostic> {
+    loop {
+      match lexer.peek_first()? {
+        &Token::Dot => {
+          lexer.next_token()?; // eat `.`
+          expr = match lexer.next_token()? {
+            Token::Int(n) => Self::ElemAst(Box::new(expr), n as usize),
+            Token::Identifier(field) if lexer.peek_first()? == &Token::LeftParen => {
+              let Some(ns) = expr.dotted_name() else {
+                return Err(syntax_error(
+                  lexer.last_span(),
+                  format!("Expected namespace before `.{}(`", field),
+                ));
+              };
+              Self::CallAst(format!("{}.{}", ns, field), Self::parse_args(lexer)?, span)
+            }
+            Token::Identifier(field) => Self::FieldAst(Box::new(expr), field),
+            _ => {
+              return Err(syntax_error(
+                lexer.last_span(),
+                "Expected tuple index or field name after `.`",
+              ))
+            }
+          };
+        }
+        &Token::LeftBracket => {
+          lexer.next_token()?; // eat `[`
+          let index = Self::parse(lexer)?;
+          match lexer.next_token()? {
+            Token::RightBracket => (),
+            _ => return Err(syntax_error(lexer.last_span(), "Expected `]` after index")),
+          }
+          expr = Self::IndexAst(Box::new(expr), Box::new(index));
+        }
+        _ => break Ok(expr),
+      }
     }
   }
 
-  fn parse_number(lexer: &mut Lexer) -> Self {
-    let Token::Number(n) = lexer.next_token() else {panic!()};
-    Self::NumAst(n)
+  /// Prefix operators bind tighter than any binary operator: `!a < b` is
+  /// `(!a) < b`.
+  fn parse_unary(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    let op = match lexer.next_token()? {
+      Token::Not => UnOp::Not,
+      Token::Sub => UnOp::Neg,
+      _ => unreachable!(),
+    };
+    let operand = Self::parse_primary(lexer)?;
+    Ok(Self::UnaryAst(op, Box::new(operand), span))
+  }
+
+  fn parse_number(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    match lexer.next_token()? {
+      Token::Number(n) => Ok(Self::NumAst(n)),
+      Token::Int(i) => Ok(Self::IntAst(i)),
+      _ => unreachable!(),
+    }
+  }
+
+  fn parse_str(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let Token::Str(s) = lexer.next_token()? else {
+      unreachable!()
+    };
+    Ok(Self::StrAst(s))
+  }
+
+  /// `(expr)` groups, while `(a, b, ...)` builds a tuple.
+  fn parse_paren(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `(`
+    if lexer.peek_first()? == &Token::RightParen {
+      lexer.next_token()?;
+      return Ok(Self::UnitAst);
+    }
+    let mut exprs = vec![Self::parse(lexer)?];
+    loop {
+      match lexer.next_token()? {
+        Token::RightParen => break,
+        Token::Comma => exprs.push(Self::parse(lexer)?),
+        _ => return Err(syntax_error(lexer.last_span(), "Expected `)` token")),
+      }
+    }
+    match exprs.len() {
+      1 => Ok(exprs.pop().unwrap()),
+      _ => Ok(Self::TupleAst(exprs)),
+    }
+  }
+
+  /// `{ expr; expr; ... }` with an optional trailing `;`. The block must
+  /// hold at least one expression, since its value is the last one.
+  fn parse_block(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `{`
+    let mut exprs = vec![];
+    loop {
+      if lexer.peek_first()? == &Token::RightBrace && !exprs.is_empty() {
+        break;
+      }
+      exprs.push(Self::parse(lexer)?);
+      match lexer.peek_first()? {
+        &Token::RightBrace => break,
+        &Token::Semi => {
+          lexer.next_token()?;
+        }
+        _ => return Err(syntax_error(lexer.span(), "Expected `}` or `;` in block")),
+      }
+    }
+    lexer.next_token()?; // eat `}`
+    Ok(Self::BlockAst(exprs))
+  }
+
+  fn parse_array(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `[`
+    let mut elems = vec![];
+    loop {
+      if lexer.peek_first()? == &Token::RightBracket {
+        break;
+      }
+      elems.push(Self::parse(lexer)?);
+      match lexer.peek_first()? {
+        &Token::RightBracket => break,
+        &Token::Comma => {
+          lexer.next_token()?;
+        }
+        _ => {
+          return Err(syntax_error(
+            lexer.span(),
+            "Expected `]` or `,` in array literal",
+          ))
+        }
+      }
+    }
+    lexer.next_token()?; // eat `]`
+    Ok(Self::ArrayAst(elems))
+  }
+
+  /// `\(x, y) body` - the body extends as far to the right as possible.
+  fn parse_lambda(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `\`
+    let args = ProtoAst::parse_args(lexer)?
+      .into_iter()
+      .map(|(arg, at, _)| (arg, at))
+      .collect();
+    let body = Self::parse(lexer)?;
+    Ok(Self::LambdaAst(args, Box::new(body)))
+  }
+
+  /// `let a = 1, b = a + 1 in body`. Bindings are scoped sequentially: each
+  /// initializer sees the bindings before it, and all of them are visible
+  /// in the body only. `let (a, b) = t` destructures a tuple.
+  fn parse_let(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `let`
+    let mut bindings = vec![];
+    loop {
+      let pat = match lexer.next_token()? {
+        Token::Identifier(name) => LetPat::Name(name, lexer.last_span()),
+        Token::LeftParen => {
+          let mut names = vec![];
+          loop {
+            match lexer.next_token()? {
+              Token::Identifier(name) => names.push((name, lexer.last_span())),
+              _ => {
+                return Err(syntax_error(
+                  lexer.last_span(),
+                  "Expected identifier in tuple pattern",
+                ))
+              }
+            }
+            match lexer.next_token()? {
+              Token::Comma => (),
+              Token::RightParen => break,
+              _ => {
+                return Err(syntax_error(
+                  lexer.last_span(),
+                  "Expected `,` or `)` in tuple pattern",
+                ))
+              }
+            }
+          }
+          LetPat::Tuple(names)
+        }
+        _ => {
+          return Err(syntax_error(
+            lexer.last_span(),
+            "Expected identifier or tuple pattern after `let`",
+          ))
+        }
+      };
+      match lexer.next_token()? {
+        Token::Assign => (),
+        _ => {
+          return Err(syntax_error(
+            lexer.last_span(),
+            "Expected `=` in let binding",
+          ))
+        }
+      }
+      bindings.push((pat, Self::parse(lexer)?));
+      match lexer.next_token()? {
+        Token::Comma => (),
+        Token::In => break,
+        _ => {
+          return Err(syntax_error(
+            lexer.last_span(),
+            "Expected `,` or `in` after let binding",
+          ))
+        }
+      }
+    }
+    let body = Self::parse(lexer)?;
+    Ok(Self::new_let(bindings, body))
+  }
+
+  /// Builds nested lets, giving each tuple pattern a `LetTupleAst` of its
+  /// own. Since bindings are scoped sequentially, the nesting doesn't change
+  /// what any of them sees.
+  fn new_let(bindings: Vec<(LetPat, ExprAst)>, mut body: ExprAst) -> Self {
+    let mut names = vec![];
+    for (pat, init) in bindings.into_iter().rev() {
+      match pat {
+        LetPat::Name(name, at) => names.insert(0, (name, at, init)),
+        LetPat::Tuple(pat) => {
+          if !names.is_empty() {
+            body = Self::LetAst(std::mem::take(&mut names), Box::new(body));
+          }
+          body = Self::LetTupleAst(pat, Box::new(init), Box::new(body));
+        }
+      }
+    }
+    match names.is_empty() {
+      true => body,
+      false => Self::LetAst(names, Box::new(body)),
+    }
+  }
+
+  /// `var a = 1, b in body` declares mutable variables that are visible in
+  /// the later initializers and the body; a missing initializer means 0.0.
+  fn parse_var_in(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let vars = Self::parse_var_list(lexer)?;
+    match lexer.next_token()? {
+      Token::In => (),
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected `,` or `in` after var declaration",
+        ))
+      }
+    }
+    let body = Self::parse(lexer)?;
+    Ok(Self::VarInAst(vars, Box::new(body)))
+  }
+
+  /// Parses `var a = 1, b` up to the token following the last declaration.
+  fn parse_var_list(lexer: &mut Lexer) -> Result<Vec<(String, Span, Option<ExprAst>)>, Diagnostic> {
+    lexer.next_token()?; // eat `var`
+    let mut vars = vec![];
+    loop {
+      let Token::Identifier(name) = lexer.next_token()? else {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected identifier after `var`",
+        ));
+      };
+      let at = lexer.last_span();
+      let init = match lexer.peek_first()? {
+        &Token::Assign => {
+          lexer.next_token()?; // eat `=`
+          Some(Self::parse(lexer)?)
+        }
+        _ => None,
+      };
+      vars.push((name, at, init));
+      match lexer.peek_first()? {
+        &Token::Comma => {
+          lexer.next_token()?;
+        }
+        _ => break Ok(vars),
+      }
+    }
+  }
+
+  fn parse_var(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    let Token::Identifier(s) = lexer.next_token()? else {
+      unreachable!()
+    };
+    Ok(Self::VarAst(s, span))
+  }
+
+  /// `&foo` refers to the function `foo` even where a variable of that name
+  /// is in scope.
+  fn parse_func_ref(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    lexer.next_token()?; // eat `&`
+    let Token::Identifier(name) = lexer.next_token()? else {
+      return Err(syntax_error(
+        lexer.last_span(),
+        "Expected function name after `&`",
+      ));
+    };
+    Ok(Self::FuncRefAst(parse_dotted(lexer, name)?, span))
+  }
+
+  /// The name `a.b.c` of a namespaced function, when parsed as fields.
+  fn dotted_name(&self) -> Option<String> {
+    match self {
+      Self::VarAst(name, _) => Some(name.clone()),
+      Self::FieldAst(expr, field) => Some(format!("{}.{}", expr.dotted_name()?, field)),
+      _ => None,
+    }
+  }
+
+  fn parse_call(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    let Token::Identifier(name) = lexer.next_token()? else {
+      unreachable!()
+    };
+    Ok(Self::CallAst(name, Self::parse_args(lexer)?, span))
+  }
+
+  /// Parses the parenthesized arguments of a call.
+  fn parse_args(lexer: &mut Lexer) -> Result<Vec<ExprAst>, Diagnostic> {
+    lexer.next_token()?; // eat `(`
+    let mut args = vec![];
+    loop {
+      if lexer.peek_first()? == &Token::RightParen {
+        break;
+      }
+      args.push(Self::parse(lexer)?);
+      match lexer.peek_first()? {
+        &Token::RightParen => break,
+        &Token::Comma => {
+          lexer.next_token()?;
+        }
+        _ => {
+          return Err(syntax_error(
+            lexer.span(),
+            "Expected ')' or ',' in argument list",
+          ))
+        }
+      }
+    }
+    lexer.next_token()?; // eat `)`
+    Ok(args)
+  }
+
+  /// The precedence of the next token as a binary operator, or -1. An
+  /// operator declared with `def binary` is registered on the lexer as soon
+  /// as its prototype is parsed, so it can be used in its own body and in
+  /// everything the lexer reads after it. `|` was such an operator before
+  /// it was bitwise or, and takes the precedence it's declared with.
+  fn peek_precedence(lexer: &mut Lexer) -> Result<i8, Diagnostic> {
+    Ok(match lexer.peek_first()? {
+      &Token::Op(c) => lexer.precedence(c).unwrap_or(-1),
+      Token::BitOr => lexer
+        .precedence('|')
+        .unwrap_or(Self::get_precedence(&Token::BitOr)),
+      tok => Self::get_precedence(tok),
+    })
+  }
+
+  fn get_precedence(token: &Token) -> i8 {
+    match token {
+      &Token::Or => 4,
+      &Token::And => 6,
+      &Token::Equal | &Token::NotEqual => 8,
+      &Token::Less | &Token::Greater | &Token::LessEq | &Token::GreaterEq => 10,
+      &Token::BitOr => 12,
+      &Token::Xor => 13,
+      &Token::BitAnd => 14,
+      &Token::Shl | &Token::Shr => 16,
+      &Token::Add => 20,
+      &Token::Sub => 20,
+      &Token::Mul | &Token::Div | &Token::Rem => 40,
+      _ => -1, // other tokens means the ending of a binary expression
+    }
+  }
+}
+
+impl BinOp {
+  /// The builtin operator that the function `name` overloads, such as `+`
+  /// for `binary+`.
+  pub fn overloaded_by(name: &str) -> Option<Self> {
+    let op = match name.strip_prefix("binary")? {
+      "+" => Self::Add,
+      "-" => Self::Sub,
+      "*" => Self::Mul,
+      "/" => Self::Div,
+      "%" => Self::Rem,
+      "<" => Self::Lt,
+      ">" => Self::Gt,
+      "<=" => Self::Le,
+      ">=" => Self::Ge,
+      "==" => Self::Eq,
+      "!=" => Self::Ne,
+      "&" => Self::BitAnd,
+      "|" => Self::BitOr,
+      "xor" => Self::Xor,
+      "<<" => Self::Shl,
+      ">>" => Self::Shr,
+      _ => return None,
+    };
+    Some(op)
+  }
+
+  pub fn is_bitwise(&self) -> bool {
+    matches!(
+      self,
+      Self::BitAnd | Self::BitOr | Self::Xor | Self::Shl | Self::Shr
+    )
+  }
+
+  fn from_token(token: &Token) -> Option<Self> {
+    match token {
+      &Token::Add => Some(Self::Add),
+      &Token::Sub => Some(Self::Sub),
+      &Token::Mul => Some(Self::Mul),
+      &Token::Div => Some(Self::Div),
+      &Token::Rem => Some(Self::Rem),
+      &Token::Less => Some(Self::Lt),
+      &Token::Greater => Some(Self::Gt),
+      &Token::LessEq => Some(Self::Le),
+      &Token::GreaterEq => Some(Self::Ge),
+      &Token::Equal => Some(Self::Eq),
+      &Token::NotEqual => Some(Self::Ne),
+      &Token::And => Some(Self::And),
+      &Token::Or => Some(Self::Or),
+      &Token::BitAnd => Some(Self::BitAnd),
+      &Token::BitOr => Some(Self::BitOr),
+      &Token::Xor => Some(Self::Xor),
+      &Token::Shl => Some(Self::Shl),
+      &Token::Shr => Some(Self::Shr),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Add => "+",
+      Self::Sub => "-",
+      Self::Mul => "*",
+      Self::Div => "/",
+      Self::Rem => "%",
+      Self::Lt => "<",
+      Self::Gt => ">",
+      Self::Le => "<=",
+      Self::Ge => ">=",
+      Self::Eq => "==",
+      Self::Ne => "!=",
+      Self::And => "&&",
+      Self::Or => "||",
+      Self::BitAnd => "&",
+      Self::BitOr => "|",
+      Self::Xor => "xor",
+      Self::Shl => "<<",
+      Self::Shr => ">>",
+    }
+  }
+}
+
+impl ProtoAst {
+  fn parse(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    let span = lexer.span();
+    let name = match lexer.next_token()? {
+      Token::Identifier(name) => parse_dotted(lexer, name)?,
+      Token::Binary => Self::parse_binary_op(lexer)?,
+      _ => return Err(syntax_error(lexer.last_span(), "Expect an identifier")),
+    };
+    let mut args = vec![];
+    let (mut arg_spans, mut arg_tys) = (vec![], vec![]);
+    for (arg, at, ty) in Self::parse_args(lexer)? {
+      args.push(arg);
+      arg_spans.push(at);
+      arg_tys.push(ty);
+    }
+    if name.starts_with("binary") && args.len() != 2 {
+      return Err(syntax_error(
+        span,
+        format!("Invalid number of operands for operator `{}`", &name[6..]),
+      ));
+    }
+    let ret_ty = Self::parse_type_ann(lexer)?;
+    Ok(Self {
+      name,
+      span,
+      args,
+      arg_spans,
+      arg_tys,
+      ret_ty,
+    })
+  }
+
+  /// Parses the `^ 5` in `def binary ^ 5 (a b)`, registering the operator
+  /// with the given precedence (30 when omitted), and returns the name of
+  /// the function implementing it. `def binary + (a: Point, b: Point)`
+  /// overloads a builtin operator, which keeps its precedence. `|` was
+  /// declared like `^` before it was bitwise or, so `def binary | 5 (a, b)`
+  /// overloads bitwise or with the precedence given.
+  fn parse_binary_op(lexer: &mut Lexer) -> Result<String, Diagnostic> {
+    let op = match lexer.next_token()? {
+      Token::Op(op) => op,
+      tok => match BinOp::from_token(&tok) {
+        Some(BinOp::And | BinOp::Or) | None => {
+          return Err(syntax_error(
+            lexer.last_span(),
+            "Expected operator after `binary`",
+          ))
+        }
+        Some(op) => return Ok(format!("binary{}", op.as_str())),
+      },
+    };
+    let prec = match lexer.peek_first()? {
+      &Token::Int(n) => {
+        lexer.next_token()?;
+        n
+      }
+      _ if op == '|' => return Ok(format!("binary{}", op)),
+      _ => 30,
+    };
+    if !(1..=100).contains(&prec) {
+      return Err(syntax_error(
+        lexer.last_span(),
+        "Invalid precedence: must be 1..100",
+      ));
+    }
+    lexer.declare_operator(op, prec as i8);
+    Ok(format!("binary{}", op))
+  }
+
+  /// Parses a parenthesized parameter list `(a, b: int, ...)`, where each
+  /// parameter may carry a type annotation, with where each is named.
+  fn parse_args(lexer: &mut Lexer) -> Result<Vec<(String, Span, Option<String>)>, Diagnostic> {
+    match lexer.next_token()? {
+      Token::LeftParen => (),
+      _ => {
+        return Err(syntax_error(
+          lexer.last_span(),
+          "Expected `(` before parameter list",
+        ))
+      }
+    }
+    let mut args = vec![];
+    loop {
+      match lexer.next_token()? {
+        Token::RightParen => break,
+        Token::Comma => (),
+        Token::Identifier(s) => {
+          let at = lexer.last_span();
+          args.push((s, at, Self::parse_type_ann(lexer)?))
+        }
+        _ => {
+          return Err(syntax_error(
+            lexer.last_span(),
+            "Expected parameter name or `)`",
+          ))
+        }
+      }
+    }
+    Ok(args)
+  }
+
+  /// Parses an optional `: type` annotation. Annotations are only recorded
+  /// here; the numeric backends ignore them.
+  fn parse_type_ann(lexer: &mut Lexer) -> Result<Option<String>, Diagnostic> {
+    if lexer.peek_first()? != &Token::Colon {
+      return Ok(None);
+    }
+    lexer.next_token()?; // eat `:`
+    Ok(Some(parse_type(lexer)?))
+  }
+
+  /// The function an extern binds to: `extern math.sin(x)` declares `sin`
+  /// under the name `math.sin`.
+  pub fn symbol(&self) -> &str {
+    self.name.rsplit('.').next().unwrap()
+  }
+}
+
+/// Parses a type name such as `int` or `geo.Point`, or a tuple type such as
+/// `(int, (str, _))`, whose `_` elements may be of any type; a function
+/// returns several values as a tuple. Like in expressions, `()` is unit and
+/// `(int)` is just `int`.
+fn parse_type(lexer: &mut Lexer) -> Result<String, Diagnostic> {
+  Ok(match lexer.next_token()? {
+    Token::Identifier(ty) => parse_dotted(lexer, ty)?,
+    Token::Underscore => "_".to_string(),
+    Token::LeftParen if lexer.peek_first()? == &Token::RightParen => {
+      lexer.next_token()?;
+      "unit".to_string()
+    }
+    Token::LeftParen => {
+      let mut elems = vec![parse_type(lexer)?];
+      loop {
+        match lexer.next_token()? {
+          Token::RightParen => break,
+          Token::Comma => elems.push(parse_type(lexer)?),
+          _ => return Err(syntax_error(lexer.last_span(), "Expected `)` token")),
+        }
+      }
+      match elems.len() {
+        1 => elems.pop().unwrap(),
+        _ => format!("({})", elems.join(", ")),
+      }
+    }
+    _ => {
+      return Err(syntax_error(
+        lexer.last_span(),
+        "Expected type name after `:`",
+      ))
+    }
+  })
+}
+
+/// Parses the rest of a namespaced name `math.sin` after its first part.
+fn parse_dotted(lexer: &mut Lexer, mut name: String) -> Result<String, Diagnostic> {
+  while lexer.peek_first()? == &Token::Dot {
+    let Token::Identifier(_) = lexer.peek_second()? else {
+      break;
+    };
+    lexer.next_token()?; // eat `.`
+    let Token::Identifier(part) = lexer.next_token()? else {
+      unreachable!()
+    };
+    name = format!("{}.{}", name, part);
+  }
+  Ok(name)
+}
+
+impl StructAst {
+  fn parse(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `struct`
+    let span = lexer.span();
+    let Token::Identifier(name) = lexer.next_token()? else {
+      return Err(syntax_error(
+        lexer.last_span(),
+        "Expected identifier after `struct`",
+      ));
+    };
+    let (fields, field_tys) = ProtoAst::parse_args(lexer)?
+      .into_iter()
+      .map(|(field, _, ty)| (field, ty))
+      .unzip();
+    Ok(Self {
+      name,
+      span,
+      fields,
+      field_tys,
+    })
+  }
+}
+
+impl FuncAst {
+  fn parse(lexer: &mut Lexer) -> Result<Self, Diagnostic> {
+    lexer.next_token()?; // eat `def`
+    let proto = ProtoAst::parse(lexer)?;
+    let body = Self::parse_body(lexer)?;
+    Ok(Self { proto, body })
+  }
+
+  /// A function body is one expression, which a `;` ends, so that a
+  /// top-level expression can follow. Braces sequence several, as in
+  /// `def f(x) { g(x); h(x); x + 1 }`: the earlier ones are evaluated for
+  /// effect and the last one is the result, in the scope of the function.
+  fn parse_body(lexer: &mut Lexer) -> Result<ExprAst, Diagnostic> {
+    Ok(match ExprAst::parse(lexer)? {
+      ExprAst::BlockAst(exprs) => ExprAst::SeqAst(exprs),
+      body => body,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::lexer;
+
+  use super::*;
+  use std::io::Cursor;
+
+  #[test]
+  fn expr_number() {
+    let src = " 42 ";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(ast, ExprAst::IntAst(42));
+  }
+
+  #[test]
+  fn expr_variable() {
+    let src = "foo";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(ast, ExprAst::VarAst("foo".to_string(), Span::default()));
+  }
+
+  #[test]
+  fn expr_paren() {
+    let src = "(foo )";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(ast, ExprAst::VarAst("foo".to_string(), Span::default()));
+  }
+
+  #[test]
+  fn expr_bin_expr_1() {
+    let src = "1 + foo";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      ExprAst::BinAst(
+        Box::new(ExprAst::IntAst(1)),
+        BinOp::Add,
+        Box::new(ExprAst::VarAst("foo".to_string(), Span::default())),
+        Span::default()
+      )
+    );
+  }
+
+  #[test]
+  fn expr_bin_expr_2() {
+    let src = "1 + foo * 42";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      ExprAst::BinAst(
+        Box::new(ExprAst::IntAst(1)),
+        BinOp::Add,
+        Box::new(ExprAst::BinAst(
+          Box::new(ExprAst::VarAst("foo".to_string(), Span::default())),
+          BinOp::Mul,
+          Box::new(ExprAst::IntAst(42)),
+          Span::default(),
+        )),
+        Span::default()
+      )
+    )
+  }
+
+  #[test]
+  fn expr_bin_expr_3() {
+    let src = "1 + foo - 42";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      ExprAst::BinAst(
+        Box::new(ExprAst::BinAst(
+          Box::new(ExprAst::IntAst(1)),
+          BinOp::Add,
+          Box::new(ExprAst::VarAst("foo".to_string(), Span::default())),
+          Span::default(),
+        )),
+        BinOp::Sub,
+        Box::new(ExprAst::IntAst(42)),
+        Span::default(),
+      )
+    )
+  }
+
+  #[test]
+  fn expr_bin_expr_4() {
+    use ExprAst::*;
+    let src = "1 < foo + bar * 42 - baz";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      BinAst(
+        Box::new(IntAst(1)),
+        BinOp::Lt,
+        Box::new(BinAst(
+          Box::new(BinAst(
+            Box::new(VarAst("foo".to_string(), Span::default())),
+            BinOp::Add,
+            Box::new(BinAst(
+              Box::new(VarAst("bar".to_string(), Span::default())),
+              BinOp::Mul,
+              Box::new(IntAst(42)),
+              Span::default()
+            )),
+            Span::default(),
+          )),
+          BinOp::Sub,
+          Box::new(VarAst("baz".to_string(), Span::default())),
+          Span::default(),
+        )),
+        Span::default(),
+      )
+    );
+  }
+
+  #[test]
+  fn expr_func_call() {
+    let src = "foo(1 + 2, bar, 42)";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    use ExprAst::*;
+    assert_eq!(
+      ast,
+      CallAst(
+        "foo".to_string(),
+        vec![
+          BinAst(
+            Box::new(IntAst(1)),
+            BinOp::Add,
+            Box::new(IntAst(2)),
+            Span::default()
+          ),
+          VarAst("bar".to_string(), Span::default()),
+          IntAst(42),
+        ],
+        Span::default()
+      )
+    )
+  }
+
+  #[test]
+  fn expr_comparisons() {
+    use ExprAst::*;
+    let src = "a <= b == c > 1";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      BinAst(
+        Box::new(BinAst(
+          Box::new(VarAst("a".to_string(), Span::default())),
+          BinOp::Le,
+          Box::new(VarAst("b".to_string(), Span::default())),
+          Span::default()
+        )),
+        BinOp::Eq,
+        Box::new(BinAst(
+          Box::new(VarAst("c".to_string(), Span::default())),
+          BinOp::Gt,
+          Box::new(IntAst(1)),
+          Span::default()
+        )),
+        Span::default(),
+      )
+    )
+  }
+
+  #[test]
+  fn expr_logical() {
+    use ExprAst::*;
+    let src = "a || b && c < 1";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      BinAst(
+        Box::new(VarAst("a".to_string(), Span::default())),
+        BinOp::Or,
+        Box::new(BinAst(
+          Box::new(VarAst("b".to_string(), Span::default())),
+          BinOp::And,
+          Box::new(BinAst(
+            Box::new(VarAst("c".to_string(), Span::default())),
+            BinOp::Lt,
+            Box::new(IntAst(1)),
+            Span::default()
+          )),
+          Span::default(),
+        )),
+        Span::default(),
+      )
+    )
+  }
+
+  #[test]
+  fn expr_unary() {
+    use ExprAst::*;
+    let src = "!a < -b[0]";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      BinAst(
+        Box::new(UnaryAst(
+          UnOp::Not,
+          Box::new(VarAst("a".to_string(), Span::default())),
+          Span::default()
+        )),
+        BinOp::Lt,
+        Box::new(UnaryAst(
+          UnOp::Neg,
+          Box::new(IndexAst(
+            Box::new(VarAst("b".to_string(), Span::default())),
+            Box::new(IntAst(0))
+          )),
+          Span::default()
+        )),
+        Span::default(),
+      )
+    )
+  }
+
+  #[test]
+  fn expr_cond() {
+    use ExprAst::*;
+    let src = "a < 1 ? b : c ? 2 : 3";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      IfAst {
+        cond: Box::new(BinAst(
+          Box::new(VarAst("a".to_string(), Span::default())),
+          BinOp::Lt,
+          Box::new(IntAst(1)),
+          Span::default()
+        )),
+        then: Box::new(VarAst("b".to_string(), Span::default())),
+        els: Box::new(IfAst {
+          cond: Box::new(VarAst("c".to_string(), Span::default())),
+          then: Box::new(IntAst(2)),
+          els: Box::new(IntAst(3))
+        }),
+      }
+    )
+  }
+
+  #[test]
+  fn expr_bool() {
+    use ExprAst::*;
+    let src = "if true then false else x";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      IfAst {
+        cond: Box::new(BoolAst(true)),
+        then: Box::new(BoolAst(false)),
+        els: Box::new(VarAst("x".to_string(), Span::default())),
+      }
+    )
+  }
+
+  #[test]
+  fn expr_if() {
+    use ExprAst::*;
+    let src = "if x < 3 then 1 else x + 1";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      IfAst {
+        cond: Box::new(BinAst(
+          Box::new(VarAst("x".to_string(), Span::default())),
+          BinOp::Lt,
+          Box::new(IntAst(3)),
+          Span::default()
+        )),
+        then: Box::new(IntAst(1)),
+        els: Box::new(BinAst(
+          Box::new(VarAst("x".to_string(), Span::default())),
+          BinOp::Add,
+          Box::new(IntAst(1)),
+          Span::default()
+        )),
+      }
+    )
+  }
+
+  #[test]
+  fn expr_block() {
+    use ExprAst::*;
+    let src = "{ foo(1); bar; 2 * 3; }";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      BlockAst(vec![
+        CallAst("foo".to_string(), vec![IntAst(1)], Span::default()),
+        VarAst("bar".to_string(), Span::default()),
+        BinAst(
+          Box::new(IntAst(2)),
+          BinOp::Mul,
+          Box::new(IntAst(3)),
+          Span::default()
+        ),
+      ])
+    )
   }
 
-  fn parse_paren(lexer: &mut Lexer) -> Self {
-    lexer.next_token(); // eat `(`
-    let expr = Self::parse(lexer);
-
-    match lexer.peek_first() {
-      &Token::RightParen => {
-        lexer.next_token();
-      } // eat `)`
-      _ => panic!("Expected `)` token"),
-    }
-    expr
+  #[test]
+  fn expr_block_empty() {
+    let src = "{}";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    assert!(ExprAst::parse(&mut lexer).is_err());
   }
 
-  fn parse_var(lexer: &mut Lexer) -> Self {
-    let Token::Identifier(s) = lexer.next_token() else {panic!("Expected Identifier token")};
-    Self::VarAst(s)
+  #[test]
+  fn expr_tuple() {
+    use ExprAst::*;
+    let src = "(1, (a, b).1).0";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      ElemAst(
+        Box::new(TupleAst(vec![
+          IntAst(1),
+          ElemAst(
+            Box::new(TupleAst(vec![
+              VarAst("a".to_string(), Span::default()),
+              VarAst("b".to_string(), Span::default())
+            ])),
+            1
+          ),
+        ])),
+        0
+      )
+    )
   }
 
-  fn parse_call(lexer: &mut Lexer) -> Self {
-    let Token::Identifier(name) = lexer.next_token() else {panic!("Expected Identifier token")};
-    lexer.next_token(); // eat `(`
-    let mut args = vec![];
-    loop {
-      if lexer.peek_first() == &Token::RightParen {
-        break;
-      }
-      args.push(Self::parse(lexer));
-      match lexer.peek_first() {
-        &Token::RightParen => break,
-        &Token::Comma => {
-          lexer.next_token();
-        }
-        _ => panic!("Expected ')' or ',' in argument list"),
-      }
-    }
-    lexer.next_token(); // eat `)`
-    Self::CallAst(name, args)
+  #[test]
+  fn expr_array() {
+    use ExprAst::*;
+    let src = "[1, [], a + 2]";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      ArrayAst(vec![
+        IntAst(1),
+        ArrayAst(vec![]),
+        BinAst(
+          Box::new(VarAst("a".to_string(), Span::default())),
+          BinOp::Add,
+          Box::new(IntAst(2)),
+          Span::default()
+        ),
+      ])
+    )
   }
 
-  fn get_precedence(token: &Token) -> i8 {
-    match token {
-      &Token::Less => 10,
-      &Token::Add => 20,
-      &Token::Sub => 20,
-      &Token::Mul => 40,
-      _ => -1, // other tokens means the ending of a binary expression
-    }
+  #[test]
+  fn expr_index() {
+    use ExprAst::*;
+    let src = "a[i][j + 1] * [1, 2][0]";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      BinAst(
+        Box::new(IndexAst(
+          Box::new(IndexAst(
+            Box::new(VarAst("a".to_string(), Span::default())),
+            Box::new(VarAst("i".to_string(), Span::default()))
+          )),
+          Box::new(BinAst(
+            Box::new(VarAst("j".to_string(), Span::default())),
+            BinOp::Add,
+            Box::new(IntAst(1)),
+            Span::default()
+          )),
+        )),
+        BinOp::Mul,
+        Box::new(IndexAst(
+          Box::new(ArrayAst(vec![IntAst(1), IntAst(2)])),
+          Box::new(IntAst(0))
+        )),
+        Span::default(),
+      )
+    )
   }
-}
 
-impl ProtoAst {
-  fn parse(lexer: &mut Lexer) -> Self {
-    let Token::Identifier(name) = lexer.next_token() else {panic!("Expect an identifier")};
-    lexer.next_token(); // eat `(`
-    let mut args = vec![];
-    loop {
-      match lexer.next_token() {
-        Token::RightParen => break,
-        Token::Comma => (),
-        Token::Identifier(s) => args.push(s),
-        _ => panic!(),
-      }
-    }
-    Self { name, args }
+  #[test]
+  fn expr_str_call() {
+    use ExprAst::*;
+    let src = r#"print("hello", x)"#;
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      CallAst(
+        "print".to_string(),
+        vec![
+          StrAst("hello".to_string()),
+          VarAst("x".to_string(), Span::default())
+        ],
+        Span::default()
+      )
+    )
   }
-}
 
-impl FuncAst {
-  fn parse(lexer: &mut Lexer) -> Self {
-    lexer.next_token(); // eat `def`
-    let proto = ProtoAst::parse(lexer);
-    let body = ExprAst::parse(lexer);
-    Self { proto, body }
+  #[test]
+  fn expr_lambda() {
+    use ExprAst::*;
+    let src = r"map(\(x, y) x + y, a)";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      CallAst(
+        "map".to_string(),
+        vec![
+          LambdaAst(
+            vec![
+              ("x".to_string(), Span::default()),
+              ("y".to_string(), Span::default())
+            ],
+            Box::new(BinAst(
+              Box::new(VarAst("x".to_string(), Span::default())),
+              BinOp::Add,
+              Box::new(VarAst("y".to_string(), Span::default())),
+              Span::default()
+            ))
+          ),
+          VarAst("a".to_string(), Span::default()),
+        ],
+        Span::default()
+      )
+    )
   }
-}
-
-#[cfg(test)]
-mod tests {
-  use crate::lexer;
-
-  use super::*;
-  use std::io::Cursor;
 
   #[test]
-  fn expr_number() {
-    let src = " 42 ";
+  fn expr_let() {
+    use ExprAst::*;
+    let src = "let a = 2, b = a in a * b";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ExprAst::parse(&mut lexer);
-    assert_eq!(ast, ExprAst::NumAst(42.0));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    assert_eq!(
+      ast,
+      LetAst(
+        vec![
+          ("a".to_string(), Span::default(), IntAst(2)),
+          (
+            "b".to_string(),
+            Span::default(),
+            VarAst("a".to_string(), Span::default())
+          ),
+        ],
+        Box::new(BinAst(
+          Box::new(VarAst("a".to_string(), Span::default())),
+          BinOp::Mul,
+          Box::new(VarAst("b".to_string(), Span::default())),
+          Span::default()
+        ))
+      )
+    )
   }
 
   #[test]
-  fn expr_variable() {
-    let src = "foo";
+  fn expr_match() {
+    use ExprAst::*;
+    let src = "match n { 0 -> a, -1..2.5 -> b, _ -> c }";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ExprAst::parse(&mut lexer);
-    assert_eq!(ast, ExprAst::VarAst("foo".to_string()));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    let var = |name: &str| VarAst(name.to_string(), Span::default());
+    assert_eq!(
+      ast,
+      MatchAst(
+        Box::new(var("n")),
+        vec![
+          (Pattern::Lit(IntAst(0)), var("a")),
+          (Pattern::Range(IntAst(-1), NumAst(2.5)), var("b")),
+          (Pattern::Wild, var("c")),
+        ],
+        Span::default()
+      )
+    );
   }
 
   #[test]
-  fn expr_paren() {
-    let src = "(foo )";
+  fn expr_return() {
+    use ExprAst::*;
+    let src = "if x then return a + 1 else b";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ExprAst::parse(&mut lexer);
-    assert_eq!(ast, ExprAst::VarAst("foo".to_string()));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    let var = |name: &str| Box::new(VarAst(name.to_string(), Span::default()));
+    let ret = BinAst(var("a"), BinOp::Add, Box::new(IntAst(1)), Span::default());
+    assert_eq!(
+      ast,
+      IfAst {
+        cond: var("x"),
+        then: Box::new(ReturnAst(Box::new(ret), Span::default())),
+        els: var("b"),
+      }
+    );
   }
 
   #[test]
-  fn expr_bin_expr_1() {
-    let src = "1 + foo";
+  fn expr_func_ref() {
+    use ExprAst::*;
+    let src = "[&f, g]";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ExprAst::parse(&mut lexer);
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    let f = FuncRefAst("f".to_string(), Span::default());
     assert_eq!(
       ast,
-      ExprAst::BinAst(
-        Box::new(ExprAst::NumAst(1.0)),
-        '+',
-        Box::new(ExprAst::VarAst("foo".to_string()))
-      )
+      ArrayAst(vec![f, VarAst("g".to_string(), Span::default())])
     );
   }
 
   #[test]
-  fn expr_bin_expr_2() {
-    let src = "1 + foo * 42";
+  fn expr_let_tuple() {
+    use ExprAst::*;
+    let src = "let x = 1, (a, b) = t, c = a in c";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ExprAst::parse(&mut lexer);
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
     assert_eq!(
       ast,
-      ExprAst::BinAst(
-        Box::new(ExprAst::NumAst(1.0)),
-        '+',
-        Box::new(ExprAst::BinAst(
-          Box::new(ExprAst::VarAst("foo".to_string())),
-          '*',
-          Box::new(ExprAst::NumAst(42.0)),
+      LetAst(
+        vec![("x".to_string(), Span::default(), IntAst(1))],
+        Box::new(LetTupleAst(
+          vec![
+            ("a".to_string(), Span::default()),
+            ("b".to_string(), Span::default())
+          ],
+          Box::new(VarAst("t".to_string(), Span::default())),
+          Box::new(LetAst(
+            vec![(
+              "c".to_string(),
+              Span::default(),
+              VarAst("a".to_string(), Span::default())
+            )],
+            Box::new(VarAst("c".to_string(), Span::default()))
+          ))
         ))
       )
     )
   }
 
   #[test]
-  fn expr_bin_expr_3() {
-    let src = "1 + foo - 42";
+  fn expr_var_in() {
+    use ExprAst::*;
+    let src = "var a = 1, b in b = a + 1";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ExprAst::parse(&mut lexer);
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
     assert_eq!(
       ast,
-      ExprAst::BinAst(
-        Box::new(ExprAst::BinAst(
-          Box::new(ExprAst::NumAst(1.0)),
-          '+',
-          Box::new(ExprAst::VarAst("foo".to_string())),
-        )),
-        '-',
-        Box::new(ExprAst::NumAst(42.0)),
+      VarInAst(
+        vec![
+          ("a".to_string(), Span::default(), Some(IntAst(1))),
+          ("b".to_string(), Span::default(), None)
+        ],
+        Box::new(AssignAst(
+          "b".to_string(),
+          Box::new(BinAst(
+            Box::new(VarAst("a".to_string(), Span::default())),
+            BinOp::Add,
+            Box::new(IntAst(1)),
+            Span::default()
+          )),
+          Span::default()
+        ))
       )
     )
   }
 
   #[test]
-  fn expr_bin_expr_4() {
+  fn expr_assign() {
     use ExprAst::*;
-    let src = "1 < foo + bar * 42 - baz";
+    let src = "a = b = c ? 1 : 2";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ExprAst::parse(&mut lexer);
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
     assert_eq!(
       ast,
-      BinAst(
-        Box::new(NumAst(1.0)),
-        '<',
-        Box::new(BinAst(
-          Box::new(BinAst(
-            Box::new(VarAst("foo".to_string())),
-            '+',
-            Box::new(BinAst(
-              Box::new(VarAst("bar".to_string())),
-              '*',
-              Box::new(NumAst(42.0))
-            )),
-          )),
-          '-',
-          Box::new(VarAst("baz".to_string())),
+      AssignAst(
+        "a".to_string(),
+        Box::new(AssignAst(
+          "b".to_string(),
+          Box::new(IfAst {
+            cond: Box::new(VarAst("c".to_string(), Span::default())),
+            then: Box::new(IntAst(1)),
+            els: Box::new(IntAst(2)),
+          }),
+          Span::default()
         )),
+        Span::default()
       )
+    )
+  }
+
+  #[test]
+  fn expr_try() {
+    use ExprAst::*;
+    let src = "try a[i] catch e -> f(e) + 1";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    let index = IndexAst(
+      Box::new(VarAst("a".to_string(), Span::default())),
+      Box::new(VarAst("i".to_string(), Span::default())),
+    );
+    let call = CallAst(
+      "f".to_string(),
+      vec![VarAst("e".to_string(), Span::default())],
+      Span::default(),
+    );
+    let handler = BinAst(
+      Box::new(call),
+      BinOp::Add,
+      Box::new(IntAst(1)),
+      Span::default(),
+    );
+    let expected = TryAst(
+      Box::new(index),
+      Some("e".to_string()),
+      Box::new(handler),
+      Span::default(),
     );
+    assert_eq!(ast, expected);
+
+    let src = "try x catch 0";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    let expected = TryAst(
+      Box::new(VarAst("x".to_string(), Span::default())),
+      None,
+      Box::new(IntAst(0)),
+      Span::default(),
+    );
+    assert_eq!(ast, expected);
   }
 
   #[test]
-  fn expr_func_call() {
-    let src = "foo(1 + 2, bar, 42)";
+  fn expr_try_without_catch() {
+    let src = "try x";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ExprAst::parse(&mut lexer);
+    let err = ExprAst::parse(&mut lexer).unwrap_err();
+    assert_eq!(err.message, "Expected `catch` after try expression");
+  }
+
+  #[test]
+  fn expr_comparison_chain() {
     use ExprAst::*;
+    let var = |s: &str| Box::new(VarAst(s.to_string(), Span::default()));
+    let bin = |l, op, r| Box::new(BinAst(l, op, r, Span::default()));
+    let src = "a < b <= c";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
     assert_eq!(
       ast,
-      CallAst(
-        "foo".to_string(),
-        vec![
-          BinAst(Box::new(NumAst(1.0)), '+', Box::new(NumAst(2.0))),
-          VarAst("bar".to_string()),
-          NumAst(42.0),
-        ]
+      *bin(
+        bin(var("a"), BinOp::Lt, var("b")),
+        BinOp::And,
+        bin(var("b"), BinOp::Le, var("c"))
       )
-    )
+    );
+
+    let src = "f() > x + 1 > 0 == 1";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ExprAst::parse(&mut lexer).unwrap().without_spans();
+    let chain = LetAst(
+      vec![(
+        "$cmp0".to_string(),
+        Span::default(),
+        CallAst("f".to_string(), vec![], Span::default()),
+      )],
+      Box::new(LetAst(
+        vec![(
+          "$cmp1".to_string(),
+          Span::default(),
+          *bin(var("x"), BinOp::Add, Box::new(IntAst(1))),
+        )],
+        bin(
+          bin(var("$cmp0"), BinOp::Gt, var("$cmp1")),
+          BinOp::And,
+          bin(var("$cmp1"), BinOp::Gt, Box::new(IntAst(0))),
+        ),
+      )),
+    );
+    assert_eq!(ast, *bin(Box::new(chain), BinOp::Eq, Box::new(IntAst(1))));
+  }
+
+  #[test]
+  fn expr_assign_literal() {
+    let src = "1 = 2";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let err = ExprAst::parse(&mut lexer).unwrap_err();
+    assert_eq!(err.message, "Destination of `=` must be a variable");
   }
 
   #[test]
   fn proto() {
     let src = "foo(a, b, c);";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = ProtoAst::parse(&mut lexer);
+    let ast = ProtoAst::parse(&mut lexer).unwrap();
     assert_eq!(
       ast,
       ProtoAst {
         name: "foo".to_string(),
+        span: Span { line: 1, col: 1 },
         args: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        arg_spans: vec![
+          Span { line: 1, col: 5 },
+          Span { line: 1, col: 8 },
+          Span { line: 1, col: 11 }
+        ],
+        arg_tys: vec![None, None, None],
+        ret_ty: None,
       }
     )
   }
 
+  #[test]
+  fn proto_type_ann() {
+    let src = "f(x: double, n: int, y) : double";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ProtoAst::parse(&mut lexer).unwrap();
+    assert_eq!(
+      ast,
+      ProtoAst {
+        name: "f".to_string(),
+        span: Span { line: 1, col: 1 },
+        args: vec!["x".to_string(), "n".to_string(), "y".to_string()],
+        arg_spans: vec![
+          Span { line: 1, col: 3 },
+          Span { line: 1, col: 14 },
+          Span { line: 1, col: 22 }
+        ],
+        arg_tys: vec![Some("double".to_string()), Some("int".to_string()), None],
+        ret_ty: Some("double".to_string()),
+      }
+    );
+    let src = "minmax(a, b): (double, double); g(t: ((int, _), geo.Point)): ()";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = ProtoAst::parse(&mut lexer).unwrap();
+    assert_eq!(ast.ret_ty, Some("(double, double)".to_string()));
+    lexer.next_token().unwrap(); // eat `;`
+    let ast = ProtoAst::parse(&mut lexer).unwrap();
+    assert_eq!(ast.arg_tys, vec![Some("((int, _), geo.Point)".to_string())]);
+    assert_eq!(ast.ret_ty, Some("unit".to_string()));
+  }
+
+  #[test]
+  fn parse_binary_op() {
+    use ExprAst::*;
+    let src = "def binary @ 5 (a b) { a; x < y @ z }";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let Ast::Func(func) = Ast::parse(&mut lexer).unwrap() else {panic!()};
+    assert_eq!(func.proto.name, "binary@");
+    assert_eq!(func.proto.args, vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(
+      func.body.without_spans(),
+      SeqAst(vec![
+        VarAst("a".to_string(), Span::default()),
+        CallAst(
+          "binary@".to_string(),
+          vec![
+            BinAst(
+              Box::new(VarAst("x".to_string(), Span::default())),
+              BinOp::Lt,
+              Box::new(VarAst("y".to_string(), Span::default())),
+              Span::default()
+            ),
+            VarAst("z".to_string(), Span::default()),
+          ],
+          Span::default()
+        ),
+      ])
+    );
+    // `|` overloads bitwise or, with the precedence given if any
+    let bin = |lhs, op, rhs| BinAst(Box::new(lhs), op, Box::new(rhs), Span::default());
+    let var = |name: &str| VarAst(name.to_string(), Span::default());
+    for (src, body) in [
+      (
+        "def binary | 5 (a, b) x | y < z",
+        bin(var("x"), BinOp::BitOr, bin(var("y"), BinOp::Lt, var("z"))),
+      ),
+      (
+        "def binary | (a: V, b: V) x | y < z",
+        bin(bin(var("x"), BinOp::BitOr, var("y")), BinOp::Lt, var("z")),
+      ),
+    ] {
+      let Ast::Func(func) = Ast::parse(&mut Lexer::new(Cursor::new(src))).unwrap() else {panic!()};
+      assert_eq!(BinOp::overloaded_by(&func.proto.name), Some(BinOp::BitOr));
+      assert_eq!(func.body.without_spans(), body, "{}", src);
+    }
+    // operators are declared to the lexer reading them, not to others
+    assert_eq!(
+      ExprAst::parse(&mut Lexer::new(Cursor::new("x @ z"))).unwrap(),
+      VarAst("x".to_string(), Span { line: 1, col: 1 })
+    );
+    let src = "def binary <= (a: V, b: V) 1";
+    let Ast::Func(func) = Ast::parse(&mut Lexer::new(Cursor::new(src))).unwrap() else {panic!()};
+    assert_eq!(func.proto.name, "binary<=");
+    assert_eq!(BinOp::overloaded_by(&func.proto.name), Some(BinOp::Le));
+    assert_eq!(BinOp::overloaded_by("binary@"), None);
+  }
+
+  #[test]
+  fn parse_struct() {
+    use ExprAst::*;
+    let src = "struct Point(x, y: int); Point(1, 2).y; p.x.0";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let module = ModuleAst::parse(&mut lexer).unwrap();
+    assert_eq!(
+      module.items[0],
+      Ast::Struct(StructAst {
+        name: "Point".to_string(),
+        span: Span { line: 1, col: 8 },
+        fields: vec!["x".to_string(), "y".to_string()],
+        field_tys: vec![None, Some("int".to_string())],
+      })
+    );
+    let Ast::Func(func) = &module.items[1] else {panic!()};
+    assert_eq!(
+      func.body.without_spans(),
+      FieldAst(
+        Box::new(CallAst(
+          "Point".to_string(),
+          vec![IntAst(1), IntAst(2)],
+          Span::default()
+        )),
+        "y".to_string()
+      )
+    );
+    let Ast::Func(func) = &module.items[2] else {panic!()};
+    assert_eq!(
+      func.body.without_spans(),
+      ElemAst(
+        Box::new(FieldAst(
+          Box::new(VarAst("p".to_string(), Span::default())),
+          "x".to_string()
+        )),
+        0
+      )
+    );
+  }
+
+  #[test]
+  fn parse_module() {
+    use ExprAst::*;
+    let src = "var g = 1, h; extern sin(x); var a in a; const N = 2";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let module = ModuleAst::parse(&mut lexer).unwrap();
+    assert_eq!(module.items.len(), 4);
+    assert_eq!(
+      module.items[0],
+      Ast::Global(
+        vec![
+          ("g".to_string(), Span { line: 1, col: 5 }, Some(IntAst(1))),
+          ("h".to_string(), Span { line: 1, col: 12 }, None)
+        ],
+        Span { line: 1, col: 1 }
+      )
+    );
+    assert_eq!(
+      module.items[3],
+      Ast::Const(
+        "N".to_string(),
+        Span { line: 1, col: 48 },
+        IntAst(2),
+        Span { line: 1, col: 52 }
+      )
+    );
+    let Ast::Func(func) = &module.items[2] else {panic!()};
+    assert_eq!(
+      func.body.without_spans(),
+      VarInAst(
+        vec![("a".to_string(), Span::default(), None)],
+        Box::new(VarAst("a".to_string(), Span::default()))
+      )
+    );
+  }
+
+  #[test]
+  fn parse_import() {
+    let src = "import \"lib/math.kale\"; def f(x) x; import util";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let module = ModuleAst::parse(&mut lexer).unwrap();
+    assert_eq!(module.items.len(), 3);
+    assert_eq!(
+      module.items[0],
+      Ast::Import("lib/math.kale".to_string(), None, Span { line: 1, col: 1 })
+    );
+    assert_eq!(
+      module.items[2],
+      Ast::Import(
+        "util.kale".to_string(),
+        Some("util".to_string()),
+        Span { line: 1, col: 37 }
+      )
+    );
+  }
+
+  #[test]
+  fn parse_namespaced() {
+    use ExprAst::*;
+    let src = "extern math.sin(x); def f(p: geo.Point) { math.sin(p.x) + &a.b.c; a.b.c(p).y }";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let module = ModuleAst::parse(&mut lexer).unwrap();
+    let Ast::Proto(proto) = &module.items[0] else {panic!()};
+    assert_eq!((proto.name.as_str(), proto.symbol()), ("math.sin", "sin"));
+    let Ast::Func(func) = &module.items[1] else {panic!()};
+    assert_eq!(func.proto.arg_tys, vec![Some("geo.Point".to_string())]);
+    let p = || Box::new(VarAst("p".to_string(), Span::default()));
+    assert_eq!(
+      func.body.without_spans(),
+      SeqAst(vec![
+        BinAst(
+          Box::new(CallAst(
+            "math.sin".to_string(),
+            vec![FieldAst(p(), "x".to_string())],
+            Span::default()
+          )),
+          BinOp::Add,
+          Box::new(FuncRefAst("a.b.c".to_string(), Span::default())),
+          Span::default()
+        ),
+        FieldAst(
+          Box::new(CallAst("a.b.c".to_string(), vec![*p()], Span::default())),
+          "y".to_string()
+        )
+      ])
+    );
+  }
+
   #[test]
   fn parse_function() {
     let src = "def foo(a, b, c) a+b*c";
     let mut lexer = Lexer::new(Cursor::new(src));
-    let ast = FuncAst::parse(&mut lexer);
+    let mut ast = FuncAst::parse(&mut lexer).unwrap();
+    ast.body = ast.body.without_spans();
     use ExprAst::*;
     assert_eq!(
       ast,
       FuncAst {
         proto: ProtoAst {
           name: "foo".to_string(),
-          args: vec!["a".to_string(), "b".to_string(), "c".to_string()]
+          span: Span { line: 1, col: 5 },
+          args: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+          arg_spans: vec![
+            Span { line: 1, col: 9 },
+            Span { line: 1, col: 12 },
+            Span { line: 1, col: 15 }
+          ],
+          arg_tys: vec![None, None, None],
+          ret_ty: None,
         },
         body: BinAst(
-          Box::new(VarAst("a".to_string())),
-          '+',
+          Box::new(VarAst("a".to_string(), Span::default())),
+          BinOp::Add,
           Box::new(BinAst(
-            Box::new(VarAst("b".to_string())),
-            '*',
-            Box::new(VarAst("c".to_string()))
-          ))
+            Box::new(VarAst("b".to_string(), Span::default())),
+            BinOp::Mul,
+            Box::new(VarAst("c".to_string(), Span::default())),
+            Span::default()
+          )),
+          Span::default()
         )
       }
     )
   }
+
+  #[test]
+  fn parse_function_seq() {
+    let src = "def f(x) { g(x); h(x); x + 1 } f(2)";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let ast = FuncAst::parse(&mut lexer).unwrap();
+    use ExprAst::*;
+    assert_eq!(
+      ast.body.without_spans(),
+      SeqAst(vec![
+        CallAst(
+          "g".to_string(),
+          vec![VarAst("x".to_string(), Span::default())],
+          Span::default()
+        ),
+        CallAst(
+          "h".to_string(),
+          vec![VarAst("x".to_string(), Span::default())],
+          Span::default()
+        ),
+        BinAst(
+          Box::new(VarAst("x".to_string(), Span::default())),
+          BinOp::Add,
+          Box::new(IntAst(1)),
+          Span::default()
+        ),
+      ])
+    );
+    assert_eq!(
+      lexer.peek_first().unwrap(),
+      &Token::Identifier("f".to_string())
+    );
+    // a `;` ends a body, so a top-level call can follow
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new("def f(x) x + 1; f(2);"))).unwrap();
+    assert_eq!(module.items.len(), 2);
+    assert!(matches!(&module.items[1], Ast::Func(func) if func.proto.name.is_empty()));
+  }
+
+  #[test]
+  fn parse_errors() {
+    let src = "def f(x)\n  x +;\n1 + 2";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let err = Ast::parse(&mut lexer).unwrap_err();
+    assert_eq!(err.code, Some("syntax"));
+    assert_eq!(err.to_string(), "2:6: Expected an expression, found Semi");
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src)));
+    assert_eq!(module.unwrap_err().to_string(), err.to_string());
+    let mut lexer = Lexer::new(Cursor::new("1 + 2"));
+    assert!(matches!(Ast::parse(&mut lexer), Ok(Ast::Func(_))));
+  }
 }
@@ -0,0 +1,799 @@
+use crate::analysis::{call_graph, effects, Effects};
+use crate::consts::eval_const;
+use crate::eval::{eval_bin, eval_unary};
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, Pattern};
+use crate::value::{truthy, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+/// Folds the constant parts of every item of `module`: builtin operators
+/// on number literals, such as `2 * 3 + 1`, become their value, and an `if`
+/// or a `match` whose condition is a literal becomes the branch it takes.
+/// Operations that would fail, like an integer division by zero, are left
+/// for the program to report when it runs. Operators on strings aren't
+/// folded, since they may be overloaded. Meant to run once the module is
+/// type-checked, since dropping a branch drops its type errors too.
+pub fn const_fold(module: &mut ModuleAst) {
+  module.items.iter_mut().for_each(const_fold_item);
+}
+
+/// Like [`const_fold`] on a single item.
+pub fn const_fold_item(item: &mut Ast) {
+  match item {
+    Ast::Func(func) => fold(&mut func.body),
+    Ast::Expr(expr) | Ast::Const(_, _, expr, _) => fold(expr),
+    Ast::Global(vars, _) => vars
+      .iter_mut()
+      .filter_map(|(.., init)| init.as_mut())
+      .for_each(fold),
+    Ast::Proto(_) | Ast::Struct(_) | Ast::Import(..) => (),
+  }
+}
+
+fn fold(expr: &mut ExprAst) {
+  expr.children_mut().into_iter().for_each(fold);
+  let folded = match expr {
+    ExprAst::UnaryAst(op, operand, _) => number(operand).and_then(|val| eval_unary(*op, val).ok()),
+    // `&&` and `||` only need their left operand to decide
+    ExprAst::BinAst(lhs, op @ (BinOp::And | BinOp::Or), rhs, _) => {
+      match (*op, number(lhs).map(truthy), number(rhs)) {
+        (BinOp::And, Some(false), _) => Some(false.into()),
+        (BinOp::Or, Some(true), _) => Some(true.into()),
+        (_, Some(_), Some(rhs)) => Some(truthy(rhs).into()),
+        _ => None,
+      }
+    }
+    ExprAst::BinAst(lhs, op, rhs, _) => match (number(lhs), number(rhs)) {
+      (Some(lhs), Some(rhs)) => eval_bin(*op, lhs, rhs).ok(),
+      _ => None,
+    },
+    ExprAst::IfAst { cond, then, els } => {
+      if let Some(cond) = number(cond) {
+        let taken = if truthy(cond) { then } else { els };
+        *expr = mem::replace(taken.as_mut(), ExprAst::UnitAst);
+      }
+      return;
+    }
+    ExprAst::MatchAst(scrutinee, arms, _) => {
+      if let Some(arm) = number(scrutinee).and_then(|val| taken_arm(&val, arms)) {
+        *expr = arms.swap_remove(arm).1;
+      }
+      return;
+    }
+    _ => None,
+  };
+  if let Some(val) = folded {
+    *expr = val.to_ast();
+  }
+}
+
+/// The value of a number literal, booleans included.
+fn number(expr: &ExprAst) -> Option<Value> {
+  match expr {
+    ExprAst::NumAst(_) | ExprAst::IntAst(_) | ExprAst::BoolAst(_) => {
+      eval_const(expr, &HashMap::new())
+    }
+    _ => None,
+  }
+}
+
+/// The index of the arm a `match` on `val` takes, unless an arm before it
+/// can't be decided here.
+fn taken_arm(val: &Value, arms: &[(Pattern, ExprAst)]) -> Option<usize> {
+  let test = |op, lit: &ExprAst| {
+    let lit = number(lit)?;
+    eval_bin(op, val.clone(), lit).ok().map(truthy)
+  };
+  for (i, (pat, _)) in arms.iter().enumerate() {
+    let matches = match pat {
+      Pattern::Wild => true,
+      Pattern::Lit(lit) => test(BinOp::Eq, lit)?,
+      Pattern::Range(lo, hi) => test(BinOp::Ge, lo)? && test(BinOp::Lt, hi)?,
+    };
+    if matches {
+      return Some(i);
+    }
+  }
+  None
+}
+
+/// Eliminates the common subexpressions of the functions of `module`, so
+/// that `f(a*b + a*b)` computes `a*b` once. An expression is reused when it
+/// is pure according to [`Effects`], mentions only parameters and local
+/// bindings that nothing assigns, and is evaluated unconditionally each
+/// time. Its first occurrence stores its value into a fresh `$cse` variable
+/// the others then read, so it is still evaluated at the same point. Meant
+/// to run once the module is type-checked.
+pub fn cse(module: &mut ModuleAst) {
+  let effects = effects(module);
+  module
+    .items
+    .iter_mut()
+    .for_each(|item| cse_item(item, &effects));
+}
+
+/// Like [`cse`] on a single item, given the effects of the module it is in.
+pub fn cse_item(item: &mut Ast, effects: &Effects) {
+  if let Ast::Func(func) = item {
+    let mut next = 0;
+    let locals = func.proto.args.clone();
+    cse_region(&mut func.body, &locals, effects, &mut next);
+  }
+}
+
+/// Eliminates the common subexpressions of a region: the parts of `root`
+/// evaluated whenever it is, with the same bindings in scope. The branches
+/// of conditionals, lambdas, `try` and the scopes of bindings are regions
+/// of their own.
+fn cse_region(root: &mut ExprAst, locals: &[String], effects: &Effects, next: &mut usize) {
+  let mut assigned = HashSet::new();
+  assignments(root, &mut assigned);
+  let reusable = |expr: &ExprAst| {
+    let mut vars = vec![];
+    free_vars(expr, &mut vars);
+    !matches!(
+      expr,
+      ExprAst::NumAst(_)
+        | ExprAst::IntAst(_)
+        | ExprAst::BoolAst(_)
+        | ExprAst::UnitAst
+        | ExprAst::StrAst(_)
+        | ExprAst::VarAst(_, _)
+        | ExprAst::FuncRefAst(..)
+    ) && effects.is_pure(expr)
+      && vars
+        .iter()
+        .all(|var| locals.contains(var) && !assigned.contains(var))
+  };
+
+  let mut vars = vec![];
+  loop {
+    let mut groups: HashMap<u64, Vec<(ExprAst, usize)>> = HashMap::new();
+    let mut order = vec![]; // the distinct expressions, in evaluation order
+    occurrences(root, &reusable, &mut groups, &mut order);
+    let repeated = order.into_iter().filter(|expr| {
+      let group = &groups[&hash(expr)];
+      group.iter().any(|(other, n)| other == expr && *n > 1)
+    });
+    // the largest first, so that its parts are only computed once too
+    let Some(expr) = repeated.rev().max_by_key(size) else {
+      break;
+    };
+    let var = format!("$cse{}", next);
+    *next += 1;
+    reuse(root, &expr, &var, &mut true);
+    vars.push((var, Span::default(), None));
+  }
+  for (part, bound) in parts(root) {
+    if let Some(bound) = bound {
+      let locals = [locals, &bound].concat();
+      cse_region(part, &locals, effects, next);
+    }
+  }
+  if !vars.is_empty() {
+    *root = ExprAst::VarInAst(vars, Box::new(mem::replace(root, ExprAst::UnitAst)));
+  }
+}
+
+/// Counts the reusable expressions of the region `expr` is the root of.
+fn occurrences(
+  expr: &mut ExprAst,
+  reusable: &impl Fn(&ExprAst) -> bool,
+  groups: &mut HashMap<u64, Vec<(ExprAst, usize)>>,
+  order: &mut Vec<ExprAst>,
+) {
+  for (part, bound) in parts(expr) {
+    if bound.is_some() {
+      continue;
+    }
+    if reusable(part) {
+      let group = groups.entry(hash(part)).or_default();
+      let part = part.without_spans();
+      match group.iter_mut().find(|(other, _)| *other == part) {
+        Some((_, n)) => *n += 1,
+        None => {
+          group.push((part.clone(), 1));
+          order.push(part);
+        }
+      }
+    }
+    occurrences(part, reusable, groups, order);
+  }
+}
+
+/// Stores the first occurrence of `target`, which has no spans, in the
+/// region of `expr` into `var`, and replaces the others with `var`.
+fn reuse(expr: &mut ExprAst, target: &ExprAst, var: &str, first: &mut bool) {
+  for (part, bound) in parts(expr) {
+    if bound.is_some() {
+      continue;
+    }
+    match hash(part) == hash(target) && part.without_spans() == *target {
+      true if mem::take(first) => {
+        let part_expr = mem::replace(part, ExprAst::UnitAst);
+        *part = ExprAst::AssignAst(var.to_string(), Box::new(part_expr), Span::default());
+      }
+      true => *part = ExprAst::VarAst(var.to_string(), Span::default()),
+      false => reuse(part, target, var, first),
+    }
+  }
+}
+
+/// The children of `expr`, each with the names it binds when it is the
+/// root of a region of its own.
+fn parts(expr: &mut ExprAst) -> Vec<(&mut ExprAst, Option<Vec<String>>)> {
+  let names = |names: &[(String, Span)]| Some(names.iter().map(|(name, _)| name.clone()).collect());
+  match expr {
+    ExprAst::IfAst { cond, then, els } => {
+      vec![(cond, None), (then, names(&[])), (els, names(&[]))]
+    }
+    ExprAst::BinAst(lhs, BinOp::And | BinOp::Or, rhs, _) => vec![(lhs, None), (rhs, names(&[]))],
+    ExprAst::MatchAst(scrutinee, arms, _) => {
+      let arms = arms.iter_mut().map(|(_, body)| (body, names(&[])));
+      [(scrutinee.as_mut(), None)]
+        .into_iter()
+        .chain(arms)
+        .collect()
+    }
+    ExprAst::TryAst(expr, name, handler, _) => {
+      let name: Vec<String> = name.iter().cloned().collect();
+      vec![(expr, names(&[])), (handler, Some(name))]
+    }
+    ExprAst::LambdaAst(args, body) => vec![(body, names(args))],
+    ExprAst::LetTupleAst(bound, init, body) => vec![(init, None), (body, names(bound))],
+    ExprAst::LetAst(bindings, body) => {
+      let bound: Vec<String> = bindings.iter().map(|(name, ..)| name.clone()).collect();
+      let inits = bindings.iter_mut().enumerate();
+      let inits = inits.map(|(i, (.., init))| (init, (i > 0).then(|| bound[..i].to_vec())));
+      inits
+        .chain([(body.as_mut(), Some(bound.clone()))])
+        .collect()
+    }
+    ExprAst::VarInAst(vars, body) => {
+      let bound: Vec<String> = vars.iter().map(|(name, ..)| name.clone()).collect();
+      let inits = vars.iter_mut().enumerate();
+      let inits = inits.filter_map(|(i, (.., init))| {
+        let init = init.as_mut()?;
+        Some((init, (i > 0).then(|| bound[..i].to_vec())))
+      });
+      inits
+        .chain([(body.as_mut(), Some(bound.clone()))])
+        .collect()
+    }
+    expr => expr
+      .children_mut()
+      .into_iter()
+      .map(|part| (part, None))
+      .collect(),
+  }
+}
+
+fn assignments(expr: &ExprAst, assigned: &mut HashSet<String>) {
+  if let ExprAst::AssignAst(name, ..) = expr {
+    assigned.insert(name.clone());
+  }
+  for child in expr.children() {
+    assignments(child, assigned);
+  }
+}
+
+/// The variables `expr` reads, including those bound inside it.
+fn free_vars(expr: &ExprAst, vars: &mut Vec<String>) {
+  if let ExprAst::VarAst(name, _) = expr {
+    vars.push(name.clone());
+  }
+  for child in expr.children() {
+    free_vars(child, vars);
+  }
+}
+
+/// A hash of `expr` that ignores spans, as [`ExprAst::without_spans`] does.
+fn hash(expr: &ExprAst) -> u64 {
+  fn feed(expr: &ExprAst, hasher: &mut DefaultHasher) {
+    mem::discriminant(expr).hash(hasher);
+    match expr {
+      ExprAst::NumAst(n) => (n + 0.0).to_bits().hash(hasher), // -0.0 == 0.0
+      ExprAst::IntAst(i) => i.hash(hasher),
+      ExprAst::BoolAst(b) => b.hash(hasher),
+      ExprAst::StrAst(name)
+      | ExprAst::VarAst(name, _)
+      | ExprAst::CallAst(name, ..)
+      | ExprAst::FieldAst(_, name)
+      | ExprAst::FuncRefAst(name, _) => name.hash(hasher),
+      ExprAst::BinAst(_, op, _, _) => mem::discriminant(op).hash(hasher),
+      ExprAst::UnaryAst(op, ..) => mem::discriminant(op).hash(hasher),
+      ExprAst::ElemAst(_, i) => i.hash(hasher),
+      _ => (),
+    }
+    for child in expr.children() {
+      feed(child, hasher);
+    }
+  }
+  let mut hasher = DefaultHasher::new();
+  feed(expr, &mut hasher);
+  hasher.finish()
+}
+
+fn size(expr: &ExprAst) -> usize {
+  1 + expr.children().into_iter().map(size).sum::<usize>()
+}
+
+/// Inlines the calls of the small functions of `module`, those whose body
+/// is at most `threshold` nodes, as described for [`Inliner`].
+pub fn inline(module: &mut ModuleAst, threshold: usize) {
+  let mut inliner = Inliner::new(threshold);
+  inliner.declare_module(module);
+  module
+    .items
+    .iter_mut()
+    .for_each(|item| inliner.inline_item(item));
+}
+
+/// Inliner - replaces the calls of small functions with their bodies, so
+/// that tiny helpers don't cost a call. `f(a + 1)` with `def f(x) x * x`
+/// becomes `var $inl0_x = a + 1 in $inl0_x * $inl0_x`: the arguments are
+/// still evaluated once and in order, into fresh mutable variables like
+/// parameters are. A function is inlined when its body is at most
+/// `threshold` nodes, it isn't recursive, it doesn't `return`, and its
+/// module defines it only once. A call isn't inlined where a local binding
+/// would capture a name the body refers to. Meant to run once the module is
+/// type-checked.
+pub struct Inliner {
+  threshold: usize,
+  funcs: HashMap<String, (Vec<String>, ExprAst)>, // the parameters and body of the inlinable ones
+}
+
+impl Inliner {
+  pub fn new(threshold: usize) -> Self {
+    Self {
+      threshold,
+      funcs: HashMap::new(),
+    }
+  }
+
+  /// Makes the inlinable functions of `module` known.
+  pub fn declare_module(&mut self, module: &ModuleAst) {
+    let graph = call_graph(module);
+    let mut defined = HashMap::new();
+    for item in &module.items {
+      if let Ast::Func(func) = item {
+        *defined.entry(&func.proto.name).or_insert(0) += 1;
+      }
+    }
+    for item in &module.items {
+      let Ast::Func(FuncAst { proto, body }) = item else {
+        continue;
+      };
+      let inlinable = !proto.name.is_empty()
+        && BinOp::overloaded_by(&proto.name).is_none()
+        && defined[&proto.name] == 1
+        && !graph.is_recursive(&proto.name)
+        && size(body) <= self.threshold
+        && !returns(body);
+      match inlinable {
+        true => self
+          .funcs
+          .insert(proto.name.clone(), (proto.args.clone(), body.clone())),
+        false => self.funcs.remove(&proto.name),
+      };
+    }
+  }
+
+  /// Forgets the function `func` redefines, unless it defines it the same.
+  pub fn define(&mut self, func: &FuncAst) {
+    let same = |(args, body): &(Vec<String>, ExprAst)| {
+      *args == func.proto.args && body.without_spans() == func.body.without_spans()
+    };
+    if !self.funcs.get(&func.proto.name).is_some_and(same) {
+      self.funcs.remove(&func.proto.name);
+    }
+  }
+
+  pub fn inline_item(&self, item: &mut Ast) {
+    let mut next = 0;
+    match item {
+      Ast::Func(func) => {
+        let mut scope = func.proto.args.clone();
+        self.visit(&mut func.body, &mut scope, &mut vec![], &mut next);
+      }
+      Ast::Expr(expr) | Ast::Const(_, _, expr, _) => {
+        self.visit(expr, &mut vec![], &mut vec![], &mut next)
+      }
+      Ast::Global(vars, _) => {
+        for init in vars.iter_mut().filter_map(|(.., init)| init.as_mut()) {
+          self.visit(init, &mut vec![], &mut vec![], &mut next);
+        }
+      }
+      Ast::Proto(_) | Ast::Struct(_) | Ast::Import(..) => (),
+    }
+  }
+
+  /// Inlines the calls in `expr`, where `scope` holds the local bindings
+  /// and `inlining` the functions whose bodies `expr` is part of.
+  fn visit(
+    &self,
+    expr: &mut ExprAst,
+    scope: &mut Vec<String>,
+    inlining: &mut Vec<String>,
+    next: &mut usize,
+  ) {
+    let depth = scope.len();
+    match expr {
+      ExprAst::LetAst(bindings, body) => {
+        for (name, _, init) in bindings {
+          self.visit(init, scope, inlining, next);
+          scope.push(name.clone());
+        }
+        self.visit(body, scope, inlining, next);
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        self.visit(init, scope, inlining, next);
+        scope.extend(names.iter().map(|(name, _)| name.clone()));
+        self.visit(body, scope, inlining, next);
+      }
+      ExprAst::VarInAst(vars, body) => {
+        for (name, _, init) in vars {
+          if let Some(init) = init {
+            self.visit(init, scope, inlining, next);
+          }
+          scope.push(name.clone());
+        }
+        self.visit(body, scope, inlining, next);
+      }
+      ExprAst::LambdaAst(args, body) => {
+        scope.extend(args.iter().map(|(arg, _)| arg.clone()));
+        self.visit(body, scope, inlining, next);
+      }
+      ExprAst::TryAst(expr, name, handler, _) => {
+        self.visit(expr, scope, inlining, next);
+        scope.extend(name.iter().cloned());
+        self.visit(handler, scope, inlining, next);
+      }
+      ExprAst::CallAst(name, args, _) => {
+        for arg in args.iter_mut() {
+          self.visit(arg, scope, inlining, next);
+        }
+        if let Some(mut body) = self.expand(name, args, scope, inlining, next) {
+          inlining.push(name.clone());
+          self.visit(&mut body, scope, inlining, next);
+          inlining.pop();
+          let ExprAst::VarInAst(vars, _) = &mut body else {panic!()};
+          vars
+            .iter_mut()
+            .zip(args.drain(..))
+            .for_each(|((.., init), arg)| *init = Some(arg));
+          *expr = body;
+        }
+      }
+      expr => {
+        for child in expr.children_mut() {
+          self.visit(child, scope, inlining, next);
+        }
+      }
+    }
+    scope.truncate(depth);
+  }
+
+  /// The body of the function `name` to replace a call with, as a `var`
+  /// binding its renamed parameters, which are yet to be initialized.
+  fn expand(
+    &self,
+    name: &str,
+    args: &[ExprAst],
+    scope: &[String],
+    inlining: &[String],
+    next: &mut usize,
+  ) -> Option<ExprAst> {
+    let (params, body) = self.funcs.get(name)?;
+    if scope.iter().any(|local| local == name)
+      || inlining.iter().any(|callee| callee == name)
+      || params.len() != args.len()
+    {
+      return None;
+    }
+    let mut free = vec![];
+    free_names(body, &mut params.clone(), &mut free);
+    if free.iter().any(|name| scope.contains(name)) {
+      return None;
+    }
+    let renamed: HashMap<_, _> = params
+      .iter()
+      .map(|param| (param.clone(), format!("$inl{}_{}", next, param)))
+      .collect();
+    *next += 1;
+    let mut body = body.clone();
+    rename(&mut body, &mut renamed.clone());
+    let vars = params
+      .iter()
+      .map(|param| (renamed[param].clone(), Span::default(), None));
+    Some(ExprAst::VarInAst(vars.collect(), Box::new(body)))
+  }
+}
+
+fn returns(expr: &ExprAst) -> bool {
+  matches!(expr, ExprAst::ReturnAst(..)) || expr.children().into_iter().any(returns)
+}
+
+/// Collects the variables and functions `expr` refers to that `bound`
+/// doesn't bind.
+fn free_names(expr: &ExprAst, bound: &mut Vec<String>, free: &mut Vec<String>) {
+  let depth = bound.len();
+  match expr {
+    ExprAst::VarAst(name, _)
+    | ExprAst::AssignAst(name, ..)
+    | ExprAst::CallAst(name, ..)
+    | ExprAst::FuncRefAst(name, _)
+      if !bound.contains(name) =>
+    {
+      free.push(name.clone())
+    }
+    ExprAst::LetAst(bindings, body) => {
+      for (name, _, init) in bindings {
+        free_names(init, bound, free);
+        bound.push(name.clone());
+      }
+      free_names(body, bound, free);
+      bound.truncate(depth);
+      return;
+    }
+    ExprAst::VarInAst(vars, body) => {
+      for (name, _, init) in vars {
+        if let Some(init) = init {
+          free_names(init, bound, free);
+        }
+        bound.push(name.clone());
+      }
+      free_names(body, bound, free);
+      bound.truncate(depth);
+      return;
+    }
+    ExprAst::LetTupleAst(names, init, body) => {
+      free_names(init, bound, free);
+      bound.extend(names.iter().map(|(name, _)| name.clone()));
+      free_names(body, bound, free);
+      bound.truncate(depth);
+      return;
+    }
+    ExprAst::LambdaAst(args, _) => bound.extend(args.iter().map(|(arg, _)| arg.clone())),
+    ExprAst::TryAst(expr, name, handler, _) => {
+      free_names(expr, bound, free);
+      bound.extend(name.iter().cloned());
+      free_names(handler, bound, free);
+      bound.truncate(depth);
+      return;
+    }
+    _ => (),
+  }
+  for child in expr.children() {
+    free_names(child, bound, free);
+  }
+  bound.truncate(depth);
+}
+
+/// Renames the variables of `expr` bound outside of it as `names` says,
+/// closures called through them included, leaving alone those that a
+/// binding inside it hides.
+fn rename(expr: &mut ExprAst, names: &mut HashMap<String, String>) {
+  match expr {
+    ExprAst::VarAst(name, _) | ExprAst::AssignAst(name, ..) | ExprAst::CallAst(name, ..) => {
+      if let Some(new) = names.get(name) {
+        *name = new.clone();
+      }
+    }
+    ExprAst::LetAst(bindings, body) => {
+      let mut names = names.clone();
+      for (name, _, init) in bindings {
+        rename(init, &mut names);
+        names.remove(name);
+      }
+      return rename(body, &mut names);
+    }
+    ExprAst::VarInAst(vars, body) => {
+      let mut names = names.clone();
+      for (name, _, init) in vars {
+        if let Some(init) = init {
+          rename(init, &mut names);
+        }
+        names.remove(name);
+      }
+      return rename(body, &mut names);
+    }
+    ExprAst::LetTupleAst(bound, init, body) => {
+      rename(init, names);
+      let mut names = names.clone();
+      for (name, _) in bound.iter() {
+        names.remove(name);
+      }
+      return rename(body, &mut names);
+    }
+    ExprAst::LambdaAst(args, body) => {
+      let mut names = names.clone();
+      for (name, _) in args.iter() {
+        names.remove(name);
+      }
+      return rename(body, &mut names);
+    }
+    ExprAst::TryAst(expr, name, handler, _) => {
+      rename(expr, names);
+      let mut names = names.clone();
+      for name in name.iter() {
+        names.remove(name);
+      }
+      return rename(handler, &mut names);
+    }
+    _ => (),
+  }
+  for child in expr.children_mut() {
+    rename(child, names);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::{Lexer, Span};
+  use std::io::Cursor;
+
+  fn folded(src: &'static str) -> Vec<ExprAst> {
+    let mut module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    const_fold(&mut module);
+    bodies(module)
+  }
+
+  #[test]
+  fn const_fold_operators() {
+    use ExprAst::*;
+    let src =
+      "2 * 3 + 1; -(1.5 * 2); 1 / 0; 7 % 4 << 2; x * (2 + 3); 0 && x; 1 && 2; \"a\" + \"b\"";
+    let x = Box::new(VarAst("x".to_string(), Span::default()));
+    assert_eq!(
+      folded(src),
+      vec![
+        IntAst(7),
+        NumAst(-3.0),
+        BinAst(
+          Box::new(IntAst(1)),
+          BinOp::Div,
+          Box::new(IntAst(0)),
+          Span::default()
+        ),
+        IntAst(12),
+        BinAst(x, BinOp::Mul, Box::new(IntAst(5)), Span::default()),
+        NumAst(0.0),
+        NumAst(1.0),
+        BinAst(
+          Box::new(StrAst("a".to_string())),
+          BinOp::Add,
+          Box::new(StrAst("b".to_string())),
+          Span::default()
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn const_fold_conditionals() {
+    use ExprAst::*;
+    let src = "def f(x) if 1 < 2 then x else y; def g(x) if 0 then 1 else if x then 2 else 3;
+      match 2 * 2 { 0..3 -> a, 4 -> b, _ -> c }; match 5 { 0 -> a }; match 1 { \"a\" -> a, 1 -> b }";
+    let var = |name: &str| VarAst(name.to_string(), Span::default());
+    let bodies = folded(src);
+    assert_eq!(bodies[0], var("x"));
+    assert_eq!(
+      bodies[1],
+      IfAst {
+        cond: Box::new(var("x")),
+        then: Box::new(IntAst(2)),
+        els: Box::new(IntAst(3))
+      }
+    );
+    assert_eq!(bodies[2], var("b"));
+    assert!(matches!(bodies[3], MatchAst(..)));
+    assert!(matches!(bodies[4], MatchAst(..)));
+  }
+
+  /// The bodies of the functions of `src` after CSE, with `$cse0` renamed
+  /// to `t0` and so on, so that they can be compared to parsed ones.
+  fn cse_bodies(src: &'static str) -> Vec<ExprAst> {
+    fn rename(expr: &mut ExprAst) {
+      let fix = |name: &mut String| *name = name.replace("$cse", "t");
+      match expr {
+        ExprAst::VarAst(name, _) | ExprAst::AssignAst(name, ..) => fix(name),
+        ExprAst::VarInAst(vars, _) => vars.iter_mut().for_each(|(name, ..)| fix(name)),
+        _ => (),
+      }
+      expr.children_mut().into_iter().for_each(rename);
+    }
+    let mut module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    cse(&mut module);
+    let mut bodies = bodies(module);
+    bodies.iter_mut().for_each(rename);
+    bodies
+  }
+
+  /// The bodies of the functions of `module`, without their spans.
+  fn bodies(module: ModuleAst) -> Vec<ExprAst> {
+    let bodies = module.items.into_iter().filter_map(|item| match item {
+      Ast::Func(func) => Some(func.body.without_spans()),
+      _ => None,
+    });
+    bodies.collect()
+  }
+
+  #[test]
+  fn cse_regions() {
+    let src = "def f(a, b) sqrt(a*b + a*b) + a*b;
+      def g(x) if x > 0 then x*x + x*x else x*x;
+      def h(a) let y = a*a in y*a + y*a + a*a;
+      def k(a) { a*2 + a*2; a = 1 } + rand()*a + rand()*a + N*2 + N*2";
+    let expected = "def f(a, b) var t0 in sqrt((t0 = a*b) + t0) + t0;
+      def g(x) if x > 0 then (var t0 in (t0 = x*x) + t0) else x*x;
+      def h(a) let y = a*a in var t0 in (t0 = y*a) + t0 + a*a;
+      def k(a) { a*2 + a*2; a = 1 } + rand()*a + rand()*a + N*2 + N*2";
+    let expected = ModuleAst::parse(&mut Lexer::new(Cursor::new(expected))).unwrap();
+    assert_eq!(cse_bodies(src), bodies(expected));
+  }
+
+  #[test]
+  fn cse_overloads() {
+    let src = "struct P(x); def binary * (a: P, b: P) { printd(1); P(a.x * b.x) };
+      def f(p) (p * p).x + (p * p).x";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    assert_eq!(cse_bodies(src), bodies(module));
+    let src = "def sq(x) x * x; def f(a) sq(a) + sq(a) + ext(a) + ext(a)";
+    let expected = "def sq(x) x * x; def f(a) var t0 in (t0 = sq(a)) + t0 + ext(a) + ext(a)";
+    let expected = ModuleAst::parse(&mut Lexer::new(Cursor::new(expected))).unwrap();
+    assert_eq!(cse_bodies(src), bodies(expected));
+  }
+
+  /// The bodies of the functions of `src` after inlining, with `$inl0_x`
+  /// renamed to `i0x` and so on, so that they can be compared to parsed ones.
+  fn inlined(src: &'static str, threshold: usize) -> Vec<ExprAst> {
+    fn rename(expr: &mut ExprAst) {
+      let fix = |name: &mut String| *name = name.replace("$inl", "i").replace('_', "");
+      match expr {
+        ExprAst::VarAst(name, _) | ExprAst::AssignAst(name, ..) | ExprAst::CallAst(name, ..) => {
+          fix(name)
+        }
+        ExprAst::VarInAst(vars, _) => vars.iter_mut().for_each(|(name, ..)| fix(name)),
+        _ => (),
+      }
+      expr.children_mut().into_iter().for_each(rename);
+    }
+    let mut module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    inline(&mut module, threshold);
+    let mut bodies = bodies(module);
+    bodies.iter_mut().for_each(rename);
+    bodies
+  }
+
+  fn parsed(src: &'static str) -> Vec<ExprAst> {
+    bodies(ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap())
+  }
+
+  #[test]
+  fn inline_small_functions() {
+    let src = "def sq(x) x * x; def twice(f, x) f(f(x)); def f(a) sq(a + 1) + sq(2);
+      def g(y) twice(\\(x) sq(x), y); def h(y) let x = y in x + y; def k(x) h(x)";
+    let expected = "def sq(x) x * x; def twice(f, x) f(f(x));
+      def f(a) (var i0x = a + 1 in i0x * i0x) + (var i1x = 2 in i1x * i1x);
+      def g(y) var i1f = \\(x) (var i0x = x in i0x * i0x), i1x = y in i1f(i1f(i1x));
+      def h(y) let x = y in x + y; def k(x) var i0y = x in let x = i0y in x + i0y";
+    assert_eq!(inlined(src, 8), parsed(expected));
+    let src = "def a(x) x + 1; def b(x) a(x) * 2; b(1)";
+    let expected = "def a(x) x + 1; def b(x) (var i0x = x in i0x + 1) * 2;
+      var i0x = 1 in (var i1x = i0x in i1x + 1) * 2";
+    assert_eq!(inlined(src, 8), parsed(expected));
+  }
+
+  #[test]
+  fn inline_skipped() {
+    let src = "def fact(n) if n < 1 then 1 else n * fact(n - 1); def g() N; def f(N) g();
+      def r(x) return x; def big(x) x + x + x + x + x; def d(x) 1; def d(x) 2;
+      def sq(x) x * x; fact(r(big(d(sq(1, 2)))))";
+    assert_eq!(inlined(src, 8), parsed(src));
+  }
+}
@@ -0,0 +1,47 @@
+use crate::lexer::Lexer;
+use crate::parser::ModuleAst;
+use std::io::Cursor;
+
+/// The functions every program can call without declaring them: math,
+/// `printd`/`putchard` for output, `readd` for input and `rand`/`srand`. The interpreter implements them as
+/// builtins, and native code links them from libm and the runtime, so they
+/// are plain externs over doubles. Those called for their effect return
+/// unit, which native code represents as 0.0.
+pub const PRELUDE: &str = "
+  extern sin(x); extern cos(x); extern exp(x); extern log(x); extern sqrt(x);
+  extern pow(x, y); extern abs(x); extern floor(x); extern min(x, y); extern max(x, y);
+  extern printd(x): unit; extern putchard(c): unit; extern readd();
+  extern rand(); extern srand(seed): unit";
+
+/// The declarations of the prelude, to be run before any user code.
+pub fn prelude() -> ModuleAst {
+  ModuleAst::parse(&mut Lexer::new(Cursor::new(PRELUDE))).expect("The prelude is well-formed")
+}
+
+/// The arity of the prelude function `name`, if it is one.
+pub fn arity(name: &str) -> Option<usize> {
+  match name {
+    "sin" | "cos" | "exp" | "log" | "sqrt" | "abs" | "floor" => Some(1),
+    "printd" | "putchard" => Some(1),
+    "readd" | "rand" => Some(0),
+    "srand" => Some(1),
+    "pow" | "min" | "max" => Some(2),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::Ast;
+
+  #[test]
+  fn prelude_arities() {
+    let module = prelude();
+    assert_eq!(module.items.len(), 15);
+    for item in &module.items {
+      let Ast::Proto(proto) = item else { panic!() };
+      assert_eq!(arity(&proto.name), Some(proto.args.len()));
+    }
+  }
+}
@@ -0,0 +1,683 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, ExprAst, FuncAst, ModuleAst, ProtoAst};
+use crate::prelude::prelude;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// The functions the interpreter and the backends provide without any
+/// declaration, besides those of the prelude.
+const BUILTINS: &[&str] = &[
+  "int", "float", "len", "chr", "ord", "format", "printf", "assert", "panic",
+];
+
+/// Resolver - checks that every name a module mentions is bound, by a
+/// parameter, a `let`/`var`/`catch` binding or a lambda in scope, or by a
+/// top-level item, so that all the undefined names of a file are reported
+/// before any of it runs. Top-level names are visible everywhere in the
+/// module, whatever the order of the items, and those of earlier modules
+/// stay visible. Errors are reported at the innermost node that carries a
+/// span, as in [`crate::typeck`]. An unknown name that is a likely
+/// misspelling of one in scope comes with the fix of using that one.
+///
+/// Calls of the functions, externs and structs declared so far must also
+/// pass as many arguments as their prototype takes. An extern declared
+/// again, as one of the prelude may be, must take as many parameters as
+/// before, and in sandboxed mode only the externs of an allow-list may be
+/// declared at all.
+///
+/// The definitions of the modules it resolves, and the uses of their names,
+/// go into a [`SymbolTable`] for tools to query.
+pub struct Resolver {
+  globals: HashSet<String>,
+  arities: HashMap<String, (usize, Span)>, // and where the prototype is
+  externs: HashMap<String, (usize, Option<Span>)>, // by symbol, and where unless the prelude
+  allowed_externs: Option<HashSet<String>>, // in sandboxed mode
+  symbols: SymbolTable,
+  module: HashMap<String, Vec<(usize, DefId)>>, // top-level, by item index
+  item: usize,                                  // the index of the item visited
+}
+
+/// DefId - identifies a definition in a [`SymbolTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DefId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefKind {
+  Function,
+  Extern,
+  Struct,
+  Global,
+  Const,
+  Param, // of a function or a lambda
+  Local, // a `let`, `var` or `catch` binding
+}
+
+impl DefKind {
+  fn describe(self) -> &'static str {
+    match self {
+      Self::Function => "a function",
+      Self::Extern => "an extern",
+      Self::Struct => "a struct",
+      Self::Global => "a global variable",
+      Self::Const => "a constant",
+      Self::Param => "a parameter",
+      Self::Local => "a binding",
+    }
+  }
+}
+
+/// A name a module defines. Functions, externs and structs are at their
+/// prototype, while parameters, bindings, globals and constants are at
+/// their names, but for the binding of a `catch`, which is at its `try`.
+#[derive(Debug, Clone)]
+pub struct Definition {
+  pub id: DefId,
+  pub name: String,
+  pub kind: DefKind,
+  pub span: Span,
+}
+
+/// SymbolTable - every definition of the modules a [`Resolver`] has
+/// resolved, in the order they appear, with the places that refer to each:
+/// the reads, assignments and calls of the name and the `&f`s, at the
+/// innermost node that carries a span. A top-level name refers to its last
+/// definition up to the item it is used in, or to the first one after it.
+/// Builtins and names declared without being resolved, such as those of the
+/// prelude, aren't in it.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+  defs: Vec<Definition>,
+  refs: Vec<Vec<Span>>,            // by definition
+  globals: HashMap<String, DefId>, // the last top-level definitions
+}
+
+impl SymbolTable {
+  fn define(&mut self, name: &str, kind: DefKind, span: Span) -> DefId {
+    let id = DefId(self.defs.len());
+    self.defs.push(Definition {
+      id,
+      name: name.to_string(),
+      kind,
+      span,
+    });
+    self.refs.push(vec![]);
+    id
+  }
+
+  pub fn get(&self, id: DefId) -> &Definition {
+    &self.defs[id.0]
+  }
+
+  /// The definitions of `name`, local ones included, in order.
+  pub fn lookup(&self, name: &str) -> Vec<&Definition> {
+    self.defs.iter().filter(|def| def.name == name).collect()
+  }
+
+  /// The definitions positioned from `range.start` up to, but excluding,
+  /// `range.end`, in order.
+  pub fn definitions_in_span(&self, range: Range<Span>) -> Vec<&Definition> {
+    let pos = |span: &Span| (span.line, span.col);
+    let range = pos(&range.start)..pos(&range.end);
+    let defs = self.defs.iter();
+    defs.filter(|def| range.contains(&pos(&def.span))).collect()
+  }
+
+  /// Where the definition `id` is referred to, in order.
+  pub fn references(&self, id: DefId) -> &[Span] {
+    &self.refs[id.0]
+  }
+}
+
+impl Resolver {
+  pub fn new() -> Self {
+    // the externs of the prelude, which is in no file the errors point at
+    let externs = prelude().items.into_iter().filter_map(|item| match item {
+      Ast::Proto(proto) => Some((proto.symbol().to_string(), (proto.args.len(), None))),
+      _ => None,
+    });
+    Self {
+      globals: BUILTINS.iter().map(|name| name.to_string()).collect(),
+      arities: HashMap::new(),
+      externs: externs.collect(),
+      allowed_externs: None,
+      symbols: SymbolTable::default(),
+      module: HashMap::new(),
+      item: 0,
+    }
+  }
+
+  pub fn symbols(&self) -> &SymbolTable {
+    &self.symbols
+  }
+
+  pub fn into_symbols(self) -> SymbolTable {
+    self.symbols
+  }
+
+  /// Enters sandboxed mode, where only the externs binding the symbols in
+  /// `allowed` may be declared from now on, or leaves it.
+  pub fn set_allowed_externs(&mut self, allowed: Option<HashSet<String>>) {
+    self.allowed_externs = allowed;
+  }
+
+  /// Checks that the extern `proto` may be declared: it must be allowed in
+  /// sandboxed mode, and take as many parameters as any earlier extern that
+  /// binds the same symbol.
+  pub fn check_extern(&self, proto: &ProtoAst) -> Result<(), Diagnostic> {
+    let symbol = proto.symbol();
+    if let Some(allowed) = &self.allowed_externs {
+      if !allowed.contains(symbol) {
+        let msg = format!("Extern `{}` is not allowed in sandboxed mode", proto.name);
+        return Err(Diagnostic::error(proto.span, msg).with_code("extern"));
+      }
+    }
+    match self.externs.get(symbol) {
+      Some(&(arity, decl)) if arity != proto.args.len() => {
+        let params = plural(proto.args.len(), "parameter");
+        let msg = match decl {
+          Some(decl) => format!(
+            "Extern `{}` is declared with {}, but was declared with {} at {}",
+            proto.name,
+            params,
+            plural(arity, "parameter"),
+            decl
+          ),
+          None => format!(
+            "Extern `{}` is declared with {}, but the prelude declares it with {}",
+            proto.name,
+            params,
+            plural(arity, "parameter")
+          ),
+        };
+        let error = Diagnostic::error(proto.span, msg).with_code("extern");
+        match decl {
+          Some(decl) => Err(error.with_label(decl, format!("`{}` first declared here", symbol))),
+          None => Err(error),
+        }
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Makes the names `item` defines visible to the modules resolved later.
+  pub fn declare(&mut self, item: &Ast) {
+    match item {
+      Ast::Func(FuncAst { proto, .. }) | Ast::Proto(proto) if !proto.name.is_empty() => {
+        self.globals.insert(proto.name.clone());
+        let arity = (proto.args.len(), proto.span);
+        self.arities.insert(proto.name.clone(), arity);
+        if let Ast::Proto(_) = item {
+          self
+            .externs
+            .entry(proto.symbol().to_string())
+            .or_insert((proto.args.len(), Some(proto.span)));
+        }
+      }
+      Ast::Global(vars, _) => self
+        .globals
+        .extend(vars.iter().map(|(name, ..)| name.clone())),
+      Ast::Const(name, ..) => {
+        self.globals.insert(name.clone());
+      }
+      Ast::Struct(decl) => {
+        self.globals.insert(decl.name.clone());
+        let arity = (decl.fields.len(), decl.span);
+        self.arities.insert(decl.name.clone(), arity);
+      }
+      Ast::Func(_) | Ast::Proto(_) | Ast::Expr(_) | Ast::Import(..) => (),
+    }
+  }
+
+  /// Declares the items of `module`, reporting the externs that can't be
+  /// declared, then reports every use of a name that is bound nowhere, in
+  /// source order.
+  pub fn resolve_module(&mut self, module: &ModuleAst) -> Vec<Diagnostic> {
+    let mut errors = vec![];
+    for item in &module.items {
+      if let Ast::Proto(proto) = item {
+        errors.extend(self.check_extern(proto).err());
+      }
+      self.declare(item);
+    }
+    for (i, item) in module.items.iter().enumerate() {
+      for (name, kind, span) in definitions(item) {
+        let id = self.symbols.define(name, kind, span);
+        self.module.entry(name.clone()).or_default().push((i, id));
+      }
+    }
+    for (i, item) in module.items.iter().enumerate() {
+      self.item = i;
+      match item {
+        Ast::Func(func) => {
+          let proto = &func.proto;
+          let args = proto.args.iter().zip(&proto.arg_spans);
+          let mut scope = args
+            .map(|(arg, &at)| self.bind(arg, DefKind::Param, at))
+            .collect();
+          self.visit(&func.body, &mut scope, proto.span, &mut errors);
+        }
+        Ast::Expr(expr) | Ast::Const(_, _, expr, _) => {
+          self.visit(expr, &mut vec![], Span::default(), &mut errors)
+        }
+        Ast::Global(vars, _) => {
+          for init in vars.iter().filter_map(|(.., init)| init.as_ref()) {
+            self.visit(init, &mut vec![], Span::default(), &mut errors);
+          }
+        }
+        Ast::Proto(_) | Ast::Struct(_) | Ast::Import(..) => (),
+      }
+    }
+    for (name, defs) in self.module.drain() {
+      let (_, last) = defs[defs.len() - 1];
+      self.symbols.globals.insert(name, last);
+    }
+    errors
+  }
+
+  fn visit(
+    &mut self,
+    expr: &ExprAst,
+    scope: &mut Vec<(String, DefId)>,
+    span: Span,
+    errors: &mut Vec<Diagnostic>,
+  ) {
+    let local = |name: &String, scope: &Vec<(String, DefId)>| {
+      scope
+        .iter()
+        .rev()
+        .find(|(n, _)| n == name)
+        .map(|&(_, id)| id)
+    };
+    let bound = |name: &String, scope: &Vec<(String, DefId)>| {
+      local(name, scope).is_some() || self.globals.contains(name)
+    };
+    let mut span = span;
+    match expr {
+      ExprAst::VarAst(name, at) if !bound(name, scope) => {
+        let error = unresolved(*at, format!("Unknown variable `{}`", name));
+        errors.push(self.did_you_mean(error, name, scope, true));
+      }
+      ExprAst::AssignAst(name, _, at) if !bound(name, scope) => {
+        let error = unresolved(*at, format!("Unknown variable `{}`", name));
+        errors.push(self.did_you_mean(error, name, scope, true));
+      }
+      ExprAst::VarAst(name, at) => {
+        let id = local(name, scope);
+        self.refer(name, id, *at);
+      }
+      ExprAst::AssignAst(name, _, at) => {
+        let id = local(name, scope);
+        self.refer(name, id, *at);
+      }
+      ExprAst::CallAst(name, _, call) if !bound(name, scope) => {
+        let error = unresolved(*call, format!("Unknown function `{}`", name));
+        errors.push(self.did_you_mean(error, name, scope, true));
+      }
+      ExprAst::CallAst(name, _, call) if local(name, scope).is_some() => {
+        span = *call;
+        let id = local(name, scope);
+        self.refer(name, id, span);
+      }
+      ExprAst::CallAst(name, args, call) => {
+        span = *call;
+        self.refer(name, None, span);
+        match self.arities.get(name) {
+          Some(&(arity, decl)) if arity != args.len() => {
+            let msg = format!(
+              "Function `{}` declared at {} expects {}, found {}",
+              name,
+              decl,
+              plural(arity, "argument"),
+              args.len()
+            );
+            let declared = format!("`{}` declared here", name);
+            errors.push(
+              Diagnostic::error(span, msg)
+                .with_code("arity")
+                .with_label(decl, declared),
+            );
+          }
+          _ => (),
+        }
+      }
+      ExprAst::FuncRefAst(name, at) if !self.globals.contains(name) => {
+        let error = unresolved(*at, format!("Unknown function `{}`", name));
+        errors.push(self.did_you_mean(error, name, &[], false));
+      }
+      ExprAst::FuncRefAst(name, at) => self.refer(name, None, *at),
+      ExprAst::LetAst(bindings, body) => {
+        let depth = scope.len();
+        for (name, at, init) in bindings {
+          self.visit(init, scope, span, errors);
+          scope.push(self.bind(name, DefKind::Local, *at));
+        }
+        self.visit(body, scope, span, errors);
+        scope.truncate(depth);
+        return;
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        self.visit(init, scope, span, errors);
+        let depth = scope.len();
+        for (name, at) in names {
+          scope.push(self.bind(name, DefKind::Local, *at));
+        }
+        self.visit(body, scope, span, errors);
+        scope.truncate(depth);
+        return;
+      }
+      ExprAst::VarInAst(vars, body) => {
+        let depth = scope.len();
+        for (name, at, init) in vars {
+          if let Some(init) = init {
+            self.visit(init, scope, span, errors);
+          }
+          scope.push(self.bind(name, DefKind::Local, *at));
+        }
+        self.visit(body, scope, span, errors);
+        scope.truncate(depth);
+        return;
+      }
+      ExprAst::LambdaAst(args, body) => {
+        let depth = scope.len();
+        for (arg, at) in args {
+          scope.push(self.bind(arg, DefKind::Param, *at));
+        }
+        self.visit(body, scope, span, errors);
+        scope.truncate(depth);
+        return;
+      }
+      ExprAst::TryAst(expr, name, handler, at) => {
+        self.visit(expr, scope, *at, errors);
+        let depth = scope.len();
+        if let Some(name) = name {
+          scope.push(self.bind(name, DefKind::Local, *at));
+        }
+        self.visit(handler, scope, *at, errors);
+        scope.truncate(depth);
+        return;
+      }
+      ExprAst::UnaryAst(_, _, at)
+      | ExprAst::BinAst(_, _, _, at)
+      | ExprAst::MatchAst(_, _, at)
+      | ExprAst::ReturnAst(_, at) => span = *at,
+      _ => (),
+    }
+    for child in expr.children() {
+      self.visit(child, scope, span, errors);
+    }
+  }
+
+  /// Adds to the `error` about the unknown `name` the name in `scope`, or
+  /// at the top level, it is most likely a misspelling of, if any: as a
+  /// note, and as the fix of replacing it when the error is `at_name`, at
+  /// the position of the name itself.
+  fn did_you_mean(
+    &self,
+    error: Diagnostic,
+    name: &str,
+    scope: &[(String, DefId)],
+    at_name: bool,
+  ) -> Diagnostic {
+    let mut globals: Vec<_> = self.globals.iter().collect();
+    globals.sort();
+    let locals = scope.iter().rev().map(|(local, id)| (local, Some(*id)));
+    let candidates = locals.chain(globals.into_iter().map(|global| (global, None)));
+    let mut best: Option<(usize, &String, Option<DefId>)> = None;
+    for (candidate, id) in candidates.filter(|(candidate, _)| !candidate.starts_with('$')) {
+      let distance = edit_distance(name, candidate);
+      if best.is_none_or(|(nearest, ..)| distance < nearest) {
+        best = Some((distance, candidate, id));
+      }
+    }
+    let Some((distance, similar, id)) = best else {
+      return error;
+    };
+    if distance > name.chars().count() / 3 {
+      return error;
+    }
+    let kind = id
+      .or_else(|| self.top_level(similar))
+      .map_or("a builtin", |id| self.symbols.get(id).kind.describe());
+    let span = error.primary_span;
+    let error = error.with_note(format!("did you mean `{}`?", similar));
+    match at_name {
+      true => error.with_suggestion(
+        span,
+        similar.clone(),
+        format!("{} has a similar name", kind),
+      ),
+      false => error,
+    }
+  }
+
+  /// The definition the top-level `name` refers to in the item visited.
+  fn top_level(&self, name: &str) -> Option<DefId> {
+    let defs = self.module.get(name);
+    let defs = defs.and_then(|defs| {
+      let before = defs.iter().rev().find(|(i, _)| *i <= self.item);
+      before.or(defs.first()).map(|&(_, id)| id)
+    });
+    defs.or_else(|| self.symbols.globals.get(name).copied())
+  }
+
+  fn bind(&mut self, name: &str, kind: DefKind, span: Span) -> (String, DefId) {
+    (name.to_string(), self.symbols.define(name, kind, span))
+  }
+
+  /// Records a use of `name` at `span`, which refers to the local `id` if
+  /// there is one, or else to a top-level definition.
+  fn refer(&mut self, name: &str, id: Option<DefId>, span: Span) {
+    if let Some(id) = id.or_else(|| self.top_level(name)) {
+      self.symbols.refs[id.0].push(span);
+    }
+  }
+}
+
+/// The top-level names `item` defines.
+fn definitions(item: &Ast) -> Vec<(&String, DefKind, Span)> {
+  match item {
+    Ast::Func(FuncAst { proto, .. }) if !proto.name.is_empty() => {
+      vec![(&proto.name, DefKind::Function, proto.span)]
+    }
+    Ast::Proto(proto) => vec![(&proto.name, DefKind::Extern, proto.span)],
+    Ast::Struct(decl) => vec![(&decl.name, DefKind::Struct, decl.span)],
+    Ast::Global(vars, _) => {
+      let names = vars
+        .iter()
+        .map(|(name, at, _)| (name, DefKind::Global, *at));
+      names.collect()
+    }
+    Ast::Const(name, at, ..) => vec![(name, DefKind::Const, *at)],
+    Ast::Func(_) | Ast::Expr(_) | Ast::Import(..) => vec![],
+  }
+}
+
+fn unresolved(span: Span, msg: String) -> Diagnostic {
+  Diagnostic::error(span, msg).with_code("unresolved")
+}
+
+/// The number of characters to insert, delete or substitute to turn `a`
+/// into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut diagonal = row[0];
+    row[0] = i + 1;
+    for (j, &cb) in b.iter().enumerate() {
+      let substituted = diagonal + usize::from(ca != cb);
+      diagonal = row[j + 1];
+      row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+    }
+  }
+  row[b.len()]
+}
+
+fn plural(n: usize, noun: &str) -> String {
+  match n {
+    1 => format!("1 {}", noun),
+    n => format!("{} {}s", n, noun),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  fn resolve(src: &'static str) -> Vec<String> {
+    let mut resolver = Resolver::new();
+    resolver.resolve_module(&prelude());
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let errors = resolver.resolve_module(&module);
+    errors.iter().map(Diagnostic::to_string).collect()
+  }
+
+  #[test]
+  fn resolve_scopes() {
+    let src = "def f(x) let y = x, z = y in var w = z in w = later(w) + N;
+      def later(a) { let (p, q) = (a, sqrt(a)) in p + q; \\(b) b + a };
+      const N = 2; var g = &later; struct P(x); try P(g).x catch e -> len(e);
+      def binary @ 5 (a b) a; 1 @ 2; match N { 2 -> N, _ -> 0 }";
+    assert_eq!(resolve(src), Vec::<String>::new());
+  }
+
+  #[test]
+  fn resolve_arity() {
+    let src = "def f(a, b, c) a; struct P(x);
+      f(1, 2); P(1, 2); extern ext(x); ext(); pow(2); let f = \\(x) x in f(1)";
+    assert_eq!(
+      resolve(src),
+      vec![
+        "2:7: Function `f` declared at 1:5 expects 3 arguments, found 2",
+        "2:16: Function `P` declared at 1:26 expects 1 argument, found 2",
+        "2:40: Function `ext` declared at 2:32 expects 1 argument, found 0",
+        "2:47: Function `pow` declared at 3:10 expects 2 arguments, found 1",
+      ]
+    );
+  }
+
+  #[test]
+  fn resolve_errors() {
+    let src = "def f(length) lenght * 2; def g(x) { let y = 1 in y; y + h(x) };
+      \\(a) a + b; &nope; x = 1; try 1 catch e -> e; e";
+    assert_eq!(
+      resolve(src),
+      vec![
+        "1:15: Unknown variable `lenght`\n  = note: did you mean `length`?",
+        "1:54: Unknown variable `y`",
+        "1:58: Unknown function `h`",
+        "2:16: Unknown variable `b`",
+        "2:19: Unknown function `nope`",
+        "2:26: Unknown variable `x`",
+        "2:53: Unknown variable `e`",
+      ]
+    );
+  }
+
+  #[test]
+  fn resolve_suggestions() {
+    let src = "def fibonacci(n) n; def f(length) { lenght = 2; fibonaci(length) };
+      var total = 0; &fibonaci; sqt(totl)";
+    let mut resolver = Resolver::new();
+    resolver.resolve_module(&prelude());
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let errors = resolver.resolve_module(&module);
+    let fixes: Vec<_> = errors
+      .iter()
+      .map(|error| {
+        let fix = error.suggestion.as_ref().map_or(String::new(), |fix| {
+          format!(" -> {} {} ({})", fix.span, fix.replacement, fix.message)
+        });
+        format!("{}{}", error, fix)
+      })
+      .collect();
+    assert_eq!(
+      fixes,
+      vec![
+        "1:37: Unknown variable `lenght`\n  = note: did you mean `length`? -> 1:37 length (a parameter has a similar name)",
+        "1:49: Unknown function `fibonaci`\n  = note: did you mean `fibonacci`? -> 1:49 fibonacci (a function has a similar name)",
+        "2:22: Unknown function `fibonaci`\n  = note: did you mean `fibonacci`?",
+        "2:33: Unknown function `sqt`\n  = note: did you mean `sqrt`? -> 2:33 sqrt (an extern has a similar name)",
+        "2:37: Unknown variable `totl`\n  = note: did you mean `total`? -> 2:37 total (a global variable has a similar name)",
+      ]
+    );
+  }
+
+  #[test]
+  fn resolve_externs() {
+    let src = "extern sin(x, y); extern pow(x, y); extern math.cos(a, b); extern f(x);
+      extern f(x, y)";
+    assert_eq!(
+      resolve(src),
+      vec![
+        "1:8: Extern `sin` is declared with 2 parameters, but the prelude declares it with 1 parameter",
+        "1:44: Extern `math.cos` is declared with 2 parameters, but the prelude declares it with 1 parameter",
+        "2:14: Extern `f` is declared with 2 parameters, but was declared with 1 parameter at 1:67",
+      ]
+    );
+    // only the labels of the user's own declarations, which are in its file
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let errors = Resolver::new().resolve_module(&module);
+    let labels: Vec<_> = errors.iter().map(|e| e.labels.len()).collect();
+    assert_eq!(labels, [0, 0, 1]);
+    let mut resolver = Resolver::new();
+    let allowed = ["sin".to_string()].into_iter().collect();
+    resolver.set_allowed_externs(Some(allowed));
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(
+      "extern sin(x); extern system(cmd)",
+    )))
+    .unwrap();
+    let errors = resolver.resolve_module(&module);
+    let errors: Vec<_> = errors.iter().map(Diagnostic::to_string).collect();
+    assert_eq!(
+      errors,
+      vec!["1:23: Extern `system` is not allowed in sandboxed mode"]
+    );
+  }
+
+  #[test]
+  fn resolve_symbols() {
+    let src = "def f(x) let y = x in y + g;
+      var g = 1; struct P(a); def h() f(g) + P(1).a; var k = &f; def f(z) z; f(2)";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut resolver = Resolver::new();
+    assert!(resolver.resolve_module(&module).is_empty());
+    let symbols = resolver.into_symbols();
+    let describe = |def: &Definition| {
+      let refs: Vec<_> = symbols
+        .references(def.id)
+        .iter()
+        .map(Span::to_string)
+        .collect();
+      format!(
+        "{:?} {} {} [{}]",
+        def.kind,
+        def.name,
+        def.span,
+        refs.join(" ")
+      )
+    };
+    let defs: Vec<_> = symbols.lookup("f").into_iter().map(describe).collect();
+    assert_eq!(
+      defs,
+      vec!["Function f 1:5 [2:39 2:62]", "Function f 2:70 [2:78]"]
+    );
+    let defs: Vec<_> = symbols.lookup("g").into_iter().map(describe).collect();
+    assert_eq!(defs, vec!["Global g 2:11 [1:27 2:41]"]);
+    let start = Span { line: 1, col: 1 };
+    let end = Span { line: 2, col: 0 };
+    let defs = symbols.definitions_in_span(start..end);
+    let defs: Vec<_> = defs.into_iter().map(describe).collect();
+    assert_eq!(
+      defs,
+      vec![
+        "Function f 1:5 [2:39 2:62]",
+        "Param x 1:7 [1:18]",
+        "Local y 1:14 [1:23]",
+      ]
+    );
+  }
+}
@@ -0,0 +1,429 @@
+use crate::eval::{eval_int_bin, eval_unary};
+use crate::parser::{BinOp, UnOp};
+use crate::prelude;
+use crate::value::Value;
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufRead, Write};
+
+thread_local! {
+  /// The state of the generator behind `rand`, shared by interpreted and
+  /// native code so that a seed yields the same numbers either way.
+  static RNG: Cell<u64> = const { Cell::new(0) };
+
+  /// Why the native code running on this thread failed, if it did.
+  static FAILURE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Calls the builtin `name`, or returns `None` if there is no such builtin.
+/// `int(x)` truncates towards zero, `float(n)` converts to double and
+/// `len(s)` counts the characters of a string or the elements of an array.
+/// `chr(n)` is the string of the character with code point `n`, and `ord(s)`
+/// the code point of a one-character string. `format(fmt, ...)` fills the
+/// `{}`s in `fmt` with the other arguments, and `printf` prints the result
+/// to `out`, returning the number of bytes written. `printd(x)` prints `x`
+/// on a line of its own and `putchard(c)` prints the character with code
+/// `c`, both returning unit. `readd()` reads a double on a line of its own
+/// from `input`. `rand()` yields the next of a sequence of doubles in
+/// `[0, 1)`, which `srand(seed)` restarts. The math functions of the prelude
+/// take and return doubles.
+pub fn call_builtin(
+  name: &str,
+  args: &[Value],
+  out: &mut dyn Write,
+  input: &mut dyn BufRead,
+) -> Option<Result<Value, String>> {
+  let res = match (name, args) {
+    ("int", [Value::Int(i)]) => Ok(Value::Int(*i)),
+    ("int", [Value::Num(n)]) => to_int(*n).map(Value::Int),
+    ("float", [val @ (Value::Int(_) | Value::Num(_))]) => Ok(Value::Num(val.as_f64().unwrap())),
+    ("int" | "float", [val]) => Err(format!("`{}` expects a number, found {}", name, val.kind())),
+    ("len", [Value::Str(s)]) => Ok(Value::Int(s.chars().count() as i64)),
+    ("len", [Value::Array(elems)]) => Ok(Value::Int(elems.len() as i64)),
+    ("len", [val]) => Err(format!(
+      "`len` expects a str or array, found {}",
+      val.kind()
+    )),
+    ("chr", [Value::Int(n)]) => match u32::try_from(*n).ok().and_then(char::from_u32) {
+      Some(c) => Ok(c.to_string().as_str().into()),
+      None => Err(format!("{} is not a valid code point", n)),
+    },
+    ("chr", [val]) => Err(format!("`chr` expects an int, found {}", val.kind())),
+    ("ord", [Value::Str(s)]) => match s.chars().collect::<Vec<_>>()[..] {
+      [c] => Ok(Value::Int(c as i64)),
+      _ => Err(format!("`ord` expects a single character, found \"{}\"", s)),
+    },
+    ("ord", [val]) => Err(format!("`ord` expects a str, found {}", val.kind())),
+    ("panic", [Value::Int(code)]) => Err(format!("Panicked with code {}", code)),
+    ("panic", [val]) => Err(format!("`panic` expects an int, found {}", val.kind())),
+    ("int" | "float" | "len" | "chr" | "ord" | "panic", _) => Err(format!(
+      "Incorrect # arguments passed to `{}`: expected 1, got {}",
+      name,
+      args.len()
+    )),
+    ("format", [Value::Str(fmt), args @ ..]) => format(fmt, args).map(|s| s.as_str().into()),
+    ("printf", [Value::Str(fmt), args @ ..]) => format(fmt, args).and_then(|s| {
+      out.write_all(s.as_bytes()).map_err(|e| e.to_string())?;
+      Ok(Value::Int(s.len() as i64))
+    }),
+    ("format" | "printf", [val, ..]) => Err(format!(
+      "`{}` expects a format str, found {}",
+      name,
+      val.kind()
+    )),
+    ("format" | "printf", []) => Err(format!("`{}` expects a format str", name)),
+    ("printd" | "putchard", [val]) => match val.as_f64() {
+      Some(x) => {
+        let res = match name {
+          "printd" => writeln!(out, "{:?}", x),
+          _ => out.write_all(&[x as u8]),
+        };
+        res.map(|_| Value::Unit).map_err(|e| e.to_string())
+      }
+      None => Err(format!("`{}` expects a number, found {}", name, val.kind())),
+    },
+    ("readd", []) => read_double(input).map(Value::Num),
+    ("rand", []) => Ok(Value::Num(rand())),
+    ("srand", [val]) => match val.as_f64() {
+      Some(seed) => {
+        srand(seed);
+        Ok(Value::Unit)
+      }
+      None => Err(format!("`srand` expects a number, found {}", val.kind())),
+    },
+    _ => math(name, args)?,
+  };
+  Some(res)
+}
+
+/// `int(n)` of a double: `n` rounded towards zero, unless that is out of
+/// the range of ints.
+pub fn to_int(n: f64) -> Result<i64, String> {
+  match n.trunc() {
+    // i64::MAX as f64 rounds up to 2^63, hence the exclusive bound
+    n if (-(2f64.powi(63))..2f64.powi(63)).contains(&n) => Ok(n as i64),
+    _ => Err(format!("Cannot convert {} to int", n)),
+  }
+}
+
+/// Whether the builtin `name` always returns the same value for the same
+/// arguments, without any effect, so that calls to it can be evaluated
+/// ahead of time.
+pub fn is_pure(name: &str) -> bool {
+  match name {
+    "int" | "float" | "len" | "chr" | "ord" | "format" => true,
+    "printd" | "putchard" | "readd" | "rand" | "srand" => false,
+    name => prelude::arity(name).is_some(),
+  }
+}
+
+/// `printd` for native code, which links it by name.
+#[no_mangle]
+pub extern "C" fn printd(x: f64) -> f64 {
+  println!("{:?}", x);
+  0.0
+}
+
+/// `putchard` for native code, which links it by name.
+#[no_mangle]
+pub extern "C" fn putchard(c: f64) -> f64 {
+  let _ = std::io::stdout().write_all(&[c as u8]);
+  0.0
+}
+
+/// `readd` for native code. At the end of input, or on a line that isn't a
+/// number, it returns NaN.
+#[no_mangle]
+pub extern "C" fn readd() -> f64 {
+  read_double(&mut io::stdin().lock()).unwrap_or(f64::NAN)
+}
+
+/// `rand` for native code: the next number of a splitmix64 sequence, scaled
+/// to `[0, 1)`.
+#[no_mangle]
+pub extern "C" fn rand() -> f64 {
+  let mut z = RNG.get().wrapping_add(0x9e3779b97f4a7c15);
+  RNG.set(z);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+  z ^= z >> 31;
+  (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// `srand` for native code. Seeds are truncated to integers.
+#[no_mangle]
+pub extern "C" fn srand(seed: f64) -> f64 {
+  RNG.set(seed as i64 as u64);
+  0.0
+}
+
+/// IntOp - an operation on ints that native code checks, and reports the
+/// failure of to [`kale_int_error`] by its code: as the interpreter, it
+/// fails on overflow, on a division by zero, on a shift by 64 bits or more
+/// and on the conversion of a double out of the range of ints.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u32)]
+pub enum IntOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Rem,
+  Shl,
+  Shr,
+  Neg,
+  ToInt, // of a double, whose bits it is given
+}
+
+impl IntOp {
+  const ALL: [IntOp; 9] = [
+    IntOp::Add,
+    IntOp::Sub,
+    IntOp::Mul,
+    IntOp::Div,
+    IntOp::Rem,
+    IntOp::Shl,
+    IntOp::Shr,
+    IntOp::Neg,
+    IntOp::ToInt,
+  ];
+
+  // the operators of those before `Neg`, in order
+  const BIN_OPS: [BinOp; 7] = [
+    BinOp::Add,
+    BinOp::Sub,
+    BinOp::Mul,
+    BinOp::Div,
+    BinOp::Rem,
+    BinOp::Shl,
+    BinOp::Shr,
+  ];
+
+  /// The checked operation of the binary operator `op`, if it can fail.
+  pub fn of(op: BinOp) -> Option<Self> {
+    let i = Self::BIN_OPS.iter().position(|&bin_op| bin_op == op)?;
+    Some(Self::ALL[i])
+  }
+
+  /// The error of the interpreter about this operation failing on `lhs`
+  /// and `rhs`.
+  fn error(self, lhs: i64, rhs: i64) -> String {
+    let res = match self {
+      IntOp::Neg => eval_unary(UnOp::Neg, Value::Int(lhs)).map(|_| ()),
+      IntOp::ToInt => to_int(f64::from_bits(lhs as u64)).map(|_| ()),
+      op => eval_int_bin(Self::BIN_OPS[op as usize], lhs, rhs).map(|_| ()),
+    };
+    res
+      .err()
+      .unwrap_or_else(|| format!("`{:?}` failed on {} and {}", self, lhs, rhs))
+  }
+}
+
+/// Reports the failure of the operation on ints with code `op`, an
+/// [`IntOp`], for native code to return from each function it is in, as
+/// [`kale_failed`] tells it. The first failure is kept until taken.
+#[no_mangle]
+pub extern "C" fn kale_int_error(op: u32, lhs: i64, rhs: i64) {
+  let op = IntOp::ALL[op as usize];
+  FAILURE.with_borrow_mut(|failure| {
+    failure.get_or_insert_with(|| op.error(lhs, rhs));
+  });
+}
+
+/// Whether native code failed, and returns from the functions it is in.
+#[no_mangle]
+pub extern "C" fn kale_failed() -> u32 {
+  FAILURE.with_borrow(Option::is_some) as u32
+}
+
+/// Why native code failed since the failure was last taken, if it did.
+pub fn take_failure() -> Option<String> {
+  FAILURE.take()
+}
+
+fn read_double(input: &mut dyn BufRead) -> Result<f64, String> {
+  let mut line = String::new();
+  match input.read_line(&mut line) {
+    Ok(0) => Err("`readd` reached the end of input".to_string()),
+    Ok(_) => {
+      let line = line.trim();
+      line
+        .parse()
+        .map_err(|_| format!("`readd` expects a number, found `{}`", line))
+    }
+    Err(e) => Err(e.to_string()),
+  }
+}
+
+/// Applies the math function `name` of the prelude, or returns `None` if
+/// there is no such function.
+fn math(name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+  let arity = prelude::arity(name)?;
+  if args.len() != arity {
+    return Some(Err(format!(
+      "Incorrect # arguments passed to `{}`: expected {}, got {}",
+      name,
+      arity,
+      args.len()
+    )));
+  }
+  let mut nums = vec![];
+  for arg in args {
+    match arg.as_f64() {
+      Some(n) => nums.push(n),
+      None => {
+        return Some(Err(format!(
+          "`{}` expects a number, found {}",
+          name,
+          arg.kind()
+        )))
+      }
+    }
+  }
+  let res = match (name, &nums[..]) {
+    ("sin", [x]) => x.sin(),
+    ("cos", [x]) => x.cos(),
+    ("exp", [x]) => x.exp(),
+    ("log", [x]) => x.ln(),
+    ("sqrt", [x]) => x.sqrt(),
+    ("abs", [x]) => x.abs(),
+    ("floor", [x]) => x.floor(),
+    ("pow", [x, y]) => x.powf(*y),
+    ("min", [x, y]) => x.min(*y),
+    ("max", [x, y]) => x.max(*y),
+    _ => unreachable!(),
+  };
+  Some(Ok(Value::Num(res)))
+}
+
+/// Replaces each `{}` in `fmt` with the next argument. `{{` and `}}` stand
+/// for literal braces.
+pub fn format(fmt: &str, args: &[Value]) -> Result<String, String> {
+  let mut res = String::new();
+  let mut holes = 0;
+  let mut chars = fmt.chars().peekable();
+  while let Some(c) = chars.next() {
+    match (c, chars.peek()) {
+      ('{', Some('{')) | ('}', Some('}')) => {
+        chars.next();
+        res.push(c);
+      }
+      ('{', Some('}')) => {
+        chars.next();
+        if let Some(arg) = args.get(holes) {
+          res += &arg.to_string();
+        }
+        holes += 1;
+      }
+      ('{' | '}', _) => return Err(format!("Unmatched `{}` in format string", c)),
+      _ => res.push(c),
+    }
+  }
+  match holes == args.len() {
+    true => Ok(res),
+    false => Err(format!(
+      "Format string has {} placeholders, but {} arguments were given",
+      holes,
+      args.len()
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn output_builtins() {
+    let mut out = vec![];
+    for arg in [Value::Int(72), Value::Num(105.0), Value::Num(10.0)] {
+      let res = call_builtin("putchard", &[arg], &mut out, &mut io::empty()).unwrap();
+      assert_eq!(res, Ok(Value::Unit));
+    }
+    call_builtin("printd", &[Value::Int(3)], &mut out, &mut io::empty())
+      .unwrap()
+      .unwrap();
+    call_builtin("printd", &[Value::Num(-0.5)], &mut out, &mut io::empty())
+      .unwrap()
+      .unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "Hi\n3.0\n-0.5\n");
+    assert_eq!(
+      call_builtin("printd", &["s".into()], &mut vec![], &mut io::empty()).unwrap(),
+      Err("`printd` expects a number, found str".to_string())
+    );
+  }
+
+  #[test]
+  fn text_builtins() {
+    let call =
+      |name, arg: Value| call_builtin(name, &[arg], &mut vec![], &mut io::empty()).unwrap();
+    assert_eq!(call("chr", Value::Int(0x1F600)), Ok("\u{1F600}".into()));
+    assert_eq!(call("ord", "\u{e9}".into()), Ok(Value::Int(0xe9)));
+    assert_eq!(call("ord", "A".into()), Ok(Value::Int(65)));
+    assert_eq!(
+      call("chr", Value::Int(0xD800)),
+      Err("55296 is not a valid code point".to_string())
+    );
+    assert_eq!(
+      call("chr", Value::Int(-1)),
+      Err("-1 is not a valid code point".to_string())
+    );
+    assert_eq!(
+      call("chr", Value::Num(65.0)),
+      Err("`chr` expects an int, found double".to_string())
+    );
+    assert_eq!(
+      call("ord", "ab".into()),
+      Err("`ord` expects a single character, found \"ab\"".to_string())
+    );
+  }
+
+  #[test]
+  fn input_builtin() {
+    let mut input = io::Cursor::new("2.5\n  -3 \nx\n");
+    let mut readd = || call_builtin("readd", &[], &mut vec![], &mut input).unwrap();
+    assert_eq!(readd(), Ok(Value::Num(2.5)));
+    assert_eq!(readd(), Ok(Value::Num(-3.0)));
+    assert_eq!(
+      readd(),
+      Err("`readd` expects a number, found `x`".to_string())
+    );
+    assert_eq!(readd(), Err("`readd` reached the end of input".to_string()));
+  }
+
+  #[test]
+  fn rand_builtins() {
+    let call = |name, args: &[Value]| {
+      let res = call_builtin(name, args, &mut vec![], &mut io::empty());
+      res.unwrap().unwrap()
+    };
+    assert_eq!(call("srand", &[Value::Int(42)]), Value::Unit);
+    let xs: Vec<_> = (0..100)
+      .map(|_| call("rand", &[]).as_f64().unwrap())
+      .collect();
+    assert!(xs.iter().all(|x| (0.0..1.0).contains(x)));
+    call("srand", &[Value::Num(42.9)]);
+    assert_eq!(call("rand", &[]), Value::Num(xs[0]));
+    assert_eq!(srand(42.0), 0.0);
+    assert_eq!(rand(), xs[0]);
+    call("srand", &[Value::Int(7)]);
+    assert_ne!(call("rand", &[]), Value::Num(xs[0]));
+  }
+
+  #[test]
+  fn format_values() {
+    let args = [Value::Int(1), Value::Num(2.5), "s".into()];
+    assert_eq!(
+      format("x = {}, y = {}, {}!", &args).unwrap(),
+      "x = 1, y = 2.5, s!"
+    );
+    assert_eq!(format("{{}} {}}}", &args[..1]).unwrap(), "{} 1}");
+    assert_eq!(
+      format("{} {}", &args).unwrap_err(),
+      "Format string has 2 placeholders, but 3 arguments were given"
+    );
+    assert_eq!(
+      format("{ }", &[]).unwrap_err(),
+      "Unmatched `{` in format string"
+    );
+  }
+}
@@ -0,0 +1,726 @@
+use crate::analysis::Effects;
+#[cfg(feature = "llvm")]
+use crate::codegen::native::{self, BuildOptions};
+use crate::codegen::Engine;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::eval::Interpreter;
+use crate::lexer::Span;
+use crate::lint::{Lint, LintLevel, Linter, Warning};
+use crate::loader::Loader;
+use crate::parser::{Ast, ExprAst, ModuleAst};
+use crate::passes::{const_fold_item, cse_item, Inliner};
+use crate::prelude::prelude;
+use crate::resolve::Resolver;
+use crate::typeck::{split_elems, TypeChecker};
+use crate::value::{Precision, Value};
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// Session - type-checks and runs top-level items one after the other, so
+/// later items see the definitions of earlier ones. The driver runs files
+/// and REPL input through it. The prelude is declared before anything else.
+pub struct Session {
+  checker: TypeChecker,
+  interp: Interpreter,
+  loader: Loader,
+  resolver: Resolver,
+  linter: Linter,
+  effects: Effects,
+  inliner: Option<Inliner>,
+  warnings: Vec<Diagnostic>, // of lints, not yet taken by the driver
+  strip_asserts: bool,
+  cse: bool,
+  engine: Option<Box<dyn Engine>>, // runs the items instead of the interpreter
+}
+
+/// Entry - where a program run as a whole starts. A program that defines
+/// `def main()` starts there, once all of its items are defined, and may
+/// then have no top-level expressions; compiled programs call it from the C
+/// `main`. Without a `main`, its top-level expressions run in order.
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+  Main,
+  TopLevel,
+}
+
+impl Entry {
+  /// Whether the program starts by calling the function `name`: `main`, or
+  /// each top-level expression, which has no name.
+  pub fn calls(&self, name: &str) -> bool {
+    match self {
+      Entry::Main => name == "main",
+      Entry::TopLevel => name.is_empty(),
+    }
+  }
+
+  pub fn of(module: &ModuleAst) -> Result<Self, Diagnostic> {
+    let find = |name: &str| {
+      module.items.iter().find_map(|item| match item {
+        Ast::Func(func) if func.proto.name == name => Some(&func.proto),
+        _ => None,
+      })
+    };
+    let Some(main) = find("main") else {
+      return Ok(Self::TopLevel);
+    };
+    if !main.args.is_empty() {
+      let msg = format!("`main` must take no arguments, found {}", main.args.len());
+      return Err(Diagnostic::error(main.span, msg).with_code("entry"));
+    }
+    match find("") {
+      Some(expr) => {
+        let msg = "Top-level expression in a program with a `main` function";
+        let error = Diagnostic::error(expr.span, msg).with_code("entry");
+        Err(error.with_label(main.span, "`main` is defined here"))
+      }
+      None => Ok(Self::Main),
+    }
+  }
+}
+
+impl Session {
+  pub fn new() -> Self {
+    Self::with_output(io::stdout())
+  }
+
+  pub fn with_output(out: impl Write + 'static) -> Self {
+    let mut session = Self {
+      checker: TypeChecker::new(),
+      interp: Interpreter::with_output(out),
+      loader: Loader::new(),
+      resolver: Resolver::new(),
+      linter: Linter::new(),
+      effects: Effects::new(),
+      inliner: None,
+      warnings: vec![],
+      strip_asserts: false,
+      cse: false,
+      engine: None,
+    };
+    for res in session.run_module(prelude()) {
+      res.expect("The prelude is well-formed");
+    }
+    session
+  }
+
+  /// Makes `readd` read from `input` instead of stdin.
+  pub fn set_input(&mut self, input: impl BufRead + 'static) {
+    self.interp.set_input(input);
+  }
+
+  /// Makes the program compute with doubles of the given precision.
+  pub fn set_precision(&mut self, precision: Precision) {
+    self.interp.set_precision(precision);
+    if let Some(engine) = &mut self.engine {
+      engine.set_precision(precision);
+    }
+  }
+
+  /// Makes the items checked from now on run as native code, compiled by
+  /// the JIT `engine`, instead of in the interpreter, or in the interpreter
+  /// again given `None`. Only the functions and externs defined from then
+  /// on can be called.
+  pub fn set_engine(&mut self, engine: Option<Box<dyn Engine>>) {
+    self.engine = engine;
+    if let Some(engine) = &mut self.engine {
+      engine.set_precision(self.interp.precision());
+    }
+  }
+
+  /// Stops warning about `lint`.
+  pub fn allow(&mut self, lint: Lint) {
+    self.linter.allow(lint);
+  }
+
+  /// Makes `lint` be reported at `level` from now on. A denied lint stops
+  /// the item it is found in from running.
+  pub fn set_lint_level(&mut self, lint: Lint, level: LintLevel) {
+    self.linter.set_level(lint, level);
+  }
+
+  /// Makes the lint named `name` be reported at `level`, warning if there is
+  /// no such lint.
+  pub fn set_lint_level_by_name(&mut self, name: &str, level: LintLevel) -> Option<Diagnostic> {
+    self.linter.set_level_by_name(name, level, Span::default())
+  }
+
+  /// Sets the levels of lints from the configuration file `src`, as
+  /// [`Linter::configure`] does.
+  pub fn configure_lints(&mut self, src: &str) -> Vec<Diagnostic> {
+    self.linter.configure(src)
+  }
+
+  /// The warnings about the items run since the last call, and the errors
+  /// of denied lints past the first one of each item.
+  pub fn take_warnings(&mut self) -> Vec<Diagnostic> {
+    std::mem::take(&mut self.warnings)
+  }
+
+  /// Makes the functions and expressions run from now on skip their
+  /// `assert`s, as an optimized build would.
+  pub fn set_strip_asserts(&mut self, strip: bool) {
+    self.strip_asserts = strip;
+  }
+
+  /// Enters sandboxed mode, where only the externs binding the symbols in
+  /// `allowed` may be declared from now on, or leaves it. Those of the
+  /// prelude are already declared either way.
+  pub fn set_allowed_externs(&mut self, allowed: Option<HashSet<String>>) {
+    self.resolver.set_allowed_externs(allowed);
+  }
+
+  /// Makes the functions defined from now on compute their common
+  /// subexpressions once.
+  pub fn set_cse(&mut self, cse: bool) {
+    self.cse = cse;
+  }
+
+  /// Makes the calls of the functions defined from now on in modules, whose
+  /// body is at most `threshold` nodes, run their body in place. Redefining
+  /// one of them stops inlining it in the items run afterwards.
+  pub fn set_inline_threshold(&mut self, threshold: usize) {
+    self.inliner = Some(Inliner::new(threshold));
+  }
+
+  /// Checks and runs one item, yielding the value of a top-level
+  /// expression. An item that fails to type-check isn't run at all. An
+  /// `import` runs the items of the imported file, stopping at the first
+  /// error.
+  pub fn run(&mut self, ast: Ast) -> Result<Option<Value>, Diagnostic> {
+    if let Ast::Import(path, ns, span) = ast {
+      let module = self.loader.import(None, &path, ns.as_deref(), span)?;
+      for res in self.run_module(module) {
+        res?;
+      }
+      return Ok(None);
+    }
+    let ast = self.prepare(ast)?;
+    self.execute(ast)
+  }
+
+  /// Runs one item that [`Session::prepare`] has checked. The value of a
+  /// top-level expression is the same whichever engine runs it.
+  fn execute(&mut self, ast: Ast) -> Result<Option<Value>, Diagnostic> {
+    if let Some(engine) = &mut self.engine {
+      let ty = match &ast {
+        Ast::Func(func) if func.proto.name.is_empty() => func.proto.ret_ty.clone(),
+        _ => None,
+      };
+      let val = engine.run(ast)?;
+      return Ok(val.map(|val| match ty {
+        Some(ty) => as_interpreted(val, &ty),
+        None => val,
+      }));
+    }
+    let res = self.interp.run(ast);
+    res.map_err(|msg| {
+      let span = self.interp.failed_at().unwrap_or_default();
+      Diagnostic::error(span, msg).with_code("runtime")
+    })
+  }
+
+  /// Checks one item other than an `import`, and transforms it as asked
+  /// to, ready to run or compile.
+  fn prepare(&mut self, mut ast: Ast) -> Result<Ast, Diagnostic> {
+    if let (Some(inliner), Ast::Func(func)) = (&mut self.inliner, &ast) {
+      inliner.define(func);
+    }
+    if let Ast::Proto(proto) = &ast {
+      self.resolver.check_extern(proto)?;
+    }
+    self.resolver.declare(&ast);
+    let lints = self.linter.lint(&ast); // of the code as written
+    self.report_lints(lints)?;
+    self.strip_asserts(&mut ast);
+    self.effects.declare(&ast);
+    self.checker.check(&mut ast)?;
+    if let Some(inliner) = &self.inliner {
+      inliner.inline_item(&mut ast);
+      self.strip_asserts(&mut ast); // of the inlined bodies
+    }
+    const_fold_item(&mut ast);
+    if self.cse {
+      cse_item(&mut ast, &self.effects);
+    }
+    Ok(ast)
+  }
+
+  /// Runs the items of a whole file in phases: the prototypes of all of its
+  /// functions are collected first, so bodies may call functions defined
+  /// further down, then all the items are checked, and then run in order.
+  /// A module that fails a phase doesn't go on to the next one: the errors
+  /// of that phase are the results then. A runtime error in one item
+  /// doesn't stop the others from running.
+  pub fn run_module(&mut self, module: ModuleAst) -> Vec<Result<Option<Value>, Diagnostic>> {
+    match self.check_module(module) {
+      Ok(module) => module
+        .items
+        .into_iter()
+        .map(|item| self.execute(item))
+        .collect(),
+      Err(errors) => errors.into_iter().map(Err).collect(),
+    }
+  }
+
+  /// Declares the items of `module`, then checks and transforms each of
+  /// them as [`Session::run`] does, reporting the errors of every item.
+  fn check_module(&mut self, module: ModuleAst) -> Result<ModuleAst, Vec<Diagnostic>> {
+    self.declare_module(&module)?;
+    let mut items = vec![];
+    let mut errors = vec![];
+    for item in module.items {
+      match self.prepare(item) {
+        Ok(item) => items.push(item),
+        Err(e) => errors.push(e),
+      }
+    }
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+    Ok(ModuleAst { items })
+  }
+
+  /// Resolves the names of `module`, and declares its items so that they
+  /// may be used before they are defined.
+  fn declare_module(&mut self, module: &ModuleAst) -> Result<(), Vec<Diagnostic>> {
+    let errors = self.resolver.resolve_module(module);
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+    self.checker.declare(module);
+    if let Some(inliner) = &mut self.inliner {
+      inliner.declare_module(module);
+    }
+    if let Some(engine) = &mut self.engine {
+      engine.declare(module);
+    }
+    module
+      .items
+      .iter()
+      .for_each(|item| self.effects.declare(item));
+    Ok(())
+  }
+
+  /// Loads the file at `path`, along with the files it imports, and runs it
+  /// as one module from its [`Entry`]: the result of calling `main` comes
+  /// last, after those of the items. `main` isn't called when the module
+  /// fails to check.
+  pub fn run_file(
+    &mut self,
+    path: &Path,
+  ) -> Result<Vec<Result<Option<Value>, Diagnostic>>, Diagnostic> {
+    let module = self.loader.load(path)?;
+    let entry = Entry::of(&module)?;
+    let lints = self.linter.lint_program(&module);
+    self.report_lints(lints)?;
+    let module = match self.check_module(module) {
+      Ok(module) => module,
+      Err(errors) => return Ok(errors.into_iter().map(Err).collect()),
+    };
+    let mut results: Vec<_> = module
+      .items
+      .into_iter()
+      .map(|item| self.execute(item))
+      .collect();
+    if entry == Entry::Main {
+      let call = ExprAst::CallAst("main".to_string(), vec![], Span::default());
+      results.push(self.run(Ast::new_top_level(call, Span::default())));
+    }
+    Ok(results)
+  }
+
+  /// Checks the file at `path` as [`Session::run_file`] does, then compiles
+  /// it to the executable `output` instead of running it. The errors of
+  /// every item are reported.
+  #[cfg(feature = "llvm")]
+  pub fn build_file(
+    &mut self,
+    path: &Path,
+    output: &Path,
+    options: &mut BuildOptions,
+  ) -> Result<(), Vec<Diagnostic>> {
+    let (module, entry) = self.check_program(path)?;
+    let precision = self.interp.precision();
+    native::build(&module, entry, precision, options, output)
+  }
+
+  /// Checks the file at `path` as [`Session::build_file`] does, then
+  /// hands it to `build`, which compiles it with another backend, as
+  /// [`backend::build`](crate::codegen::backend::build) does, with where it
+  /// starts and the precision of numbers.
+  pub fn build_with(
+    &mut self,
+    path: &Path,
+    build: impl FnOnce(&ModuleAst, Entry, Precision) -> Result<(), Vec<Diagnostic>>,
+  ) -> Result<(), Vec<Diagnostic>> {
+    let (module, entry) = self.check_program(path)?;
+    build(&module, entry, self.interp.precision())
+  }
+
+  /// Loads and checks the file at `path`, along with the files it imports,
+  /// for a backend to compile: the items as they would run, and where the
+  /// program starts.
+  fn check_program(&mut self, path: &Path) -> Result<(ModuleAst, Entry), Vec<Diagnostic>> {
+    let module = self.loader.load(path).map_err(|e| vec![e])?;
+    let entry = Entry::of(&module).map_err(|e| vec![e])?;
+    let lints = self.linter.lint_program(&module);
+    self.report_lints(lints).map_err(|e| vec![e])?;
+    Ok((self.check_module(module)?, entry))
+  }
+
+  /// Queues the warnings of lints, failing with the first one that is
+  /// denied.
+  fn report_lints(&mut self, lints: Vec<Warning>) -> Result<(), Diagnostic> {
+    let mut denied = None;
+    for lint in &lints {
+      let diagnostic = self.linter.diagnostic(lint);
+      match diagnostic.severity {
+        Severity::Error if denied.is_none() => denied = Some(diagnostic),
+        _ => self.warnings.push(diagnostic),
+      }
+    }
+    denied.map_or(Ok(()), Err)
+  }
+
+  /// Replaces the calls of `assert` in `ast` with `()` if asked to.
+  fn strip_asserts(&self, ast: &mut Ast) {
+    if self.strip_asserts {
+      match ast {
+        Ast::Func(func) => func.body.strip_asserts(),
+        Ast::Expr(expr) => expr.strip_asserts(),
+        _ => (),
+      }
+    }
+  }
+}
+
+/// `val`, computed by an engine for a value of the type `ty`, as the
+/// interpreter gives it: the numeric backends compute unit as 0.0.
+fn as_interpreted(val: Value, ty: &str) -> Value {
+  let tys = ty.strip_prefix('(').and_then(|tys| tys.strip_suffix(')'));
+  match (val, tys) {
+    _ if ty == "unit" => Value::Unit,
+    (Value::Tuple(elems), Some(tys)) => Value::Tuple(
+      elems
+        .iter()
+        .zip(split_elems(tys))
+        .map(|(elem, ty)| as_interpreted(elem.clone(), ty))
+        .collect(),
+    ),
+    (val, _) => val,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::vm::{Vm, VmEngine};
+  use std::io::Cursor;
+  use std::rc::Rc;
+
+  fn run(session: &mut Session, src: &'static str) -> Result<Option<Value>, String> {
+    let mut lexer = Lexer::new(Cursor::new(src));
+    session
+      .run(Ast::parse(&mut lexer).unwrap())
+      .map_err(|e| e.to_string())
+  }
+
+  #[test]
+  fn session_forward_refs() {
+    let src = "def even(n: int): bool if n == 0 then true else odd(n - 1);
+      def odd(n: int): bool if n == 0 then false else even(n - 1); even(10); odd(7)";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let module = ModuleAst::parse(&mut lexer).unwrap();
+    let vals: Vec<_> = Session::with_output(io::sink())
+      .run_module(module)
+      .into_iter()
+      .map(Result::unwrap)
+      .collect();
+    let one = Some(Value::Num(1.0));
+    assert_eq!(vals, vec![None, None, one.clone(), one]);
+  }
+
+  #[test]
+  fn session_import() {
+    let dir = std::env::temp_dir().join(format!("kale-session-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let lib = dir.join("lib.kale");
+    std::fs::write(&lib, "def twice(x) 2 * x").unwrap();
+    let mut session = Session::with_output(io::sink());
+    let import = format!("import \"{}\"", lib.display());
+    let mut lexer = Lexer::new(Cursor::new(import));
+    assert_eq!(session.run(Ast::parse(&mut lexer).unwrap()), Ok(None));
+    assert_eq!(run(&mut session, "twice(4)"), Ok(Some(Value::Num(8.0))));
+    let err = run(&mut session, "  import none").unwrap_err();
+    assert!(err.starts_with("1:3: Cannot import `none.kale`: "));
+    std::fs::write(
+      dir.join("text.kale"),
+      "extern len(s: str): int; def size(s: str) len(s)",
+    )
+    .unwrap();
+    let main = dir.join("main.kale");
+    std::fs::write(&main, "import text; text.size(\"abc\") + text.len(\"de\")").unwrap();
+    let results = session.run_file(&main).unwrap();
+    assert_eq!(results.last(), Some(&Ok(Some(Value::Int(5)))));
+  }
+
+  #[test]
+  fn session_entry() {
+    let entry = |src: &'static str| {
+      Entry::of(&ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap())
+        .map_err(|e| e.to_string())
+    };
+    assert_eq!(entry("def f() 1; f()"), Ok(Entry::TopLevel));
+    assert_eq!(entry("def main() f(); def f() 1"), Ok(Entry::Main));
+    assert_eq!(
+      entry("def main(argc) 0"),
+      Err("1:5: `main` must take no arguments, found 1".to_string())
+    );
+    assert_eq!(
+      entry("def main() 0; main()"),
+      Err("1:15: Top-level expression in a program with a `main` function".to_string())
+    );
+
+    let path = std::env::temp_dir().join(format!("kale-entry-{}.kale", std::process::id()));
+    std::fs::write(
+      &path,
+      "var n = 1; def main() { n = n + helper(); n }; def helper() 2; def unused() 3",
+    )
+    .unwrap();
+    let mut session = Session::with_output(io::sink());
+    let results = session.run_file(&path).unwrap();
+    assert_eq!(
+      results,
+      vec![
+        Ok(None),
+        Ok(None),
+        Ok(None),
+        Ok(None),
+        Ok(Some(Value::Int(3)))
+      ]
+    );
+    let warnings: Vec<_> = session
+      .take_warnings()
+      .iter()
+      .map(|warning| format!("{} [{}]", warning, warning.code.unwrap()))
+      .collect();
+    assert_eq!(
+      warnings,
+      vec!["1:68: Function `unused` is never called [dead-function]"]
+    );
+  }
+
+  #[test]
+  fn session_resolve() {
+    let src = "printd(1); def f(n) n + m; g(2)";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let results = Session::with_output(io::sink()).run_module(module);
+    assert_eq!(
+      results,
+      vec![
+        Err(
+          Diagnostic::error(Span { line: 1, col: 25 }, "Unknown variable `m`")
+            .with_code("unresolved")
+        ),
+        Err(
+          Diagnostic::error(Span { line: 1, col: 28 }, "Unknown function `g`")
+            .with_code("unresolved")
+        ),
+      ]
+    );
+    let errors: Vec<_> = results
+      .iter()
+      .map(|e| e.as_ref().unwrap_err().to_string())
+      .collect();
+    assert_eq!(
+      errors,
+      vec!["1:25: Unknown variable `m`", "1:28: Unknown function `g`",]
+    );
+  }
+
+  #[test]
+  fn session_phases() {
+    let run_module = |session: &mut Session, src: &'static str| {
+      let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+      let results = session.run_module(module).into_iter();
+      results
+        .map(|res| res.map_err(|e| e.to_string()))
+        .collect::<Vec<_>>()
+    };
+    let mut session = Session::with_output(io::sink());
+    assert_eq!(
+      run_module(&mut session, "printd(1); def f(s: str) s * 2; f(\"a\")"),
+      vec![Err("1:28: `*` expects a number, found str".to_string())]
+    );
+    session.set_lint_level(Lint::Shadowing, LintLevel::Deny);
+    assert_eq!(
+      run_module(&mut session, "printd(1); def h(n) let n = 1 in n; h(2)"),
+      vec![Err(
+        "1:25: Binding `n` shadows a parameter of the same name".to_string()
+      )]
+    );
+  }
+
+  #[test]
+  fn session_engine_unit() {
+    let src = "(); ((), 2); def f() (); f()";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut session = Session::with_output(io::sink());
+    session.set_engine(Some(Box::new(VmEngine::new(Vm::builder().build()))));
+    let unit_pair = Value::Tuple(Rc::new([Value::Unit, Value::Int(2)]));
+    assert_eq!(
+      session.run_module(module),
+      vec![
+        Ok(Some(Value::Unit)),
+        Ok(Some(unit_pair)),
+        Ok(None),
+        Ok(Some(Value::Unit))
+      ]
+    );
+  }
+
+  #[test]
+  fn session_cse() {
+    let src = "def f(a, b) sqrt(a*b + a*b) + (if a > 1 then a*b else 0) + a*b;
+      def g(n) { var k = n in { k*2; k = k + 1; k*2 } } + n*2; f(1, 2) + f(2, 9) + g(3)";
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let module = ModuleAst::parse(&mut lexer).unwrap();
+    let mut session = Session::with_output(io::sink());
+    session.set_cse(true);
+    let results = session.run_module(module);
+    assert_eq!(results[2], Ok(Some(Value::Num(60.0))));
+  }
+
+  #[test]
+  fn session_inline() {
+    let src = "def sq(x) x * x; def f(a) { assert(a > 0); sq(a) + 1 }; f(3)";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let mut session = Session::with_output(io::sink());
+    session.set_inline_threshold(8);
+    let results = session.run_module(module);
+    assert_eq!(results[2], Ok(Some(Value::Num(10.0))));
+    assert_eq!(run(&mut session, "def sq(x) x + 1"), Ok(None));
+    assert_eq!(run(&mut session, "def g(a) sq(a) * 2"), Ok(None));
+    assert_eq!(run(&mut session, "g(3)"), Ok(Some(Value::Num(8.0))));
+  }
+
+  #[test]
+  fn session_warnings() {
+    let mut session = Session::with_output(io::sink());
+    assert_eq!(run(&mut session, "def f(x, y) x"), Ok(None));
+    assert_eq!(
+      run(&mut session, "let z = 1 in f(2, 3)"),
+      Ok(Some(Value::Num(2.0)))
+    );
+    let warnings: Vec<_> = session
+      .take_warnings()
+      .iter()
+      .map(|warning| format!("{} [{}]", warning, warning.code.unwrap()))
+      .collect();
+    assert_eq!(
+      warnings,
+      vec![
+        "1:10: Unused parameter `y` of `f` [unused-param]",
+        "1:5: Unused binding `z` [unused-binding]",
+      ]
+    );
+    assert_eq!(session.take_warnings(), vec![]);
+    session.allow(Lint::UnusedParam);
+    assert_eq!(run(&mut session, "def g(x) 0"), Ok(None));
+    assert_eq!(session.take_warnings(), vec![]);
+    session.set_lint_level(Lint::Shadowing, LintLevel::Deny);
+    let src = "def h(n) let n = 1, m = 2 in n";
+    assert_eq!(
+      run(&mut session, src),
+      Err("1:14: Binding `n` shadows a parameter of the same name".to_string())
+    );
+    let warnings = session.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].severity, Severity::Warning);
+    assert_eq!(
+      run(&mut session, "h(1)"),
+      Err("1:1: Unknown function `h`".to_string())
+    );
+  }
+
+  #[test]
+  fn session_prelude() {
+    let mut session = Session::with_output(io::sink());
+    let src = "pow(2, 10) + sqrt(abs(-16)) + floor(max(1.5, min(2.7, 3)))";
+    assert_eq!(run(&mut session, src), Ok(Some(Value::Num(1030.0))));
+    assert_eq!(
+      run(&mut session, "sin(\"x\")"),
+      Err("1:1: Argument 1 of `sin` expects double, found str".to_string())
+    );
+    assert_eq!(run(&mut session, "def abs(x) 7; abs(-1)"), Ok(None));
+    assert_eq!(run(&mut session, "abs(-1)"), Ok(Some(Value::Int(7))));
+    assert_eq!(
+      run(&mut session, "1 + printd(2)"),
+      Err("1:3: `+` expects a number, found unit".to_string())
+    );
+    assert_eq!(run(&mut session, "srand(1)"), Ok(Some(Value::Unit)));
+    session.set_input(Cursor::new("4\n"));
+    assert_eq!(run(&mut session, "readd() * 2"), Ok(Some(Value::Num(8.0))));
+  }
+
+  #[test]
+  fn session_asserts() {
+    let mut session = Session::with_output(io::sink());
+    assert_eq!(run(&mut session, "assert(1 < 2)"), Ok(Some(Value::Unit)));
+    let src = "def check(x) { assert(x > 0); x }";
+    assert_eq!(run(&mut session, src), Ok(None));
+    assert_eq!(
+      run(&mut session, "check(-1)"),
+      Err("1:16: Assertion failed".to_string())
+    );
+    let e = session.run(Ast::parse(&mut Lexer::new(Cursor::new("check(-2)"))).unwrap());
+    let e = e.unwrap_err();
+    assert_eq!(
+      (e.message.as_str(), e.primary_span),
+      ("Assertion failed", Span { line: 1, col: 16 })
+    );
+    assert_eq!(
+      run(&mut session, "try { check(-1); \"\" } catch e -> e.1"),
+      Ok(Some("Assertion failed".into()))
+    );
+    assert_eq!(
+      run(&mut session, "assert(1, 2)"),
+      Err("1:1: Function `assert` expects 1 argument, found 2".to_string())
+    );
+    session.set_strip_asserts(true);
+    assert_eq!(
+      run(&mut session, "check(-1)"),
+      Err("1:16: Assertion failed".to_string())
+    );
+    assert_eq!(run(&mut session, src), Ok(None));
+    assert_eq!(run(&mut session, "check(-1)"), Ok(Some(Value::Num(-1.0))));
+    session.take_warnings();
+    assert_eq!(run(&mut session, "def pos(x) assert(x > 0)"), Ok(None));
+    assert_eq!(session.take_warnings(), vec![]);
+  }
+
+  #[test]
+  fn session_repl_forward_refs() {
+    let mut session = Session::with_output(io::sink());
+    let even = "def even(n) if n == 0 then 1 else odd(n - 1)";
+    assert_eq!(run(&mut session, even), Ok(None));
+    assert_eq!(
+      run(&mut session, "even(3)"),
+      Err("Unknown function referenced `odd`".to_string())
+    );
+    let odd = "def odd(n) if n == 0 then 0 else even(n - 1)";
+    assert_eq!(run(&mut session, odd), Ok(None));
+    assert_eq!(run(&mut session, "even(3)"), Ok(Some(Value::Int(0))));
+    assert_eq!(run(&mut session, "def f(x) g(x, 1)"), Ok(None));
+    assert_eq!(
+      run(&mut session, "def g(x) x"),
+      Err("1:10: Function `g` expects 1 arguments, found 2".to_string())
+    );
+    assert_eq!(
+      run(&mut session, "g(1)"),
+      Err("1:1: Unknown function `g`".to_string())
+    );
+  }
+}
@@ -0,0 +1,1309 @@
+use crate::consts::eval_const;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ExprAst, FuncAst, ModuleAst, Pattern, ProtoAst, StructAst, UnOp};
+use crate::value::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+/// Type - the static type of an expression. An int is accepted wherever a
+/// double is expected, but a bool is never taken for a number. The types of
+/// array elements are not tracked, unlike those of tuple elements. Neither
+/// are the signatures of closures and function values: calling a `func` may
+/// yield anything.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Type {
+  Unit,
+  Double,
+  Int,
+  Bool,
+  Str,
+  Array,
+  Tuple(Vec<Option<Type>>),
+  Struct(Rc<str>), // named by the struct declaration
+  Func,
+}
+
+impl Type {
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "unit" => Some(Self::Unit),
+      "double" => Some(Self::Double),
+      "int" => Some(Self::Int),
+      "bool" => Some(Self::Bool),
+      "str" => Some(Self::Str),
+      "array" => Some(Self::Array),
+      "func" => Some(Self::Func),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Unit => write!(f, "unit"),
+      Self::Double => write!(f, "double"),
+      Self::Int => write!(f, "int"),
+      Self::Bool => write!(f, "bool"),
+      Self::Str => write!(f, "str"),
+      Self::Array => write!(f, "array"),
+      Self::Tuple(elems) => {
+        let elems: Vec<_> = elems
+          .iter()
+          .map(|ty| ty.as_ref().map_or("_".to_string(), Type::to_string))
+          .collect();
+        write!(f, "({})", elems.join(", "))
+      }
+      Self::Struct(name) => write!(f, "{}", name),
+      Self::Func => write!(f, "func"),
+    }
+  }
+}
+
+/// The type of an expression while checking it. `None` stands for the result
+/// of a recursive call, whose type is only known once the whole body has
+/// been checked, for array elements and for `return`, which yields no value
+/// where it appears; it is compatible with everything.
+type Ty = Option<Type>;
+
+struct Sig {
+  args: Vec<Type>,
+  ret: Ty,
+}
+
+/// A call, in the body of a definition, to a function that isn't defined
+/// yet. It gets checked once the function is.
+struct Pending {
+  callee: String,
+  args: Vec<Ty>,
+  span: Span,
+}
+
+/// The parameters of the definition being checked whose types are inferred,
+/// by position, with the type their uses require so far and where the first
+/// of those uses is.
+struct Inference {
+  func: String,
+  params: Vec<bool>, // whether the type of each parameter is inferred
+  found: Vec<Option<(Type, Span)>>,
+  conflict: Option<Diagnostic>,
+}
+
+/// TypeChecker - checks top-level items in order, remembering the signatures
+/// of the functions and the types of the globals seen so far. Parameters
+/// without an annotation get the type their uses in the body require, as
+/// `s` is a str in `def f(s) "Hello " + s`, and are doubles when no use
+/// says more than that they are numbers. Return types are inferred from the
+/// body unless annotated. Checked prototypes are annotated with the
+/// resulting types, so backends can rely on `arg_tys` and `ret_ty`.
+///
+/// A definition may call functions that are only defined later: either they
+/// were [declared](TypeChecker::declare) up front, or the call is checked
+/// once the callee gets defined, as when typing definitions into the REPL.
+pub struct TypeChecker {
+  funcs: HashMap<String, Sig>,
+  overloads: Vec<(BinOp, Sig)>, // of builtin operators, by operand types
+  structs: HashMap<String, Vec<(String, Type)>>, // the fields of each struct
+  globals: HashMap<String, Ty>,
+  consts: HashMap<String, Value>, // the values of the constants
+  returns: Vec<(Ty, Span)>,       // the `return`s in the body being checked
+  pending: Vec<Pending>,
+  later: HashSet<String>,       // declared, but with parameter types to infer
+  inference: Option<Inference>, // while inferring parameter types
+  in_def: bool,                 // checking the body of a named function
+}
+
+impl TypeChecker {
+  pub fn new() -> Self {
+    Self {
+      funcs: HashMap::new(),
+      overloads: vec![],
+      structs: HashMap::new(),
+      globals: HashMap::new(),
+      consts: HashMap::new(),
+      returns: vec![],
+      pending: vec![],
+      later: HashSet::new(),
+      inference: None,
+      in_def: false,
+    }
+  }
+
+  /// Records the signatures of the functions and externs of `module`, so
+  /// that any body can call any of them. Return types that aren't annotated
+  /// stay unknown until the definition is checked, and so do functions with
+  /// parameters to infer: calls to them get checked once they are defined.
+  /// Prototypes with unknown types are skipped here and reported when their
+  /// item is checked.
+  pub fn declare(&mut self, module: &ModuleAst) {
+    for item in &module.items {
+      let (proto, ret) = match item {
+        Ast::Func(func) if BinOp::overloaded_by(&func.proto.name).is_some() => continue,
+        Ast::Func(func) if func.proto.arg_tys.contains(&None) => {
+          self.later.insert(func.proto.name.clone());
+          continue;
+        }
+        Ast::Func(func) if !func.proto.name.is_empty() => (&func.proto, None),
+        Ast::Proto(proto) => (proto, Some(Type::Double)),
+        _ => continue,
+      };
+      let (Ok(args), Ok(declared)) = (self.arg_types(proto), self.ret_type(proto)) else {
+        continue;
+      };
+      let ret = declared.or(ret);
+      self.funcs.insert(proto.name.clone(), Sig { args, ret });
+    }
+  }
+
+  pub fn check(&mut self, ast: &mut Ast) -> Result<(), Diagnostic> {
+    match ast {
+      Ast::Expr(expr) => self
+        .check_expr(expr, &mut vec![], Span::default())
+        .map(|_| ()),
+      Ast::Proto(proto) => {
+        let args = self.arg_types(proto)?;
+        let ret = self.ret_type(proto)?.unwrap_or(Type::Double);
+        Self::annotate(proto, &args, &ret);
+        let sig = Sig {
+          args,
+          ret: Some(ret),
+        };
+        self.funcs.insert(proto.name.clone(), sig);
+        self.resolve_pending(&proto.name)
+      }
+      Ast::Func(func) => self.check_func(func),
+      Ast::Global(vars, _) => {
+        for (name, _, init) in vars {
+          let ty = match init {
+            Some(init) => self.check_expr(init, &mut vec![], Span::default())?,
+            None => Some(Type::Double),
+          };
+          self.globals.insert(name.clone(), ty);
+        }
+        Ok(())
+      }
+      Ast::Const(name, _, init, span) => {
+        let ty = self.check_expr(init, &mut vec![], *span)?;
+        let Some(val) = eval_const(init, &self.consts) else {
+          let msg = format!(
+            "Initializer of const `{}` is not a constant expression",
+            name
+          );
+          return Err(type_error(*span, msg));
+        };
+        self.consts.insert(name.clone(), val);
+        self.globals.insert(name.clone(), ty);
+        Ok(())
+      }
+      Ast::Struct(decl) => self.check_struct(decl),
+      Ast::Import(..) => Ok(()),
+    }
+  }
+
+  pub fn check_module(&mut self, module: &mut ModuleAst) -> Result<(), Diagnostic> {
+    self.declare(module);
+    for item in module.items.iter_mut() {
+      self.check(item)?;
+    }
+    Ok(())
+  }
+
+  fn check_struct(&mut self, decl: &mut StructAst) -> Result<(), Diagnostic> {
+    if self.structs.contains_key(&decl.name) {
+      return Err(type_error(
+        decl.span,
+        format!("Struct `{}` is already defined", decl.name),
+      ));
+    }
+    let tys = decl.field_tys.iter();
+    let tys = tys
+      .map(|ty| self.parse_type(ty.as_deref(), decl.span))
+      .collect::<Result<Vec<_>, _>>()?;
+    decl.field_tys = tys.iter().map(|ty| Some(ty.to_string())).collect();
+    let fields = decl.fields.iter().cloned().zip(tys).collect();
+    self.structs.insert(decl.name.clone(), fields);
+    self.resolve_pending(&decl.name)
+  }
+
+  fn check_func(&mut self, func: &mut FuncAst) -> Result<(), Diagnostic> {
+    if let Some(op) = BinOp::overloaded_by(&func.proto.name) {
+      return self.check_overload(op, func);
+    }
+    let args = self.arg_types(&func.proto)?;
+    let declared = self.ret_type(&func.proto)?;
+    let sig = Sig {
+      args: args.clone(),
+      ret: declared.clone(),
+    };
+    let name = func.proto.name.clone();
+    let prev = self.funcs.insert(name.clone(), sig);
+    let pending = self.pending.len();
+    self.in_def = !name.is_empty();
+    let res = self.infer_args(func, args, &declared).and_then(|args| {
+      self.funcs.get_mut(&name).unwrap().args = args.clone();
+      let tys: Vec<_> = args.iter().cloned().map(Some).collect();
+      let ret = self.check_body(func, &tys, declared)?;
+      Ok((args, ret))
+    });
+    self.in_def = false;
+    let res = res.and_then(|(args, ret)| {
+      Self::annotate(&mut func.proto, &args, &ret);
+      self.funcs.get_mut(&name).unwrap().ret = Some(ret);
+      self.resolve_pending(&name)
+    });
+    if res.is_err() {
+      // the function doesn't get defined after all
+      match prev {
+        Some(prev) => self.funcs.insert(name, prev),
+        None => self.funcs.remove(&name),
+      };
+      self.pending.truncate(pending);
+    }
+    res
+  }
+
+  /// Infers the types of the parameters of `func` without an annotation
+  /// from their uses, by checking its body with them unknown, and returns
+  /// the types of all its parameters. Errors of that first pass get
+  /// reported by the check of the body with the types found.
+  fn infer_args(
+    &mut self,
+    func: &mut FuncAst,
+    args: Vec<Type>,
+    declared: &Option<Type>,
+  ) -> Result<Vec<Type>, Diagnostic> {
+    let params: Vec<_> = func.proto.arg_tys.iter().map(Option::is_none).collect();
+    if !params.contains(&true) {
+      return Ok(args);
+    }
+    let tys: Vec<_> = args
+      .iter()
+      .zip(&params)
+      .map(|(ty, inferred)| (!inferred).then(|| ty.clone()))
+      .collect();
+    self.inference = Some(Inference {
+      func: func.proto.name.clone(),
+      found: vec![None; params.len()],
+      params,
+      conflict: None,
+    });
+    let pending = self.pending.len();
+    let _ = self.check_body(func, &tys, declared.clone());
+    self.pending.truncate(pending);
+    let inference = self.inference.take().unwrap();
+    if let Some(conflict) = inference.conflict {
+      return Err(conflict);
+    }
+    let found = inference.found.into_iter();
+    Ok(
+      args
+        .into_iter()
+        .zip(found)
+        .map(|(ty, found)| found.map_or(ty, |(ty, _)| ty))
+        .collect(),
+    )
+  }
+
+  /// Records that the use of `name` at `span` requires a value of type `ty`,
+  /// if it is a parameter whose type is being inferred. Requiring a double
+  /// says nothing, as that is what a parameter is by default.
+  fn require(&mut self, name: &str, scope: &[(String, Ty)], ty: Type, span: Span) {
+    let Some(inference) = &mut self.inference else {
+      return;
+    };
+    let Some(i) = scope.iter().rposition(|(n, _)| n == name) else {
+      return;
+    };
+    if ty == Type::Double || !inference.params.get(i).copied().unwrap_or(false) {
+      return;
+    }
+    match &inference.found[i] {
+      None => inference.found[i] = Some((ty, span)),
+      Some((found, _)) if *found == ty => (),
+      Some((found, at)) => {
+        let msg = format!(
+          "Parameter `{}` of `{}` is used as {} at {}, but as {} here",
+          name, inference.func, found, at, ty
+        );
+        inference.conflict.get_or_insert(type_error(span, msg));
+      }
+    }
+  }
+
+  fn require_expr(&mut self, expr: &ExprAst, scope: &[(String, Ty)], ty: Type, span: Span) {
+    if let ExprAst::VarAst(name, _) = expr {
+      self.require(name, scope, ty, span);
+    }
+  }
+
+  /// Requires the arguments of a call to `name` to have the types of its
+  /// parameters.
+  fn require_args(&mut self, name: &str, args: &[ExprAst], scope: &[(String, Ty)], span: Span) {
+    let params = match (self.structs.get(name), self.funcs.get(name)) {
+      (Some(fields), _) => fields.iter().map(|(_, ty)| ty.clone()).collect(),
+      (None, Some(sig)) => sig.args.clone(),
+      _ => match name {
+        "chr" | "panic" => vec![Type::Int],
+        "ord" | "format" | "printf" => vec![Type::Str],
+        _ => return,
+      },
+    };
+    for (arg, ty) in args.iter().zip(params) {
+      self.require_expr(arg, scope, ty, span);
+    }
+  }
+
+  /// Checks an overload of the builtin operator `op`, which replaces one for
+  /// the same operand types. Operators the builtin one already applies to,
+  /// and those on numbers and booleans, can't be overloaded.
+  fn check_overload(&mut self, op: BinOp, func: &mut FuncAst) -> Result<(), Diagnostic> {
+    let span = func.proto.span;
+    let args = self.arg_types(&func.proto)?;
+    let declared = self.ret_type(&func.proto)?;
+    let (lhs, rhs) = (Some(args[0].clone()), Some(args[1].clone()));
+    let scalar = |ty: &Ty| is_number(ty) || ty == &Some(Type::Bool);
+    if (scalar(&lhs) && scalar(&rhs)) || Self::check_bin(op, &lhs, &rhs, span).is_ok() {
+      return Err(type_error(
+        span,
+        format!(
+          "Operator `{}` cannot be overloaded for {} and {}",
+          op.as_str(),
+          args[0],
+          args[1]
+        ),
+      ));
+    }
+    let prev = self
+      .overloads
+      .iter()
+      .position(|(o, sig)| *o == op && sig.args == args);
+    let prev = prev.map(|i| self.overloads.remove(i));
+    let sig = Sig {
+      args: args.clone(),
+      ret: declared.clone(),
+    };
+    self.overloads.push((op, sig));
+    let tys: Vec<_> = args.iter().cloned().map(Some).collect();
+    match self.check_body(func, &tys, declared) {
+      Ok(ret) => {
+        Self::annotate(&mut func.proto, &args, &ret);
+        self.overloads.last_mut().unwrap().1.ret = Some(ret);
+        Ok(())
+      }
+      Err(e) => {
+        self.overloads.pop();
+        self.overloads.extend(prev);
+        Err(e)
+      }
+    }
+  }
+
+  /// The result type of the user-defined overload of `op` that applies to
+  /// `lhs` and `rhs`, if any. An operand of unknown type may select any
+  /// overload, so several applying are only an error when both are known.
+  fn check_overloads(
+    &self,
+    op: BinOp,
+    lhs: &Ty,
+    rhs: &Ty,
+    span: Span,
+  ) -> Result<Option<Ty>, Diagnostic> {
+    if is_number(lhs) && is_number(rhs) {
+      return Ok(None);
+    }
+    let found: Vec<_> = self
+      .overloads
+      .iter()
+      .filter(|(o, sig)| *o == op && accepts(&sig.args[0], lhs) && accepts(&sig.args[1], rhs))
+      .collect();
+    match &found[..] {
+      [] => Ok(None),
+      [(_, sig)] => Ok(Some(sig.ret.clone())),
+      [(_, a), (_, b), ..] => match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => Err(type_error(
+          span,
+          format!(
+            "Ambiguous operator `{}` for {} and {}: overloads for ({}, {}) and ({}, {}) both apply",
+            op.as_str(),
+            lhs,
+            rhs,
+            a.args[0],
+            a.args[1],
+            b.args[0],
+            b.args[1]
+          ),
+        )),
+        _ => Ok(Some(None)),
+      },
+    }
+  }
+
+  /// Checks the calls to `name` made before it was defined.
+  fn resolve_pending(&mut self, name: &str) -> Result<(), Diagnostic> {
+    let pending = std::mem::take(&mut self.pending);
+    let res = pending
+      .iter()
+      .filter(|call| call.callee == name)
+      .try_for_each(|call| self.check_call(name, &call.args, call.span).map(|_| ()));
+    self.pending = pending;
+    if res.is_ok() {
+      self.pending.retain(|call| call.callee != name);
+    }
+    res
+  }
+
+  /// Checks the body of `func`, with its parameters of types `args`, and
+  /// returns its return type.
+  fn check_body(
+    &mut self,
+    func: &mut FuncAst,
+    args: &[Ty],
+    declared: Option<Type>,
+  ) -> Result<Type, Diagnostic> {
+    let proto = &func.proto;
+    let mut scope = proto
+      .args
+      .iter()
+      .cloned()
+      .zip(args.iter().cloned())
+      .collect();
+    self.returns.clear();
+    let mut body = self.check_expr(&mut func.body, &mut scope, proto.span)?;
+    for (ty, span) in std::mem::take(&mut self.returns) {
+      body = match &declared {
+        Some(ret) if !accepts(ret, &ty) => {
+          return Err(type_error(
+            span,
+            format!(
+              "`{}` is declared to return {}, but returns {}",
+              proto.name,
+              ret,
+              ty.unwrap()
+            ),
+          ))
+        }
+        Some(_) => body,
+        None => join(&format!("Returns of `{}`", proto.name), body, ty, span)?,
+      };
+    }
+    match declared {
+      Some(ret) if !accepts(&ret, &body) => Err(type_error(
+        proto.span,
+        format!(
+          "`{}` is declared to return {}, but its body is {}",
+          proto.name,
+          ret,
+          body.unwrap()
+        ),
+      )),
+      Some(ret) => Ok(ret),
+      None => Ok(body.unwrap_or(Type::Double)),
+    }
+  }
+
+  /// Checks `expr` with the local bindings in `scope`, innermost last.
+  /// Errors are reported at `span`, the innermost node that carries one.
+  fn check_expr(
+    &mut self,
+    expr: &mut ExprAst,
+    scope: &mut Vec<(String, Ty)>,
+    span: Span,
+  ) -> Result<Ty, Diagnostic> {
+    let err = |msg: String| Err(type_error(span, msg));
+    match expr {
+      ExprAst::NumAst(_) => Ok(Some(Type::Double)),
+      ExprAst::IntAst(_) => Ok(Some(Type::Int)),
+      ExprAst::BoolAst(_) => Ok(Some(Type::Bool)),
+      ExprAst::UnitAst => Ok(Some(Type::Unit)),
+      ExprAst::StrAst(_) => Ok(Some(Type::Str)),
+      ExprAst::VarAst(name, _) => match self.lookup(name, scope) {
+        Some(ty) => Ok(ty),
+        None if self.funcs.contains_key(name) => Ok(Some(Type::Func)),
+        None => err(format!("Unknown variable `{}`", name)),
+      },
+      ExprAst::UnaryAst(op, operand, span) => {
+        let ty = self.check_expr(operand, scope, *span)?;
+        match op {
+          UnOp::Not => Ok(Some(Type::Bool)),
+          UnOp::Neg => Self::expect_number("-", &ty, *span).map(|_| ty),
+        }
+      }
+      ExprAst::BinAst(lhs, op, rhs, span) => {
+        let lhs_ty = self.check_expr(lhs, scope, *span)?;
+        let rhs_ty = self.check_expr(rhs, scope, *span)?;
+        for (operand, other) in [(&**lhs, &rhs_ty), (&**rhs, &lhs_ty)] {
+          if let Some(ty) = operand_type(*op, other) {
+            self.require_expr(operand, scope, ty, *span);
+          }
+        }
+        match self.check_overloads(*op, &lhs_ty, &rhs_ty, *span)? {
+          Some(ty) => Ok(ty),
+          None => Self::check_bin(*op, &lhs_ty, &rhs_ty, *span),
+        }
+      }
+      ExprAst::CallAst(name, args, span) => {
+        let arg_tys = args
+          .iter_mut()
+          .map(|arg| self.check_expr(arg, scope, *span))
+          .collect::<Result<Vec<_>, _>>()?;
+        match self.lookup(name, scope) {
+          // a variable that may hold a closure shadows the function
+          Some(Some(Type::Func) | None) => {
+            self.require(name, scope, Type::Func, *span);
+            Ok(None)
+          }
+          _ => {
+            self.require_args(name, args, scope, *span);
+            self.check_call(name, &arg_tys, *span)
+          }
+        }
+      }
+      ExprAst::LambdaAst(args, body) => {
+        // the parameters may be bound to anything, and a `return` in the
+        // body leaves the lambda rather than the enclosing function
+        let depth = scope.len();
+        scope.extend(args.iter().map(|(arg, _)| (arg.clone(), None)));
+        let returns = std::mem::take(&mut self.returns);
+        let res = self.check_expr(body, scope, span);
+        self.returns = returns;
+        scope.truncate(depth);
+        res.map(|_| Some(Type::Func))
+      }
+      ExprAst::IfAst { cond, then, els } => {
+        self.check_expr(cond, scope, span)?;
+        let then = self.check_expr(then, scope, span)?;
+        let els = self.check_expr(els, scope, span)?;
+        join("Branches of `if`", then, els, span)
+      }
+      ExprAst::MatchAst(expr, arms, span) => {
+        let ty = self.check_expr(expr, scope, *span)?;
+        let mut res = None;
+        for (pat, body) in arms {
+          let lits = match pat {
+            Pattern::Wild => vec![],
+            Pattern::Lit(lit) => vec![lit],
+            Pattern::Range(lo, hi) => vec![lo, hi],
+          };
+          for lit in lits {
+            let lit = self.check_expr(lit, scope, *span)?;
+            if let Some(ty @ (Type::Str | Type::Bool)) = &lit {
+              self.require_expr(expr, scope, ty.clone(), *span);
+            }
+            if !comparable(&ty, &lit) {
+              return Err(type_error(
+                *span,
+                format!(
+                  "Pattern of type {} cannot match a value of type {}",
+                  lit.unwrap(),
+                  ty.unwrap()
+                ),
+              ));
+            }
+          }
+          let body = self.check_expr(body, scope, *span)?;
+          res = Some(match res {
+            None => body,
+            Some(prev) => join("Arms of `match`", prev, body, *span)?,
+          });
+        }
+        Ok(res.flatten())
+      }
+      ExprAst::ArrayAst(elems) => {
+        for elem in elems {
+          self.check_expr(elem, scope, span)?;
+        }
+        Ok(Some(Type::Array))
+      }
+      ExprAst::TupleAst(elems) => {
+        let elems = elems
+          .iter_mut()
+          .map(|elem| self.check_expr(elem, scope, span))
+          .collect::<Result<_, _>>()?;
+        Ok(Some(Type::Tuple(elems)))
+      }
+      ExprAst::ElemAst(tuple, i) => match self.check_expr(tuple, scope, span)? {
+        None => Ok(None),
+        Some(Type::Tuple(elems)) => match elems.get(*i) {
+          Some(ty) => Ok(ty.clone()),
+          None => err(format!(
+            "Tuple index {} out of range for {}",
+            i,
+            Type::Tuple(elems)
+          )),
+        },
+        Some(ty) => err(format!("Cannot take element {} of {}", i, ty)),
+      },
+      ExprAst::IndexAst(array, index) => {
+        self.require_expr(array, scope, Type::Array, span);
+        self.require_expr(index, scope, Type::Int, span);
+        match self.check_expr(array, scope, span)? {
+          None | Some(Type::Array) => (),
+          Some(ty) => return err(format!("Cannot index into {}", ty)),
+        }
+        match self.check_expr(index, scope, span)? {
+          None | Some(Type::Int) => Ok(None),
+          Some(ty) => err(format!("Array index must be an int, found {}", ty)),
+        }
+      }
+      ExprAst::FieldAst(expr, field) => {
+        let owners: Vec<_> = self
+          .structs
+          .iter()
+          .filter(|(_, fields)| fields.iter().any(|(f, _)| f == field))
+          .map(|(name, _)| name.clone())
+          .collect();
+        if let [owner] = &owners[..] {
+          self.require_expr(expr, scope, Type::Struct(owner.as_str().into()), span);
+        }
+        match self.check_expr(expr, scope, span)? {
+          None => Ok(None),
+          Some(Type::Struct(name)) => {
+            let fields = &self.structs[name.as_ref()];
+            match fields.iter().find(|(f, _)| f == field) {
+              Some((_, ty)) => Ok(Some(ty.clone())),
+              None => err(format!("No field `{}` on struct `{}`", field, name)),
+            }
+          }
+          Some(ty) => err(format!("Cannot access field `{}` of {}", field, ty)),
+        }
+      }
+      ExprAst::BlockAst(exprs) | ExprAst::SeqAst(exprs) => {
+        let mut ty = None;
+        for expr in exprs {
+          ty = self.check_expr(expr, scope, span)?;
+        }
+        Ok(ty)
+      }
+      ExprAst::LetAst(bindings, body) => {
+        let depth = scope.len();
+        for (name, _, init) in bindings {
+          let ty = self.check_expr(init, scope, span);
+          scope.push((name.clone(), ty?));
+        }
+        let res = self.check_expr(body, scope, span);
+        scope.truncate(depth);
+        res
+      }
+      ExprAst::LetTupleAst(names, init, body) => {
+        let tys = match self.check_expr(init, scope, span)? {
+          None => vec![None; names.len()],
+          Some(Type::Tuple(elems)) if elems.len() == names.len() => elems,
+          Some(ty @ Type::Tuple(_)) => {
+            return err(format!(
+              "Cannot destructure {} into {} bindings",
+              ty,
+              names.len()
+            ))
+          }
+          Some(ty) => return err(format!("Cannot destructure {} as a tuple", ty)),
+        };
+        let depth = scope.len();
+        scope.extend(names.iter().map(|(name, _)| name.clone()).zip(tys));
+        let res = self.check_expr(body, scope, span);
+        scope.truncate(depth);
+        res
+      }
+      ExprAst::VarInAst(vars, body) => {
+        let depth = scope.len();
+        for (name, _, init) in vars {
+          let ty = match init {
+            Some(init) => self.check_expr(init, scope, span)?,
+            None => Some(Type::Double),
+          };
+          scope.push((name.clone(), ty));
+        }
+        let res = self.check_expr(body, scope, span);
+        scope.truncate(depth);
+        res
+      }
+      ExprAst::AssignAst(name, val, _) => {
+        let val = self.check_expr(val, scope, span)?;
+        match self.lookup(name, scope) {
+          Some(Some(ty)) if !accepts(&ty, &val) => err(format!(
+            "Cannot assign {} to `{}` of type {}",
+            val.unwrap(),
+            name,
+            ty
+          )),
+          Some(ty) => Ok(ty),
+          None => err(format!("Unknown variable `{}`", name)),
+        }
+      }
+      ExprAst::TryAst(expr, name, handler, span) => {
+        let ty = self.check_expr(expr, scope, *span)?;
+        let depth = scope.len();
+        let error = Type::Tuple(vec![Some(Type::Int), Some(Type::Str)]);
+        scope.extend(name.iter().map(|name| (name.clone(), Some(error.clone()))));
+        let res = self.check_expr(handler, scope, *span);
+        scope.truncate(depth);
+        join("Branches of `try`", ty, res?, *span)
+      }
+      ExprAst::ReturnAst(val, span) => {
+        let ty = self.check_expr(val, scope, *span)?;
+        self.returns.push((ty, *span));
+        Ok(None)
+      }
+      ExprAst::FuncRefAst(name, span) => match self.funcs.contains_key(name) {
+        true => Ok(Some(Type::Func)),
+        false => Err(type_error(*span, format!("Unknown function `{}`", name))),
+      },
+    }
+  }
+
+  fn check_bin(op: BinOp, lhs: &Ty, rhs: &Ty, span: Span) -> Result<Ty, Diagnostic> {
+    let err = |verb: &str| {
+      let name = |ty: &Ty| ty.as_ref().map_or("a number".to_string(), Type::to_string);
+      let msg = format!(
+        "Operator `{}` cannot {} {} {} {}",
+        op.as_str(),
+        verb,
+        name(lhs),
+        if verb == "compare" { "with" } else { "and" },
+        name(rhs)
+      );
+      Err(type_error(span, msg))
+    };
+    let is_str = |ty: &Ty| matches!(ty, None | Some(Type::Str));
+    match op {
+      BinOp::And | BinOp::Or => Ok(Some(Type::Bool)),
+      BinOp::BitAnd | BinOp::BitOr | BinOp::Xor | BinOp::Shl | BinOp::Shr => match (lhs, rhs) {
+        (None | Some(Type::Int), None | Some(Type::Int)) => Ok(Some(Type::Int)),
+        _ => err("be applied to"),
+      },
+      BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge | BinOp::Eq | BinOp::Ne => {
+        let comparable = match (lhs, rhs) {
+          (a, b) if is_number(a) && is_number(b) => true,
+          (a, b) if is_str(a) && is_str(b) => true,
+          (Some(Type::Bool), Some(Type::Bool)) => matches!(op, BinOp::Eq | BinOp::Ne),
+          _ => false,
+        };
+        match comparable {
+          true => Ok(Some(Type::Bool)),
+          false => err("compare"),
+        }
+      }
+      BinOp::Add if lhs == &Some(Type::Str) || rhs == &Some(Type::Str) => {
+        match is_str(lhs) && is_str(rhs) {
+          true => Ok(Some(Type::Str)),
+          false => err("be applied to"),
+        }
+      }
+      BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem => {
+        Self::expect_number(op.as_str(), lhs, span)?;
+        Self::expect_number(op.as_str(), rhs, span)?;
+        match (lhs, rhs) {
+          (Some(Type::Int), Some(Type::Int)) => Ok(Some(Type::Int)),
+          (None, _) | (_, None) => Ok(None),
+          _ => Ok(Some(Type::Double)),
+        }
+      }
+    }
+  }
+
+  fn check_call(&mut self, name: &str, args: &[Ty], span: Span) -> Result<Ty, Diagnostic> {
+    let err = |msg: String| Err(type_error(span, msg));
+    if let Some(fields) = self.structs.get(name) {
+      let params: Vec<_> = fields.iter().map(|(_, ty)| ty.clone()).collect();
+      Self::check_args(name, &params, args, span)?;
+      return Ok(Some(Type::Struct(name.into())));
+    }
+    let Some(sig) = self.funcs.get(name) else {
+      return match name {
+        "int" | "float" | "len" | "chr" | "ord" | "assert" | "panic" if args.len() != 1 => {
+          err(format!(
+            "Function `{}` expects 1 argument, found {}",
+            name,
+            args.len()
+          ))
+        }
+        "int" => Self::expect_number("int", &args[0], span).map(|_| Some(Type::Int)),
+        "float" => Self::expect_number("float", &args[0], span).map(|_| Some(Type::Double)),
+        "chr" => match &args[0] {
+          None | Some(Type::Int) => Ok(Some(Type::Str)),
+          Some(ty) => err(format!("`chr` expects an int, found {}", ty)),
+        },
+        "ord" => match &args[0] {
+          None | Some(Type::Str) => Ok(Some(Type::Int)),
+          Some(ty) => err(format!("`ord` expects a str, found {}", ty)),
+        },
+        "assert" => Ok(Some(Type::Unit)),
+        // never returns, so its result fits anywhere
+        "panic" => match &args[0] {
+          None | Some(Type::Int) => Ok(None),
+          Some(ty) => err(format!("`panic` expects an int, found {}", ty)),
+        },
+        "len" => match &args[0] {
+          None | Some(Type::Str | Type::Array) => Ok(Some(Type::Int)),
+          Some(ty) => err(format!("`len` expects a str or array, found {}", ty)),
+        },
+        "format" | "printf" => match args.first() {
+          Some(None | Some(Type::Str)) if name == "format" => Ok(Some(Type::Str)),
+          Some(None | Some(Type::Str)) => Ok(Some(Type::Int)),
+          Some(Some(ty)) => err(format!("`{}` expects a format str, found {}", name, ty)),
+          None => err(format!("`{}` expects a format str", name)),
+        },
+        _ if self.in_def || self.later.contains(name) => {
+          self.pending.push(Pending {
+            callee: name.to_string(),
+            args: args.to_vec(),
+            span,
+          });
+          Ok(None)
+        }
+        _ => err(format!("Unknown function `{}`", name)),
+      };
+    };
+    Self::check_args(name, &sig.args, args, span)?;
+    Ok(sig.ret.clone())
+  }
+
+  fn check_args(name: &str, params: &[Type], args: &[Ty], span: Span) -> Result<(), Diagnostic> {
+    let err = |msg: String| Err(type_error(span, msg));
+    if params.len() != args.len() {
+      return err(format!(
+        "Function `{}` expects {} arguments, found {}",
+        name,
+        params.len(),
+        args.len()
+      ));
+    }
+    for (i, (param, arg)) in params.iter().zip(args).enumerate() {
+      if !accepts(param, arg) {
+        return err(format!(
+          "Argument {} of `{}` expects {}, found {}",
+          i + 1,
+          name,
+          param,
+          arg.as_ref().unwrap()
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  fn expect_number(what: &str, ty: &Ty, span: Span) -> Result<(), Diagnostic> {
+    match ty {
+      Some(ty) if !matches!(ty, Type::Int | Type::Double) => Err(type_error(
+        span,
+        format!("`{}` expects a number, found {}", what, ty),
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  fn lookup(&self, name: &str, scope: &[(String, Ty)]) -> Option<Ty> {
+    let local = scope.iter().rev().find(|(n, _)| n == name);
+    local
+      .map(|(_, ty)| ty.clone())
+      .or_else(|| self.globals.get(name).cloned())
+  }
+
+  fn arg_types(&self, proto: &ProtoAst) -> Result<Vec<Type>, Diagnostic> {
+    let tys = proto.arg_tys.iter();
+    tys
+      .map(|ty| self.parse_type(ty.as_deref(), proto.span))
+      .collect()
+  }
+
+  /// The declared return type, if there is one.
+  fn ret_type(&self, proto: &ProtoAst) -> Result<Option<Type>, Diagnostic> {
+    match &proto.ret_ty {
+      Some(ty) => self.parse_type(Some(ty), proto.span).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  /// Resolves a type annotation, which may name a struct.
+  fn parse_type(&self, name: Option<&str>, span: Span) -> Result<Type, Diagnostic> {
+    let Some(name) = name else {
+      return Ok(Type::Double);
+    };
+    if let Some(elems) = name.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+      let elems = split_elems(elems)
+        .into_iter()
+        .map(|elem| match elem {
+          "_" => Ok(None),
+          elem => self.parse_type(Some(elem), span).map(Some),
+        })
+        .collect::<Result<_, _>>()?;
+      return Ok(Type::Tuple(elems));
+    }
+    match Type::from_name(name) {
+      Some(ty) => Ok(ty),
+      None if self.structs.contains_key(name) => Ok(Type::Struct(name.into())),
+      None => Err(type_error(span, format!("Unknown type `{}`", name))),
+    }
+  }
+
+  fn annotate(proto: &mut ProtoAst, args: &[Type], ret: &Type) {
+    proto.arg_tys = args.iter().map(|ty| Some(ty.to_string())).collect();
+    proto.ret_ty = Some(ret.to_string());
+  }
+}
+
+fn type_error(span: Span, msg: String) -> Diagnostic {
+  Diagnostic::error(span, msg).with_code("type")
+}
+
+fn is_number(ty: &Ty) -> bool {
+  matches!(ty, None | Some(Type::Int | Type::Double))
+}
+
+/// The type an operand of `op` must have, given the type of the other one,
+/// when that says more than that it is a number.
+fn operand_type(op: BinOp, other: &Ty) -> Option<Type> {
+  match (op, other) {
+    (BinOp::BitAnd | BinOp::BitOr | BinOp::Xor | BinOp::Shl | BinOp::Shr, _) => Some(Type::Int),
+    (BinOp::Add | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge, Some(Type::Str)) => {
+      Some(Type::Str)
+    }
+    (BinOp::Eq | BinOp::Ne, Some(ty @ (Type::Str | Type::Bool))) => Some(ty.clone()),
+    _ => None,
+  }
+}
+
+/// Whether values of these types may be compared with `==`.
+fn comparable(a: &Ty, b: &Ty) -> bool {
+  a.is_none() || b.is_none() || a == b || (is_number(a) && is_number(b))
+}
+
+/// The type of an expression that evaluates to one of two alternatives.
+fn join(what: &str, a: Ty, b: Ty, span: Span) -> Result<Ty, Diagnostic> {
+  match (a, b) {
+    (None, ty) | (ty, None) => Ok(ty),
+    (Some(a), Some(b)) if a == b => Ok(Some(a)),
+    (Some(Type::Int), Some(Type::Double)) | (Some(Type::Double), Some(Type::Int)) => {
+      Ok(Some(Type::Double))
+    }
+    (Some(a), Some(b)) => Err(type_error(
+      span,
+      format!("{} have mismatched types: {} and {}", what, a, b),
+    )),
+  }
+}
+
+/// Whether a value of type `found` may be used where `expected` is wanted.
+fn accepts(expected: &Type, found: &Ty) -> bool {
+  match (expected, found) {
+    (_, None) | (Type::Double, Some(Type::Int)) => true,
+    (Type::Tuple(expected), Some(Type::Tuple(found))) => {
+      expected.len() == found.len()
+        && expected
+          .iter()
+          .zip(found)
+          .all(|(expected, found)| expected.as_ref().is_none_or(|ty| accepts(ty, found)))
+    }
+    (expected, Some(found)) => expected == found,
+  }
+}
+
+/// Splits the elements of a tuple type, as spelled by [`Type`]'s `Display`,
+/// at the commas that aren't nested in an inner tuple.
+pub(crate) fn split_elems(elems: &str) -> Vec<&str> {
+  let (mut parts, mut depth, mut start) = (vec![], 0, 0);
+  for (i, c) in elems.char_indices() {
+    match c {
+      '(' => depth += 1,
+      ')' => depth -= 1,
+      ',' if depth == 0 => {
+        parts.push(elems[start..i].trim());
+        start = i + 1;
+      }
+      _ => (),
+    }
+  }
+  parts.push(elems[start..].trim());
+  parts
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::io::Cursor;
+
+  fn check(src: &'static str) -> Result<ModuleAst, Diagnostic> {
+    let mut lexer = Lexer::new(Cursor::new(src));
+    let mut module = ModuleAst::parse(&mut lexer).unwrap();
+    TypeChecker::new().check_module(&mut module)?;
+    Ok(module)
+  }
+
+  fn check_err(src: &'static str) -> String {
+    check(src).unwrap_err().to_string()
+  }
+
+  #[test]
+  fn typeck_annotates() {
+    let src = "def f(n: int) n * 2; def g(x) f(1) + x; def lt(a, b) a < b;
+      def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2)";
+    let module = check(src).unwrap();
+    let sigs: Vec<_> = module
+      .items
+      .iter()
+      .map(|item| {
+        let Ast::Func(func) = item else { panic!() };
+        (
+          func.proto.arg_tys.clone(),
+          func.proto.ret_ty.clone().unwrap(),
+        )
+      })
+      .collect();
+    let some = |ty: &str| Some(ty.to_string());
+    assert_eq!(
+      sigs,
+      vec![
+        (vec![some("int")], "int".to_string()),
+        (vec![some("double")], "double".to_string()),
+        (vec![some("double"), some("double")], "bool".to_string()),
+        (vec![some("double")], "int".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn typeck_infers() {
+    let src = "def greet(name) \"Hello \" + name; def bits(n, k: int) n << k;
+      def at(xs, i) xs[i]; struct P(x, y); def px(p) p.x; def apply(f, x) f(x);
+      def yes(s) match s { \"y\" -> true, _ -> false }; def inc(x) x + 1;
+      def wrap(c) { let w = greet(c) in w }; greet(\"you\")";
+    let module = check(src).unwrap();
+    let sigs: Vec<_> = module
+      .items
+      .iter()
+      .filter_map(|item| match item {
+        Ast::Func(func) if !func.proto.name.is_empty() => {
+          let args: Vec<_> = func.proto.arg_tys.iter().flatten().cloned().collect();
+          Some(format!("{}({})", func.proto.name, args.join(", ")))
+        }
+        _ => None,
+      })
+      .collect();
+    assert_eq!(
+      sigs,
+      vec![
+        "greet(str)",
+        "bits(int, int)",
+        "at(array, int)",
+        "px(P)",
+        "apply(func, double)",
+        "yes(str)",
+        "inc(double)",
+        "wrap(str)",
+      ]
+    );
+    assert_eq!(
+      check_err("def main() shout(1); def shout(s) s + \"!\""),
+      "1:12: Argument 1 of `shout` expects str, found int"
+    );
+    assert_eq!(
+      check_err("def f(x) if x == \"a\" then chr(x) else x"),
+      "1:27: Parameter `x` of `f` is used as str at 1:15, but as int here"
+    );
+    assert_eq!(
+      check_err("def f(s) { ord(s); s * 2 }"),
+      "1:22: `*` expects a number, found str"
+    );
+  }
+
+  #[test]
+  fn typeck_accepts() {
+    let src = "var g = 1.5; const N = 3; def f(x: bool, n: int): double if x then n else g;
+      f(N > 2, N); f(!0, int(g)) * 2; var i = 0 in { i = i + N; i == 3 && true };
+      def greet(name: str) \"hi \" + name; len(greet(\"x\")) > 3 && \"a\" < \"b\";
+      len(format(\"{} {}\", 1, true)) + printf(\"\");
+      def first(a: array) a[0]; first([1, 2]) + len([true]) * [1.5][0];
+      struct Point(x: int, y); def mk(p: Point): Point p; mk(Point(1, 2.5)).x + 1;
+      def minmax(a, b) if a < b then (a, b) else (b, a); let (lo, hi) = minmax(1, 2) in hi - lo;
+      def grade(n: int) match n { 0 -> 0.0, 1..5 -> n, _ -> -1 }; match \"x\" { \"y\" -> 1, _ -> 2 };
+      def sgn(x): int { if x < 0 then return -1 else 0; 1 }; def abs(x) if x < 0 then return -x else x;
+      def apply(f: func, x) f(x) + 1; apply(\\(x) { return x * 2 }, 3); let g = \\(y) y(1) in 0;
+      apply(sgn, -2); def twice(f: func): func \\(x) f(f(x)); let g = twice(abs) in g(3);
+      var ops = [&sgn, &abs]; let f = &apply in f(&abs, 1)";
+    assert!(check(src).is_ok());
+  }
+
+  #[test]
+  fn typeck_tuple_returns() {
+    let src = "def minmax(a: int, b: int): (int, int) if a < b then (a, b) else (b, a);
+      let (lo, hi) = minmax(3, 1) in hi - lo; def f(): (double, (_, str)) (1, (f, \"x\"))";
+    let module = check(src).unwrap();
+    let Ast::Func(f) = &module.items[2] else {panic!()};
+    assert_eq!(f.proto.ret_ty.as_deref(), Some("(double, (_, str))"));
+    assert_eq!(
+      check_err("def g(): (int, int) (1, \"x\")"),
+      "1:5: `g` is declared to return (int, int), but its body is (int, str)"
+    );
+    assert_eq!(
+      check_err("def h(): (int, nope) (1, 2)"),
+      "1:5: Unknown type `nope`"
+    );
+  }
+
+  #[test]
+  fn typeck_try() {
+    assert!(
+      check("def f(i: int): str try chr(i) catch e -> e.1; try 1 catch e -> panic(e.0)").is_ok()
+    );
+    assert_eq!(
+      check_err("try 1 catch \"none\""),
+      "1:1: Branches of `try` have mismatched types: int and str"
+    );
+    assert_eq!(
+      check_err("try 1 catch e -> e.1 * 2"),
+      "1:22: `*` expects a number, found str"
+    );
+    assert_eq!(
+      check_err("panic(\"x\")"),
+      "1:1: `panic` expects an int, found str"
+    );
+  }
+
+  #[test]
+  fn typeck_overloads() {
+    let src = "struct V(x, y); def binary + (a: V, b: V): V V(a.x + b.x, a.y + b.y);
+      def binary * (a: V, k: int) if k == 0 then V(0, 0) else a + a * (k - 1);
+      def binary < (a: V, b: V) a.x < b.x; def f(v: V): bool v * 3 < v + v";
+    let module = check(src).unwrap();
+    let Ast::Func(mul) = &module.items[2] else {panic!()};
+    assert_eq!(mul.proto.ret_ty, Some("V".to_string()));
+    assert_eq!(
+      check_err("struct V(x); def binary + (a: V, b: V) a; V(1) + 1"),
+      "1:48: `+` expects a number, found V"
+    );
+  }
+
+  #[test]
+  fn typeck_errors() {
+    let src = "def f(a, b) a + b;\n  f(1, 2 < 3)";
+    assert_eq!(
+      check_err(src),
+      "2:3: Argument 2 of `f` expects double, found bool"
+    );
+    assert_eq!(
+      check_err("def e() 1; const K = [1, 2][1]; const L = K + e()"),
+      "1:43: Initializer of const `L` is not a constant expression"
+    );
+    let src = "def f(a, b) a + b; f(1)";
+    assert_eq!(
+      check_err(src),
+      "1:20: Function `f` expects 2 arguments, found 1"
+    );
+    assert_eq!(
+      check_err("1 +\n true"),
+      "1:3: `+` expects a number, found bool"
+    );
+    assert_eq!(
+      check_err("true == 1"),
+      "1:6: Operator `==` cannot compare bool with int"
+    );
+    let src = "def f(x) if x then 1 else false";
+    assert_eq!(
+      check_err(src),
+      "1:5: Branches of `if` have mismatched types: int and bool"
+    );
+    let src = "def f(): int 1.5";
+    assert_eq!(
+      check_err(src),
+      "1:5: `f` is declared to return int, but its body is double"
+    );
+    assert_eq!(check_err("def f(x: text) x"), "1:5: Unknown type `text`");
+    assert_eq!(
+      check_err("chr(ord(\"a\")) + ord(\"b\")"),
+      "1:15: Operator `+` cannot be applied to str and int"
+    );
+    assert_eq!(check_err("ord(1)"), "1:1: `ord` expects a str, found int");
+    assert_eq!(
+      check_err("def f(n: int) n << 2 | 1.5"),
+      "1:22: Operator `|` cannot be applied to int and double"
+    );
+    assert_eq!(
+      check_err("def binary + (a, b: bool) a"),
+      "1:5: Operator `+` cannot be overloaded for double and bool"
+    );
+    assert_eq!(
+      check_err("def binary < (a: str, b: str) 1"),
+      "1:5: Operator `<` cannot be overloaded for str and str"
+    );
+    let src = "struct V(x); def binary * (a: V, b) a; def binary * (a: V, b: int) a; V(1) * 2";
+    assert_eq!(
+      check_err(src),
+      "1:76: Ambiguous operator `*` for V and int: overloads for (V, double) and (V, int) both apply"
+    );
+    assert_eq!(
+      check_err("var n = 1 in n = 0.5"),
+      "1:1: Cannot assign double to `n` of type int"
+    );
+    assert_eq!(check_err("g(1)"), "1:1: Unknown function `g`");
+    let src = r#"def f(s: str) s + "!"; f("a") + 1"#;
+    assert_eq!(
+      check_err(src),
+      "1:31: Operator `+` cannot be applied to str and int"
+    );
+    assert_eq!(
+      check_err(r#"1 < "a""#),
+      "1:3: Operator `<` cannot compare int with str"
+    );
+    assert_eq!(
+      check_err("len(1)"),
+      "1:1: `len` expects a str or array, found int"
+    );
+    assert_eq!(
+      check_err("[1][1.0]"),
+      "1:1: Array index must be an int, found double"
+    );
+    assert_eq!(
+      check_err("printf(1)"),
+      "1:1: `printf` expects a format str, found int"
+    );
+    let src = "struct P(x); P(true)";
+    assert_eq!(
+      check_err(src),
+      "1:14: Argument 1 of `P` expects double, found bool"
+    );
+    assert_eq!(
+      check_err("struct P(x); P(1).z"),
+      "1:14: No field `z` on struct `P`"
+    );
+    assert_eq!(
+      check_err("struct P(x); struct P(y)"),
+      "1:21: Struct `P` is already defined"
+    );
+    assert_eq!(
+      check_err("[1] + 1"),
+      "1:5: `+` expects a number, found array"
+    );
+    let src = "def pair(x: bool) (x, 1); let (a, b, c) = pair(true) in a";
+    assert_eq!(
+      check_err(src),
+      "1:27: Cannot destructure (bool, int) into 3 bindings"
+    );
+    assert_eq!(
+      check_err("(1, 2.5).2"),
+      "1:1: Tuple index 2 out of range for (int, double)"
+    );
+    assert_eq!(
+      check_err("match 1 { \"a\" -> 1, _ -> 2 }"),
+      "1:1: Pattern of type str cannot match a value of type int"
+    );
+    assert_eq!(
+      check_err("match 1 { 0 -> 1, 1..2 -> \"a\" }"),
+      "1:1: Arms of `match` have mismatched types: int and str"
+    );
+    assert_eq!(
+      check_err("def f(x): int { if x then return 1.5 else 0; 2 }"),
+      "1:27: `f` is declared to return int, but returns double"
+    );
+    assert_eq!(
+      check_err("def f(x) { if x then return \"a\" else 0; 2 }"),
+      "1:22: Returns of `f` have mismatched types: int and str"
+    );
+    assert_eq!(
+      check_err("def apply(f, x) x; apply(\\(x) x, 1)"),
+      "1:20: Argument 1 of `apply` expects double, found func"
+    );
+    assert_eq!(check_err("1 + &nope"), "1:5: Unknown function `nope`");
+  }
+}
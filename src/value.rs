@@ -0,0 +1,208 @@
+use crate::lexer::Span;
+use crate::parser::{ExprAst, FuncAst, StructAst};
+use std::fmt;
+use std::rc::Rc;
+
+/// Value - a runtime value. Literals with a fractional part are doubles,
+/// the others are 64-bit integers. `()` is the unit value, the result of
+/// calls made only for their effect, which is not a number. Arithmetic on two integers stays exact,
+/// while mixing an integer with a double promotes the integer. Strings,
+/// arrays, tuples, structs and closures are immutable, so copies share their
+/// contents. A function value refers to the definition current when it was
+/// taken.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+  Unit,
+  Num(f64),
+  Int(i64),
+  Str(Rc<str>),
+  Array(Rc<[Value]>),
+  Tuple(Rc<[Value]>),
+  Struct(Rc<StructVal>),
+  Closure(Rc<Closure>),
+  Func(Rc<FuncAst>),
+}
+
+/// Precision - the width of the doubles a program computes with. In `F32`
+/// mode every double is rounded to the nearest 32-bit float as soon as it
+/// is produced, which gives the results of doing the arithmetic in `f32`.
+/// Integers are unaffected.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Precision {
+  #[default]
+  F64,
+  F32,
+}
+
+impl Precision {
+  pub fn round(self, n: f64) -> f64 {
+    match self {
+      Self::F64 => n,
+      Self::F32 => n as f32 as f64,
+    }
+  }
+
+  pub fn round_value(self, val: Value) -> Value {
+    match val {
+      Value::Num(n) => Value::Num(self.round(n)),
+      val => val,
+    }
+  }
+}
+
+/// StructVal - an instance of a struct, with its fields in declaration order.
+#[derive(Debug, PartialEq)]
+pub struct StructVal {
+  pub decl: Rc<StructAst>,
+  pub fields: Vec<Value>,
+}
+
+/// Closure - a lambda together with the bindings it captured, by value,
+/// when it was evaluated. Only the bindings its body mentions are kept.
+#[derive(Debug, PartialEq)]
+pub struct Closure {
+  pub args: Vec<String>,
+  pub body: ExprAst,
+  pub captured: Vec<(String, Value)>,
+}
+
+impl StructVal {
+  pub fn field(&self, name: &str) -> Option<&Value> {
+    let i = self.decl.fields.iter().position(|f| f == name)?;
+    self.fields.get(i)
+  }
+}
+
+impl Value {
+  /// The numeric value as a double, or `None` for other kinds of values.
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      Self::Num(n) => Some(*n),
+      Self::Int(i) => Some(*i as f64),
+      _ => None,
+    }
+  }
+
+  /// The name of the kind of this value, for error messages.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::Unit => "unit",
+      Self::Num(_) => "double",
+      Self::Int(_) => "int",
+      Self::Str(_) => "str",
+      Self::Array(_) => "array",
+      Self::Tuple(_) => "tuple",
+      Self::Struct(_) => "struct",
+      Self::Closure(_) => "closure",
+      Self::Func(_) => "function",
+    }
+  }
+
+  /// The literal expression evaluating to this value.
+  pub fn to_ast(&self) -> ExprAst {
+    match self {
+      Self::Unit => ExprAst::UnitAst,
+      Self::Num(n) => ExprAst::NumAst(*n),
+      Self::Int(i) => ExprAst::IntAst(*i),
+      Self::Str(s) => ExprAst::StrAst(s.to_string()),
+      Self::Array(elems) => ExprAst::ArrayAst(elems.iter().map(Value::to_ast).collect()),
+      Self::Tuple(elems) => ExprAst::TupleAst(elems.iter().map(Value::to_ast).collect()),
+      Self::Struct(s) => ExprAst::CallAst(
+        s.decl.name.clone(),
+        s.fields.iter().map(Value::to_ast).collect(),
+        Span::default(),
+      ),
+      Self::Func(func) => ExprAst::FuncRefAst(func.proto.name.clone(), Span::default()),
+      Self::Closure(c) => {
+        let args = c.args.iter().map(|arg| (arg.clone(), Span::default()));
+        let lambda = ExprAst::LambdaAst(args.collect(), Box::new(c.body.clone()));
+        match c.captured.is_empty() {
+          true => lambda,
+          false => ExprAst::LetAst(
+            c.captured
+              .iter()
+              .map(|(name, val)| (name.clone(), Span::default(), val.to_ast()))
+              .collect(),
+            Box::new(lambda),
+          ),
+        }
+      }
+    }
+  }
+}
+
+impl From<bool> for Value {
+  fn from(b: bool) -> Self {
+    Self::Num(b as u8 as f64)
+  }
+}
+
+impl From<&str> for Value {
+  fn from(s: &str) -> Self {
+    Self::Str(s.into())
+  }
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Unit => write!(f, "()"),
+      Self::Num(n) if n.is_nan() => write!(f, "nan"), // as spelled in programs
+      Self::Num(n) => write!(f, "{:?}", n),           // keeps the `.0`, unlike `{}`
+      Self::Int(i) => write!(f, "{}", i),
+      Self::Str(s) => write!(f, "{}", s),
+      Self::Array(elems) => {
+        let elems: Vec<_> = elems.iter().map(Value::to_string).collect();
+        write!(f, "[{}]", elems.join(", "))
+      }
+      Self::Tuple(elems) => {
+        let elems: Vec<_> = elems.iter().map(Value::to_string).collect();
+        write!(f, "({})", elems.join(", "))
+      }
+      Self::Struct(s) => {
+        let fields: Vec<_> = s.fields.iter().map(Value::to_string).collect();
+        write!(f, "{}({})", s.decl.name, fields.join(", "))
+      }
+      Self::Closure(c) => write!(f, "\\({})", c.args.join(", ")),
+      Self::Func(func) => write!(f, "&{}", func.proto.name),
+    }
+  }
+}
+
+/// The truthiness rule shared by every construct that tests a condition: a
+/// value is true unless it is unit, zero, the empty string or the empty
+/// array, so tuples, structs, closures and functions are always true. In
+/// particular -0.0 is false, while NaN compares unequal to everything and is
+/// therefore true.
+pub fn truthy(val: Value) -> bool {
+  match val {
+    Value::Unit => false,
+    Value::Num(n) => n != 0.0,
+    Value::Int(i) => i != 0,
+    Value::Str(s) => !s.is_empty(),
+    Value::Array(elems) => !elems.is_empty(),
+    Value::Tuple(_) | Value::Struct(_) | Value::Closure(_) | Value::Func(_) => true,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn value_display() {
+    assert_eq!(Value::Unit.to_string(), "()");
+    assert_eq!(Value::Num(3.0).to_string(), "3.0");
+    assert_eq!(Value::Num(0.5).to_string(), "0.5");
+    assert_eq!(Value::Int(-3).to_string(), "-3");
+    assert_eq!(Value::from("a\"b").to_string(), "a\"b");
+    let array = Value::Array(Rc::new([
+      Value::Int(1),
+      Value::Array(Rc::new([])),
+      "s".into(),
+    ]));
+    assert_eq!(array.to_string(), "[1, [], s]");
+    let tuple = Value::Tuple(Rc::new([Value::Num(1.0), Value::Int(2)]));
+    assert_eq!(tuple.to_string(), "(1.0, 2)");
+  }
+}
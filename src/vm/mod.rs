@@ -0,0 +1,475 @@
+mod register;
+mod stack;
+
+use crate::bytecode::{self, Kind, Op, Program};
+use crate::codegen::backend::{define_module, Backend};
+use crate::codegen::{unsupported_item, Definitions, Engine};
+use crate::diagnostic::Diagnostic;
+use crate::eval::{eval_int_bin, eval_unary};
+use crate::ir::{self, IrBackend};
+use crate::lexer::Span;
+use crate::parser::{Ast, BinOp, ModuleAst, UnOp};
+use crate::prelude;
+use crate::runtime;
+use crate::session::Entry;
+use crate::value::{Precision, Value};
+use std::io::{self, BufRead, Write};
+
+/// Mode - how a [`Vm`] runs bytecode: as it is, on the operand stack, or
+/// translated first to the code of a register machine, whose instructions
+/// take their operands from the locals and constants themselves, so it
+/// runs fewer of them to compute the same.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Mode {
+  Stack,
+  Register,
+}
+
+/// ExternFn - a function of the host that programs call as an extern,
+/// with the numbers they pass it.
+pub type ExternFn = Box<dyn FnMut(&[f64]) -> Result<f64, String>>;
+
+/// Vm - runs bytecode, which needs neither LLVM nor Cranelift, faster than
+/// the interpreter walks the AST. Its operand stack holds the locals of
+/// each call below the operands of its instructions, and it keeps the
+/// functions it returns to in call frames. Both are limited, so that
+/// runaway recursion is an error rather than a crash. Ints are held in the
+/// bits of `f64`s there, which only the ops of ints read.
+///
+/// Externs are linked by their symbols, before the program runs: to the
+/// functions given to [`VmBuilder::extern_fn`], else to the builtins of
+/// the runtime and the math of the prelude, as in the interpreter. Calling
+/// one linked to neither is an error.
+pub struct Vm {
+  mode: Mode,
+  precision: Precision,
+  stack_limit: usize,
+  frame_limit: usize,
+  out: Box<dyn Write>,
+  input: Box<dyn BufRead>,
+  externs: Vec<(String, ExternFn)>, // by their symbols
+}
+
+/// VmBuilder - the configuration of a [`Vm`].
+pub struct VmBuilder {
+  vm: Vm,
+}
+
+impl VmBuilder {
+  /// Runs bytecode in `mode`, `Stack` by default.
+  pub fn mode(mut self, mode: Mode) -> Self {
+    self.vm.mode = mode;
+    self
+  }
+
+  /// Computes with numbers of `precision`, `F64` by default.
+  pub fn precision(mut self, precision: Precision) -> Self {
+    self.vm.precision = precision;
+    self
+  }
+
+  /// Limits the operand stack to `values` numbers, locals included, a
+  /// million by default.
+  pub fn stack_limit(mut self, values: usize) -> Self {
+    self.vm.stack_limit = values;
+    self
+  }
+
+  /// Limits the depth of calls to `frames`, ten thousand by default.
+  pub fn frame_limit(mut self, frames: usize) -> Self {
+    self.vm.frame_limit = frames;
+    self
+  }
+
+  /// Where `printd` and `putchard` write, stdout by default.
+  pub fn output(mut self, out: impl Write + 'static) -> Self {
+    self.vm.out = Box::new(out);
+    self
+  }
+
+  /// Where `readd` reads, stdin by default.
+  pub fn input(mut self, input: impl BufRead + 'static) -> Self {
+    self.vm.input = Box::new(input);
+    self
+  }
+
+  /// Links the externs of symbol `symbol` to `f`, in place of any builtin.
+  pub fn extern_fn(
+    mut self,
+    symbol: &str,
+    f: impl FnMut(&[f64]) -> Result<f64, String> + 'static,
+  ) -> Self {
+    self.vm.externs.retain(|(name, _)| name != symbol);
+    self.vm.externs.push((symbol.to_string(), Box::new(f)));
+    self
+  }
+
+  pub fn build(self) -> Vm {
+    self.vm
+  }
+}
+
+impl Vm {
+  pub fn builder() -> VmBuilder {
+    VmBuilder {
+      vm: Vm {
+        mode: Mode::Stack,
+        precision: Precision::F64,
+        stack_limit: 1 << 20,
+        frame_limit: 10_000,
+        out: Box::new(io::stdout()),
+        input: Box::new(io::BufReader::new(io::stdin())),
+        externs: vec![],
+      },
+    }
+  }
+
+  pub fn set_precision(&mut self, precision: Precision) {
+    self.precision = precision;
+  }
+
+  /// Runs the functions that start `program` in order, yielding what each
+  /// returns: a number or an int, or a tuple when it returns several.
+  pub fn run(&mut self, program: &Program) -> Result<Vec<Value>, String> {
+    let linked = self.link(program)?;
+    let mut values = vec![];
+    for &func in &program.start {
+      let function = &program.functions[func as usize];
+      if !function.params.is_empty() {
+        return Err(format!(
+          "`{}` takes {} arguments, so it can't start the program",
+          function.name,
+          function.params.len()
+        ));
+      }
+      let results = self.execute(&linked, func, &[])?;
+      let mut results = results
+        .into_iter()
+        .zip(&function.returns)
+        .map(|(x, kind)| match kind {
+          Kind::Num => Value::Num(x),
+          Kind::Int => Value::Int(int_of(x)),
+        });
+      values.push(match results.len() {
+        1 => results.next().unwrap(),
+        _ => Value::Tuple(results.collect()),
+      });
+    }
+    Ok(values)
+  }
+
+  /// Calls the function `name` of `program` with `args`, yielding the
+  /// numbers it returns. Those it takes or returns as ints are converted,
+  /// the arguments as `int` does.
+  pub fn call(&mut self, program: &Program, name: &str, args: &[f64]) -> Result<Vec<f64>, String> {
+    let Some(func) = program.function(name) else {
+      return Err(format!("Unknown function `{}`", name));
+    };
+    let function = &program.functions[func as usize];
+    if args.len() != function.params.len() {
+      return Err(format!(
+        "Incorrect # arguments passed to `{}`: expected {}, got {}",
+        name,
+        function.params.len(),
+        args.len()
+      ));
+    }
+    let linked = self.link(program)?;
+    let args = args
+      .iter()
+      .zip(&function.params)
+      .map(|(&n, kind)| match kind {
+        Kind::Num => Ok(self.precision.round(n)),
+        Kind::Int => runtime::to_int(n).map(int),
+      });
+    let args = args.collect::<Result<Vec<_>, _>>()?;
+    let results = self.execute(&linked, func, &args)?;
+    let results = results.into_iter().zip(&function.returns);
+    Ok(
+      results
+        .map(|(x, kind)| match kind {
+          Kind::Num => x,
+          Kind::Int => int_of(x) as f64,
+        })
+        .collect(),
+    )
+  }
+
+  /// The result of `op`, which negates an int or converts a number to an
+  /// int or back, on `x`.
+  fn int_unary(&self, op: Op, x: f64) -> Result<f64, String> {
+    match op {
+      Op::INeg => match eval_unary(UnOp::Neg, Value::Int(int_of(x)))? {
+        Value::Int(i) => Ok(int(i)),
+        _ => unreachable!(),
+      },
+      Op::ToInt => runtime::to_int(x).map(int),
+      _ => Ok(self.precision.round(int_of(x) as f64)),
+    }
+  }
+
+  fn execute(&mut self, linked: &Linked, func: u32, args: &[f64]) -> Result<Vec<f64>, String> {
+    match self.mode {
+      Mode::Stack => stack::execute(self, linked, func, args),
+      Mode::Register => register::execute(self, linked, func, args),
+    }
+  }
+
+  /// Checks `program`, and finds what its externs and constants are for
+  /// this machine, translating its functions for the register machine in
+  /// that mode.
+  fn link<'a>(&self, program: &'a Program) -> Result<Linked<'a>, String> {
+    bytecode::check(program).map_err(|e| e.message)?;
+    let mut externs = vec![];
+    for ext in &program.externs {
+      let host = self
+        .externs
+        .iter()
+        .position(|(name, _)| *name == ext.symbol);
+      externs.push(match host {
+        Some(i) => Callable::Host(i),
+        None if is_builtin(&ext.symbol) => Callable::Builtin(ext.symbol.clone()),
+        None => Callable::Unlinked(ext.symbol.clone()),
+      });
+    }
+    let depths = (0..program.functions.len() as u32)
+      .map(|func| stack::max_depth(program, func))
+      .collect::<Result<_, _>>()?;
+    let registers = match self.mode {
+      Mode::Stack => vec![],
+      Mode::Register => (0..program.functions.len() as u32)
+        .map(|func| register::translate(program, func))
+        .collect::<Result<_, _>>()?,
+    };
+    Ok(Linked {
+      program,
+      constants: program
+        .constants
+        .iter()
+        .map(|&n| self.precision.round(n))
+        .collect(),
+      ints: program.ints.iter().map(|&i| int(i)).collect(),
+      externs,
+      depths,
+      registers,
+    })
+  }
+
+  /// Calls the extern `callable` with `args`.
+  fn call_extern(&mut self, callable: &Callable, args: &[f64]) -> Result<f64, String> {
+    let n = match callable {
+      Callable::Host(i) => (self.externs[*i].1)(args)?,
+      Callable::Builtin(symbol) => {
+        let args: Vec<_> = args.iter().map(|&n| Value::Num(n)).collect();
+        let res = runtime::call_builtin(symbol, &args, &mut *self.out, &mut *self.input);
+        match res.expect("builtins are linked")? {
+          Value::Unit => 0.0,
+          val => val.as_f64().unwrap_or(f64::NAN),
+        }
+      }
+      Callable::Unlinked(symbol) => return Err(format!("Cannot link the extern `{}`", symbol)),
+    };
+    Ok(self.precision.round(n))
+  }
+}
+
+/// The builtins of the runtime that take and return numbers, which
+/// programs declare as externs.
+fn is_builtin(symbol: &str) -> bool {
+  let runtime = ["printd", "putchard", "readd", "rand", "srand"];
+  runtime.contains(&symbol) || prelude::arity(symbol).is_some()
+}
+
+/// An int as the stack and the registers hold it.
+fn int(i: i64) -> f64 {
+  f64::from_bits(i as u64)
+}
+
+fn int_of(x: f64) -> i64 {
+  x.to_bits() as i64
+}
+
+/// The result of `op`, an arithmetic, bitwise or comparison op of ints, on
+/// `x` and `y`, which fails as in the interpreter.
+fn int_binary(op: Op, x: f64, y: f64) -> Result<f64, String> {
+  let op = match op {
+    Op::IAdd => BinOp::Add,
+    Op::ISub => BinOp::Sub,
+    Op::IMul => BinOp::Mul,
+    Op::IDiv => BinOp::Div,
+    Op::IRem => BinOp::Rem,
+    Op::BitAnd => BinOp::BitAnd,
+    Op::BitOr => BinOp::BitOr,
+    Op::Xor => BinOp::Xor,
+    Op::Shl => BinOp::Shl,
+    Op::Shr => BinOp::Shr,
+    Op::ILt => BinOp::Lt,
+    Op::IGt => BinOp::Gt,
+    Op::ILe => BinOp::Le,
+    Op::IGe => BinOp::Ge,
+    Op::IEq => BinOp::Eq,
+    Op::INe => BinOp::Ne,
+    op => unreachable!("`{}` doesn't take two ints", op),
+  };
+  match eval_int_bin(op, int_of(x), int_of(y))? {
+    Value::Int(i) => Ok(int(i)),
+    Value::Num(b) => Ok(b),
+    _ => unreachable!(),
+  }
+}
+
+/// Linked - a program checked for a machine, with its constants in the
+/// precision of the machine and its ints as the machine holds them.
+struct Linked<'a> {
+  program: &'a Program,
+  constants: Vec<f64>,
+  ints: Vec<f64>,
+  externs: Vec<Callable>,
+  depths: Vec<usize>,                    // the most operands of each function
+  registers: Vec<register::RegFunction>, // in `Register` mode
+}
+
+/// What an extern is linked to.
+enum Callable {
+  Host(usize), // an index into the functions of the host
+  Builtin(String),
+  Unlinked(String),
+}
+
+/// VmEngine - runs the items of a session with a [`Vm`], compiling the
+/// functions and externs defined so far anew along with each top-level
+/// expression, as the Cranelift JIT does.
+pub struct VmEngine {
+  defs: Definitions,
+  vm: Vm,
+}
+
+impl VmEngine {
+  pub fn new(vm: Vm) -> Self {
+    Self {
+      defs: Definitions::new(),
+      vm,
+    }
+  }
+}
+
+impl Engine for VmEngine {
+  fn declare(&mut self, module: &ModuleAst) {
+    self.defs.declare(module);
+  }
+
+  fn run(&mut self, item: Ast) -> Result<Option<Value>, Diagnostic> {
+    let item = match item {
+      Ast::Expr(expr) => Ast::new_top_level(expr, Span::default()),
+      item => item,
+    };
+    match item {
+      Ast::Func(func) if func.proto.name.is_empty() => {
+        let mut backend = IrBackend::new();
+        backend.set_optimize(self.vm.precision);
+        define_module(&mut backend, self.defs.module()).map_err(|mut errors| errors.remove(0))?;
+        backend.define_function(&func)?;
+        let module = backend
+          .finish(Entry::TopLevel)
+          .map_err(|mut errors| errors.remove(0))?;
+        let program = bytecode::compile(&module);
+        let mut values = self
+          .vm
+          .run(&program)
+          .map_err(|msg| Diagnostic::error(Span::default(), msg).with_code("vm"))?;
+        Ok(values.pop())
+      }
+      item @ (Ast::Func(_) | Ast::Proto(_)) => {
+        let lower = |defs: &ModuleAst| match ir::lower(defs, Entry::TopLevel) {
+          Ok(_) => Ok(()),
+          Err(mut errors) => Err(errors.remove(0)),
+        };
+        self.defs.define(item, lower).map(|_| None)
+      }
+      item => Err(unsupported_item("VM", &item)),
+    }
+  }
+
+  fn set_precision(&mut self, precision: Precision) {
+    self.vm.set_precision(precision);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use std::cell::RefCell;
+  use std::io::Cursor;
+  use std::rc::Rc;
+
+  fn compile(src: &'static str, entry: Entry) -> Program {
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    bytecode::compile(&ir::lower(&module, entry).unwrap())
+  }
+
+  /// A writer whose bytes can be read after the VM owns it.
+  #[derive(Clone, Default)]
+  struct Shared(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn vm_run() {
+    let src = "extern printd(x); extern twice(x);
+      def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2);
+      def minmax(a, b) if a < b then (a, b) else (b, a);
+      fib(20); minmax(3, 2); printd(7 % 3) + twice(0.1 + 0.2);";
+    let program = compile(src, Entry::TopLevel);
+    let out = Shared::default();
+    let mut vm = Vm::builder()
+      .output(out.clone())
+      .extern_fn("twice", |args| Ok(args[0] * 2.0))
+      .build();
+    let values = vm.run(&program).unwrap();
+    assert_eq!(
+      values.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+      ["6765.0", "(2.0, 3.0)", "0.6000000000000001"]
+    );
+    assert_eq!(String::from_utf8(out.0.take()).unwrap(), "1.0\n");
+    assert_eq!(vm.call(&program, "fib", &[10.0]), Ok(vec![55.0]));
+
+    let mut vm = Vm::builder()
+      .precision(Precision::F32)
+      .extern_fn("twice", |args| Ok(args[0] * 2.0))
+      .build();
+    let values = vm.call(&program, "minmax", &[0.3, 0.1]).unwrap();
+    assert_eq!(values, [0.1f32 as f64, 0.3f32 as f64]);
+
+    let mut vm = Vm::builder().frame_limit(100).build();
+    assert_eq!(
+      vm.run(&program),
+      Err("Cannot link the extern `twice`".to_string())
+    );
+    let program = compile("def f(n) if n then f(n - 1) else 0;", Entry::TopLevel);
+    assert_eq!(vm.call(&program, "f", &[99.0]), Ok(vec![0.0]));
+    assert_eq!(
+      vm.call(&program, "f", &[100.0]),
+      Err("Stack overflow: more than 100 frames".to_string())
+    );
+
+    let mut engine = VmEngine::new(Vm::builder().build());
+    let src = "def g(x) x; def f(x) g(x) + 1; f(1); def g(x) x * 10; f(1);";
+    let mut values = vec![];
+    for item in ModuleAst::parse(&mut Lexer::new(Cursor::new(src)))
+      .unwrap()
+      .items
+    {
+      values.extend(engine.run(item).unwrap());
+    }
+    assert_eq!(values, [Value::Num(2.0), Value::Num(11.0)]);
+  }
+}
@@ -0,0 +1,620 @@
+use super::stack::{depths, max_depth};
+use super::{int_binary, Linked, Vm};
+use crate::bytecode::{Op, Program};
+use std::collections::HashSet;
+
+/// Src - an operand of a register instruction: a register of the frame,
+/// a number of the constants pool or an int of the ints pool.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Src {
+  Reg(u32),
+  Const(u32),
+  Int(u32),
+}
+
+/// RegOp - an instruction of the register machine, which reads its
+/// operands where they are and writes its result to the register it names
+/// first. The registers of a frame are the locals of its function, then
+/// one for each operand the stack machine would have on its stack. A call
+/// finds its arguments in registers from the one it names, where it leaves
+/// its results. The ops of ints are those of the stack machine.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RegOp {
+  Move(u32, Src),
+  Neg(u32, Src),
+  Not(u32, Src),
+  IntUnary(Op, u32, Src),
+  IntBinary(Op, u32, Src, Src),
+  Add(u32, Src, Src),
+  Sub(u32, Src, Src),
+  Mul(u32, Src, Src),
+  Div(u32, Src, Src),
+  Rem(u32, Src, Src),
+  Lt(u32, Src, Src),
+  Gt(u32, Src, Src),
+  Le(u32, Src, Src),
+  Ge(u32, Src, Src),
+  Eq(u32, Src, Src),
+  Ne(u32, Src, Src),
+  Call(u32, u32),       // a function, and its arguments
+  CallExtern(u32, u32), // an extern, likewise
+  Jump(u32),
+  JumpIfNot(Src, u32),
+  Ret(u32), // the first of the numbers returned
+}
+
+impl RegOp {
+  /// The register the instruction writes, unless it is a call or a jump.
+  fn dst(&mut self) -> Option<&mut u32> {
+    match self {
+      RegOp::Move(dst, _)
+      | RegOp::Neg(dst, _)
+      | RegOp::Not(dst, _)
+      | RegOp::IntUnary(_, dst, _)
+      | RegOp::IntBinary(_, dst, ..)
+      | RegOp::Add(dst, ..)
+      | RegOp::Sub(dst, ..)
+      | RegOp::Mul(dst, ..)
+      | RegOp::Div(dst, ..)
+      | RegOp::Rem(dst, ..)
+      | RegOp::Lt(dst, ..)
+      | RegOp::Gt(dst, ..)
+      | RegOp::Le(dst, ..)
+      | RegOp::Ge(dst, ..)
+      | RegOp::Eq(dst, ..)
+      | RegOp::Ne(dst, ..) => Some(dst),
+      _ => None,
+    }
+  }
+}
+
+/// RegFunction - the code of a function for the register machine, and how
+/// many registers its frames take.
+pub struct RegFunction {
+  pub code: Vec<RegOp>,
+  pub registers: u32,
+}
+
+/// Translates the function `func` of `program` to code for the register
+/// machine. The operands of the stack machine become operands of the
+/// instructions that take them, unless they are still on its stack at a
+/// jump, or at the start of the code that jumps go to, where each has a
+/// register of its own. Each result goes straight to the local it is
+/// stored in next, and the code is [`optimize`]d after.
+pub fn translate(program: &Program, func: u32) -> Result<RegFunction, String> {
+  let function = &program.functions[func as usize];
+  let depths = depths(program, func)?;
+  let targets: HashSet<_> = function
+    .code
+    .iter()
+    .filter_map(|op| match *op {
+      Op::Jump(to) | Op::JumpIfNot(to) => Some(to as usize),
+      _ => None,
+    })
+    .collect();
+  let mut t = Translation {
+    locals: function.locals,
+    code: vec![],
+    stack: vec![],
+    result: false,
+    copies: vec![None; function.locals as usize],
+  };
+  let mut starts = vec![0; function.code.len()];
+  let mut fixups = vec![];
+  let mut falls_through = true;
+  for (at, op) in function.code.iter().enumerate() {
+    starts[at] = t.code.len() as u32;
+    let Some(depth) = depths[at] else {
+      continue;
+    };
+    if targets.contains(&at) {
+      if falls_through {
+        t.flush(depth);
+      }
+      t.stack = (0..depth).map(|i| Src::Reg(t.slot(i))).collect();
+      t.result = false;
+      t.copies.fill(None);
+    }
+    falls_through = true;
+    match *op {
+      Op::Const(i) => t.stack.push(Src::Const(i)),
+      Op::Int(i) => t.stack.push(Src::Int(i)),
+      Op::Load(i) => t.stack.push(t.copies[i as usize].unwrap_or(Src::Reg(i))),
+      Op::Store(i) => t.store(i),
+      Op::Neg => t.unary(RegOp::Neg),
+      Op::Not => t.unary(RegOp::Not),
+      Op::Add => t.binary(RegOp::Add),
+      Op::Sub => t.binary(RegOp::Sub),
+      Op::Mul => t.binary(RegOp::Mul),
+      Op::Div => t.binary(RegOp::Div),
+      Op::Rem => t.binary(RegOp::Rem),
+      Op::Lt => t.binary(RegOp::Lt),
+      Op::Gt => t.binary(RegOp::Gt),
+      Op::Le => t.binary(RegOp::Le),
+      Op::Ge => t.binary(RegOp::Ge),
+      Op::Eq => t.binary(RegOp::Eq),
+      Op::Ne => t.binary(RegOp::Ne),
+      Op::INeg | Op::ToInt | Op::ToNum => {
+        let x = t.stack.pop().unwrap();
+        t.push_result(RegOp::IntUnary(*op, t.slot(t.stack.len()), x));
+      }
+      Op::IAdd
+      | Op::ISub
+      | Op::IMul
+      | Op::IDiv
+      | Op::IRem
+      | Op::BitAnd
+      | Op::BitOr
+      | Op::Xor
+      | Op::Shl
+      | Op::Shr
+      | Op::ILt
+      | Op::IGt
+      | Op::ILe
+      | Op::IGe
+      | Op::IEq
+      | Op::INe => {
+        let y = t.stack.pop().unwrap();
+        let x = t.stack.pop().unwrap();
+        t.push_result(RegOp::IntBinary(*op, t.slot(t.stack.len()), x, y));
+      }
+      Op::Call(i) => {
+        let callee = &program.functions[i as usize];
+        t.call(RegOp::Call(i, 0), callee.params.len(), callee.returns.len());
+      }
+      Op::CallExtern(i) => t.call(
+        RegOp::CallExtern(i, 0),
+        program.externs[i as usize].params,
+        1,
+      ),
+      Op::Jump(to) => {
+        t.flush(t.stack.len());
+        fixups.push((t.code.len(), to));
+        t.emit(RegOp::Jump(0));
+        falls_through = false;
+      }
+      Op::JumpIfNot(to) => {
+        let cond = t.stack.pop().unwrap();
+        t.flush(t.stack.len());
+        fixups.push((t.code.len(), to));
+        t.emit(RegOp::JumpIfNot(cond, 0));
+      }
+      // numbers already in consecutive registers are returned from there
+      Op::Ret => {
+        let first = t.stack.len() - function.returns.len();
+        let consecutive = match t.stack[first..] {
+          [Src::Reg(reg), ..] => (first..t.stack.len())
+            .all(|depth| t.stack[depth] == Src::Reg(reg + (depth - first) as u32))
+            .then_some(reg),
+          _ => None,
+        };
+        let reg = consecutive.unwrap_or_else(|| {
+          t.flush(first);
+          t.slot(first)
+        });
+        t.emit(RegOp::Ret(reg));
+        falls_through = false;
+      }
+    }
+  }
+  for (at, to) in fixups {
+    let start = starts[to as usize];
+    match &mut t.code[at] {
+      RegOp::Jump(target) | RegOp::JumpIfNot(_, target) => *target = start,
+      _ => unreachable!(),
+    }
+  }
+  let registers = function.locals + max_depth(program, func)? as u32;
+  let mut code = t.code;
+  let returns = function.returns.len() as u32;
+  optimize(&mut code, program, returns, registers);
+  Ok(RegFunction { code, registers })
+}
+
+/// Translation - the state of the translation of a function: the
+/// operands the stack machine would have, in order, and the locals that
+/// hold a copy of a constant or of another local since the last jump
+/// target, which are loaded from there.
+struct Translation {
+  locals: u32,
+  code: Vec<RegOp>,
+  stack: Vec<Src>,
+  result: bool,             // whether the last instruction pushed its result
+  copies: Vec<Option<Src>>, // of each local
+}
+
+impl Translation {
+  /// The register of the operand at `depth` on the stack.
+  fn slot(&self, depth: usize) -> u32 {
+    self.locals + depth as u32
+  }
+
+  fn emit(&mut self, op: RegOp) {
+    self.code.push(op);
+    self.result = false;
+  }
+
+  /// Moves the operands on the stack from `from` up to their registers.
+  fn flush(&mut self, from: usize) {
+    for depth in from..self.stack.len() {
+      let slot = self.slot(depth);
+      if self.stack[depth] != Src::Reg(slot) {
+        let src = std::mem::replace(&mut self.stack[depth], Src::Reg(slot));
+        self.emit(RegOp::Move(slot, src));
+      }
+    }
+  }
+
+  fn push_result(&mut self, op: RegOp) {
+    let slot = self.slot(self.stack.len());
+    self.emit(op);
+    self.stack.push(Src::Reg(slot));
+    self.result = true;
+  }
+
+  fn unary(&mut self, op: fn(u32, Src) -> RegOp) {
+    let x = self.stack.pop().unwrap();
+    self.push_result(op(self.slot(self.stack.len()), x));
+  }
+
+  fn binary(&mut self, op: fn(u32, Src, Src) -> RegOp) {
+    let y = self.stack.pop().unwrap();
+    let x = self.stack.pop().unwrap();
+    self.push_result(op(self.slot(self.stack.len()), x, y));
+  }
+
+  fn call(&mut self, op: RegOp, params: usize, returns: usize) {
+    let first = self.stack.len() - params;
+    self.flush(first);
+    self.stack.truncate(first);
+    let args = self.slot(first);
+    self.emit(match op {
+      RegOp::Call(func, _) => RegOp::Call(func, args),
+      RegOp::CallExtern(ext, _) => RegOp::CallExtern(ext, args),
+      _ => unreachable!(),
+    });
+    for depth in first..first + returns {
+      self.stack.push(Src::Reg(self.slot(depth)));
+    }
+  }
+
+  /// Stores the operand on top of the stack to the local `local`, once
+  /// the operands that are still that local are moved to registers of
+  /// their own. The result of the last instruction goes straight there.
+  fn store(&mut self, local: u32) {
+    let src = self.stack.pop().unwrap();
+    let result = self.result;
+    let mut moved = false;
+    for depth in 0..self.stack.len() {
+      if self.stack[depth] == Src::Reg(local) {
+        let slot = self.slot(depth);
+        self.stack[depth] = Src::Reg(slot);
+        self.emit(RegOp::Move(slot, Src::Reg(local)));
+        moved = true;
+      }
+    }
+    let last = self.code.last_mut().and_then(RegOp::dst);
+    match last {
+      Some(dst) if result && !moved && src == Src::Reg(*dst) => *dst = local,
+      _ => self.emit(RegOp::Move(local, src)),
+    }
+    self.result = false;
+    for copy in &mut self.copies {
+      if *copy == Some(Src::Reg(local)) {
+        *copy = None;
+      }
+    }
+    self.copies[local as usize] = match src {
+      Src::Reg(reg) if reg >= self.locals || reg == local => None,
+      src => Some(src),
+    };
+  }
+}
+
+/// Removes the instructions whose results are never read, and the jumps
+/// to the next instruction, and makes those whose result is only moved to
+/// another register write it there instead, until there are none left.
+fn optimize(code: &mut Vec<RegOp>, program: &Program, returns: u32, registers: u32) {
+  loop {
+    let live = liveness(code, program, returns, registers as usize);
+    let targets: HashSet<_> = code.iter().filter_map(target).collect();
+    let mut removed = vec![false; code.len()];
+    for at in 0..code.len() {
+      let moved = match code[at] {
+        RegOp::Jump(to) if to as usize == at + 1 => {
+          removed[at] = true;
+          continue;
+        }
+        RegOp::Move(dst, Src::Reg(src)) if !live[at][src as usize] => Some((dst, src)),
+        _ => None,
+      };
+      let op = code[at];
+      let dead = match code[at].dst() {
+        Some(&mut dst) => !live[at][dst as usize] || op == RegOp::Move(dst, Src::Reg(dst)),
+        None => false,
+      };
+      if dead {
+        removed[at] = true;
+        continue;
+      }
+      let Some((dst, src)) = moved else {
+        continue;
+      };
+      if at > 0 && !removed[at - 1] && !targets.contains(&(at as u32)) {
+        if let Some(last) = code[at - 1].dst().filter(|last| **last == src) {
+          *last = dst;
+          removed[at] = true;
+        }
+      }
+    }
+    if !removed.contains(&true) {
+      return;
+    }
+    // the instructions kept before each, where jumps to it go now
+    let mut kept = vec![0; code.len() + 1];
+    for at in 0..code.len() {
+      kept[at + 1] = kept[at] + !removed[at] as u32;
+    }
+    let mut at = 0;
+    code.retain(|_| {
+      at += 1;
+      !removed[at - 1]
+    });
+    for op in code.iter_mut() {
+      if let RegOp::Jump(to) | RegOp::JumpIfNot(_, to) = op {
+        *to = kept[*to as usize];
+      }
+    }
+  }
+}
+
+fn target(op: &RegOp) -> Option<u32> {
+  match *op {
+    RegOp::Jump(to) | RegOp::JumpIfNot(_, to) => Some(to),
+    _ => None,
+  }
+}
+
+/// The registers live after each instruction of `code`, in a function
+/// that returns `returns` numbers: those read before they are written
+/// again.
+fn liveness(code: &[RegOp], program: &Program, returns: u32, registers: usize) -> Vec<Vec<bool>> {
+  let mut live_in = vec![vec![false; registers]; code.len() + 1];
+  let mut live_out = vec![vec![false; registers]; code.len()];
+  let mut changed = true;
+  while changed {
+    changed = false;
+    for at in (0..code.len()).rev() {
+      let mut op = code[at];
+      let succs = match op {
+        RegOp::Jump(to) => vec![to as usize],
+        RegOp::JumpIfNot(_, to) => vec![at + 1, to as usize],
+        RegOp::Ret(_) => vec![],
+        _ => vec![at + 1],
+      };
+      let mut out = vec![false; registers];
+      for succ in succs {
+        out
+          .iter_mut()
+          .zip(&live_in[succ])
+          .for_each(|(out, live)| *out |= live);
+      }
+      let mut live = out.clone();
+      let (reads, writes) = match op {
+        RegOp::Call(func, args) => {
+          let callee = &program.functions[func as usize];
+          let (params, returns) = (callee.params.len(), callee.returns.len());
+          (args..args + params as u32, args..args + returns as u32)
+        }
+        RegOp::CallExtern(ext, args) => (
+          args..args + program.externs[ext as usize].params as u32,
+          args..args + 1,
+        ),
+        RegOp::Ret(first) => (first..first + returns, 0..0),
+        _ => (0..0, op.dst().map_or(0..0, |dst| *dst..*dst + 1)),
+      };
+      for reg in writes {
+        live[reg as usize] = false;
+      }
+      for reg in reads.chain(srcs(&op)) {
+        live[reg as usize] = true;
+      }
+      changed |= live != live_in[at];
+      live_in[at] = live;
+      live_out[at] = out;
+    }
+  }
+  live_out
+}
+
+/// The registers the operands of `op` are.
+fn srcs(op: &RegOp) -> Vec<u32> {
+  let srcs = match *op {
+    RegOp::Move(_, x)
+    | RegOp::Neg(_, x)
+    | RegOp::Not(_, x)
+    | RegOp::IntUnary(_, _, x)
+    | RegOp::JumpIfNot(x, _) => vec![x],
+    RegOp::IntBinary(_, _, x, y)
+    | RegOp::Add(_, x, y)
+    | RegOp::Sub(_, x, y)
+    | RegOp::Mul(_, x, y)
+    | RegOp::Div(_, x, y)
+    | RegOp::Rem(_, x, y)
+    | RegOp::Lt(_, x, y)
+    | RegOp::Gt(_, x, y)
+    | RegOp::Le(_, x, y)
+    | RegOp::Ge(_, x, y)
+    | RegOp::Eq(_, x, y)
+    | RegOp::Ne(_, x, y) => vec![x, y],
+    _ => vec![],
+  };
+  srcs
+    .into_iter()
+    .filter_map(|src| match src {
+      Src::Reg(reg) => Some(reg),
+      Src::Const(_) | Src::Int(_) => None,
+    })
+    .collect()
+}
+
+/// Frame - a call in progress: its function, the instruction it runs next,
+/// and where its registers start.
+#[derive(Clone, Copy)]
+struct Frame {
+  func: u32,
+  pc: usize,
+  base: usize,
+}
+
+/// Calls the function `func` of the linked program with `args`, as
+/// [`super::stack::execute`] does, with the code of the register machine.
+pub fn execute(vm: &mut Vm, linked: &Linked, func: u32, args: &[f64]) -> Result<Vec<f64>, String> {
+  let program = linked.program;
+  let functions = &linked.registers;
+  let (constants, ints) = (&linked.constants[..], &linked.ints[..]);
+  let mut regs = args.to_vec();
+  let mut frames: Vec<Frame> = vec![];
+  let mut frame = enter(vm, linked, &mut regs, func, 0)?;
+  let mut code = &functions[func as usize].code[..];
+  loop {
+    let op = code[frame.pc];
+    frame.pc += 1;
+    let base = frame.base;
+    let get = |regs: &[f64], src: Src| match src {
+      Src::Reg(r) => regs[base + r as usize],
+      Src::Const(i) => constants[i as usize],
+      Src::Int(i) => ints[i as usize],
+    };
+    let round = |n: f64| vm.precision.round(n);
+    let (dst, n) = match op {
+      RegOp::Move(dst, x) => (dst, get(&regs, x)),
+      RegOp::Neg(dst, x) => (dst, -get(&regs, x)),
+      RegOp::Not(dst, x) => (dst, (get(&regs, x) == 0.0) as i32 as f64),
+      RegOp::IntUnary(op, dst, x) => (dst, vm.int_unary(op, get(&regs, x))?),
+      RegOp::IntBinary(op, dst, x, y) => (dst, int_binary(op, get(&regs, x), get(&regs, y))?),
+      RegOp::Add(dst, x, y) => (dst, round(get(&regs, x) + get(&regs, y))),
+      RegOp::Sub(dst, x, y) => (dst, round(get(&regs, x) - get(&regs, y))),
+      RegOp::Mul(dst, x, y) => (dst, round(get(&regs, x) * get(&regs, y))),
+      RegOp::Div(dst, x, y) => (dst, round(get(&regs, x) / get(&regs, y))),
+      RegOp::Rem(dst, x, y) => (dst, round(get(&regs, x) % get(&regs, y))),
+      RegOp::Lt(dst, x, y) => (dst, (get(&regs, x) < get(&regs, y)) as i32 as f64),
+      RegOp::Gt(dst, x, y) => (dst, (get(&regs, x) > get(&regs, y)) as i32 as f64),
+      RegOp::Le(dst, x, y) => (dst, (get(&regs, x) <= get(&regs, y)) as i32 as f64),
+      RegOp::Ge(dst, x, y) => (dst, (get(&regs, x) >= get(&regs, y)) as i32 as f64),
+      RegOp::Eq(dst, x, y) => (dst, (get(&regs, x) == get(&regs, y)) as i32 as f64),
+      RegOp::Ne(dst, x, y) => (dst, (get(&regs, x) != get(&regs, y)) as i32 as f64),
+      RegOp::Jump(to) => {
+        frame.pc = to as usize;
+        continue;
+      }
+      RegOp::JumpIfNot(cond, to) => {
+        if get(&regs, cond) == 0.0 {
+          frame.pc = to as usize;
+        }
+        continue;
+      }
+      RegOp::Call(callee, args) => {
+        if frames.len() + 1 >= vm.frame_limit {
+          return Err(format!(
+            "Stack overflow: more than {} frames",
+            vm.frame_limit
+          ));
+        }
+        frames.push(frame);
+        frame = enter(vm, linked, &mut regs, callee, base + args as usize)?;
+        code = &functions[callee as usize].code;
+        continue;
+      }
+      RegOp::CallExtern(ext, dst) => {
+        let first = base + dst as usize;
+        let params = program.externs[ext as usize].params;
+        let args = regs[first..first + params].to_vec();
+        (dst, vm.call_extern(&linked.externs[ext as usize], &args)?)
+      }
+      RegOp::Ret(first) => {
+        let returns = program.functions[frame.func as usize].returns.len();
+        let first = base + first as usize;
+        regs.copy_within(first..first + returns, base);
+        match frames.pop() {
+          Some(caller) => {
+            frame = caller;
+            code = &functions[frame.func as usize].code;
+            continue;
+          }
+          None => return Ok(regs[base..base + returns].to_vec()),
+        }
+      }
+    };
+    regs[base + dst as usize] = n;
+  }
+}
+
+/// Starts a call to `func`, whose arguments are in the registers from
+/// `base`, making sure its frame has the registers it takes.
+fn enter(
+  vm: &Vm,
+  linked: &Linked,
+  regs: &mut Vec<f64>,
+  func: u32,
+  base: usize,
+) -> Result<Frame, String> {
+  let end = base + linked.registers[func as usize].registers as usize;
+  if end > vm.stack_limit {
+    return Err(format!(
+      "Stack overflow: more than {} values",
+      vm.stack_limit
+    ));
+  }
+  if regs.len() < end {
+    regs.resize(end, 0.0);
+  }
+  Ok(Frame { func, pc: 0, base })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bytecode;
+  use crate::ir::lower;
+  use crate::lexer::Lexer;
+  use crate::parser::ModuleAst;
+  use crate::session::Entry;
+  use crate::vm::Mode;
+  use std::io::Cursor;
+
+  #[test]
+  fn vm_register() {
+    let src = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2);
+      def swap(a, b) (b, a);
+      def logic(x, y) !(x < y || x == 3);
+      fib(15); let (x, y) = swap(1, 2) in (x - y, y); logic(3, 4) + logic(4, 3) * 10;";
+    let module = ModuleAst::parse(&mut Lexer::new(Cursor::new(src))).unwrap();
+    let program = bytecode::compile(&lower(&module, Entry::TopLevel).unwrap());
+    let fib = translate(&program, 0).unwrap();
+    let code: Vec<_> = fib.code.iter().map(|op| format!("{:?}", op)).collect();
+    assert_eq!(
+      code,
+      [
+        "Lt(2, Reg(0), Const(0))",
+        "JumpIfNot(Reg(2), 4)",
+        "Move(10, Reg(0))",
+        "Jump(11)",
+        "Sub(11, Reg(0), Const(1))",
+        "Call(0, 11)",
+        "Move(5, Reg(11))",
+        "Sub(11, Reg(0), Const(0))",
+        "Call(0, 11)",
+        "Move(8, Reg(11))",
+        "Add(10, Reg(5), Reg(8))",
+        "Ret(10)",
+      ]
+    );
+    let run = |mode| Vm::builder().mode(mode).build().run(&program);
+    assert_eq!(run(Mode::Register), run(Mode::Stack));
+    assert_eq!(
+      run(Mode::Register).unwrap()[2],
+      crate::value::Value::Num(10.0)
+    );
+  }
+}
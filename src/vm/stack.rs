@@ -0,0 +1,196 @@
+use super::{int_binary, Linked, Vm};
+use crate::bytecode::{Function, Op, Program};
+
+/// Frame - a call in progress: its function, the instruction it runs next,
+/// and where its locals start on the stack.
+#[derive(Clone, Copy)]
+struct Frame {
+  func: u32,
+  pc: usize,
+  base: usize,
+}
+
+/// Calls the function `func` of the linked program with `args`, yielding
+/// the numbers it returns.
+pub fn execute(vm: &mut Vm, linked: &Linked, func: u32, args: &[f64]) -> Result<Vec<f64>, String> {
+  let program = linked.program;
+  let mut stack = args.to_vec();
+  let mut frames: Vec<Frame> = vec![];
+  let mut frame = enter(vm, linked, &mut stack, func, 0)?;
+  let mut code = &program.functions[func as usize].code[..];
+  let pop = |stack: &mut Vec<f64>| stack.pop().expect("the stack is checked");
+  loop {
+    let op = code[frame.pc];
+    frame.pc += 1;
+    match op {
+      Op::Const(i) => stack.push(linked.constants[i as usize]),
+      Op::Int(i) => stack.push(linked.ints[i as usize]),
+      Op::Load(i) => stack.push(stack[frame.base + i as usize]),
+      Op::Store(i) => {
+        let x = pop(&mut stack);
+        stack[frame.base + i as usize] = x;
+      }
+      Op::Neg | Op::Not => {
+        let x = pop(&mut stack);
+        stack.push(match op {
+          Op::Neg => -x,
+          _ => (x == 0.0) as i32 as f64,
+        });
+      }
+      Op::INeg | Op::ToInt | Op::ToNum => {
+        let x = pop(&mut stack);
+        stack.push(vm.int_unary(op, x)?);
+      }
+      Op::Jump(to) => frame.pc = to as usize,
+      Op::JumpIfNot(to) => {
+        if pop(&mut stack) == 0.0 {
+          frame.pc = to as usize;
+        }
+      }
+      Op::Call(callee) => {
+        if frames.len() + 1 >= vm.frame_limit {
+          return Err(format!(
+            "Stack overflow: more than {} frames",
+            vm.frame_limit
+          ));
+        }
+        let params = program.functions[callee as usize].params.len();
+        let base = stack.len() - params;
+        frames.push(frame);
+        frame = enter(vm, linked, &mut stack, callee, base)?;
+        code = &program.functions[callee as usize].code;
+      }
+      Op::CallExtern(i) => {
+        let params = program.externs[i as usize].params;
+        let args = stack.split_off(stack.len() - params);
+        let n = vm.call_extern(&linked.externs[i as usize], &args)?;
+        stack.push(n);
+      }
+      Op::Ret => {
+        let returns = program.functions[frame.func as usize].returns.len();
+        let results = stack.len() - returns;
+        stack.copy_within(results.., frame.base);
+        stack.truncate(frame.base + returns);
+        match frames.pop() {
+          Some(caller) => {
+            frame = caller;
+            code = &program.functions[frame.func as usize].code;
+          }
+          None => return Ok(stack),
+        }
+      }
+      Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Rem => {
+        let y = pop(&mut stack);
+        let x = pop(&mut stack);
+        stack.push(match op {
+          Op::Add => vm.precision.round(x + y),
+          Op::Sub => vm.precision.round(x - y),
+          Op::Mul => vm.precision.round(x * y),
+          Op::Div => vm.precision.round(x / y),
+          _ => vm.precision.round(x % y),
+        });
+      }
+      Op::Lt | Op::Gt | Op::Le | Op::Ge | Op::Eq | Op::Ne => {
+        let y = pop(&mut stack);
+        let x = pop(&mut stack);
+        stack.push(match op {
+          Op::Lt => (x < y) as i32 as f64,
+          Op::Gt => (x > y) as i32 as f64,
+          Op::Le => (x <= y) as i32 as f64,
+          Op::Ge => (x >= y) as i32 as f64,
+          Op::Eq => (x == y) as i32 as f64,
+          _ => (x != y) as i32 as f64,
+        });
+      }
+      op => {
+        let y = pop(&mut stack);
+        let x = pop(&mut stack);
+        stack.push(int_binary(op, x, y)?);
+      }
+    }
+  }
+}
+
+/// Starts a call to `func`, whose arguments are on the stack from `base`,
+/// making room for its other locals and its operands.
+fn enter(
+  vm: &Vm,
+  linked: &Linked,
+  stack: &mut Vec<f64>,
+  func: u32,
+  base: usize,
+) -> Result<Frame, String> {
+  let function = &linked.program.functions[func as usize];
+  let locals = base + function.locals as usize;
+  if locals + linked.depths[func as usize] > vm.stack_limit {
+    return Err(format!(
+      "Stack overflow: more than {} values",
+      vm.stack_limit
+    ));
+  }
+  stack.resize(locals, 0.0);
+  Ok(Frame { func, pc: 0, base })
+}
+
+/// The most operands the function `func` of `program` has on the stack,
+/// checking that each instruction finds those it takes there, as many
+/// whichever way it is reached, and that none runs past the end.
+pub fn max_depth(program: &Program, func: u32) -> Result<usize, String> {
+  let function = &program.functions[func as usize];
+  let depths = depths(program, func)?;
+  let after = |(op, depth): (&Op, &Option<usize>)| {
+    let (pops, pushes) = effect(program, function, op);
+    depth.map_or(0, |depth| depth - pops + pushes)
+  };
+  let max = function.code.iter().zip(&depths).map(after).max();
+  Ok(max.unwrap_or(0))
+}
+
+/// The operands of `op`, an instruction of `function`, and its results.
+fn effect(program: &Program, function: &Function, op: &Op) -> (usize, usize) {
+  match *op {
+    Op::Const(_) | Op::Int(_) | Op::Load(_) => (0, 1),
+    Op::Store(_) | Op::JumpIfNot(_) => (1, 0),
+    Op::Neg | Op::Not | Op::INeg | Op::ToInt | Op::ToNum => (1, 1),
+    Op::Call(i) => {
+      let callee = &program.functions[i as usize];
+      (callee.params.len(), callee.returns.len())
+    }
+    Op::CallExtern(i) => (program.externs[i as usize].params, 1),
+    Op::Jump(_) => (0, 0),
+    Op::Ret => (function.returns.len(), 0),
+    _ => (2, 1),
+  }
+}
+
+/// The operands on the stack before each instruction of the function
+/// `func` of `program`, or `None` for those never run, checked as
+/// [`max_depth`] does.
+pub fn depths(program: &Program, func: u32) -> Result<Vec<Option<usize>>, String> {
+  let function = &program.functions[func as usize];
+  let error = |at: usize, msg: &str| format!("In `{}`, {}: {}", function.name, at, msg);
+  let mut depths: Vec<Option<usize>> = vec![None; function.code.len()];
+  let mut work = vec![(0, 0)];
+  while let Some((at, depth)) = work.pop() {
+    let Some(op) = function.code.get(at) else {
+      return Err(error(at, "the code runs past its end"));
+    };
+    match depths[at] {
+      Some(seen) if seen == depth => continue,
+      Some(_) => return Err(error(at, "the stack differs between the ways in")),
+      None => depths[at] = Some(depth),
+    }
+    let (pops, pushes) = effect(program, function, op);
+    if depth < pops {
+      return Err(error(at, "the stack underflows"));
+    }
+    let depth = depth - pops + pushes;
+    match *op {
+      Op::Jump(to) => work.push((to as usize, depth)),
+      Op::JumpIfNot(to) => work.extend([(to as usize, depth), (at + 1, depth)]),
+      Op::Ret => {}
+      _ => work.push((at + 1, depth)),
+    }
+  }
+  Ok(depths)
+}